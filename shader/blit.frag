@@ -0,0 +1,11 @@
+#version 450
+
+layout (binding = 0) uniform texture2D input_texture;
+layout (binding = 1) uniform sampler sampler_llc;
+
+layout (location = 0) in vec2 o_uv;
+layout (location = 0) out vec4 uFragColor;
+
+void main() {
+    uFragColor = texture(sampler2D(input_texture, sampler_llc), o_uv);
+}
@@ -0,0 +1,28 @@
+// Timing inside shaders, for heatmaps of where a shader spends its time. `SHADER_CLOCK` is
+// defined when the device supports VK_KHR_shader_clock, without it every delta is zero.
+#ifdef SHADER_CLOCK
+#extension GL_ARB_shader_clock : require
+
+uvec2 clock_now() {
+    return clock2x32ARB();
+}
+#else
+uvec2 clock_now() {
+    return uvec2(0);
+}
+#endif
+
+// Ticks between two `clock_now()` calls of the same invocation, in an unspecified unit.
+float clock_ticks(uvec2 start, uvec2 end) {
+    uint low = end.x - start.x;
+    uint high = end.y - start.y - (end.x < start.x ? 1 : 0);
+    return float(high) * 4294967296.0 + float(low);
+}
+
+// Blue over green to red as `ticks` goes up to `max_ticks`.
+vec3 clock_heatmap(float ticks, float max_ticks) {
+    float t = clamp(ticks / max_ticks, 0.0, 1.0);
+    vec3 low = mix(vec3(0.0, 0.0, 1.0), vec3(0.0, 1.0, 0.0), t * 2.0);
+    vec3 high = mix(vec3(0.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0), t * 2.0 - 1.0);
+    return t < 0.5 ? low : high;
+}
@@ -0,0 +1,55 @@
+#version 450
+
+// Writes the working image to the swapchain, see `CompositePass`. Values are encoded to sRGB
+// exactly once: by the hardware for `_SRGB` formats, here for `_UNORM` formats.
+layout (binding = 0) uniform texture2D input_texture;
+layout (binding = 1) uniform sampler sampler_llc;
+
+layout(push_constant) uniform PushConstants {
+    uint input_srgb;
+    // 0 hardware, 1 shader, 2 linear
+    uint output_encoding;
+    float dither_steps;
+    uint frame_index;
+} pc;
+
+layout (location = 0) in vec2 o_uv;
+layout (location = 0) out vec4 uFragColor;
+
+vec3 srgb_to_linear(vec3 color) {
+    return mix(color / 12.92, pow((color + 0.055) / 1.055, vec3(2.4)), greaterThan(color, vec3(0.04045)));
+}
+
+vec3 linear_to_srgb(vec3 color) {
+    return mix(color * 12.92, 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, greaterThan(color, vec3(0.0031308)));
+}
+
+// https://www.shadertoy.com/view/4djSRW
+float hash(vec3 p) {
+    p = fract(p * 0.1031);
+    p += dot(p, p.zyx + 31.32);
+    return fract((p.x + p.y) * p.z);
+}
+
+void main() {
+    vec4 color = texture(sampler2D(input_texture, sampler_llc), o_uv);
+    if (pc.input_srgb != 0) {
+        color.rgb = srgb_to_linear(color.rgb);
+    }
+
+    if (pc.output_encoding == 2) {
+        uFragColor = color;
+        return;
+    }
+
+    vec3 encoded = linear_to_srgb(clamp(color.rgb, 0.0, 1.0));
+    if (pc.dither_steps > 0.0) {
+        // triangular noise of up to a step in either direction, dithered in the encoded space
+        // where the quantization happens
+        vec3 seed = vec3(gl_FragCoord.xy, float(pc.frame_index % 64));
+        float noise = hash(seed) + hash(seed + 17.0) - 1.0;
+        encoded = clamp(encoded + noise / pc.dither_steps, 0.0, 1.0);
+    }
+
+    uFragColor = vec4(pc.output_encoding == 1 ? encoded : srgb_to_linear(encoded), color.a);
+}
@@ -0,0 +1,15 @@
+#version 450
+
+layout (location = 0) in vec3 a_position;
+layout (location = 1) in vec4 a_color;
+
+layout (location = 0) out vec4 o_color;
+
+layout(push_constant) uniform PushConstants {
+    mat4 view_proj;
+} pc;
+
+void main() {
+    o_color = a_color;
+    gl_Position = pc.view_proj * vec4(a_position, 1.0);
+}
@@ -0,0 +1,36 @@
+#version 450
+#include <global.glsl>
+
+// Colors the base color texture by the mip level it's sampled at, blue for the full
+// resolution level up to red for the smaller ones. Untextured surfaces are gray.
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    Material material;
+    Camera camera;
+} pc;
+
+layout (location = 0) in vec4 o_color;
+layout (location = 1) in vec2 o_uv;
+layout (location = 0) out vec4 uFragColor;
+
+const vec3 LEVEL_COLORS[6] = vec3[](
+    vec3(0.0, 0.0, 1.0),
+    vec3(0.0, 0.8, 1.0),
+    vec3(0.0, 1.0, 0.0),
+    vec3(1.0, 1.0, 0.0),
+    vec3(1.0, 0.5, 0.0),
+    vec3(1.0, 0.0, 0.0)
+);
+
+void main() {
+    if (pc.material.base_color_texture_index == -1) {
+        uFragColor = vec4(0.3, 0.3, 0.3, 1.0);
+        return;
+    }
+
+    float level = textureQueryLod(sampler2D(u_textures[pc.material.base_color_texture_index], sampler_nlr), o_uv).x;
+    level = clamp(level, 0.0, 5.0);
+    int lower = int(floor(level));
+    int upper = min(lower + 1, 5);
+    uFragColor = vec4(mix(LEVEL_COLORS[lower], LEVEL_COLORS[upper], fract(level)), 1.0);
+}
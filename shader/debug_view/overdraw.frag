@@ -0,0 +1,9 @@
+#version 450
+
+// Blended additively by `DebugView::Overdraw`, a pixel goes from red to yellow to white
+// the more fragments cover it.
+layout (location = 0) out vec4 uFragColor;
+
+void main() {
+    uFragColor = vec4(1.0 / 4.0, 1.0 / 12.0, 1.0 / 32.0, 1.0);
+}
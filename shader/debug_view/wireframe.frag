@@ -0,0 +1,8 @@
+#version 450
+
+// Edges drawn over the shaded frame by `DebugView::Wireframe`.
+layout (location = 0) out vec4 uFragColor;
+
+void main() {
+    uFragColor = vec4(0.0, 1.0, 0.3, 1.0);
+}
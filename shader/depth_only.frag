@@ -0,0 +1,5 @@
+#version 450
+
+// Used by passes that only write depth, the pipeline still needs a fragment stage.
+void main() {
+}
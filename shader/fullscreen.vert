@@ -0,0 +1,9 @@
+#version 450
+
+// Single triangle covering the whole viewport, drawn without any vertex buffers.
+layout (location = 0) out vec2 o_uv;
+
+void main() {
+    o_uv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+    gl_Position = vec4(o_uv * 2.0 - 1.0, 0.0, 1.0);
+}
@@ -11,6 +11,19 @@ layout (buffer_reference) buffer Camera {
     mat4 proj;
     mat4 inverse_proj;
     vec3 world_position;
+    uint frame_index;
+    mat4 unjittered_view_proj;
+    mat4 previous_view_proj;
+    vec2 viewport_size;
+    // subpixel offset applied to `view_proj` and `proj`, in NDC
+    vec2 jitter;
+};
+
+// `FrameTimeUniforms` in src/render/frame_time.rs
+layout (buffer_reference) buffer FrameTime {
+    float time;
+    float delta_time;
+    uint frame_index;
 };
 
 layout (buffer_reference) buffer Material {
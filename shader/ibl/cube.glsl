@@ -0,0 +1,28 @@
+// Direction through texel `coord` of cubemap face `face`, in the +X, -X, +Y, -Y, +Z, -Z order
+// of the array layers.
+vec3 cube_direction(ivec2 coord, int face, ivec2 size) {
+    vec2 uv = (vec2(coord) + 0.5) / vec2(size) * 2.0 - 1.0;
+    vec3 direction;
+    switch (face) {
+        case 0: direction = vec3(1.0, -uv.y, -uv.x); break;
+        case 1: direction = vec3(-1.0, -uv.y, uv.x); break;
+        case 2: direction = vec3(uv.x, 1.0, uv.y); break;
+        case 3: direction = vec3(uv.x, -1.0, -uv.y); break;
+        case 4: direction = vec3(uv.x, -uv.y, 1.0); break;
+        default: direction = vec3(-uv.x, -uv.y, -1.0); break;
+    }
+    return normalize(direction);
+}
+
+// Orthonormal basis around `n`, for turning tangent space samples into world space.
+mat3 tangent_frame(vec3 n) {
+    vec3 up = abs(n.y) < 0.999 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(up, n));
+    return mat3(tangent, cross(n, tangent), n);
+}
+
+vec2 hammersley(uint i, uint count) {
+    return vec2(float(i) / float(count), float(bitfieldReverse(i)) * 2.3283064365386963e-10);
+}
+
+const float PI = 3.14159265359;
@@ -0,0 +1,12 @@
+#version 450
+
+layout (binding = 0) uniform texture2D draw_texture;
+layout (binding = 1) uniform sampler sampler_llc;
+
+layout (location = 0) in vec2 o_uv;
+layout (location = 1) in vec4 o_color;
+layout (location = 0) out vec4 uFragColor;
+
+void main() {
+    uFragColor = o_color * texture(sampler2D(draw_texture, sampler_llc), o_uv);
+}
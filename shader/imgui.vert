@@ -0,0 +1,29 @@
+#version 450
+
+layout (location = 0) in vec2 a_pos;
+layout (location = 1) in vec2 a_uv;
+layout (location = 2) in vec4 a_color;
+
+layout (location = 0) out vec2 o_uv;
+layout (location = 1) out vec4 o_color;
+
+layout(push_constant) uniform PushConstants {
+    // maps imgui display coordinates to clip space
+    vec2 scale;
+    vec2 translate;
+    uint srgb_target;
+} pc;
+
+// imgui colors are authored in sRGB, the hardware encodes them again when writing an sRGB target
+vec3 linear_from_srgb(vec3 srgb) {
+    vec3 cutoff = step(vec3(0.04045), srgb);
+    vec3 lower = srgb / vec3(12.92);
+    vec3 higher = pow((srgb + vec3(0.055)) / vec3(1.055), vec3(2.4));
+    return mix(lower, higher, cutoff);
+}
+
+void main() {
+    o_uv = a_uv;
+    o_color = pc.srgb_target != 0 ? vec4(linear_from_srgb(a_color.rgb), a_color.a) : a_color;
+    gl_Position = vec4(a_pos * pc.scale + pc.translate, 0.0, 1.0);
+}
@@ -0,0 +1,52 @@
+#extension GL_EXT_buffer_reference2 : enable
+
+struct Meshlet {
+    uint vertex_offset;
+    uint triangle_offset;
+    uint vertex_count;
+    uint triangle_count;
+    vec4 bounds;
+};
+
+// matches `mesh::Vertex`, which is padded to 64 bytes
+struct MeshVertex {
+    float position[3];
+    float normal[3];
+    float uv[2];
+    float tangent[3];
+    float color[4];
+    float padding;
+};
+
+layout (buffer_reference, std430) readonly buffer Meshlets {
+    Meshlet meshlets[];
+};
+
+layout (buffer_reference, std430) readonly buffer MeshletVertices {
+    uint vertices[];
+};
+
+layout (buffer_reference, std430) readonly buffer MeshletTriangles {
+    uint triangles[];
+};
+
+layout (buffer_reference, std430) readonly buffer Vertices {
+    MeshVertex vertices[];
+};
+
+layout(push_constant) uniform PushConstants {
+    mat4 model_view_proj;
+    Meshlets meshlets;
+    MeshletVertices meshlet_vertices;
+    MeshletTriangles meshlet_triangles;
+    Vertices vertices;
+    uint meshlet_count;
+    // largest scale of the model matrix, applied to the meshlet bounds
+    float scale;
+} pc;
+
+#define TASK_GROUP_SIZE 32
+
+struct TaskPayload {
+    uint meshlet_indices[TASK_GROUP_SIZE];
+};
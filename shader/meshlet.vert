@@ -0,0 +1,16 @@
+#version 460
+#include <meshlet.glsl>
+
+// Fallback for devices without mesh shaders, draws the meshlets as an indexed triangle list.
+// The indices point into the vertex buffer of the mesh, which is read like the mesh shader does.
+layout (location = 0) out vec4 o_color;
+layout (location = 1) out vec2 o_uv;
+
+void main() {
+    MeshVertex vertex = pc.vertices.vertices[gl_VertexIndex];
+
+    vec3 position = vec3(vertex.position[0], vertex.position[1], vertex.position[2]);
+    gl_Position = pc.model_view_proj * vec4(position, 1.0);
+    o_color = vec4(vertex.color[0], vertex.color[1], vertex.color[2], vertex.color[3]);
+    o_uv = vec2(vertex.uv[0], vertex.uv[1]);
+}
@@ -0,0 +1,11 @@
+#version 450
+
+layout (location = 0) in vec4 i_color;
+layout (location = 1) in vec2 i_uv;
+
+layout (location = 0) out vec4 o_color;
+
+void main() {
+    float falloff = 1.0 - smoothstep(0.5, 1.0, length(i_uv));
+    o_color = vec4(i_color.rgb, i_color.a * falloff);
+}
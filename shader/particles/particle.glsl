@@ -0,0 +1,31 @@
+// Buffers shared by the particle kernels, see `ParticleSystem`.
+#extension GL_EXT_buffer_reference2 : enable
+
+struct Particle {
+    vec3 position;
+    float age;
+    vec3 velocity;
+    float lifetime;
+    vec4 color;
+    float size;
+};
+
+layout (buffer_reference, std430) buffer Particles {
+    Particle values[];
+};
+
+layout (buffer_reference, std430) buffer Uints {
+    uint values[];
+};
+
+layout (buffer_reference, std430) buffer ParticleState {
+    // particles in each of the two alive lists, the lists swap roles every frame
+    uint alive_count[2];
+    int dead_count;
+    // slots past this were never used, they're handed out once the dead list is empty
+    uint high_water;
+    // VkDispatchIndirectCommand over the alive particles
+    uvec4 update_dispatch;
+    // VkDrawIndirectCommand of a quad per alive particle
+    uvec4 draw;
+};
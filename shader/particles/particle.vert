@@ -0,0 +1,33 @@
+#version 450
+#include "particle.glsl"
+
+// Expands every alive particle into a camera facing quad, reading the particles through the
+// (sorted) alive list instead of vertex buffers.
+layout (location = 0) out vec4 o_color;
+layout (location = 1) out vec2 o_uv;
+
+layout(push_constant) uniform PushConstants {
+    mat4 view_proj;
+    vec4 camera_right;
+    vec4 camera_up;
+    Particles particles;
+    Uints alive;
+} pc;
+
+const vec2 CORNERS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)
+);
+
+void main() {
+    Particle particle = pc.particles.values[pc.alive.values[gl_InstanceIndex]];
+    vec2 corner = CORNERS[gl_VertexIndex];
+    vec3 position = particle.position
+        + (pc.camera_right.xyz * corner.x + pc.camera_up.xyz * corner.y) * particle.size;
+
+    // fade out over the last fifth of the lifetime
+    float fade = clamp((particle.lifetime - particle.age) / (particle.lifetime * 0.2), 0.0, 1.0);
+    o_color = vec4(particle.color.rgb, particle.color.a * fade);
+    o_uv = corner;
+    gl_Position = pc.view_proj * vec4(position, 1.0);
+}
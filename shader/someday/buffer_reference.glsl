@@ -0,0 +1,39 @@
+// Helpers for reading buffers through their device address, every buffer has one. Shipped with
+// the crate, include it with `#include <someday/buffer_reference.glsl>`.
+#ifndef SOMEDAY_BUFFER_REFERENCE_GLSL
+#define SOMEDAY_BUFFER_REFERENCE_GLSL
+
+#extension GL_EXT_buffer_reference2 : require
+#extension GL_EXT_buffer_reference_uvec2 : require
+
+// Declares NAME as a reference to an array of TYPE, index it with `ref.values[i]`. Structs
+// generated with `glsl_struct!` come with a `<Struct>Ref` already.
+#define BUFFER_REFERENCE(NAME, TYPE) \
+    layout (buffer_reference, std430) buffer NAME { TYPE values[]; }
+#define READONLY_BUFFER_REFERENCE(NAME, TYPE) \
+    layout (buffer_reference, std430) readonly buffer NAME { TYPE values[]; }
+
+// Same without any padding, `vec3`s take 12 bytes and arrays are tightly packed. Only defined
+// when the device was created with `scalar_block_layout`.
+#ifdef SCALAR_BLOCK_LAYOUT
+#extension GL_EXT_scalar_block_layout : require
+#define SCALAR_BUFFER_REFERENCE(NAME, TYPE) \
+    layout (buffer_reference, scalar) buffer NAME { TYPE values[]; }
+#define READONLY_SCALAR_BUFFER_REFERENCE(NAME, TYPE) \
+    layout (buffer_reference, scalar) readonly buffer NAME { TYPE values[]; }
+#endif
+
+// Adds `bytes` to an address, with 32-bit math since uint64_t needs shaderInt64.
+uvec2 address_add(uvec2 address, uint bytes) {
+    uint carry;
+    uint low = uaddCarry(address.x, bytes, carry);
+    return uvec2(low, address.y + carry);
+}
+
+// A reference of type NAME, `bytes` past REF.
+#define REFERENCE_OFFSET(NAME, REF, bytes) NAME(address_add(uvec2(REF), bytes))
+
+// References passed as 0 from the CPU, for optional buffers.
+#define IS_NULL_REFERENCE(REF) (uvec2(REF) == uvec2(0))
+
+#endif
@@ -0,0 +1,13 @@
+#version 450
+
+layout (binding = 0) uniform texture2D glyph_atlas;
+layout (binding = 1) uniform sampler sampler_llc;
+
+layout (location = 0) in vec2 o_uv;
+layout (location = 1) in vec4 o_color;
+layout (location = 0) out vec4 uFragColor;
+
+void main() {
+    float coverage = texture(sampler2D(glyph_atlas, sampler_llc), o_uv).r;
+    uFragColor = vec4(o_color.rgb, o_color.a * coverage);
+}
@@ -0,0 +1,21 @@
+#version 450
+
+// one instance per glyph, expanded into a quad from the vertex index
+layout (location = 0) in vec4 a_rect;
+layout (location = 1) in vec4 a_uv_rect;
+layout (location = 2) in vec4 a_color;
+
+layout (location = 0) out vec2 o_uv;
+layout (location = 1) out vec4 o_color;
+
+layout(push_constant) uniform PushConstants {
+    // maps pixels to clip space
+    vec2 scale;
+} pc;
+
+void main() {
+    vec2 corner = vec2(gl_VertexIndex & 1, gl_VertexIndex >> 1);
+    o_uv = mix(a_uv_rect.xy, a_uv_rect.zw, corner);
+    o_color = a_color;
+    gl_Position = vec4((a_rect.xy + a_rect.zw * corner) * pc.scale - 1.0, 0.0, 1.0);
+}
@@ -0,0 +1,160 @@
+//! Derive macros for the renderer, enabled with its `derive` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use proc_macro_crate::{crate_name, FoundCrate};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
+
+/// Implements `ShaderBind` and `GlslLayout` for a `repr(C)` struct, and checks at compile time
+/// that every field is where GLSL puts it. Blocks are std140 `uniform` blocks, or std430
+/// `buffer` blocks with `#[shader_bind(storage)]`.
+///
+/// The renderer is found through the `Cargo.toml` of the crate using the derive; crates that
+/// re-export it point at it with `#[shader_bind(crate = "path::to::someday")]`.
+///
+/// ```ignore
+/// #[repr(C)]
+/// #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable, ShaderBind)]
+/// struct Globals {
+///     view_proj: Mat4,
+///     sun_direction: Vec3,
+///     time: f32,
+/// }
+/// ```
+#[proc_macro_derive(ShaderBind, attributes(shader_bind))]
+pub fn derive_shader_bind(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_shader_bind(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand_shader_bind(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let ident = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "ShaderBind can't be derived for generic structs",
+        ));
+    }
+
+    let mut storage = false;
+    let mut krate = None;
+    let mut repr_c = false;
+    for attr in input.attrs.iter() {
+        if attr.path().is_ident("shader_bind") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("storage") {
+                    storage = true;
+                    Ok(())
+                } else if meta.path.is_ident("crate") {
+                    let path: syn::LitStr = meta.value()?.parse()?;
+                    krate = Some(path.parse::<syn::Path>()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("expected `storage` or `crate`"))
+                }
+            })?;
+        } else if attr.path().is_ident("repr") {
+            attr.parse_nested_meta(|meta| {
+                repr_c |= meta.path.is_ident("C");
+                // skip the arguments of `align(N)` and friends
+                if meta.input.peek(syn::token::Paren) {
+                    let arguments;
+                    syn::parenthesized!(arguments in meta.input);
+                    arguments.parse::<TokenStream2>()?;
+                }
+                Ok(())
+            })?;
+        }
+    }
+    if !repr_c {
+        return Err(syn::Error::new_spanned(
+            ident,
+            "ShaderBind structs need #[repr(C)], otherwise Rust can reorder the fields",
+        ));
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    "ShaderBind needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "ShaderBind can only be derived for structs",
+            ))
+        }
+    };
+
+    let krate = match krate {
+        Some(path) => quote!(#path),
+        None => renderer_path()?,
+    };
+    let shader_bind = quote!(#krate::render::shader_bind);
+    let glsl = quote!(#krate::render::glsl_struct);
+    let (kind, layout, layout_const) = if storage {
+        (quote!(Storage), "std430", quote!(STD430))
+    } else {
+        (quote!(Uniform), "std140", quote!(STD140))
+    };
+
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let checks = fields.iter().map(|field| {
+        let name = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let message = format!("`{}::{}` isn't where {} puts it", ident, name, layout);
+        quote! {
+            (
+                ::core::mem::offset_of!(#ident, #name),
+                ::core::mem::size_of::<#ty>(),
+                <#ty as #glsl::GlslLayout>::#layout_const.0,
+                <#ty as #glsl::GlslLayout>::#layout_const.1,
+                #message,
+            )
+        }
+    });
+
+    Ok(quote! {
+        impl #shader_bind::ShaderBind for #ident {
+            const KIND: #shader_bind::BlockKind = #shader_bind::BlockKind::#kind;
+        }
+
+        impl #glsl::GlslLayout for #ident {
+            const STD140: (usize, usize) = #glsl::BlockLayout::Std140
+                .struct_layout(&[#(<#types as #glsl::GlslLayout>::STD140),*]);
+            const STD430: (usize, usize) = #glsl::BlockLayout::Std430
+                .struct_layout(&[#(<#types as #glsl::GlslLayout>::STD430),*]);
+            const SCALAR: (usize, usize) = #glsl::BlockLayout::Scalar
+                .struct_layout(&[#(<#types as #glsl::GlslLayout>::SCALAR),*]);
+        }
+
+        const _: () = #glsl::check_fields(&[#(#checks),*]);
+    })
+}
+
+/// Path to the renderer as the crate using the derive calls it, `crate` within the renderer
+/// itself.
+fn renderer_path() -> syn::Result<TokenStream2> {
+    match crate_name("someday") {
+        Ok(FoundCrate::Itself) => Ok(quote!(crate)),
+        Ok(FoundCrate::Name(name)) => {
+            let ident = syn::Ident::new(&name, Span::call_site());
+            Ok(quote!(::#ident))
+        }
+        Err(e) => Err(syn::Error::new(
+            Span::call_site(),
+            format!(
+                "can't find the someday crate, point at it with #[shader_bind(crate = \"...\")]: {}",
+                e
+            ),
+        )),
+    }
+}
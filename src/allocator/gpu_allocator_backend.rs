@@ -0,0 +1,97 @@
+use ash::vk;
+use gpu_allocator::vulkan;
+
+use super::{
+    Allocation, AllocationCreateDesc, AllocationError, AllocationScheme, AllocatorBackend,
+    MemoryLocation,
+};
+
+pub struct GpuAllocatorBackend {
+    allocator: vulkan::Allocator,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+}
+
+impl From<gpu_allocator::AllocationError> for AllocationError {
+    fn from(error: gpu_allocator::AllocationError) -> Self {
+        match error {
+            gpu_allocator::AllocationError::OutOfMemory => AllocationError::OutOfMemory,
+            error => AllocationError::Backend(error.to_string()),
+        }
+    }
+}
+
+impl GpuAllocatorBackend {
+    pub fn new(
+        instance: &ash::Instance,
+        device: &ash::Device,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<Self, AllocationError> {
+        let allocator = vulkan::Allocator::new(&vulkan::AllocatorCreateDesc {
+            instance: instance.clone(),
+            device: device.clone(),
+            physical_device,
+            debug_settings: Default::default(),
+            buffer_device_address: true, // Ideally, check the BufferDeviceAddressFeatures struct.
+            allocation_sizes: Default::default(),
+        })?;
+        let memory_properties =
+            unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        Ok(Self {
+            allocator,
+            memory_properties,
+        })
+    }
+}
+
+impl AllocatorBackend for GpuAllocatorBackend {
+    fn allocate(&mut self, desc: &AllocationCreateDesc) -> Result<Allocation, AllocationError> {
+        // gpu-allocator picks the memory type from the location, the hints narrow down the
+        // types it can pick from
+        let mut requirements = desc.requirements;
+        requirements.memory_type_bits = desc
+            .hints
+            .filter_memory_types(&self.memory_properties, requirements.memory_type_bits);
+        if requirements.memory_type_bits == 0 {
+            return Err(AllocationError::NoMemoryType(desc.hints));
+        }
+
+        let allocation = self.allocator.allocate(&vulkan::AllocationCreateDesc {
+            name: desc.name,
+            requirements,
+            location: match desc.location {
+                MemoryLocation::Unknown => gpu_allocator::MemoryLocation::Unknown,
+                MemoryLocation::GpuOnly => gpu_allocator::MemoryLocation::GpuOnly,
+                MemoryLocation::CpuToGpu => gpu_allocator::MemoryLocation::CpuToGpu,
+                MemoryLocation::GpuToCpu => gpu_allocator::MemoryLocation::GpuToCpu,
+            },
+            linear: desc.linear,
+            allocation_scheme: match desc.allocation_scheme {
+                AllocationScheme::DedicatedBuffer(buffer) => {
+                    vulkan::AllocationScheme::DedicatedBuffer(buffer)
+                }
+                AllocationScheme::DedicatedImage(image) => {
+                    vulkan::AllocationScheme::DedicatedImage(image)
+                }
+                AllocationScheme::Managed => vulkan::AllocationScheme::GpuAllocatorManaged,
+            },
+        })?;
+        Ok(Allocation::new(
+            unsafe { allocation.memory() },
+            allocation.offset(),
+            allocation.size(),
+            allocation.mapped_ptr(),
+            allocation,
+        ))
+    }
+
+    fn free(&mut self, allocation: Allocation) -> Result<(), AllocationError> {
+        self.allocator
+            .free(allocation.into_backend::<vulkan::Allocation>())?;
+        Ok(())
+    }
+
+    fn report(&self) -> Option<String> {
+        // the allocation breakdown, with the memory reserved in blocks
+        Some(format!("{:?}", self.allocator))
+    }
+}
@@ -0,0 +1,326 @@
+//! Device memory allocation behind [`AllocatorBackend`], so the rest of the renderer doesn't
+//! depend on a specific allocator. gpu-allocator is used by default, enabling the `vma` feature
+//! switches to the Vulkan Memory Allocator.
+
+#[cfg(feature = "gpu-allocator")]
+mod gpu_allocator_backend;
+mod report;
+#[cfg(feature = "vma")]
+mod vma_backend;
+
+#[cfg(not(any(feature = "gpu-allocator", feature = "vma")))]
+compile_error!("Enable either the `gpu-allocator` or the `vma` feature");
+
+use std::{any::Any, collections::HashMap, ffi::c_void, fmt, ptr::NonNull};
+
+use ash::vk;
+use thiserror::Error;
+
+#[cfg(feature = "gpu-allocator")]
+pub use gpu_allocator_backend::GpuAllocatorBackend;
+pub use report::{AllocationReport, AllocationTotals, AllocatorReport, MemoryBlockReport};
+#[cfg(feature = "vma")]
+pub use vma_backend::VmaBackend;
+
+/// Where the memory of an allocation should live, which decides if it's mapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemoryLocation {
+    /// Any memory type, only used for memory the renderer doesn't allocate itself.
+    Unknown,
+    /// Device local, not mapped.
+    GpuOnly,
+    /// Mapped memory the CPU writes and the GPU reads, like uniforms and staging buffers.
+    CpuToGpu,
+    /// Mapped memory the GPU writes and the CPU reads back.
+    GpuToCpu,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum AllocationScheme {
+    /// Memory of its own, bound to the buffer.
+    DedicatedBuffer(vk::Buffer),
+    /// Memory of its own, bound to the image.
+    DedicatedImage(vk::Image),
+    /// Placed in a shared memory block by the backend.
+    Managed,
+}
+
+/// Steers which memory type an allocation ends up in, for tuning memory on integrated GPUs
+/// where the default type of a [`MemoryLocation`] isn't the best fit. Preferences fall back to
+/// the other allowed types when the device has none of the preferred ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MemoryHints {
+    /// `DEVICE_LOCAL | HOST_VISIBLE` memory, so mapped data doesn't go through system memory.
+    pub prefer_device_local_host_visible: bool,
+    /// `LAZILY_ALLOCATED` memory, which tile based GPUs may never back for transient
+    /// attachments that only live within a render pass. The image needs
+    /// `TRANSIENT_ATTACHMENT` usage.
+    pub prefer_lazily_allocated: bool,
+    /// A bit per memory heap index that must not be used.
+    pub forbidden_heaps: u32,
+    /// Index of the only memory type to use, ignoring the other hints.
+    pub memory_type_override: Option<u32>,
+}
+
+impl MemoryHints {
+    /// The types of `memory_type_bits` that the hints allow, narrowed down to the preferred
+    /// ones when there are any. 0 when nothing is left.
+    pub fn filter_memory_types(
+        &self,
+        properties: &vk::PhysicalDeviceMemoryProperties,
+        memory_type_bits: u32,
+    ) -> u32 {
+        if let Some(index) = self.memory_type_override {
+            return memory_type_bits & 1u32.checked_shl(index).unwrap_or(0);
+        }
+
+        let types = &properties.memory_types[..properties.memory_type_count as usize];
+        let matching = |matches: &dyn Fn(&vk::MemoryType) -> bool| {
+            types
+                .iter()
+                .enumerate()
+                .filter(|(_, memory_type)| matches(memory_type))
+                .fold(0, |bits, (index, _)| bits | 1 << index)
+        };
+
+        let mut bits = memory_type_bits
+            & matching(&|memory_type| self.forbidden_heaps & (1 << memory_type.heap_index) == 0);
+        for (preferred, flags) in [
+            (
+                self.prefer_device_local_host_visible,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_VISIBLE,
+            ),
+            (
+                self.prefer_lazily_allocated,
+                vk::MemoryPropertyFlags::LAZILY_ALLOCATED,
+            ),
+        ] {
+            let preferred_bits =
+                bits & matching(&|memory_type| memory_type.property_flags.contains(flags));
+            if preferred && preferred_bits != 0 {
+                bits = preferred_bits;
+            }
+        }
+        bits
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AllocationCreateDesc<'a> {
+    /// Shows up in the reports of the backend.
+    pub name: &'a str,
+    pub requirements: vk::MemoryRequirements,
+    pub location: MemoryLocation,
+    /// Buffers and linear images, which some backends keep apart from optimal images.
+    pub linear: bool,
+    pub allocation_scheme: AllocationScheme,
+    pub hints: MemoryHints,
+}
+
+#[derive(Debug, Error)]
+pub enum AllocationError {
+    #[error("Out of device memory")]
+    OutOfMemory,
+    #[error("No memory type is left after applying {0:?}")]
+    NoMemoryType(MemoryHints),
+    #[error("{0}")]
+    Backend(String),
+}
+
+/// A block of device memory handed out by an [`AllocatorBackend`]. Has to be given back to the
+/// allocator it came from with [`Allocator::free`].
+pub struct Allocation {
+    memory: vk::DeviceMemory,
+    offset: u64,
+    size: u64,
+    mapped_ptr: Option<NonNull<c_void>>,
+    /// Whatever the backend needs to free the allocation.
+    backend: Box<dyn Any + Send + Sync>,
+}
+
+// the mapped pointer is only written through `&mut` borrows of the buffer owning the allocation
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl fmt::Debug for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Allocation")
+            .field("memory", &self.memory)
+            .field("offset", &self.offset)
+            .field("size", &self.size)
+            .field("mapped_ptr", &self.mapped_ptr)
+            .finish()
+    }
+}
+
+impl Allocation {
+    /// For backends, `backend` is returned by [`Allocation::into_backend`] when it's freed.
+    pub fn new(
+        memory: vk::DeviceMemory,
+        offset: u64,
+        size: u64,
+        mapped_ptr: Option<NonNull<c_void>>,
+        backend: impl Any + Send + Sync,
+    ) -> Self {
+        Self {
+            memory,
+            offset,
+            size,
+            mapped_ptr,
+            backend: Box::new(backend),
+        }
+    }
+
+    /// Shared with other allocations, so bind at [`Allocation::offset`].
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Pointer to the start of the allocation, `None` when it isn't host visible.
+    pub fn mapped_ptr(&self) -> Option<NonNull<c_void>> {
+        self.mapped_ptr
+    }
+
+    /// Panics when the allocation was made by another backend.
+    pub fn into_backend<T: 'static>(self) -> T {
+        *self
+            .backend
+            .downcast()
+            .expect("Allocation was freed by a different allocator backend")
+    }
+}
+
+pub trait AllocatorBackend: Send {
+    fn allocate(&mut self, desc: &AllocationCreateDesc) -> Result<Allocation, AllocationError>;
+
+    fn free(&mut self, allocation: Allocation) -> Result<(), AllocationError>;
+
+    /// The backend's own breakdown of its memory, added to the [`AllocatorReport`].
+    fn report(&self) -> Option<String> {
+        None
+    }
+}
+
+pub struct Allocator {
+    backend: Box<dyn AllocatorBackend>,
+    /// Live allocations by memory and offset, for [`Allocator::report`].
+    allocations: HashMap<(vk::DeviceMemory, u64), AllocationReport>,
+}
+
+impl Allocator {
+    pub fn new(backend: impl AllocatorBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+            allocations: HashMap::new(),
+        }
+    }
+
+    /// Creates the backend selected with cargo features, VMA when both are enabled.
+    pub fn from_features(
+        instance: &ash::Instance,
+        device: &ash::Device,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<Self, AllocationError> {
+        #[cfg(feature = "vma")]
+        return Ok(Self::new(VmaBackend::new(
+            instance,
+            device,
+            physical_device,
+        )?));
+
+        #[cfg(not(feature = "vma"))]
+        return Ok(Self::new(GpuAllocatorBackend::new(
+            instance,
+            device,
+            physical_device,
+        )?));
+    }
+
+    pub fn allocate(&mut self, desc: &AllocationCreateDesc) -> Result<Allocation, AllocationError> {
+        let allocation = self.backend.allocate(desc)?;
+        self.allocations.insert(
+            (allocation.memory, allocation.offset),
+            AllocationReport {
+                name: desc.name.to_string(),
+                location: desc.location,
+                memory: allocation.memory,
+                offset: allocation.offset,
+                size: allocation.size,
+                dedicated: !matches!(desc.allocation_scheme, AllocationScheme::Managed),
+            },
+        );
+        Ok(allocation)
+    }
+
+    pub fn free(&mut self, allocation: Allocation) -> Result<(), AllocationError> {
+        self.allocations
+            .remove(&(allocation.memory, allocation.offset));
+        self.backend.free(allocation)
+    }
+
+    /// The live allocations grouped by memory block, `heaps` are listed in the summary.
+    pub fn report(&self, heaps: &[vk::MemoryHeap]) -> AllocatorReport {
+        AllocatorReport::new(
+            self.allocations.values().cloned(),
+            heaps.to_vec(),
+            self.backend.report(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_memory_types() {
+        let mut properties = vk::PhysicalDeviceMemoryProperties {
+            memory_type_count: 3,
+            memory_heap_count: 2,
+            ..Default::default()
+        };
+        properties.memory_types[0] = vk::MemoryType {
+            property_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            heap_index: 0,
+        };
+        properties.memory_types[1] = vk::MemoryType {
+            property_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL
+                | vk::MemoryPropertyFlags::HOST_VISIBLE,
+            heap_index: 0,
+        };
+        properties.memory_types[2] = vk::MemoryType {
+            property_flags: vk::MemoryPropertyFlags::HOST_VISIBLE,
+            heap_index: 1,
+        };
+
+        let hints = MemoryHints::default();
+        assert_eq!(hints.filter_memory_types(&properties, 0b111), 0b111);
+        let hints = MemoryHints {
+            prefer_device_local_host_visible: true,
+            ..Default::default()
+        };
+        assert_eq!(hints.filter_memory_types(&properties, 0b111), 0b010);
+        // falls back when the preferred type isn't allowed by the resource
+        assert_eq!(hints.filter_memory_types(&properties, 0b101), 0b101);
+        let hints = MemoryHints {
+            prefer_lazily_allocated: true,
+            forbidden_heaps: 0b10,
+            ..Default::default()
+        };
+        assert_eq!(hints.filter_memory_types(&properties, 0b111), 0b011);
+        assert_eq!(hints.filter_memory_types(&properties, 0b100), 0);
+        let hints = MemoryHints {
+            memory_type_override: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(hints.filter_memory_types(&properties, 0b111), 0b100);
+    }
+}
@@ -0,0 +1,357 @@
+use std::{collections::BTreeMap, fmt, fmt::Write as _, path::Path};
+
+use ash::vk::{self, Handle};
+
+use crate::error::Result;
+
+use super::MemoryLocation;
+
+/// A live allocation as the [`Allocator`](super::Allocator) handed it out.
+#[derive(Clone, Debug)]
+pub struct AllocationReport {
+    pub name: String,
+    pub location: MemoryLocation,
+    pub memory: vk::DeviceMemory,
+    pub offset: u64,
+    pub size: u64,
+    /// Asked for memory of its own with a dedicated allocation scheme.
+    pub dedicated: bool,
+}
+
+/// The allocations sharing one `vk::DeviceMemory`.
+#[derive(Clone, Debug)]
+pub struct MemoryBlockReport {
+    pub memory: vk::DeviceMemory,
+    pub location: MemoryLocation,
+    /// Sorted by offset.
+    pub allocations: Vec<AllocationReport>,
+}
+
+impl MemoryBlockReport {
+    pub fn used(&self) -> u64 {
+        self.allocations
+            .iter()
+            .map(|allocation| allocation.size)
+            .sum()
+    }
+
+    /// End of the last allocation, a lower bound of the block size which the backends don't
+    /// expose.
+    pub fn extent(&self) -> u64 {
+        self.allocations
+            .iter()
+            .map(|allocation| allocation.offset + allocation.size)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Count and bytes of a group of allocations.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocationTotals {
+    pub count: usize,
+    pub bytes: u64,
+}
+
+impl AllocationTotals {
+    fn add(&mut self, size: u64) {
+        self.count += 1;
+        self.bytes += size;
+    }
+}
+
+/// Every live allocation grouped by the memory block it's in, with totals per
+/// [`MemoryLocation`], for memory audits. Created with
+/// [`ExampleBase::allocator_report`](crate::ctx::ExampleBase::allocator_report).
+#[derive(Clone, Debug)]
+pub struct AllocatorReport {
+    pub blocks: Vec<MemoryBlockReport>,
+    pub heaps: Vec<vk::MemoryHeap>,
+    /// The breakdown of the backend itself, which includes the memory it reserved.
+    pub backend: Option<String>,
+}
+
+impl AllocatorReport {
+    pub(super) fn new(
+        allocations: impl IntoIterator<Item = AllocationReport>,
+        heaps: Vec<vk::MemoryHeap>,
+        backend: Option<String>,
+    ) -> Self {
+        let mut blocks = BTreeMap::new();
+        for allocation in allocations {
+            blocks
+                .entry(allocation.memory.as_raw())
+                .or_insert_with(|| MemoryBlockReport {
+                    memory: allocation.memory,
+                    location: allocation.location,
+                    allocations: vec![],
+                })
+                .allocations
+                .push(allocation);
+        }
+        let mut blocks = blocks.into_values().collect::<Vec<_>>();
+        for block in blocks.iter_mut() {
+            block
+                .allocations
+                .sort_by_key(|allocation| allocation.offset);
+        }
+        blocks.sort_by_key(|block| std::cmp::Reverse(block.used()));
+        Self {
+            blocks,
+            heaps,
+            backend,
+        }
+    }
+
+    pub fn allocations(&self) -> impl Iterator<Item = &AllocationReport> {
+        self.blocks
+            .iter()
+            .flat_map(|block| block.allocations.iter())
+    }
+
+    pub fn total(&self) -> AllocationTotals {
+        let mut totals = AllocationTotals::default();
+        for allocation in self.allocations() {
+            totals.add(allocation.size);
+        }
+        totals
+    }
+
+    pub fn dedicated(&self) -> AllocationTotals {
+        let mut totals = AllocationTotals::default();
+        for allocation in self.allocations().filter(|allocation| allocation.dedicated) {
+            totals.add(allocation.size);
+        }
+        totals
+    }
+
+    pub fn by_location(&self) -> Vec<(MemoryLocation, AllocationTotals)> {
+        let mut totals: Vec<(MemoryLocation, AllocationTotals)> = vec![];
+        for allocation in self.allocations() {
+            match totals
+                .iter_mut()
+                .find(|(location, _)| *location == allocation.location)
+            {
+                Some((_, totals)) => totals.add(allocation.size),
+                None => {
+                    let mut location_totals = AllocationTotals::default();
+                    location_totals.add(allocation.size);
+                    totals.push((allocation.location, location_totals));
+                }
+            }
+        }
+        totals.sort_by_key(|(_, totals)| std::cmp::Reverse(totals.bytes));
+        totals
+    }
+
+    /// One row per allocation, for spreadsheets.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("name,location,memory,offset,size,dedicated\n");
+        for allocation in self.allocations() {
+            writeln!(
+                csv,
+                "{},{:?},{:#x},{},{},{}",
+                csv_field(&allocation.name),
+                allocation.location,
+                allocation.memory.as_raw(),
+                allocation.offset,
+                allocation.size,
+                allocation.dedicated
+            )
+            .unwrap();
+        }
+        csv
+    }
+
+    /// A standalone page with the summary and a bar per memory block, showing where its
+    /// allocations are and the gaps between them.
+    pub fn to_html(&self) -> String {
+        let mut html = String::from(concat!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n",
+            "<title>Allocator report</title>\n<style>\n",
+            "body { font-family: sans-serif; margin: 2em; }\n",
+            ".block { position: relative; height: 24px; background: #ddd; margin-bottom: 1em; }\n",
+            ".allocation { position: absolute; top: 0; height: 100%; ",
+            "box-sizing: border-box; border-right: 1px solid #fff; }\n",
+            ".GpuOnly { background: #4a7ebb; }\n.CpuToGpu { background: #5aa469; }\n",
+            ".GpuToCpu { background: #d9822b; }\n.Unknown { background: #888; }\n",
+            "td, th { padding: 0 1em 0 0; text-align: left; }\n",
+            "</style>\n</head>\n<body>\n<h1>Allocator report</h1>\n<pre>",
+        ));
+        html.push_str(&html_escape(&self.to_string()));
+        html.push_str("</pre>\n");
+
+        for block in &self.blocks {
+            let extent = block.extent().max(1) as f64;
+            writeln!(
+                html,
+                "<h3>{:#x} ({:?}, {} in {} allocations)</h3>\n<div class=\"block\">",
+                block.memory.as_raw(),
+                block.location,
+                format_bytes(block.used()),
+                block.allocations.len()
+            )
+            .unwrap();
+            for allocation in &block.allocations {
+                writeln!(
+                    html,
+                    "<div class=\"allocation {:?}\" style=\"left: {:.4}%; width: {:.4}%\" \
+                     title=\"{} ({} at {})\"></div>",
+                    allocation.location,
+                    allocation.offset as f64 / extent * 100.0,
+                    allocation.size as f64 / extent * 100.0,
+                    html_escape(&allocation.name),
+                    format_bytes(allocation.size),
+                    allocation.offset
+                )
+                .unwrap();
+            }
+            html.push_str("</div>\n<table>\n<tr><th>Name</th><th>Offset</th><th>Size</th></tr>\n");
+            for allocation in &block.allocations {
+                writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    html_escape(&allocation.name),
+                    allocation.offset,
+                    format_bytes(allocation.size)
+                )
+                .unwrap();
+            }
+            html.push_str("</table>\n");
+        }
+
+        if let Some(backend) = &self.backend {
+            html.push_str("<h2>Backend</h2>\n<pre>");
+            html.push_str(&html_escape(backend));
+            html.push_str("</pre>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    pub fn write_csv(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path.as_ref(), self.to_csv())?;
+        println!("Wrote allocator report to {}", path.as_ref().display());
+        Ok(())
+    }
+
+    pub fn write_html(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path.as_ref(), self.to_html())?;
+        println!("Wrote allocator report to {}", path.as_ref().display());
+        Ok(())
+    }
+}
+
+/// The summary, without the allocations.
+impl fmt::Display for AllocatorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total();
+        let dedicated = self.dedicated();
+        writeln!(
+            f,
+            "{} in {} allocations across {} memory blocks",
+            format_bytes(total.bytes),
+            total.count,
+            self.blocks.len()
+        )?;
+        writeln!(
+            f,
+            "  dedicated: {} in {} allocations",
+            format_bytes(dedicated.bytes),
+            dedicated.count
+        )?;
+        for (location, totals) in self.by_location() {
+            writeln!(
+                f,
+                "  {:?}: {} in {} allocations",
+                location,
+                format_bytes(totals.bytes),
+                totals.count
+            )?;
+        }
+        for (index, heap) in self.heaps.iter().enumerate() {
+            writeln!(
+                f,
+                "  heap {}: {} {:?}",
+                index,
+                format_bytes(heap.size),
+                heap.flags
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", size, UNITS[unit])
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_groups_blocks() {
+        let allocation = |name: &str, memory: u64, offset: u64, size: u64| AllocationReport {
+            name: name.to_string(),
+            location: MemoryLocation::GpuOnly,
+            memory: vk::DeviceMemory::from_raw(memory),
+            offset,
+            size,
+            dedicated: false,
+        };
+        let report = AllocatorReport::new(
+            [
+                allocation("b", 1, 256, 256),
+                allocation("a", 1, 0, 256),
+                allocation("vertices, \"large\"", 2, 0, 4096),
+            ],
+            vec![],
+            None,
+        );
+
+        assert_eq!(report.blocks.len(), 2);
+        assert_eq!(report.blocks[0].used(), 4096);
+        assert_eq!(report.blocks[1].allocations[0].name, "a");
+        assert_eq!(report.blocks[1].extent(), 512);
+        assert_eq!(
+            report.total(),
+            AllocationTotals {
+                count: 3,
+                bytes: 4608
+            }
+        );
+        assert_eq!(
+            report.to_csv().lines().nth(1).unwrap(),
+            "\"vertices, \"\"large\"\"\",GpuOnly,0x2,0,4096,false"
+        );
+        assert_eq!(format_bytes(1536), "1.50 KiB");
+    }
+}
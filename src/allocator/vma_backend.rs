@@ -0,0 +1,112 @@
+use std::ptr::NonNull;
+
+use ash::vk;
+
+use super::{
+    Allocation, AllocationCreateDesc, AllocationError, AllocationScheme, AllocatorBackend,
+    MemoryLocation,
+};
+
+/// The Vulkan Memory Allocator, for its defragmentation and memory budget tracking.
+pub struct VmaBackend {
+    allocator: vk_mem::Allocator,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+}
+
+fn vma_error(result: vk::Result) -> AllocationError {
+    match result {
+        vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => AllocationError::OutOfMemory,
+        result => AllocationError::Backend(result.to_string()),
+    }
+}
+
+impl VmaBackend {
+    pub fn new(
+        instance: &ash::Instance,
+        device: &ash::Device,
+        physical_device: vk::PhysicalDevice,
+    ) -> Result<Self, AllocationError> {
+        let mut create_info = vk_mem::AllocatorCreateInfo::new(instance, device, physical_device);
+        create_info.flags = vk_mem::AllocatorCreateFlags::BUFFER_DEVICE_ADDRESS;
+        let allocator = unsafe { vk_mem::Allocator::new(create_info) }.map_err(vma_error)?;
+        let memory_properties =
+            unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        Ok(Self {
+            allocator,
+            memory_properties,
+        })
+    }
+
+    pub fn vma(&self) -> &vk_mem::Allocator {
+        &self.allocator
+    }
+}
+
+impl AllocatorBackend for VmaBackend {
+    fn allocate(&mut self, desc: &AllocationCreateDesc) -> Result<Allocation, AllocationError> {
+        // the same memory types gpu-allocator picks for each location
+        let (required_flags, preferred_flags) = match desc.location {
+            MemoryLocation::Unknown => Default::default(),
+            MemoryLocation::GpuOnly => (
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                vk::MemoryPropertyFlags::empty(),
+            ),
+            MemoryLocation::CpuToGpu => (
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ),
+            MemoryLocation::GpuToCpu => (
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                vk::MemoryPropertyFlags::HOST_CACHED,
+            ),
+        };
+        let memory_type_bits = desc
+            .hints
+            .filter_memory_types(&self.memory_properties, desc.requirements.memory_type_bits);
+        if memory_type_bits == 0 {
+            return Err(AllocationError::NoMemoryType(desc.hints));
+        }
+        let mut flags = vk_mem::AllocationCreateFlags::MAPPED;
+        if !matches!(desc.allocation_scheme, AllocationScheme::Managed) {
+            flags |= vk_mem::AllocationCreateFlags::DEDICATED_MEMORY;
+        }
+        let create_info = vk_mem::AllocationCreateInfo {
+            flags,
+            usage: vk_mem::MemoryUsage::Unknown,
+            required_flags,
+            preferred_flags,
+            memory_type_bits,
+            ..Default::default()
+        };
+
+        let allocation = unsafe {
+            match desc.allocation_scheme {
+                AllocationScheme::DedicatedBuffer(buffer) => self
+                    .allocator
+                    .allocate_memory_for_buffer(buffer, &create_info),
+                AllocationScheme::DedicatedImage(image) => self
+                    .allocator
+                    .allocate_memory_for_image(image, &create_info),
+                AllocationScheme::Managed => self
+                    .allocator
+                    .allocate_memory(&desc.requirements, &create_info),
+            }
+        }
+        .map_err(vma_error)?;
+
+        let info = self.allocator.get_allocation_info(&allocation);
+        Ok(Allocation::new(
+            info.device_memory,
+            info.offset,
+            info.size,
+            NonNull::new(info.mapped_data),
+            allocation,
+        ))
+    }
+
+    fn free(&mut self, allocation: Allocation) -> Result<(), AllocationError> {
+        let mut allocation = allocation.into_backend::<vk_mem::Allocation>();
+        unsafe { self.allocator.free_memory(&mut allocation) };
+        Ok(())
+    }
+}
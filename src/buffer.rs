@@ -1,22 +1,94 @@
-use std::slice::from_raw_parts_mut;
-
-use ash::vk::{self, DeviceSize};
-use gpu_allocator::{
-    vulkan::{Allocation, AllocationCreateDesc, Allocator},
-    MemoryLocation,
+use std::{
+    ops::{Deref, DerefMut},
+    slice::from_raw_parts_mut,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
 };
+
+use ash::vk;
 use image::DynamicImage;
 
-use crate::render::{RenderAllocator, RenderInstance};
+use crate::{
+    allocator::{
+        Allocation, AllocationCreateDesc, AllocationScheme, Allocator, MemoryHints, MemoryLocation,
+    },
+    error::Result,
+    render::{
+        barrier::Usage,
+        deferred_destroy, stats,
+        tracking::{self, ResourceKind},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+static DEDICATED_ALLOCATION_THRESHOLD: AtomicU64 = AtomicU64::new(32 * 1024 * 1024);
+
+/// Buffers and images of at least `bytes` get their own allocation instead of being placed in a
+/// shared memory block. Render targets always get one, drivers prefer those to be dedicated.
+pub fn set_dedicated_allocation_threshold(bytes: u64) {
+    DEDICATED_ALLOCATION_THRESHOLD.store(bytes, Ordering::Relaxed);
+}
+
+fn exceeds_dedicated_allocation_threshold(requirements: &vk::MemoryRequirements) -> bool {
+    requirements.size >= DEDICATED_ALLOCATION_THRESHOLD.load(Ordering::Relaxed)
+}
+
+static REBAR_AVAILABLE: AtomicBool = AtomicBool::new(false);
+static REBAR_BUDGET: AtomicU64 = AtomicU64::new(0);
+static REBAR_USED: AtomicU64 = AtomicU64::new(0);
+static REBAR_SIZE_LIMIT: AtomicU64 = AtomicU64::new(16 * 1024 * 1024);
+
+/// Set by the renderer when the device has a large `DEVICE_LOCAL | HOST_VISIBLE` heap of
+/// `heap_size` bytes (resizable BAR or smart access memory). Small `GpuOnly` buffers that can be
+/// copied into are then allocated mapped, so [`Buffer::write`] skips the staging copy. They
+/// take up at most half of the heap, the rest is left to buffers that ask for `CpuToGpu`.
+pub fn set_rebar_available(heap_size: Option<u64>) {
+    REBAR_AVAILABLE.store(heap_size.is_some(), Ordering::Relaxed);
+    REBAR_BUDGET.store(heap_size.unwrap_or(0) / 2, Ordering::Relaxed);
+}
+
+pub fn rebar_available() -> bool {
+    REBAR_AVAILABLE.load(Ordering::Relaxed)
+}
+
+/// `GpuOnly` buffers larger than `bytes` stay in video memory that isn't host visible. Those
+/// are mostly written once, and would use up the heap for the buffers that are written often.
+pub fn set_rebar_size_limit(bytes: u64) {
+    REBAR_SIZE_LIMIT.store(bytes, Ordering::Relaxed);
+}
 
+/// Takes `size` bytes of the budget for a buffer moved to the resizable BAR heap, fails when the
+/// buffer is too large or the budget is used up.
+fn reserve_rebar(size: u64) -> bool {
+    if !rebar_available() || size > REBAR_SIZE_LIMIT.load(Ordering::Relaxed) {
+        return false;
+    }
+    let budget = REBAR_BUDGET.load(Ordering::Relaxed);
+    REBAR_USED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            (used + size <= budget).then_some(used + size)
+        })
+        .is_ok()
+}
+
+fn release_rebar(size: u64) {
+    REBAR_USED.fetch_sub(size, Ordering::Relaxed);
+}
+
+/// Dropping a buffer hands it to the deferred destroy queue, so it stays alive until in-flight
+/// frames are done with it. [`Buffer::destroy`] destroys it right away instead.
 #[derive(Debug)]
 pub struct Buffer {
     pub buffer: vk::Buffer,
     pub allocation: Option<Allocation>,
     pub size: u64,
+    pub usage: vk::BufferUsageFlags,
     pub device_addr: u64,
     pub has_been_written_to: bool,
     pub offset: u64,
+    pub location: MemoryLocation,
+    /// Moved to the resizable BAR heap instead of the `GpuOnly` memory it asked for, which
+    /// counts against the budget of [`set_rebar_available`].
+    pub rebar: bool,
 }
 
 impl Buffer {
@@ -25,7 +97,25 @@ impl Buffer {
         allocator: &mut Allocator,
         buffer_info: &vk::BufferCreateInfo,
         location: MemoryLocation,
-    ) -> Buffer {
+    ) -> Result<Buffer> {
+        Self::with_hints(
+            device,
+            allocator,
+            buffer_info,
+            location,
+            MemoryHints::default(),
+        )
+    }
+
+    /// Like [`Self::new`], with `hints` on which memory type to pick.
+    pub fn with_hints(
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        buffer_info: &vk::BufferCreateInfo,
+        location: MemoryLocation,
+        hints: MemoryHints,
+    ) -> Result<Buffer> {
+        profile_scope!("Buffer::new");
         let size = buffer_info.size;
         let buffer_info = &mut buffer_info.clone();
 
@@ -36,8 +126,25 @@ impl Buffer {
             buffer_info.usage |= vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
         }
 
-        let buffer = unsafe { device.create_buffer(buffer_info, None) }.unwrap();
+        let buffer = unsafe { device.create_buffer(buffer_info, None) }?;
+
+        // CpuToGpu prefers the device local heap, and falls back to system memory when it's full
+        let rebar = location == MemoryLocation::GpuOnly
+            && buffer_info
+                .usage
+                .contains(vk::BufferUsageFlags::TRANSFER_DST)
+            && reserve_rebar(size);
+        let location = if rebar {
+            MemoryLocation::CpuToGpu
+        } else {
+            location
+        };
         let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let allocation_scheme = if exceeds_dedicated_allocation_threshold(&requirements) {
+            AllocationScheme::DedicatedBuffer(buffer)
+        } else {
+            AllocationScheme::Managed
+        };
 
         let allocation = allocator
             .allocate(&AllocationCreateDesc {
@@ -45,16 +152,21 @@ impl Buffer {
                 requirements,
                 location,
                 linear: true,
-                allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+                allocation_scheme,
+                hints,
             })
-            .unwrap();
+            .map_err(|error| {
+                unsafe { device.destroy_buffer(buffer, None) };
+                if rebar {
+                    release_rebar(size);
+                }
+                error
+            })?;
 
         let offset = allocation.offset();
         let device_addr: u64;
         unsafe {
-            device
-                .bind_buffer_memory(buffer, allocation.memory(), offset)
-                .unwrap();
+            device.bind_buffer_memory(buffer, allocation.memory(), offset)?;
 
             device_addr = device.get_buffer_device_address(&vk::BufferDeviceAddressInfo {
                 buffer,
@@ -64,40 +176,201 @@ impl Buffer {
             });
         };
 
-        Self {
+        tracking::track(
+            ResourceKind::Buffer,
+            buffer,
+            format!("{} bytes, {:?}, {:?}", size, buffer_info.usage, location),
+        );
+        Ok(Self {
             buffer,
             allocation: Some(allocation),
             size,
+            usage: buffer_info.usage,
             device_addr,
             has_been_written_to: false,
             offset,
-        }
+            location,
+            rebar,
+        })
     }
 
+    /// Shown in the leak report when the buffer is still alive at shutdown.
+    pub fn set_name(&self, name: &str) {
+        tracking::set_name(ResourceKind::Buffer, self.buffer, name);
+    }
+
+    /// The GPU has to be done with the buffer.
     pub fn destroy(&mut self, device: &ash::Device, allocator: &mut Allocator) {
-        allocator.free(self.allocation.take().unwrap()).unwrap();
-        unsafe { device.destroy_buffer(self.buffer, None) };
+        tracking::untrack(ResourceKind::Buffer, self.buffer);
+        // aliased buffers don't own their memory
+        if let Some(allocation) = self.allocation.take() {
+            allocator.free(allocation).unwrap();
+        }
+        if std::mem::take(&mut self.rebar) {
+            release_rebar(self.size);
+        }
+        unsafe { device.destroy_buffer(std::mem::take(&mut self.buffer), None) };
+    }
+
+    /// Makes the buffer read as zeroes, mapped buffers are cleared right away and others before
+    /// the next frame is rendered. Some drivers hand out memory with the contents of whatever
+    /// used it before.
+    pub fn zero_initialized(mut self, render_instance: &RenderInstance) -> Self {
+        if self.is_mapped() {
+            self.mapped_bytes_mut().fill(0);
+        } else {
+            assert!(
+                self.usage.contains(vk::BufferUsageFlags::TRANSFER_DST),
+                "Zero-initialized buffers need TRANSFER_DST usage, unless they are mapped"
+            );
+            render_instance
+                .0
+                .staging_belt
+                .lock()
+                .unwrap()
+                .clear_buffer(&self);
+        }
+        self
     }
 
-    pub fn copy_from_slice<T>(&mut self, slice: &[T], offset: usize)
-    where
-        T: Copy,
-    {
+    /// Whether [`Self::copy_from_slice`] can be used.
+    pub fn is_mapped(&self) -> bool {
+        self.allocation
+            .as_ref()
+            .is_some_and(|allocation| allocation.mapped_ptr().is_some())
+    }
+
+    /// Writes `data` at `offset`, directly when the buffer is mapped and otherwise through the
+    /// staging belt, which needs the buffer to have `TRANSFER_DST` usage.
+    pub fn write(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &RenderAllocator,
+        data: &[u8],
+        offset: u64,
+    ) -> Result<()> {
+        if self.is_mapped() {
+            self.copy_from_slice(data, offset as usize);
+            return Ok(());
+        }
+        render_instance
+            .0
+            .staging_belt
+            .lock()
+            .unwrap()
+            .write_buffer(
+                render_instance.device(),
+                render_allocator,
+                data,
+                self,
+                offset,
+            )?;
+        self.has_been_written_to = true;
+        Ok(())
+    }
+
+    /// The mapped memory of the whole buffer.
+    fn mapped_bytes_mut(&mut self) -> &mut [u8] {
         let Some(allocation) = self.allocation.as_ref() else {
-            panic!("Tried writing to buffer but buffer not allocated");
+            panic!(
+                "Tried mapping buffer {:?}, but it doesn't own its memory",
+                self.buffer
+            );
+        };
+        let Some(ptr) = allocation.mapped_ptr() else {
+            panic!(
+                "Tried mapping buffer {:?}, but it was allocated {:?}. Use CpuToGpu or GpuToCpu for buffers that are accessed from the CPU",
+                self.buffer, self.location
+            );
         };
-        //assert!(std::mem::size_of_val(slice) + offset <= self.info.get_size());
+        unsafe { from_raw_parts_mut(ptr.as_ptr() as *mut u8, self.size as usize) }
+    }
 
-        unsafe {
-            let ptr = allocation.mapped_ptr().unwrap().as_ptr() as *mut u8;
-            let mem_ptr = ptr.add(offset);
-            let mapped_slice = from_raw_parts_mut(mem_ptr as *mut T, slice.len());
-            mapped_slice.copy_from_slice(slice);
+    /// Maps the buffer as a slice of `T`, see [`MappedSlice`]. `device` is only used when the
+    /// memory might not be host coherent. Panics when the buffer isn't host visible or the
+    /// memory isn't aligned for `T`.
+    pub fn map<'a, T: bytemuck::Pod>(&'a mut self, device: &'a ash::Device) -> MappedSlice<'a, T> {
+        // gpu-allocator only maps coherent memory for these, other locations can end up in
+        // mappable device memory on integrated GPUs
+        let coherent = matches!(
+            self.location,
+            MemoryLocation::CpuToGpu | MemoryLocation::GpuToCpu
+        );
+        let memory = self
+            .allocation
+            .as_ref()
+            .map(|allocation| allocation.memory())
+            .unwrap_or_default();
+        self.has_been_written_to = true;
+
+        let bytes = self.mapped_bytes_mut();
+        let len = bytes.len() / std::mem::size_of::<T>() * std::mem::size_of::<T>();
+        MappedSlice {
+            slice: bytemuck::cast_slice_mut(&mut bytes[..len]),
+            flush: (!coherent).then_some((device, memory)),
         }
+    }
+
+    pub fn copy_from_slice<T: bytemuck::Pod>(&mut self, slice: &[T], offset: usize) {
+        let bytes: &[u8] = bytemuck::cast_slice(slice);
+        self.mapped_bytes_mut()[offset..offset + bytes.len()].copy_from_slice(bytes);
+        stats::count_bytes_uploaded(bytes.len() as u64);
         self.has_been_written_to = true;
     }
 }
 
+/// A typed view of a mapped [`Buffer`], from [`Buffer::map`]. Writes are flushed when the guard
+/// is dropped if the memory isn't host coherent.
+pub struct MappedSlice<'a, T> {
+    slice: &'a mut [T],
+    flush: Option<(&'a ash::Device, vk::DeviceMemory)>,
+}
+
+impl<T> Deref for MappedSlice<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.slice
+    }
+}
+
+impl<T> DerefMut for MappedSlice<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.slice
+    }
+}
+
+impl<T> Drop for MappedSlice<'_, T> {
+    fn drop(&mut self) {
+        let Some((device, memory)) = self.flush else {
+            return;
+        };
+        // the whole memory object, so the range doesn't have to be aligned to nonCoherentAtomSize
+        unsafe {
+            device
+                .flush_mapped_memory_ranges(&[vk::MappedMemoryRange::default()
+                    .memory(memory)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE)])
+                .expect("Failed to flush the mapped buffer")
+        };
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if self.buffer == vk::Buffer::null() {
+            return;
+        }
+        deferred_destroy::release(Buffer {
+            buffer: std::mem::take(&mut self.buffer),
+            allocation: self.allocation.take(),
+            ..*self
+        });
+    }
+}
+
+/// Dropping an image queues it, together with its view, like a [`Buffer`].
 #[derive(Debug)]
 pub struct Image {
     pub image: vk::Image,
@@ -138,9 +411,30 @@ impl Image {
         device: &ash::Device,
         allocator: &mut Allocator,
         image_info: &vk::ImageCreateInfo,
-    ) -> Image {
-        let image = unsafe { device.create_image(image_info, None) }.unwrap();
+    ) -> Result<Image> {
+        Self::with_hints(device, allocator, image_info, MemoryHints::default())
+    }
+
+    /// Like [`Self::new`], with `hints` on which memory type to pick, e.g. lazily allocated
+    /// memory for `TRANSIENT_ATTACHMENT` images.
+    pub fn with_hints(
+        device: &ash::Device,
+        allocator: &mut Allocator,
+        image_info: &vk::ImageCreateInfo,
+        hints: MemoryHints,
+    ) -> Result<Image> {
+        profile_scope!("Image::new");
+        let image = unsafe { device.create_image(image_info, None) }?;
         let requirements = unsafe { device.get_image_memory_requirements(image) };
+        let is_attachment = image_info.usage.intersects(
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        );
+        let allocation_scheme =
+            if is_attachment || exceeds_dedicated_allocation_threshold(&requirements) {
+                AllocationScheme::DedicatedImage(image)
+            } else {
+                AllocationScheme::Managed
+            };
 
         let allocation = allocator
             .allocate(&AllocationCreateDesc {
@@ -148,30 +442,44 @@ impl Image {
                 requirements,
                 location: MemoryLocation::GpuOnly,
                 linear: false,
-                allocation_scheme: gpu_allocator::vulkan::AllocationScheme::GpuAllocatorManaged,
+                allocation_scheme,
+                hints,
             })
-            .unwrap();
+            .map_err(|error| {
+                unsafe { device.destroy_image(image, None) };
+                error
+            })?;
         let offset = allocation.offset();
 
-        unsafe {
-            device
-                .bind_image_memory(image, allocation.memory(), allocation.offset())
-                .unwrap()
-        };
+        unsafe { device.bind_image_memory(image, allocation.memory(), allocation.offset())? };
+        tracking::track(
+            ResourceKind::Image,
+            image,
+            format!(
+                "{:?} {}x{}x{}, {} mips, {} layers, {:?}",
+                image_info.format,
+                image_info.extent.width,
+                image_info.extent.height,
+                image_info.extent.depth,
+                image_info.mip_levels,
+                image_info.array_layers,
+                image_info.usage
+            ),
+        );
 
-        Self {
+        Ok(Self {
             image,
             allocation: Some(allocation),
             view: None,
             format: image_info.format,
             extent: image_info.extent,
             offset,
-        }
+        })
     }
 
-    pub fn create_view(&mut self, device: &ash::Device) -> vk::ImageView {
-        if self.view.is_some() {
-            return self.view.unwrap();
+    pub fn create_view(&mut self, device: &ash::Device) -> Result<vk::ImageView> {
+        if let Some(view) = self.view {
+            return Ok(view);
         }
         let view = unsafe {
             device.create_image_view(
@@ -195,18 +503,56 @@ impl Image {
                 },
                 None,
             )
-        }
-        .unwrap();
+        }?;
+        self.set_view(view);
+        Ok(view)
+    }
+
+    /// Takes ownership of `view`, it's destroyed together with the image.
+    pub fn set_view(&mut self, view: vk::ImageView) {
+        tracking::set_view(self.image, view);
         self.view = Some(view);
-        view
+    }
+
+    /// Clears the image to zero before the next frame is rendered, leaving it in the layout of
+    /// `first_usage`. Needs `TRANSFER_DST` usage.
+    pub fn zero_initialized(self, render_instance: &RenderInstance, first_usage: Usage) -> Self {
+        render_instance
+            .0
+            .staging_belt
+            .lock()
+            .unwrap()
+            .clear_image(&self, first_usage);
+        self
+    }
+
+    /// Moves the image into the layout of `first_usage` before the next frame is rendered,
+    /// batched with the other images created during the frame.
+    pub fn initial_layout(self, render_instance: &RenderInstance, first_usage: Usage) -> Self {
+        render_instance
+            .0
+            .staging_belt
+            .lock()
+            .unwrap()
+            .transition_image(&self, first_usage);
+        self
+    }
+
+    /// Shown in the leak report when the image is still alive at shutdown.
+    pub fn set_name(&self, name: &str) {
+        tracking::set_name(ResourceKind::Image, self.image, name);
     }
 
     pub fn destroy(&mut self, device: &ash::Device, allocator: &mut Allocator) {
+        tracking::untrack(ResourceKind::Image, self.image);
         if let Some(view) = self.view.take() {
             unsafe { device.destroy_image_view(view, None) };
         }
-        allocator.free(self.allocation.take().unwrap()).unwrap();
-        unsafe { device.destroy_image(self.image, None) };
+        // aliased images don't own their memory
+        if let Some(allocation) = self.allocation.take() {
+            allocator.free(allocation).unwrap();
+        }
+        unsafe { device.destroy_image(std::mem::take(&mut self.image), None) };
     }
 
     pub fn from_image_buffer(
@@ -214,10 +560,10 @@ impl Image {
         render_allocator: &mut RenderAllocator,
         image: DynamicImage,
         format: vk::Format,
-    ) -> Self {
+    ) -> Result<Self> {
         let texture = Self::new(
             render_instance.device(),
-            render_allocator.allocator(),
+            &mut render_allocator.allocator(),
             &vk::ImageCreateInfo::default()
                 .image_type(vk::ImageType::TYPE_2D)
                 .format(format)
@@ -232,7 +578,7 @@ impl Image {
                 .tiling(vk::ImageTiling::OPTIMAL)
                 .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
                 .sharing_mode(vk::SharingMode::EXCLUSIVE),
-        );
+        )?;
 
         {
             // let image_data = match format {
@@ -241,25 +587,16 @@ impl Image {
             //     _ => unimplemented!("Format not supported yet"),
             // };
             let image_data = image.to_rgba8().into_raw();
-            let mut img_buffer = Buffer::new(
+            // copied together with the other uploads of the frame
+            render_instance.0.staging_belt.lock().unwrap().write_image(
                 render_instance.device(),
-                render_allocator.allocator(),
-                &vk::BufferCreateInfo::default()
-                    .size(image_data.len() as DeviceSize)
-                    .usage(vk::BufferUsageFlags::TRANSFER_SRC)
-                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
-                MemoryLocation::CpuToGpu,
-            );
-            img_buffer.copy_from_slice(&image_data, 0);
-
-            render_instance
-                .0
-                .copy_buffer_to_texture(&img_buffer, &texture);
-
-            img_buffer.destroy(render_instance.device(), render_allocator.allocator());
+                render_allocator,
+                &image_data,
+                &texture,
+            )?;
         }
 
-        texture
+        Ok(texture)
     }
 
     pub fn bytes_per_texel(&self) -> u32 {
@@ -291,3 +628,17 @@ impl Image {
         }
     }
 }
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if self.image == vk::Image::null() {
+            return;
+        }
+        deferred_destroy::release(Image {
+            image: std::mem::take(&mut self.image),
+            allocation: self.allocation.take(),
+            view: self.view.take(),
+            ..*self
+        });
+    }
+}
@@ -1,20 +1,22 @@
-use std::cell::UnsafeCell;
+use std::sync::Mutex;
 
-struct TempListInner<T> {
-    payload: arrayvec::ArrayVec<T, 8>,
-    next: Option<TempList<T>>,
-}
+/// Chunk size of [`TempList::new`].
+const DEFAULT_CHUNK_CAPACITY: usize = 8;
 
-impl<T> Default for TempListInner<T> {
-    fn default() -> Self {
-        Self {
-            payload: Default::default(),
-            next: None,
-        }
-    }
+struct Chunks<T> {
+    /// Never grown past their capacity, so the items don't move while they are borrowed.
+    chunks: Vec<Vec<T>>,
+    /// First chunk that may have room left.
+    current: usize,
 }
 
-pub struct TempList<T>(UnsafeCell<Box<TempListInner<T>>>);
+/// An append-only arena that hands out references to the items it stores, like the immutable
+/// samplers a descriptor set layout points at. Items can be added from multiple threads, and
+/// [`TempList::clear`] keeps the chunks around so a list can be reused every frame.
+pub struct TempList<T> {
+    inner: Mutex<Chunks<T>>,
+    chunk_capacity: usize,
+}
 
 impl<T> Default for TempList<T> {
     fn default() -> Self {
@@ -24,28 +26,55 @@ impl<T> Default for TempList<T> {
 
 impl<T> TempList<T> {
     pub fn new() -> Self {
-        Self(UnsafeCell::new(Box::default()))
+        Self::with_capacity(DEFAULT_CHUNK_CAPACITY)
+    }
+
+    /// Reserves room for `capacity` items up front, later chunks are the same size.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let chunk_capacity = capacity.max(1);
+        Self {
+            inner: Mutex::new(Chunks {
+                chunks: vec![Vec::with_capacity(chunk_capacity)],
+                current: 0,
+            }),
+            chunk_capacity,
+        }
     }
 
     pub fn add(&self, item: T) -> &T {
-        unsafe {
-            let inner = &mut *self.0.get();
-            if let Err(err) = inner.payload.try_push(item) {
-                let mut new_payload = arrayvec::ArrayVec::new();
-                new_payload.push(err.element());
-
-                let mut new_node = Box::new(TempListInner {
-                    payload: new_payload,
-                    next: None,
-                });
-
-                std::mem::swap(&mut new_node, inner);
-                inner.next = Some(TempList(UnsafeCell::new(new_node)));
-                &inner.payload[0]
-            } else {
-                &inner.payload[inner.payload.len() - 1]
+        let mut inner = self.inner.lock().unwrap();
+        while inner.chunks[inner.current].len() == self.chunk_capacity {
+            inner.current += 1;
+            if inner.current == inner.chunks.len() {
+                inner.chunks.push(Vec::with_capacity(self.chunk_capacity));
             }
         }
+
+        let current = inner.current;
+        let chunk = &mut inner.chunks[current];
+        chunk.push(item);
+        let item: *const T = chunk.last().unwrap();
+        // the chunk doesn't reallocate and items are only dropped by `clear`, which needs
+        // `&mut self`, so the item lives as long as the borrow of the list
+        unsafe { &*item }
+    }
+
+    pub fn len(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.chunks.iter().map(|chunk| chunk.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops all items, keeping the memory of the chunks.
+    pub fn clear(&mut self) {
+        let inner = self.inner.get_mut().unwrap();
+        for chunk in inner.chunks.iter_mut() {
+            chunk.clear();
+        }
+        inner.current = 0;
     }
 }
 
@@ -63,3 +92,24 @@ fn test_add() {
         assert_eq!(i, *refs[i as usize]);
     }
 }
+
+#[test]
+fn test_add_from_threads_and_clear() {
+    let mut list = TempList::with_capacity(16);
+    std::thread::scope(|scope| {
+        for thread in 0..4u32 {
+            let list = &list;
+            scope.spawn(move || {
+                for i in 0..100 {
+                    let value = thread * 1000 + i;
+                    assert_eq!(*list.add(value), value);
+                }
+            });
+        }
+    });
+    assert_eq!(list.len(), 400);
+
+    list.clear();
+    assert!(list.is_empty());
+    assert_eq!(*list.add(7), 7);
+}
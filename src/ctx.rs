@@ -1,3 +1,7 @@
+#[cfg(unix)]
+use ash::vk::{KhrExternalMemoryFdFn, KhrExternalSemaphoreFdFn};
+#[cfg(windows)]
+use ash::vk::{KhrExternalMemoryWin32Fn, KhrExternalSemaphoreWin32Fn};
 #[cfg(any(target_os = "macos", target_os = "ios"))]
 use ash::vk::{
     KhrGetMemoryRequirements2Fn, KhrGetPhysicalDeviceProperties2Fn, KhrPortabilityEnumerationFn,
@@ -5,26 +9,55 @@ use ash::vk::{
 };
 use ash::{
     extensions::{
-        ext::DebugUtils,
-        khr::{DynamicRendering, Surface, Swapchain, Synchronization2},
+        ext::{DebugUtils, MeshShader},
+        khr::{
+            AccelerationStructure, DeferredHostOperations, DrawIndirectCount, DynamicRendering,
+            PresentWait, RayTracingPipeline, Surface, Swapchain, Synchronization2,
+        },
     },
     vk::{
         BufferImageCopy, CommandBuffer, ExtDescriptorIndexingFn, ImageLayout,
+        KhrFragmentShadingRateFn, KhrPresentIdFn, KhrRayQueryFn, KhrVideoDecodeH264Fn,
+        KhrVideoDecodeH265Fn, KhrVideoDecodeQueueFn, KhrVideoQueueFn,
         PhysicalDeviceBufferDeviceAddressFeaturesKHR, PhysicalDeviceDescriptorIndexingFeatures,
         API_VERSION_1_2,
     },
 };
-use ash::{vk, Entry};
+use ash::{prelude::VkResult, vk, Entry};
 use ash::{Device, Instance};
-use bevy::window::{PresentMode, RawHandleWrapper};
+use bevy::{
+    prelude::Resource,
+    window::{PresentMode, RawHandleWrapper},
+};
 use rayon::ThreadPool;
 use std::default::Default;
-use std::ffi::CStr;
+use std::ffi::{CStr, CString};
 use std::{borrow::Cow, collections::HashMap};
-use std::{ops::Drop, sync::RwLock};
+use std::{
+    ops::Drop,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, OnceLock, RwLock,
+    },
+};
 use std::{os::raw::c_char, sync::Arc};
 
-use crate::buffer::{Buffer, Image};
+use crate::{
+    allocator::{Allocator, AllocatorReport},
+    buffer::{Buffer, Image},
+    error::{Error, Result},
+    render::{
+        config::{RendererConfig, MAX_FRAMES_IN_FLIGHT},
+        deferred_destroy,
+        descriptor_allocator::DescriptorAllocator,
+        frame_dump,
+        graph::RenderGraph,
+        registry::ResourceRegistry,
+        shaders,
+        staging_belt::StagingBelt,
+        tracking::{self, LeakReport, ResourceKind},
+    },
+};
 
 // /// Helper function for submitting command buffers. Immediately waits for the fence before the command buffer
 // /// is executed. That way we can delay the waiting for the fences by 1 frame which is good for performance.
@@ -88,37 +121,29 @@ pub fn record_submit_commandbuffer<F: FnOnce(&Device, vk::CommandBuffer)>(
     command_buffer: vk::CommandBuffer,
     command_buffer_reuse_fence: vk::Fence,
     submit_queue: vk::Queue,
+    queue_lock: &Mutex<()>,
     wait_mask: &[vk::PipelineStageFlags],
     wait_semaphores: &[vk::Semaphore],
     signal_semaphores: &[vk::Semaphore],
     f: F,
-) {
+) -> Result<()> {
+    profile_scope!("record_submit_commandbuffer");
     unsafe {
-        device
-            .wait_for_fences(&[command_buffer_reuse_fence], true, std::u64::MAX)
-            .expect("Wait for fence failed.");
+        device.wait_for_fences(&[command_buffer_reuse_fence], true, std::u64::MAX)?;
 
-        device
-            .reset_fences(&[command_buffer_reuse_fence])
-            .expect("Reset fences failed.");
+        device.reset_fences(&[command_buffer_reuse_fence])?;
 
-        device
-            .reset_command_buffer(
-                command_buffer,
-                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
-            )
-            .expect("Reset command buffer failed.");
+        device.reset_command_buffer(
+            command_buffer,
+            vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+        )?;
 
         let command_buffer_begin_info = vk::CommandBufferBeginInfo::default()
             .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-        device
-            .begin_command_buffer(command_buffer, &command_buffer_begin_info)
-            .expect("Begin commandbuffer");
+        device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
         f(device, command_buffer);
-        device
-            .end_command_buffer(command_buffer)
-            .expect("End commandbuffer");
+        device.end_command_buffer(command_buffer)?;
 
         let command_buffers = vec![command_buffer];
 
@@ -128,11 +153,167 @@ pub fn record_submit_commandbuffer<F: FnOnce(&Device, vk::CommandBuffer)>(
             .command_buffers(&command_buffers)
             .signal_semaphores(signal_semaphores);
 
-        device
-            .queue_submit(submit_queue, &[submit_info], command_buffer_reuse_fence)
-            .expect("queue submit failed.");
-        device.queue_wait_idle(submit_queue).unwrap();
+        let _queue = queue_lock.lock().unwrap();
+        device.queue_submit(submit_queue, &[submit_info], command_buffer_reuse_fence)?;
+        device.queue_wait_idle(submit_queue)?;
+    }
+    Ok(())
+}
+
+/// Without resizable BAR only a 256 MiB window of video memory is host visible.
+const BAR_WINDOW_SIZE: vk::DeviceSize = 256 * 1024 * 1024;
+
+/// Size of the largest host visible device local heap, when it's larger than the BAR window.
+fn resizable_bar_heap_size(
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+) -> Option<vk::DeviceSize> {
+    let host_visible_device_local =
+        vk::MemoryPropertyFlags::DEVICE_LOCAL | vk::MemoryPropertyFlags::HOST_VISIBLE;
+    memory_properties.memory_types[..memory_properties.memory_type_count as usize]
+        .iter()
+        .filter(|memory_type| memory_type.property_flags.contains(host_visible_device_local))
+        .map(|memory_type| memory_properties.memory_heaps[memory_type.heap_index as usize])
+        .filter(|heap| {
+            heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL) && heap.size > BAR_WINDOW_SIZE
+        })
+        .map(|heap| heap.size)
+        .max()
+}
+
+/// The first of `preferred` the surface supports. Otherwise the first format of the surface,
+/// or with `hdr` its first 10 bit HDR10 format if it has one.
+fn choose_surface_format(
+    formats: &[vk::SurfaceFormatKHR],
+    preferred: &[vk::SurfaceFormatKHR],
+    hdr: bool,
+) -> vk::SurfaceFormatKHR {
+    if let Some(format) = preferred.iter().find(|format| formats.contains(format)) {
+        return *format;
+    }
+    formats
+        .iter()
+        .find(|format| {
+            hdr && format.color_space == vk::ColorSpaceKHR::HDR10_ST2084_EXT
+                && matches!(
+                    format.format,
+                    vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32
+                )
+        })
+        .copied()
+        .unwrap_or(formats[0])
+}
+
+/// `requested` clamped to the image counts the surface supports, one more than its minimum when
+/// nothing is requested so acquiring doesn't wait on the presentation engine.
+fn swapchain_image_count(
+    requested: Option<u32>,
+    surface_capabilities: &vk::SurfaceCapabilitiesKHR,
+) -> u32 {
+    let max_image_count = match surface_capabilities.max_image_count {
+        // no limit
+        0 => u32::MAX,
+        max_image_count => max_image_count,
+    };
+    requested
+        .unwrap_or(surface_capabilities.min_image_count + 1)
+        .clamp(surface_capabilities.min_image_count, max_image_count)
+}
+
+/// Creates the swapchain for `surface`, returns its format, size and image usage along with it.
+unsafe fn create_swapchain(
+    surface_loader: &Surface,
+    swapchain_loader: &Swapchain,
+    pdevice: vk::PhysicalDevice,
+    surface: vk::SurfaceKHR,
+    present_mode: PresentMode,
+    image_count: Option<u32>,
+    preferred_formats: &[vk::SurfaceFormatKHR],
+    hdr_output: bool,
+) -> Result<(
+    vk::SurfaceFormatKHR,
+    vk::Extent2D,
+    vk::ImageUsageFlags,
+    vk::SwapchainKHR,
+)> {
+    let surface_format = choose_surface_format(
+        &surface_loader.get_physical_device_surface_formats(pdevice, surface)?,
+        preferred_formats,
+        hdr_output,
+    );
+    if preferred_formats.contains(&surface_format) {
+        println!("Swapchain uses the preferred format {:?}", surface_format);
+    } else {
+        if !preferred_formats.is_empty() {
+            println!("The surface supports none of the preferred swapchain formats");
+        }
+        println!("Swapchain uses {:?}", surface_format);
+    }
+
+    let surface_capabilities =
+        surface_loader.get_physical_device_surface_capabilities(pdevice, surface)?;
+    let desired_image_count = swapchain_image_count(image_count, &surface_capabilities);
+    if let Some(image_count) = image_count.filter(|count| *count != desired_image_count) {
+        println!(
+            "The surface doesn't support {} swapchain images, using {}",
+            image_count, desired_image_count
+        );
     }
+    let surface_resolution = match surface_capabilities.current_extent.width {
+        // std::u32::MAX => vk::Extent2D {
+        //     width: 1280,
+        //     height: 720,
+        // },
+        _ => surface_capabilities.current_extent,
+    };
+    let pre_transform = if surface_capabilities
+        .supported_transforms
+        .contains(vk::SurfaceTransformFlagsKHR::IDENTITY)
+    {
+        vk::SurfaceTransformFlagsKHR::IDENTITY
+    } else {
+        surface_capabilities.current_transform
+    };
+    let present_modes =
+        surface_loader.get_physical_device_surface_present_modes(pdevice, surface)?;
+
+    let present_mode = match present_mode {
+        PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+        PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+        PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+        PresentMode::AutoNoVsync => vk::PresentModeKHR::IMMEDIATE,
+        PresentMode::AutoVsync => vk::PresentModeKHR::FIFO_RELAXED,
+    };
+    if !present_modes.contains(&present_mode) {
+        return Err(Error::Unsupported(format!(
+            "Present mode {present_mode:?} not supported."
+        )));
+    }
+    // copying out of the swapchain images lets the presented frames be captured
+    let swapchain_image_usage = vk::ImageUsageFlags::COLOR_ATTACHMENT
+        | (surface_capabilities.supported_usage_flags & vk::ImageUsageFlags::TRANSFER_SRC);
+
+    let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
+        .surface(surface)
+        .min_image_count(desired_image_count)
+        .image_color_space(surface_format.color_space)
+        .image_format(surface_format.format)
+        .image_extent(surface_resolution)
+        .image_usage(swapchain_image_usage)
+        .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .pre_transform(pre_transform)
+        .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+        .present_mode(present_mode)
+        .clipped(true)
+        .image_array_layers(1);
+
+    let swapchain = swapchain_loader.create_swapchain(&swapchain_create_info, None)?;
+
+    Ok((
+        surface_format,
+        surface_resolution,
+        swapchain_image_usage,
+        swapchain,
+    ))
 }
 
 unsafe extern "system" fn vulkan_debug_callback(
@@ -156,13 +337,114 @@ unsafe extern "system" fn vulkan_debug_callback(
         CStr::from_ptr(callback_data.p_message).to_string_lossy()
     };
 
+    if message_id_name.contains("DEBUG-PRINTF") {
+        // the layer prefixes the message with its id, what the shader printed comes last
+        let printed = message.rsplit("| ").next().unwrap_or_default();
+        log::info!(target: "shader_printf", "{}", printed.trim_end());
+        return vk::FALSE;
+    }
+
     println!(
       "{message_severity:?}:\n{message_type:?} [{message_id_name} ({message_id_number})] : {message}\n",
   );
 
+    #[cfg(feature = "renderdoc")]
+    if message_severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        let frames = CAPTURE_ON_VALIDATION_ERROR.swap(0, std::sync::atomic::Ordering::Relaxed);
+        if let (true, Some(renderdoc)) = (frames > 0, renderdoc()) {
+            if let Ok(mut renderdoc) = renderdoc.try_lock() {
+                renderdoc.trigger_multi_frame_capture(frames);
+            }
+        }
+    }
+
     vk::FALSE
 }
 
+#[cfg(feature = "renderdoc")]
+type RenderDocApi = std::sync::Mutex<renderdoc::RenderDoc<renderdoc::V141>>;
+
+/// Loaded before the instance is created, so RenderDoc can hook into Vulkan.
+#[cfg(feature = "renderdoc")]
+static RENDERDOC: std::sync::OnceLock<Option<RenderDocApi>> = std::sync::OnceLock::new();
+
+/// Amount of frames to capture on the first validation error, zero when disabled.
+#[cfg(feature = "renderdoc")]
+static CAPTURE_ON_VALIDATION_ERROR: std::sync::atomic::AtomicU32 =
+    std::sync::atomic::AtomicU32::new(0);
+
+/// Returns the RenderDoc API when the application was launched through RenderDoc, or when its
+/// library can be found on the library path and is loaded here instead.
+#[cfg(feature = "renderdoc")]
+fn renderdoc() -> Option<&'static RenderDocApi> {
+    RENDERDOC
+        .get_or_init(|| {
+            if let Ok(api) = renderdoc::RenderDoc::new() {
+                return Some(std::sync::Mutex::new(api));
+            }
+
+            #[cfg(windows)]
+            let library_name = "renderdoc.dll";
+            #[cfg(not(windows))]
+            let library_name = "librenderdoc.so";
+            // stays loaded for the rest of the process, like it would when injected
+            match unsafe { libloading::Library::new(library_name) } {
+                Ok(library) => std::mem::forget(library),
+                Err(error) => {
+                    println!("RenderDoc is not available: {}", error);
+                    return None;
+                }
+            }
+
+            renderdoc::RenderDoc::new()
+                .map_err(|error| println!("RenderDoc is not available: {}", error))
+                .ok()
+                .map(std::sync::Mutex::new)
+        })
+        .as_ref()
+}
+
+/// Name of the pipeline cache in [`RendererConfig::shader_cache_dir`].
+const PIPELINE_CACHE_FILE: &str = "pipeline_cache.bin";
+
+/// Starts from the cache saved by an earlier run, when there is one. The driver checks the
+/// header of the data and ignores caches of other devices or driver versions.
+unsafe fn create_pipeline_cache(
+    device: &Device,
+    config: &RendererConfig,
+) -> VkResult<vk::PipelineCache> {
+    let data = config
+        .shader_cache_dir
+        .as_ref()
+        .and_then(|dir| std::fs::read(dir.join(PIPELINE_CACHE_FILE)).ok())
+        .unwrap_or_default();
+    device.create_pipeline_cache(
+        &vk::PipelineCacheCreateInfo::default().initial_data(&data),
+        None,
+    )
+}
+
+unsafe fn save_pipeline_cache(device: &Device, cache: vk::PipelineCache, config: &RendererConfig) {
+    let Some(dir) = &config.shader_cache_dir else {
+        return;
+    };
+    let saved = device
+        .get_pipeline_cache_data(cache)
+        .map_err(|err| err.to_string())
+        .and_then(|data| {
+            std::fs::create_dir_all(dir)
+                .and_then(|_| std::fs::write(dir.join(PIPELINE_CACHE_FILE), data))
+                .map_err(|err| err.to_string())
+        });
+    if let Err(err) = saved {
+        println!(
+            "Failed to save the pipeline cache to {}: {}",
+            dir.display(),
+            err
+        );
+    }
+}
+
 pub fn find_memorytype_index(
     memory_req: &vk::MemoryRequirements,
     memory_prop: &vk::PhysicalDeviceMemoryProperties,
@@ -183,6 +465,377 @@ pub struct SamplerDesc {
     pub texel_filter: vk::Filter,
     pub mipmap_mode: vk::SamplerMipmapMode,
     pub address_modes: vk::SamplerAddressMode,
+    /// Depth comparison sampler (`LESS_OR_EQUAL`), used for sampling shadow maps.
+    pub compare: bool,
+}
+
+/// Line rasterization the device supports, see [`LineMode`](crate::render::pipeline::LineMode).
+/// Everything but `wide` needs `VK_EXT_line_rasterization`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LineSupport {
+    /// Line widths other than 1.0.
+    pub wide: bool,
+    pub rectangular: bool,
+    pub bresenham: bool,
+    pub smooth: bool,
+    pub stippled_rectangular: bool,
+    pub stippled_bresenham: bool,
+    pub stippled_smooth: bool,
+}
+
+/// Subgroups (waves) of the device. Shaders are compiled with [`SubgroupSupport::defines`], so
+/// compute kernels can specialize for wave32 or wave64.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SubgroupSupport {
+    /// Size shaders run with when the pipeline doesn't ask for one.
+    pub size: u32,
+    /// Sizes a compute pipeline can require with `size_control`, both are `size` without it.
+    pub min_size: u32,
+    pub max_size: u32,
+    /// Stages the subgroup operations can be used in.
+    pub stages: vk::ShaderStageFlags,
+    pub operations: vk::SubgroupFeatureFlags,
+    /// `VK_EXT_subgroup_size_control` is enabled, with `computeFullSubgroups`.
+    pub size_control: bool,
+}
+
+impl SubgroupSupport {
+    /// `SUBGROUP_SIZE`, `SUBGROUP_MIN_SIZE` and `SUBGROUP_MAX_SIZE`, and `SUBGROUP_BALLOT` and
+    /// the like for every supported group of operations.
+    pub fn defines(&self) -> Vec<(String, String)> {
+        let mut defines = vec![
+            ("SUBGROUP_SIZE".to_string(), self.size.to_string()),
+            ("SUBGROUP_MIN_SIZE".to_string(), self.min_size.to_string()),
+            ("SUBGROUP_MAX_SIZE".to_string(), self.max_size.to_string()),
+        ];
+        let operations = [
+            (vk::SubgroupFeatureFlags::BASIC, "SUBGROUP_BASIC"),
+            (vk::SubgroupFeatureFlags::VOTE, "SUBGROUP_VOTE"),
+            (vk::SubgroupFeatureFlags::ARITHMETIC, "SUBGROUP_ARITHMETIC"),
+            (vk::SubgroupFeatureFlags::BALLOT, "SUBGROUP_BALLOT"),
+            (vk::SubgroupFeatureFlags::SHUFFLE, "SUBGROUP_SHUFFLE"),
+            (
+                vk::SubgroupFeatureFlags::SHUFFLE_RELATIVE,
+                "SUBGROUP_SHUFFLE_RELATIVE",
+            ),
+            (vk::SubgroupFeatureFlags::CLUSTERED, "SUBGROUP_CLUSTERED"),
+            (vk::SubgroupFeatureFlags::QUAD, "SUBGROUP_QUAD"),
+        ];
+        for (flag, name) in operations {
+            if self.operations.contains(flag) {
+                defines.push((name.to_string(), "1".to_string()));
+            }
+        }
+        defines
+    }
+}
+
+/// Types smaller than 32 bits in shaders. Each one enabled defines a macro for the shaders,
+/// `SHADER_FLOAT16`, `SHADER_INT8`, `STORAGE_BUFFER_16BIT` and `STORAGE_BUFFER_8BIT`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SmallTypes {
+    /// `float16_t` arithmetic, `shaderFloat16`.
+    pub float16: bool,
+    /// `int8_t` arithmetic, `shaderInt8`.
+    pub int8: bool,
+    /// 16-bit types in storage buffers, `storageBuffer16BitAccess`.
+    pub storage_buffer_16bit: bool,
+    /// 8-bit types in storage buffers, `storageBuffer8BitAccess`.
+    pub storage_buffer_8bit: bool,
+}
+
+impl SmallTypes {
+    fn defines(&self) -> impl Iterator<Item = &'static str> {
+        [
+            (self.float16, "SHADER_FLOAT16"),
+            (self.int8, "SHADER_INT8"),
+            (self.storage_buffer_16bit, "STORAGE_BUFFER_16BIT"),
+            (self.storage_buffer_8bit, "STORAGE_BUFFER_8BIT"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, name)| enabled.then_some(name))
+    }
+}
+
+/// A matrix multiply-add the device runs in hardware through `VK_KHR_cooperative_matrix`,
+/// `result = a * b + c` with `a` of `m` by `k`, `b` of `k` by `n` and `c` of `m` by `n`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CooperativeMatrixConfig {
+    pub m: u32,
+    pub n: u32,
+    pub k: u32,
+    pub a_type: vk::ComponentTypeKHR,
+    pub b_type: vk::ComponentTypeKHR,
+    pub c_type: vk::ComponentTypeKHR,
+    pub result_type: vk::ComponentTypeKHR,
+    pub saturating_accumulation: bool,
+    pub scope: vk::ScopeKHR,
+}
+
+/// The descriptor indexing features that were enabled, the ones the device supports out of
+/// those the renderer uses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DescriptorIndexing {
+    /// Bindings can be left unwritten when shaders don't read them, which the layouts of every
+    /// shader rely on. Without it every descriptor of a binding has to be written.
+    pub partially_bound: bool,
+    /// Shaders can declare arrays without a size, like the bindless textures.
+    pub runtime_descriptor_array: bool,
+    pub sampled_image_non_uniform_indexing: bool,
+    pub sampled_image_update_after_bind: bool,
+    pub uniform_buffer_update_after_bind: bool,
+    pub storage_buffer_update_after_bind: bool,
+    pub input_attachment_dynamic_indexing: bool,
+    pub storage_texel_buffer_dynamic_indexing: bool,
+    pub uniform_texel_buffer_dynamic_indexing: bool,
+}
+
+impl DescriptorIndexing {
+    fn from_features(features: &PhysicalDeviceDescriptorIndexingFeatures) -> Self {
+        Self {
+            partially_bound: features.descriptor_binding_partially_bound != 0,
+            runtime_descriptor_array: features.runtime_descriptor_array != 0,
+            sampled_image_non_uniform_indexing: features
+                .shader_sampled_image_array_non_uniform_indexing
+                != 0,
+            sampled_image_update_after_bind: features
+                .descriptor_binding_sampled_image_update_after_bind
+                != 0,
+            uniform_buffer_update_after_bind: features
+                .descriptor_binding_uniform_buffer_update_after_bind
+                != 0,
+            storage_buffer_update_after_bind: features
+                .descriptor_binding_storage_buffer_update_after_bind
+                != 0,
+            input_attachment_dynamic_indexing: features
+                .shader_input_attachment_array_dynamic_indexing
+                != 0,
+            storage_texel_buffer_dynamic_indexing: features
+                .shader_storage_texel_buffer_array_dynamic_indexing
+                != 0,
+            uniform_texel_buffer_dynamic_indexing: features
+                .shader_uniform_texel_buffer_array_dynamic_indexing
+                != 0,
+        }
+    }
+
+    fn to_features(self) -> PhysicalDeviceDescriptorIndexingFeatures<'static> {
+        PhysicalDeviceDescriptorIndexingFeatures::default()
+            .descriptor_binding_partially_bound(self.partially_bound)
+            .runtime_descriptor_array(self.runtime_descriptor_array)
+            .shader_sampled_image_array_non_uniform_indexing(
+                self.sampled_image_non_uniform_indexing,
+            )
+            .descriptor_binding_sampled_image_update_after_bind(
+                self.sampled_image_update_after_bind,
+            )
+            .descriptor_binding_uniform_buffer_update_after_bind(
+                self.uniform_buffer_update_after_bind,
+            )
+            .descriptor_binding_storage_buffer_update_after_bind(
+                self.storage_buffer_update_after_bind,
+            )
+            .shader_input_attachment_array_dynamic_indexing(self.input_attachment_dynamic_indexing)
+            .shader_storage_texel_buffer_array_dynamic_indexing(
+                self.storage_texel_buffer_dynamic_indexing,
+            )
+            .shader_uniform_texel_buffer_array_dynamic_indexing(
+                self.uniform_texel_buffer_dynamic_indexing,
+            )
+    }
+}
+
+/// Formats whose optimal tiling features are queried into [`DeviceCapabilities`], the ones
+/// render targets, depth buffers and compressed textures are usually made of.
+pub const KEY_FORMATS: [vk::Format; 26] = [
+    vk::Format::R8_UNORM,
+    vk::Format::R8G8_UNORM,
+    vk::Format::R8G8B8A8_UNORM,
+    vk::Format::R8G8B8A8_SRGB,
+    vk::Format::B8G8R8A8_UNORM,
+    vk::Format::B8G8R8A8_SRGB,
+    vk::Format::A2B10G10R10_UNORM_PACK32,
+    vk::Format::B10G11R11_UFLOAT_PACK32,
+    vk::Format::R16_SFLOAT,
+    vk::Format::R16G16_SFLOAT,
+    vk::Format::R16G16B16A16_SFLOAT,
+    vk::Format::R32_UINT,
+    vk::Format::R32_SFLOAT,
+    vk::Format::R32G32_SFLOAT,
+    vk::Format::R32G32B32A32_SFLOAT,
+    vk::Format::D16_UNORM,
+    vk::Format::D32_SFLOAT,
+    vk::Format::D24_UNORM_S8_UINT,
+    vk::Format::D32_SFLOAT_S8_UINT,
+    vk::Format::BC1_RGBA_SRGB_BLOCK,
+    vk::Format::BC5_UNORM_BLOCK,
+    vk::Format::BC7_UNORM_BLOCK,
+    vk::Format::BC7_SRGB_BLOCK,
+    vk::Format::ASTC_4X4_SRGB_BLOCK,
+    vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+    vk::Format::E5B9G9R9_UFLOAT_PACK32,
+];
+
+/// Optional features and extensions that ended up enabled, on top of the ones the renderer
+/// can't run without.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EnabledFeatures {
+    pub acceleration_structure: bool,
+    pub ray_tracing_pipeline: bool,
+    pub ray_query: bool,
+    pub multiview: bool,
+    pub shader_printf: bool,
+    pub scalar_block_layout: bool,
+    pub robustness: bool,
+    pub sample_rate_shading: bool,
+    pub fill_mode_non_solid: bool,
+    pub shader_clock: bool,
+    pub cooperative_matrix: bool,
+    pub shading_rate_image: bool,
+    pub line_rasterization: bool,
+    pub mesh_shader: bool,
+    pub external_interop: bool,
+    pub rebar: bool,
+    pub present_wait: bool,
+    pub hdr_metadata: bool,
+    pub video_decode_h264: bool,
+    pub video_decode_h265: bool,
+    pub async_compute_queue: bool,
+    pub transfer_queue: bool,
+}
+
+/// How many descriptors update-after-bind sets like the bindless textures can hold.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BindlessLimits {
+    /// Size of the bindless arrays the renderer declares, see
+    /// [`ExampleBase::max_descriptor_count`].
+    pub descriptor_count: u32,
+    pub max_descriptors_in_all_pools: u32,
+    pub max_per_stage_samplers: u32,
+    pub max_per_stage_sampled_images: u32,
+    pub max_per_stage_storage_images: u32,
+    pub max_per_stage_storage_buffers: u32,
+    pub max_per_stage_uniform_buffers: u32,
+    pub max_per_stage_resources: u32,
+    pub max_set_sampled_images: u32,
+    pub max_set_storage_images: u32,
+    pub max_set_storage_buffers: u32,
+}
+
+impl BindlessLimits {
+    fn from_properties(
+        properties: &vk::PhysicalDeviceDescriptorIndexingProperties,
+        descriptor_count: u32,
+    ) -> Self {
+        Self {
+            descriptor_count,
+            max_descriptors_in_all_pools: properties.max_update_after_bind_descriptors_in_all_pools,
+            max_per_stage_samplers: properties.max_per_stage_descriptor_update_after_bind_samplers,
+            max_per_stage_sampled_images: properties
+                .max_per_stage_descriptor_update_after_bind_sampled_images,
+            max_per_stage_storage_images: properties
+                .max_per_stage_descriptor_update_after_bind_storage_images,
+            max_per_stage_storage_buffers: properties
+                .max_per_stage_descriptor_update_after_bind_storage_buffers,
+            max_per_stage_uniform_buffers: properties
+                .max_per_stage_descriptor_update_after_bind_uniform_buffers,
+            max_per_stage_resources: properties.max_per_stage_update_after_bind_resources,
+            max_set_sampled_images: properties.max_descriptor_set_update_after_bind_sampled_images,
+            max_set_storage_images: properties.max_descriptor_set_update_after_bind_storage_images,
+            max_set_storage_buffers: properties
+                .max_descriptor_set_update_after_bind_storage_buffers,
+        }
+    }
+}
+
+/// What the device was created with, for code that has to work around missing features or
+/// branch on limits without querying the device again.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceCapabilities {
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: u32,
+    pub limits: vk::PhysicalDeviceLimits,
+    pub features: EnabledFeatures,
+    pub descriptor_indexing: DescriptorIndexing,
+    pub bindless: BindlessLimits,
+    pub subgroup: SubgroupSupport,
+    pub small_types: SmallTypes,
+    /// Optimal tiling features of the [`KEY_FORMATS`].
+    pub formats: HashMap<vk::Format, vk::FormatFeatureFlags>,
+}
+
+impl DeviceCapabilities {
+    /// Optimal tiling features of `format`, empty for formats that aren't in [`KEY_FORMATS`].
+    pub fn format_features(&self, format: vk::Format) -> vk::FormatFeatureFlags {
+        self.formats.get(&format).copied().unwrap_or_default()
+    }
+
+    pub fn supports_format(&self, format: vk::Format, features: vk::FormatFeatureFlags) -> bool {
+        self.format_features(format).contains(features)
+    }
+
+    /// The [`KEY_FORMATS`] with all of `features`, in the order of the list.
+    pub fn formats_with(&self, features: vk::FormatFeatureFlags) -> Vec<vk::Format> {
+        KEY_FORMATS
+            .into_iter()
+            .filter(|format| self.supports_format(*format, features))
+            .collect()
+    }
+
+    /// The first of `candidates` with all of `features`, like a depth format that can be
+    /// sampled.
+    pub fn first_supported_format(
+        &self,
+        candidates: &[vk::Format],
+        features: vk::FormatFeatureFlags,
+    ) -> Option<vk::Format> {
+        candidates
+            .iter()
+            .copied()
+            .find(|format| self.supports_format(*format, features))
+    }
+}
+
+/// Extra requirements for the device created by [`ExampleBase::new`], like the extensions an
+/// OpenXR runtime needs. Insert it as a resource before the render plugin is added.
+#[derive(Resource, Default)]
+pub struct DeviceRequirements {
+    pub instance_extensions: Vec<CString>,
+    pub device_extensions: Vec<CString>,
+    /// Enables multiview, to render both eyes of a stereo view in a single pass.
+    pub multiview: bool,
+    /// Prefers an HDR10 swapchain, encoded with the ST 2084 (PQ) curve, when the surface offers
+    /// one. See [`ExampleBase::hdr_output`].
+    pub hdr_output: bool,
+    /// Formats and color spaces for the swapchain, the first one the surface supports is used
+    /// before `hdr_output` is considered. Which one it ended up with is
+    /// [`ExampleBase::surface_format`].
+    pub surface_formats: Vec<vk::SurfaceFormatKHR>,
+    /// Turns on `debugPrintfEXT` in shaders through the validation layer, which is then loaded
+    /// in release builds too. Shaders are compiled with `SHADER_PRINTF` defined, and what they
+    /// print is logged with the `shader_printf` target.
+    pub shader_printf: bool,
+    /// Enables `VK_KHR_cooperative_matrix` when the device supports it, together with the
+    /// Vulkan memory model, `float16` and `storage_buffer_16bit` the matrix shaders need. See
+    /// [`ExampleBase::cooperative_matrix`].
+    pub cooperative_matrix: bool,
+    /// Enabled as far as the device supports them, see [`ExampleBase::small_types`].
+    pub small_types: SmallTypes,
+    /// Enables `scalarBlockLayout` when the device supports it, see
+    /// [`ExampleBase::scalar_block_layout`].
+    pub scalar_block_layout: bool,
+    /// Instruments shaders through the validation layer, which then catches out of bounds
+    /// accesses and unwritten descriptors on the GPU. Slow, and loads the layer like
+    /// `shader_printf` does.
+    pub gpu_assisted_validation: bool,
+    /// Enables `VK_EXT_robustness2` when the device supports it, see
+    /// [`ExampleBase::robustness`].
+    pub robustness: bool,
+    /// Picks the physical device, instead of the first one that can present to the window. An
+    /// error fails the creation of the [`ExampleBase`].
+    #[allow(clippy::type_complexity)]
+    pub physical_device: Option<Box<dyn Fn(&Instance) -> Result<vk::PhysicalDevice> + Send + Sync>>,
 }
 
 pub struct ExampleBase {
@@ -191,47 +844,258 @@ pub struct ExampleBase {
     pub device: Device,
     pub synchronization2: Synchronization2,
     pub dynamic_rendering: DynamicRendering,
+    pub draw_indirect_count: DrawIndirectCount,
+    /// Only present when the device supports `VK_KHR_acceleration_structure`.
+    pub acceleration_structure: Option<AccelerationStructure>,
+    pub min_acceleration_structure_scratch_offset_alignment: u32,
+    /// Only present when the device supports `VK_KHR_ray_tracing_pipeline`.
+    pub ray_tracing_pipeline: Option<RayTracingPipeline>,
+    pub shader_group_handle_size: u32,
+    pub shader_group_handle_alignment: u32,
+    pub shader_group_base_alignment: u32,
+    /// `VK_KHR_ray_query` is enabled, so shaders can use `rayQueryEXT`.
+    pub supports_ray_query: bool,
+    /// Multiview was requested through [`DeviceRequirements`].
+    pub multiview: bool,
+    /// Created by [`ExampleBase::new_headless`], nothing can be presented.
+    pub headless: bool,
     pub surface_loader: Surface,
     pub swapchain_loader: Swapchain,
     pub debug_utils_loader: DebugUtils,
     pub debug_call_back: vk::DebugUtilsMessengerEXT,
     pub immutable_samplers: HashMap<SamplerDesc, vk::Sampler>,
     pub max_descriptor_count: u32,
+    pub capabilities: DeviceCapabilities,
     pub command_thread_pool: ThreadPool,
-    pub threaded_command_buffers: Arc<RwLock<HashMap<usize, CommandBuffer>>>,
+    /// Secondary command buffers per thread of `command_thread_pool`, one for every frame in
+    /// flight indexed like [`ExampleBase::frames`].
+    pub threaded_command_buffers: Arc<RwLock<HashMap<usize, Vec<CommandBuffer>>>>,
 
     pub pdevice: vk::PhysicalDevice,
     pub device_memory_properties: vk::PhysicalDeviceMemoryProperties,
     pub queue_family_index: u32,
     pub present_queue: vk::Queue,
+    /// Queue of a compute-only family, when the device has one.
+    pub async_compute_queue: Option<vk::Queue>,
+    pub async_compute_queue_family_index: Option<u32>,
+    /// Queue of a transfer-only family, when the device has one.
+    pub transfer_queue: Option<vk::Queue>,
+    pub transfer_queue_family_index: Option<u32>,
+    /// Queue of a family with video decode support, when `VK_KHR_video_decode_queue` and at
+    /// least one of the H.264 and H.265 decode extensions are available.
+    pub video_decode_queue: Option<vk::Queue>,
+    pub video_decode_queue_family_index: Option<u32>,
+    pub supports_video_decode_h264: bool,
+    pub supports_video_decode_h265: bool,
+    /// Memory and timeline semaphores can be exported to other APIs, like CUDA, through the
+    /// opaque fd or win32 handle extensions.
+    pub supports_external_interop: bool,
+    /// The whole of video memory can be mapped (resizable BAR), see
+    /// [`crate::buffer::set_rebar_available`].
+    pub supports_rebar: bool,
+    /// `sampleRateShading` is enabled, so pipelines can set a minimum sample shading.
+    /// Pixels covered by a texel of a shading rate image, only set when
+    /// `VK_KHR_fragment_shading_rate` supports attachments.
+    pub shading_rate_texel_size: Option<vk::Extent2D>,
+    /// Only present when the device supports `VK_EXT_line_rasterization`.
+    pub line_rasterization: Option<vk::ExtLineRasterizationFn>,
+    pub line_support: LineSupport,
+    /// Only present when the device supports task and mesh shaders through
+    /// `VK_EXT_mesh_shader`.
+    pub mesh_shader: Option<MeshShader>,
+    pub supports_sample_rate_shading: bool,
+    /// `PolygonMode::LINE` and `POINT`, needed for wireframes.
+    pub supports_fill_mode_non_solid: bool,
+    /// `debugPrintfEXT` was requested through [`DeviceRequirements`].
+    pub shader_printf: bool,
+    pub subgroup: SubgroupSupport,
+    /// What was enabled of [`DeviceRequirements::small_types`], plus what the cooperative
+    /// matrix needs.
+    pub small_types: SmallTypes,
+    /// Blocks can be declared with `layout(scalar)`, which packs them like `repr(C)` structs
+    /// without std140 or std430 padding. Shaders are compiled with `SCALAR_BLOCK_LAYOUT`
+    /// defined, see `shader/someday/buffer_reference.glsl` and
+    /// [`BlockLayout::Scalar`](crate::render::glsl_struct::BlockLayout::Scalar).
+    pub scalar_block_layout: bool,
+    /// Matrix sizes and types shaders can use with `GL_KHR_cooperative_matrix`, empty unless
+    /// requested through [`DeviceRequirements`] and supported.
+    pub cooperative_matrix: Vec<CooperativeMatrixConfig>,
+    /// `VK_KHR_shader_clock` is enabled, shaders are compiled with `SHADER_CLOCK` defined and
+    /// can time themselves with the helpers in `shader/clock.glsl`.
+    pub supports_shader_clock: bool,
+    /// Out of bounds buffer and image accesses return zero instead of undefined values, and
+    /// descriptors can be written with null handles, which keeps the partially bound sets safe
+    /// when a shader reads a binding that was never written.
+    pub robustness: bool,
+    /// Only present when an HDR output was requested and the device supports
+    /// `VK_EXT_hdr_metadata`.
+    pub hdr_metadata: Option<vk::ExtHdrMetadataFn>,
+    /// Only present when the device supports `VK_KHR_present_id` and `VK_KHR_present_wait`.
+    pub present_wait: Option<PresentWait>,
+    /// Id of the last present, ids are only passed to the swapchain when `present_wait` is set.
+    pub present_id: AtomicU64,
 
     pub surface: vk::SurfaceKHR,
+    /// Format of the swapchain, from [`DeviceRequirements::surface_formats`] when the surface
+    /// supports one of them.
     pub surface_format: vk::SurfaceFormatKHR,
     pub surface_resolution: vk::Extent2D,
 
     pub swapchain: vk::SwapchainKHR,
+    pub swapchain_image_usage: vk::ImageUsageFlags,
     pub present_images: Vec<vk::Image>,
     pub present_image_views: Vec<vk::ImageView>,
 
     pub pool: vk::CommandPool,
-    pub draw_command_buffer: vk::CommandBuffer,
     pub setup_command_buffer: vk::CommandBuffer,
+    /// One per [`RendererConfig::frames_in_flight`], see [`ExampleBase::begin_frame`].
+    pub frames: Vec<FrameContext>,
+    frame_index: AtomicUsize,
 
     pub depth_image: vk::Image,
     pub depth_image_view: vk::ImageView,
     pub depth_image_memory: vk::DeviceMemory,
     pub depth_image_format: vk::Format,
 
-    pub present_complete_semaphore: vk::Semaphore,
-    pub rendering_complete_semaphore: vk::Semaphore,
+    /// One per image of the swapchain, signaled by the frame rendering into it and waited on
+    /// by its present. An image isn't acquired again before its present is done, while a frame
+    /// context can be reused before that.
+    pub rendering_complete_semaphores: Vec<vk::Semaphore>,
 
-    pub draw_commands_reuse_fence: vk::Fence,
     pub setup_commands_reuse_fence: vk::Fence,
+
+    /// Resources referred to by handle, see [`ResourceRegistry`].
+    pub registry: RwLock<ResourceRegistry>,
+    /// Submitting to or presenting on `present_queue` has to be externally synchronized.
+    pub queue_lock: Mutex<()>,
+    /// Free list for [`ExampleBase::submit_setup_commands`], so threads don't share command pools.
+    setup_contexts: Mutex<Vec<SetupContext>>,
+    /// Shared by all pipelines, freeing sets has to be externally synchronized as well.
+    pub descriptor_allocator: Mutex<DescriptorAllocator>,
+    /// Uploads of the frame, flushed by the graphics submission.
+    pub staging_belt: Mutex<StagingBelt>,
+    /// Shared with the [`RenderAllocator`](crate::render::RenderAllocator) once it's created, so
+    /// the resources still waiting to be destroyed can be destroyed on drop.
+    pub(crate) allocator: OnceLock<Arc<Mutex<Allocator>>>,
+    /// Destroy the buffers, images and shader modules that are still alive on drop, after they
+    /// are reported. Enabled by default.
+    pub destroy_leaked_resources: AtomicBool,
+    /// What the renderer was created with, environment variables included.
+    pub config: RendererConfig,
+    /// Passed to every pipeline that's created, saved to
+    /// [`RendererConfig::shader_cache_dir`] on drop.
+    pub pipeline_cache: vk::PipelineCache,
+}
+
+/// What a frame is recorded into while the GPU may still be executing the frames before it.
+#[derive(Debug)]
+pub struct FrameContext {
+    pub command_buffer: vk::CommandBuffer,
+    /// Signaled once the submission of the frame is done, the context can be reused after.
+    pub fence: vk::Fence,
+    /// Signaled once the swapchain image acquired for the frame can be rendered to.
+    pub image_available: vk::Semaphore,
+}
+
+struct SetupContext {
+    pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+}
+
+impl SetupContext {
+    fn new(device: &Device, queue_family_index: u32) -> Result<Self> {
+        unsafe {
+            let pool = device.create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+                    .queue_family_index(queue_family_index),
+                None,
+            )?;
+            let command_buffer = device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::default()
+                    .command_buffer_count(1)
+                    .command_pool(pool)
+                    .level(vk::CommandBufferLevel::PRIMARY),
+            )?[0];
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            Ok(Self {
+                pool,
+                command_buffer,
+                fence,
+            })
+        }
+    }
+
+    unsafe fn submit<F: FnOnce(&Device, vk::CommandBuffer)>(
+        &self,
+        base: &ExampleBase,
+        f: F,
+    ) -> Result<()> {
+        let device = &base.device;
+        device.reset_fences(&[self.fence])?;
+        device.reset_command_pool(self.pool, vk::CommandPoolResetFlags::empty())?;
+
+        device.begin_command_buffer(
+            self.command_buffer,
+            &vk::CommandBufferBeginInfo::default()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+        )?;
+        f(device, self.command_buffer);
+        device.end_command_buffer(self.command_buffer)?;
+
+        let command_buffers = [self.command_buffer];
+        let submit_info = vk::SubmitInfo::default().command_buffers(&command_buffers);
+        {
+            let _queue = base.queue_lock.lock().unwrap();
+            device.queue_submit(base.present_queue, &[submit_info], self.fence)?;
+        }
+        // only this submission is waited on, other threads can keep using the queue
+        device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+        Ok(())
+    }
+
+    unsafe fn destroy(&self, device: &Device) {
+        device.destroy_fence(self.fence, None);
+        device.destroy_command_pool(self.pool, None);
+    }
 }
 
 impl ExampleBase {
-    pub fn new(window: &RawHandleWrapper, present_mode: PresentMode) -> Self {
+    /// Fails with the [`Error`](crate::error::Error) of `crate::error` when the device lacks
+    /// what `requirements` asks for or a Vulkan call fails, like every other fallible call of
+    /// the renderer.
+    pub fn new(
+        window: &RawHandleWrapper,
+        present_mode: PresentMode,
+        requirements: &DeviceRequirements,
+        config: &RendererConfig,
+    ) -> Result<Self> {
+        Self::create(Some(window), present_mode, requirements, config)
+    }
+
+    /// Creates the device without a window, surface or swapchain, for running compute work on
+    /// a headless machine with the buffers and shaders of this crate. `present_queue` is then
+    /// a compute queue, of a compute-only family when the device has one, and the swapchain
+    /// and depth image handles are null.
+    pub fn new_headless(
+        requirements: &DeviceRequirements,
+        config: &RendererConfig,
+    ) -> Result<Self> {
+        Self::create(None, PresentMode::Fifo, requirements, config)
+    }
+
+    fn create(
+        window: Option<&RawHandleWrapper>,
+        present_mode: PresentMode,
+        requirements: &DeviceRequirements,
+        config: &RendererConfig,
+    ) -> Result<Self> {
+        let present_mode = config.vsync.unwrap_or(present_mode);
         unsafe {
+            #[cfg(feature = "renderdoc")]
+            renderdoc();
+
             let entry = Entry::linked();
             let app_name = CStr::from_bytes_with_nul_unchecked(b"VulkanTriangle\0");
 
@@ -239,12 +1103,25 @@ impl ExampleBase {
                 b"VK_LAYER_KHRONOS_synchronization2\0",
             )];
 
-            if cfg!(debug_assertions) {
-                println!("{:?}", "Debug mode: enable validation layers");
-
-                layer_names.push(CStr::from_bytes_with_nul_unchecked(
-                    b"VK_LAYER_KHRONOS_validation\0",
-                ))
+            let validation_layer =
+                CStr::from_bytes_with_nul_unchecked(b"VK_LAYER_KHRONOS_validation\0");
+            if config.validation {
+                println!("{:?}", "Enable validation layers");
+
+                layer_names.push(validation_layer)
+            } else if requirements.shader_printf || requirements.gpu_assisted_validation {
+                let has_validation_layer = entry
+                    .enumerate_instance_layer_properties()?
+                    .iter()
+                    .any(|layer| CStr::from_ptr(layer.layer_name.as_ptr()) == validation_layer);
+                if !has_validation_layer {
+                    return Err(Error::Unsupported(
+                        "Shader printf and GPU-assisted validation need the \
+                         VK_LAYER_KHRONOS_validation layer"
+                            .to_string(),
+                    ));
+                }
+                layer_names.push(validation_layer);
             }
 
             let layers_names_raw: Vec<*const c_char> = layer_names
@@ -252,11 +1129,37 @@ impl ExampleBase {
                 .map(|raw_name| raw_name.as_ptr())
                 .collect();
 
-            let mut extension_names =
-                ash_window::enumerate_required_extensions(window.display_handle)
-                    .unwrap()
-                    .to_vec();
+            let mut extension_names = match window {
+                Some(window) => {
+                    ash_window::enumerate_required_extensions(window.display_handle)?.to_vec()
+                }
+                None => vec![],
+            };
             extension_names.push(DebugUtils::NAME.as_ptr());
+            if requirements.shader_printf || requirements.gpu_assisted_validation {
+                // provided by the validation layer
+                extension_names.push(vk::ExtValidationFeaturesFn::NAME.as_ptr());
+            }
+            for name in requirements.instance_extensions.iter() {
+                if !extension_names
+                    .iter()
+                    .any(|existing| CStr::from_ptr(*existing) == name.as_c_str())
+                {
+                    extension_names.push(name.as_ptr());
+                }
+            }
+            if requirements.hdr_output && window.is_some() {
+                let supports_colorspace = entry
+                    .enumerate_instance_extension_properties(None)?
+                    .iter()
+                    .any(|extension| {
+                        CStr::from_ptr(extension.extension_name.as_ptr())
+                            == vk::ExtSwapchainColorspaceFn::NAME
+                    });
+                if supports_colorspace {
+                    extension_names.push(vk::ExtSwapchainColorspaceFn::NAME.as_ptr());
+                }
+            }
             #[cfg(any(target_os = "macos", target_os = "ios"))]
             {
                 extension_names.push(KhrPortabilityEnumerationFn::NAME.as_ptr());
@@ -277,15 +1180,29 @@ impl ExampleBase {
                 vk::InstanceCreateFlags::default()
             };
 
-            let create_info = vk::InstanceCreateInfo::default()
+            let mut enabled_validation_features = vec![];
+            if requirements.shader_printf {
+                enabled_validation_features.push(vk::ValidationFeatureEnableEXT::DEBUG_PRINTF);
+                shaders::define_global("SHADER_PRINTF", "1");
+            }
+            if requirements.gpu_assisted_validation {
+                enabled_validation_features.extend([
+                    vk::ValidationFeatureEnableEXT::GPU_ASSISTED,
+                    vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT,
+                ]);
+            }
+            let mut validation_features = vk::ValidationFeaturesEXT::default()
+                .enabled_validation_features(&enabled_validation_features);
+            let mut create_info = vk::InstanceCreateInfo::default()
                 .application_info(&appinfo)
                 .enabled_layer_names(&layers_names_raw)
                 .enabled_extension_names(&extension_names)
                 .flags(create_flags);
+            if !enabled_validation_features.is_empty() {
+                create_info = create_info.push_next(&mut validation_features);
+            }
 
-            let instance: Instance = entry
-                .create_instance(&create_info, None)
-                .expect("Instance creation error");
+            let instance: Instance = entry.create_instance(&create_info, None)?;
 
             let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::default()
                 .message_severity(
@@ -301,62 +1218,467 @@ impl ExampleBase {
                 .pfn_user_callback(Some(vulkan_debug_callback));
 
             let debug_utils_loader = DebugUtils::new(&entry, &instance);
-            let debug_call_back = debug_utils_loader
-                .create_debug_utils_messenger(&debug_info, None)
-                .unwrap();
-            let surface = ash_window::create_surface(
-                &entry,
-                &instance,
-                window.get_display_handle(),
-                window.get_window_handle(),
-                None,
-            )
-            .unwrap();
-            let pdevices = instance
-                .enumerate_physical_devices()
-                .expect("Physical device error");
+            let debug_call_back =
+                debug_utils_loader.create_debug_utils_messenger(&debug_info, None)?;
+            let surface = match window {
+                Some(window) => ash_window::create_surface(
+                    &entry,
+                    &instance,
+                    window.get_display_handle(),
+                    window.get_window_handle(),
+                    None,
+                )?,
+                None => vk::SurfaceKHR::null(),
+            };
+            let mut pdevices = instance.enumerate_physical_devices()?;
             let surface_loader = Surface::new(&entry, &instance);
+            let required_pdevice = requirements
+                .physical_device
+                .as_ref()
+                .map(|select| select(&instance))
+                .transpose()?;
+            if let Some(preferred_gpu) = &config.preferred_gpu {
+                // tried first, the order of the others is kept
+                let preferred = pdevices.iter().enumerate().position(|(index, pdevice)| {
+                    let properties = instance.get_physical_device_properties(*pdevice);
+                    let name = CStr::from_ptr(properties.device_name.as_ptr());
+                    config.is_preferred_gpu(index, &name.to_string_lossy())
+                });
+                match preferred {
+                    Some(position) => pdevices[..=position].rotate_right(1),
+                    None => println!("No GPU matches {:?}, using the default", preferred_gpu),
+                }
+            }
             let (pdevice, queue_family_index) = pdevices
                 .iter()
+                .filter(|pdevice| required_pdevice.map_or(true, |required| required == **pdevice))
                 .find_map(|pdevice| {
-                    instance
-                        .get_physical_device_queue_family_properties(*pdevice)
-                        .iter()
-                        .enumerate()
-                        .find_map(|(index, info)| {
-                            let supports_graphic_and_surface =
-                                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                                    && surface_loader
-                                        .get_physical_device_surface_support(
-                                            *pdevice,
-                                            index as u32,
-                                            surface,
-                                        )
-                                        .unwrap();
-                            if supports_graphic_and_surface {
-                                Some((*pdevice, index))
-                            } else {
-                                None
-                            }
-                        })
+                    let families = instance.get_physical_device_queue_family_properties(*pdevice);
+                    if window.is_none() {
+                        return families
+                            .iter()
+                            .position(|info| {
+                                info.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                                    && !info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                            })
+                            .or_else(|| {
+                                families.iter().position(|info| {
+                                    info.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                                })
+                            })
+                            .map(|index| (*pdevice, index));
+                    }
+                    families.iter().enumerate().find_map(|(index, info)| {
+                        let supports_graphic_and_surface =
+                            info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                                && surface_loader
+                                    .get_physical_device_surface_support(
+                                        *pdevice,
+                                        index as u32,
+                                        surface,
+                                    )
+                                    .unwrap_or(false);
+                        if supports_graphic_and_surface {
+                            Some((*pdevice, index))
+                        } else {
+                            None
+                        }
+                    })
                 })
-                .expect("Couldn't find suitable device.");
+                .ok_or_else(|| Error::Unsupported("Couldn't find suitable device.".to_string()))?;
 
             let device_properties = instance.get_physical_device_properties(pdevice);
             let queue_family_index = queue_family_index as u32;
-            let device_extension_names_raw = [
-                Swapchain::NAME.as_ptr(),
+            let supported_extensions = instance.enumerate_device_extension_properties(pdevice)?;
+            let supports_extension = |name: &CStr| {
+                supported_extensions
+                    .iter()
+                    .any(|extension| CStr::from_ptr(extension.extension_name.as_ptr()) == name)
+            };
+            let supports_acceleration_structure = supports_extension(AccelerationStructure::NAME)
+                && supports_extension(DeferredHostOperations::NAME);
+            let supports_ray_tracing_pipeline =
+                supports_acceleration_structure && supports_extension(RayTracingPipeline::NAME);
+            let supports_ray_query =
+                supports_acceleration_structure && supports_extension(KhrRayQueryFn::NAME);
+            let video_decode_queue_family_index = if supports_extension(KhrVideoQueueFn::NAME)
+                && supports_extension(KhrVideoDecodeQueueFn::NAME)
+            {
+                instance
+                    .get_physical_device_queue_family_properties(pdevice)
+                    .iter()
+                    .position(|info| info.queue_flags.contains(vk::QueueFlags::VIDEO_DECODE_KHR))
+                    .map(|index| index as u32)
+            } else {
+                None
+            };
+            let supports_video_decode_h264 = video_decode_queue_family_index.is_some()
+                && supports_extension(KhrVideoDecodeH264Fn::NAME);
+            let supports_video_decode_h265 = video_decode_queue_family_index.is_some()
+                && supports_extension(KhrVideoDecodeH265Fn::NAME);
+            let video_decode_queue_family_index = video_decode_queue_family_index
+                .filter(|_| supports_video_decode_h264 || supports_video_decode_h265);
+            #[cfg(unix)]
+            let external_interop_extensions =
+                [KhrExternalMemoryFdFn::NAME, KhrExternalSemaphoreFdFn::NAME];
+            #[cfg(windows)]
+            let external_interop_extensions = [
+                KhrExternalMemoryWin32Fn::NAME,
+                KhrExternalSemaphoreWin32Fn::NAME,
+            ];
+            let mut timeline_semaphore_support =
+                vk::PhysicalDeviceTimelineSemaphoreFeatures::default();
+            instance.get_physical_device_features2(
+                pdevice,
+                &mut vk::PhysicalDeviceFeatures2::default()
+                    .push_next(&mut timeline_semaphore_support),
+            );
+            let supports_external_interop = timeline_semaphore_support.timeline_semaphore != 0
+                && external_interop_extensions
+                    .iter()
+                    .all(|name| supports_extension(name));
+            let mut present_id_support = vk::PhysicalDevicePresentIdFeaturesKHR::default();
+            let mut present_wait_support = vk::PhysicalDevicePresentWaitFeaturesKHR::default();
+            if supports_extension(KhrPresentIdFn::NAME) && supports_extension(PresentWait::NAME) {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default()
+                        .push_next(&mut present_id_support)
+                        .push_next(&mut present_wait_support),
+                );
+            }
+            let supports_present_wait = window.is_some()
+                && present_id_support.present_id != 0
+                && present_wait_support.present_wait != 0;
+            let mut shading_rate_support =
+                vk::PhysicalDeviceFragmentShadingRateFeaturesKHR::default();
+            if supports_extension(KhrFragmentShadingRateFn::NAME) {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default()
+                        .push_next(&mut shading_rate_support),
+                );
+            }
+            let supports_shading_rate_image = shading_rate_support.pipeline_fragment_shading_rate
+                != 0
+                && shading_rate_support.attachment_fragment_shading_rate != 0;
+
+            let supports_line_rasterization = supports_extension(vk::ExtLineRasterizationFn::NAME);
+            let mut mesh_shader_support = vk::PhysicalDeviceMeshShaderFeaturesEXT::default();
+            if supports_extension(MeshShader::NAME) {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default().push_next(&mut mesh_shader_support),
+                );
+            }
+            let supports_mesh_shader =
+                mesh_shader_support.task_shader != 0 && mesh_shader_support.mesh_shader != 0;
+            let supports_hdr_metadata = requirements.hdr_output
+                && window.is_some()
+                && supports_extension(vk::ExtHdrMetadataFn::NAME);
+            let mut robustness2_support = vk::PhysicalDeviceRobustness2FeaturesEXT::default();
+            if requirements.robustness && supports_extension(vk::ExtRobustness2Fn::NAME) {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default().push_next(&mut robustness2_support),
+                );
+            }
+            let mut shader_clock_support = vk::PhysicalDeviceShaderClockFeaturesKHR::default();
+            if supports_extension(vk::KhrShaderClockFn::NAME) {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default()
+                        .push_next(&mut shader_clock_support),
+                );
+            }
+            let supports_shader_clock = shader_clock_support.shader_subgroup_clock != 0;
+            if supports_shader_clock {
+                shaders::define_global("SHADER_CLOCK", "1");
+            }
+            let mut subgroup_properties = vk::PhysicalDeviceSubgroupProperties::default();
+            let mut subgroup_size_control_properties =
+                vk::PhysicalDeviceSubgroupSizeControlProperties::default();
+            let mut subgroup_size_control_support =
+                vk::PhysicalDeviceSubgroupSizeControlFeatures::default();
+            let supports_subgroup_size_control =
+                supports_extension(vk::ExtSubgroupSizeControlFn::NAME);
+            if supports_subgroup_size_control {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default()
+                        .push_next(&mut subgroup_size_control_support),
+                );
+                instance.get_physical_device_properties2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceProperties2::default()
+                        .push_next(&mut subgroup_properties)
+                        .push_next(&mut subgroup_size_control_properties),
+                );
+            } else {
+                instance.get_physical_device_properties2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceProperties2::default()
+                        .push_next(&mut subgroup_properties),
+                );
+            }
+            let size_control = subgroup_size_control_support.subgroup_size_control != 0
+                && subgroup_size_control_support.compute_full_subgroups != 0;
+            let subgroup = SubgroupSupport {
+                size: subgroup_properties.subgroup_size,
+                min_size: if size_control {
+                    subgroup_size_control_properties.min_subgroup_size
+                } else {
+                    subgroup_properties.subgroup_size
+                },
+                max_size: if size_control {
+                    subgroup_size_control_properties.max_subgroup_size
+                } else {
+                    subgroup_properties.subgroup_size
+                },
+                stages: subgroup_properties.supported_stages,
+                operations: subgroup_properties.supported_operations,
+                size_control,
+            };
+            for (name, value) in subgroup.defines() {
+                shaders::define_global(&name, &value);
+            }
+            let mut float16_int8_support = vk::PhysicalDeviceShaderFloat16Int8Features::default();
+            let mut storage_16bit_support = vk::PhysicalDevice16BitStorageFeatures::default();
+            let mut storage_8bit_support = vk::PhysicalDevice8BitStorageFeatures::default();
+            instance.get_physical_device_features2(
+                pdevice,
+                &mut vk::PhysicalDeviceFeatures2::default()
+                    .push_next(&mut float16_int8_support)
+                    .push_next(&mut storage_16bit_support)
+                    .push_next(&mut storage_8bit_support),
+            );
+            let small_type_support = SmallTypes {
+                float16: float16_int8_support.shader_float16 != 0,
+                int8: float16_int8_support.shader_int8 != 0,
+                storage_buffer_16bit: storage_16bit_support.storage_buffer16_bit_access != 0,
+                storage_buffer_8bit: storage_8bit_support.storage_buffer8_bit_access != 0,
+            };
+
+            let mut cooperative_matrix_support =
+                vk::PhysicalDeviceCooperativeMatrixFeaturesKHR::default();
+            let mut memory_model_support = vk::PhysicalDeviceVulkanMemoryModelFeatures::default();
+            if requirements.cooperative_matrix
+                && supports_extension(vk::KhrCooperativeMatrixFn::NAME)
+            {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default()
+                        .push_next(&mut cooperative_matrix_support)
+                        .push_next(&mut memory_model_support),
+                );
+            }
+            let supports_cooperative_matrix = cooperative_matrix_support.cooperative_matrix != 0
+                && memory_model_support.vulkan_memory_model != 0
+                && small_type_support.float16
+                && small_type_support.storage_buffer_16bit;
+            let small_types = SmallTypes {
+                float16: small_type_support.float16
+                    && (requirements.small_types.float16 || supports_cooperative_matrix),
+                int8: small_type_support.int8 && requirements.small_types.int8,
+                storage_buffer_16bit: small_type_support.storage_buffer_16bit
+                    && (requirements.small_types.storage_buffer_16bit
+                        || supports_cooperative_matrix),
+                storage_buffer_8bit: small_type_support.storage_buffer_8bit
+                    && requirements.small_types.storage_buffer_8bit,
+            };
+            for name in small_types.defines() {
+                shaders::define_global(name, "1");
+            }
+            // core in Vulkan 1.2, the extension is only there for older drivers
+            let mut scalar_block_layout_support =
+                vk::PhysicalDeviceScalarBlockLayoutFeatures::default();
+            instance.get_physical_device_features2(
+                pdevice,
+                &mut vk::PhysicalDeviceFeatures2::default()
+                    .push_next(&mut scalar_block_layout_support),
+            );
+            let mut descriptor_indexing_support =
+                PhysicalDeviceDescriptorIndexingFeatures::default();
+            instance.get_physical_device_features2(
+                pdevice,
+                &mut vk::PhysicalDeviceFeatures2::default()
+                    .push_next(&mut descriptor_indexing_support),
+            );
+            let descriptor_indexing =
+                DescriptorIndexing::from_features(&descriptor_indexing_support);
+            if !descriptor_indexing.partially_bound || !descriptor_indexing.runtime_descriptor_array
+            {
+                println!(
+                    "The device lacks descriptor indexing features, shaders with bindless arrays won't work: {:?}",
+                    descriptor_indexing
+                );
+            }
+            let scalar_block_layout = requirements.scalar_block_layout
+                && scalar_block_layout_support.scalar_block_layout != 0;
+            if scalar_block_layout {
+                shaders::define_global("SCALAR_BLOCK_LAYOUT", "1");
+            }
+            let cooperative_matrix = if supports_cooperative_matrix {
+                let cooperative_matrix_fn = vk::KhrCooperativeMatrixFn::load(|name| {
+                    std::mem::transmute(
+                        entry.get_instance_proc_addr(instance.handle(), name.as_ptr()),
+                    )
+                });
+                let mut count = 0;
+                (cooperative_matrix_fn.get_physical_device_cooperative_matrix_properties_khr)(
+                    pdevice,
+                    &mut count,
+                    std::ptr::null_mut(),
+                )
+                .result()?;
+                let mut properties =
+                    vec![vk::CooperativeMatrixPropertiesKHR::default(); count as usize];
+                (cooperative_matrix_fn.get_physical_device_cooperative_matrix_properties_khr)(
+                    pdevice,
+                    &mut count,
+                    properties.as_mut_ptr(),
+                )
+                .result()?;
+                properties
+                    .iter()
+                    .take(count as usize)
+                    .map(|properties| CooperativeMatrixConfig {
+                        m: properties.m_size,
+                        n: properties.n_size,
+                        k: properties.k_size,
+                        a_type: properties.a_type,
+                        b_type: properties.b_type,
+                        c_type: properties.c_type,
+                        result_type: properties.result_type,
+                        saturating_accumulation: properties.saturating_accumulation != 0,
+                        scope: properties.scope,
+                    })
+                    .collect()
+            } else {
+                vec![]
+            };
+            let robustness = robustness2_support.robust_buffer_access2 != 0
+                && robustness2_support.robust_image_access2 != 0
+                && robustness2_support.null_descriptor != 0;
+
+            let mut device_extension_names_raw = vec![
                 DynamicRendering::NAME.as_ptr(),
                 Synchronization2::NAME.as_ptr(),
+                DrawIndirectCount::NAME.as_ptr(),
                 ExtDescriptorIndexingFn::NAME.as_ptr(),
                 #[cfg(any(target_os = "macos", target_os = "ios"))]
                 KhrPortabilitySubsetFn::NAME.as_ptr(),
                 #[cfg(any(target_os = "macos", target_os = "ios"))]
                 KhrGetMemoryRequirements2Fn::NAME.as_ptr(),
             ];
+            if window.is_some() {
+                device_extension_names_raw.push(Swapchain::NAME.as_ptr());
+            }
+            if supports_acceleration_structure {
+                device_extension_names_raw.push(AccelerationStructure::NAME.as_ptr());
+                device_extension_names_raw.push(DeferredHostOperations::NAME.as_ptr());
+            }
+            if supports_ray_tracing_pipeline {
+                device_extension_names_raw.push(RayTracingPipeline::NAME.as_ptr());
+            }
+            if supports_ray_query {
+                device_extension_names_raw.push(KhrRayQueryFn::NAME.as_ptr());
+            }
+            if video_decode_queue_family_index.is_some() {
+                device_extension_names_raw.push(KhrVideoQueueFn::NAME.as_ptr());
+                device_extension_names_raw.push(KhrVideoDecodeQueueFn::NAME.as_ptr());
+            }
+            if supports_video_decode_h264 {
+                device_extension_names_raw.push(KhrVideoDecodeH264Fn::NAME.as_ptr());
+            }
+            if supports_video_decode_h265 {
+                device_extension_names_raw.push(KhrVideoDecodeH265Fn::NAME.as_ptr());
+            }
+            if supports_external_interop {
+                device_extension_names_raw
+                    .extend(external_interop_extensions.iter().map(|name| name.as_ptr()));
+            }
+            if supports_present_wait {
+                device_extension_names_raw.push(KhrPresentIdFn::NAME.as_ptr());
+                device_extension_names_raw.push(PresentWait::NAME.as_ptr());
+            }
+            if supports_shading_rate_image {
+                device_extension_names_raw.push(KhrFragmentShadingRateFn::NAME.as_ptr());
+            }
+            if supports_line_rasterization {
+                device_extension_names_raw.push(vk::ExtLineRasterizationFn::NAME.as_ptr());
+            }
+            if supports_mesh_shader {
+                device_extension_names_raw.push(MeshShader::NAME.as_ptr());
+            }
+            if supports_hdr_metadata {
+                device_extension_names_raw.push(vk::ExtHdrMetadataFn::NAME.as_ptr());
+            }
+            if requirements.shader_printf {
+                device_extension_names_raw.push(vk::KhrShaderNonSemanticInfoFn::NAME.as_ptr());
+            }
+            if robustness {
+                device_extension_names_raw.push(vk::ExtRobustness2Fn::NAME.as_ptr());
+            }
+            if supports_shader_clock {
+                device_extension_names_raw.push(vk::KhrShaderClockFn::NAME.as_ptr());
+            }
+            if subgroup.size_control {
+                device_extension_names_raw.push(vk::ExtSubgroupSizeControlFn::NAME.as_ptr());
+            }
+            if supports_cooperative_matrix {
+                device_extension_names_raw.push(vk::KhrCooperativeMatrixFn::NAME.as_ptr());
+            }
+            if scalar_block_layout && supports_extension(vk::ExtScalarBlockLayoutFn::NAME) {
+                device_extension_names_raw.push(vk::ExtScalarBlockLayoutFn::NAME.as_ptr());
+            }
+            for name in requirements.device_extensions.iter() {
+                if !device_extension_names_raw
+                    .iter()
+                    .any(|existing| CStr::from_ptr(*existing) == name.as_c_str())
+                {
+                    device_extension_names_raw.push(name.as_ptr());
+                }
+            }
+            let device_features = instance.get_physical_device_features(pdevice);
+            let supports_sample_rate_shading = device_features.sample_rate_shading != 0;
+            let supports_fill_mode_non_solid = device_features.fill_mode_non_solid != 0;
+            let mut line_rasterization_support =
+                vk::PhysicalDeviceLineRasterizationFeaturesEXT::default();
+            if supports_line_rasterization {
+                instance.get_physical_device_features2(
+                    pdevice,
+                    &mut vk::PhysicalDeviceFeatures2::default()
+                        .push_next(&mut line_rasterization_support),
+                );
+            }
+            let line_support = LineSupport {
+                wide: device_features.wide_lines != 0,
+                rectangular: line_rasterization_support.rectangular_lines != 0,
+                bresenham: line_rasterization_support.bresenham_lines != 0,
+                smooth: line_rasterization_support.smooth_lines != 0,
+                stippled_rectangular: line_rasterization_support.stippled_rectangular_lines != 0,
+                stippled_bresenham: line_rasterization_support.stippled_bresenham_lines != 0,
+                stippled_smooth: line_rasterization_support.stippled_smooth_lines != 0,
+            };
+            let instrument_shaders =
+                requirements.shader_printf || requirements.gpu_assisted_validation;
             let features = vk::PhysicalDeviceFeatures {
                 shader_clip_distance: 1,
                 sampler_anisotropy: 1,
+                multi_draw_indirect: 1,
+                sample_rate_shading: supports_sample_rate_shading as u32,
+                wide_lines: line_support.wide as u32,
+                fill_mode_non_solid: supports_fill_mode_non_solid as u32,
+                // the instrumentation writes to a buffer from every stage
+                vertex_pipeline_stores_and_atomics: (instrument_shaders
+                    && device_features.vertex_pipeline_stores_and_atomics != 0)
+                    as u32,
+                fragment_stores_and_atomics: (instrument_shaders
+                    && device_features.fragment_stores_and_atomics != 0)
+                    as u32,
+                // the robustness2 accesses build on it
+                robust_buffer_access: robustness as u32,
                 ..Default::default()
             };
             let priorities = [1.0];
@@ -369,121 +1691,233 @@ impl ExampleBase {
             let mut buffer_features =
                 PhysicalDeviceBufferDeviceAddressFeaturesKHR::default().buffer_device_address(true);
 
-            let mut indexing_features = PhysicalDeviceDescriptorIndexingFeatures::default()
-                .descriptor_binding_partially_bound(true)
-                .runtime_descriptor_array(true)
-                .shader_sampled_image_array_non_uniform_indexing(true)
-                // .shader_uniform_buffer_array_non_uniform_indexing(true)
-                // .shader_storage_buffer_array_non_uniform_indexing(true)
-                // after bind
-                .descriptor_binding_sampled_image_update_after_bind(true)
-                .descriptor_binding_uniform_buffer_update_after_bind(true)
-                .descriptor_binding_storage_buffer_update_after_bind(true)
-                // dynamic indexing
-                .shader_input_attachment_array_dynamic_indexing(true)
-                .shader_storage_texel_buffer_array_dynamic_indexing(true)
-                .shader_uniform_texel_buffer_array_dynamic_indexing(true);
-
-            let queue_info = vk::DeviceQueueCreateInfo::default()
+            let mut indexing_features = descriptor_indexing.to_features();
+
+            // a compute-only family is usually backed by separate hardware queues, so work
+            // submitted to it can overlap with graphics work
+            let async_compute_queue_family_index = instance
+                .get_physical_device_queue_family_properties(pdevice)
+                .iter()
+                .position(|info| {
+                    info.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                        && !info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                })
+                .map(|index| index as u32)
+                // a headless device already runs everything on it
+                .filter(|index| *index != queue_family_index);
+            // usually a DMA engine, which copies without taking time from the other queues
+            let transfer_queue_family_index = instance
+                .get_physical_device_queue_family_properties(pdevice)
+                .iter()
+                .position(|info| {
+                    info.queue_flags.contains(vk::QueueFlags::TRANSFER)
+                        && !info
+                            .queue_flags
+                            .intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+                })
+                .map(|index| index as u32);
+
+            let mut queue_infos = vec![vk::DeviceQueueCreateInfo::default()
                 .queue_family_index(queue_family_index)
-                .queue_priorities(&priorities);
+                .queue_priorities(&priorities)];
+            for family_index in [
+                async_compute_queue_family_index,
+                transfer_queue_family_index,
+                video_decode_queue_family_index,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if queue_infos
+                    .iter()
+                    .all(|info| info.queue_family_index != family_index)
+                {
+                    queue_infos.push(
+                        vk::DeviceQueueCreateInfo::default()
+                            .queue_family_index(family_index)
+                            .queue_priorities(&priorities),
+                    );
+                }
+            }
 
-            let device_create_info = vk::DeviceCreateInfo::default()
-                .queue_create_infos(std::slice::from_ref(&queue_info))
+            let mut acceleration_structure_features =
+                vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default()
+                    .acceleration_structure(true);
+            let mut ray_tracing_pipeline_features =
+                vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default()
+                    .ray_tracing_pipeline(true);
+            let mut ray_query_features =
+                vk::PhysicalDeviceRayQueryFeaturesKHR::default().ray_query(true);
+            let mut multiview_features =
+                vk::PhysicalDeviceMultiviewFeatures::default().multiview(true);
+            // decoded frames are sampled through a YCbCr conversion
+            let mut ycbcr_features = vk::PhysicalDeviceSamplerYcbcrConversionFeatures::default()
+                .sampler_ycbcr_conversion(true);
+            // shared semaphores are timeline semaphores, so both sides can wait on values
+            let mut timeline_semaphore_features =
+                vk::PhysicalDeviceTimelineSemaphoreFeatures::default().timeline_semaphore(true);
+            // lets the frame pacing wait until a frame is actually on screen
+            let mut present_id_features =
+                vk::PhysicalDevicePresentIdFeaturesKHR::default().present_id(true);
+            let mut present_wait_features =
+                vk::PhysicalDevicePresentWaitFeaturesKHR::default().present_wait(true);
+
+            let mut shading_rate_features =
+                vk::PhysicalDeviceFragmentShadingRateFeaturesKHR::default()
+                    .pipeline_fragment_shading_rate(true)
+                    .attachment_fragment_shading_rate(true);
+            let mut line_rasterization_features =
+                vk::PhysicalDeviceLineRasterizationFeaturesEXT::default()
+                    .rectangular_lines(line_support.rectangular)
+                    .bresenham_lines(line_support.bresenham)
+                    .smooth_lines(line_support.smooth)
+                    .stippled_rectangular_lines(line_support.stippled_rectangular)
+                    .stippled_bresenham_lines(line_support.stippled_bresenham)
+                    .stippled_smooth_lines(line_support.stippled_smooth);
+            let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::default()
+                .task_shader(true)
+                .mesh_shader(true);
+            let mut robustness2_features = vk::PhysicalDeviceRobustness2FeaturesEXT::default()
+                .robust_buffer_access2(true)
+                .robust_image_access2(true)
+                .null_descriptor(true);
+            let mut shader_clock_features = vk::PhysicalDeviceShaderClockFeaturesKHR::default()
+                .shader_subgroup_clock(true)
+                .shader_device_clock(shader_clock_support.shader_device_clock != 0);
+            let mut subgroup_size_control_features =
+                vk::PhysicalDeviceSubgroupSizeControlFeatures::default()
+                    .subgroup_size_control(true)
+                    .compute_full_subgroups(true);
+            let mut cooperative_matrix_features =
+                vk::PhysicalDeviceCooperativeMatrixFeaturesKHR::default().cooperative_matrix(true);
+            let mut memory_model_features =
+                vk::PhysicalDeviceVulkanMemoryModelFeatures::default().vulkan_memory_model(true);
+            let mut float16_int8_features = vk::PhysicalDeviceShaderFloat16Int8Features::default()
+                .shader_float16(small_types.float16)
+                .shader_int8(small_types.int8);
+            let mut storage_16bit_features = vk::PhysicalDevice16BitStorageFeatures::default()
+                .storage_buffer16_bit_access(small_types.storage_buffer_16bit);
+            let mut storage_8bit_features = vk::PhysicalDevice8BitStorageFeatures::default()
+                .storage_buffer8_bit_access(small_types.storage_buffer_8bit);
+            let mut scalar_block_layout_features =
+                vk::PhysicalDeviceScalarBlockLayoutFeatures::default()
+                    .scalar_block_layout(scalar_block_layout);
+
+            let mut device_create_info = vk::DeviceCreateInfo::default()
+                .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&device_extension_names_raw)
                 .enabled_features(&features)
                 .push_next(&mut dynamic_rendering_features)
                 .push_next(&mut synchronization2_features)
                 .push_next(&mut buffer_features)
-                .push_next(&mut indexing_features);
+                .push_next(&mut indexing_features)
+                .push_next(&mut float16_int8_features)
+                .push_next(&mut storage_16bit_features)
+                .push_next(&mut storage_8bit_features)
+                .push_next(&mut scalar_block_layout_features);
+            if supports_acceleration_structure {
+                device_create_info =
+                    device_create_info.push_next(&mut acceleration_structure_features);
+            }
+            if supports_ray_tracing_pipeline {
+                device_create_info =
+                    device_create_info.push_next(&mut ray_tracing_pipeline_features);
+            }
+            if supports_ray_query {
+                device_create_info = device_create_info.push_next(&mut ray_query_features);
+            }
+            if requirements.multiview {
+                device_create_info = device_create_info.push_next(&mut multiview_features);
+            }
+            if video_decode_queue_family_index.is_some() {
+                device_create_info = device_create_info.push_next(&mut ycbcr_features);
+            }
+            if supports_external_interop {
+                device_create_info = device_create_info.push_next(&mut timeline_semaphore_features);
+            }
+            if supports_present_wait {
+                device_create_info = device_create_info
+                    .push_next(&mut present_id_features)
+                    .push_next(&mut present_wait_features);
+            }
+            if supports_shading_rate_image {
+                device_create_info = device_create_info.push_next(&mut shading_rate_features);
+            }
+            if supports_line_rasterization {
+                device_create_info = device_create_info.push_next(&mut line_rasterization_features);
+            }
+            if supports_mesh_shader {
+                device_create_info = device_create_info.push_next(&mut mesh_shader_features);
+            }
+            if robustness {
+                device_create_info = device_create_info.push_next(&mut robustness2_features);
+            }
+            if supports_shader_clock {
+                device_create_info = device_create_info.push_next(&mut shader_clock_features);
+            }
+            if subgroup.size_control {
+                device_create_info =
+                    device_create_info.push_next(&mut subgroup_size_control_features);
+            }
+            if supports_cooperative_matrix {
+                device_create_info = device_create_info
+                    .push_next(&mut cooperative_matrix_features)
+                    .push_next(&mut memory_model_features);
+            }
 
-            let device: Device = instance
-                .create_device(pdevice, &device_create_info, None)
-                .unwrap();
+            let device: Device = instance.create_device(pdevice, &device_create_info, None)?;
+            let pipeline_cache = create_pipeline_cache(&device, config)?;
 
             let present_queue = device.get_device_queue(queue_family_index, 0);
+            let async_compute_queue = async_compute_queue_family_index
+                .map(|family_index| device.get_device_queue(family_index, 0));
+            let transfer_queue = transfer_queue_family_index
+                .map(|family_index| device.get_device_queue(family_index, 0));
+            let video_decode_queue = video_decode_queue_family_index
+                .map(|family_index| device.get_device_queue(family_index, 0));
 
-            let surface_format = surface_loader
-                .get_physical_device_surface_formats(pdevice, surface)
-                .unwrap()[0];
-
-            let surface_capabilities = surface_loader
-                .get_physical_device_surface_capabilities(pdevice, surface)
-                .unwrap();
-            let mut desired_image_count = surface_capabilities.min_image_count + 1;
-            if surface_capabilities.max_image_count > 0
-                && desired_image_count > surface_capabilities.max_image_count
-            {
-                desired_image_count = surface_capabilities.max_image_count;
-            }
-            let surface_resolution = match surface_capabilities.current_extent.width {
-                // std::u32::MAX => vk::Extent2D {
-                //     width: 1280,
-                //     height: 720,
-                // },
-                _ => surface_capabilities.current_extent,
-            };
-            let pre_transform = if surface_capabilities
-                .supported_transforms
-                .contains(vk::SurfaceTransformFlagsKHR::IDENTITY)
-            {
-                vk::SurfaceTransformFlagsKHR::IDENTITY
-            } else {
-                surface_capabilities.current_transform
-            };
-            let present_modes = surface_loader
-                .get_physical_device_surface_present_modes(pdevice, surface)
-                .unwrap();
-
-            let present_mode = match present_mode {
-                PresentMode::Fifo => vk::PresentModeKHR::FIFO,
-                PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
-                PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
-                PresentMode::AutoNoVsync => vk::PresentModeKHR::IMMEDIATE,
-                PresentMode::AutoVsync => vk::PresentModeKHR::FIFO_RELAXED,
-            };
-            assert!(
-                present_modes.contains(&present_mode),
-                "Present mode not supported."
-            );
             let swapchain_loader = Swapchain::new(&instance, &device);
-
-            let swapchain_create_info = vk::SwapchainCreateInfoKHR::default()
-                .surface(surface)
-                .min_image_count(desired_image_count)
-                .image_color_space(surface_format.color_space)
-                .image_format(surface_format.format)
-                .image_extent(surface_resolution)
-                .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
-                .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-                .pre_transform(pre_transform)
-                .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-                .present_mode(present_mode)
-                .clipped(true)
-                .image_array_layers(1);
-
-            let swapchain = swapchain_loader
-                .create_swapchain(&swapchain_create_info, None)
-                .unwrap();
+            // nothing is presented without a window, the surface fields keep their defaults
+            let (surface_format, surface_resolution, swapchain_image_usage, swapchain) =
+                match window {
+                    Some(_) => create_swapchain(
+                        &surface_loader,
+                        &swapchain_loader,
+                        pdevice,
+                        surface,
+                        present_mode,
+                        config.swapchain_image_count,
+                        &requirements.surface_formats,
+                        requirements.hdr_output,
+                    )?,
+                    None => (
+                        vk::SurfaceFormatKHR::default(),
+                        vk::Extent2D::default(),
+                        vk::ImageUsageFlags::empty(),
+                        vk::SwapchainKHR::null(),
+                    ),
+                };
 
             let pool_create_info = vk::CommandPoolCreateInfo::default()
                 .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
                 .queue_family_index(queue_family_index);
 
-            let pool = device.create_command_pool(&pool_create_info, None).unwrap();
+            let pool = device.create_command_pool(&pool_create_info, None)?;
 
+            let frames_in_flight = config.frames_in_flight.clamp(1, MAX_FRAMES_IN_FLIGHT);
             let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
-                .command_buffer_count(2)
+                .command_buffer_count(1 + frames_in_flight)
                 .command_pool(pool)
                 .level(vk::CommandBufferLevel::PRIMARY);
 
-            let command_buffers = device
-                .allocate_command_buffers(&command_buffer_allocate_info)
-                .unwrap();
+            let command_buffers = device.allocate_command_buffers(&command_buffer_allocate_info)?;
             let setup_command_buffer = command_buffers[0];
-            let draw_command_buffer = command_buffers[1];
 
-            let present_images = swapchain_loader.get_swapchain_images(swapchain).unwrap();
+            let present_images = if window.is_some() {
+                swapchain_loader.get_swapchain_images(swapchain)?
+            } else {
+                vec![]
+            };
             let present_image_views: Vec<vk::ImageView> = present_images
                 .iter()
                 .map(|&image| {
@@ -504,169 +1938,343 @@ impl ExampleBase {
                             layer_count: 1,
                         })
                         .image(image);
-                    device.create_image_view(&create_view_info, None).unwrap()
+                    device.create_image_view(&create_view_info, None)
                 })
-                .collect();
+                .collect::<VkResult<_>>()?;
             let device_memory_properties = instance.get_physical_device_memory_properties(pdevice);
-            let depth_image_create_info = vk::ImageCreateInfo::default()
-                .image_type(vk::ImageType::TYPE_2D)
-                .format(vk::Format::D16_UNORM)
-                .extent(surface_resolution.into())
-                .mip_levels(1)
-                .array_layers(1)
-                .samples(vk::SampleCountFlags::TYPE_1)
-                .tiling(vk::ImageTiling::OPTIMAL)
-                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-            let depth_image = device.create_image(&depth_image_create_info, None).unwrap();
-            let depth_image_memory_req = device.get_image_memory_requirements(depth_image);
-            let depth_image_memory_index = find_memorytype_index(
-                &depth_image_memory_req,
-                &device_memory_properties,
-                vk::MemoryPropertyFlags::DEVICE_LOCAL,
-            )
-            .expect("Unable to find suitable memory index for depth image.");
-
-            let depth_image_allocate_info = vk::MemoryAllocateInfo::default()
-                .allocation_size(depth_image_memory_req.size)
-                .memory_type_index(depth_image_memory_index);
-
-            let depth_image_memory = device
-                .allocate_memory(&depth_image_allocate_info, None)
-                .unwrap();
-
-            device
-                .bind_image_memory(depth_image, depth_image_memory, 0)
-                .expect("Unable to bind depth image memory");
-
+            let rebar_heap_size = resizable_bar_heap_size(&device_memory_properties);
+            let supports_rebar = rebar_heap_size.is_some();
+            crate::buffer::set_rebar_available(rebar_heap_size);
             let fence_create_info =
                 vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED);
 
-            let draw_commands_reuse_fence = device
-                .create_fence(&fence_create_info, None)
-                .expect("Create fence failed.");
-            let setup_commands_reuse_fence = device
-                .create_fence(&fence_create_info, None)
-                .expect("Create fence failed.");
+            let setup_commands_reuse_fence = device.create_fence(&fence_create_info, None)?;
 
-            record_submit_commandbuffer(
+            let queue_lock = Mutex::new(());
+            let staging_belt = StagingBelt::new(
                 &device,
-                setup_command_buffer,
-                setup_commands_reuse_fence,
-                present_queue,
-                &[],
-                &[],
-                &[],
-                |device, setup_command_buffer| {
-                    let layout_transition_barriers = vk::ImageMemoryBarrier::default()
-                        .image(depth_image)
-                        .dst_access_mask(
-                            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
-                                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
-                        )
-                        .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
-                        .old_layout(vk::ImageLayout::UNDEFINED)
-                        .subresource_range(
-                            vk::ImageSubresourceRange::default()
-                                .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                                .layer_count(1)
-                                .level_count(1),
+                transfer_queue.unwrap_or(present_queue),
+                transfer_queue_family_index.unwrap_or(queue_family_index),
+                queue_family_index,
+            )?;
+            let depth_image_format = vk::Format::D16_UNORM;
+            let (depth_image, depth_image_memory, depth_image_view) = if window.is_some() {
+                let depth_image_create_info = vk::ImageCreateInfo::default()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(depth_image_format)
+                    .extent(surface_resolution.into())
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    // transfer source so the depth under the cursor can be read back
+                    .usage(
+                        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+                            | vk::ImageUsageFlags::TRANSFER_SRC,
+                    )
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+                let depth_image = device.create_image(&depth_image_create_info, None)?;
+                let depth_image_memory_req = device.get_image_memory_requirements(depth_image);
+                let depth_image_memory_index = find_memorytype_index(
+                    &depth_image_memory_req,
+                    &device_memory_properties,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                )
+                .ok_or_else(|| {
+                    Error::Unsupported(
+                        "Unable to find suitable memory index for depth image.".to_string(),
+                    )
+                })?;
+
+                let depth_image_allocate_info = vk::MemoryAllocateInfo::default()
+                    .allocation_size(depth_image_memory_req.size)
+                    .memory_type_index(depth_image_memory_index);
+
+                let depth_image_memory =
+                    device.allocate_memory(&depth_image_allocate_info, None)?;
+
+                device.bind_image_memory(depth_image, depth_image_memory, 0)?;
+
+                record_submit_commandbuffer(
+                    &device,
+                    setup_command_buffer,
+                    setup_commands_reuse_fence,
+                    present_queue,
+                    &queue_lock,
+                    &[],
+                    &[],
+                    &[],
+                    |device, setup_command_buffer| {
+                        let layout_transition_barriers = vk::ImageMemoryBarrier::default()
+                            .image(depth_image)
+                            .dst_access_mask(
+                                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                            )
+                            .new_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                            .old_layout(vk::ImageLayout::UNDEFINED)
+                            .subresource_range(
+                                vk::ImageSubresourceRange::default()
+                                    .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                                    .layer_count(1)
+                                    .level_count(1),
+                            );
+
+                        device.cmd_pipeline_barrier(
+                            setup_command_buffer,
+                            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                            vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                            vk::DependencyFlags::empty(),
+                            &[],
+                            &[],
+                            &[layout_transition_barriers],
                         );
-
-                    device.cmd_pipeline_barrier(
-                        setup_command_buffer,
-                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
-                        vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
-                        vk::DependencyFlags::empty(),
-                        &[],
-                        &[],
-                        &[layout_transition_barriers],
-                    );
-                },
-            );
-
-            let depth_image_view_info = vk::ImageViewCreateInfo::default()
-                .subresource_range(
-                    vk::ImageSubresourceRange::default()
-                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                        .level_count(1)
-                        .layer_count(1),
+                    },
+                )?;
+
+                let depth_image_view_info = vk::ImageViewCreateInfo::default()
+                    .subresource_range(
+                        vk::ImageSubresourceRange::default()
+                            .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                            .level_count(1)
+                            .layer_count(1),
+                    )
+                    .image(depth_image)
+                    .format(depth_image_format)
+                    .view_type(vk::ImageViewType::TYPE_2D);
+
+                let depth_image_view = device.create_image_view(&depth_image_view_info, None)?;
+
+                (depth_image, depth_image_memory, depth_image_view)
+            } else {
+                (
+                    vk::Image::null(),
+                    vk::DeviceMemory::null(),
+                    vk::ImageView::null(),
                 )
-                .image(depth_image)
-                .format(depth_image_create_info.format)
-                .view_type(vk::ImageViewType::TYPE_2D);
-
-            let depth_image_view = device
-                .create_image_view(&depth_image_view_info, None)
-                .unwrap();
+            };
 
             let semaphore_create_info = vk::SemaphoreCreateInfo::default();
 
-            let present_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            let rendering_complete_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
+            let rendering_complete_semaphores = present_images
+                .iter()
+                .map(|_| device.create_semaphore(&semaphore_create_info, None))
+                .collect::<VkResult<Vec<_>>>()?;
+            let frames = command_buffers[1..]
+                .iter()
+                .map(|&command_buffer| {
+                    Ok(FrameContext {
+                        command_buffer,
+                        // the first wait of every context returns right away
+                        fence: device.create_fence(&fence_create_info, None)?,
+                        image_available: device.create_semaphore(&semaphore_create_info, None)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
 
-            let immutable_samplers = Self::create_samplers(&device);
+            let immutable_samplers = Self::create_samplers(&device)?;
             let (command_thread_pool, threaded_command_buffers) =
-                Self::create_command_thread_pool(device.clone(), queue_family_index);
+                Self::create_command_thread_pool(&device, queue_family_index, frames_in_flight)?;
 
             let synchronization2 = Synchronization2::new(&instance, &device);
             let dynamic_rendering = DynamicRendering::new(&instance, &device);
+            let draw_indirect_count = DrawIndirectCount::new(&instance, &device);
+            let acceleration_structure = supports_acceleration_structure
+                .then(|| AccelerationStructure::new(&instance, &device));
+            let ray_tracing_pipeline = supports_ray_tracing_pipeline
+                .then(|| RayTracingPipeline::new(&instance, &device));
+            let present_wait = supports_present_wait.then(|| PresentWait::new(&instance, &device));
+            let mesh_shader = supports_mesh_shader.then(|| MeshShader::new(&instance, &device));
+            let hdr_metadata = supports_hdr_metadata.then(|| {
+                vk::ExtHdrMetadataFn::load(|name| {
+                    std::mem::transmute(
+                        instance.get_device_proc_addr(device.handle(), name.as_ptr()),
+                    )
+                })
+            });
+            let line_rasterization = supports_line_rasterization.then(|| {
+                vk::ExtLineRasterizationFn::load(|name| {
+                    std::mem::transmute(
+                        instance.get_device_proc_addr(device.handle(), name.as_ptr()),
+                    )
+                })
+            });
+
+            let mut acceleration_structure_properties =
+                vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+            if supports_acceleration_structure {
+                let mut properties = vk::PhysicalDeviceProperties2::default()
+                    .push_next(&mut acceleration_structure_properties);
+                instance.get_physical_device_properties2(pdevice, &mut properties);
+            }
+            let mut ray_tracing_pipeline_properties =
+                vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+            if supports_ray_tracing_pipeline {
+                let mut properties = vk::PhysicalDeviceProperties2::default()
+                    .push_next(&mut ray_tracing_pipeline_properties);
+                instance.get_physical_device_properties2(pdevice, &mut properties);
+            }
+            let mut shading_rate_properties =
+                vk::PhysicalDeviceFragmentShadingRatePropertiesKHR::default();
+            if supports_shading_rate_image {
+                let mut properties = vk::PhysicalDeviceProperties2::default()
+                    .push_next(&mut shading_rate_properties);
+                instance.get_physical_device_properties2(pdevice, &mut properties);
+            }
 
             println!("{:?}", device_properties);
 
-            ExampleBase {
+            let mut descriptor_indexing_properties =
+                vk::PhysicalDeviceDescriptorIndexingProperties::default();
+            let mut properties = vk::PhysicalDeviceProperties2::default()
+                .push_next(&mut descriptor_indexing_properties);
+            instance.get_physical_device_properties2(pdevice, &mut properties);
+            // TODO: fetch from device
+            let max_descriptor_count = (512 * 1024).min(
+                device_properties.limits.max_per_stage_descriptor_sampled_images // https://github.com/KhronosGroup/MoltenVK/issues/394 - prob just use 16 samplers and then bind them instead of COMBINED_IMAGE_SAMPLERS
+                    - RESERVED_DESCRIPTOR_COUNT,
+            );
+            let capabilities = DeviceCapabilities {
+                name: CStr::from_ptr(device_properties.device_name.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+                device_type: device_properties.device_type,
+                api_version: device_properties.api_version,
+                limits: device_properties.limits,
+                features: EnabledFeatures {
+                    acceleration_structure: acceleration_structure.is_some(),
+                    ray_tracing_pipeline: ray_tracing_pipeline.is_some(),
+                    ray_query: supports_ray_query,
+                    multiview: requirements.multiview,
+                    shader_printf: requirements.shader_printf,
+                    scalar_block_layout,
+                    robustness,
+                    sample_rate_shading: supports_sample_rate_shading,
+                    fill_mode_non_solid: supports_fill_mode_non_solid,
+                    shader_clock: supports_shader_clock,
+                    cooperative_matrix: !cooperative_matrix.is_empty(),
+                    shading_rate_image: supports_shading_rate_image,
+                    line_rasterization: line_rasterization.is_some(),
+                    mesh_shader: mesh_shader.is_some(),
+                    external_interop: supports_external_interop,
+                    rebar: supports_rebar,
+                    present_wait: present_wait.is_some(),
+                    hdr_metadata: hdr_metadata.is_some(),
+                    video_decode_h264: supports_video_decode_h264,
+                    video_decode_h265: supports_video_decode_h265,
+                    async_compute_queue: async_compute_queue.is_some(),
+                    transfer_queue: transfer_queue.is_some(),
+                },
+                descriptor_indexing,
+                bindless: BindlessLimits::from_properties(
+                    &descriptor_indexing_properties,
+                    max_descriptor_count,
+                ),
+                subgroup,
+                small_types,
+                formats: KEY_FORMATS
+                    .into_iter()
+                    .map(|format| {
+                        let properties =
+                            instance.get_physical_device_format_properties(pdevice, format);
+                        (format, properties.optimal_tiling_features)
+                    })
+                    .collect(),
+            };
+
+            Ok(ExampleBase {
                 entry,
                 instance,
                 device,
                 synchronization2,
                 dynamic_rendering,
+                draw_indirect_count,
+                acceleration_structure,
+                min_acceleration_structure_scratch_offset_alignment:
+                    acceleration_structure_properties
+                        .min_acceleration_structure_scratch_offset_alignment,
+                ray_tracing_pipeline,
+                shader_group_handle_size: ray_tracing_pipeline_properties.shader_group_handle_size,
+                shader_group_handle_alignment: ray_tracing_pipeline_properties
+                    .shader_group_handle_alignment,
+                shader_group_base_alignment: ray_tracing_pipeline_properties
+                    .shader_group_base_alignment,
+                supports_ray_query,
+                multiview: requirements.multiview,
+                headless: window.is_none(),
                 queue_family_index,
                 pdevice,
                 immutable_samplers,
                 command_thread_pool,
                 threaded_command_buffers,
-                // TODO: fetch from device
-                max_descriptor_count: {
-                    (512 * 1024).min(
-                    device_properties
-                        .limits
-                        .max_per_stage_descriptor_sampled_images // https://github.com/KhronosGroup/MoltenVK/issues/394 - prob just use 16 samplers and then bind them instead of COMBINED_IMAGE_SAMPLERS
-                        - RESERVED_DESCRIPTOR_COUNT,
-                    )                     
-                },
+                max_descriptor_count,
+                capabilities,
                 device_memory_properties,
                 surface_loader,
                 surface_format,
                 present_queue,
+                async_compute_queue,
+                async_compute_queue_family_index,
+                transfer_queue,
+                transfer_queue_family_index,
+                video_decode_queue,
+                video_decode_queue_family_index,
+                supports_video_decode_h264,
+                supports_video_decode_h265,
+                supports_external_interop,
+                supports_rebar,
+                supports_sample_rate_shading,
+                supports_fill_mode_non_solid,
+                shader_printf: requirements.shader_printf,
+                config: config.clone(),
+                pipeline_cache,
+                subgroup,
+                small_types,
+                scalar_block_layout,
+                cooperative_matrix,
+                supports_shader_clock,
+                robustness,
+                hdr_metadata,
+                line_rasterization,
+                line_support,
+                mesh_shader,
+                shading_rate_texel_size: supports_shading_rate_image.then_some(
+                    shading_rate_properties.max_fragment_shading_rate_attachment_texel_size,
+                ),
+                present_wait,
+                present_id: AtomicU64::new(0),
                 surface_resolution,
                 swapchain_loader,
                 swapchain,
+                swapchain_image_usage,
                 present_images,
                 present_image_views,
                 pool,
-                draw_command_buffer,
                 setup_command_buffer,
+                frames,
+                frame_index: AtomicUsize::new(0),
                 depth_image,
                 depth_image_view,
-                depth_image_format: depth_image_create_info.format,
-                present_complete_semaphore,
-                rendering_complete_semaphore,
-                draw_commands_reuse_fence,
+                depth_image_format,
+                rendering_complete_semaphores,
                 setup_commands_reuse_fence,
                 surface,
                 debug_call_back,
                 debug_utils_loader,
                 depth_image_memory,
-            }
+                registry: RwLock::default(),
+                queue_lock,
+                setup_contexts: Mutex::default(),
+                descriptor_allocator: Mutex::default(),
+                staging_belt: Mutex::new(staging_belt),
+                allocator: OnceLock::new(),
+                destroy_leaked_resources: AtomicBool::new(true),
+            })
         }
     }
 
-    fn create_samplers(device: &ash::Device) -> HashMap<SamplerDesc, vk::Sampler> {
+    fn create_samplers(device: &ash::Device) -> Result<HashMap<SamplerDesc, vk::Sampler>> {
         let texel_filters = [vk::Filter::NEAREST, vk::Filter::LINEAR];
         let mipmap_modes = [
             vk::SamplerMipmapMode::NEAREST,
@@ -682,75 +2290,222 @@ impl ExampleBase {
         for &texel_filter in &texel_filters {
             for &mipmap_mode in &mipmap_modes {
                 for &address_modes in &address_modes {
-                    let anisotropy_enable = texel_filter == vk::Filter::LINEAR;
-
-                    result.insert(
-                        SamplerDesc {
-                            texel_filter,
-                            mipmap_mode,
-                            address_modes,
-                        },
-                        unsafe {
-                            device.create_sampler(
-                                &vk::SamplerCreateInfo::default()
-                                    .mag_filter(texel_filter)
-                                    .min_filter(texel_filter)
-                                    .mipmap_mode(mipmap_mode)
-                                    .address_mode_u(address_modes)
-                                    .address_mode_v(address_modes)
-                                    .address_mode_w(address_modes)
-                                    .max_lod(vk::LOD_CLAMP_NONE)
-                                    .max_anisotropy(16.0)
-                                    .anisotropy_enable(anisotropy_enable),
-                                None,
-                            )
-                        }
-                        .expect("create_sampler"),
-                    );
+                    for compare in [false, true] {
+                        let anisotropy_enable = texel_filter == vk::Filter::LINEAR;
+
+                        result.insert(
+                            SamplerDesc {
+                                texel_filter,
+                                mipmap_mode,
+                                address_modes,
+                                compare,
+                            },
+                            unsafe {
+                                device.create_sampler(
+                                    &vk::SamplerCreateInfo::default()
+                                        .mag_filter(texel_filter)
+                                        .min_filter(texel_filter)
+                                        .mipmap_mode(mipmap_mode)
+                                        .address_mode_u(address_modes)
+                                        .address_mode_v(address_modes)
+                                        .address_mode_w(address_modes)
+                                        .max_lod(vk::LOD_CLAMP_NONE)
+                                        .max_anisotropy(16.0)
+                                        .anisotropy_enable(anisotropy_enable)
+                                        .compare_enable(compare)
+                                        .compare_op(vk::CompareOp::LESS_OR_EQUAL),
+                                    None,
+                                )?
+                            },
+                        );
+                    }
                 }
             }
         }
 
-        result
+        Ok(result)
     }
 
+    /// A thread pool to record secondary command buffers on, with `frame_count` command buffers
+    /// per thread keyed by its index in the pool.
     pub fn create_command_thread_pool(
-        device: Device,
+        device: &Device,
         queue_family_index: u32,
-    ) -> (ThreadPool, Arc<RwLock<HashMap<usize, CommandBuffer>>>) {
-        let m_command_buffers: Arc<RwLock<HashMap<usize, CommandBuffer>>> =
-            Arc::new(RwLock::new(HashMap::new()));
-        let m_command_buffers_clone = m_command_buffers.clone();
-
+        frame_count: u32,
+    ) -> Result<(ThreadPool, Arc<RwLock<HashMap<usize, Vec<CommandBuffer>>>>)> {
         let pool = rayon::ThreadPoolBuilder::new()
             .thread_name(|x| format!("Command buffer generation thread {}", x))
-            .start_handler(move |x| {
-                let pool_create_info = vk::CommandPoolCreateInfo::default()
-                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
-                    .queue_family_index(queue_family_index);
+            .build()?;
 
-                let pool = unsafe { device.create_command_pool(&pool_create_info, None).unwrap() };
+        // recorded only by the thread of the same index, so the pools can be created up front
+        let mut command_buffers = HashMap::new();
+        for x in 0..pool.current_num_threads() {
+            let pool_create_info = vk::CommandPoolCreateInfo::default()
+                .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                .queue_family_index(queue_family_index);
 
-                let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
-                    .command_buffer_count(1)
-                    .command_pool(pool)
-                    .level(vk::CommandBufferLevel::SECONDARY);
+            let command_pool = unsafe { device.create_command_pool(&pool_create_info, None)? };
 
-                let command_buffers = unsafe {
-                    device
-                        .allocate_command_buffers(&command_buffer_allocate_info)
-                        .unwrap()
-                };
+            let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
+                .command_buffer_count(frame_count)
+                .command_pool(command_pool)
+                .level(vk::CommandBufferLevel::SECONDARY);
 
-                m_command_buffers
-                    .write()
-                    .unwrap()
-                    .insert(x, command_buffers[0]);
-            })
-            .build()
-            .unwrap();
+            command_buffers.insert(x, unsafe {
+                device.allocate_command_buffers(&command_buffer_allocate_info)?
+            });
+        }
 
-        (pool, m_command_buffers_clone)
+        Ok((pool, Arc::new(RwLock::new(command_buffers))))
+    }
+
+    /// Captures the next presented frame with RenderDoc, returns `false` when RenderDoc isn't
+    /// available.
+    #[cfg(feature = "renderdoc")]
+    pub fn trigger_capture(&self) -> bool {
+        self.trigger_multi_frame_capture(1)
+    }
+
+    /// Captures the next `frames` presented frames into separate captures.
+    #[cfg(feature = "renderdoc")]
+    pub fn trigger_multi_frame_capture(&self, frames: u32) -> bool {
+        let Some(renderdoc) = renderdoc() else {
+            return false;
+        };
+        renderdoc
+            .lock()
+            .unwrap()
+            .trigger_multi_frame_capture(frames);
+        true
+    }
+
+    /// Captures everything submitted until [`ExampleBase::end_capture`], for work that isn't
+    /// tied to presenting like compute or setup submissions.
+    #[cfg(feature = "renderdoc")]
+    pub fn start_capture(&self) -> bool {
+        let Some(renderdoc) = renderdoc() else {
+            return false;
+        };
+        renderdoc
+            .lock()
+            .unwrap()
+            .start_frame_capture(std::ptr::null(), std::ptr::null());
+        true
+    }
+
+    #[cfg(feature = "renderdoc")]
+    pub fn end_capture(&self) {
+        if let Some(renderdoc) = renderdoc() {
+            renderdoc
+                .lock()
+                .unwrap()
+                .end_frame_capture(std::ptr::null(), std::ptr::null());
+        }
+    }
+
+    /// Captures `frames` frames once the validation layers report the first error, which only
+    /// happens in debug builds. The frame the error came from has already been submitted, so
+    /// this catches errors that show up every frame.
+    #[cfg(feature = "renderdoc")]
+    pub fn capture_on_validation_error(&self, frames: u32) {
+        CAPTURE_ON_VALIDATION_ERROR.store(frames, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// The swapchain expects ST 2084 (PQ) encoded Rec. 2020 colors instead of sRGB.
+    pub fn hdr_output(&self) -> bool {
+        self.surface_format.color_space == vk::ColorSpaceKHR::HDR10_ST2084_EXT
+    }
+
+    /// Zips the render graph, the live resources with their sizes, formats and layouts, the
+    /// shader hashes and the last [`frame_dump::checkpoint`]s into `path`, to attach to a
+    /// bug report.
+    pub fn dump_frame_state(
+        &self,
+        graph: &RenderGraph,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<()> {
+        frame_dump::write(self, graph, path.as_ref())
+    }
+
+    /// The live allocations of `allocator` by memory block, with totals per location and the
+    /// memory heaps of the device. Write it out with [`AllocatorReport::write_html`] or
+    /// [`AllocatorReport::write_csv`] for a memory audit.
+    pub fn allocator_report(&self, allocator: &Allocator) -> AllocatorReport {
+        let heaps = &self.device_memory_properties.memory_heaps
+            [..self.device_memory_properties.memory_heap_count as usize];
+        allocator.report(heaps)
+    }
+
+    /// Index into [`ExampleBase::frames`] of the frame being recorded.
+    pub fn frame_index(&self) -> usize {
+        self.frame_index.load(Ordering::Relaxed)
+    }
+
+    /// Moves on to the next [`FrameContext`] and waits until the GPU is done with the frame that
+    /// used it last, which bounds how far the CPU runs ahead to the frames in flight.
+    pub fn begin_frame(&self) -> Result<&FrameContext> {
+        profile_scope!("begin_frame");
+        let index = (self.frame_index() + 1) % self.frames.len();
+        self.frame_index.store(index, Ordering::Relaxed);
+        let frame = &self.frames[index];
+        unsafe {
+            self.device
+                .wait_for_fences(&[frame.fence], true, u64::MAX)?
+        };
+        Ok(frame)
+    }
+
+    /// Records `f` into the command buffer of `frame` and submits it without waiting, the fence
+    /// of the frame is signaled once it's done.
+    pub fn record_submit_frame<F: FnOnce(&Device, vk::CommandBuffer)>(
+        &self,
+        frame: &FrameContext,
+        wait_mask: &[vk::PipelineStageFlags],
+        wait_semaphores: &[vk::Semaphore],
+        signal_semaphores: &[vk::Semaphore],
+        f: F,
+    ) -> Result<()> {
+        profile_scope!("record_submit_frame");
+        let device = &self.device;
+        unsafe {
+            device.reset_command_buffer(
+                frame.command_buffer,
+                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+            )?;
+            device.begin_command_buffer(
+                frame.command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            f(device, frame.command_buffer);
+            device.end_command_buffer(frame.command_buffer)?;
+
+            let command_buffers = [frame.command_buffer];
+            let submit_info = vk::SubmitInfo::default()
+                .wait_semaphores(wait_semaphores)
+                .wait_dst_stage_mask(wait_mask)
+                .command_buffers(&command_buffers)
+                .signal_semaphores(signal_semaphores);
+
+            let _queue = self.queue_lock.lock().unwrap();
+            // reset right before the submit, a fence left unsignaled by an earlier error would
+            // block the next wait forever
+            device.reset_fences(&[frame.fence])?;
+            device.queue_submit(self.present_queue, &[submit_info], frame.fence)?;
+        }
+        Ok(())
+    }
+
+    /// Waits until the GPU is done with all frames in flight, before changing something they
+    /// use that can't be changed while it's in use, like a descriptor set.
+    pub fn wait_for_frames(&self) -> Result<()> {
+        let fences = self
+            .frames
+            .iter()
+            .map(|frame| frame.fence)
+            .collect::<Vec<_>>();
+        unsafe { self.device.wait_for_fences(&fences, true, u64::MAX)? };
+        Ok(())
     }
 
     pub fn get_sampler(&self, desc: SamplerDesc) -> vk::Sampler {
@@ -765,74 +2520,83 @@ impl ExampleBase {
             texel_filter: vk::Filter::LINEAR,
             mipmap_mode: vk::SamplerMipmapMode::LINEAR,
             address_modes: vk::SamplerAddressMode::REPEAT,
+            compare: false,
         })
     }
 
-    pub fn copy_buffer_to_texture(&self, buffer: &Buffer, texture: &Image) {
-        unsafe {
-            record_submit_commandbuffer(
-                &self.device,
-                self.setup_command_buffer,
-                self.setup_commands_reuse_fence,
-                self.present_queue,
-                &[],
-                &[],
-                &[],
-                |device, setup_command_buffer| {
-                    {
-                        let image_memory_barrier = vk::ImageMemoryBarrier2::default()
-                            .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                            .dst_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                            .src_access_mask(vk::AccessFlags2::empty())
-                            .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                            .old_layout(vk::ImageLayout::UNDEFINED)
-                            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                            .image(texture.image)
-                            .subresource_range(vk::ImageSubresourceRange {
-                                aspect_mask: vk::ImageAspectFlags::COLOR,
-                                layer_count: 1,
-                                level_count: 1,
-                                ..Default::default()
-                            });
-
-                        let dependency_info = vk::DependencyInfo::default()
-                            .image_memory_barriers(std::slice::from_ref(&image_memory_barrier));
-
-                        self.synchronization2
-                            .cmd_pipeline_barrier2(setup_command_buffer, &dependency_info);
-                    }
+    /// Records `f` into a command buffer of its own and waits until the GPU has executed it.
+    /// Can be called from any thread, like an asset loader, while the render world is running.
+    pub fn submit_setup_commands<F: FnOnce(&Device, vk::CommandBuffer)>(
+        &self,
+        f: F,
+    ) -> Result<()> {
+        profile_scope!("submit_setup_commands");
+        let context = self.setup_contexts.lock().unwrap().pop();
+        let context = match context {
+            Some(context) => context,
+            None => SetupContext::new(&self.device, self.queue_family_index)?,
+        };
+        let result = unsafe { context.submit(self, f) };
+        self.setup_contexts.lock().unwrap().push(context);
+        result
+    }
 
-                    // println!(
-                    //     "{:?} {:?} {:?}",
-                    //     buffer.size,
-                    //     texture.extent.width * texture.bytes_per_texel(),
-                    //     texture.extent.width
-                    // );
-
-                    device.cmd_copy_buffer_to_image(
-                        setup_command_buffer,
-                        buffer.buffer,
-                        texture.image,
-                        ImageLayout::TRANSFER_DST_OPTIMAL,
-                        &[BufferImageCopy::default()
-                            .buffer_offset(0)
-                            .buffer_row_length(texture.extent.width)
-                            .buffer_image_height(0)
-                            .image_subresource(vk::ImageSubresourceLayers {
-                                aspect_mask: vk::ImageAspectFlags::COLOR,
-                                mip_level: 0,
-                                base_array_layer: 0,
-                                layer_count: 1,
-                            })
-                            .image_extent(texture.extent)],
-                    );
+    pub fn copy_buffer_to_texture(&self, buffer: &Buffer, texture: &Image) -> Result<()> {
+        tracking::assert_alive(ResourceKind::Buffer, buffer.buffer);
+        tracking::assert_alive(ResourceKind::Image, texture.image);
+        self.submit_setup_commands(|device, setup_command_buffer| unsafe {
+            {
+                let image_memory_barrier = vk::ImageMemoryBarrier2::default()
+                    .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                    .dst_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                    .src_access_mask(vk::AccessFlags2::empty())
+                    .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .image(texture.image)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        layer_count: 1,
+                        level_count: 1,
+                        ..Default::default()
+                    });
+
+                let dependency_info = vk::DependencyInfo::default()
+                    .image_memory_barriers(std::slice::from_ref(&image_memory_barrier));
+
+                self.synchronization2
+                    .cmd_pipeline_barrier2(setup_command_buffer, &dependency_info);
+            }
 
-                    // {
+            // println!(
+            //     "{:?} {:?} {:?}",
+            //     buffer.size,
+            //     texture.extent.width * texture.bytes_per_texel(),
+            //     texture.extent.width
+            // );
 
-                    // }
-                },
+            device.cmd_copy_buffer_to_image(
+                setup_command_buffer,
+                buffer.buffer,
+                texture.image,
+                ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[BufferImageCopy::default()
+                    .buffer_offset(0)
+                    .buffer_row_length(texture.extent.width)
+                    .buffer_image_height(0)
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(texture.extent)],
             );
-        }
+
+            // {
+
+            // }
+        })
     }
 }
 
@@ -840,15 +2604,36 @@ impl Drop for ExampleBase {
     fn drop(&mut self) {
         unsafe {
             self.device.device_wait_idle().unwrap();
+            // what was released while the render world was torn down, the allocator frees its
+            // memory here as well when nothing else holds on to it
+            if let Some(allocator) = self.allocator.take() {
+                if let Err(e) =
+                    deferred_destroy::destroy_released(self, &mut allocator.lock().unwrap())
+                {
+                    println!("Failed to destroy the released resources: {}", e);
+                }
+            }
+            self.staging_belt.get_mut().unwrap().destroy(&self.device);
 
-            self.device
-                .destroy_semaphore(self.present_complete_semaphore, None);
-            self.device
-                .destroy_semaphore(self.rendering_complete_semaphore, None);
-            self.device
-                .destroy_fence(self.draw_commands_reuse_fence, None);
+            let leaks = LeakReport::live();
+            leaks.print();
+            if self.destroy_leaked_resources.load(Ordering::Relaxed) {
+                leaks.destroy(&self.device);
+            }
+
+            for &semaphore in self.rendering_complete_semaphores.iter() {
+                self.device.destroy_semaphore(semaphore, None);
+            }
+            for frame in self.frames.iter() {
+                self.device.destroy_fence(frame.fence, None);
+                self.device.destroy_semaphore(frame.image_available, None);
+            }
             self.device
                 .destroy_fence(self.setup_commands_reuse_fence, None);
+            for context in self.setup_contexts.get_mut().unwrap().iter() {
+                context.destroy(&self.device);
+            }
+            self.descriptor_allocator.get_mut().unwrap().destroy(&self.device);
             self.device.free_memory(self.depth_image_memory, None);
             self.device.destroy_image_view(self.depth_image_view, None);
             self.device.destroy_image(self.depth_image, None);
@@ -856,6 +2641,9 @@ impl Drop for ExampleBase {
                 self.device.destroy_image_view(image_view, None);
             }
             self.device.destroy_command_pool(self.pool, None);
+            save_pipeline_cache(&self.device, self.pipeline_cache, &self.config);
+            self.device
+                .destroy_pipeline_cache(self.pipeline_cache, None);
             self.swapchain_loader
                 .destroy_swapchain(self.swapchain, None);
             self.device.destroy_device(None);
@@ -866,3 +2654,36 @@ impl Drop for ExampleBase {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swapchain_image_count() {
+        let capabilities = vk::SurfaceCapabilitiesKHR {
+            min_image_count: 2,
+            max_image_count: 4,
+            ..Default::default()
+        };
+        assert_eq!(swapchain_image_count(None, &capabilities), 3);
+        assert_eq!(swapchain_image_count(Some(2), &capabilities), 2);
+        assert_eq!(swapchain_image_count(Some(1), &capabilities), 2);
+        assert_eq!(swapchain_image_count(Some(8), &capabilities), 4);
+
+        let unlimited = vk::SurfaceCapabilitiesKHR {
+            min_image_count: 2,
+            max_image_count: 0,
+            ..Default::default()
+        };
+        assert_eq!(swapchain_image_count(Some(8), &unlimited), 8);
+
+        // a minimum at the maximum leaves no room for the extra image
+        let fixed = vk::SurfaceCapabilitiesKHR {
+            min_image_count: 3,
+            max_image_count: 3,
+            ..Default::default()
+        };
+        assert_eq!(swapchain_image_count(None, &fixed), 3);
+    }
+}
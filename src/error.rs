@@ -0,0 +1,54 @@
+use ash::vk;
+use thiserror::Error;
+
+/// Everything the renderer can fail with that an application might want to recover from, like
+/// running out of device memory or a shader that doesn't compile after an edit. Programming
+/// errors, like using a buffer without the right usage flags, still panic.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Vulkan call failed: {0}")]
+    Vulkan(#[from] vk::Result),
+    #[error("GPU memory allocation failed: {0}")]
+    Allocation(#[from] crate::allocator::AllocationError),
+    #[error("Failed to compile shader {path}: {message}")]
+    ShaderCompile { path: String, message: String },
+    #[error("Failed to reflect shader: {0}")]
+    Reflection(#[from] rspirv_reflect::ReflectError),
+    #[error("Invalid write to descriptor set {set}: {message}")]
+    DescriptorWrite {
+        set: u32,
+        binding: u32,
+        message: String,
+    },
+    #[error("Descriptor set {0:?} doesn't belong to the pipeline")]
+    ForeignDescriptorSet(vk::DescriptorSet),
+    #[error("Failed to spawn thread pool: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+    #[error("I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to write zip: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("Failed to parse font: {0}")]
+    Font(String),
+    #[error("Failed to load image: {0}")]
+    Image(#[from] image::ImageError),
+    #[cfg(feature = "gltf")]
+    #[error("Failed to load glTF: {0}")]
+    Gltf(#[from] gltf::Error),
+    #[cfg(feature = "openxr")]
+    #[error("OpenXR call failed: {0}")]
+    Xr(#[from] openxr::sys::Result),
+    #[error("Failed to load render graph {path}: {message}")]
+    GraphFile { path: String, message: String },
+    #[error("Shader has no binding called {0}")]
+    UnknownBinding(String),
+    #[error("Push constants {type_name} don't match the shader: {message}")]
+    PushConstantLayout {
+        type_name: &'static str,
+        message: String,
+    },
+    #[error("{0}")]
+    Unsupported(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
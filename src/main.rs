@@ -17,10 +17,21 @@ use render::primitives;
 use render::RenderPlugin;
 use std::default::Default;
 
+/// Opens a puffin scope until the end of the enclosing block, compiled out without the `puffin`
+/// feature. Users embedding puffin still have to call `puffin::GlobalProfiler::new_frame`.
+macro_rules! profile_scope {
+    ($name:expr $(, $data:expr)?) => {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!($name $(, $data)?);
+    };
+}
+
+mod allocator;
 mod buffer;
 mod camera_controller;
 mod chunky_list;
 mod ctx;
+mod error;
 mod passes;
 mod render;
 
@@ -93,13 +104,7 @@ fn spawn_stuff(
         .spawn(CameraBundle {
             transform: Transform::from_translation(Vec3::new(0.0, 0.0, 10.0))
                 .looking_at(Vec3::ZERO, Vec3::Y),
-            camera: Camera {
-                projection: Mat4::perspective_infinite_reverse_rh(
-                    60_f32.to_radians(),
-                    16.0 / 9.0,
-                    0.1,
-                ),
-            },
+            camera: Camera::perspective(60_f32.to_radians(), 16.0 / 9.0, 0.1),
         })
         .insert(CameraController::default());
 }
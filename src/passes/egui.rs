@@ -1,10 +1,9 @@
 use ash::vk;
 use egui::{epaint::Vertex, Context};
-use gpu_allocator::MemoryLocation;
 use inline_spirv::inline_spirv;
 use winit::event_loop::{self, EventLoop};
 
-use crate::buffer::Buffer;
+use crate::{allocator::MemoryLocation, buffer::Buffer};
 
 pub struct EguiPass {
     context: egui::Context,
@@ -0,0 +1,733 @@
+use std::mem::size_of;
+
+use ash::{extensions::khr::AccelerationStructure, vk};
+use bevy::prelude::*;
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{
+    barrier::{self, Usage},
+    deferred_destroy::{DeferredDestroyQueue, DeferredResource},
+    descriptor_writer::DescriptorWriter,
+    RenderAllocator, RenderInstance,
+};
+
+/// Triangles of a single geometry in a [`Blas`], read through the device addresses of the buffers.
+/// The buffers need the `ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR` usage.
+#[derive(Clone, Copy, Debug)]
+pub struct BlasGeometry<'a> {
+    pub vertex_buffer: &'a Buffer,
+    /// Format of the position, which has to be the first member of the vertex.
+    pub vertex_format: vk::Format,
+    pub vertex_stride: u64,
+    pub vertex_count: u32,
+    /// `u32` indices, without it every three vertices make up a triangle.
+    pub index_buffer: Option<&'a Buffer>,
+    pub triangle_count: u32,
+    /// Opaque geometry skips the any hit shaders.
+    pub opaque: bool,
+}
+
+impl<'a> BlasGeometry<'a> {
+    fn to_vk(self) -> vk::AccelerationStructureGeometryKHR<'static> {
+        validate_build_input(self.vertex_buffer);
+        let mut triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::default()
+            .vertex_format(self.vertex_format)
+            .vertex_data(vk::DeviceOrHostAddressConstKHR {
+                device_address: self.vertex_buffer.device_addr,
+            })
+            .vertex_stride(self.vertex_stride)
+            .max_vertex(self.vertex_count.saturating_sub(1));
+
+        triangles = match self.index_buffer {
+            Some(index_buffer) => {
+                validate_build_input(index_buffer);
+                triangles.index_type(vk::IndexType::UINT32).index_data(
+                    vk::DeviceOrHostAddressConstKHR {
+                        device_address: index_buffer.device_addr,
+                    },
+                )
+            }
+            None => triangles.index_type(vk::IndexType::NONE_KHR),
+        };
+
+        vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR { triangles })
+            .flags(if self.opaque {
+                vk::GeometryFlagsKHR::OPAQUE
+            } else {
+                vk::GeometryFlagsKHR::empty()
+            })
+    }
+}
+
+/// Bottom-level acceleration structure over the triangles of one or more geometries.
+#[derive(Debug)]
+pub struct Blas {
+    pub handle: vk::AccelerationStructureKHR,
+    pub buffer: Buffer,
+    /// Address used to reference the BLAS from TLAS instances.
+    pub device_addr: u64,
+    pub flags: vk::BuildAccelerationStructureFlagsKHR,
+    /// Released when the BLAS gets compacted and can't be updated anymore.
+    scratch: Option<Buffer>,
+    /// Triangle count of each geometry, updates have to keep them the same.
+    primitive_counts: Vec<u32>,
+}
+
+impl Blas {
+    /// Creates the acceleration structure and records its build into `command_buffer`. The
+    /// geometry buffers have to stay alive until the command buffer finished executing.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        command_buffer: vk::CommandBuffer,
+        geometries: &[BlasGeometry],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<Self> {
+        let loader = acceleration_structure_loader(render_instance);
+
+        let vk_geometries = geometries
+            .iter()
+            .map(|geometry| geometry.to_vk())
+            .collect::<Vec<_>>();
+        let primitive_counts = geometries
+            .iter()
+            .map(|geometry| geometry.triangle_count)
+            .collect::<Vec<_>>();
+
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(flags)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(&vk_geometries);
+
+        let sizes = unsafe {
+            loader.get_acceleration_structure_build_sizes(
+                vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                &build_info,
+                &primitive_counts,
+            )
+        };
+
+        let (handle, buffer) = create_acceleration_structure(
+            render_instance,
+            render_allocator,
+            vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+            sizes.acceleration_structure_size,
+        )?;
+        let scratch = create_scratch_buffer(
+            render_instance,
+            render_allocator,
+            sizes.build_scratch_size.max(sizes.update_scratch_size),
+        )?;
+
+        let build_info = build_info.dst_acceleration_structure(handle).scratch_data(
+            vk::DeviceOrHostAddressKHR {
+                device_address: scratch_address(render_instance, &scratch),
+            },
+        );
+        let ranges = primitive_counts
+            .iter()
+            .map(|count| {
+                vk::AccelerationStructureBuildRangeInfoKHR::default().primitive_count(*count)
+            })
+            .collect::<Vec<_>>();
+
+        record_build(render_instance, command_buffer, &[build_info], &[&ranges]);
+
+        let device_addr = unsafe {
+            loader.get_acceleration_structure_device_address(
+                &vk::AccelerationStructureDeviceAddressInfoKHR::default()
+                    .acceleration_structure(handle),
+            )
+        };
+
+        Ok(Self {
+            handle,
+            buffer,
+            device_addr,
+            flags,
+            scratch: Some(scratch),
+            primitive_counts,
+        })
+    }
+
+    /// Refits the BLAS to new vertex data, for deformable or skinned geometry. The geometries
+    /// must match the ones it was built with apart from the buffers and vertex positions, and
+    /// the BLAS needs the `ALLOW_UPDATE` flag.
+    pub fn update(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        geometries: &[BlasGeometry],
+    ) {
+        assert!(
+            self.flags
+                .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE),
+            "Blas was created without ALLOW_UPDATE"
+        );
+        assert_eq!(
+            geometries.len(),
+            self.primitive_counts.len(),
+            "Blas updates can't change the amount of geometries"
+        );
+        for (geometry, count) in geometries.iter().zip(&self.primitive_counts) {
+            assert_eq!(
+                geometry.triangle_count, *count,
+                "Blas updates can't change the amount of triangles"
+            );
+        }
+        let scratch = self
+            .scratch
+            .as_ref()
+            .expect("Blas scratch buffer was released");
+
+        let vk_geometries = geometries
+            .iter()
+            .map(|geometry| geometry.to_vk())
+            .collect::<Vec<_>>();
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+            .flags(self.flags)
+            .mode(vk::BuildAccelerationStructureModeKHR::UPDATE)
+            .geometries(&vk_geometries)
+            .src_acceleration_structure(self.handle)
+            .dst_acceleration_structure(self.handle)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: scratch_address(render_instance, scratch),
+            });
+        let ranges = self
+            .primitive_counts
+            .iter()
+            .map(|count| {
+                vk::AccelerationStructureBuildRangeInfoKHR::default().primitive_count(*count)
+            })
+            .collect::<Vec<_>>();
+
+        record_build(render_instance, command_buffer, &[build_info], &[&ranges]);
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let loader = acceleration_structure_loader(render_instance);
+        unsafe { loader.destroy_acceleration_structure(self.handle, None) };
+        self.buffer
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+        if let Some(scratch) = self.scratch.as_mut() {
+            scratch.destroy(render_instance.device(), &mut render_allocator.allocator());
+        }
+    }
+}
+
+/// Queries the compacted sizes of BLASes built with `ALLOW_COMPACTION`, so they can be copied
+/// into right-sized acceleration structures once the builds have finished executing.
+pub struct BlasCompaction {
+    query_pool: vk::QueryPool,
+    count: u32,
+}
+
+impl BlasCompaction {
+    /// Records the size queries, the builds have to be recorded before this in the same order.
+    pub fn query(
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        blases: &[&Blas],
+    ) -> Result<Self> {
+        for blas in blases {
+            assert!(
+                blas.flags
+                    .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION),
+                "Blas was created without ALLOW_COMPACTION"
+            );
+        }
+
+        let device = render_instance.device();
+        let loader = acceleration_structure_loader(render_instance);
+        let count = blases.len() as u32;
+        let handles = blases.iter().map(|blas| blas.handle).collect::<Vec<_>>();
+
+        let query_pool = unsafe {
+            device.create_query_pool(
+                &vk::QueryPoolCreateInfo::default()
+                    .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+                    .query_count(count.max(1)),
+                None,
+            )
+        }?;
+
+        unsafe {
+            device.cmd_reset_query_pool(command_buffer, query_pool, 0, count.max(1));
+            if count > 0 {
+                loader.cmd_write_acceleration_structures_properties(
+                    command_buffer,
+                    &handles,
+                    vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                    query_pool,
+                    0,
+                );
+            }
+        }
+
+        Ok(Self { query_pool, count })
+    }
+
+    /// Records copies of `blases` into right-sized acceleration structures and swaps them in.
+    /// The command buffer the queries were recorded in has to be finished, the originals are
+    /// destroyed through `destroy_queue` once the copies are done. Returns the bytes saved.
+    pub fn compact(
+        self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        command_buffer: vk::CommandBuffer,
+        blases: &mut [&mut Blas],
+        destroy_queue: &mut DeferredDestroyQueue,
+    ) -> Result<u64> {
+        assert_eq!(
+            blases.len() as u32,
+            self.count,
+            "Compacting a different amount of BLASes than were queried"
+        );
+        let device = render_instance.device();
+        let loader = acceleration_structure_loader(render_instance);
+
+        let mut sizes = vec![0u64; self.count as usize];
+        if self.count > 0 {
+            unsafe {
+                device.get_query_pool_results(
+                    self.query_pool,
+                    0,
+                    &mut sizes,
+                    vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+                )
+            }?;
+        }
+        unsafe { device.destroy_query_pool(self.query_pool, None) };
+
+        let mut saved = 0;
+        for (blas, size) in blases.iter_mut().zip(sizes) {
+            let (handle, buffer) = create_acceleration_structure(
+                render_instance,
+                render_allocator,
+                vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+                size,
+            )?;
+            unsafe {
+                loader.cmd_copy_acceleration_structure(
+                    command_buffer,
+                    &vk::CopyAccelerationStructureInfoKHR::default()
+                        .src(blas.handle)
+                        .dst(handle)
+                        .mode(vk::CopyAccelerationStructureModeKHR::COMPACT),
+                )
+            };
+            saved += blas.buffer.size.saturating_sub(size);
+
+            let old_handle = std::mem::replace(&mut blas.handle, handle);
+            let old_buffer = std::mem::replace(&mut blas.buffer, buffer);
+            destroy_queue.push(DeferredResource::AccelerationStructure(
+                old_handle, old_buffer,
+            ));
+            if !blas
+                .flags
+                .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE)
+            {
+                if let Some(scratch) = blas.scratch.take() {
+                    destroy_queue.push(scratch);
+                }
+            }
+
+            blas.device_addr = unsafe {
+                loader.get_acceleration_structure_device_address(
+                    &vk::AccelerationStructureDeviceAddressInfoKHR::default()
+                        .acceleration_structure(handle),
+                )
+            };
+        }
+
+        unsafe {
+            render_instance.0.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().memory_barriers(&[barrier::memory_barrier(
+                    Usage::AccelerationStructureBuild,
+                    Usage::AccelerationStructureRead,
+                )]),
+            )
+        };
+
+        Ok(saved)
+    }
+}
+
+pub(crate) fn acceleration_structure_loader(
+    render_instance: &RenderInstance,
+) -> &AccelerationStructure {
+    render_instance
+        .0
+        .acceleration_structure
+        .as_ref()
+        .expect("Device doesn't support VK_KHR_acceleration_structure")
+}
+
+fn validate_build_input(buffer: &Buffer) {
+    assert!(
+        buffer
+            .usage
+            .contains(vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR),
+        "Buffer used as acceleration structure build input was created without \
+         ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR usage"
+    );
+}
+
+pub(crate) fn create_acceleration_structure(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    ty: vk::AccelerationStructureTypeKHR,
+    size: u64,
+) -> Result<(vk::AccelerationStructureKHR, Buffer)> {
+    let buffer = Buffer::new(
+        render_instance.device(),
+        &mut render_allocator.allocator(),
+        &vk::BufferCreateInfo::default()
+            .size(size)
+            .usage(vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        MemoryLocation::GpuOnly,
+    )?;
+
+    let handle = unsafe {
+        acceleration_structure_loader(render_instance).create_acceleration_structure(
+            &vk::AccelerationStructureCreateInfoKHR::default()
+                .buffer(buffer.buffer)
+                .size(size)
+                .ty(ty),
+            None,
+        )?
+    };
+
+    Ok((handle, buffer))
+}
+
+/// The scratch buffer is over-allocated so its address can be aligned afterwards.
+pub(crate) fn create_scratch_buffer(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    size: u64,
+) -> Result<Buffer> {
+    let alignment = render_instance
+        .0
+        .min_acceleration_structure_scratch_offset_alignment as u64;
+    Buffer::new(
+        render_instance.device(),
+        &mut render_allocator.allocator(),
+        &vk::BufferCreateInfo::default()
+            .size(size + alignment)
+            .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        MemoryLocation::GpuOnly,
+    )
+}
+
+pub(crate) fn scratch_address(render_instance: &RenderInstance, scratch: &Buffer) -> u64 {
+    let alignment = (render_instance
+        .0
+        .min_acceleration_structure_scratch_offset_alignment as u64)
+        .max(1);
+    (scratch.device_addr + alignment - 1) / alignment * alignment
+}
+
+/// Records the builds surrounded by barriers, so the inputs written by earlier transfers are
+/// visible and the result can be traversed or used to build a TLAS afterwards.
+pub(crate) fn record_build(
+    render_instance: &RenderInstance,
+    command_buffer: vk::CommandBuffer,
+    infos: &[vk::AccelerationStructureBuildGeometryInfoKHR],
+    ranges: &[&[vk::AccelerationStructureBuildRangeInfoKHR]],
+) {
+    let renderer = render_instance.0.as_ref();
+    let loader = acceleration_structure_loader(render_instance);
+
+    unsafe {
+        renderer.synchronization2.cmd_pipeline_barrier2(
+            command_buffer,
+            &vk::DependencyInfo::default().memory_barriers(&[
+                barrier::memory_barrier(
+                    Usage::TransferWrite,
+                    Usage::AccelerationStructureBuildInput,
+                ),
+                // scratch memory may still be in use by a previous build
+                barrier::memory_barrier(
+                    Usage::AccelerationStructureBuild,
+                    Usage::AccelerationStructureBuild,
+                ),
+            ]),
+        );
+
+        loader.cmd_build_acceleration_structures(command_buffer, infos, ranges);
+
+        renderer.synchronization2.cmd_pipeline_barrier2(
+            command_buffer,
+            &vk::DependencyInfo::default().memory_barriers(&[barrier::memory_barrier(
+                Usage::AccelerationStructureBuild,
+                Usage::AccelerationStructureRead,
+            )]),
+        );
+    }
+}
+
+/// An instance of a [`Blas`] placed in a [`Tlas`].
+#[derive(Clone, Copy, Debug)]
+pub struct TlasInstance {
+    pub transform: Mat4,
+    /// `gl_InstanceCustomIndexEXT` in shaders, only the lower 24 bits are used.
+    pub custom_index: u32,
+    /// Only traced by rays whose cull mask shares a bit with this mask.
+    pub mask: u8,
+    /// Offset of the hit group of this instance in the shader binding table.
+    pub sbt_offset: u32,
+    pub flags: vk::GeometryInstanceFlagsKHR,
+    pub blas_address: u64,
+}
+
+impl TlasInstance {
+    pub fn new(blas: &Blas, transform: Mat4) -> Self {
+        Self {
+            transform,
+            custom_index: 0,
+            mask: 0xff,
+            sbt_offset: 0,
+            flags: vk::GeometryInstanceFlagsKHR::empty(),
+            blas_address: blas.device_addr,
+        }
+    }
+
+    fn to_raw(self) -> RawTlasInstance {
+        // row major 3x4 matrix
+        let rows = self.transform.transpose().to_cols_array();
+        let mut transform = [0.0; 12];
+        transform.copy_from_slice(&rows[..12]);
+
+        RawTlasInstance {
+            transform,
+            // 24 bits of the index or offset with 8 bits of the mask or flags on top
+            custom_index_and_mask: (self.custom_index & 0xff_ffff) | (u32::from(self.mask) << 24),
+            sbt_offset_and_flags: (self.sbt_offset & 0xff_ffff) | (self.flags.as_raw() << 24),
+            blas_address: self.blas_address,
+        }
+    }
+}
+
+/// The layout of `vk::AccelerationStructureInstanceKHR`, which holds a union and can't be
+/// [`bytemuck::Pod`] itself.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct RawTlasInstance {
+    transform: [f32; 12],
+    custom_index_and_mask: u32,
+    sbt_offset_and_flags: u32,
+    blas_address: u64,
+}
+
+const _: () =
+    assert!(size_of::<RawTlasInstance>() == size_of::<vk::AccelerationStructureInstanceKHR>());
+
+/// Top-level acceleration structure, sized for a maximum amount of instances up front so it
+/// can be rebuilt or updated every frame without reallocating.
+pub struct Tlas {
+    pub handle: vk::AccelerationStructureKHR,
+    pub buffer: Buffer,
+    pub flags: vk::BuildAccelerationStructureFlagsKHR,
+    instance_buffer: Buffer,
+    scratch: Buffer,
+    max_instances: u32,
+    instance_count: u32,
+}
+
+impl Tlas {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        max_instances: u32,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<Self> {
+        let loader = acceleration_structure_loader(render_instance);
+
+        let instance_buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(
+                    (max_instances.max(1) as usize
+                        * size_of::<vk::AccelerationStructureInstanceKHR>())
+                        as u64,
+                )
+                .usage(vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+
+        let geometries = [instances_geometry(&instance_buffer)];
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(flags)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(&geometries);
+        let sizes = unsafe {
+            loader.get_acceleration_structure_build_sizes(
+                vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                &build_info,
+                &[max_instances],
+            )
+        };
+
+        let (handle, buffer) = create_acceleration_structure(
+            render_instance,
+            render_allocator,
+            vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+            sizes.acceleration_structure_size,
+        )?;
+        let scratch = create_scratch_buffer(
+            render_instance,
+            render_allocator,
+            sizes.build_scratch_size.max(sizes.update_scratch_size),
+        )?;
+
+        Ok(Self {
+            handle,
+            buffer,
+            flags,
+            instance_buffer,
+            scratch,
+            max_instances,
+            instance_count: 0,
+        })
+    }
+
+    pub fn instance_count(&self) -> u32 {
+        self.instance_count
+    }
+
+    /// Writes the instances and records a full build. The instance buffer is host visible and
+    /// overwritten right away, so the previous build has to be finished executing.
+    pub fn build(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        instances: &[TlasInstance],
+    ) {
+        self.write_instances(instances);
+        self.record(
+            render_instance,
+            command_buffer,
+            vk::BuildAccelerationStructureModeKHR::BUILD,
+        );
+    }
+
+    /// Refits the previous build to the new transforms, which is cheaper than [`Tlas::build`]
+    /// but requires the same instances and `ALLOW_UPDATE` in the flags.
+    pub fn update(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        instances: &[TlasInstance],
+    ) {
+        assert!(
+            self.flags
+                .contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_UPDATE),
+            "Tlas was created without ALLOW_UPDATE"
+        );
+        assert_eq!(
+            instances.len() as u32,
+            self.instance_count,
+            "Tlas updates can't change the amount of instances"
+        );
+        self.write_instances(instances);
+        self.record(
+            render_instance,
+            command_buffer,
+            vk::BuildAccelerationStructureModeKHR::UPDATE,
+        );
+    }
+
+    fn write_instances(&mut self, instances: &[TlasInstance]) {
+        assert!(
+            instances.len() <= self.max_instances as usize,
+            "Tlas can hold {} instances, got {}",
+            self.max_instances,
+            instances.len()
+        );
+        let instances = instances
+            .iter()
+            .map(|instance| instance.to_raw())
+            .collect::<Vec<_>>();
+        self.instance_buffer.copy_from_slice(&instances, 0);
+        self.instance_count = instances.len() as u32;
+    }
+
+    fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        mode: vk::BuildAccelerationStructureModeKHR,
+    ) {
+        let geometries = [instances_geometry(&self.instance_buffer)];
+        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(self.flags)
+            .mode(mode)
+            .geometries(&geometries)
+            .dst_acceleration_structure(self.handle)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: scratch_address(render_instance, &self.scratch),
+            });
+        if mode == vk::BuildAccelerationStructureModeKHR::UPDATE {
+            build_info = build_info.src_acceleration_structure(self.handle);
+        }
+        let ranges = [vk::AccelerationStructureBuildRangeInfoKHR::default()
+            .primitive_count(self.instance_count)];
+
+        record_build(render_instance, command_buffer, &[build_info], &[&ranges]);
+    }
+
+    /// Binds the TLAS to an `accelerationStructureEXT` binding of a reflected descriptor set.
+    pub fn write_descriptor(
+        &self,
+        render_instance: &RenderInstance,
+        set: vk::DescriptorSet,
+        binding: u32,
+    ) {
+        DescriptorWriter::new()
+            .acceleration_structure(set, binding, self)
+            .flush(render_instance.device());
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let loader = acceleration_structure_loader(render_instance);
+        unsafe { loader.destroy_acceleration_structure(self.handle, None) };
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.buffer.destroy(device, &mut allocator);
+        self.instance_buffer.destroy(device, &mut allocator);
+        self.scratch.destroy(device, &mut allocator);
+    }
+}
+
+fn instances_geometry(instance_buffer: &Buffer) -> vk::AccelerationStructureGeometryKHR<'static> {
+    vk::AccelerationStructureGeometryKHR::default()
+        .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+        .geometry(vk::AccelerationStructureGeometryDataKHR {
+            instances: vk::AccelerationStructureGeometryInstancesDataKHR::default()
+                .array_of_pointers(false)
+                .data(vk::DeviceOrHostAddressConstKHR {
+                    device_address: instance_buffer.device_addr,
+                }),
+        })
+}
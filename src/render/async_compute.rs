@@ -0,0 +1,271 @@
+use std::sync::Mutex;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::error::Result;
+
+use super::{
+    gpu_scope,
+    query::{QueryKind, QueryPoolRing},
+    RenderInstance,
+};
+
+#[derive(Debug)]
+struct AsyncComputeFrame {
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    finished_semaphore: vk::Semaphore,
+}
+
+/// Submits compute work to the dedicated compute queue, so it can overlap with the graphics
+/// work of the frame. [`AsyncCompute::submit`] returns a semaphore the graphics submission that
+/// consumes the results has to wait on, which joins the two queues back together. Devices
+/// without a compute-only queue family submit to the graphics queue instead, which keeps the
+/// joins but doesn't overlap anything.
+///
+/// The passes added with [`RenderGraph::add_async_compute_pass`] are submitted through it once
+/// per frame, see [`SequentialNode::record_async_compute`].
+///
+/// Resources used on both queues have to be created with `SharingMode::CONCURRENT` over
+/// [`AsyncCompute::queue_family_indices`], otherwise they need queue family ownership transfers.
+///
+/// [`RenderGraph::add_async_compute_pass`]: super::graph::RenderGraph::add_async_compute_pass
+/// [`SequentialNode::record_async_compute`]: super::SequentialNode::record_async_compute
+#[derive(Resource, Debug)]
+pub struct AsyncCompute {
+    pub queue: vk::Queue,
+    pub queue_family_index: u32,
+    /// Whether `queue` is a queue of its own, rather than the graphics queue.
+    pub dedicated: bool,
+    command_pool: vk::CommandPool,
+    /// One per frame in flight, a frame waits for the submission that used it before.
+    frames: Vec<AsyncComputeFrame>,
+    frame: usize,
+    /// Start and end of every submission, `None` when the queue family has no timestamps.
+    timestamps: Option<QueryPoolRing>,
+}
+
+impl AsyncCompute {
+    pub fn new(render_instance: &RenderInstance) -> Result<Self> {
+        let renderer = render_instance.0.as_ref();
+        let (queue, queue_family_index, dedicated) = match (
+            renderer.async_compute_queue,
+            renderer.async_compute_queue_family_index,
+        ) {
+            (Some(queue), Some(queue_family_index)) => (queue, queue_family_index, true),
+            _ => (renderer.present_queue, renderer.queue_family_index, false),
+        };
+        let device = render_instance.device();
+
+        let command_pool = unsafe {
+            device.create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                    .queue_family_index(queue_family_index),
+                None,
+            )?
+        };
+        let mut async_compute = Self {
+            queue,
+            queue_family_index,
+            dedicated,
+            command_pool,
+            frames: vec![],
+            frame: 0,
+            timestamps: None,
+        };
+        // whatever was created before an error is destroyed with the rest
+        if let Err(e) = async_compute.create_frames(render_instance) {
+            async_compute.destroy(render_instance);
+            return Err(e);
+        }
+        Ok(async_compute)
+    }
+
+    fn create_frames(&mut self, render_instance: &RenderInstance) -> Result<()> {
+        let renderer = render_instance.0.as_ref();
+        let device = render_instance.device();
+        let frame_count = renderer.frames.len() as u32;
+
+        unsafe {
+            // the pool frees its command buffers along with it
+            let command_buffers = device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::default()
+                    .command_buffer_count(frame_count)
+                    .command_pool(self.command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY),
+            )?;
+            for command_buffer in command_buffers {
+                let fence = device.create_fence(
+                    &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
+                    None,
+                )?;
+                let finished_semaphore =
+                    match device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None) {
+                        Ok(semaphore) => semaphore,
+                        Err(e) => {
+                            device.destroy_fence(fence, None);
+                            return Err(e.into());
+                        }
+                    };
+                self.frames.push(AsyncComputeFrame {
+                    command_buffer,
+                    fence,
+                    finished_semaphore,
+                });
+            }
+
+            let timestamp_valid_bits = renderer
+                .instance
+                .get_physical_device_queue_family_properties(renderer.pdevice)
+                [self.queue_family_index as usize]
+                .timestamp_valid_bits;
+            if timestamp_valid_bits > 0 {
+                self.timestamps = Some(QueryPoolRing::new(
+                    render_instance,
+                    QueryKind::Timestamp,
+                    2,
+                    frame_count,
+                )?);
+            }
+        }
+        Ok(())
+    }
+
+    /// The graphics and the async compute queue family, for resources shared between them.
+    /// Only the graphics family when there is no dedicated queue.
+    pub fn queue_family_indices(&self, render_instance: &RenderInstance) -> Vec<u32> {
+        let mut families = vec![render_instance.0.queue_family_index];
+        if self.dedicated {
+            families.push(self.queue_family_index);
+        }
+        families
+    }
+
+    /// Records `f` and submits it to the compute queue without waiting for it. The returned
+    /// semaphore is signaled once the work is done and has to be waited on by exactly one later
+    /// submission, with the stages that consume the results as wait mask. Waits for the
+    /// submission from frames in flight ago first, so its command buffer can be reused.
+    pub fn submit<F: FnOnce(&ash::Device, vk::CommandBuffer)>(
+        &mut self,
+        render_instance: &RenderInstance,
+        wait_semaphores: &[vk::Semaphore],
+        f: F,
+    ) -> Result<vk::Semaphore> {
+        let device = render_instance.device();
+        self.frame = (self.frame + 1) % self.frames.len();
+        let frame = &self.frames[self.frame];
+
+        unsafe {
+            device.wait_for_fences(&[frame.fence], true, std::u64::MAX)?;
+            device.reset_command_buffer(
+                frame.command_buffer,
+                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+            )?;
+
+            device.begin_command_buffer(
+                frame.command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            if let Some(timestamps) = self.timestamps.as_mut() {
+                // the fence was waited on, so the submission that used the pool is done
+                timestamps.begin_frame(device, frame.command_buffer);
+                if let (Some(start), Some(end)) =
+                    (timestamps.timestamp_nanos(0), timestamps.timestamp_nanos(1))
+                {
+                    gpu_scope::record_async_compute_span(start, end);
+                }
+                timestamps.write_timestamp(
+                    device,
+                    frame.command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                );
+            }
+            f(device, frame.command_buffer);
+            if let Some(timestamps) = self.timestamps.as_mut() {
+                timestamps.write_timestamp(
+                    device,
+                    frame.command_buffer,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                );
+            }
+            device.end_command_buffer(frame.command_buffer)?;
+
+            let wait_mask = vec![vk::PipelineStageFlags::COMPUTE_SHADER; wait_semaphores.len()];
+            let command_buffers = [frame.command_buffer];
+            let signal_semaphores = [frame.finished_semaphore];
+            let submit_info = vk::SubmitInfo::default()
+                .wait_semaphores(wait_semaphores)
+                .wait_dst_stage_mask(&wait_mask)
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&signal_semaphores);
+
+            // the graphics queue is shared when there is no dedicated one
+            let _queue = render_instance.0.queue_lock.lock().unwrap();
+            // reset right before the submit, a fence left unsignaled by an earlier error would
+            // block the next wait forever
+            device.reset_fences(&[frame.fence])?;
+            device.queue_submit(self.queue, &[submit_info], frame.fence)?;
+        }
+
+        Ok(frame.finished_semaphore)
+    }
+
+    pub fn destroy(&mut self, render_instance: &RenderInstance) {
+        let device = render_instance.device();
+        unsafe {
+            for frame in self.frames.drain(..) {
+                device
+                    .wait_for_fences(&[frame.fence], true, std::u64::MAX)
+                    .unwrap();
+                device.destroy_semaphore(frame.finished_semaphore, None);
+                device.destroy_fence(frame.fence, None);
+            }
+            if let Some(mut timestamps) = self.timestamps.take() {
+                timestamps.destroy(device);
+            }
+            device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
+
+/// The semaphores of the async compute submission of this frame with the stages that wait for
+/// them, taken by the graphics submission. Whatever it didn't take is waited on at the end of
+/// the frame, a binary semaphore can't be signaled again before it's waited on.
+#[derive(Resource, Debug, Default)]
+pub struct AsyncComputeJoins {
+    joins: Mutex<Vec<(vk::Semaphore, vk::PipelineStageFlags)>>,
+}
+
+impl AsyncComputeJoins {
+    pub fn push(&self, semaphore: vk::Semaphore, stage: vk::PipelineStageFlags) {
+        self.joins.lock().unwrap().push((semaphore, stage));
+    }
+
+    pub fn take(&self) -> Vec<(vk::Semaphore, vk::PipelineStageFlags)> {
+        std::mem::take(&mut *self.joins.lock().unwrap())
+    }
+
+    /// Waits on the joins that no graphics submission took, with an empty submission.
+    pub fn wait_remaining(&self, render_instance: &RenderInstance) -> Result<()> {
+        let (semaphores, stages): (Vec<_>, Vec<_>) = self.take().into_iter().unzip();
+        if semaphores.is_empty() {
+            return Ok(());
+        }
+        let submit_info = vk::SubmitInfo::default()
+            .wait_semaphores(&semaphores)
+            .wait_dst_stage_mask(&stages);
+        let renderer = render_instance.0.as_ref();
+        let _queue = renderer.queue_lock.lock().unwrap();
+        unsafe {
+            render_instance.device().queue_submit(
+                renderer.present_queue,
+                &[submit_info],
+                vk::Fence::null(),
+            )?
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+/// Packs rectangles into a fixed size texture, row by row. Each rectangle goes into the first
+/// shelf that is tall enough and has room left, or opens a new shelf below the others. Good for
+/// similarly sized entries like glyphs, it never frees single rectangles.
+#[derive(Clone, Debug)]
+pub struct AtlasAllocator {
+    width: u32,
+    height: u32,
+    /// `(y, height, used width)` of every shelf.
+    shelves: Vec<(u32, u32, u32)>,
+}
+
+impl AtlasAllocator {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            shelves: vec![],
+        }
+    }
+
+    /// Returns the top left corner of the allocated rectangle, `None` when the atlas is full.
+    pub fn allocate(&mut self, width: u32, height: u32) -> Option<[u32; 2]> {
+        if width > self.width {
+            return None;
+        }
+
+        // shelves much taller than the rectangle would waste most of their space
+        if let Some((y, _, used)) = self.shelves.iter_mut().find(|(_, shelf_height, used)| {
+            height <= *shelf_height && height * 2 >= *shelf_height && *used + width <= self.width
+        }) {
+            let x = *used;
+            *used += width;
+            return Some([x, *y]);
+        }
+
+        let y = self
+            .shelves
+            .last()
+            .map_or(0, |(y, shelf_height, _)| y + shelf_height);
+        if y + height > self.height {
+            return None;
+        }
+        self.shelves.push((y, height, width));
+        Some([0, y])
+    }
+
+    pub fn clear(&mut self) {
+        self.shelves.clear();
+    }
+
+    pub fn size(&self) -> [u32; 2] {
+        [self.width, self.height]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_atlas_allocator() {
+        let mut atlas = AtlasAllocator::new(16, 16);
+        assert_eq!(atlas.allocate(8, 8), Some([0, 0]));
+        assert_eq!(atlas.allocate(8, 6), Some([8, 0]));
+        // doesn't fit next to the others anymore
+        assert_eq!(atlas.allocate(4, 8), Some([0, 8]));
+        // too short for the last shelf and no room for a new one
+        assert_eq!(atlas.allocate(2, 2), None);
+        atlas.clear();
+        assert_eq!(atlas.allocate(16, 16), Some([0, 0]));
+        assert_eq!(atlas.allocate(1, 1), None);
+    }
+}
@@ -0,0 +1,320 @@
+use ash::{extensions::khr::Synchronization2, vk};
+
+use super::tracking::{self, ResourceKind};
+
+/// How a resource is accessed by a pass. Barriers are derived from the usage a resource
+/// had in the previous pass and the usage it will have in the next one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Usage {
+    /// Contents are not needed, only valid as the source of a barrier.
+    Undefined,
+    ComputeRead,
+    ComputeWrite,
+    ComputeSampled,
+    VertexSampled,
+    FragmentSampled,
+    ColorAttachmentWrite,
+    DepthAttachmentWrite,
+    DepthAttachmentRead,
+    /// Read as the shading rate image of a [`RenderTarget`](super::passes::graphics::RenderTarget).
+    ShadingRateAttachment,
+    VertexBuffer,
+    IndexBuffer,
+    IndirectBuffer,
+    TransferRead,
+    TransferWrite,
+    Present,
+    /// Vertex, index and instance data read by an acceleration structure build.
+    AccelerationStructureBuildInput,
+    AccelerationStructureBuild,
+    /// Ray queries from compute or fragment shaders, or the source of another build.
+    AccelerationStructureRead,
+}
+
+impl Usage {
+    pub fn stage_mask(self) -> vk::PipelineStageFlags2 {
+        match self {
+            Usage::Undefined => vk::PipelineStageFlags2::TOP_OF_PIPE,
+            Usage::ComputeRead | Usage::ComputeWrite | Usage::ComputeSampled => {
+                vk::PipelineStageFlags2::COMPUTE_SHADER
+            }
+            Usage::VertexSampled => vk::PipelineStageFlags2::VERTEX_SHADER,
+            Usage::FragmentSampled => vk::PipelineStageFlags2::FRAGMENT_SHADER,
+            Usage::ColorAttachmentWrite => vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT,
+            Usage::DepthAttachmentWrite | Usage::DepthAttachmentRead => {
+                vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS
+            }
+            Usage::ShadingRateAttachment => {
+                vk::PipelineStageFlags2::FRAGMENT_SHADING_RATE_ATTACHMENT_KHR
+            }
+            Usage::VertexBuffer => vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT,
+            Usage::IndexBuffer => vk::PipelineStageFlags2::INDEX_INPUT,
+            Usage::IndirectBuffer => vk::PipelineStageFlags2::DRAW_INDIRECT,
+            Usage::TransferRead | Usage::TransferWrite => vk::PipelineStageFlags2::TRANSFER,
+            Usage::Present => vk::PipelineStageFlags2::BOTTOM_OF_PIPE,
+            Usage::AccelerationStructureBuildInput | Usage::AccelerationStructureBuild => {
+                vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR
+            }
+            Usage::AccelerationStructureRead => {
+                vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR
+                    | vk::PipelineStageFlags2::COMPUTE_SHADER
+                    | vk::PipelineStageFlags2::FRAGMENT_SHADER
+            }
+        }
+    }
+
+    pub fn access_mask(self) -> vk::AccessFlags2 {
+        match self {
+            Usage::Undefined | Usage::Present => vk::AccessFlags2::empty(),
+            Usage::ComputeRead => vk::AccessFlags2::SHADER_STORAGE_READ,
+            Usage::ComputeWrite => {
+                vk::AccessFlags2::SHADER_STORAGE_READ | vk::AccessFlags2::SHADER_STORAGE_WRITE
+            }
+            Usage::ComputeSampled | Usage::VertexSampled | Usage::FragmentSampled => {
+                vk::AccessFlags2::SHADER_SAMPLED_READ
+            }
+            Usage::ColorAttachmentWrite => {
+                vk::AccessFlags2::COLOR_ATTACHMENT_READ | vk::AccessFlags2::COLOR_ATTACHMENT_WRITE
+            }
+            Usage::DepthAttachmentWrite => {
+                vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE
+            }
+            Usage::DepthAttachmentRead => vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ,
+            Usage::ShadingRateAttachment => {
+                vk::AccessFlags2::FRAGMENT_SHADING_RATE_ATTACHMENT_READ_KHR
+            }
+            Usage::VertexBuffer => vk::AccessFlags2::VERTEX_ATTRIBUTE_READ,
+            Usage::IndexBuffer => vk::AccessFlags2::INDEX_READ,
+            Usage::IndirectBuffer => vk::AccessFlags2::INDIRECT_COMMAND_READ,
+            Usage::TransferRead => vk::AccessFlags2::TRANSFER_READ,
+            Usage::TransferWrite => vk::AccessFlags2::TRANSFER_WRITE,
+            Usage::AccelerationStructureBuildInput => vk::AccessFlags2::SHADER_READ,
+            Usage::AccelerationStructureBuild => {
+                vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR
+                    | vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR
+            }
+            Usage::AccelerationStructureRead => vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR,
+        }
+    }
+
+    pub fn is_write(self) -> bool {
+        matches!(
+            self,
+            Usage::ComputeWrite
+                | Usage::ColorAttachmentWrite
+                | Usage::DepthAttachmentWrite
+                | Usage::TransferWrite
+                | Usage::AccelerationStructureBuild
+        )
+    }
+
+    /// Read after read needs no barrier, unless an image changes layout on the way.
+    pub fn needs_barrier(from: Usage, to: Usage, is_image: bool) -> bool {
+        from.is_write() || to.is_write() || (is_image && from.image_layout() != to.image_layout())
+    }
+
+    /// The layout an image has to be in for this usage, buffer-only usages map to `UNDEFINED`.
+    pub fn image_layout(self) -> vk::ImageLayout {
+        match self {
+            Usage::Undefined
+            | Usage::VertexBuffer
+            | Usage::IndexBuffer
+            | Usage::IndirectBuffer
+            | Usage::AccelerationStructureBuildInput
+            | Usage::AccelerationStructureBuild
+            | Usage::AccelerationStructureRead => vk::ImageLayout::UNDEFINED,
+            Usage::ComputeRead | Usage::ComputeWrite => vk::ImageLayout::GENERAL,
+            Usage::ComputeSampled | Usage::VertexSampled | Usage::FragmentSampled => {
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            }
+            Usage::ColorAttachmentWrite => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            Usage::DepthAttachmentWrite => vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL,
+            Usage::DepthAttachmentRead => vk::ImageLayout::DEPTH_READ_ONLY_OPTIMAL,
+            Usage::ShadingRateAttachment => {
+                vk::ImageLayout::FRAGMENT_SHADING_RATE_ATTACHMENT_OPTIMAL_KHR
+            }
+            Usage::TransferRead => vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            Usage::TransferWrite => vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            Usage::Present => vk::ImageLayout::PRESENT_SRC_KHR,
+        }
+    }
+}
+
+pub fn aspect_mask_from_format(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => {
+            vk::ImageAspectFlags::DEPTH
+        }
+        vk::Format::D16_UNORM_S8_UINT
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::D32_SFLOAT_S8_UINT => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        vk::Format::S8_UINT => vk::ImageAspectFlags::STENCIL,
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+pub fn image_barrier(
+    image: vk::Image,
+    aspect_mask: vk::ImageAspectFlags,
+    from: Usage,
+    to: Usage,
+) -> vk::ImageMemoryBarrier2<'static> {
+    tracking::assert_alive(ResourceKind::Image, image);
+    tracking::set_layout(image, to.image_layout());
+    vk::ImageMemoryBarrier2::default()
+        .src_stage_mask(from.stage_mask())
+        .src_access_mask(from.access_mask())
+        .old_layout(from.image_layout())
+        .dst_stage_mask(to.stage_mask())
+        .dst_access_mask(to.access_mask())
+        .new_layout(to.image_layout())
+        .image(image)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask,
+            level_count: vk::REMAINING_MIP_LEVELS,
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+            ..Default::default()
+        })
+}
+
+pub fn buffer_barrier(
+    buffer: vk::Buffer,
+    from: Usage,
+    to: Usage,
+) -> vk::BufferMemoryBarrier2<'static> {
+    tracking::assert_alive(ResourceKind::Buffer, buffer);
+    vk::BufferMemoryBarrier2::default()
+        .src_stage_mask(from.stage_mask())
+        .src_access_mask(from.access_mask())
+        .dst_stage_mask(to.stage_mask())
+        .dst_access_mask(to.access_mask())
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE)
+}
+
+/// Global barrier, for when the resources involved aren't known or there are too many of them.
+pub fn memory_barrier(from: Usage, to: Usage) -> vk::MemoryBarrier2<'static> {
+    vk::MemoryBarrier2::default()
+        .src_stage_mask(from.stage_mask())
+        .src_access_mask(from.access_mask())
+        .dst_stage_mask(to.stage_mask())
+        .dst_access_mask(to.access_mask())
+}
+
+/// Collects the barriers between two passes, so they are recorded as one
+/// `vkCmdPipelineBarrier2` instead of one call per resource. Transitions that need no barrier,
+/// see [`Usage::needs_barrier`], are left out.
+#[derive(Debug, Default)]
+pub struct BarrierBatch {
+    image_barriers: Vec<vk::ImageMemoryBarrier2<'static>>,
+    buffer_barriers: Vec<vk::BufferMemoryBarrier2<'static>>,
+    memory_barriers: Vec<vk::MemoryBarrier2<'static>>,
+}
+
+impl BarrierBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.image_barriers.is_empty()
+            && self.buffer_barriers.is_empty()
+            && self.memory_barriers.is_empty()
+    }
+
+    pub fn image(
+        &mut self,
+        image: vk::Image,
+        aspect_mask: vk::ImageAspectFlags,
+        from: Usage,
+        to: Usage,
+    ) -> &mut Self {
+        if Usage::needs_barrier(from, to, true) {
+            self.image_barriers
+                .push(image_barrier(image, aspect_mask, from, to));
+        }
+        self
+    }
+
+    pub fn buffer(&mut self, buffer: vk::Buffer, from: Usage, to: Usage) -> &mut Self {
+        if Usage::needs_barrier(from, to, false) {
+            self.buffer_barriers.push(buffer_barrier(buffer, from, to));
+        }
+        self
+    }
+
+    pub fn memory(&mut self, from: Usage, to: Usage) -> &mut Self {
+        if Usage::needs_barrier(from, to, false) {
+            self.memory_barriers.push(memory_barrier(from, to));
+        }
+        self
+    }
+
+    /// For barriers that don't fit [`Usage`], like subresource ranges or queue transfers.
+    pub fn push_image(&mut self, barrier: vk::ImageMemoryBarrier2<'static>) -> &mut Self {
+        self.image_barriers.push(barrier);
+        self
+    }
+
+    pub fn push_buffer(&mut self, barrier: vk::BufferMemoryBarrier2<'static>) -> &mut Self {
+        self.buffer_barriers.push(barrier);
+        self
+    }
+
+    /// Records all barriers at once and clears the batch, does nothing when it's empty.
+    pub fn flush(
+        &mut self,
+        synchronization2: &Synchronization2,
+        command_buffer: vk::CommandBuffer,
+    ) {
+        if self.is_empty() {
+            return;
+        }
+        unsafe {
+            synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default()
+                    .image_memory_barriers(&self.image_barriers)
+                    .buffer_memory_barriers(&self.buffer_barriers)
+                    .memory_barriers(&self.memory_barriers),
+            )
+        };
+        self.image_barriers.clear();
+        self.buffer_barriers.clear();
+        self.memory_barriers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_barrier_batch_skips_read_after_read() {
+        let mut batch = BarrierBatch::new();
+        batch
+            .buffer(vk::Buffer::null(), Usage::ComputeRead, Usage::VertexBuffer)
+            .memory(Usage::IndirectBuffer, Usage::ComputeRead);
+        assert!(batch.is_empty());
+
+        // same access, but the image has to change layout
+        batch.image(
+            vk::Image::null(),
+            vk::ImageAspectFlags::COLOR,
+            Usage::ComputeRead,
+            Usage::FragmentSampled,
+        );
+        batch.buffer(
+            vk::Buffer::null(),
+            Usage::ComputeWrite,
+            Usage::FragmentSampled,
+        );
+        assert_eq!(batch.image_barriers.len(), 1);
+        assert_eq!(batch.buffer_barriers.len(), 1);
+    }
+}
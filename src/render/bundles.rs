@@ -1,5 +1,6 @@
 use bevy::prelude::*;
 
+pub use super::camera::Camera;
 use super::{material::Material, mesh::Mesh};
 
 #[derive(Bundle, Clone, Debug)]
@@ -14,11 +15,6 @@ pub struct MaterialMeshBundle {
     // pub computed_visibility: ComputedVisibility,
 }
 
-#[derive(Component, Clone, Default)]
-pub struct Camera {
-    pub projection: Mat4,
-}
-
 #[derive(Bundle, Clone, Default)]
 pub struct CameraBundle {
     pub camera: Camera,
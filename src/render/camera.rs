@@ -0,0 +1,219 @@
+use bevy::prelude::*;
+
+/// Projections use reverse depth, the near plane maps to 1 and the far plane to 0, so depth
+/// tests use `Greater` and depth buffers are cleared to 0.
+#[derive(Clone, Copy, Debug)]
+pub enum Projection {
+    /// Infinite far plane, `fov_y` in radians.
+    Perspective { fov_y: f32, aspect: f32, near: f32 },
+    /// `height` world units are visible vertically, centered on the camera.
+    Orthographic {
+        height: f32,
+        aspect: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+impl Projection {
+    pub fn matrix(&self) -> Mat4 {
+        match *self {
+            Projection::Perspective {
+                fov_y,
+                aspect,
+                near,
+            } => Mat4::perspective_infinite_reverse_rh(fov_y, aspect, near),
+            Projection::Orthographic {
+                height,
+                aspect,
+                near,
+                far,
+            } => {
+                let half_height = height * 0.5;
+                let half_width = half_height * aspect;
+                Mat4::orthographic_rh(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    far,
+                    near,
+                )
+            }
+        }
+    }
+
+    pub fn set_aspect(&mut self, value: f32) {
+        match self {
+            Projection::Perspective { aspect, .. } | Projection::Orthographic { aspect, .. } => {
+                *aspect = value
+            }
+        }
+    }
+}
+
+/// The camera is placed by the [`Transform`] on the same entity, looking down its -Z axis.
+#[derive(Component, Clone, Debug)]
+pub struct Camera {
+    pub projection: Projection,
+    /// Subpixel offset in pixels, within -0.5 to 0.5. Set it from [`halton_jitter`] every frame
+    /// for temporal anti-aliasing.
+    pub jitter: Vec2,
+    /// Resolution the view is rendered at before it's upscaled, like
+    /// [`Upscaler::render_resolution`](super::passes::upscale::Upscaler::render_resolution).
+    /// The jitter is in pixels of this resolution. `None` renders at the surface resolution.
+    pub render_resolution: Option<UVec2>,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::perspective(60_f32.to_radians(), 16.0 / 9.0, 0.1)
+    }
+}
+
+impl Camera {
+    pub fn perspective(fov_y: f32, aspect: f32, near: f32) -> Self {
+        Self {
+            projection: Projection::Perspective {
+                fov_y,
+                aspect,
+                near,
+            },
+            jitter: Vec2::ZERO,
+            render_resolution: None,
+        }
+    }
+
+    pub fn orthographic(height: f32, aspect: f32, near: f32, far: f32) -> Self {
+        Self {
+            projection: Projection::Orthographic {
+                height,
+                aspect,
+                near,
+                far,
+            },
+            jitter: Vec2::ZERO,
+            render_resolution: None,
+        }
+    }
+
+    /// Offset the jitter moves clip space by, for a viewport of `viewport_size` pixels.
+    pub fn jitter_ndc(&self, viewport_size: Vec2) -> Vec2 {
+        self.jitter * 2.0 / viewport_size
+    }
+
+    /// The projection with the jitter applied, which works for both kinds of projection since
+    /// the offset is scaled by `w`.
+    pub fn jittered_projection(&self, viewport_size: Vec2) -> Mat4 {
+        Mat4::from_translation(self.jitter_ndc(viewport_size).extend(0.0))
+            * self.projection.matrix()
+    }
+}
+
+/// Points of the Halton (2, 3) sequence within -0.5 to 0.5, repeating every 16 frames.
+pub fn halton_jitter(frame_index: u32) -> Vec2 {
+    halton_jitter_sequence(frame_index, 16)
+}
+
+/// Like [`halton_jitter`] with a sequence of `phase_count` points, upscalers need more of them
+/// the larger the upscaling ratio is.
+pub fn halton_jitter_sequence(frame_index: u32, phase_count: u32) -> Vec2 {
+    let halton = |mut index: u32, base: u32| {
+        let mut fraction = 1.0;
+        let mut result = 0.0;
+        while index > 0 {
+            fraction /= base as f32;
+            result += fraction * (index % base) as f32;
+            index /= base;
+        }
+        result
+    };
+    let index = frame_index % phase_count.max(1) + 1;
+    Vec2::new(halton(index, 2), halton(index, 3)) - 0.5
+}
+
+/// Per-view constants, matching the `Camera` block in `shader/global.glsl`. The layout is the
+/// same under std140 and std430:
+///
+/// | offset | GLSL type | field                  |
+/// |--------|-----------|------------------------|
+/// | 0      | `mat4`    | `view_proj`            |
+/// | 64     | `mat4`    | `inverse_view_proj`    |
+/// | 128    | `mat4`    | `view`                 |
+/// | 192    | `mat4`    | `inverse_view`         |
+/// | 256    | `mat4`    | `proj`                 |
+/// | 320    | `mat4`    | `inverse_proj`         |
+/// | 384    | `vec3`    | `world_position`       |
+/// | 396    | `uint`    | `frame_index`          |
+/// | 400    | `mat4`    | `unjittered_view_proj` |
+/// | 464    | `mat4`    | `previous_view_proj`   |
+/// | 528    | `vec2`    | `viewport_size`        |
+/// | 536    | `vec2`    | `jitter`               |
+///
+/// `view` is the transform of the camera and `inverse_view` goes from world to view space.
+/// `view_proj` and `proj` include the jitter, `jitter` is the offset in NDC.
+/// `previous_view_proj` is the unjittered matrix of the previous frame, for motion vectors.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ViewUniforms {
+    pub view_proj: Mat4,
+    pub inverse_view_proj: Mat4,
+    pub view: Mat4,
+    pub inverse_view: Mat4,
+    pub proj: Mat4,
+    pub inverse_proj: Mat4,
+    pub world_position: Vec3,
+    pub frame_index: u32,
+    pub unjittered_view_proj: Mat4,
+    pub previous_view_proj: Mat4,
+    pub viewport_size: Vec2,
+    pub jitter: Vec2,
+}
+
+impl ViewUniforms {
+    pub fn new(
+        camera: &Camera,
+        transform: &Transform,
+        viewport_size: Vec2,
+        previous_view_proj: Mat4,
+        frame_index: u32,
+    ) -> Self {
+        let view = transform.compute_matrix();
+        let inverse_view = view.inverse();
+        let proj = camera.jittered_projection(viewport_size);
+        let inverse_proj = proj.inverse();
+
+        Self {
+            view_proj: proj * inverse_view,
+            inverse_view_proj: view * inverse_proj,
+            view,
+            inverse_view,
+            proj,
+            inverse_proj,
+            world_position: transform.translation,
+            frame_index,
+            unjittered_view_proj: camera.projection.matrix() * inverse_view,
+            previous_view_proj,
+            viewport_size,
+            jitter: camera.jitter_ndc(viewport_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_view_uniforms_layout() {
+        assert_eq!(std::mem::size_of::<ViewUniforms>(), 544);
+        assert_eq!(std::mem::offset_of!(ViewUniforms, world_position), 384);
+        assert_eq!(std::mem::offset_of!(ViewUniforms, frame_index), 396);
+        assert_eq!(
+            std::mem::offset_of!(ViewUniforms, unjittered_view_proj),
+            400
+        );
+        assert_eq!(std::mem::offset_of!(ViewUniforms, viewport_size), 528);
+        assert_eq!(std::mem::offset_of!(ViewUniforms, jitter), 536);
+    }
+}
@@ -0,0 +1,481 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::Buffer,
+    ctx::ExampleBase,
+    error::{Error, Result},
+};
+
+use super::{
+    barrier::{self, Usage},
+    deferred_destroy::DESTROY_DELAY_FRAMES,
+    extract::Extract,
+    RenderAllocator, RenderInstance,
+};
+
+/// Where [`FrameCapture`] writes the frames to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureOutput {
+    /// `frame_000000.png` and onwards, the directory is created when it doesn't exist.
+    PngSequence(PathBuf),
+    /// Raw RGBA8 frames piped into the stdin of `program`. `{width}` and `{height}` in the
+    /// arguments are replaced by the size of the frames.
+    Encoder { program: String, args: Vec<String> },
+}
+
+impl CaptureOutput {
+    /// Encodes an H.264 video at `path` with `ffmpeg`, which has to be on the `PATH`.
+    pub fn ffmpeg(path: impl Into<PathBuf>, frame_rate: u32) -> Self {
+        let path = path.into().to_string_lossy().into_owned();
+        let frame_rate = frame_rate.to_string();
+        let args: [&str; 16] = [
+            "-y",
+            "-f",
+            "rawvideo",
+            "-pix_fmt",
+            "rgba",
+            "-s",
+            "{width}x{height}",
+            "-r",
+            &frame_rate,
+            "-i",
+            "-",
+            "-c:v",
+            "libx264",
+            "-pix_fmt",
+            "yuv420p",
+            &path,
+        ];
+        CaptureOutput::Encoder {
+            program: "ffmpeg".to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+}
+
+/// Captures the presented frames, insert or change it in the main app. Frames are copied out
+/// of the swapchain and written a few frames later, once the GPU is done with them.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct FrameCapture {
+    /// `None` stops the capture, writing the frames that are still in flight.
+    pub output: Option<CaptureOutput>,
+    /// Stops after this many frames.
+    pub frame_limit: Option<u32>,
+}
+
+pub(crate) fn extract_frame_capture(
+    capture: Extract<Option<Res<FrameCapture>>>,
+    mut state: ResMut<FrameCaptureState>,
+) {
+    if let Some(capture) = capture.as_ref() {
+        if capture.is_changed() {
+            state.settings = FrameCapture::clone(capture);
+        }
+    }
+}
+
+/// The render world side of [`FrameCapture`].
+#[derive(Resource, Default)]
+pub struct FrameCaptureState {
+    settings: FrameCapture,
+    /// Output the running capture was started with.
+    active: Option<CaptureOutput>,
+    readback: Option<Readback>,
+    sink: Option<Sink>,
+    /// Frames written to the sink since the capture started.
+    captured: u32,
+}
+
+/// A host visible buffer per frame in flight, the copy into one is read back when the ring
+/// wraps around to it again.
+struct Readback {
+    buffers: Vec<Buffer>,
+    /// Whether a copy into the buffer was recorded since it was last read.
+    written: Vec<AtomicBool>,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    frame: usize,
+}
+
+impl Readback {
+    fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<Self> {
+        let renderer = render_instance.0.as_ref();
+        if !renderer
+            .swapchain_image_usage
+            .contains(vk::ImageUsageFlags::TRANSFER_SRC)
+        {
+            return Err(Error::Unsupported(
+                "The swapchain images can't be copied from".to_string(),
+            ));
+        }
+        let format = renderer.surface_format.format;
+        if rgba_channel_order(format).is_none() {
+            return Err(Error::Unsupported(format!(
+                "Capturing {:?} swapchains",
+                format
+            )));
+        }
+
+        let extent = renderer.surface_resolution;
+        let size = extent.width as u64 * extent.height as u64 * 4;
+        let buffers = (0..DESTROY_DELAY_FRAMES + 1)
+            .map(|_| {
+                let buffer = Buffer::new(
+                    render_instance.device(),
+                    &mut render_allocator.allocator(),
+                    &vk::BufferCreateInfo::default()
+                        .size(size)
+                        .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                    MemoryLocation::GpuToCpu,
+                )?;
+                buffer.set_name("frame capture");
+                Ok(buffer)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Readback {
+            written: buffers.iter().map(|_| AtomicBool::new(false)).collect(),
+            buffers,
+            extent,
+            format,
+            frame: 0,
+        })
+    }
+}
+
+enum Sink {
+    Png(PathBuf),
+    Encoder(Child),
+}
+
+impl Sink {
+    fn open(output: &CaptureOutput, extent: vk::Extent2D) -> Result<Self> {
+        match output {
+            CaptureOutput::PngSequence(directory) => {
+                std::fs::create_dir_all(directory)?;
+                Ok(Sink::Png(directory.clone()))
+            }
+            CaptureOutput::Encoder { program, args } => {
+                let child = Command::new(program)
+                    .args(encoder_args(args, extent))
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                Ok(Sink::Encoder(child))
+            }
+        }
+    }
+
+    fn write(&mut self, index: u32, extent: vk::Extent2D, rgba: Vec<u8>) -> Result<()> {
+        match self {
+            Sink::Png(directory) => {
+                let path = directory.join(format!("frame_{:06}.png", index));
+                // encoding is slow, so it doesn't hold up the frame
+                rayon::spawn(move || {
+                    let result = image::save_buffer(
+                        &path,
+                        &rgba,
+                        extent.width,
+                        extent.height,
+                        image::ColorType::Rgba8,
+                    );
+                    if let Err(e) = result {
+                        println!("Failed to write {}: {}", path.display(), e);
+                    }
+                });
+                Ok(())
+            }
+            Sink::Encoder(child) => {
+                child.stdin.as_mut().unwrap().write_all(&rgba)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Waits for the encoder to finish the file.
+    fn close(self) {
+        if let Sink::Encoder(mut child) = self {
+            drop(child.stdin.take());
+            if let Err(e) = child.wait() {
+                println!("Frame capture encoder failed: {}", e);
+            }
+        }
+    }
+}
+
+impl FrameCaptureState {
+    /// Records a copy of the presented image into the buffer of this frame, when capturing.
+    /// The image has to be in the `COLOR_ATTACHMENT_OPTIMAL` layout and is left in
+    /// `PRESENT_SRC_KHR`, returns `false` without recording anything otherwise.
+    pub fn record_copy(
+        &self,
+        renderer: &ExampleBase,
+        command_buffer: vk::CommandBuffer,
+        image: vk::Image,
+    ) -> bool {
+        let Some(readback) = self.readback.as_ref() else {
+            return false;
+        };
+        // resized after the capture was prepared, the buffers don't fit the image
+        if readback.extent != renderer.surface_resolution {
+            return false;
+        }
+        let buffer = &readback.buffers[readback.frame];
+        let device = &renderer.device;
+
+        let to_transfer = barrier::image_barrier(
+            image,
+            vk::ImageAspectFlags::COLOR,
+            Usage::ColorAttachmentWrite,
+            Usage::TransferRead,
+        );
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[to_transfer]),
+            );
+            device.cmd_copy_image_to_buffer(
+                command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                buffer.buffer,
+                &[vk::BufferImageCopy::default()
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(readback.extent.into())],
+            );
+            // the copy has to be visible to the host once the frame's fence is signaled
+            let to_host =
+                barrier::buffer_barrier(buffer.buffer, Usage::TransferWrite, Usage::TransferWrite)
+                    .dst_stage_mask(vk::PipelineStageFlags2::HOST)
+                    .dst_access_mask(vk::AccessFlags2::HOST_READ);
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default()
+                    .image_memory_barriers(&[barrier::image_barrier(
+                        image,
+                        vk::ImageAspectFlags::COLOR,
+                        Usage::TransferRead,
+                        Usage::Present,
+                    )])
+                    .buffer_memory_barriers(&[to_host]),
+            );
+        }
+        readback.written[readback.frame].store(true, Ordering::Relaxed);
+        true
+    }
+
+    fn start(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        output: &CaptureOutput,
+    ) -> Result<()> {
+        let readback = Readback::new(render_instance, render_allocator)?;
+        self.sink = Some(Sink::open(output, readback.extent)?);
+        self.readback = Some(readback);
+        self.captured = 0;
+        Ok(())
+    }
+
+    /// Continues a PNG sequence at the new size of the swapchain. Encoders take frames of a
+    /// single size, so their capture is stopped instead.
+    fn resize(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<()> {
+        if !matches!(self.sink, Some(Sink::Png(_))) {
+            return Err(Error::Unsupported(
+                "The encoder can't change the size of the frames".to_string(),
+            ));
+        }
+        self.write_in_flight(render_instance);
+        self.readback = Some(Readback::new(render_instance, render_allocator)?);
+        Ok(())
+    }
+
+    /// Writes the frames still in flight and closes the output.
+    fn stop(&mut self, render_instance: &RenderInstance) {
+        if self.readback.is_some() {
+            self.write_in_flight(render_instance);
+        }
+        self.readback = None;
+        if let Some(sink) = self.sink.take() {
+            sink.close();
+        }
+        println!("Captured {} frames", self.captured);
+    }
+
+    /// Waits for the GPU and writes every frame that was copied but not written yet.
+    fn write_in_flight(&mut self, render_instance: &RenderInstance) {
+        unsafe { render_instance.device().device_wait_idle() }
+            .expect("Failed to wait for the device");
+        let frame_count = self.readback.as_ref().unwrap().buffers.len();
+        // oldest frame first
+        for _ in 0..frame_count {
+            self.advance(render_instance);
+        }
+    }
+
+    /// Moves on to the buffer of the next frame, writing the frame that was copied into it.
+    fn advance(&mut self, render_instance: &RenderInstance) {
+        let readback = self.readback.as_mut().unwrap();
+        readback.frame = (readback.frame + 1) % readback.buffers.len();
+        if !readback.written[readback.frame].swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let buffer = &mut readback.buffers[readback.frame];
+        let mut rgba = buffer.map::<u8>(render_instance.device()).to_vec();
+        to_rgba(&mut rgba, rgba_channel_order(readback.format).unwrap());
+        let extent = readback.extent;
+        if let Err(e) = self
+            .sink
+            .as_mut()
+            .unwrap()
+            .write(self.captured, extent, rgba)
+        {
+            println!("Failed to write captured frame {}: {}", self.captured, e);
+        }
+        self.captured += 1;
+    }
+
+    fn frames_in_flight(&self) -> u32 {
+        self.readback.as_ref().map_or(0, |readback| {
+            readback
+                .written
+                .iter()
+                .filter(|written| written.load(Ordering::Relaxed))
+                .count() as u32
+        })
+    }
+}
+
+/// Starts and stops the capture and writes the frame the GPU finished, before the present
+/// node copies the next one.
+pub(crate) fn prepare_frame_capture(
+    mut state: ResMut<FrameCaptureState>,
+    render_instance: Res<RenderInstance>,
+    mut render_allocator: ResMut<RenderAllocator>,
+) {
+    let state = &mut *state;
+    let limit_reached = state
+        .settings
+        .frame_limit
+        .is_some_and(|limit| state.captured + state.frames_in_flight() >= limit);
+    if limit_reached && state.active.is_some() {
+        // stays off until the main app changes the settings again
+        state.settings.output = None;
+    }
+
+    if state.settings.output != state.active {
+        if state.active.take().is_some() {
+            state.stop(&render_instance);
+        }
+        if let Some(output) = state.settings.output.clone() {
+            match state.start(&render_instance, &mut render_allocator, &output) {
+                Ok(()) => state.active = Some(output),
+                Err(e) => {
+                    println!("Failed to start the frame capture: {}", e);
+                    state.settings.output = None;
+                }
+            }
+        }
+    }
+
+    let resized = state
+        .readback
+        .as_ref()
+        .is_some_and(|readback| readback.extent != render_instance.0.surface_resolution);
+    if resized {
+        if let Err(e) = state.resize(&render_instance, &mut render_allocator) {
+            println!("Stopping the frame capture: {}", e);
+            state.active = None;
+            state.settings.output = None;
+            state.stop(&render_instance);
+        }
+    }
+
+    if state.readback.is_some() {
+        state.advance(&render_instance);
+    }
+}
+
+/// Replaces `{width}` and `{height}` in the arguments of an encoder.
+fn encoder_args(args: &[String], extent: vk::Extent2D) -> Vec<String> {
+    args.iter()
+        .map(|arg| {
+            arg.replace("{width}", &extent.width.to_string())
+                .replace("{height}", &extent.height.to_string())
+        })
+        .collect()
+}
+
+/// Index of the red, green, blue and alpha channel in a texel of a format that can be captured.
+fn rgba_channel_order(format: vk::Format) -> Option<[usize; 4]> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => Some([0, 1, 2, 3]),
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => Some([2, 1, 0, 3]),
+        _ => None,
+    }
+}
+
+/// Reorders the texels to RGBA, with an opaque alpha since the swapchain's is meaningless.
+fn to_rgba(texels: &mut [u8], order: [usize; 4]) {
+    for texel in texels.chunks_exact_mut(4) {
+        let [r, g, b, _] = order.map(|channel| texel[channel]);
+        texel.copy_from_slice(&[r, g, b, u8::MAX]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::{encoder_args, rgba_channel_order, to_rgba, CaptureOutput};
+
+    #[test]
+    fn test_to_rgba() {
+        let mut texels = [1, 2, 3, 0, 10, 20, 30, 0];
+        to_rgba(
+            &mut texels,
+            rgba_channel_order(vk::Format::B8G8R8A8_SRGB).unwrap(),
+        );
+        assert_eq!(texels, [3, 2, 1, 255, 30, 20, 10, 255]);
+        assert!(rgba_channel_order(vk::Format::A2B10G10R10_UNORM_PACK32).is_none());
+    }
+
+    #[test]
+    fn test_encoder_args() {
+        let CaptureOutput::Encoder { program, args } = CaptureOutput::ffmpeg("out.mp4", 60) else {
+            unreachable!()
+        };
+        assert_eq!(program, "ffmpeg");
+        let args = encoder_args(
+            &args,
+            vk::Extent2D {
+                width: 1280,
+                height: 720,
+            },
+        );
+        assert!(args.windows(2).any(|pair| pair == ["-s", "1280x720"]));
+        assert_eq!(args.last().unwrap(), "out.mp4");
+    }
+}
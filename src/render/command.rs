@@ -0,0 +1,317 @@
+use std::{mem::size_of, ops::Range};
+
+use ash::vk;
+
+use crate::{buffer::Buffer, error::Result};
+
+use super::{
+    instancing::{InstanceBuffer, InstanceData},
+    push_constants::{self, PushConstantProgram, PushConstants},
+    stats,
+    tracking::{self, ResourceKind},
+    GpuMesh, RenderInstance,
+};
+
+/// A command buffer that is recording inside of a pass, together with the pipeline layout
+/// that's bound, so draws don't have to pass the device and layout around for every command.
+pub struct DrawContext<'a> {
+    pub render_instance: &'a RenderInstance,
+    pub command_buffer: vk::CommandBuffer,
+    pub layout: vk::PipelineLayout,
+}
+
+impl<'a> DrawContext<'a> {
+    pub fn device(&self) -> &ash::Device {
+        self.render_instance.device()
+    }
+
+    pub fn push_constants<T: bytemuck::Pod>(&self, data: &T) {
+        unsafe {
+            self.device().cmd_push_constants(
+                self.command_buffer,
+                self.layout,
+                vk::ShaderStageFlags::ALL_GRAPHICS,
+                0,
+                bytemuck::bytes_of(data),
+            )
+        };
+    }
+
+    /// Like [`Self::push_constants`], checking `data` against the push constant block of the
+    /// shaders of `program` first.
+    pub fn set_push_constants<T: PushConstants>(
+        &self,
+        program: &impl PushConstantProgram,
+        data: &T,
+    ) -> Result<()> {
+        push_constants::set_push_constants(self.device(), self.command_buffer, program, data)
+    }
+
+    /// Only for passes drawing lines, widths other than 1.0 need `LineSupport::wide`.
+    pub fn set_line_width(&self, width: f32) {
+        unsafe { self.device().cmd_set_line_width(self.command_buffer, width) };
+    }
+
+    /// Only for passes with `stippled_lines`, every bit of `pattern` is drawn or skipped for
+    /// `factor` pixels, starting at the lowest bit.
+    pub fn set_line_stipple(&self, factor: u32, pattern: u16) {
+        let line_rasterization = self
+            .render_instance
+            .0
+            .line_rasterization
+            .as_ref()
+            .expect("VK_EXT_line_rasterization isn't supported by the device");
+        unsafe {
+            (line_rasterization.cmd_set_line_stipple_ext)(self.command_buffer, factor, pattern)
+        };
+    }
+
+    pub fn bind_vertex_buffer(&self, buffer: &Buffer) {
+        tracking::assert_alive(ResourceKind::Buffer, buffer.buffer);
+        unsafe {
+            self.device()
+                .cmd_bind_vertex_buffers(self.command_buffer, 0, &[buffer.buffer], &[0])
+        };
+    }
+
+    pub fn bind_index_buffer(&self, buffer: &Buffer) {
+        tracking::assert_alive(ResourceKind::Buffer, buffer.buffer);
+        unsafe {
+            self.device().cmd_bind_index_buffer(
+                self.command_buffer,
+                buffer.buffer,
+                0,
+                vk::IndexType::UINT32,
+            )
+        };
+    }
+
+    pub fn draw(&self, vertex_count: u32, first_vertex: u32) {
+        unsafe {
+            self.device()
+                .cmd_draw(self.command_buffer, vertex_count, 1, first_vertex, 0)
+        };
+        stats::count_draw(vertex_count as u64 / 3);
+    }
+
+    pub fn draw_indexed(&self, index_count: u32, first_index: u32, vertex_offset: i32) {
+        unsafe {
+            self.device().cmd_draw_indexed(
+                self.command_buffer,
+                index_count,
+                1,
+                first_index,
+                vertex_offset,
+                0,
+            )
+        };
+        stats::count_draw(index_count as u64 / 3);
+    }
+
+    /// Launches `x * y * z` task shader workgroups, or mesh shader workgroups for pipelines
+    /// without a task shader. Needs `VK_EXT_mesh_shader`.
+    pub fn draw_mesh_tasks(&self, x: u32, y: u32, z: u32) {
+        let mesh_shader = self
+            .render_instance
+            .0
+            .mesh_shader
+            .as_ref()
+            .expect("VK_EXT_mesh_shader isn't supported by the device");
+        unsafe { mesh_shader.cmd_draw_mesh_tasks(self.command_buffer, x, y, z) };
+        // the triangles are only known to the mesh shader
+        stats::count_draw(0);
+    }
+
+    /// Submits `draw_count` non-indexed draws from `buffer`, each described by a
+    /// `vk::DrawIndirectCommand` that is `stride` bytes apart, starting at `offset`.
+    pub fn draw_indirect(&self, buffer: &Buffer, offset: u64, draw_count: u32, stride: u32) {
+        validate_indirect_buffer(
+            buffer,
+            offset,
+            draw_count,
+            stride,
+            size_of::<vk::DrawIndirectCommand>(),
+        );
+
+        unsafe {
+            self.device().cmd_draw_indirect(
+                self.command_buffer,
+                buffer.buffer,
+                offset,
+                draw_count,
+                stride,
+            )
+        };
+        stats::count_draw(0);
+    }
+
+    /// Submits `draw_count` draws from `buffer`, each described by a
+    /// `vk::DrawIndexedIndirectCommand` that is `stride` bytes apart, starting at `offset`.
+    pub fn draw_indexed_indirect(
+        &self,
+        buffer: &Buffer,
+        offset: u64,
+        draw_count: u32,
+        stride: u32,
+    ) {
+        validate_indirect_buffer(
+            buffer,
+            offset,
+            draw_count,
+            stride,
+            size_of::<vk::DrawIndexedIndirectCommand>(),
+        );
+
+        unsafe {
+            self.device().cmd_draw_indexed_indirect(
+                self.command_buffer,
+                buffer.buffer,
+                offset,
+                draw_count,
+                stride,
+            )
+        };
+        stats::count_draw(0);
+    }
+
+    /// Like [`DrawContext::draw_indexed_indirect`], but the amount of draws is read from a `u32`
+    /// at `count_offset` in `count_buffer`, clamped to `max_draw_count`. This lets a culling
+    /// pass decide how many draws are submitted without reading the count back on the CPU.
+    pub fn draw_indexed_indirect_count(
+        &self,
+        buffer: &Buffer,
+        offset: u64,
+        count_buffer: &Buffer,
+        count_offset: u64,
+        max_draw_count: u32,
+        stride: u32,
+    ) {
+        validate_indirect_buffer(
+            buffer,
+            offset,
+            max_draw_count,
+            stride,
+            size_of::<vk::DrawIndexedIndirectCommand>(),
+        );
+        tracking::assert_alive(ResourceKind::Buffer, count_buffer.buffer);
+        assert!(
+            count_buffer
+                .usage
+                .contains(vk::BufferUsageFlags::INDIRECT_BUFFER),
+            "Count buffer was created without INDIRECT_BUFFER usage"
+        );
+        assert!(
+            count_offset % 4 == 0 && count_offset + 4 <= count_buffer.size,
+            "Count offset {} is unaligned or out of bounds",
+            count_offset
+        );
+
+        unsafe {
+            self.render_instance
+                .0
+                .draw_indirect_count
+                .cmd_draw_indexed_indirect_count(
+                    self.command_buffer,
+                    buffer.buffer,
+                    offset,
+                    count_buffer.buffer,
+                    count_offset,
+                    max_draw_count,
+                    stride,
+                )
+        };
+        stats::count_draw(0);
+    }
+
+    pub fn bind_instance_buffer<T: InstanceData>(&self, instances: &InstanceBuffer<T>) {
+        tracking::assert_alive(ResourceKind::Buffer, instances.buffer.buffer);
+        unsafe {
+            self.device().cmd_bind_vertex_buffers(
+                self.command_buffer,
+                1,
+                &[instances.buffer.buffer],
+                &[0],
+            )
+        };
+    }
+
+    /// Draws `instances` of the mesh, the pipeline is expected to use
+    /// [`VertexInputLayout::instanced`](super::instancing::VertexInputLayout::instanced).
+    pub(crate) fn draw_mesh_instanced<T: InstanceData>(
+        &self,
+        mesh: &GpuMesh,
+        instance_buffer: &InstanceBuffer<T>,
+        instances: Range<u32>,
+    ) {
+        assert!(
+            instances.end <= instance_buffer.len(),
+            "Instance range {:?} is out of bounds, the buffer holds {} instances",
+            instances,
+            instance_buffer.len()
+        );
+
+        self.bind_vertex_buffer(&mesh.vertex_buffer);
+        self.bind_instance_buffer(instance_buffer);
+        let instance_count = instances.end - instances.start;
+        let vertex_count = if mesh.index_buffer.is_some() {
+            mesh.index_count
+        } else {
+            mesh.vertex_count
+        };
+        stats::count_draw(vertex_count as u64 / 3 * instance_count as u64);
+
+        unsafe {
+            if let Some(index_buffer) = mesh.index_buffer.as_ref() {
+                self.bind_index_buffer(index_buffer);
+                self.device().cmd_draw_indexed(
+                    self.command_buffer,
+                    mesh.index_count,
+                    instance_count,
+                    0,
+                    0,
+                    instances.start,
+                );
+            } else {
+                self.device().cmd_draw(
+                    self.command_buffer,
+                    mesh.vertex_count,
+                    instance_count,
+                    0,
+                    instances.start,
+                );
+            }
+        }
+    }
+}
+
+fn validate_indirect_buffer(
+    buffer: &Buffer,
+    offset: u64,
+    draw_count: u32,
+    stride: u32,
+    command_size: usize,
+) {
+    tracking::assert_alive(ResourceKind::Buffer, buffer.buffer);
+    assert!(
+        buffer.usage.contains(vk::BufferUsageFlags::INDIRECT_BUFFER),
+        "Buffer used for indirect draws was created without INDIRECT_BUFFER usage"
+    );
+    assert!(
+        offset % 4 == 0,
+        "Indirect buffer offset has to be a multiple of 4"
+    );
+    if draw_count > 1 {
+        assert!(
+            stride % 4 == 0 && stride as usize >= command_size,
+            "Indirect stride {} has to be a multiple of 4 and at least the size of a command",
+            stride
+        );
+    }
+    let end = offset + (draw_count.saturating_sub(1) as u64 * stride as u64) + command_size as u64;
+    assert!(
+        draw_count == 0 || end <= buffer.size,
+        "Indirect draws read up to byte {}, but the buffer is only {} bytes",
+        end,
+        buffer.size
+    );
+}
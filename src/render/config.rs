@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+
+use bevy::{prelude::Resource, window::PresentMode};
+
+use super::deferred_destroy::DESTROY_DELAY_FRAMES;
+
+/// The fewest frames [`RendererConfig::uniform_ring_frames`] can be, values bound
+/// [`DESTROY_DELAY_FRAMES`] frames ago have to stay intact.
+pub const MIN_UNIFORM_RING_FRAMES: u32 = DESTROY_DELAY_FRAMES as u32 + 1;
+/// The most frames [`RendererConfig::frames_in_flight`] can be, resources released by a frame
+/// are destroyed [`DESTROY_DELAY_FRAMES`] frames later.
+pub const MAX_FRAMES_IN_FLIGHT: u32 = DESTROY_DELAY_FRAMES as u32;
+
+/// Settings of the renderer that don't need a rebuild of the host app to change. Insert it as
+/// a resource before the render plugin is added, the `SOMEDAY_*` environment variables listed
+/// at [`RendererConfig::apply_env`] are applied on top of it.
+#[derive(Resource, Clone, Debug)]
+pub struct RendererConfig {
+    /// Loads `VK_LAYER_KHRONOS_validation`, on by default in debug builds.
+    pub validation: bool,
+    /// Frames of per-frame values kept by the ring buffers of the view uniforms, the frame time
+    /// and [`ShaderBlocks`](super::shader_bind::ShaderBlocks), at least
+    /// [`MIN_UNIFORM_RING_FRAMES`], which is more than the frames in flight can be.
+    pub uniform_ring_frames: u32,
+    /// Frames the CPU records ahead of the GPU, each with a command buffer and fence of its
+    /// own, from 1 up to [`MAX_FRAMES_IN_FLIGHT`]. 1 waits for every frame to finish before the
+    /// next one is recorded, which lowers latency at the cost of keeping the GPU busy.
+    pub frames_in_flight: u32,
+    /// Images the swapchain is created with, 2 for double and 3 for triple buffering. Clamped to
+    /// what the surface supports, one more than its minimum by default. Independent of
+    /// [`RendererConfig::frames_in_flight`], which bounds the frames being recorded or rendered,
+    /// while more images let more finished frames queue up for presentation.
+    pub swapchain_image_count: Option<u32>,
+    /// Replaces the present mode of the window.
+    pub vsync: Option<PresentMode>,
+    /// Index or part of the name of the GPU to use, ignored when
+    /// [`DeviceRequirements::physical_device`](crate::ctx::DeviceRequirements::physical_device)
+    /// is set. Falls back to the first suitable GPU when none matches.
+    pub preferred_gpu: Option<String>,
+    /// Where the Vulkan pipeline cache is loaded from and saved to on shutdown, so the driver
+    /// doesn't compile the same shaders again on the next run.
+    pub shader_cache_dir: Option<PathBuf>,
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            validation: cfg!(debug_assertions),
+            uniform_ring_frames: MIN_UNIFORM_RING_FRAMES,
+            frames_in_flight: MAX_FRAMES_IN_FLIGHT,
+            swapchain_image_count: None,
+            vsync: None,
+            preferred_gpu: None,
+            shader_cache_dir: None,
+        }
+    }
+}
+
+impl RendererConfig {
+    /// The defaults with the environment variables applied.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        config.apply_env(|name| std::env::var(name).ok());
+        config
+    }
+
+    /// Overrides the settings that have a variable set, invalid values are reported and
+    /// ignored:
+    ///
+    /// - `SOMEDAY_VALIDATION`: `1` or `0`
+    /// - `SOMEDAY_UNIFORM_RING_FRAMES`: at least [`MIN_UNIFORM_RING_FRAMES`]
+    /// - `SOMEDAY_FRAMES_IN_FLIGHT`: 1 to [`MAX_FRAMES_IN_FLIGHT`]
+    /// - `SOMEDAY_SWAPCHAIN_IMAGES`: at least 1
+    /// - `SOMEDAY_VSYNC`: `on`, `off`, `mailbox` or `relaxed`
+    /// - `SOMEDAY_GPU`: index or part of the name
+    /// - `SOMEDAY_SHADER_CACHE_DIR`: directory
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) {
+        let invalid = |name: &str, value: &str| {
+            println!("Ignoring {}={:?}, it isn't a valid value", name, value);
+        };
+
+        if let Some(value) = var("SOMEDAY_VALIDATION") {
+            match value.to_lowercase().as_str() {
+                "1" | "true" | "on" => self.validation = true,
+                "0" | "false" | "off" => self.validation = false,
+                _ => invalid("SOMEDAY_VALIDATION", &value),
+            }
+        }
+        if let Some(value) = var("SOMEDAY_UNIFORM_RING_FRAMES") {
+            match value.parse() {
+                Ok(frames) if frames >= MIN_UNIFORM_RING_FRAMES => {
+                    self.uniform_ring_frames = frames
+                }
+                _ => invalid("SOMEDAY_UNIFORM_RING_FRAMES", &value),
+            }
+        }
+        if let Some(value) = var("SOMEDAY_FRAMES_IN_FLIGHT") {
+            match value.parse() {
+                Ok(frames) if (1..=MAX_FRAMES_IN_FLIGHT).contains(&frames) => {
+                    self.frames_in_flight = frames
+                }
+                _ => invalid("SOMEDAY_FRAMES_IN_FLIGHT", &value),
+            }
+        }
+        if let Some(value) = var("SOMEDAY_SWAPCHAIN_IMAGES") {
+            match value.parse() {
+                Ok(count) if count >= 1 => self.swapchain_image_count = Some(count),
+                _ => invalid("SOMEDAY_SWAPCHAIN_IMAGES", &value),
+            }
+        }
+        if let Some(value) = var("SOMEDAY_VSYNC") {
+            match value.to_lowercase().as_str() {
+                "1" | "on" | "fifo" => self.vsync = Some(PresentMode::Fifo),
+                "0" | "off" | "immediate" => self.vsync = Some(PresentMode::Immediate),
+                "mailbox" => self.vsync = Some(PresentMode::Mailbox),
+                "relaxed" => self.vsync = Some(PresentMode::AutoVsync),
+                _ => invalid("SOMEDAY_VSYNC", &value),
+            }
+        }
+        if let Some(value) = var("SOMEDAY_GPU").filter(|value| !value.is_empty()) {
+            self.preferred_gpu = Some(value);
+        }
+        if let Some(value) = var("SOMEDAY_SHADER_CACHE_DIR").filter(|value| !value.is_empty()) {
+            self.shader_cache_dir = Some(PathBuf::from(value));
+        }
+    }
+
+    /// Whether the GPU at `index` called `name` is the [`RendererConfig::preferred_gpu`].
+    pub fn is_preferred_gpu(&self, index: usize, name: &str) -> bool {
+        let Some(preferred) = &self.preferred_gpu else {
+            return false;
+        };
+        match preferred.parse::<usize>() {
+            Ok(preferred_index) => preferred_index == index,
+            Err(_) => name.to_lowercase().contains(&preferred.to_lowercase()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_apply_env() {
+        let vars = HashMap::from([
+            ("SOMEDAY_VALIDATION", "1"),
+            ("SOMEDAY_UNIFORM_RING_FRAMES", "1"),
+            ("SOMEDAY_FRAMES_IN_FLIGHT", "1"),
+            ("SOMEDAY_SWAPCHAIN_IMAGES", "2"),
+            ("SOMEDAY_VSYNC", "Mailbox"),
+            ("SOMEDAY_GPU", "radeon"),
+        ]);
+        let mut config = RendererConfig {
+            validation: false,
+            ..Default::default()
+        };
+        config.apply_env(|name| vars.get(name).map(|value| value.to_string()));
+
+        assert!(config.validation);
+        // too few frames is ignored
+        assert_eq!(config.uniform_ring_frames, MIN_UNIFORM_RING_FRAMES);
+        assert_eq!(config.frames_in_flight, 1);
+        assert_eq!(config.swapchain_image_count, Some(2));
+        assert_eq!(config.vsync, Some(PresentMode::Mailbox));
+        assert_eq!(config.shader_cache_dir, None);
+        assert!(config.is_preferred_gpu(1, "AMD Radeon RX 7900 XTX"));
+        assert!(!config.is_preferred_gpu(0, "NVIDIA GeForce RTX 4090"));
+
+        config.preferred_gpu = Some("1".to_string());
+        assert!(config.is_preferred_gpu(1, "NVIDIA GeForce RTX 4090"));
+    }
+}
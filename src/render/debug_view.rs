@@ -0,0 +1,30 @@
+use bevy::prelude::*;
+
+use super::extract::Extract;
+
+/// Swaps the shading of the main pass for a diagnostic view, insert or change it in the main
+/// app. The shaders of the scene aren't touched, the renderer draws it with its own.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DebugView {
+    #[default]
+    None,
+    /// The edges of every triangle over the shaded frame. Needs the `fillModeNonSolid`
+    /// feature, see `ExampleBase::supports_fill_mode_non_solid`, and shows the plain frame
+    /// without it.
+    Wireframe,
+    /// How many fragments cover every pixel, as a heatmap from black over red to white.
+    Overdraw,
+    /// The mip level the base color texture is sampled at.
+    MipLevel,
+}
+
+pub(crate) fn extract_debug_view(
+    debug_view: Extract<Option<Res<DebugView>>>,
+    mut render_debug_view: ResMut<DebugView>,
+) {
+    if let Some(debug_view) = debug_view.as_ref() {
+        if debug_view.is_changed() {
+            *render_debug_view = **debug_view;
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use std::{collections::VecDeque, sync::OnceLock};
+
+use ash::vk;
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::{
+    allocator::Allocator,
+    buffer::{Buffer, Image},
+    ctx::ExampleBase,
+    error::Result,
+};
+
+use super::{
+    descriptor_cache::{CachedBinding, DescriptorSetCache},
+    shaders,
+    tracking::{self, ResourceKind},
+    RenderAllocator, RenderInstance,
+};
+
+/// Amount of frames a queued resource is kept alive, long enough for any command buffer that
+/// was recorded before it got queued to finish executing.
+pub const DESTROY_DELAY_FRAMES: u64 = 2;
+
+#[derive(Debug)]
+pub enum DeferredResource {
+    Buffer(Buffer),
+    Image(Image),
+    /// The acceleration structure together with the buffer backing it.
+    AccelerationStructure(vk::AccelerationStructureKHR, Buffer),
+    ShaderModule(vk::ShaderModule),
+    /// Memory allocated outside of the allocator, like the exportable memory of the interop
+    /// resources.
+    Memory(vk::DeviceMemory),
+    /// A pipeline together with its layout and descriptor set layouts. Its descriptor sets are
+    /// handed back to the [`DescriptorAllocator`](super::descriptor_allocator::DescriptorAllocator)
+    /// pool they came from.
+    Pipeline {
+        pipeline: vk::Pipeline,
+        layout: vk::PipelineLayout,
+        set_layouts: Vec<vk::DescriptorSetLayout>,
+        descriptor_pool: vk::DescriptorPool,
+        descriptor_sets: Vec<vk::DescriptorSet>,
+    },
+    /// Sets handed back to the pool they came from, like the ones evicted from the
+    /// [`DescriptorSetCache`](super::descriptor_cache::DescriptorSetCache).
+    DescriptorSets {
+        descriptor_pool: vk::DescriptorPool,
+        descriptor_sets: Vec<vk::DescriptorSet>,
+    },
+}
+
+impl From<Buffer> for DeferredResource {
+    fn from(buffer: Buffer) -> Self {
+        DeferredResource::Buffer(buffer)
+    }
+}
+
+impl From<Image> for DeferredResource {
+    fn from(image: Image) -> Self {
+        DeferredResource::Image(image)
+    }
+}
+
+impl DeferredResource {
+    /// Lets the leak report tell queued resources apart from leaked ones.
+    fn mark_released(&self) {
+        match self {
+            DeferredResource::Buffer(buffer)
+            | DeferredResource::AccelerationStructure(_, buffer) => {
+                tracking::mark_released(ResourceKind::Buffer, buffer.buffer)
+            }
+            DeferredResource::Image(image) => {
+                tracking::mark_released(ResourceKind::Image, image.image)
+            }
+            DeferredResource::ShaderModule(module) => {
+                tracking::mark_released(ResourceKind::ShaderModule, *module)
+            }
+            DeferredResource::Memory(_)
+            | DeferredResource::Pipeline { .. }
+            | DeferredResource::DescriptorSets { .. } => {}
+        }
+    }
+
+    /// A cached set of `layout` with `bindings` refers to the resource.
+    fn is_bound(&self, layout: vk::DescriptorSetLayout, bindings: &[(u32, CachedBinding)]) -> bool {
+        if let DeferredResource::Pipeline { set_layouts, .. } = self {
+            return set_layouts.contains(&layout);
+        }
+        bindings.iter().any(|(_, binding)| match (self, binding) {
+            (DeferredResource::Buffer(buffer), CachedBinding::Buffer { buffer: bound, .. }) => {
+                *bound == buffer.buffer
+            }
+            (DeferredResource::Image(image), CachedBinding::Image { view: bound, .. }) => {
+                image.view == Some(*bound)
+            }
+            (
+                DeferredResource::AccelerationStructure(handle, _),
+                CachedBinding::AccelerationStructure(bound),
+            ) => bound == handle,
+            _ => false,
+        })
+    }
+
+    /// Destroys everything it can, even when handing the descriptor sets back fails.
+    fn destroy(mut self, base: &ExampleBase, allocator: &mut Allocator) -> Result<()> {
+        let device = &base.device;
+        match &mut self {
+            DeferredResource::Buffer(buffer) => buffer.destroy(device, allocator),
+            DeferredResource::Image(image) => image.destroy(device, allocator),
+            DeferredResource::AccelerationStructure(handle, buffer) => {
+                let loader = base
+                    .acceleration_structure
+                    .as_ref()
+                    .expect("Device doesn't support VK_KHR_acceleration_structure");
+                unsafe { loader.destroy_acceleration_structure(*handle, None) };
+                buffer.destroy(device, allocator);
+            }
+            DeferredResource::ShaderModule(module) => unsafe {
+                tracking::untrack(ResourceKind::ShaderModule, *module);
+                device.destroy_shader_module(*module, None)
+            },
+            DeferredResource::Memory(memory) => unsafe { device.free_memory(*memory, None) },
+            DeferredResource::Pipeline {
+                pipeline,
+                layout,
+                set_layouts,
+                descriptor_pool,
+                descriptor_sets,
+            } => unsafe {
+                let freed = base.descriptor_allocator.lock().unwrap().free(
+                    device,
+                    *descriptor_pool,
+                    descriptor_sets,
+                );
+                device.destroy_pipeline(*pipeline, None);
+                device.destroy_pipeline_layout(*layout, None);
+                shaders::forget_descriptor_set_layouts(set_layouts);
+                for set_layout in set_layouts.iter() {
+                    device.destroy_descriptor_set_layout(*set_layout, None);
+                }
+                return freed;
+            },
+            DeferredResource::DescriptorSets {
+                descriptor_pool,
+                descriptor_sets,
+            } => {
+                return base.descriptor_allocator.lock().unwrap().free(
+                    device,
+                    *descriptor_pool,
+                    descriptor_sets,
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+fn released() -> &'static (Sender<DeferredResource>, Receiver<DeferredResource>) {
+    static RELEASED: OnceLock<(Sender<DeferredResource>, Receiver<DeferredResource>)> =
+        OnceLock::new();
+    RELEASED.get_or_init(crossbeam_channel::unbounded)
+}
+
+/// Hands a dropped resource to the [`DeferredDestroyQueue`], which picks it up at the end of
+/// the frame. Resources don't know about the render world, so this goes through a channel.
+pub(crate) fn release(resource: impl Into<DeferredResource>) {
+    let resource = resource.into();
+    resource.mark_released();
+    released().0.send(resource).unwrap();
+}
+
+/// Resources that may still be referenced by in-flight command buffers. They are destroyed
+/// [`DESTROY_DELAY_FRAMES`] frames after being pushed.
+#[derive(Resource, Default)]
+pub struct DeferredDestroyQueue {
+    frame: u64,
+    pending: VecDeque<(u64, DeferredResource)>,
+}
+
+impl DeferredDestroyQueue {
+    pub fn push(&mut self, resource: impl Into<DeferredResource>) {
+        let resource = resource.into();
+        resource.mark_released();
+        self.pending.push_back((self.frame, resource));
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn receive_released(&mut self) {
+        for resource in released().1.try_iter() {
+            self.pending.push_back((self.frame, resource));
+        }
+    }
+
+    /// Ends the current frame and destroys everything that was queued long enough ago, together
+    /// with the cached descriptor sets referring to it. Returns the first error, after
+    /// destroying the rest.
+    pub fn advance_frame(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        descriptor_cache: &mut DescriptorSetCache,
+    ) -> Result<()> {
+        self.receive_released();
+        self.frame += 1;
+        let mut destroyed = Vec::new();
+        while let Some((frame, _)) = self.pending.front() {
+            if frame + DESTROY_DELAY_FRAMES > self.frame {
+                break;
+            }
+            destroyed.push(self.pending.pop_front().unwrap().1);
+        }
+        if destroyed.is_empty() {
+            return Ok(());
+        }
+
+        // before a new resource can get the same handle
+        descriptor_cache.invalidate(|layout, bindings| {
+            destroyed
+                .iter()
+                .any(|resource| resource.is_bound(layout, bindings))
+        });
+        let mut allocator = render_allocator.allocator();
+        destroyed.into_iter().fold(Ok(()), |result, resource| {
+            result.and(resource.destroy(&render_instance.0, &mut allocator))
+        })
+    }
+
+    /// Destroys everything right away, the device has to be idle.
+    pub fn flush(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<()> {
+        self.receive_released();
+        let mut allocator = render_allocator.allocator();
+        self.pending
+            .drain(..)
+            .fold(Ok(()), |result, (_, resource)| {
+                result.and(resource.destroy(&render_instance.0, &mut allocator))
+            })
+    }
+}
+
+impl Drop for DeferredDestroyQueue {
+    fn drop(&mut self) {
+        // destroyed by `destroy_released` once the context is dropped
+        for (_, resource) in self.pending.drain(..) {
+            released().0.send(resource).unwrap();
+        }
+    }
+}
+
+/// Destroys everything that was released since the last frame, including what was still
+/// queued when the [`DeferredDestroyQueue`] was dropped. Called when the [`ExampleBase`] is
+/// dropped, the device has to be idle.
+pub(crate) fn destroy_released(base: &ExampleBase, allocator: &mut Allocator) -> Result<()> {
+    released().1.try_iter().fold(Ok(()), |result, resource| {
+        result.and(resource.destroy(base, allocator))
+    })
+}
+
+pub(crate) fn advance_deferred_destroy_queue(
+    render_instance: Res<RenderInstance>,
+    mut render_allocator: ResMut<RenderAllocator>,
+    mut queue: ResMut<DeferredDestroyQueue>,
+    mut descriptor_cache: ResMut<DescriptorSetCache>,
+) {
+    let result = queue.advance_frame(
+        &render_instance,
+        &mut render_allocator,
+        &mut descriptor_cache,
+    );
+    if let Err(e) = result {
+        println!("Failed to destroy a released resource: {}", e);
+    }
+}
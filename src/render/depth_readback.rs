@@ -0,0 +1,227 @@
+use ash::vk;
+use glam::{Mat4, Vec2, Vec3};
+
+use crate::error::Result;
+
+use super::{
+    barrier::Usage,
+    camera::ViewUniforms,
+    readback::{Readback, ReadbackRing, ReadbackTicket},
+    RenderAllocator, RenderInstance,
+};
+
+/// The depth of one pixel, with the matrices of the frame it was rendered in so it can be
+/// unprojected after the camera moved on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthSample {
+    pub x: u32,
+    pub y: u32,
+    /// Reverse depth, 1 at the near plane and 0 at infinity.
+    pub depth: f32,
+    pub inverse_view_proj: Mat4,
+    pub viewport_size: Vec2,
+}
+
+impl DepthSample {
+    /// Nothing was drawn at the pixel, the depth buffer still has its clear value.
+    pub fn is_background(&self) -> bool {
+        self.depth <= 0.0
+    }
+
+    pub fn ndc(&self) -> Vec3 {
+        pixel_to_ndc(self.x, self.y, self.viewport_size).extend(self.depth)
+    }
+
+    /// Where the pixel is in world space, `None` for the background which is infinitely far.
+    pub fn world_position(&self) -> Option<Vec3> {
+        (!self.is_background()).then(|| unproject(self.inverse_view_proj, self.ndc()))
+    }
+}
+
+/// NDC of the center of a pixel. The viewport isn't flipped, so the top row is at -1.
+pub fn pixel_to_ndc(x: u32, y: u32, viewport_size: Vec2) -> Vec2 {
+    (Vec2::new(x as f32, y as f32) + 0.5) / viewport_size * 2.0 - 1.0
+}
+
+/// The world position of a point in NDC, with the `inverse_view_proj` of [`ViewUniforms`].
+pub fn unproject(inverse_view_proj: Mat4, ndc: Vec3) -> Vec3 {
+    inverse_view_proj.project_point3(ndc)
+}
+
+/// Reads the depth buffer at the cursor back to the CPU, for placing objects on surfaces or
+/// focusing an orbit camera on what's under it. Like
+/// [`ObjectPicker`](super::passes::picking::ObjectPicker) the result arrives a few frames after
+/// it was asked for.
+#[derive(Debug)]
+pub struct DepthReadback {
+    readback: ReadbackRing,
+    /// Read back next, with the matrices of the frame that asked for it.
+    requested: Option<(u32, u32, Mat4, Vec2)>,
+    pending: Option<(DepthSample, vk::Format, ReadbackTicket)>,
+    result: Option<DepthSample>,
+}
+
+impl DepthReadback {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<Self> {
+        Ok(Self {
+            readback: ReadbackRing::new(render_instance, render_allocator, 16)?,
+            requested: None,
+            pending: None,
+            result: None,
+        })
+    }
+
+    /// The depth at `x`, `y` once its readback finished, `None` while it's in flight. `view`
+    /// is what the frame being recorded renders with. Keep calling it for the same position
+    /// to keep reading it back as the scene changes.
+    pub fn read_depth(&mut self, x: u32, y: u32, view: &ViewUniforms) -> Option<DepthSample> {
+        if x as f32 >= view.viewport_size.x || y as f32 >= view.viewport_size.y {
+            return None;
+        }
+        self.requested = Some((x, y, view.inverse_view_proj, view.viewport_size));
+        self.result.filter(|sample| (sample.x, sample.y) == (x, y))
+    }
+
+    /// The last depth read back, at whatever position it was asked for.
+    pub fn latest(&self) -> Option<DepthSample> {
+        self.result
+    }
+
+    /// Picks up the finished readback, call once per frame before recording.
+    pub fn begin_frame(&mut self, render_instance: &RenderInstance) {
+        self.readback.begin_frame();
+        let Some((sample, format, ticket)) = self.pending else {
+            return;
+        };
+        match self.readback.read(render_instance, ticket) {
+            Readback::Pending => {}
+            Readback::Ready(bytes) => {
+                self.result = Some(DepthSample {
+                    depth: decode_depth(format, &bytes),
+                    ..sample
+                });
+                self.pending = None;
+            }
+            Readback::Expired => self.pending = None,
+        }
+    }
+
+    /// Records the copy of the requested depth, after the passes writing it. `depth_image`
+    /// needs `TRANSFER_SRC` usage and is left in the layout of `usage`.
+    pub fn record_readback(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        depth_image: vk::Image,
+        format: vk::Format,
+        usage: Usage,
+    ) {
+        if self.pending.is_some() {
+            return;
+        }
+        let Some((x, y, inverse_view_proj, viewport_size)) = self.requested.take() else {
+            return;
+        };
+        let ticket = self
+            .readback
+            .copy_texel(
+                render_instance,
+                command_buffer,
+                depth_image,
+                format,
+                usage,
+                x,
+                y,
+            )
+            .expect("Depth readback copies one texel per frame");
+        let sample = DepthSample {
+            x,
+            y,
+            depth: 0.0,
+            inverse_view_proj,
+            viewport_size,
+        };
+        self.pending = Some((sample, format, ticket));
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.readback.destroy(render_instance, render_allocator);
+    }
+}
+
+/// The depth in a texel copied out of the depth aspect of `format`.
+fn decode_depth(format: vk::Format, bytes: &[u8]) -> f32 {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::D16_UNORM_S8_UINT => {
+            u16::from_ne_bytes(bytes.try_into().unwrap()) as f32 / u16::MAX as f32
+        }
+        vk::Format::D32_SFLOAT | vk::Format::D32_SFLOAT_S8_UINT => {
+            f32::from_ne_bytes(bytes.try_into().unwrap())
+        }
+        // the upper 8 bits of the copied texel are undefined
+        vk::Format::D24_UNORM_S8_UINT | vk::Format::X8_D24_UNORM_PACK32 => {
+            let texel = u32::from_ne_bytes(bytes.try_into().unwrap());
+            (texel & 0x00ff_ffff) as f32 / 0x00ff_ffff as f32
+        }
+        _ => panic!("{:?} isn't a depth format", format),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unproject_round_trip() {
+        let viewport_size = Vec2::new(800.0, 600.0);
+        let proj = Mat4::perspective_infinite_reverse_rh(1.0, 800.0 / 600.0, 0.1);
+        let view = Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0));
+        let view_proj = proj * view.inverse();
+
+        let point = Vec3::new(1.5, 1.0, -7.0);
+        let ndc = view_proj.project_point3(point);
+        let sample = DepthSample {
+            x: ((ndc.x + 1.0) * 0.5 * viewport_size.x) as u32,
+            y: ((ndc.y + 1.0) * 0.5 * viewport_size.y) as u32,
+            depth: ndc.z,
+            inverse_view_proj: view * proj.inverse(),
+            viewport_size,
+        };
+        let position = sample.world_position().unwrap();
+        // off by at most half a pixel
+        assert!(position.distance(point) < 0.05, "{position}");
+
+        assert_eq!(pixel_to_ndc(0, 0, Vec2::splat(2.0)), Vec2::splat(-0.5));
+        let background = DepthSample {
+            depth: 0.0,
+            ..sample
+        };
+        assert_eq!(background.world_position(), None);
+    }
+
+    #[test]
+    fn test_decode_depth() {
+        assert_eq!(
+            decode_depth(vk::Format::D16_UNORM, &u16::MAX.to_ne_bytes()),
+            1.0
+        );
+        assert_eq!(
+            decode_depth(vk::Format::D32_SFLOAT, &0.25f32.to_ne_bytes()),
+            0.25
+        );
+        assert_eq!(
+            decode_depth(
+                vk::Format::X8_D24_UNORM_PACK32,
+                &0xff00_0000u32.to_ne_bytes()
+            ),
+            0.0
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::error::Result;
+
+use super::{deferred_destroy::DESTROY_DELAY_FRAMES, shaders, RenderInstance};
+
+/// Amount of sets a new pool is sized for, based on the sets that didn't fit anywhere else.
+const SETS_PER_POOL: u32 = 16;
+/// Descriptor counts above this aren't multiplied by [`SETS_PER_POOL`], so a pool for a
+/// bindless array doesn't reserve room for sixteen of them.
+const MAX_SCALED_DESCRIPTOR_COUNT: u32 = 4096;
+
+/// Hands out descriptor sets from a few shared pools, instead of creating a pool for every
+/// pipeline. Pools are added when the existing ones are full. Sets from [`Self::allocate`]
+/// are handed back with [`Self::free`], while sets from [`Self::allocate_transient`] are
+/// reclaimed all at once a few frames later.
+#[derive(Debug, Default)]
+pub struct DescriptorAllocator {
+    pools: Vec<vk::DescriptorPool>,
+    /// One more than the frames that can be in flight, the pools of a frame are reset at the
+    /// end of the frame after it.
+    transient_pools: [Vec<vk::DescriptorPool>; DESTROY_DELAY_FRAMES as usize + 1],
+    frame: usize,
+}
+
+impl DescriptorAllocator {
+    /// Allocates a set for every layout, `sizes` are the descriptors they need together. The
+    /// sets all come from the returned pool.
+    pub fn allocate(
+        &mut self,
+        device: &ash::Device,
+        layouts: &[vk::DescriptorSetLayout],
+        sizes: &[vk::DescriptorPoolSize],
+    ) -> Result<(vk::DescriptorPool, Vec<vk::DescriptorSet>)> {
+        allocate_from(
+            device,
+            &mut self.pools,
+            vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET,
+            layouts,
+            sizes,
+        )
+    }
+
+    /// Returns sets to the pool they were allocated from, in-flight frames can't be using them.
+    pub fn free(
+        &mut self,
+        device: &ash::Device,
+        pool: vk::DescriptorPool,
+        sets: &[vk::DescriptorSet],
+    ) -> Result<()> {
+        if !sets.is_empty() {
+            unsafe { device.free_descriptor_sets(pool, sets)? };
+            shaders::forget_descriptor_sets(sets);
+        }
+        Ok(())
+    }
+
+    /// Like [`Self::allocate`], but the sets are only valid until the frame ends and don't have
+    /// to be freed.
+    pub fn allocate_transient(
+        &mut self,
+        device: &ash::Device,
+        layouts: &[vk::DescriptorSetLayout],
+        sizes: &[vk::DescriptorPoolSize],
+    ) -> Result<Vec<vk::DescriptorSet>> {
+        let (_, sets) = allocate_from(
+            device,
+            &mut self.transient_pools[self.frame],
+            vk::DescriptorPoolCreateFlags::empty(),
+            layouts,
+            sizes,
+        )?;
+        Ok(sets)
+    }
+
+    /// Starts a new frame, resetting the transient pools of the frame that used them
+    /// [`DESTROY_DELAY_FRAMES`] + 1 frames ago.
+    pub fn begin_frame(&mut self, device: &ash::Device) -> Result<()> {
+        self.frame = (self.frame + 1) % self.transient_pools.len();
+        for pool in self.transient_pools[self.frame].iter() {
+            unsafe { device.reset_descriptor_pool(*pool, vk::DescriptorPoolResetFlags::empty())? };
+        }
+        Ok(())
+    }
+
+    /// Destroys all pools, together with the sets that are still allocated from them.
+    pub fn destroy(&mut self, device: &ash::Device) {
+        let transient_pools = self
+            .transient_pools
+            .iter_mut()
+            .flat_map(|pools| pools.drain(..));
+        for pool in self.pools.drain(..).chain(transient_pools) {
+            unsafe { device.destroy_descriptor_pool(pool, None) };
+        }
+    }
+}
+
+fn allocate_from(
+    device: &ash::Device,
+    pools: &mut Vec<vk::DescriptorPool>,
+    flags: vk::DescriptorPoolCreateFlags,
+    layouts: &[vk::DescriptorSetLayout],
+    sizes: &[vk::DescriptorPoolSize],
+) -> Result<(vk::DescriptorPool, Vec<vk::DescriptorSet>)> {
+    // the most recent pool is the most likely to have room left
+    for pool in pools.iter().rev() {
+        match allocate_sets(device, *pool, layouts) {
+            Ok(sets) => return Ok((*pool, sets)),
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY | vk::Result::ERROR_FRAGMENTED_POOL) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let pool_sizes = sizes
+        .iter()
+        .map(|size| vk::DescriptorPoolSize {
+            ty: size.ty,
+            descriptor_count: size
+                .descriptor_count
+                .saturating_mul(SETS_PER_POOL)
+                .min(size.descriptor_count.max(MAX_SCALED_DESCRIPTOR_COUNT)),
+        })
+        .collect::<Vec<_>>();
+    let pool = unsafe {
+        device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::default()
+                .flags(flags)
+                .pool_sizes(&pool_sizes)
+                .max_sets(layouts.len() as u32 * SETS_PER_POOL),
+            None,
+        )?
+    };
+    pools.push(pool);
+
+    Ok((pool, allocate_sets(device, pool, layouts)?))
+}
+
+fn allocate_sets(
+    device: &ash::Device,
+    pool: vk::DescriptorPool,
+    layouts: &[vk::DescriptorSetLayout],
+) -> ash::prelude::VkResult<Vec<vk::DescriptorSet>> {
+    let sets = unsafe {
+        device.allocate_descriptor_sets(
+            &vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(layouts),
+        )?
+    };
+    shaders::register_descriptor_sets(layouts, &sets);
+    Ok(sets)
+}
+
+pub(crate) fn begin_descriptor_frame(render_instance: Res<RenderInstance>) {
+    let result = render_instance
+        .0
+        .descriptor_allocator
+        .lock()
+        .unwrap()
+        .begin_frame(render_instance.device());
+    if let Err(e) = result {
+        println!("Failed to reset the transient descriptor pools: {}", e);
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::error::Result;
+
+use super::{
+    deferred_destroy::{self, DeferredResource},
+    descriptor_writer::DescriptorWriter,
+    shaders, RenderInstance,
+};
+
+/// Sets that weren't asked for in this many frames are freed.
+const EVICT_AFTER_FRAMES: u64 = 8;
+
+/// A resource written to a binding of a cached set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CachedBinding {
+    Buffer {
+        ty: vk::DescriptorType,
+        buffer: vk::Buffer,
+        offset: vk::DeviceSize,
+        range: vk::DeviceSize,
+    },
+    Image {
+        ty: vk::DescriptorType,
+        view: vk::ImageView,
+        sampler: vk::Sampler,
+        layout: vk::ImageLayout,
+    },
+    AccelerationStructure(vk::AccelerationStructureKHR),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct DescriptorSetKey {
+    layout: vk::DescriptorSetLayout,
+    /// Sorted by binding, so the order they're passed in doesn't matter.
+    bindings: Vec<(u32, CachedBinding)>,
+}
+
+impl DescriptorSetKey {
+    fn new(layout: vk::DescriptorSetLayout, bindings: &[(u32, CachedBinding)]) -> Self {
+        let mut bindings = bindings.to_vec();
+        bindings.sort_by_key(|(binding, _)| *binding);
+        Self { layout, bindings }
+    }
+}
+
+#[derive(Debug)]
+struct CachedSet {
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    last_used: u64,
+}
+
+/// Reuses descriptor sets when the same resources are bound to the same layout again, so
+/// mostly static scenes write their descriptors once instead of every draw or frame.
+///
+/// Sets are keyed by raw handles. The sets referring to a resource or pipeline layout destroyed
+/// by the [`DeferredDestroyQueue`](super::deferred_destroy::DeferredDestroyQueue) are dropped
+/// with it, resources that are destroyed otherwise have to drop their sets with
+/// [`Self::invalidate`] before a new object can get the same handle.
+#[derive(Resource, Debug, Default)]
+pub struct DescriptorSetCache {
+    sets: HashMap<DescriptorSetKey, CachedSet>,
+    frame: u64,
+}
+
+impl DescriptorSetCache {
+    /// The set of `layout` with `bindings` written to it, allocated and written on a miss.
+    /// `layout_info` is the matching entry of a pipeline's `set_layout_info`.
+    pub fn get(
+        &mut self,
+        render_instance: &RenderInstance,
+        layout: vk::DescriptorSetLayout,
+        layout_info: &HashMap<u32, vk::DescriptorType>,
+        bindings: &[(u32, CachedBinding)],
+    ) -> Result<vk::DescriptorSet> {
+        let key = DescriptorSetKey::new(layout, bindings);
+        if let Some(cached) = self.sets.get_mut(&key) {
+            cached.last_used = self.frame;
+            return Ok(cached.set);
+        }
+
+        let (pool, sets) = render_instance
+            .0
+            .descriptor_allocator
+            .lock()
+            .unwrap()
+            .allocate(
+                render_instance.device(),
+                &[layout],
+                &shaders::descriptor_pool_sizes(render_instance, std::slice::from_ref(layout_info)),
+            )?;
+        let set = sets[0];
+
+        let mut writer = DescriptorWriter::new();
+        for (binding, resource) in key.bindings.iter() {
+            match *resource {
+                CachedBinding::Buffer {
+                    ty,
+                    buffer,
+                    offset,
+                    range,
+                } => writer.buffers(
+                    set,
+                    *binding,
+                    0,
+                    ty,
+                    &[vk::DescriptorBufferInfo::default()
+                        .buffer(buffer)
+                        .offset(offset)
+                        .range(range)],
+                ),
+                CachedBinding::Image {
+                    ty,
+                    view,
+                    sampler,
+                    layout,
+                } => writer.images(
+                    set,
+                    *binding,
+                    0,
+                    ty,
+                    &[vk::DescriptorImageInfo::default()
+                        .image_view(view)
+                        .sampler(sampler)
+                        .image_layout(layout)],
+                ),
+                CachedBinding::AccelerationStructure(handle) => {
+                    writer.acceleration_structures(set, *binding, &[handle])
+                }
+            };
+        }
+        writer.flush(render_instance.device());
+
+        self.sets.insert(
+            key,
+            CachedSet {
+                pool,
+                set,
+                last_used: self.frame,
+            },
+        );
+        Ok(set)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    /// Frees the sets of the layouts and bindings `matches` returns true for, once the frames
+    /// in flight are done with them.
+    pub fn invalidate(
+        &mut self,
+        matches: impl Fn(vk::DescriptorSetLayout, &[(u32, CachedBinding)]) -> bool,
+    ) {
+        self.sets.retain(|key, cached| {
+            let keep = !matches(key.layout, &key.bindings);
+            if !keep {
+                release(cached);
+            }
+            keep
+        });
+    }
+
+    /// Ends the frame, freeing the sets that weren't used for [`EVICT_AFTER_FRAMES`] frames.
+    pub fn end_frame(&mut self) {
+        let frame = self.frame;
+        self.sets.retain(|_, cached| {
+            let keep = cached.last_used + EVICT_AFTER_FRAMES > frame;
+            if !keep {
+                release(cached);
+            }
+            keep
+        });
+        self.frame += 1;
+    }
+}
+
+fn release(cached: &CachedSet) {
+    deferred_destroy::release(DeferredResource::DescriptorSets {
+        descriptor_pool: cached.pool,
+        descriptor_sets: vec![cached.set],
+    });
+}
+
+pub(crate) fn evict_descriptor_sets(mut cache: ResMut<DescriptorSetCache>) {
+    cache.end_frame();
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk::{self, Handle};
+
+    use super::{CachedBinding, DescriptorSetKey};
+
+    #[test]
+    fn test_key_ignores_binding_order() {
+        let layout = vk::DescriptorSetLayout::from_raw(1);
+        let buffer = |raw| CachedBinding::Buffer {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            buffer: vk::Buffer::from_raw(raw),
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        };
+        assert_eq!(
+            DescriptorSetKey::new(layout, &[(0, buffer(2)), (1, buffer(3))]),
+            DescriptorSetKey::new(layout, &[(1, buffer(3)), (0, buffer(2))])
+        );
+        assert_ne!(
+            DescriptorSetKey::new(layout, &[(0, buffer(2)), (1, buffer(3))]),
+            DescriptorSetKey::new(layout, &[(0, buffer(3)), (1, buffer(2))])
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use ash::vk;
+
+use crate::{buffer::Buffer, error::Result};
+
+use super::{acceleration_structure::Tlas, shaders, stats};
+
+#[derive(Debug)]
+enum WriteKind {
+    Images(vk::DescriptorType, Vec<vk::DescriptorImageInfo>),
+    Buffers(vk::DescriptorType, Vec<vk::DescriptorBufferInfo>),
+    AccelerationStructures(Vec<vk::AccelerationStructureKHR>),
+}
+
+#[derive(Debug)]
+struct PendingWrite {
+    set: vk::DescriptorSet,
+    binding: u32,
+    array_element: u32,
+    kind: WriteKind,
+}
+
+/// Collects descriptor writes across any number of sets and applies them with a single
+/// `vkUpdateDescriptorSets`. The writer owns the image, buffer and acceleration structure infos,
+/// so they stay alive until [`DescriptorWriter::flush`].
+#[derive(Debug, Default)]
+pub struct DescriptorWriter {
+    writes: Vec<PendingWrite>,
+}
+
+impl DescriptorWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    /// Writes `infos` to consecutive array elements of `binding`, starting at `array_element`.
+    pub fn images(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        array_element: u32,
+        ty: vk::DescriptorType,
+        infos: &[vk::DescriptorImageInfo],
+    ) -> &mut Self {
+        self.push(
+            set,
+            binding,
+            array_element,
+            WriteKind::Images(ty, infos.to_vec()),
+        )
+    }
+
+    /// Storage images are expected in the `GENERAL` layout, anything else in
+    /// `SHADER_READ_ONLY_OPTIMAL`.
+    pub fn image(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+        view: vk::ImageView,
+        sampler: vk::Sampler,
+    ) -> &mut Self {
+        let layout = if ty == vk::DescriptorType::STORAGE_IMAGE {
+            vk::ImageLayout::GENERAL
+        } else {
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        };
+        self.images(
+            set,
+            binding,
+            0,
+            ty,
+            &[vk::DescriptorImageInfo::default()
+                .image_view(view)
+                .image_layout(layout)
+                .sampler(sampler)],
+        )
+    }
+
+    /// Writes `infos` to consecutive array elements of `binding`, starting at `array_element`.
+    pub fn buffers(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        array_element: u32,
+        ty: vk::DescriptorType,
+        infos: &[vk::DescriptorBufferInfo],
+    ) -> &mut Self {
+        self.push(
+            set,
+            binding,
+            array_element,
+            WriteKind::Buffers(ty, infos.to_vec()),
+        )
+    }
+
+    /// Binds the whole buffer.
+    pub fn buffer(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+        buffer: &Buffer,
+    ) -> &mut Self {
+        self.buffers(
+            set,
+            binding,
+            0,
+            ty,
+            &[vk::DescriptorBufferInfo::default()
+                .buffer(buffer.buffer)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)],
+        )
+    }
+
+    pub fn acceleration_structure(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        tlas: &Tlas,
+    ) -> &mut Self {
+        self.acceleration_structures(set, binding, &[tlas.handle])
+    }
+
+    /// Writes `handles` to consecutive array elements of `binding`.
+    pub fn acceleration_structures(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        handles: &[vk::AccelerationStructureKHR],
+    ) -> &mut Self {
+        self.push(
+            set,
+            binding,
+            0,
+            WriteKind::AccelerationStructures(handles.to_vec()),
+        )
+    }
+
+    fn push(
+        &mut self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        array_element: u32,
+        kind: WriteKind,
+    ) -> &mut Self {
+        self.writes.push(PendingWrite {
+            set,
+            binding,
+            array_element,
+            kind,
+        });
+        self
+    }
+
+    /// Builds the Vulkan writes, which borrow the infos owned by `self`.
+    fn with_writes<R>(&self, f: impl FnOnce(&[vk::WriteDescriptorSet]) -> R) -> R {
+        let mut acceleration_structure_writes = self
+            .writes
+            .iter()
+            .map(|write| match &write.kind {
+                WriteKind::AccelerationStructures(handles) => Some(
+                    vk::WriteDescriptorSetAccelerationStructureKHR::default()
+                        .acceleration_structures(handles),
+                ),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let writes = self
+            .writes
+            .iter()
+            .zip(acceleration_structure_writes.iter_mut())
+            .map(|(write, acceleration_structure_write)| {
+                let vk_write = vk::WriteDescriptorSet::default()
+                    .dst_set(write.set)
+                    .dst_binding(write.binding)
+                    .dst_array_element(write.array_element);
+                match &write.kind {
+                    WriteKind::Images(ty, infos) => vk_write.descriptor_type(*ty).image_info(infos),
+                    WriteKind::Buffers(ty, infos) => {
+                        vk_write.descriptor_type(*ty).buffer_info(infos)
+                    }
+                    WriteKind::AccelerationStructures(handles) => {
+                        let mut vk_write = vk_write
+                            .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                            .push_next(acceleration_structure_write.as_mut().unwrap());
+                        // not derived from the extension struct
+                        vk_write.descriptor_count = handles.len() as u32;
+                        vk_write
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        f(&writes)
+    }
+
+    /// Runs `validate` on every pending write, like
+    /// [`ComputePipeline::validate_descriptor_write`](super::pipeline::ComputePipeline::validate_descriptor_write).
+    pub fn validate(&self, validate: impl Fn(&vk::WriteDescriptorSet) -> Result<()>) -> Result<()> {
+        self.with_writes(|writes| writes.iter().try_for_each(validate))
+    }
+
+    /// Applies all pending writes and clears the writer, so it can be reused. In debug builds the
+    /// writes are checked against the reflection of the shaders first, see
+    /// [`shaders::assert_valid_descriptor_write`].
+    pub fn flush(&mut self, device: &ash::Device) {
+        if self.writes.is_empty() {
+            return;
+        }
+        self.with_writes(|writes| {
+            writes
+                .iter()
+                .for_each(shaders::assert_valid_descriptor_write);
+            unsafe { device.update_descriptor_sets(writes, &[]) }
+        });
+        stats::count_descriptor_writes(self.writes.len() as u64);
+        self.writes.clear();
+    }
+}
@@ -0,0 +1,183 @@
+use std::{
+    mem::{align_of, size_of},
+    sync::Mutex,
+};
+
+use bevy::prelude::*;
+
+/// Size of the chunks the arena allocates from, larger slices get a chunk of their own.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Unit the chunks are made of, so every chunk starts 16 byte aligned.
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct Block([u8; 16]);
+
+const BLOCK_SIZE: usize = size_of::<Block>();
+
+struct Chunk {
+    /// Boxed, so slices handed out stay where they are when the list of chunks grows.
+    blocks: Box<[Block]>,
+    used: usize,
+}
+
+impl Chunk {
+    fn new(size: usize) -> Self {
+        Self {
+            blocks: vec![Block([0; 16]); size.div_ceil(BLOCK_SIZE)].into_boxed_slice(),
+            used: 0,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.blocks.len() * BLOCK_SIZE
+    }
+
+    /// Offset of `size` bytes aligned to `align`, if they fit.
+    fn bump(&mut self, size: usize, align: usize) -> Option<usize> {
+        let offset = self.used.next_multiple_of(align);
+        if offset + size > self.size() {
+            return None;
+        }
+        self.used = offset + size;
+        Some(offset)
+    }
+}
+
+#[derive(Default)]
+struct Chunks {
+    chunks: Vec<Chunk>,
+    /// First chunk that may have room left.
+    current: usize,
+}
+
+/// Scratch memory for assembling data on the CPU before it's uploaded, reset at the end of
+/// every frame. Slices are bump allocated from chunks that are kept around, so building
+/// instance data or vertices every frame doesn't allocate once the arena has grown to fit.
+/// They're plain `Pod` slices that go straight into the upload APIs:
+///
+/// ```ignore
+/// let instances = arena.alloc_slice::<InstanceData>(count);
+/// // fill `instances`
+/// buffer.write(render_instance, render_allocator, bytemuck::cast_slice(instances), 0)?;
+/// ```
+#[derive(Resource, Default)]
+pub struct FrameArena {
+    inner: Mutex<Chunks>,
+}
+
+impl std::fmt::Debug for FrameArena {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FrameArena")
+            .field("allocated", &self.allocated())
+            .field("capacity", &self.capacity())
+            .finish()
+    }
+}
+
+impl FrameArena {
+    /// `len` zeroed elements that live until the next [`Self::reset`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: bytemuck::Pod>(&self, len: usize) -> &mut [T] {
+        assert!(
+            align_of::<T>() <= BLOCK_SIZE,
+            "Frame arena slices are aligned to at most {} bytes",
+            BLOCK_SIZE
+        );
+        let size = len * size_of::<T>();
+        if size == 0 {
+            return &mut [];
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let ptr = loop {
+            let current = inner.current;
+            if current == inner.chunks.len() {
+                inner.chunks.push(Chunk::new(size.max(CHUNK_SIZE)));
+            }
+            let chunk = &mut inner.chunks[current];
+            if let Some(offset) = chunk.bump(size, align_of::<T>()) {
+                break unsafe { chunk.blocks.as_mut_ptr().cast::<u8>().add(offset) };
+            }
+            inner.current += 1;
+        };
+        // the chunks aren't freed or reused before `reset`, which needs `&mut self`, and every
+        // range is handed out once, so the slice lives as long as the borrow of the arena
+        let bytes = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
+        bytes.fill(0);
+        bytemuck::cast_slice_mut(bytes)
+    }
+
+    /// A copy of `data` that lives until the next [`Self::reset`].
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_copy<T: bytemuck::Pod>(&self, data: &[T]) -> &mut [T] {
+        let slice = self.alloc_slice(data.len());
+        slice.copy_from_slice(data);
+        slice
+    }
+
+    /// Collects `items` into the arena instead of a `Vec`.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_from_iter<T: bytemuck::Pod>(
+        &self,
+        items: impl ExactSizeIterator<Item = T>,
+    ) -> &mut [T] {
+        let slice = self.alloc_slice(items.len());
+        for (slot, item) in slice.iter_mut().zip(items) {
+            *slot = item;
+        }
+        slice
+    }
+
+    /// Bytes handed out since the last reset, including alignment padding.
+    pub fn allocated(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.chunks.iter().map(|chunk| chunk.used).sum()
+    }
+
+    pub fn capacity(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.chunks.iter().map(Chunk::size).sum()
+    }
+
+    /// Frees all slices, keeping the chunks. Oversized chunks of a single large slice are
+    /// dropped, so a one-off upload doesn't pin its memory.
+    pub fn reset(&mut self) {
+        let inner = self.inner.get_mut().unwrap();
+        inner.chunks.retain(|chunk| chunk.size() <= CHUNK_SIZE);
+        for chunk in inner.chunks.iter_mut() {
+            chunk.used = 0;
+        }
+        inner.current = 0;
+    }
+}
+
+pub(crate) fn reset_frame_arena(mut arena: ResMut<FrameArena>) {
+    arena.reset();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_alloc_and_reset() {
+        let mut arena = FrameArena::default();
+        let bytes = arena.alloc_copy(&[1u8, 2, 3]);
+        let matrices = arena.alloc_slice::<[f32; 4]>(4);
+        assert_eq!(matrices.as_ptr() as usize % align_of::<[f32; 4]>(), 0);
+        assert!(matrices.iter().flatten().all(|value| *value == 0.0));
+        let squares = arena.alloc_from_iter((0..4u32).map(|i| i * i));
+        assert_eq!(bytes, &[1, 2, 3]);
+        assert_eq!(squares, &[0, 1, 4, 9]);
+
+        // too big for a shared chunk
+        arena.alloc_slice::<u8>(CHUNK_SIZE * 2);
+        assert_eq!(arena.capacity(), CHUNK_SIZE * 3);
+
+        arena.reset();
+        assert_eq!(arena.allocated(), 0);
+        assert_eq!(arena.capacity(), CHUNK_SIZE);
+        assert_eq!(arena.alloc_slice::<u32>(2), &[0, 0]);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+use ash::vk;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{ctx::ExampleBase, error::Result};
+
+use super::{
+    graph::RenderGraph,
+    tracking::{LeakReport, LiveResource, ResourceKind},
+};
+
+/// How many checkpoints are kept, a few frames worth of passes.
+const MAX_CHECKPOINTS: usize = 256;
+
+static FRAME: AtomicU64 = AtomicU64::new(0);
+static CHECKPOINTS: Mutex<VecDeque<Checkpoint>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Checkpoint {
+    pub frame: u64,
+    /// Since the first checkpoint.
+    pub millis: f64,
+    pub label: String,
+}
+
+fn start() -> Instant {
+    static START: OnceLock<Instant> = OnceLock::new();
+    *START.get_or_init(Instant::now)
+}
+
+/// Marks the start of a frame for the checkpoints recorded after it.
+pub(crate) fn next_frame() {
+    FRAME.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Remembers that the CPU got to `label`, the last ones end up in
+/// [`ExampleBase::dump_frame_state`]. Every pass of the
+/// [`SequentialPassSystem`](super::SequentialPassSystem) records one before it runs.
+pub fn checkpoint(label: &str) {
+    let checkpoint = Checkpoint {
+        frame: FRAME.load(Ordering::Relaxed),
+        millis: start().elapsed().as_secs_f64() * 1000.0,
+        label: label.to_string(),
+    };
+    let mut checkpoints = CHECKPOINTS.lock().unwrap();
+    if checkpoints.len() == MAX_CHECKPOINTS {
+        checkpoints.pop_front();
+    }
+    checkpoints.push_back(checkpoint);
+}
+
+/// Oldest first.
+pub fn recent_checkpoints() -> Vec<Checkpoint> {
+    CHECKPOINTS.lock().unwrap().iter().cloned().collect()
+}
+
+#[derive(serde::Serialize)]
+struct DeviceDump {
+    name: String,
+    api_version: String,
+    driver_version: u32,
+    vendor_id: u32,
+    device_id: u32,
+    surface_format: String,
+    surface_resolution: (u32, u32),
+}
+
+#[derive(serde::Serialize)]
+struct ResourceDump {
+    kind: String,
+    handle: String,
+    name: Option<String>,
+    description: String,
+    layout: Option<String>,
+    released: bool,
+}
+
+fn device_dump(renderer: &ExampleBase) -> DeviceDump {
+    let properties = unsafe {
+        renderer
+            .instance
+            .get_physical_device_properties(renderer.pdevice)
+    };
+    let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) };
+    DeviceDump {
+        name: name.to_string_lossy().into_owned(),
+        api_version: format!(
+            "{}.{}.{}",
+            vk::api_version_major(properties.api_version),
+            vk::api_version_minor(properties.api_version),
+            vk::api_version_patch(properties.api_version)
+        ),
+        driver_version: properties.driver_version,
+        vendor_id: properties.vendor_id,
+        device_id: properties.device_id,
+        surface_format: format!(
+            "{:?} {:?}",
+            renderer.surface_format.format, renderer.surface_format.color_space
+        ),
+        surface_resolution: (
+            renderer.surface_resolution.width,
+            renderer.surface_resolution.height,
+        ),
+    }
+}
+
+/// Writes everything needed to make sense of a rendering bug into a zip at `path`:
+///
+/// - `device.json`, the GPU and the swapchain
+/// - `graph.json` and `graph.dot`, the compiled render graph
+/// - `resources.json`, live buffers and images with their sizes, formats and last layout
+/// - `shaders.json`, live shader modules with the hash of their SPIR-V
+/// - `checkpoints.json`, the labels recorded with [`checkpoint`]
+pub(crate) fn write(renderer: &ExampleBase, graph: &RenderGraph, path: &Path) -> Result<()> {
+    let (shaders, resources): (Vec<_>, Vec<_>) = LeakReport::live()
+        .resources
+        .into_iter()
+        .partition(|resource| resource.kind == ResourceKind::ShaderModule);
+    let dump = |resources: Vec<LiveResource>| {
+        let resources = resources
+            .into_iter()
+            .map(|resource| ResourceDump {
+                kind: format!("{:?}", resource.kind),
+                handle: format!("{:#x}", resource.handle),
+                name: resource.name,
+                description: resource.description,
+                layout: resource.layout.map(|layout| format!("{:?}", layout)),
+                released: resource.released,
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string_pretty(&resources).unwrap()
+    };
+
+    let files = [
+        (
+            "device.json",
+            serde_json::to_string_pretty(&device_dump(renderer)).unwrap(),
+        ),
+        ("graph.json", graph.dump_json()),
+        ("graph.dot", graph.dump_graphviz()),
+        ("resources.json", dump(resources)),
+        ("shaders.json", dump(shaders)),
+        (
+            "checkpoints.json",
+            serde_json::to_string_pretty(&recent_checkpoints()).unwrap(),
+        ),
+    ];
+
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, contents) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(contents.as_bytes())?;
+    }
+    zip.finish()?;
+    println!("Wrote frame state to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checkpoint, recent_checkpoints, MAX_CHECKPOINTS};
+
+    #[test]
+    fn test_checkpoints_keep_the_most_recent() {
+        for i in 0..MAX_CHECKPOINTS + 10 {
+            checkpoint(&format!("test {}", i));
+        }
+        let checkpoints = recent_checkpoints();
+        assert_eq!(checkpoints.len(), MAX_CHECKPOINTS);
+        assert_eq!(
+            checkpoints.last().unwrap().label,
+            format!("test {}", MAX_CHECKPOINTS + 9)
+        );
+    }
+}
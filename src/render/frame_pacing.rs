@@ -0,0 +1,82 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use ash::vk;
+use bevy::{prelude::*, utils::Instant};
+
+use super::{extract::Extract, RenderInstance};
+
+/// How long the latency limiter waits for a present before giving up, so a minimized window
+/// doesn't block the renderer.
+const PRESENT_WAIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Frame pacing settings, insert or change it in the main app.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct FramePacing {
+    /// Waits until the previous frame is on screen before recording the next one, so input is
+    /// sampled as late as possible. Needs `VK_KHR_present_wait`, ignored without it.
+    pub latency_limit: bool,
+    /// Frames per second the renderer is limited to, rates that aren't finite and positive are
+    /// ignored.
+    pub target_frame_rate: Option<f64>,
+}
+
+pub(crate) fn extract_frame_pacing(
+    pacing: Extract<Option<Res<FramePacing>>>,
+    mut render_pacing: ResMut<FramePacing>,
+) {
+    if let Some(pacing) = pacing.as_ref() {
+        if pacing.is_changed() {
+            if let Some(target) = pacing
+                .target_frame_rate
+                .filter(|target| frame_time(*target).is_none())
+            {
+                println!(
+                    "Ignoring the target frame rate {}, it has to be finite and positive",
+                    target
+                );
+            }
+            *render_pacing = FramePacing::clone(pacing);
+        }
+    }
+}
+
+/// Runs before the frame is recorded.
+pub(crate) fn pace_frame(
+    pacing: Res<FramePacing>,
+    render_instance: Res<RenderInstance>,
+    mut frame_start: Local<Option<Instant>>,
+) {
+    let renderer = render_instance.0.as_ref();
+    if pacing.latency_limit {
+        let present_id = renderer.present_id.load(Ordering::Relaxed);
+        if let (Some(present_wait), true) = (renderer.present_wait.as_ref(), present_id > 0) {
+            let result = unsafe {
+                present_wait.wait_for_present(
+                    renderer.swapchain,
+                    present_id,
+                    PRESENT_WAIT_TIMEOUT.as_nanos() as u64,
+                )
+            };
+            match result {
+                Ok(()) | Err(vk::Result::TIMEOUT) => {}
+                Err(e) => println!("Waiting for present {} failed: {}", present_id, e),
+            }
+        }
+    }
+
+    let frame_time = pacing.target_frame_rate.and_then(frame_time);
+    if let (Some(frame_time), Some(start)) = (frame_time, *frame_start) {
+        if let Some(remaining) = frame_time.checked_sub(start.elapsed()) {
+            std::thread::sleep(remaining);
+        }
+    }
+    *frame_start = Some(Instant::now());
+}
+
+/// `None` for rates that aren't finite and positive.
+fn frame_time(target_frame_rate: f64) -> Option<Duration> {
+    if !(target_frame_rate.is_finite() && target_frame_rate > 0.0) {
+        return None;
+    }
+    Duration::try_from_secs_f64(1.0 / target_frame_rate).ok()
+}
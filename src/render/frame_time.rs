@@ -0,0 +1,73 @@
+use std::mem::size_of;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::error::Result;
+
+use super::{
+    extract::Extract,
+    ring::{RingAllocation, RingBuffer},
+    RenderAllocator, RenderInstance,
+};
+
+/// Matches the `FrameTime` `buffer_reference` in `shader/global.glsl`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FrameTimeUniforms {
+    /// Seconds since startup, wrapped around every hour so it keeps its precision as a float.
+    pub time: f32,
+    /// Seconds since the previous frame.
+    pub delta_time: f32,
+    pub frame_index: u32,
+    pub _padding: u32,
+}
+
+/// The [`FrameTimeUniforms`] of every frame, pass `current.device_addr` to a shader to animate
+/// with the time or to scale clock deltas by the frame time.
+#[derive(Resource)]
+pub struct FrameTimeBuffer {
+    ring: RingBuffer,
+    /// `None` until the main app has a `Time` resource.
+    pub current: Option<RingAllocation>,
+    frame_index: u32,
+}
+
+impl FrameTimeBuffer {
+    pub(crate) fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<Self> {
+        Ok(Self {
+            ring: RingBuffer::new(
+                render_instance,
+                render_allocator,
+                size_of::<FrameTimeUniforms>() as u64,
+                render_instance.0.config.uniform_ring_frames,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            )?,
+            current: None,
+            frame_index: 0,
+        })
+    }
+}
+
+pub(crate) fn extract_frame_time(
+    time: Extract<Option<Res<Time>>>,
+    mut frame_time_buffer: ResMut<FrameTimeBuffer>,
+) {
+    let Some(time) = time.as_ref() else {
+        return;
+    };
+
+    let frame_time_buffer = &mut *frame_time_buffer;
+    let uniforms = FrameTimeUniforms {
+        time: time.elapsed_seconds_wrapped(),
+        delta_time: time.delta_seconds(),
+        frame_index: frame_time_buffer.frame_index,
+        _padding: 0,
+    };
+    frame_time_buffer.ring.begin_frame();
+    frame_time_buffer.current = Some(frame_time_buffer.ring.push(&uniforms));
+    frame_time_buffer.frame_index = frame_time_buffer.frame_index.wrapping_add(1);
+}
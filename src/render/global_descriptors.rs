@@ -3,16 +3,22 @@ use std::collections::{BTreeMap, HashMap};
 use ash::vk::{self, ShaderStageFlags};
 use bevy::{asset::HandleId, prelude::*};
 
-use super::RenderInstance;
+use crate::error::Result;
+
+use super::{
+    descriptor_writer::DescriptorWriter, registry::ImageHandle, streaming::TextureStreamer,
+    RenderAllocator, RenderInstance,
+};
 
 #[derive(Resource)]
 pub struct GlobalDescriptorSet {
     // pub set_layouts: Vec<vk::DescriptorSetLayout>,
     // pub descriptor_sets: Vec<vk::DescriptorSet>,
     // set_layout_info: Vec<HashMap<u32, vk::DescriptorType>>,
-    pub textures: BTreeMap<Handle<super::image::Image>, crate::buffer::Image>,
-    pub buffers: BTreeMap<HandleId, crate::buffer::Buffer>,
-    image_infos: HashMap<Handle<super::image::Image>, Vec<vk::DescriptorImageInfo>>,
+    /// The images live in the registry of the [`RenderInstance`].
+    pub textures: BTreeMap<Handle<super::image::Image>, ImageHandle>,
+    /// Slot of every texture in the bindless array, which never changes once it's assigned.
+    texture_indices: HashMap<Handle<super::image::Image>, u32>,
     buffer_infos: HashMap<HandleId, Vec<vk::DescriptorBufferInfo>>,
 }
 
@@ -111,10 +117,9 @@ impl GlobalDescriptorSet {
             // set_layouts,
             // descriptor_sets,
             // set_layout_info,
-            buffers: BTreeMap::new(),
             textures: BTreeMap::new(),
+            texture_indices: HashMap::new(),
             buffer_infos: HashMap::new(),
-            image_infos: HashMap::new(),
         }
     }
 
@@ -123,30 +128,83 @@ impl GlobalDescriptorSet {
     //     self.buffers.iter().position(|(k, _)| k.eq(key))
     // }
 
-    /// TODO: use a Vec and a hashmap to prevent O(n) lookup
+    /// Registers `texture` for `key`. A texture that was already registered is swapped out
+    /// behind its handle, which queues the old one for destruction.
+    pub fn insert_texture(
+        &mut self,
+        render_instance: &RenderInstance,
+        key: Handle<super::image::Image>,
+        texture: crate::buffer::Image,
+    ) {
+        let mut registry = render_instance.registry_mut();
+        match self.textures.get(&key) {
+            Some(handle) => {
+                registry.images.replace(*handle, texture);
+            }
+            None => {
+                let index = self.texture_indices.len() as u32;
+                self.texture_indices.insert(key.clone(), index);
+                self.textures.insert(key, registry.insert_image(texture));
+            }
+        }
+    }
+
+    /// Uploads `image` and registers it for `key`, unless that already happened. Goes through
+    /// the streamer when streaming is enabled. Returns the index in the bindless array.
+    pub fn register_texture(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        streamer: &mut TextureStreamer,
+        key: &Handle<super::image::Image>,
+        image: &super::image::Image,
+    ) -> Result<usize> {
+        if let Some(index) = self.get_texture_index(key) {
+            return Ok(index);
+        }
+        if streamer.is_enabled() {
+            streamer.insert(render_instance, render_allocator, self, key.clone(), image)?;
+        } else {
+            let mut texture = crate::buffer::Image::from_image_buffer(
+                render_instance,
+                render_allocator,
+                image.data.clone(),
+                image.format,
+            )?;
+            texture.create_view(render_instance.device())?;
+            self.insert_texture(render_instance, key.clone(), texture);
+        }
+        Ok(self.get_texture_index(key).unwrap())
+    }
+
     pub fn get_texture_index(&self, key: &Handle<super::image::Image>) -> Option<usize> {
-        self.textures.iter().position(|(k, _)| k == key)
+        self.texture_indices.get(key).map(|index| *index as usize)
     }
 
     pub fn update_descriptor_set(
         &mut self,
         set: vk::DescriptorSet,
         render_instance: &RenderInstance,
-    ) {
-        let mut write_desc_sets = vec![];
-
-        for (key, texture) in self.textures.iter_mut() {
-            let view = texture.create_view(render_instance.device());
-
-            if !self.image_infos.contains_key(key) {
-                self.image_infos.insert(
-                    key.clone(),
-                    vec![vk::DescriptorImageInfo::default()
-                        .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-                        .image_view(view)
-                        .sampler(render_instance.0.get_default_sampler())],
-                );
-            }
+    ) -> Result<()> {
+        profile_scope!("GlobalDescriptorSet::update_descriptor_set");
+        let mut writer = DescriptorWriter::new();
+
+        let mut registry = render_instance.registry_mut();
+        for (key, texture) in self.textures.iter() {
+            let view = registry
+                .image_mut(*texture)
+                .create_view(render_instance.device())?;
+
+            writer.images(
+                set,
+                0,
+                self.texture_indices[key],
+                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                &[vk::DescriptorImageInfo::default()
+                    .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                    .image_view(view)
+                    .sampler(render_instance.0.get_default_sampler())],
+            );
         }
 
         // for (key, buffer) in self.buffers.iter_mut() {
@@ -161,17 +219,6 @@ impl GlobalDescriptorSet {
         //     }
         // }
 
-        for (index, (key, _)) in self.textures.iter_mut().enumerate() {
-            write_desc_sets.push(
-                vk::WriteDescriptorSet::default()
-                    .dst_set(set)
-                    .dst_binding(0)
-                    .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-                    .dst_array_element(index as u32)
-                    .image_info(self.image_infos.get(key).unwrap()),
-            );
-        }
-
         // for (index, (key, _)) in self.buffers.iter_mut().enumerate() {
         //     write_desc_sets.push(
         //         vk::WriteDescriptorSet::default()
@@ -183,10 +230,7 @@ impl GlobalDescriptorSet {
         //     );
         // }
 
-        unsafe {
-            render_instance
-                .device()
-                .update_descriptor_sets(&write_desc_sets, &[]);
-        };
+        writer.flush(render_instance.device());
+        Ok(())
     }
 }
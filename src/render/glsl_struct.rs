@@ -0,0 +1,543 @@
+use std::marker::PhantomData;
+
+use bevy::prelude::{IVec2, IVec4, Mat3, Mat4, Quat, UVec2, UVec4, Vec2, Vec3, Vec4};
+
+use super::shaders;
+
+/// How the members of a GLSL block are laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockLayout {
+    /// The layout of uniform blocks, like std430 but arrays and structs are aligned to 16 bytes
+    /// and every array element is padded to 16 bytes.
+    Std140,
+    /// The default for storage buffers, vectors are aligned to their size with `vec3` taking up
+    /// 16 bytes in arrays, and structs to their largest member.
+    Std430,
+    /// `GL_EXT_scalar_block_layout`, everything is aligned to its components like in a
+    /// `repr(C)` struct. Needs
+    /// [`ExampleBase::scalar_block_layout`](crate::ctx::ExampleBase::scalar_block_layout).
+    Scalar,
+}
+
+impl BlockLayout {
+    fn qualifier(self) -> &'static str {
+        match self {
+            BlockLayout::Std140 => "std140",
+            BlockLayout::Std430 => "std430",
+            BlockLayout::Scalar => "scalar",
+        }
+    }
+
+    /// Alignment and size of an array of `len` elements, with the alignment and size of an
+    /// `element`.
+    pub const fn array(self, element: (usize, usize), len: usize) -> (usize, usize) {
+        let (align, size) = element;
+        match self {
+            // std140 rounds the alignment and stride of arrays up to a vec4
+            BlockLayout::Std140 => {
+                let align = align.next_multiple_of(16);
+                (align, size.next_multiple_of(align) * len)
+            }
+            BlockLayout::Std430 => (align, size.next_multiple_of(align) * len),
+            BlockLayout::Scalar => (align, size * len),
+        }
+    }
+
+    /// Alignment and size of a struct with `(align, size)` members, in declaration order.
+    pub const fn struct_layout(self, members: &[(usize, usize)]) -> (usize, usize) {
+        // std140 rounds the alignment of structs up to a vec4 as well
+        let mut align = match self {
+            BlockLayout::Std140 => 16,
+            BlockLayout::Std430 | BlockLayout::Scalar => 1,
+        };
+        let mut size: usize = 0;
+        let mut i = 0;
+        while i < members.len() {
+            let (member_align, member_size) = members[i];
+            if member_align > align {
+                align = member_align;
+            }
+            size = size.next_multiple_of(member_align) + member_size;
+            i += 1;
+        }
+        (align, size.next_multiple_of(align))
+    }
+}
+
+/// Alignment and size of a type in every [`BlockLayout`]. They're constants so
+/// `#[derive(ShaderBind)]` can check the fields of a struct at compile time, [`GlslType`]
+/// reads them for the types that have them.
+pub trait GlslLayout {
+    const STD140: (usize, usize);
+    const STD430: (usize, usize);
+    const SCALAR: (usize, usize);
+}
+
+/// Alignment and size of `T` in a block with `layout`.
+pub const fn layout_of<T: GlslLayout>(layout: BlockLayout) -> (usize, usize) {
+    match layout {
+        BlockLayout::Std140 => T::STD140,
+        BlockLayout::Std430 => T::STD430,
+        BlockLayout::Scalar => T::SCALAR,
+    }
+}
+
+/// Panics with the message of the first field that isn't where GLSL puts it, used by
+/// `#[derive(ShaderBind)]`. Fields are `(offset, size, glsl_align, glsl_size, message)`.
+pub const fn check_fields(fields: &[(usize, usize, usize, usize, &str)]) {
+    let mut end: usize = 0;
+    let mut i = 0;
+    while i < fields.len() {
+        let (offset, size, glsl_align, glsl_size, message) = fields[i];
+        if offset != end.next_multiple_of(glsl_align) || size != glsl_size {
+            panic!("{}", message);
+        }
+        end = offset + size;
+        i += 1;
+    }
+}
+
+/// Rust types with a GLSL equivalent, used for the fields of a [`GlslStruct`].
+pub trait GlslType {
+    /// Alignment of the GLSL type in a block with `layout`.
+    fn align(layout: BlockLayout) -> usize;
+
+    /// Size of the GLSL type in a block with `layout`, which is more than the size of the Rust
+    /// type when GLSL pads it.
+    fn size(layout: BlockLayout) -> usize;
+
+    /// Declaration of a field called `name`, like `float name` or `vec2 name[4]`.
+    fn declare(name: &str) -> String;
+}
+
+macro_rules! glsl_types {
+    ($($ty:ty => $glsl:literal, $align:literal, $scalar_align:literal;)*) => {
+        $(impl GlslLayout for $ty {
+            const STD140: (usize, usize) = ($align, std::mem::size_of::<$ty>());
+            const STD430: (usize, usize) = ($align, std::mem::size_of::<$ty>());
+            const SCALAR: (usize, usize) = ($scalar_align, std::mem::size_of::<$ty>());
+        }
+
+        impl GlslType for $ty {
+            fn align(layout: BlockLayout) -> usize {
+                layout_of::<$ty>(layout).0
+            }
+
+            fn size(layout: BlockLayout) -> usize {
+                layout_of::<$ty>(layout).1
+            }
+
+            fn declare(name: &str) -> String {
+                format!(concat!($glsl, " {}"), name)
+            }
+        })*
+    };
+}
+
+glsl_types! {
+    f32 => "float", 4, 4;
+    u32 => "uint", 4, 4;
+    i32 => "int", 4, 4;
+    Vec2 => "vec2", 8, 4;
+    UVec2 => "uvec2", 8, 4;
+    IVec2 => "ivec2", 8, 4;
+    Vec3 => "vec3", 16, 4;
+    Vec4 => "vec4", 16, 4;
+    UVec4 => "uvec4", 16, 4;
+    IVec4 => "ivec4", 16, 4;
+    Quat => "vec4", 16, 4;
+    Mat4 => "mat4", 16, 4;
+}
+
+/// Its columns are `vec3`s, padded to 16 bytes in std140 and std430 and tightly packed like in
+/// Rust in a scalar block.
+impl GlslLayout for Mat3 {
+    const STD140: (usize, usize) = (16, 48);
+    const STD430: (usize, usize) = (16, 48);
+    const SCALAR: (usize, usize) = (4, 36);
+}
+
+impl GlslType for Mat3 {
+    fn align(layout: BlockLayout) -> usize {
+        layout_of::<Mat3>(layout).0
+    }
+
+    fn size(layout: BlockLayout) -> usize {
+        layout_of::<Mat3>(layout).1
+    }
+
+    fn declare(name: &str) -> String {
+        format!("mat3 {}", name)
+    }
+}
+
+impl<T: GlslLayout, const N: usize> GlslLayout for [T; N] {
+    const STD140: (usize, usize) = BlockLayout::Std140.array(T::STD140, N);
+    const STD430: (usize, usize) = BlockLayout::Std430.array(T::STD430, N);
+    const SCALAR: (usize, usize) = BlockLayout::Scalar.array(T::SCALAR, N);
+}
+
+impl<T: GlslType, const N: usize> GlslType for [T; N] {
+    fn align(layout: BlockLayout) -> usize {
+        layout.array((T::align(layout), T::size(layout)), N).0
+    }
+
+    fn size(layout: BlockLayout) -> usize {
+        layout.array((T::align(layout), T::size(layout)), N).1
+    }
+
+    fn declare(name: &str) -> String {
+        T::declare(&format!("{}[{}]", name, N))
+    }
+}
+
+/// The device address of a buffer holding `T`s, declared as the `<T>Ref` `buffer_reference`
+/// that comes with every [`GlslStruct`].
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct BufferRef<T> {
+    pub address: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T> BufferRef<T> {
+    pub fn new(address: u64) -> Self {
+        Self {
+            address,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for BufferRef<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for BufferRef<T> {}
+
+// a u64 whatever `T` is
+unsafe impl<T: 'static> bytemuck::Zeroable for BufferRef<T> {}
+unsafe impl<T: 'static> bytemuck::Pod for BufferRef<T> {}
+
+impl<T> GlslLayout for BufferRef<T> {
+    const STD140: (usize, usize) = (8, 8);
+    const STD430: (usize, usize) = (8, 8);
+    const SCALAR: (usize, usize) = (8, 8);
+}
+
+impl<T: GlslStruct> GlslType for BufferRef<T> {
+    fn align(layout: BlockLayout) -> usize {
+        layout_of::<Self>(layout).0
+    }
+
+    fn size(layout: BlockLayout) -> usize {
+        layout_of::<Self>(layout).1
+    }
+
+    fn declare(name: &str) -> String {
+        format!("{}Ref {}", T::NAME, name)
+    }
+}
+
+/// A field of a [`GlslStruct`].
+#[derive(Clone, Debug)]
+pub struct GlslField {
+    pub declaration: String,
+    /// Offset and size of the Rust field.
+    pub offset: usize,
+    pub size: usize,
+    glsl_layout: fn(BlockLayout) -> (usize, usize),
+}
+
+impl GlslField {
+    /// Alignment of the GLSL field in a block with `layout`.
+    pub fn align(&self, layout: BlockLayout) -> usize {
+        (self.glsl_layout)(layout).0
+    }
+
+    /// Size of the GLSL field in a block with `layout`.
+    pub fn glsl_size(&self, layout: BlockLayout) -> usize {
+        (self.glsl_layout)(layout).1
+    }
+}
+
+fn glsl_layout<F: GlslType>(layout: BlockLayout) -> (usize, usize) {
+    (F::align(layout), F::size(layout))
+}
+
+/// A struct that is shared with shaders through a device address, with its fields in the order
+/// of the GLSL struct. Implement it with [`glsl_struct!`](crate::glsl_struct), which generates
+/// the GLSL declaration from the Rust one so they can't drift apart.
+pub trait GlslStruct: bytemuck::Pod + GlslType {
+    const NAME: &'static str;
+
+    fn fields() -> Vec<GlslField>;
+
+    /// The GLSL struct followed by `<NAME>Ref`, a `buffer_reference` to an array of them
+    /// declared with `layout`.
+    fn glsl_declaration(layout: BlockLayout) -> String {
+        let mut out = format!("struct {} {{\n", Self::NAME);
+        for field in Self::fields() {
+            out.push_str(&format!("    {};\n", field.declaration));
+        }
+        out.push_str("};\n\n");
+        out.push_str(&format!(
+            "layout (buffer_reference, {1}) buffer {0}Ref {{\n    {0} values[];\n}};\n",
+            Self::NAME,
+            layout.qualifier()
+        ));
+        out
+    }
+}
+
+/// Alignment and size of `T` in a block with `layout`, with the fields where GLSL puts them.
+pub fn struct_layout<T: GlslStruct>(layout: BlockLayout) -> (usize, usize) {
+    let members = T::fields()
+        .iter()
+        .map(|field| (field.align(layout), field.glsl_size(layout)))
+        .collect::<Vec<_>>();
+    layout.struct_layout(&members)
+}
+
+/// Where GLSL puts the fields in a block with `layout` differs from where Rust put them, which
+/// happens with `repr(C)` structs that leave out the padding std430 adds.
+pub fn check_layout<T: GlslStruct>(layout: BlockLayout) -> Result<(), String> {
+    let fields = T::fields();
+    let mut offset: usize = 0;
+    for field in fields.iter() {
+        offset = offset.next_multiple_of(field.align(layout));
+        if field.offset != offset {
+            return Err(format!(
+                "{} is at offset {} in Rust, {} puts it at {}",
+                field.declaration,
+                field.offset,
+                layout.qualifier(),
+                offset
+            ));
+        }
+        if field.size != field.glsl_size(layout) {
+            return Err(format!(
+                "{} takes {} bytes in Rust and {} bytes in {}",
+                field.declaration,
+                field.size,
+                field.glsl_size(layout),
+                layout.qualifier()
+            ));
+        }
+        offset += field.size;
+    }
+    let size = struct_layout::<T>(layout).1;
+    if std::mem::size_of::<T>() != size {
+        return Err(format!(
+            "it's {} bytes in Rust and {} bytes in {}",
+            std::mem::size_of::<T>(),
+            size,
+            layout.qualifier()
+        ));
+    }
+    Ok(())
+}
+
+/// Adds the GLSL declaration of `T` as `#include <structs/<NAME>.glsl>`, panics when its layout
+/// doesn't match `layout`. Structs it refers to through a [`BufferRef`] have to be included
+/// before.
+pub fn register<T: GlslStruct>(layout: BlockLayout) {
+    if let Err(message) = check_layout::<T>(layout) {
+        panic!("{} doesn't match its GLSL struct: {}", T::NAME, message);
+    }
+    let mut extensions = "#extension GL_EXT_buffer_reference2 : require\n".to_string();
+    if layout == BlockLayout::Scalar {
+        extensions.push_str("#extension GL_EXT_scalar_block_layout : require\n");
+    }
+    let guard = format!("STRUCTS_{}_GLSL", T::NAME.to_uppercase());
+    shaders::add_include(
+        &format!("structs/{}.glsl", T::NAME),
+        format!(
+            "#ifndef {0}\n#define {0}\n{1}\n{2}#endif\n",
+            guard,
+            extensions,
+            T::glsl_declaration(layout)
+        ),
+    );
+}
+
+/// The field `field` returns, used by [`glsl_struct!`](crate::glsl_struct).
+pub fn glsl_field<T, F: GlslType>(
+    name: &str,
+    offset: usize,
+    _field: impl Fn(&T) -> &F,
+) -> GlslField {
+    GlslField {
+        declaration: F::declare(name),
+        offset,
+        size: std::mem::size_of::<F>(),
+        glsl_layout: glsl_layout::<F>,
+    }
+}
+
+/// Implements [`GlslStruct`] for a `repr(C)` struct by listing its fields in declaration order,
+/// the GLSL types follow from the Rust ones.
+///
+/// ```ignore
+/// glsl_struct!(Particle { position, velocity, age });
+/// ```
+#[macro_export]
+macro_rules! glsl_struct {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        impl $crate::render::glsl_struct::GlslStruct for $ty {
+            const NAME: &'static str = stringify!($ty);
+
+            fn fields() -> Vec<$crate::render::glsl_struct::GlslField> {
+                let zeroed = <$ty as bytemuck::Zeroable>::zeroed();
+                vec![$($crate::render::glsl_struct::glsl_field(
+                    stringify!($field),
+                    bytemuck::offset_of!(zeroed, $ty, $field),
+                    |value: &$ty| &value.$field,
+                )),*]
+            }
+        }
+
+        impl $crate::render::glsl_struct::GlslType for $ty {
+            fn align(layout: $crate::render::glsl_struct::BlockLayout) -> usize {
+                $crate::render::glsl_struct::struct_layout::<$ty>(layout).0
+            }
+
+            fn size(layout: $crate::render::glsl_struct::BlockLayout) -> usize {
+                $crate::render::glsl_struct::struct_layout::<$ty>(layout).1
+            }
+
+            fn declare(name: &str) -> String {
+                format!("{} {}", stringify!($ty), name)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::{Mat3, Mat4, Vec2, Vec3, Vec4};
+
+    use super::{check_fields, check_layout, BlockLayout, BufferRef, GlslLayout, GlslStruct};
+
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct Particle {
+        position: Vec4,
+        velocity: Vec2,
+        age: f32,
+        size: f32,
+    }
+    glsl_struct!(Particle {
+        position,
+        velocity,
+        age,
+        size
+    });
+
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct Emitter {
+        count: u32,
+        _padding: u32,
+        particles: BufferRef<Particle>,
+    }
+    glsl_struct!(Emitter {
+        count,
+        _padding,
+        particles
+    });
+
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct Packed {
+        flags: u32,
+        offset: [f32; 2],
+    }
+    glsl_struct!(Packed { flags, offset });
+
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct Vertex {
+        position: Vec3,
+        normal: Vec3,
+        uv: Vec2,
+    }
+    glsl_struct!(Vertex {
+        position,
+        normal,
+        uv
+    });
+
+    #[repr(C)]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct Transform {
+        rotation: Mat3,
+        scale: f32,
+    }
+    glsl_struct!(Transform { rotation, scale });
+
+    #[test]
+    fn test_glsl_struct() {
+        assert_eq!(
+            Particle::glsl_declaration(BlockLayout::Std430),
+            "struct Particle {\n    vec4 position;\n    vec2 velocity;\n    float age;\n    float size;\n};\n\n\
+             layout (buffer_reference, std430) buffer ParticleRef {\n    Particle values[];\n};\n"
+        );
+        assert!(check_layout::<Particle>(BlockLayout::Std430).is_ok());
+        assert_eq!(Emitter::fields()[2].declaration, "ParticleRef particles");
+        assert!(check_layout::<Emitter>(BlockLayout::Std430).is_ok());
+        // unlike std140, std430 doesn't pad arrays of scalars to 16 bytes
+        assert_eq!(Packed::fields()[1].declaration, "float offset[2]");
+        assert!(check_layout::<Packed>(BlockLayout::Std430).is_ok());
+    }
+
+    #[test]
+    fn test_scalar_layout() {
+        // std430 aligns the vec3s to 16 bytes, the scalar layout packs them like Rust does
+        assert!(check_layout::<Vertex>(BlockLayout::Std430).is_err());
+        assert!(check_layout::<Vertex>(BlockLayout::Scalar).is_ok());
+        assert!(check_layout::<Particle>(BlockLayout::Scalar).is_ok());
+        // a mat3 takes 48 bytes in std430, and 36 without the column padding in a scalar block
+        assert!(check_layout::<Transform>(BlockLayout::Std430).is_err());
+        assert!(check_layout::<Transform>(BlockLayout::Scalar).is_ok());
+        assert!(Vertex::glsl_declaration(BlockLayout::Scalar)
+            .contains("layout (buffer_reference, scalar) buffer VertexRef"));
+    }
+
+    #[test]
+    fn test_std140_layout() {
+        // std140 pads every array element to a vec4, std430 only arrays of vec3s
+        assert_eq!(<[f32; 4]>::STD140, (16, 64));
+        assert_eq!(<[f32; 4]>::STD430, (4, 16));
+        assert_eq!(<[Vec3; 2]>::STD430, (16, 32));
+        assert_eq!(<[Vec4; 2]>::STD140, (16, 32));
+        assert_eq!(
+            BlockLayout::Std140.struct_layout(&[(16, 12), (4, 4), (16, 64)]),
+            (16, 80)
+        );
+        // std140 also rounds the size of structs up to a vec4
+        assert_eq!(BlockLayout::Std140.struct_layout(&[(4, 4)]), (16, 16));
+        assert_eq!(BlockLayout::Std430.struct_layout(&[(4, 4)]), (4, 4));
+        assert!(check_layout::<Particle>(BlockLayout::Std140).is_ok());
+        assert!(check_layout::<Packed>(BlockLayout::Std140).is_err());
+    }
+
+    #[test]
+    fn test_check_fields() {
+        // a float fills the padding after a vec3
+        check_fields(&[
+            (0, 64, Mat4::STD140.0, Mat4::STD140.1, "view_proj"),
+            (64, 12, Vec3::STD140.0, Vec3::STD140.1, "sun"),
+            (76, 4, f32::STD140.0, f32::STD140.1, "time"),
+        ]);
+        let misplaced = std::panic::catch_unwind(|| {
+            check_fields(&[
+                (0, 4, f32::STD140.0, f32::STD140.1, "time"),
+                (4, 12, Vec3::STD140.0, Vec3::STD140.1, "sun"),
+            ])
+        });
+        assert!(misplaced.is_err());
+    }
+}
@@ -1,496 +1,627 @@
-// use std::{collections::HashMap, path::Path};
-
-// use ash::vk::{CullModeFlags, PrimitiveTopology};
-// use bevy::{
-//     asset::{AssetIoError, AssetLoader, AssetPath, LoadContext, LoadedAsset},
-//     math::vec3,
-//     prelude::*,
-//     utils::{BoxedFuture, HashSet},
-// };
-// use gltf::{
-//     accessor::Iter,
-//     mesh::{util::ReadIndices, Mode},
-//     texture::{MagFilter, MinFilter, WrappingMode},
-//     Node, Primitive,
-// };
-// use thiserror::Error;
-
-// use super::{image::Image, material::AlphaMode, mesh::Mesh};
-
-// /// An error that occurs when loading a glTF file.
-// #[derive(Error, Debug)]
-// pub enum GltfError {
-//     #[error("unsupported primitive mode")]
-//     UnsupportedPrimitive { mode: Mode },
-//     #[error("invalid glTF file: {0}")]
-//     Gltf(#[from] gltf::Error),
-//     #[error("binary blob is missing")]
-//     MissingBlob,
-//     #[error("failed to decode base64 mesh data")]
-//     Base64Decode(#[from] base64::DecodeError),
-//     #[error("unsupported buffer format")]
-//     BufferFormatUnsupported,
-//     #[error("invalid image mime type: {0}")]
-//     InvalidImageMimeType(String),
-//     // #[error("You may need to add the feature for the file format: {0}")]
-//     // ImageError(#[from] TextureError),
-//     #[error("failed to load an asset path: {0}")]
-//     AssetIoError(#[from] AssetIoError),
-//     #[error("Missing sampler for animation {0}")]
-//     MissingAnimationSampler(usize),
-//     // #[error("failed to generate tangents: {0}")]
-//     // GenerateTangentsError(#[from] bevy_render::mesh::GenerateTangentsError),
-//     // #[error("failed to generate morph targets: {0}")]
-//     // MorphTarget(#[from] bevy_render::mesh::morph::MorphBuildError),
-// }
-
-// /// Loads glTF files with all of their data as their corresponding bevy representations.
-// pub struct GltfLoader;
-
-// impl AssetLoader for GltfLoader {
-//     fn load<'a>(
-//         &'a self,
-//         bytes: &'a [u8],
-//         load_context: &'a mut LoadContext,
-//     ) -> BoxedFuture<'a, anyhow::Result<()>> {
-//         Box::pin(async move { Ok(load_gltf(bytes, load_context, self).await?) })
-//     }
-
-//     fn extensions(&self) -> &[&str] {
-//         &["gltf", "glb"]
-//     }
-// }
-
-// /// Loads an entire glTF file.
-// async fn load_gltf<'a, 'b>(
-//     bytes: &'a [u8],
-//     load_context: &'a mut LoadContext<'b>,
-//     loader: &GltfLoader,
-// ) -> Result<(), GltfError> {
-//     let gltf = gltf::Gltf::from_slice(bytes)?;
-//     let buffer_data = load_buffers(&gltf, load_context, load_context.path()).await?;
-
-//     let mut materials = vec![];
-//     let mut named_materials: HashMap<String, Handle<crate::Material>> = HashMap::default();
-//     let mut linear_textures = HashSet::default();
-//     for material in gltf.materials() {
-//         let handle = load_material(&material, load_context);
-//         if let Some(name) = material.name() {
-//             named_materials.insert(name.to_string(), handle.clone());
-//         }
-//         materials.push(handle);
-//         if let Some(texture) = material.normal_texture() {
-//             linear_textures.insert(texture.texture().index());
-//         }
-//         if let Some(texture) = material.occlusion_texture() {
-//             linear_textures.insert(texture.texture().index());
-//         }
-//         if let Some(texture) = material
-//             .pbr_metallic_roughness()
-//             .metallic_roughness_texture()
-//         {
-//             linear_textures.insert(texture.texture().index());
-//         }
-//     }
-
-//     let mut meshes = vec![];
-//     let mut named_meshes = HashMap::default();
-//     for gltf_mesh in gltf.meshes() {
-//         let mut primitives = vec![];
-//         for primitive in gltf_mesh.primitives() {
-//             let primitive_label = primitive_label(&gltf_mesh, &primitive);
-//             let primitive_topology = get_primitive_topology(primitive.mode())?;
-
-//             let mut mesh = Mesh {
-//                 primitive_topology,
-//                 indices: vec![],
-//                 vertices: vec![],
-//             };
-
-//             // Read vertex attributes
-//             for (semantic, accessor) in primitive.attributes() {
-//                 let view = accessor.view().unwrap();
-//                 let reader = accessor.reader(|buffer| Some(&buffer.view().unwrap().data()));
-//                 let count = accessor.count();
-
-//                 // Read data based on attribute semantic
-//                 match semantic {
-//                     gltf::Semantic::Positions => {
-//                         if let Some(gltf::accessor::ReadVertices::F32(iter)) =
-//                             reader.read_vertices()
-//                         {
-//                             for vertex in iter.take(count) {
-//                                 let position: [f32; 3] = vertex.into();
-//                                 mesh.vertices.push(Vertex {
-//                                     position,
-//                                     ..Default::default()
-//                                 });
-//                             }
-//                         }
-//                     }
-//                     gltf::Semantic::Normals => {
-//                         if let Some(gltf::accessor::ReadVertices::F32(iter)) =
-//                             reader.read_vertices()
-//                         {
-//                             for (vertex, normal) in mesh.vertices.iter_mut().zip(iter.take(count)) {
-//                                 vertex.normal = normal.into();
-//                             }
-//                         }
-//                     }
-//                     gltf::Semantic::TexCoords(_) => {
-//                         if let Some(gltf::accessor::ReadVertices::F32(iter)) =
-//                             reader.read_vertices()
-//                         {
-//                             for (vertex, uv) in mesh.vertices.iter_mut().zip(iter.take(count)) {
-//                                 vertex.uv = uv.into();
-//                             }
-//                         }
-//                     }
-//                     gltf::Semantic::Tangents => {
-//                         if let Some(gltf::accessor::ReadVertices::F32(iter)) =
-//                             reader.read_vertices()
-//                         {
-//                             for (vertex, tangent) in mesh.vertices.iter_mut().zip(iter.take(count))
-//                             {
-//                                 vertex.tangent = tangent.into();
-//                             }
-//                         }
-//                     }
-//                     gltf::Semantic::Colors(_) => {
-//                         if let Some(gltf::accessor::ReadVertices::F32(iter)) =
-//                             reader.read_vertices()
-//                         {
-//                             for (vertex, color) in mesh.vertices.iter_mut().zip(iter.take(count)) {
-//                                 vertex.color = color.into();
-//                             }
-//                         }
-//                     }
-//                     _ => {}
-//                 }
-//             }
-
-//             // Read vertex indices
-//             let reader = primitive.reader(|buffer| Some(buffer_data[buffer.index()].as_slice()));
-//             if let Some(indices) = reader.read_indices() {
-//                 mesh.indices = match indices {
-//                     ReadIndices::U32(iter) => iter.collect(),
-//                     ReadIndices::U16(iter) => iter.map(|i| i as u32).collect(),
-//                     ReadIndices::U8(iter) => iter.map(|i| i as u32).collect(),
-//                 };
-//             };
-
-//             {
-//                 let morph_target_reader = reader.read_morph_targets();
-//                 if morph_target_reader.len() != 0 {
-//                     let morph_targets_label = morph_targets_label(&gltf_mesh, &primitive);
-//                     let morph_target_image = MorphTargetImage::new(
-//                         morph_target_reader.map(PrimitiveMorphAttributesIter),
-//                         mesh.count_vertices(),
-//                     )?;
-//                     let handle = load_context.set_labeled_asset(
-//                         &morph_targets_label,
-//                         LoadedAsset::new(morph_target_image.0),
-//                     );
-
-//                     mesh.set_morph_targets(handle);
-//                     let extras = gltf_mesh.extras().as_ref();
-//                     if let Option::<MorphTargetNames>::Some(names) =
-//                         extras.and_then(|extras| serde_json::from_str(extras.get()).ok())
-//                     {
-//                         mesh.set_morph_target_names(names.target_names);
-//                     }
-//                 }
-//             }
-
-//             if mesh.attribute(Mesh::ATTRIBUTE_NORMAL).is_none()
-//                 && matches!(mesh.primitive_topology, PrimitiveTopology::TRIANGLE_LIST)
-//             {
-//                 let vertex_count_before = mesh.count_vertices();
-//                 mesh.duplicate_vertices();
-//                 mesh.compute_flat_normals();
-//                 let vertex_count_after = mesh.count_vertices();
-
-//                 if vertex_count_before != vertex_count_after {
-//                     bevy_log::debug!("Missing vertex normals in indexed geometry, computing them as flat. Vertex count increased from {} to {}", vertex_count_before, vertex_count_after);
-//                 } else {
-//                     bevy_log::debug!(
-//                         "Missing vertex normals in indexed geometry, computing them as flat."
-//                     );
-//                 }
-//             }
-
-//             if let Some(vertex_attribute) = reader
-//                 .read_tangents()
-//                 .map(|v| VertexAttributeValues::Float32x4(v.collect()))
-//             {
-//                 mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, vertex_attribute);
-//             } else if mesh.attribute(Mesh::ATTRIBUTE_NORMAL).is_some()
-//                 && primitive.material().normal_texture().is_some()
-//             {
-//                 bevy_log::debug!(
-//                     "Missing vertex tangents, computing them using the mikktspace algorithm"
-//                 );
-//                 if let Err(err) = mesh.generate_tangents() {
-//                     bevy_log::warn!(
-//                         "Failed to generate vertex tangents using the mikktspace algorithm: {:?}",
-//                         err
-//                     );
-//                 }
-//             }
-
-//             let mesh = load_context.set_labeled_asset(&primitive_label, LoadedAsset::new(mesh));
-//             primitives.push(super::GltfPrimitive {
-//                 mesh,
-//                 material: primitive
-//                     .material()
-//                     .index()
-//                     .and_then(|i| materials.get(i).cloned()),
-//                 extras: get_gltf_extras(primitive.extras()),
-//                 material_extras: get_gltf_extras(primitive.material().extras()),
-//             });
-//         }
-
-//         let handle = load_context.set_labeled_asset(
-//             &mesh_label(&gltf_mesh),
-//             LoadedAsset::new(super::GltfMesh {
-//                 primitives,
-//                 extras: get_gltf_extras(gltf_mesh.extras()),
-//             }),
-//         );
-//         if let Some(name) = gltf_mesh.name() {
-//             named_meshes.insert(name.to_string(), handle.clone());
-//         }
-//         meshes.push(handle);
-//     }
-
-//     Ok(())
-// }
-
-// struct DataUri<'a> {
-//     mime_type: &'a str,
-//     base64: bool,
-//     data: &'a str,
-// }
-// fn split_once(input: &str, delimiter: char) -> Option<(&str, &str)> {
-//     let mut iter = input.splitn(2, delimiter);
-//     Some((iter.next()?, iter.next()?))
-// }
-
-// impl<'a> DataUri<'a> {
-//     fn parse(uri: &'a str) -> Result<DataUri<'a>, ()> {
-//         let uri = uri.strip_prefix("data:").ok_or(())?;
-//         let (mime_type, data) = split_once(uri, ',').ok_or(())?;
-
-//         let (mime_type, base64) = match mime_type.strip_suffix(";base64") {
-//             Some(mime_type) => (mime_type, true),
-//             None => (mime_type, false),
-//         };
-
-//         Ok(DataUri {
-//             mime_type,
-//             base64,
-//             data,
-//         })
-//     }
-
-//     fn decode(&self) -> Result<Vec<u8>, base64::DecodeError> {
-//         if self.base64 {
-//             base64::decode(self.data)
-//         } else {
-//             Ok(self.data.as_bytes().to_owned())
-//         }
-//     }
-// }
-
-// /// Loads the raw glTF buffer data for a specific glTF file.
-// async fn load_buffers(
-//     gltf: &gltf::Gltf,
-//     load_context: &LoadContext<'_>,
-//     asset_path: &Path,
-// ) -> Result<Vec<Vec<u8>>, GltfError> {
-//     const VALID_MIME_TYPES: &[&str] = &["application/octet-stream", "application/gltf-buffer"];
-
-//     let mut buffer_data = Vec::new();
-//     for buffer in gltf.buffers() {
-//         match buffer.source() {
-//             gltf::buffer::Source::Uri(uri) => {
-//                 let uri = percent_encoding::percent_decode_str(uri)
-//                     .decode_utf8()
-//                     .unwrap();
-//                 let uri = uri.as_ref();
-//                 let buffer_bytes = match DataUri::parse(uri) {
-//                     Ok(data_uri) if VALID_MIME_TYPES.contains(&data_uri.mime_type) => {
-//                         data_uri.decode()?
-//                     }
-//                     Ok(_) => return Err(GltfError::BufferFormatUnsupported),
-//                     Err(()) => {
-//                         // TODO: Remove this and add dep
-//                         let buffer_path = asset_path.parent().unwrap().join(uri);
-//                         load_context.read_asset_bytes(buffer_path).await?
-//                     }
-//                 };
-//                 buffer_data.push(buffer_bytes);
-//             }
-//             gltf::buffer::Source::Bin => {
-//                 if let Some(blob) = gltf.blob.as_deref() {
-//                     buffer_data.push(blob.into());
-//                 } else {
-//                     return Err(GltfError::MissingBlob);
-//                 }
-//             }
-//         }
-//     }
-
-//     Ok(buffer_data)
-// }
-
-// /// Loads a glTF material as a bevy [`StandardMaterial`] and returns it.
-// fn load_material(
-//     material: &gltf::Material,
-//     load_context: &mut LoadContext,
-// ) -> Handle<crate::Material> {
-//     let material_label = material_label(material);
-
-//     let pbr = material.pbr_metallic_roughness();
-
-//     let color = pbr.base_color_factor();
-//     let base_color_texture = pbr.base_color_texture().map(|info| {
-//         // TODO: handle info.tex_coord() (the *set* index for the right texcoords)
-//         let label = texture_label(&info.texture());
-//         let path = AssetPath::new_ref(load_context.path(), Some(&label));
-//         load_context.get_handle(path)
-//     });
-
-//     let normal_map_texture: Option<Handle<Image>> =
-//         material.normal_texture().map(|normal_texture| {
-//             // TODO: handle normal_texture.scale
-//             // TODO: handle normal_texture.tex_coord() (the *set* index for the right texcoords)
-//             let label = texture_label(&normal_texture.texture());
-//             let path = AssetPath::new_ref(load_context.path(), Some(&label));
-//             load_context.get_handle(path)
-//         });
-
-//     let metallic_roughness_texture = pbr.metallic_roughness_texture().map(|info| {
-//         // TODO: handle info.tex_coord() (the *set* index for the right texcoords)
-//         let label = texture_label(&info.texture());
-//         let path = AssetPath::new_ref(load_context.path(), Some(&label));
-//         load_context.get_handle(path)
-//     });
-
-//     let occlusion_texture = material.occlusion_texture().map(|occlusion_texture| {
-//         // TODO: handle occlusion_texture.tex_coord() (the *set* index for the right texcoords)
-//         // TODO: handle occlusion_texture.strength() (a scalar multiplier for occlusion strength)
-//         let label = texture_label(&occlusion_texture.texture());
-//         let path = AssetPath::new_ref(load_context.path(), Some(&label));
-//         load_context.get_handle(path)
-//     });
-
-//     let emissive = material.emissive_factor();
-//     let emissive_texture = material.emissive_texture().map(|info| {
-//         // TODO: handle occlusion_texture.tex_coord() (the *set* index for the right texcoords)
-//         // TODO: handle occlusion_texture.strength() (a scalar multiplier for occlusion strength)
-//         let label = texture_label(&info.texture());
-//         let path = AssetPath::new_ref(load_context.path(), Some(&label));
-//         load_context.get_handle(path)
-//     });
-
-//     load_context.set_labeled_asset(
-//         &material_label,
-//         LoadedAsset::new(crate::Material {
-//             base_color: vec3(color[0], color[1], color[2]),
-//             base_color_texture,
-//             perceptual_roughness: pbr.roughness_factor(),
-//             metallic: pbr.metallic_factor(),
-//             metallic_roughness_texture,
-//             normal_map_texture,
-//             double_sided: material.double_sided(),
-//             cull_mode: if material.double_sided() {
-//                 None
-//             } else {
-//                 Some(CullModeFlags::BACK)
-//             },
-//             occlusion_texture,
-//             emissive: vec3(emissive[0], emissive[1], emissive[2]),
-//             emissive_texture,
-//             unlit: material.unlit(),
-//             alpha_mode: alpha_mode(material),
-//             ..Default::default()
-//         }),
-//     )
-// }
-
-// /// Returns the label for the `mesh`.
-// fn mesh_label(mesh: &gltf::Mesh) -> String {
-//     format!("Mesh{}", mesh.index())
-// }
-
-// /// Returns the label for the `mesh` and `primitive`.
-// fn primitive_label(mesh: &gltf::Mesh, primitive: &Primitive) -> String {
-//     format!("Mesh{}/Primitive{}", mesh.index(), primitive.index())
-// }
-
-// fn primitive_name(mesh: &gltf::Mesh, primitive: &Primitive) -> String {
-//     let mesh_name = mesh.name().unwrap_or("Mesh");
-//     if mesh.primitives().len() > 1 {
-//         format!("{}.{}", mesh_name, primitive.index())
-//     } else {
-//         mesh_name.to_string()
-//     }
-// }
-
-// /// Returns the label for the morph target of `primitive`.
-// fn morph_targets_label(mesh: &gltf::Mesh, primitive: &Primitive) -> String {
-//     format!(
-//         "Mesh{}/Primitive{}/MorphTargets",
-//         mesh.index(),
-//         primitive.index()
-//     )
-// }
-
-// /// Returns the label for the `material`.
-// fn material_label(material: &gltf::Material) -> String {
-//     if let Some(index) = material.index() {
-//         format!("Material{index}")
-//     } else {
-//         "MaterialDefault".to_string()
-//     }
-// }
-
-// /// Returns the label for the `texture`.
-// fn texture_label(texture: &gltf::Texture) -> String {
-//     format!("Texture{}", texture.index())
-// }
-
-// /// Returns the label for the `node`.
-// fn node_label(node: &gltf::Node) -> String {
-//     format!("Node{}", node.index())
-// }
-
-// /// Returns the label for the `scene`.
-// fn scene_label(scene: &gltf::Scene) -> String {
-//     format!("Scene{}", scene.index())
-// }
-
-// fn skin_label(skin: &gltf::Skin) -> String {
-//     format!("Skin{}", skin.index())
-// }
-
-// fn alpha_mode(material: &gltf::Material) -> AlphaMode {
-//     match material.alpha_mode() {
-//         gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
-//         gltf::material::AlphaMode::Mask => AlphaMode::Mask(material.alpha_cutoff().unwrap_or(0.5)),
-//         gltf::material::AlphaMode::Blend => AlphaMode::Blend,
-//     }
-// }
-
-// /// Maps the `primitive_topology` form glTF to `wgpu`.
-// fn get_primitive_topology(mode: Mode) -> Result<PrimitiveTopology, GltfError> {
-//     match mode {
-//         Mode::Points => Ok(PrimitiveTopology::POINT_LIST),
-//         Mode::Lines => Ok(PrimitiveTopology::LINE_LIST),
-//         Mode::LineStrip => Ok(PrimitiveTopology::LINE_STRIP),
-//         Mode::Triangles => Ok(PrimitiveTopology::TRIANGLE_LIST),
-//         Mode::TriangleStrip => Ok(PrimitiveTopology::TRIANGLE_STRIP),
-//         mode => Err(GltfError::UnsupportedPrimitive { mode }),
-//     }
-// }
+use std::{collections::HashSet, mem::size_of, path::Path};
+
+use ash::vk;
+use bevy::{
+    math::{Mat4, Vec3},
+    prelude::info_span,
+};
+use gltf::{image::Format, mesh::Mode};
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::{Buffer, Image},
+    error::Result,
+};
+
+use super::{
+    acceleration_structure::{Blas, BlasGeometry},
+    barrier::{self, Usage},
+    material::MaterialUniform,
+    mesh::Vertex,
+    RenderAllocator, RenderInstance,
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GltfLoadOptions {
+    /// Builds a [`Blas`] per mesh, which requires ray tracing support.
+    pub build_blas: bool,
+}
+
+/// A triangle list with device local buffers, its vertices follow the [`Vertex`] layout.
+#[derive(Debug)]
+pub struct GltfPrimitive {
+    pub vertex_buffer: Buffer,
+    pub index_buffer: Buffer,
+    pub vertex_count: u32,
+    pub index_count: u32,
+    /// Index into [`GltfScene::materials`].
+    pub material: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct GltfMesh {
+    pub primitives: Vec<GltfPrimitive>,
+    /// One geometry per primitive, when requested in the [`GltfLoadOptions`].
+    pub blas: Option<Blas>,
+}
+
+/// A mesh placed in the scene, with the transforms of its parent nodes applied.
+#[derive(Clone, Copy, Debug)]
+pub struct GltfInstance {
+    /// Index into [`GltfScene::meshes`].
+    pub mesh: usize,
+    pub transform: Mat4,
+}
+
+/// Everything in a glTF file uploaded to the GPU and ready to draw. The texture indices of the
+/// materials point into [`GltfScene::textures`], `-1` if a material doesn't use one.
+#[derive(Debug)]
+pub struct GltfScene {
+    pub meshes: Vec<GltfMesh>,
+    pub instances: Vec<GltfInstance>,
+    /// Sampled images with a full mip chain, their views are created.
+    pub textures: Vec<Image>,
+    pub materials: Vec<MaterialUniform>,
+    /// [`GltfScene::materials`] in a device local storage buffer.
+    pub material_buffer: Buffer,
+}
+
+/// Copies recorded into one submission, the staging buffers are freed once it's done.
+#[derive(Default)]
+struct Uploads {
+    staging: Vec<Buffer>,
+    buffers: Vec<(usize, vk::Buffer, u64)>,
+    images: Vec<(usize, vk::Image, vk::Extent3D, u32)>,
+}
+
+impl Uploads {
+    fn stage<T: bytemuck::Pod>(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        data: &[T],
+    ) -> Result<usize> {
+        let mut staging = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(std::mem::size_of_val(data) as u64)
+                .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+        staging.copy_from_slice(data, 0);
+        self.staging.push(staging);
+        Ok(self.staging.len() - 1)
+    }
+
+    fn buffer<T: bytemuck::Pod>(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        data: &[T],
+        usage: vk::BufferUsageFlags,
+    ) -> Result<Buffer> {
+        let size = std::mem::size_of_val(data) as u64;
+        let buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(size)
+                .usage(usage | vk::BufferUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::GpuOnly,
+        )?;
+        let staging = self.stage(render_instance, render_allocator, data)?;
+        self.buffers.push((staging, buffer.buffer, size));
+        Ok(buffer)
+    }
+
+    fn texture(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        data: &gltf::image::Data,
+        format: vk::Format,
+    ) -> Result<Image> {
+        let extent = vk::Extent3D {
+            width: data.width,
+            height: data.height,
+            depth: 1,
+        };
+        let mip_levels = 32 - data.width.max(data.height).leading_zeros();
+        let mut image = Image::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format)
+                .extent(extent)
+                .mip_levels(mip_levels)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(
+                    vk::ImageUsageFlags::SAMPLED
+                        | vk::ImageUsageFlags::TRANSFER_SRC
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                )
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?;
+        let view = unsafe {
+            render_instance.device().create_image_view(
+                &vk::ImageViewCreateInfo::default()
+                    .image(image.image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(format)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: mip_levels,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    }),
+                None,
+            )
+        }?;
+        image.set_view(view);
+
+        let staging = self.stage(render_instance, render_allocator, &to_rgba8(data))?;
+        self.images.push((staging, image.image, extent, mip_levels));
+        Ok(image)
+    }
+
+    /// Copies the buffers and level 0 of the images, then blits the rest of the mip chains.
+    fn record(&self, render_instance: &RenderInstance, command_buffer: vk::CommandBuffer) {
+        let device = render_instance.device();
+        let synchronization2 = &render_instance.0.synchronization2;
+        let level_barrier = |image: vk::Image, level: u32, from: Usage, to: Usage| {
+            let mut barrier = barrier::image_barrier(image, vk::ImageAspectFlags::COLOR, from, to);
+            barrier.subresource_range.base_mip_level = level;
+            barrier.subresource_range.level_count = 1;
+            barrier
+        };
+
+        unsafe {
+            for (staging, buffer, size) in self.buffers.iter() {
+                device.cmd_copy_buffer(
+                    command_buffer,
+                    self.staging[*staging].buffer,
+                    *buffer,
+                    &[vk::BufferCopy::default().size(*size)],
+                );
+            }
+
+            let barriers = self
+                .images
+                .iter()
+                .map(|(_, image, _, _)| {
+                    barrier::image_barrier(
+                        *image,
+                        vk::ImageAspectFlags::COLOR,
+                        Usage::Undefined,
+                        Usage::TransferWrite,
+                    )
+                })
+                .collect::<Vec<_>>();
+            synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&barriers),
+            );
+
+            for (staging, image, extent, mip_levels) in self.images.iter() {
+                device.cmd_copy_buffer_to_image(
+                    command_buffer,
+                    self.staging[*staging].buffer,
+                    *image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[vk::BufferImageCopy::default()
+                        .image_subresource(vk::ImageSubresourceLayers {
+                            aspect_mask: vk::ImageAspectFlags::COLOR,
+                            mip_level: 0,
+                            base_array_layer: 0,
+                            layer_count: 1,
+                        })
+                        .image_extent(*extent)],
+                );
+
+                for level in 1..*mip_levels {
+                    synchronization2.cmd_pipeline_barrier2(
+                        command_buffer,
+                        &vk::DependencyInfo::default().image_memory_barriers(&[level_barrier(
+                            *image,
+                            level - 1,
+                            Usage::TransferWrite,
+                            Usage::TransferRead,
+                        )]),
+                    );
+                    let size = |level: u32| {
+                        [
+                            vk::Offset3D::default(),
+                            vk::Offset3D {
+                                x: (extent.width >> level).max(1) as i32,
+                                y: (extent.height >> level).max(1) as i32,
+                                z: 1,
+                            },
+                        ]
+                    };
+                    let subresource = |level: u32| vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: level,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    };
+                    device.cmd_blit_image(
+                        command_buffer,
+                        *image,
+                        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                        *image,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        &[vk::ImageBlit::default()
+                            .src_subresource(subresource(level - 1))
+                            .src_offsets(size(level - 1))
+                            .dst_subresource(subresource(level))
+                            .dst_offsets(size(level))],
+                        vk::Filter::LINEAR,
+                    );
+                }
+            }
+
+            // all levels but the last one were blitted from
+            let mut barriers = vec![];
+            for (_, image, _, mip_levels) in self.images.iter() {
+                let last = mip_levels - 1;
+                if last > 0 {
+                    let mut barrier = barrier::image_barrier(
+                        *image,
+                        vk::ImageAspectFlags::COLOR,
+                        Usage::TransferRead,
+                        Usage::FragmentSampled,
+                    );
+                    barrier.subresource_range.level_count = last;
+                    barriers.push(barrier);
+                }
+                barriers.push(level_barrier(
+                    *image,
+                    last,
+                    Usage::TransferWrite,
+                    Usage::FragmentSampled,
+                ));
+            }
+            let memory_barriers = [barrier::memory_barrier(
+                Usage::TransferWrite,
+                Usage::AccelerationStructureBuildInput,
+            )];
+            synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default()
+                    .memory_barriers(&memory_barriers)
+                    .image_memory_barriers(&barriers),
+            );
+        }
+    }
+
+    fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        for mut staging in self.staging.drain(..) {
+            staging.destroy(render_instance.device(), &mut render_allocator.allocator());
+        }
+    }
+}
+
+/// Converts images to 8-bit RGBA, the only texture format the loader creates. 16-bit images
+/// lose their low bits and float images are clamped to `0..=1`.
+fn to_rgba8(data: &gltf::image::Data) -> Vec<u8> {
+    let channels = match data.format {
+        Format::R8 | Format::R16 => 1,
+        Format::R8G8 | Format::R16G16 => 2,
+        Format::R8G8B8 | Format::R16G16B16 | Format::R32G32B32FLOAT => 3,
+        Format::R8G8B8A8 | Format::R16G16B16A16 | Format::R32G32B32A32FLOAT => 4,
+    };
+    let samples = match data.format {
+        Format::R8 | Format::R8G8 | Format::R8G8B8 | Format::R8G8B8A8 => data.pixels.clone(),
+        Format::R16 | Format::R16G16 | Format::R16G16B16 | Format::R16G16B16A16 => data
+            .pixels
+            .chunks_exact(2)
+            .map(|bytes| (u16::from_ne_bytes([bytes[0], bytes[1]]) >> 8) as u8)
+            .collect(),
+        Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => data
+            .pixels
+            .chunks_exact(4)
+            .map(|bytes| {
+                let value = f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                (value.clamp(0.0, 1.0) * 255.0).round() as u8
+            })
+            .collect(),
+    };
+    if channels == 4 {
+        return samples;
+    }
+    samples
+        .chunks_exact(channels)
+        .flat_map(|pixel| match *pixel {
+            [r] => [r, 0, 0, 255],
+            [r, g] => [r, g, 0, 255],
+            [r, g, b] => [r, g, b, 255],
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+/// The vertices and indices of `primitive`, `None` if it has no positions to draw.
+fn read_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+) -> Option<(Vec<Vertex>, Vec<u32>)> {
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    let mut vertices = reader
+        .read_positions()?
+        .map(|position| Vertex {
+            position,
+            color: [1.0; 4],
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    if let Some(normals) = reader.read_normals() {
+        for (vertex, normal) in vertices.iter_mut().zip(normals) {
+            vertex.normal = normal;
+        }
+    }
+    if let Some(uvs) = reader.read_tex_coords(0) {
+        for (vertex, uv) in vertices.iter_mut().zip(uvs.into_f32()) {
+            vertex.uv = uv;
+        }
+    }
+    if let Some(tangents) = reader.read_tangents() {
+        for (vertex, tangent) in vertices.iter_mut().zip(tangents) {
+            vertex.tangent = [tangent[0], tangent[1], tangent[2]];
+        }
+    }
+    if let Some(colors) = reader.read_colors(0) {
+        for (vertex, color) in vertices.iter_mut().zip(colors.into_rgba_f32()) {
+            vertex.color = color;
+        }
+    }
+    let indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+    Some((vertices, indices))
+}
+
+fn collect_instances(node: gltf::Node, parent: Mat4, instances: &mut Vec<GltfInstance>) {
+    let transform = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+    if let Some(mesh) = node.mesh() {
+        instances.push(GltfInstance {
+            mesh: mesh.index(),
+            transform,
+        });
+    }
+    for child in node.children() {
+        collect_instances(child, transform, instances);
+    }
+}
+
+/// Loads a `.gltf` or `.glb` file and uploads it, blocking until the GPU is done. Only
+/// triangle lists of the default scene are loaded, other primitive modes and primitives
+/// without positions are skipped.
+pub fn load_scene(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    path: impl AsRef<Path>,
+    options: GltfLoadOptions,
+) -> Result<GltfScene> {
+    let (document, buffers, images) = gltf::import(path)?;
+    let _ = info_span!("Uploading glTF scene").entered();
+    let mut uploads = Uploads::default();
+
+    // color textures are sampled as sRGB, everything else holds linear data
+    let srgb_textures = document
+        .materials()
+        .flat_map(|material| {
+            [
+                material.pbr_metallic_roughness().base_color_texture(),
+                material.emissive_texture(),
+            ]
+        })
+        .flatten()
+        .map(|info| info.texture().index())
+        .collect::<HashSet<_>>();
+    let textures = document
+        .textures()
+        .map(|texture| {
+            let format = if srgb_textures.contains(&texture.index()) {
+                vk::Format::R8G8B8A8_SRGB
+            } else {
+                vk::Format::R8G8B8A8_UNORM
+            };
+            uploads.texture(
+                render_instance,
+                render_allocator,
+                &images[texture.source().index()],
+                format,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let texture_index =
+        |info: Option<gltf::texture::Texture>| info.map_or(-1, |texture| texture.index() as i32);
+    let materials = document
+        .materials()
+        .map(|material| {
+            let pbr = material.pbr_metallic_roughness();
+            let [r, g, b, _] = pbr.base_color_factor();
+            MaterialUniform {
+                base_color: Vec3::new(r, g, b),
+                base_color_texture_index: texture_index(
+                    pbr.base_color_texture().map(|info| info.texture()),
+                ),
+                emissive: Vec3::from(material.emissive_factor()),
+                emissive_texture_index: texture_index(
+                    material.emissive_texture().map(|info| info.texture()),
+                ),
+                perceptual_roughness: pbr.roughness_factor(),
+                metallic: pbr.metallic_factor(),
+                metallic_roughness_texture_index: texture_index(
+                    pbr.metallic_roughness_texture().map(|info| info.texture()),
+                ),
+                // glTF has no reflectance, this is the default of 4%
+                reflectance: 0.5,
+                normal_map_texture_index: texture_index(
+                    material.normal_texture().map(|info| info.texture()),
+                ),
+                flip_normal_map_y: 0,
+                occlusion_texture_index: texture_index(
+                    material.occlusion_texture().map(|info| info.texture()),
+                ),
+                depth_bias: 0.0,
+            }
+        })
+        .collect::<Vec<_>>();
+    // a storage buffer can't be empty
+    let default_material = [MaterialUniform::default()];
+    let material_buffer = uploads.buffer(
+        render_instance,
+        render_allocator,
+        if materials.is_empty() {
+            &default_material
+        } else {
+            &materials
+        },
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+    )?;
+
+    let mut vertex_usage =
+        vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER;
+    let mut index_usage = vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER;
+    if options.build_blas {
+        vertex_usage |= vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR;
+        index_usage |= vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR;
+    }
+    let mut meshes = document
+        .meshes()
+        .map(|mesh| {
+            Ok(GltfMesh {
+                primitives: mesh
+                    .primitives()
+                    .filter(|primitive| primitive.mode() == Mode::Triangles)
+                    .filter_map(|primitive| {
+                        let (vertices, indices) = read_primitive(&primitive, &buffers)?;
+                        Some((vertices, indices, primitive.material().index()))
+                    })
+                    .map(|(vertices, indices, material)| {
+                        Ok(GltfPrimitive {
+                            vertex_buffer: uploads.buffer(
+                                render_instance,
+                                render_allocator,
+                                &vertices,
+                                vertex_usage,
+                            )?,
+                            index_buffer: uploads.buffer(
+                                render_instance,
+                                render_allocator,
+                                &indices,
+                                index_usage,
+                            )?,
+                            vertex_count: vertices.len() as u32,
+                            index_count: indices.len() as u32,
+                            material,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+                blas: None,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let renderer = render_instance.0.as_ref();
+    let mut blas_result = Ok(());
+    renderer.submit_setup_commands(|_, command_buffer| {
+        uploads.record(render_instance, command_buffer);
+        if !options.build_blas {
+            return;
+        }
+        for mesh in meshes.iter_mut() {
+            if mesh.primitives.is_empty() {
+                continue;
+            }
+            let geometries = mesh
+                .primitives
+                .iter()
+                .map(|primitive| BlasGeometry {
+                    vertex_buffer: &primitive.vertex_buffer,
+                    vertex_format: vk::Format::R32G32B32_SFLOAT,
+                    vertex_stride: size_of::<Vertex>() as u64,
+                    vertex_count: primitive.vertex_count,
+                    index_buffer: Some(&primitive.index_buffer),
+                    triangle_count: primitive.index_count / 3,
+                    opaque: true,
+                })
+                .collect::<Vec<_>>();
+            match Blas::new(
+                render_instance,
+                render_allocator,
+                command_buffer,
+                &geometries,
+                vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE,
+            ) {
+                Ok(blas) => mesh.blas = Some(blas),
+                Err(err) => {
+                    blas_result = Err(err);
+                    return;
+                }
+            }
+        }
+    })?;
+    blas_result?;
+    uploads.destroy(render_instance, render_allocator);
+
+    let mut instances = vec![];
+    if let Some(scene) = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+    {
+        for node in scene.nodes() {
+            collect_instances(node, Mat4::IDENTITY, &mut instances);
+        }
+    }
+
+    Ok(GltfScene {
+        meshes,
+        instances,
+        textures,
+        materials,
+        material_buffer,
+    })
+}
+
+impl GltfScene {
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        for mesh in self.meshes.iter_mut() {
+            if let Some(blas) = mesh.blas.as_mut() {
+                blas.destroy(render_instance, render_allocator);
+            }
+            for primitive in mesh.primitives.iter_mut() {
+                primitive
+                    .vertex_buffer
+                    .destroy(device, &mut render_allocator.allocator());
+                primitive
+                    .index_buffer
+                    .destroy(device, &mut render_allocator.allocator());
+            }
+        }
+        for texture in self.textures.iter_mut() {
+            texture.destroy(device, &mut render_allocator.allocator());
+        }
+        self.material_buffer
+            .destroy(device, &mut render_allocator.allocator());
+    }
+}
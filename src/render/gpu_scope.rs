@@ -0,0 +1,294 @@
+use std::{collections::VecDeque, ffi::CString, sync::Mutex, time::Duration};
+
+use ash::vk;
+
+use crate::error::Result;
+
+use super::{
+    command::DrawContext,
+    query::{QueryKind, QueryPoolRing},
+    stats, RenderInstance,
+};
+
+/// Timestamps of the scopes of every frame in flight, `None` until [`enable`] is called.
+static PROFILER: Mutex<Option<GpuProfiler>> = Mutex::new(None);
+
+/// Busy spans of a queue kept to measure the overlap with, older ones are dropped.
+const MAX_QUEUE_SPANS: usize = 8;
+
+#[derive(Debug)]
+struct ScopeTimestamps {
+    name: String,
+    start: u32,
+    end: Option<u32>,
+}
+
+#[derive(Debug)]
+struct GpuProfiler {
+    timestamps: QueryPoolRing,
+    /// Scopes written into each pool of `timestamps`.
+    frames: Vec<Vec<ScopeTimestamps>>,
+    frame: usize,
+    /// From the first to the last scope of the frames that were read back, in nanoseconds.
+    graphics_spans: VecDeque<(u64, u64)>,
+    /// Async compute submissions that weren't compared with the graphics spans yet.
+    async_compute_spans: VecDeque<(u64, u64)>,
+}
+
+/// Starts measuring [`gpu_scope!`] scopes with timestamps, room for `max_scopes` per frame.
+/// Their times are added to the [`FrameStats`](super::stats::FrameStats) `frame_count` frames
+/// later, scopes that don't fit are counted as dropped instead. Without it the scopes only label
+/// the command buffer and open a tracing span.
+pub fn enable(render_instance: &RenderInstance, max_scopes: u32, frame_count: u32) -> Result<()> {
+    let timestamps = QueryPoolRing::new(
+        render_instance,
+        QueryKind::Timestamp,
+        max_scopes * 2,
+        frame_count,
+    )?;
+    let previous = PROFILER.lock().unwrap().replace(GpuProfiler {
+        timestamps,
+        frames: (0..frame_count).map(|_| vec![]).collect(),
+        frame: 0,
+        graphics_spans: VecDeque::new(),
+        async_compute_spans: VecDeque::new(),
+    });
+    if let Some(mut previous) = previous {
+        previous.timestamps.destroy(render_instance.device());
+    }
+    Ok(())
+}
+
+/// The GPU has to be done with the frames that wrote timestamps.
+pub fn disable(render_instance: &RenderInstance) {
+    if let Some(mut profiler) = PROFILER.lock().unwrap().take() {
+        profiler.timestamps.destroy(render_instance.device());
+    }
+}
+
+/// Reports the scopes of the frame the timestamp pools wrapped around to and resets it. Record
+/// it once per frame, before the first scope and outside a render pass.
+pub fn begin_frame(render_instance: &RenderInstance, command_buffer: vk::CommandBuffer) {
+    let mut profiler = PROFILER.lock().unwrap();
+    let Some(profiler) = profiler.as_mut() else {
+        return;
+    };
+    profiler
+        .timestamps
+        .begin_frame(render_instance.device(), command_buffer);
+    profiler.frame = (profiler.frame + 1) % profiler.frames.len();
+
+    let mut span: Option<(u64, u64)> = None;
+    for scope in profiler.frames[profiler.frame].drain(..) {
+        let Some(end) = scope.end else {
+            continue;
+        };
+        if let Some(duration) = profiler.timestamps.elapsed(scope.start, end) {
+            stats::record_gpu_pass_time(scope.name, duration);
+        }
+        if let (Some(start), Some(end)) = (
+            profiler.timestamps.timestamp_nanos(scope.start),
+            profiler.timestamps.timestamp_nanos(end),
+        ) {
+            span = Some(span.map_or((start, end), |(first, last)| {
+                (first.min(start), last.max(end))
+            }));
+        }
+    }
+
+    if let Some(span) = span {
+        push_span(&mut profiler.graphics_spans, span);
+        let settled = settle_overlaps(&profiler.graphics_spans, &mut profiler.async_compute_spans);
+        for (busy, overlap) in settled {
+            stats::record_async_compute(busy, overlap);
+        }
+    }
+}
+
+/// Adds the start and end in nanoseconds of a submission to the async compute queue. How much
+/// of it overlapped the graphics work is reported to the
+/// [`FrameStats`](super::stats::FrameStats) once the graphics frames up to its end are read
+/// back. The timestamps of both queues are compared directly, which the desktop drivers allow
+/// even though Vulkan only promises it within a queue.
+pub(crate) fn record_async_compute_span(start: u64, end: u64) {
+    if let Some(profiler) = PROFILER.lock().unwrap().as_mut() {
+        push_span(&mut profiler.async_compute_spans, (start, end));
+    }
+}
+
+fn push_span(spans: &mut VecDeque<(u64, u64)>, span: (u64, u64)) {
+    if spans.len() == MAX_QUEUE_SPANS {
+        spans.pop_front();
+    }
+    spans.push_back(span);
+}
+
+/// Takes the async compute spans that end before the last graphics span does, with how long
+/// each ran and how much of that the graphics queue was busy as well. Graphics spans run one
+/// after the other, so their overlaps add up.
+fn settle_overlaps(
+    graphics_spans: &VecDeque<(u64, u64)>,
+    async_compute_spans: &mut VecDeque<(u64, u64)>,
+) -> Vec<(Duration, Duration)> {
+    let Some(&(_, graphics_end)) = graphics_spans.back() else {
+        return vec![];
+    };
+    let mut settled = vec![];
+    while let Some(&(start, end)) = async_compute_spans.front() {
+        if end > graphics_end {
+            break;
+        }
+        let overlap = graphics_spans
+            .iter()
+            .map(|&(span_start, span_end)| span_end.min(end).saturating_sub(span_start.max(start)))
+            .sum();
+        settled.push((
+            Duration::from_nanos(end.saturating_sub(start)),
+            Duration::from_nanos(overlap),
+        ));
+        async_compute_spans.pop_front();
+    }
+    settled
+}
+
+/// A command buffer that is being recorded, what [`gpu_scope!`] takes.
+pub trait ScopeTarget<'a> {
+    fn render_instance(&self) -> &'a RenderInstance;
+    fn command_buffer(&self) -> vk::CommandBuffer;
+}
+
+impl<'a> ScopeTarget<'a> for (&'a RenderInstance, vk::CommandBuffer) {
+    fn render_instance(&self) -> &'a RenderInstance {
+        self.0
+    }
+
+    fn command_buffer(&self) -> vk::CommandBuffer {
+        self.1
+    }
+}
+
+impl<'a> ScopeTarget<'a> for &DrawContext<'a> {
+    fn render_instance(&self) -> &'a RenderInstance {
+        self.render_instance
+    }
+
+    fn command_buffer(&self) -> vk::CommandBuffer {
+        self.command_buffer
+    }
+}
+
+/// Guard of a [`gpu_scope!`], closes the label, the timestamp scope and the span when dropped.
+pub struct GpuScope<'a> {
+    render_instance: &'a RenderInstance,
+    command_buffer: vk::CommandBuffer,
+    /// Index into the scopes of the profiler's current frame.
+    scope: Option<usize>,
+    _span: tracing::span::EnteredSpan,
+}
+
+impl<'a> GpuScope<'a> {
+    pub fn begin(target: impl ScopeTarget<'a>, name: &str) -> Self {
+        let render_instance = target.render_instance();
+        let command_buffer = target.command_buffer();
+        let span = tracing::info_span!("gpu_scope", name).entered();
+
+        let label = CString::new(name).expect("Scope names can't contain nul bytes");
+        unsafe {
+            render_instance
+                .0
+                .debug_utils_loader
+                .cmd_begin_debug_utils_label(
+                    command_buffer,
+                    &vk::DebugUtilsLabelEXT::default().label_name(&label),
+                )
+        };
+
+        let scope = PROFILER.lock().unwrap().as_mut().and_then(|profiler| {
+            let Some(start) = profiler.timestamps.write_timestamp(
+                render_instance.device(),
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+            ) else {
+                stats::count_dropped_gpu_scope();
+                return None;
+            };
+            let scopes = &mut profiler.frames[profiler.frame];
+            scopes.push(ScopeTimestamps {
+                name: name.to_string(),
+                start,
+                end: None,
+            });
+            Some(scopes.len() - 1)
+        });
+
+        Self {
+            render_instance,
+            command_buffer,
+            scope,
+            _span: span,
+        }
+    }
+}
+
+impl Drop for GpuScope<'_> {
+    fn drop(&mut self) {
+        if let Some(scope) = self.scope {
+            // the profiler may have been replaced while the scope was open
+            if let Some(profiler) = PROFILER.lock().unwrap().as_mut() {
+                if let Some(timestamps) = profiler.frames[profiler.frame].get_mut(scope) {
+                    timestamps.end = profiler.timestamps.write_timestamp(
+                        self.render_instance.device(),
+                        self.command_buffer,
+                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    );
+                    // nested scopes can use up the queries between a start and its end
+                    if timestamps.end.is_none() {
+                        stats::count_dropped_gpu_scope();
+                    }
+                }
+            }
+        }
+        unsafe {
+            self.render_instance
+                .0
+                .debug_utils_loader
+                .cmd_end_debug_utils_label(self.command_buffer)
+        };
+    }
+}
+
+/// Opens a debug utils label, a timestamp scope and a tracing span with the same name until
+/// the end of the enclosing block, so captures, GPU timings and CPU traces line up. `cmd` is a
+/// [`DrawContext`] or a `(&RenderInstance, vk::CommandBuffer)`.
+///
+/// ```ignore
+/// gpu_scope!((render_instance, command_buffer), "bloom");
+/// ```
+#[macro_export]
+macro_rules! gpu_scope {
+    ($cmd:expr, $name:expr) => {
+        let _gpu_scope = $crate::render::gpu_scope::GpuScope::begin($cmd, $name);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
+    use super::settle_overlaps;
+
+    #[test]
+    fn test_settle_overlaps() {
+        let graphics = VecDeque::from([(0, 100), (150, 300)]);
+        let mut async_compute = VecDeque::from([(50, 200), (250, 400)]);
+
+        let settled = settle_overlaps(&graphics, &mut async_compute);
+
+        // 50..100 and 150..200 overlap, the second span ends after the graphics frames
+        assert_eq!(
+            settled,
+            [(Duration::from_nanos(150), Duration::from_nanos(100))]
+        );
+        assert_eq!(async_compute, [(250, 400)]);
+    }
+}
@@ -0,0 +1,777 @@
+use std::{collections::HashMap, fmt::Write};
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{
+    allocator::{
+        Allocation, AllocationCreateDesc, AllocationScheme, Allocator, MemoryHints, MemoryLocation,
+    },
+    buffer::{Buffer, Image},
+    error::{Error, Result},
+};
+
+use super::{
+    registry::{BufferHandle, ImageHandle},
+    RenderInstance,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ResourceId(usize);
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransientImageDesc {
+    pub extent: vk::Extent2D,
+    pub format: vk::Format,
+    pub usage: vk::ImageUsageFlags,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TransientBufferDesc {
+    pub size: u64,
+    pub usage: vk::BufferUsageFlags,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ResourceDesc {
+    Image(TransientImageDesc),
+    Buffer(TransientBufferDesc),
+}
+
+#[derive(Debug)]
+pub struct GraphResource {
+    pub name: String,
+    pub desc: ResourceDesc,
+    /// First and last pass (inclusive) that use the resource, `None` when no pass that
+    /// survived culling touches it.
+    pub lifetime: Option<(usize, usize)>,
+    /// Memory slot the resource is placed in, resources sharing a slot alias each other.
+    pub alias_slot: Option<usize>,
+    pub is_output: bool,
+}
+
+/// Queue a pass is submitted to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PassQueue {
+    Graphics,
+    /// See [`AsyncCompute`](super::async_compute::AsyncCompute).
+    AsyncCompute,
+}
+
+#[derive(Debug)]
+pub struct GraphPass {
+    pub name: String,
+    pub reads: Vec<ResourceId>,
+    pub writes: Vec<ResourceId>,
+    pub queue: PassQueue,
+    /// Set when none of the resources the pass writes to are read afterwards.
+    pub culled: bool,
+}
+
+/// A pass that has to wait on an earlier pass writing to `resource`, which is where
+/// a barrier ends up in the command stream.
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+pub struct GraphDependency {
+    pub from_pass: usize,
+    pub to_pass: usize,
+    pub resource: usize,
+}
+
+#[derive(serde::Serialize)]
+struct PassDump<'a> {
+    name: &'a str,
+    reads: Vec<usize>,
+    writes: Vec<usize>,
+    queue: PassQueue,
+    culled: bool,
+    cull_reason: Option<&'static str>,
+}
+
+#[derive(serde::Serialize)]
+struct ResourceDump<'a> {
+    name: &'a str,
+    desc: String,
+    lifetime: Option<(usize, usize)>,
+    alias_slot: Option<usize>,
+    is_output: bool,
+    image: Option<ImageHandle>,
+    buffer: Option<BufferHandle>,
+}
+
+#[derive(serde::Serialize)]
+struct SlotDump {
+    size: u64,
+    alignment: u64,
+    memory_type_bits: u32,
+}
+
+#[derive(serde::Serialize)]
+struct GraphDump<'a> {
+    passes: Vec<PassDump<'a>>,
+    resources: Vec<ResourceDump<'a>>,
+    alias_slots: Vec<SlotDump>,
+    dependencies: Vec<GraphDependency>,
+}
+
+const CULL_REASON: &str = "none of its writes are read by a later pass or marked as output";
+
+impl ResourceDesc {
+    fn describe(&self) -> String {
+        match self {
+            ResourceDesc::Image(desc) => format!(
+                "{:?} {}x{}",
+                desc.format, desc.extent.width, desc.extent.height
+            ),
+            ResourceDesc::Buffer(desc) => format!("buffer {} bytes", desc.size),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AliasSlot {
+    pub requirements: vk::MemoryRequirements,
+    allocation: Option<Allocation>,
+}
+
+/// Describes the transient resources of a frame and which passes read and write them.
+///
+/// Passes are declared in execution order, the names should match the ids used in the
+/// [`SequentialPassSystem`](super::SequentialPassSystem) so culled passes are skipped.
+/// Compiling the graph places resources with disjoint lifetimes in the same memory, so the
+/// first pass using an aliased resource has to treat its contents as undefined. The compiled
+/// resources live in the [`ResourceRegistry`](super::registry::ResourceRegistry) of the
+/// [`RenderInstance`], the graph only keeps their handles.
+#[derive(Resource, Default)]
+pub struct RenderGraph {
+    resources: Vec<GraphResource>,
+    passes: Vec<GraphPass>,
+    slots: Vec<AliasSlot>,
+    images: HashMap<ResourceId, ImageHandle>,
+    buffers: HashMap<ResourceId, BufferHandle>,
+}
+
+impl RenderGraph {
+    pub fn create_image(&mut self, name: &str, desc: TransientImageDesc) -> ResourceId {
+        self.add_resource(name, ResourceDesc::Image(desc))
+    }
+
+    pub fn create_buffer(&mut self, name: &str, desc: TransientBufferDesc) -> ResourceId {
+        self.add_resource(name, ResourceDesc::Buffer(desc))
+    }
+
+    fn add_resource(&mut self, name: &str, desc: ResourceDesc) -> ResourceId {
+        self.resources.push(GraphResource {
+            name: name.to_string(),
+            desc,
+            lifetime: None,
+            alias_slot: None,
+            is_output: false,
+        });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    pub fn add_pass(&mut self, name: &str, reads: &[ResourceId], writes: &[ResourceId]) {
+        self.push_pass(name, reads, writes, PassQueue::Graphics);
+    }
+
+    /// Adds a compute pass that is submitted to the async compute queue. Its resources are
+    /// shared between both queues and never aliased, since the pass can run at any point
+    /// between its dependencies.
+    pub fn add_async_compute_pass(
+        &mut self,
+        name: &str,
+        reads: &[ResourceId],
+        writes: &[ResourceId],
+    ) {
+        self.push_pass(name, reads, writes, PassQueue::AsyncCompute);
+    }
+
+    fn push_pass(
+        &mut self,
+        name: &str,
+        reads: &[ResourceId],
+        writes: &[ResourceId],
+        queue: PassQueue,
+    ) {
+        self.passes.push(GraphPass {
+            name: name.to_string(),
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+            queue,
+            culled: false,
+        });
+    }
+
+    /// Keeps the resource alive until the end of the frame and the passes writing to it from being culled.
+    pub fn mark_output(&mut self, id: ResourceId) {
+        self.resources[id.0].is_output = true;
+    }
+
+    pub fn resources(&self) -> &[GraphResource] {
+        &self.resources
+    }
+
+    pub fn passes(&self) -> &[GraphPass] {
+        &self.passes
+    }
+
+    pub fn alias_slots(&self) -> &[AliasSlot] {
+        &self.slots
+    }
+
+    pub fn is_culled(&self, pass_name: &str) -> bool {
+        self.passes
+            .iter()
+            .any(|pass| pass.name == pass_name && pass.culled)
+    }
+
+    /// Queue of the pass called `pass_name`, `None` when the graph doesn't have it.
+    pub fn pass_queue(&self, pass_name: &str) -> Option<PassQueue> {
+        self.passes
+            .iter()
+            .find(|pass| pass.name == pass_name)
+            .map(|pass| pass.queue)
+    }
+
+    /// Resolved through [`RenderInstance::registry`], until the graph is compiled again.
+    pub fn image(&self, id: ResourceId) -> ImageHandle {
+        *self.images.get(&id).expect("Image is not used by any pass")
+    }
+
+    pub fn buffer(&self, id: ResourceId) -> BufferHandle {
+        *self
+            .buffers
+            .get(&id)
+            .expect("Buffer is not used by any pass")
+    }
+
+    /// For every access of a pass that survived culling, the last earlier pass writing the same resource.
+    pub fn dependencies(&self) -> Vec<GraphDependency> {
+        let mut dependencies = vec![];
+        for (to_pass, pass) in self.passes.iter().enumerate() {
+            if pass.culled {
+                continue;
+            }
+            for id in pass.reads.iter().chain(pass.writes.iter()) {
+                let writer = self.passes[..to_pass]
+                    .iter()
+                    .rposition(|earlier| !earlier.culled && earlier.writes.contains(id));
+                if let Some(from_pass) = writer {
+                    dependencies.push(GraphDependency {
+                        from_pass,
+                        to_pass,
+                        resource: id.0,
+                    });
+                }
+            }
+        }
+        dependencies
+    }
+
+    /// Dependencies between passes on different queues, which need a semaphore instead of a
+    /// barrier: the submission of `to_pass` has to wait on the one containing `from_pass`.
+    /// Async compute passes are submitted before the graphics passes of the frame, so only
+    /// graphics passes can wait, see [`RenderGraph::compile`].
+    pub fn queue_joins(&self) -> Vec<GraphDependency> {
+        self.dependencies()
+            .into_iter()
+            .filter(|dependency| {
+                self.passes[dependency.from_pass].queue != self.passes[dependency.to_pass].queue
+            })
+            .collect()
+    }
+
+    /// Writes the compiled graph in graphviz `dot` format. Culled passes are dashed, resources
+    /// sharing memory are grouped per alias slot and the red edges are the barriers between passes.
+    pub fn dump_graphviz(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph RenderGraph {{").unwrap();
+        writeln!(out, "    rankdir=LR;").unwrap();
+        writeln!(out, "    node [fontname=\"monospace\"];").unwrap();
+
+        for (index, pass) in self.passes.iter().enumerate() {
+            if pass.culled {
+                writeln!(
+                    out,
+                    "    pass_{} [shape=box, style=dashed, color=gray, label=\"{}\\nculled: {}\"];",
+                    index,
+                    escape_label(&pass.name),
+                    CULL_REASON
+                )
+                .unwrap();
+            } else if pass.queue == PassQueue::AsyncCompute {
+                writeln!(
+                    out,
+                    "    pass_{} [shape=box, color=blue, label=\"{}\\nasync compute\"];",
+                    index,
+                    escape_label(&pass.name)
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    out,
+                    "    pass_{} [shape=box, label=\"{}\"];",
+                    index,
+                    escape_label(&pass.name)
+                )
+                .unwrap();
+            }
+        }
+
+        let resource_node = |index: usize, resource: &GraphResource| {
+            let lifetime = match resource.lifetime {
+                Some((first, last)) => format!("passes {}-{}", first, last),
+                None => "unused".to_string(),
+            };
+            format!(
+                "res_{} [shape=ellipse, label=\"{}\\n{}\\n{}\"];",
+                index,
+                escape_label(&resource.name),
+                resource.desc.describe(),
+                lifetime
+            )
+        };
+
+        for (slot_index, slot) in self.slots.iter().enumerate() {
+            writeln!(out, "    subgraph cluster_slot_{} {{", slot_index).unwrap();
+            writeln!(
+                out,
+                "        label=\"alias slot {} ({} bytes)\";",
+                slot_index, slot.requirements.size
+            )
+            .unwrap();
+            for (index, resource) in self.resources.iter().enumerate() {
+                if resource.alias_slot == Some(slot_index) {
+                    writeln!(out, "        {}", resource_node(index, resource)).unwrap();
+                }
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+        for (index, resource) in self.resources.iter().enumerate() {
+            if resource.alias_slot.is_none() {
+                writeln!(out, "    {}", resource_node(index, resource)).unwrap();
+            }
+        }
+
+        for (index, pass) in self.passes.iter().enumerate() {
+            for id in pass.writes.iter() {
+                writeln!(out, "    pass_{} -> res_{} [label=\"write\"];", index, id.0).unwrap();
+            }
+            for id in pass.reads.iter() {
+                writeln!(out, "    res_{} -> pass_{} [label=\"read\"];", id.0, index).unwrap();
+            }
+        }
+
+        for dependency in self.dependencies() {
+            let kind = if self.passes[dependency.from_pass].queue
+                == self.passes[dependency.to_pass].queue
+            {
+                "barrier"
+            } else {
+                "semaphore"
+            };
+            writeln!(
+                out,
+                "    pass_{} -> pass_{} [style=dotted, color=red, label=\"{}: {}\"];",
+                dependency.from_pass,
+                dependency.to_pass,
+                kind,
+                escape_label(&self.resources[dependency.resource].name)
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    /// Same information as [`RenderGraph::dump_graphviz`] for tools that would rather parse JSON.
+    pub fn dump_json(&self) -> String {
+        let dump = GraphDump {
+            passes: self
+                .passes
+                .iter()
+                .map(|pass| PassDump {
+                    name: &pass.name,
+                    reads: pass.reads.iter().map(|id| id.0).collect(),
+                    writes: pass.writes.iter().map(|id| id.0).collect(),
+                    queue: pass.queue,
+                    culled: pass.culled,
+                    cull_reason: pass.culled.then_some(CULL_REASON),
+                })
+                .collect(),
+            resources: self
+                .resources
+                .iter()
+                .enumerate()
+                .map(|(index, resource)| ResourceDump {
+                    name: &resource.name,
+                    desc: resource.desc.describe(),
+                    lifetime: resource.lifetime,
+                    alias_slot: resource.alias_slot,
+                    is_output: resource.is_output,
+                    image: self.images.get(&ResourceId(index)).copied(),
+                    buffer: self.buffers.get(&ResourceId(index)).copied(),
+                })
+                .collect(),
+            alias_slots: self
+                .slots
+                .iter()
+                .map(|slot| SlotDump {
+                    size: slot.requirements.size,
+                    alignment: slot.requirements.alignment,
+                    memory_type_bits: slot.requirements.memory_type_bits,
+                })
+                .collect(),
+            dependencies: self.dependencies(),
+        };
+
+        serde_json::to_string_pretty(&dump).unwrap()
+    }
+
+    fn cull_passes(&mut self) {
+        let mut needed = self
+            .resources
+            .iter()
+            .map(|resource| resource.is_output)
+            .collect::<Vec<_>>();
+
+        for pass in self.passes.iter_mut().rev() {
+            pass.culled = !pass.writes.is_empty() && !pass.writes.iter().any(|id| needed[id.0]);
+            if !pass.culled {
+                for id in pass.reads.iter() {
+                    needed[id.0] = true;
+                }
+            }
+        }
+    }
+
+    fn compute_lifetimes(&mut self) {
+        for resource in self.resources.iter_mut() {
+            resource.lifetime = None;
+            resource.alias_slot = None;
+        }
+
+        for (index, pass) in self.passes.iter().enumerate() {
+            if pass.culled {
+                continue;
+            }
+            for id in pass.reads.iter().chain(pass.writes.iter()) {
+                let resource = &mut self.resources[id.0];
+                resource.lifetime = Some(match resource.lifetime {
+                    Some((first, _)) => (first, index),
+                    None => (index, index),
+                });
+            }
+        }
+
+        let pass_count = self.passes.len();
+        for resource in self.resources.iter_mut() {
+            if let (true, Some((first, _))) = (resource.is_output, resource.lifetime) {
+                resource.lifetime = Some((first, pass_count));
+            }
+        }
+
+        for index in 0..self.resources.len() {
+            if self.resources[index].lifetime.is_some() && self.is_async_resource(ResourceId(index))
+            {
+                self.resources[index].lifetime = Some((0, pass_count));
+            }
+        }
+    }
+
+    fn validate_queue_joins(&self) -> Result<()> {
+        let join = self
+            .queue_joins()
+            .into_iter()
+            .find(|join| self.passes[join.to_pass].queue == PassQueue::AsyncCompute);
+        match join {
+            Some(join) => Err(Error::Unsupported(format!(
+                "Async compute pass {} uses {} written by graphics pass {}, the graphics passes \
+                 are submitted after it",
+                self.passes[join.to_pass].name,
+                self.resources[join.resource].name,
+                self.passes[join.from_pass].name
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn is_async_resource(&self, id: ResourceId) -> bool {
+        self.passes.iter().any(|pass| {
+            !pass.culled
+                && pass.queue == PassQueue::AsyncCompute
+                && (pass.reads.contains(&id) || pass.writes.contains(&id))
+        })
+    }
+
+    /// Culls unused passes, creates all transient resources and binds the ones with
+    /// disjoint lifetimes to the same memory. Previously compiled resources are destroyed, on
+    /// error the ones created so far stay in the graph until it's destroyed or compiled again.
+    /// Fails when an async compute pass uses what a graphics pass wrote earlier in the frame.
+    pub fn compile(
+        &mut self,
+        render_instance: &RenderInstance,
+        allocator: &mut Allocator,
+    ) -> Result<()> {
+        self.destroy(render_instance, allocator);
+        self.cull_passes();
+        self.validate_queue_joins()?;
+        self.compute_lifetimes();
+
+        let device = render_instance.device();
+        let shared_families = render_instance
+            .0
+            .async_compute_queue_family_index
+            .map(|family_index| [render_instance.0.queue_family_index, family_index]);
+        let mut image_ids = vec![];
+        let mut buffer_ids = vec![];
+
+        for (index, resource) in self.resources.iter().enumerate() {
+            if resource.lifetime.is_none() {
+                continue;
+            }
+            let id = ResourceId(index);
+            let (sharing_mode, queue_families) =
+                match (shared_families.as_ref(), self.is_async_resource(id)) {
+                    (Some(families), true) => (vk::SharingMode::CONCURRENT, &families[..]),
+                    _ => (vk::SharingMode::EXCLUSIVE, &[][..]),
+                };
+            match resource.desc {
+                ResourceDesc::Image(desc) => {
+                    let image = unsafe {
+                        device.create_image(
+                            &vk::ImageCreateInfo::default()
+                                .image_type(vk::ImageType::TYPE_2D)
+                                .format(desc.format)
+                                .extent(desc.extent.into())
+                                .mip_levels(1)
+                                .array_layers(1)
+                                .samples(vk::SampleCountFlags::TYPE_1)
+                                .tiling(vk::ImageTiling::OPTIMAL)
+                                .usage(desc.usage)
+                                .sharing_mode(sharing_mode)
+                                .queue_family_indices(queue_families),
+                            None,
+                        )
+                    }?;
+
+                    let handle = render_instance.registry_mut().insert_image(Image {
+                        image,
+                        allocation: None,
+                        view: None,
+                        format: desc.format,
+                        extent: desc.extent.into(),
+                        offset: 0,
+                    });
+                    self.images.insert(id, handle);
+                    image_ids.push(id);
+                }
+                ResourceDesc::Buffer(desc) => {
+                    let buffer = unsafe {
+                        device.create_buffer(
+                            &vk::BufferCreateInfo::default()
+                                .size(desc.size)
+                                .usage(desc.usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS)
+                                .sharing_mode(sharing_mode)
+                                .queue_family_indices(queue_families),
+                            None,
+                        )
+                    }?;
+
+                    let handle = render_instance.registry_mut().insert_buffer(Buffer {
+                        buffer,
+                        allocation: None,
+                        size: desc.size,
+                        usage: desc.usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS,
+                        device_addr: 0,
+                        has_been_written_to: false,
+                        offset: 0,
+                        location: MemoryLocation::GpuOnly,
+                        rebar: false,
+                    });
+                    self.buffers.insert(id, handle);
+                    buffer_ids.push(id);
+                }
+            }
+        }
+
+        // images and buffers are aliased separately to stay clear of bufferImageGranularity
+        for (ids, linear) in [(image_ids, false), (buffer_ids, true)] {
+            let lifetimes = ids
+                .iter()
+                .map(|id| self.resources[id.0].lifetime.unwrap())
+                .collect::<Vec<_>>();
+            let requirements = {
+                let registry = render_instance.registry();
+                ids.iter()
+                    .map(|id| unsafe {
+                        if linear {
+                            device.get_buffer_memory_requirements(
+                                registry.buffer(self.buffers[id]).buffer,
+                            )
+                        } else {
+                            device.get_image_memory_requirements(
+                                registry.image(self.images[id]).image,
+                            )
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            let (assignment, slot_requirements) = assign_alias_slots(&lifetimes, &requirements);
+            let first_slot = self.slots.len();
+            let mut registry = render_instance.registry_mut();
+            for requirements in slot_requirements {
+                let allocation = allocator.allocate(&AllocationCreateDesc {
+                    name: "render graph transient",
+                    requirements,
+                    location: MemoryLocation::GpuOnly,
+                    linear,
+                    allocation_scheme: AllocationScheme::Managed,
+                    hints: MemoryHints::default(),
+                })?;
+                self.slots.push(AliasSlot {
+                    requirements,
+                    allocation: Some(allocation),
+                });
+            }
+
+            for (id, slot) in ids.iter().zip(assignment) {
+                let slot_index = first_slot + slot;
+                self.resources[id.0].alias_slot = Some(slot_index);
+                let allocation = self.slots[slot_index].allocation.as_ref().unwrap();
+
+                unsafe {
+                    if linear {
+                        let buffer = registry.buffer_mut(self.buffers[id]);
+                        device.bind_buffer_memory(
+                            buffer.buffer,
+                            allocation.memory(),
+                            allocation.offset(),
+                        )?;
+                        buffer.offset = allocation.offset();
+                        buffer.device_addr = device.get_buffer_device_address(
+                            &vk::BufferDeviceAddressInfo::default().buffer(buffer.buffer),
+                        );
+                    } else {
+                        let image = registry.image_mut(self.images[id]);
+                        device.bind_image_memory(
+                            image.image,
+                            allocation.memory(),
+                            allocation.offset(),
+                        )?;
+                        image.offset = allocation.offset();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the resources from the registry and destroys them right away, the frames in
+    /// flight can't be using them anymore.
+    pub fn destroy(&mut self, render_instance: &RenderInstance, allocator: &mut Allocator) {
+        let device = render_instance.device();
+        let mut registry = render_instance.registry_mut();
+        for (_, handle) in self.images.drain() {
+            if let Some(mut image) = registry.images.remove(handle) {
+                image.destroy(device, allocator);
+            }
+        }
+        for (_, handle) in self.buffers.drain() {
+            if let Some(mut buffer) = registry.buffers.remove(handle) {
+                buffer.destroy(device, allocator);
+            }
+        }
+        for mut slot in self.slots.drain(..) {
+            if let Some(allocation) = slot.allocation.take() {
+                allocator.free(allocation).unwrap();
+            }
+        }
+    }
+}
+
+fn escape_label(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Greedily places resources in memory slots. Two resources can share a slot when their
+/// (inclusive) lifetimes don't overlap and they have a memory type in common.
+/// Returns the slot of every resource and the combined requirements of every slot.
+fn assign_alias_slots(
+    lifetimes: &[(usize, usize)],
+    requirements: &[vk::MemoryRequirements],
+) -> (Vec<usize>, Vec<vk::MemoryRequirements>) {
+    let mut order = (0..lifetimes.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&index| lifetimes[index].0);
+
+    let mut slots: Vec<(vk::MemoryRequirements, usize)> = vec![];
+    let mut assignment = vec![0; lifetimes.len()];
+
+    for index in order {
+        let (first, last) = lifetimes[index];
+        let requirement = requirements[index];
+
+        let free_slot = slots.iter().position(|(slot, slot_last)| {
+            *slot_last < first && slot.memory_type_bits & requirement.memory_type_bits != 0
+        });
+
+        if let Some(slot_index) = free_slot {
+            let (slot, slot_last) = &mut slots[slot_index];
+            slot.size = slot.size.max(requirement.size);
+            slot.alignment = slot.alignment.max(requirement.alignment);
+            slot.memory_type_bits &= requirement.memory_type_bits;
+            *slot_last = last;
+            assignment[index] = slot_index;
+        } else {
+            slots.push((requirement, last));
+            assignment[index] = slots.len() - 1;
+        }
+    }
+
+    (
+        assignment,
+        slots.into_iter().map(|(requirements, _)| requirements).collect(),
+    )
+}
+
+#[test]
+fn test_assign_alias_slots() {
+    let requirements = |size| vk::MemoryRequirements {
+        size,
+        alignment: 256,
+        memory_type_bits: 0b11,
+    };
+
+    let (assignment, slots) = assign_alias_slots(
+        &[(0, 1), (1, 2), (2, 3)],
+        &[requirements(1024), requirements(512), requirements(4096)],
+    );
+
+    assert_eq!(assignment, vec![0, 1, 0]);
+    assert_eq!(slots.len(), 2);
+    assert_eq!(slots[0].size, 4096);
+}
+
+#[test]
+fn test_validate_queue_joins() {
+    let desc = TransientBufferDesc {
+        size: 16,
+        usage: vk::BufferUsageFlags::STORAGE_BUFFER,
+    };
+    let mut graph = RenderGraph::default();
+    let particles = graph.create_buffer("particles", desc);
+    let lights = graph.create_buffer("lights", desc);
+    graph.add_async_compute_pass("simulate", &[], &[particles]);
+    graph.add_pass("shade", &[particles], &[lights]);
+    graph.mark_output(lights);
+    graph.cull_passes();
+    assert!(graph.validate_queue_joins().is_ok());
+
+    // the graphics submission comes after the async compute one
+    graph.add_async_compute_pass("sort", &[lights], &[particles]);
+    graph.mark_output(particles);
+    graph.cull_passes();
+    assert!(graph.validate_queue_joins().is_err());
+}
@@ -0,0 +1,386 @@
+use std::{
+    collections::HashMap,
+    ops::BitOr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use ash::vk;
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+
+use super::{
+    graph::{PassQueue, RenderGraph, ResourceId, TransientBufferDesc, TransientImageDesc},
+    RenderAllocator, RenderInstance, SequentialPassSystem,
+};
+
+/// A transient image, sized to the swapchain times `scale` unless `width` and `height` are set.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ImageEntry {
+    pub name: String,
+    /// Name of a core `VkFormat` without the prefix, like `R16G16B16A16_SFLOAT`.
+    pub format: String,
+    /// Names of `VkImageUsageFlagBits` without the prefix, like `COLOR_ATTACHMENT`.
+    pub usage: Vec<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BufferEntry {
+    pub name: String,
+    pub size: u64,
+    /// Names of `VkBufferUsageFlagBits` without the prefix, like `STORAGE_BUFFER`.
+    pub usage: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PassEntry {
+    /// Id of the [`SequentialNode`](super::SequentialNode) that records the pass.
+    pub name: String,
+    /// Shader the node should build its pipeline from.
+    pub shader: Option<String>,
+    #[serde(default = "default_queue")]
+    pub queue: PassQueue,
+    #[serde(default)]
+    pub reads: Vec<String>,
+    #[serde(default)]
+    pub writes: Vec<String>,
+    /// Images the pass renders to, in color attachment order. Count as writes.
+    #[serde(default)]
+    pub attachments: Vec<String>,
+}
+
+/// The passes of a frame and the resources connecting them, as written in a `.ron` or `.toml`
+/// file. Passes run in the order they are listed.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GraphFile {
+    #[serde(default)]
+    pub images: Vec<ImageEntry>,
+    #[serde(default)]
+    pub buffers: Vec<BufferEntry>,
+    pub passes: Vec<PassEntry>,
+    /// Resources that are marked as output, everything that doesn't lead to one is culled.
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_queue() -> PassQueue {
+    PassQueue::Graphics
+}
+
+fn graph_error(path: &Path, message: impl ToString) -> Error {
+    Error::GraphFile {
+        path: path.display().to_string(),
+        message: message.to_string(),
+    }
+}
+
+/// Matches `name` against the debug names of the core formats.
+fn parse_format(name: &str) -> Option<vk::Format> {
+    (0..=184)
+        .map(vk::Format::from_raw)
+        .find(|format| format!("{:?}", format) == name)
+}
+
+/// Matches every name against the debug names of the single bit flags.
+fn parse_flags<F: std::fmt::Debug + Copy + BitOr<Output = F>>(
+    names: &[String],
+    from_raw: impl Fn(u32) -> F,
+) -> std::result::Result<F, String> {
+    let mut flags = from_raw(0);
+    for name in names {
+        let flag = (0..32)
+            .map(|bit| from_raw(1 << bit))
+            .find(|flag| format!("{:?}", flag) == *name)
+            .ok_or_else(|| format!("unknown usage {}", name))?;
+        flags = flags | flag;
+    }
+    Ok(flags)
+}
+
+impl GraphFile {
+    /// Parses the file as RON or TOML, depending on its extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|e| graph_error(path, e))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("ron") => ron::from_str(&source).map_err(|e| graph_error(path, e)),
+            Some("toml") => toml::from_str(&source).map_err(|e| graph_error(path, e)),
+            _ => Err(graph_error(path, "expected a .ron or .toml file")),
+        }
+    }
+
+    pub fn pass(&self, name: &str) -> Option<&PassEntry> {
+        self.passes.iter().find(|pass| pass.name == name)
+    }
+
+    /// Declares the resources and passes on `graph`, which should be empty. Returns the id of
+    /// every resource by name. `path` is only used for errors.
+    pub fn build(
+        &self,
+        path: &Path,
+        graph: &mut RenderGraph,
+        surface_extent: vk::Extent2D,
+    ) -> Result<HashMap<String, ResourceId>> {
+        let mut ids = HashMap::new();
+        let mut declare = |name: &str, id: ResourceId| {
+            if ids.insert(name.to_string(), id).is_some() {
+                return Err(graph_error(
+                    path,
+                    format!("resource {} is declared twice", name),
+                ));
+            }
+            Ok(())
+        };
+
+        for image in self.images.iter() {
+            let format = parse_format(&image.format).ok_or_else(|| {
+                graph_error(
+                    path,
+                    format!("unknown format {} of {}", image.format, image.name),
+                )
+            })?;
+            let usage = parse_flags(&image.usage, vk::ImageUsageFlags::from_raw)
+                .map_err(|e| graph_error(path, format!("{} of {}", e, image.name)))?;
+            let scaled = |size: u32| ((size as f32 * image.scale) as u32).max(1);
+            let extent = vk::Extent2D {
+                width: image.width.unwrap_or_else(|| scaled(surface_extent.width)),
+                height: image
+                    .height
+                    .unwrap_or_else(|| scaled(surface_extent.height)),
+            };
+            let id = graph.create_image(
+                &image.name,
+                TransientImageDesc {
+                    extent,
+                    format,
+                    usage,
+                },
+            );
+            declare(&image.name, id)?;
+        }
+
+        for buffer in self.buffers.iter() {
+            let usage = parse_flags(&buffer.usage, vk::BufferUsageFlags::from_raw)
+                .map_err(|e| graph_error(path, format!("{} of {}", e, buffer.name)))?;
+            let id = graph.create_buffer(
+                &buffer.name,
+                TransientBufferDesc {
+                    size: buffer.size,
+                    usage,
+                },
+            );
+            declare(&buffer.name, id)?;
+        }
+
+        let lookup = |pass: &str, name: &String| {
+            ids.get(name).copied().ok_or_else(|| {
+                graph_error(
+                    path,
+                    format!("pass {} uses unknown resource {}", pass, name),
+                )
+            })
+        };
+
+        for pass in self.passes.iter() {
+            let reads = pass
+                .reads
+                .iter()
+                .map(|name| lookup(&pass.name, name))
+                .collect::<Result<Vec<_>>>()?;
+            let writes = pass
+                .writes
+                .iter()
+                .chain(pass.attachments.iter())
+                .map(|name| lookup(&pass.name, name))
+                .collect::<Result<Vec<_>>>()?;
+            match pass.queue {
+                PassQueue::Graphics => graph.add_pass(&pass.name, &reads, &writes),
+                PassQueue::AsyncCompute => {
+                    graph.add_async_compute_pass(&pass.name, &reads, &writes)
+                }
+            }
+        }
+
+        for name in self.outputs.iter() {
+            graph.mark_output(lookup("outputs", name)?);
+        }
+
+        Ok(ids)
+    }
+}
+
+/// Builds the [`RenderGraph`] from a graph file and rebuilds it whenever the file changes, so
+/// the frame can be restructured while the app is running. Nodes can look up their shader and
+/// resources here, and should rebuild their pipelines when `generation` changes.
+#[derive(Resource)]
+pub struct GraphFileWatcher {
+    pub path: PathBuf,
+    pub file: GraphFile,
+    pub resources: HashMap<String, ResourceId>,
+    /// Bumped every time the graph is rebuilt.
+    pub generation: u64,
+    /// Modification time of the file that was last built, `None` before the first build.
+    modified: Option<SystemTime>,
+}
+
+impl GraphFileWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: GraphFile::default(),
+            resources: HashMap::new(),
+            generation: 0,
+            modified: None,
+        }
+    }
+
+    pub fn resource(&self, name: &str) -> ResourceId {
+        *self
+            .resources
+            .get(name)
+            .unwrap_or_else(|| panic!("Graph file has no resource named {}", name))
+    }
+
+    fn modified_time(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+}
+
+/// Rebuilds and compiles the graph when the watched file changed, and puts the
+/// [`SequentialPassSystem`] in the order of the file. A file that fails to load keeps the
+/// previous graph running.
+pub(crate) fn reload_graph_file(world: &mut World) {
+    let Some(watcher) = world.get_resource::<GraphFileWatcher>() else {
+        return;
+    };
+    let modified = watcher.modified_time();
+    if modified.is_none() || modified == watcher.modified {
+        return;
+    }
+    let path = watcher.path.clone();
+    let is_reload = watcher.modified.is_some();
+
+    let file = match GraphFile::load(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Keeping the current render graph: {}", e);
+            world.resource_mut::<GraphFileWatcher>().modified = modified;
+            return;
+        }
+    };
+
+    let render_instance = world.resource::<RenderInstance>().clone();
+    let mut graph = RenderGraph::default();
+    let resources = match file.build(&path, &mut graph, render_instance.0.surface_resolution) {
+        Ok(resources) => resources,
+        Err(e) => {
+            println!("Keeping the current render graph: {}", e);
+            world.resource_mut::<GraphFileWatcher>().modified = modified;
+            return;
+        }
+    };
+
+    let compiled = world.resource_scope(|world, mut current: Mut<RenderGraph>| {
+        let mut allocator = world.resource::<RenderAllocator>().allocator();
+        if let Err(e) = graph.compile(&render_instance, &mut allocator) {
+            println!("Keeping the current render graph: {}", e);
+            graph.destroy(&render_instance, &mut allocator);
+            return false;
+        }
+        if is_reload {
+            // the transient resources of the old graph can still be in use by the frames in flight
+            unsafe { render_instance.device().device_wait_idle().unwrap() };
+        }
+        let mut old_graph = std::mem::replace(&mut *current, graph);
+        old_graph.destroy(&render_instance, &mut allocator);
+        true
+    });
+    if !compiled {
+        world.resource_mut::<GraphFileWatcher>().modified = modified;
+        return;
+    }
+
+    world
+        .resource_mut::<SequentialPassSystem>()
+        .sort_by_pass_order(
+            &file
+                .passes
+                .iter()
+                .map(|pass| &*pass.name)
+                .collect::<Vec<_>>(),
+        );
+
+    let mut watcher = world.resource_mut::<GraphFileWatcher>();
+    watcher.file = file;
+    watcher.resources = resources;
+    watcher.generation += 1;
+    watcher.modified = modified;
+    if is_reload {
+        println!("Reloaded render graph from {}", path.display());
+    }
+}
+
+#[test]
+fn test_build_graph_file() {
+    let file: GraphFile = toml::from_str(
+        r#"
+        outputs = ["color"]
+
+        [[images]]
+        name = "color"
+        format = "R16G16B16A16_SFLOAT"
+        usage = ["COLOR_ATTACHMENT", "SAMPLED"]
+        scale = 0.5
+
+        [[passes]]
+        name = "main"
+        shader = "assets/shaders/main.frag"
+        attachments = ["color"]
+
+        [[passes]]
+        name = "unused"
+        reads = ["color"]
+        "#,
+    )
+    .unwrap();
+
+    let mut graph = RenderGraph::default();
+    let ids = file
+        .build(
+            Path::new("test.toml"),
+            &mut graph,
+            vk::Extent2D {
+                width: 1920,
+                height: 1080,
+            },
+        )
+        .unwrap();
+
+    let color = &graph.resources()[0];
+    match color.desc {
+        super::graph::ResourceDesc::Image(desc) => {
+            assert_eq!(desc.format, vk::Format::R16G16B16A16_SFLOAT);
+            assert_eq!(desc.extent.width, 960);
+            assert_eq!(
+                desc.usage,
+                vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED
+            );
+        }
+        _ => panic!("expected an image"),
+    }
+    assert_eq!(graph.passes()[0].writes, vec![ids["color"]]);
+    assert_eq!(file.pass("main").unwrap().queue, PassQueue::Graphics);
+    assert!(parse_format("NOT_A_FORMAT").is_none());
+}
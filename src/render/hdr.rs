@@ -0,0 +1,90 @@
+use ash::vk;
+use bevy::prelude::*;
+
+use super::{extract::Extract, RenderInstance};
+
+/// Describes the mastering display of the frames to an HDR10 monitor, so it can map them to
+/// its own range. Insert or change it in the main app, it's only sent when the swapchain is
+/// HDR, see `ExampleBase::hdr_output`.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct HdrMetadata {
+    /// CIE 1931 xy coordinates of the red, green and blue primaries.
+    pub primaries: [Vec2; 3],
+    pub white_point: Vec2,
+    /// In nits.
+    pub max_luminance: f32,
+    pub min_luminance: f32,
+    /// Brightest pixel of the content, in nits.
+    pub max_content_light_level: f32,
+    /// Brightest average of a frame, in nits.
+    pub max_frame_average_light_level: f32,
+}
+
+impl Default for HdrMetadata {
+    /// Rec. 2020 primaries with a D65 white point, mastered at 1000 nits.
+    fn default() -> Self {
+        Self {
+            primaries: [
+                Vec2::new(0.708, 0.292),
+                Vec2::new(0.170, 0.797),
+                Vec2::new(0.131, 0.046),
+            ],
+            white_point: Vec2::new(0.3127, 0.3290),
+            max_luminance: 1000.0,
+            min_luminance: 0.001,
+            max_content_light_level: 1000.0,
+            max_frame_average_light_level: 400.0,
+        }
+    }
+}
+
+impl HdrMetadata {
+    pub fn to_vk(&self) -> vk::HdrMetadataEXT<'static> {
+        let xy = |value: Vec2| vk::XYColorEXT {
+            x: value.x,
+            y: value.y,
+        };
+        vk::HdrMetadataEXT::default()
+            .display_primary_red(xy(self.primaries[0]))
+            .display_primary_green(xy(self.primaries[1]))
+            .display_primary_blue(xy(self.primaries[2]))
+            .white_point(xy(self.white_point))
+            .max_luminance(self.max_luminance)
+            .min_luminance(self.min_luminance)
+            .max_content_light_level(self.max_content_light_level)
+            .max_frame_average_light_level(self.max_frame_average_light_level)
+    }
+}
+
+pub(crate) fn extract_hdr_metadata(
+    metadata: Extract<Option<Res<HdrMetadata>>>,
+    mut render_metadata: ResMut<HdrMetadata>,
+) {
+    if let Some(metadata) = metadata.as_ref() {
+        if metadata.is_changed() {
+            *render_metadata = HdrMetadata::clone(metadata);
+        }
+    }
+}
+
+/// Passes the metadata to the swapchain whenever it changes.
+pub(crate) fn apply_hdr_metadata(metadata: Res<HdrMetadata>, render_instance: Res<RenderInstance>) {
+    let renderer = render_instance.0.as_ref();
+    let Some(hdr_metadata) = renderer.hdr_metadata.as_ref() else {
+        return;
+    };
+    if !metadata.is_changed() || !renderer.hdr_output() {
+        return;
+    }
+
+    let swapchains = [renderer.swapchain];
+    let metadata = metadata.to_vk();
+    unsafe {
+        (hdr_metadata.set_hdr_metadata_ext)(
+            renderer.device.handle(),
+            swapchains.len() as u32,
+            swapchains.as_ptr(),
+            &metadata,
+        )
+    };
+}
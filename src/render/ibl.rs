@@ -0,0 +1,521 @@
+use std::{mem::size_of, path::Path};
+
+use ash::vk;
+use bevy::prelude::info_span;
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::{Buffer, Image},
+    error::Result,
+};
+
+use super::{
+    barrier::{self, Usage},
+    passes::{compute::ComputePass, write_image_descriptors},
+    RenderAllocator, RenderInstance,
+};
+
+const CUBE_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const EQUIRECT_FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+
+#[derive(Clone, Copy, Debug)]
+pub struct IblSettings {
+    /// Face size of the skybox, which also gets a full mip chain.
+    pub skybox_size: u32,
+    pub irradiance_size: u32,
+    /// Face size of the first specular mip, every next mip is prefiltered for a higher
+    /// roughness, up to 1 at the last one.
+    pub specular_size: u32,
+    pub specular_mip_count: u32,
+    pub specular_sample_count: u32,
+    /// Angle in radians between the samples of the irradiance convolution.
+    pub irradiance_sample_delta: f32,
+}
+
+impl Default for IblSettings {
+    fn default() -> Self {
+        Self {
+            skybox_size: 512,
+            irradiance_size: 32,
+            specular_size: 128,
+            specular_mip_count: 5,
+            specular_sample_count: 512,
+            irradiance_sample_delta: 0.025,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct PrefilterConstants {
+    roughness: f32,
+    sample_count: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct IrradianceConstants {
+    sample_delta: f32,
+    lod: f32,
+}
+
+/// Cubemaps for image based lighting, baked from an HDR equirectangular image. All three are
+/// `R16G16B16A16_SFLOAT` with a `CUBE` view in `view` and are left in the
+/// `SHADER_READ_ONLY_OPTIMAL` layout, to be sampled as `textureCube` from fragment shaders.
+///
+/// Pick the specular mip with `roughness * (specular_mip_count - 1)`.
+#[derive(Debug)]
+pub struct Environment {
+    pub skybox: Image,
+    /// Cosine weighted irradiance, the diffuse term without the albedo.
+    pub irradiance: Image,
+    /// GGX prefiltered radiance, one roughness per mip.
+    pub specular: Image,
+    pub specular_mip_count: u32,
+}
+
+impl Environment {
+    /// Loads an equirectangular image like a `.hdr` file and bakes it.
+    pub fn load(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        path: impl AsRef<Path>,
+        settings: IblSettings,
+    ) -> Result<Self> {
+        let data = image::open(path)?.into_rgba32f();
+        let mut equirect = Image::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(EQUIRECT_FORMAT)
+                .extent(vk::Extent3D {
+                    width: data.width(),
+                    height: data.height(),
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?;
+        let mut staging = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size((data.len() * size_of::<f32>()) as u64)
+                .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+        staging.copy_from_slice(data.as_raw(), 0);
+
+        let environment = bake(
+            render_instance,
+            render_allocator,
+            &mut equirect,
+            Some(&staging),
+            settings,
+        );
+
+        staging.destroy(render_instance.device(), &mut render_allocator.allocator());
+        equirect.destroy(render_instance.device(), &mut render_allocator.allocator());
+        environment
+    }
+
+    /// Bakes from an equirectangular `equirect` created with `SAMPLED` usage, which has to be
+    /// in the `SHADER_READ_ONLY_OPTIMAL` layout. Blocks until the GPU is done.
+    pub fn from_equirect(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        equirect: &mut Image,
+        settings: IblSettings,
+    ) -> Result<Self> {
+        bake(render_instance, render_allocator, equirect, None, settings)
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.skybox.destroy(device, &mut allocator);
+        self.irradiance.destroy(device, &mut allocator);
+        self.specular.destroy(device, &mut allocator);
+    }
+}
+
+fn create_cube(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    size: u32,
+    mip_levels: u32,
+    extra_usage: vk::ImageUsageFlags,
+) -> Result<Image> {
+    let mut image = Image::new(
+        render_instance.device(),
+        &mut render_allocator.allocator(),
+        &vk::ImageCreateInfo::default()
+            .flags(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(CUBE_FORMAT)
+            .extent(vk::Extent3D {
+                width: size,
+                height: size,
+                depth: 1,
+            })
+            .mip_levels(mip_levels)
+            .array_layers(6)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | extra_usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+    )?;
+    image.set_view(create_view(
+        render_instance,
+        &image,
+        vk::ImageViewType::CUBE,
+        0,
+        mip_levels,
+    )?);
+    Ok(image)
+}
+
+fn create_view(
+    render_instance: &RenderInstance,
+    image: &Image,
+    view_type: vk::ImageViewType,
+    base_mip_level: u32,
+    level_count: u32,
+) -> Result<vk::ImageView> {
+    let view = unsafe {
+        render_instance.device().create_image_view(
+            &vk::ImageViewCreateInfo::default()
+                .image(image.image)
+                .view_type(view_type)
+                .format(image.format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level,
+                    level_count,
+                    base_array_layer: 0,
+                    layer_count: 6,
+                }),
+            None,
+        )?
+    };
+    Ok(view)
+}
+
+/// A compute pass reading `input` and writing all faces of one mip through `output`, following
+/// the binding layout of the shaders in `shader/ibl`.
+fn create_pass(
+    render_instance: &RenderInstance,
+    path: &str,
+    push_constant_size: u32,
+    input: vk::ImageView,
+    output: vk::ImageView,
+) -> Result<ComputePass> {
+    let pass = ComputePass::from_file(render_instance, path, push_constant_size)?;
+    write_image_descriptors(
+        render_instance,
+        &pass.pipeline.set_layout_info,
+        &pass.pipeline.descriptor_sets,
+        &[(0, input), (2, output)],
+    );
+    Ok(pass)
+}
+
+fn level_barrier(
+    image: &Image,
+    level: u32,
+    level_count: u32,
+    from: Usage,
+    to: Usage,
+) -> vk::ImageMemoryBarrier2<'static> {
+    let mut barrier = barrier::image_barrier(image.image, vk::ImageAspectFlags::COLOR, from, to);
+    barrier.subresource_range.base_mip_level = level;
+    barrier.subresource_range.level_count = level_count;
+    barrier
+}
+
+/// Converts `equirect` into the skybox and blits its mip chain, which the prefiltering reads
+/// from to keep the sample counts low. Everything is recorded into a single submission.
+fn bake(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    equirect: &mut Image,
+    upload: Option<&Buffer>,
+    settings: IblSettings,
+) -> Result<Environment> {
+    let _ = info_span!("Baking environment").entered();
+    let device = render_instance.device();
+    let renderer = render_instance.0.as_ref();
+    let skybox_mip_count = 32 - settings.skybox_size.leading_zeros();
+    let specular_mip_count = settings
+        .specular_mip_count
+        .min(32 - settings.specular_size.leading_zeros());
+
+    let skybox = create_cube(
+        render_instance,
+        render_allocator,
+        settings.skybox_size,
+        skybox_mip_count,
+        vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST,
+    )?;
+    let irradiance = create_cube(
+        render_instance,
+        render_allocator,
+        settings.irradiance_size,
+        1,
+        vk::ImageUsageFlags::empty(),
+    )?;
+    let specular = create_cube(
+        render_instance,
+        render_allocator,
+        settings.specular_size,
+        specular_mip_count,
+        vk::ImageUsageFlags::empty(),
+    )?;
+
+    // storage views of single mips, only needed while baking
+    let skybox_target = create_view(
+        render_instance,
+        &skybox,
+        vk::ImageViewType::TYPE_2D_ARRAY,
+        0,
+        1,
+    )?;
+    let irradiance_target = create_view(
+        render_instance,
+        &irradiance,
+        vk::ImageViewType::TYPE_2D_ARRAY,
+        0,
+        1,
+    )?;
+    let specular_targets = (0..specular_mip_count)
+        .map(|level| {
+            create_view(
+                render_instance,
+                &specular,
+                vk::ImageViewType::TYPE_2D_ARRAY,
+                level,
+                1,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let skybox_view = skybox.view.unwrap();
+    let equirect_pass = create_pass(
+        render_instance,
+        "./shader/ibl/equirect_to_cube.comp",
+        0,
+        equirect.create_view(device)?,
+        skybox_target,
+    )?;
+    let irradiance_pass = create_pass(
+        render_instance,
+        "./shader/ibl/irradiance.comp",
+        size_of::<IrradianceConstants>() as u32,
+        skybox_view,
+        irradiance_target,
+    )?;
+    let specular_passes = specular_targets
+        .iter()
+        .map(|target| {
+            create_pass(
+                render_instance,
+                "./shader/ibl/prefilter_specular.comp",
+                size_of::<PrefilterConstants>() as u32,
+                skybox_view,
+                *target,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    renderer.submit_setup_commands(|device, command_buffer| unsafe {
+        let pipeline_barrier = |barriers: &[vk::ImageMemoryBarrier2]| {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(barriers),
+            )
+        };
+
+        if let Some(staging) = upload {
+            pipeline_barrier(&[barrier::image_barrier(
+                equirect.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::Undefined,
+                Usage::TransferWrite,
+            )]);
+            device.cmd_copy_buffer_to_image(
+                command_buffer,
+                staging.buffer,
+                equirect.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::BufferImageCopy::default()
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(equirect.extent)],
+            );
+            pipeline_barrier(&[barrier::image_barrier(
+                equirect.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::TransferWrite,
+                Usage::ComputeSampled,
+            )]);
+        }
+
+        pipeline_barrier(&[
+            level_barrier(&skybox, 0, 1, Usage::Undefined, Usage::ComputeWrite),
+            barrier::image_barrier(
+                irradiance.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::Undefined,
+                Usage::ComputeWrite,
+            ),
+            barrier::image_barrier(
+                specular.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::Undefined,
+                Usage::ComputeWrite,
+            ),
+        ]);
+        equirect_pass.record(
+            render_instance,
+            command_buffer,
+            (settings.skybox_size, settings.skybox_size, 6),
+            &[],
+        );
+
+        pipeline_barrier(&[level_barrier(
+            &skybox,
+            0,
+            1,
+            Usage::ComputeWrite,
+            Usage::TransferRead,
+        )]);
+        for level in 1..skybox_mip_count {
+            pipeline_barrier(&[level_barrier(
+                &skybox,
+                level,
+                1,
+                Usage::Undefined,
+                Usage::TransferWrite,
+            )]);
+            let size = |level: u32| {
+                [
+                    vk::Offset3D::default(),
+                    vk::Offset3D {
+                        x: (settings.skybox_size >> level).max(1) as i32,
+                        y: (settings.skybox_size >> level).max(1) as i32,
+                        z: 1,
+                    },
+                ]
+            };
+            let subresource = |level: u32| vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: level,
+                base_array_layer: 0,
+                layer_count: 6,
+            };
+            device.cmd_blit_image(
+                command_buffer,
+                skybox.image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                skybox.image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::ImageBlit::default()
+                    .src_subresource(subresource(level - 1))
+                    .src_offsets(size(level - 1))
+                    .dst_subresource(subresource(level))
+                    .dst_offsets(size(level))],
+                vk::Filter::LINEAR,
+            );
+            pipeline_barrier(&[level_barrier(
+                &skybox,
+                level,
+                1,
+                Usage::TransferWrite,
+                Usage::TransferRead,
+            )]);
+        }
+        pipeline_barrier(&[level_barrier(
+            &skybox,
+            0,
+            skybox_mip_count,
+            Usage::TransferRead,
+            Usage::ComputeSampled,
+        )]);
+
+        // the diffuse term is smooth enough to be read from a 32x32 mip
+        let irradiance_lod = skybox_mip_count.saturating_sub(6) as f32;
+        irradiance_pass.record(
+            render_instance,
+            command_buffer,
+            (settings.irradiance_size, settings.irradiance_size, 6),
+            bytemuck::bytes_of(&IrradianceConstants {
+                sample_delta: settings.irradiance_sample_delta,
+                lod: irradiance_lod,
+            }),
+        );
+        for (level, pass) in specular_passes.iter().enumerate() {
+            let size = (settings.specular_size >> level).max(1);
+            let roughness = level as f32 / (specular_mip_count - 1).max(1) as f32;
+            pass.record(
+                render_instance,
+                command_buffer,
+                (size, size, 6),
+                bytemuck::bytes_of(&PrefilterConstants {
+                    roughness,
+                    sample_count: settings.specular_sample_count,
+                }),
+            );
+        }
+
+        pipeline_barrier(&[
+            barrier::image_barrier(
+                skybox.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::ComputeSampled,
+                Usage::FragmentSampled,
+            ),
+            barrier::image_barrier(
+                irradiance.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::ComputeWrite,
+                Usage::FragmentSampled,
+            ),
+            barrier::image_barrier(
+                specular.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::ComputeWrite,
+                Usage::FragmentSampled,
+            ),
+        ]);
+    })?;
+
+    unsafe {
+        for view in [skybox_target, irradiance_target]
+            .into_iter()
+            .chain(specular_targets)
+        {
+            device.destroy_image_view(view, None);
+        }
+    }
+
+    Ok(Environment {
+        skybox,
+        irradiance,
+        specular,
+        specular_mip_count,
+    })
+}
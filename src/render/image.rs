@@ -39,6 +39,7 @@ impl AssetLoader for ImageTextureLoader {
                     texel_filter: vk::Filter::LINEAR,
                     mipmap_mode: vk::SamplerMipmapMode::LINEAR,
                     address_modes: vk::SamplerAddressMode::REPEAT,
+                    compare: false,
                 },
             };
 
@@ -0,0 +1,140 @@
+use std::{marker::PhantomData, mem::size_of};
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{GpuMesh, RenderAllocator, RenderInstance};
+
+/// Per instance data that can be read as vertex attributes, every attribute is a
+/// `(format, offset)` pair that gets the next free location after the mesh attributes.
+pub trait InstanceData: bytemuck::Pod {
+    fn attributes() -> Vec<(vk::Format, u32)>;
+}
+
+/// A model matrix per instance, which takes up four locations.
+impl InstanceData for Mat4 {
+    fn attributes() -> Vec<(vk::Format, u32)> {
+        (0..4)
+            .map(|column| (vk::Format::R32G32B32A32_SFLOAT, column * 16))
+            .collect()
+    }
+}
+
+/// Host visible buffer with one `T` per instance, bound as vertex binding 1 with the
+/// `INSTANCE` input rate.
+#[derive(Debug)]
+pub struct InstanceBuffer<T: InstanceData> {
+    pub buffer: Buffer,
+    capacity: u32,
+    len: u32,
+    _marker: PhantomData<T>,
+}
+
+impl<T: InstanceData> InstanceBuffer<T> {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        capacity: u32,
+    ) -> Result<Self> {
+        let buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size((capacity as usize * size_of::<T>()) as u64)
+                .usage(vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+
+        Ok(Self {
+            buffer,
+            capacity,
+            len: 0,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Replaces all instances, don't call this while a previous frame may still be reading them.
+    pub fn write(&mut self, instances: &[T]) {
+        assert!(
+            instances.len() <= self.capacity as usize,
+            "InstanceBuffer can hold {} instances, tried to write {}",
+            self.capacity,
+            instances.len()
+        );
+        self.buffer.copy_from_slice(instances, 0);
+        self.len = instances.len() as u32;
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.buffer
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+    }
+}
+
+/// Owned vertex bindings and attributes, since the pipeline create info only borrows them.
+#[derive(Debug, Default)]
+pub struct VertexInputLayout {
+    pub bindings: Vec<vk::VertexInputBindingDescription>,
+    pub attributes: Vec<vk::VertexInputAttributeDescription>,
+}
+
+impl VertexInputLayout {
+    /// Mesh vertices at binding 0 and locations 0 to 4.
+    pub fn mesh() -> Self {
+        Self {
+            bindings: vec![GpuMesh::vertex_binding_descriptors()],
+            attributes: GpuMesh::vertex_input_descriptors().to_vec(),
+        }
+    }
+
+    /// Mesh vertices at binding 0 followed by the attributes of `T` at binding 1,
+    /// so a `Mat4` ends up at locations 5 to 8.
+    pub fn instanced<T: InstanceData>() -> Self {
+        let mut layout = Self::mesh();
+        let first_location = layout.attributes.len() as u32;
+
+        layout.bindings.push(
+            vk::VertexInputBindingDescription::default()
+                .binding(1)
+                .input_rate(vk::VertexInputRate::INSTANCE)
+                .stride(size_of::<T>() as u32),
+        );
+        layout
+            .attributes
+            .extend(
+                T::attributes()
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, (format, offset))| {
+                        vk::VertexInputAttributeDescription::default()
+                            .binding(1)
+                            .location(first_location + index as u32)
+                            .format(format)
+                            .offset(offset)
+                    }),
+            );
+
+        layout
+    }
+
+    pub fn create_info(&self) -> vk::PipelineVertexInputStateCreateInfo<'_> {
+        vk::PipelineVertexInputStateCreateInfo::default()
+            .vertex_binding_descriptions(&self.bindings)
+            .vertex_attribute_descriptions(&self.attributes)
+    }
+}
@@ -0,0 +1,377 @@
+#[cfg(unix)]
+use ash::extensions::khr::{ExternalMemoryFd, ExternalSemaphoreFd};
+#[cfg(windows)]
+use ash::extensions::khr::{ExternalMemoryWin32, ExternalSemaphoreWin32};
+use ash::vk;
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::{Buffer, Image},
+    ctx::find_memorytype_index,
+    error::{Error, Result},
+};
+
+use super::{
+    deferred_destroy::{self, DeferredResource},
+    RenderInstance,
+};
+
+/// An exported handle, imported on the other side with `cudaImportExternalMemory` or
+/// `cudaImportExternalSemaphore` as an opaque fd or win32 handle. Ownership of an fd moves to
+/// whoever imports it.
+#[cfg(unix)]
+pub type ExternalHandle = std::os::raw::c_int;
+#[cfg(windows)]
+pub type ExternalHandle = vk::HANDLE;
+
+#[cfg(unix)]
+const MEMORY_HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags =
+    vk::ExternalMemoryHandleTypeFlags::OPAQUE_FD;
+#[cfg(windows)]
+const MEMORY_HANDLE_TYPE: vk::ExternalMemoryHandleTypeFlags =
+    vk::ExternalMemoryHandleTypeFlags::OPAQUE_WIN32;
+
+#[cfg(unix)]
+const SEMAPHORE_HANDLE_TYPE: vk::ExternalSemaphoreHandleTypeFlags =
+    vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_FD;
+#[cfg(windows)]
+const SEMAPHORE_HANDLE_TYPE: vk::ExternalSemaphoreHandleTypeFlags =
+    vk::ExternalSemaphoreHandleTypeFlags::OPAQUE_WIN32;
+
+fn check_supported(render_instance: &RenderInstance) -> Result<()> {
+    if !render_instance.0.supports_external_interop {
+        return Err(Error::Unsupported(
+            "Device doesn't support exporting memory and timeline semaphores".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+unsafe fn export_memory(
+    render_instance: &RenderInstance,
+    memory: vk::DeviceMemory,
+) -> Result<ExternalHandle> {
+    ExternalMemoryFd::new(&render_instance.0.instance, render_instance.device())
+        .get_memory_fd(
+            &vk::MemoryGetFdInfoKHR::default()
+                .memory(memory)
+                .handle_type(MEMORY_HANDLE_TYPE),
+        )
+        .map_err(Error::from)
+}
+
+#[cfg(windows)]
+unsafe fn export_memory(
+    render_instance: &RenderInstance,
+    memory: vk::DeviceMemory,
+) -> Result<ExternalHandle> {
+    ExternalMemoryWin32::new(&render_instance.0.instance, render_instance.device())
+        .get_memory_win32_handle(
+            &vk::MemoryGetWin32HandleInfoKHR::default()
+                .memory(memory)
+                .handle_type(MEMORY_HANDLE_TYPE),
+        )
+        .map_err(Error::from)
+}
+
+/// Dedicated, exportable device local memory for `requirements`. CUDA maps the whole
+/// allocation, so resources never share it.
+unsafe fn allocate_exportable(
+    render_instance: &RenderInstance,
+    requirements: vk::MemoryRequirements,
+    dedicated: vk::MemoryDedicatedAllocateInfo,
+    device_address: bool,
+) -> Result<vk::DeviceMemory> {
+    let memory_type_index = find_memorytype_index(
+        &requirements,
+        &render_instance.0.device_memory_properties,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )
+    .ok_or_else(|| {
+        Error::Unsupported("Unable to find suitable memory index for exported memory.".to_string())
+    })?;
+
+    let mut dedicated = dedicated;
+    let mut export_info = vk::ExportMemoryAllocateInfo::default().handle_types(MEMORY_HANDLE_TYPE);
+    let mut flags_info =
+        vk::MemoryAllocateFlagsInfo::default().flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS);
+    let mut allocate_info = vk::MemoryAllocateInfo::default()
+        .allocation_size(requirements.size)
+        .memory_type_index(memory_type_index)
+        .push_next(&mut export_info)
+        .push_next(&mut dedicated);
+    if device_address {
+        allocate_info = allocate_info.push_next(&mut flags_info);
+    }
+
+    Ok(render_instance
+        .device()
+        .allocate_memory(&allocate_info, None)?)
+}
+
+/// A buffer in its own exportable allocation, to share with CUDA or OptiX kernels.
+#[derive(Debug)]
+pub struct SharedBuffer {
+    /// Doesn't own its memory, so it can be used like any other buffer of the renderer.
+    pub buffer: Buffer,
+    pub memory: vk::DeviceMemory,
+    /// Size of the allocation, which CUDA wants when importing it.
+    pub allocation_size: u64,
+}
+
+impl SharedBuffer {
+    pub fn new(
+        render_instance: &RenderInstance,
+        size: u64,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<Self> {
+        check_supported(render_instance)?;
+        let device = render_instance.device();
+        let usage = usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS;
+
+        unsafe {
+            let buffer = device.create_buffer(
+                &vk::BufferCreateInfo::default()
+                    .size(size)
+                    .usage(usage)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .push_next(
+                        &mut vk::ExternalMemoryBufferCreateInfo::default()
+                            .handle_types(MEMORY_HANDLE_TYPE),
+                    ),
+                None,
+            )?;
+            let requirements = device.get_buffer_memory_requirements(buffer);
+            let memory = allocate_exportable(
+                render_instance,
+                requirements,
+                vk::MemoryDedicatedAllocateInfo::default().buffer(buffer),
+                true,
+            )?;
+            device.bind_buffer_memory(buffer, memory, 0)?;
+            let device_addr = device
+                .get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(buffer));
+
+            Ok(Self {
+                buffer: Buffer {
+                    buffer,
+                    allocation: None,
+                    size,
+                    usage,
+                    device_addr,
+                    has_been_written_to: false,
+                    offset: 0,
+                    location: MemoryLocation::GpuOnly,
+                    rebar: false,
+                },
+                memory,
+                allocation_size: requirements.size,
+            })
+        }
+    }
+
+    /// Exports the memory, every call returns a new handle.
+    pub fn export(&self, render_instance: &RenderInstance) -> Result<ExternalHandle> {
+        unsafe { export_memory(render_instance, self.memory) }
+    }
+
+    pub fn destroy(&mut self, render_instance: &RenderInstance) {
+        let device = render_instance.device();
+        unsafe {
+            device.destroy_buffer(std::mem::take(&mut self.buffer.buffer), None);
+            device.free_memory(std::mem::take(&mut self.memory), None);
+        }
+    }
+}
+
+impl Drop for SharedBuffer {
+    fn drop(&mut self) {
+        // the buffer queues itself, its memory isn't owned by the allocator
+        if self.memory != vk::DeviceMemory::null() {
+            deferred_destroy::release(DeferredResource::Memory(std::mem::take(&mut self.memory)));
+        }
+    }
+}
+
+/// An image in its own exportable allocation. CUDA imports it as a mipmapped array, which
+/// requires optimal tiling on the Vulkan side.
+#[derive(Debug)]
+pub struct SharedImage {
+    /// Doesn't own its memory, so it can be used like any other image of the renderer.
+    pub image: Image,
+    pub memory: vk::DeviceMemory,
+    pub allocation_size: u64,
+}
+
+impl SharedImage {
+    pub fn new(render_instance: &RenderInstance, image_info: &vk::ImageCreateInfo) -> Result<Self> {
+        check_supported(render_instance)?;
+        let device = render_instance.device();
+
+        unsafe {
+            let mut external_info =
+                vk::ExternalMemoryImageCreateInfo::default().handle_types(MEMORY_HANDLE_TYPE);
+            let image_info = image_info.clone().push_next(&mut external_info);
+            let image = device.create_image(&image_info, None)?;
+            let requirements = device.get_image_memory_requirements(image);
+            let memory = allocate_exportable(
+                render_instance,
+                requirements,
+                vk::MemoryDedicatedAllocateInfo::default().image(image),
+                false,
+            )?;
+            device.bind_image_memory(image, memory, 0)?;
+
+            Ok(Self {
+                image: Image {
+                    image,
+                    allocation: None,
+                    view: None,
+                    format: image_info.format,
+                    extent: image_info.extent,
+                    offset: 0,
+                },
+                memory,
+                allocation_size: requirements.size,
+            })
+        }
+    }
+
+    pub fn export(&self, render_instance: &RenderInstance) -> Result<ExternalHandle> {
+        unsafe { export_memory(render_instance, self.memory) }
+    }
+
+    pub fn destroy(&mut self, render_instance: &RenderInstance) {
+        let device = render_instance.device();
+        unsafe {
+            if let Some(view) = self.image.view.take() {
+                device.destroy_image_view(view, None);
+            }
+            device.destroy_image(std::mem::take(&mut self.image.image), None);
+            device.free_memory(std::mem::take(&mut self.memory), None);
+        }
+    }
+}
+
+impl Drop for SharedImage {
+    fn drop(&mut self) {
+        if self.memory != vk::DeviceMemory::null() {
+            deferred_destroy::release(DeferredResource::Memory(std::mem::take(&mut self.memory)));
+        }
+    }
+}
+
+/// An exportable timeline semaphore that orders work between the renderer and CUDA. Each side
+/// waits for the value the other side signals after producing a resource, so no fences or
+/// device idles are needed in between.
+#[derive(Debug)]
+pub struct SharedSemaphore {
+    pub semaphore: vk::Semaphore,
+    /// Last value handed out by [`SharedSemaphore::next_value`].
+    pub value: u64,
+}
+
+impl SharedSemaphore {
+    pub fn new(render_instance: &RenderInstance) -> Result<Self> {
+        check_supported(render_instance)?;
+        let semaphore = unsafe {
+            render_instance.device().create_semaphore(
+                &vk::SemaphoreCreateInfo::default()
+                    .push_next(
+                        &mut vk::SemaphoreTypeCreateInfo::default()
+                            .semaphore_type(vk::SemaphoreType::TIMELINE)
+                            .initial_value(0),
+                    )
+                    .push_next(
+                        &mut vk::ExportSemaphoreCreateInfo::default()
+                            .handle_types(SEMAPHORE_HANDLE_TYPE),
+                    ),
+                None,
+            )?
+        };
+
+        Ok(Self {
+            semaphore,
+            value: 0,
+        })
+    }
+
+    #[cfg(unix)]
+    pub fn export(&self, render_instance: &RenderInstance) -> Result<ExternalHandle> {
+        unsafe {
+            ExternalSemaphoreFd::new(&render_instance.0.instance, render_instance.device())
+                .get_semaphore_fd(
+                    &vk::SemaphoreGetFdInfoKHR::default()
+                        .semaphore(self.semaphore)
+                        .handle_type(SEMAPHORE_HANDLE_TYPE),
+                )
+                .map_err(Error::from)
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn export(&self, render_instance: &RenderInstance) -> Result<ExternalHandle> {
+        unsafe {
+            ExternalSemaphoreWin32::new(&render_instance.0.instance, render_instance.device())
+                .get_semaphore_win32_handle(
+                    &vk::SemaphoreGetWin32HandleInfoKHR::default()
+                        .semaphore(self.semaphore)
+                        .handle_type(SEMAPHORE_HANDLE_TYPE),
+                )
+                .map_err(Error::from)
+        }
+    }
+
+    /// Reserves the next value to signal, from either side of the interop.
+    pub fn next_value(&mut self) -> u64 {
+        self.value += 1;
+        self.value
+    }
+
+    /// Waits for or signals `value` at `stage`, in the wait or signal semaphore infos of a
+    /// `queue_submit2`.
+    pub fn submit_info(
+        &self,
+        value: u64,
+        stage: vk::PipelineStageFlags2,
+    ) -> vk::SemaphoreSubmitInfo<'static> {
+        vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.semaphore)
+            .value(value)
+            .stage_mask(stage)
+    }
+
+    /// Blocks the CPU until `value` has been signaled.
+    pub fn wait(&self, render_instance: &RenderInstance, value: u64) -> Result<()> {
+        let semaphores = [self.semaphore];
+        let values = [value];
+        unsafe {
+            render_instance.device().wait_semaphores(
+                &vk::SemaphoreWaitInfo::default()
+                    .semaphores(&semaphores)
+                    .values(&values),
+                u64::MAX,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Value of the last signal that completed.
+    pub fn completed_value(&self, render_instance: &RenderInstance) -> Result<u64> {
+        let value = unsafe {
+            render_instance
+                .device()
+                .get_semaphore_counter_value(self.semaphore)?
+        };
+        Ok(value)
+    }
+
+    pub fn destroy(&mut self, render_instance: &RenderInstance) {
+        unsafe {
+            render_instance
+                .device()
+                .destroy_semaphore(self.semaphore, None)
+        };
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, mem::size_of};
+
+use ash::vk;
+use bevy::{asset::HandleId, prelude::*};
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{
+    global_descriptors::GlobalDescriptorSet,
+    image::Image,
+    material::{Material, MaterialUniform},
+    streaming::TextureStreamer,
+    RenderAllocator, RenderInstance,
+};
+
+/// Room the table starts out with, it doubles whenever it's full.
+const INITIAL_CAPACITY: usize = 64;
+
+/// Index of a material in the [`MaterialTable`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialId(pub u32);
+
+/// The parameters of every material in one buffer, addressed by [`MaterialId`]. Textures are
+/// referenced by their index in the bindless array of the [`GlobalDescriptorSet`], so a draw
+/// only needs the address of its material.
+#[derive(Resource, Default)]
+pub struct MaterialTable {
+    buffer: Option<Buffer>,
+    materials: Vec<MaterialUniform>,
+    free: Vec<MaterialId>,
+    /// Materials created for a [`Material`] asset.
+    assets: HashMap<HandleId, MaterialId>,
+}
+
+impl MaterialTable {
+    pub fn create(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        material: MaterialUniform,
+    ) -> Result<MaterialId> {
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.materials.push(MaterialUniform::default());
+                MaterialId(self.materials.len() as u32 - 1)
+            }
+        };
+        let capacity = self.buffer.as_ref().map_or(0, |buffer| {
+            buffer.size as usize / size_of::<MaterialUniform>()
+        });
+        if self.materials.len() > capacity {
+            self.grow(render_instance, render_allocator)?;
+        }
+        self.update(id, material);
+        Ok(id)
+    }
+
+    pub fn update(&mut self, id: MaterialId, material: MaterialUniform) {
+        self.materials[id.0 as usize] = material;
+        self.buffer
+            .as_mut()
+            .unwrap()
+            .copy_from_slice(&[material], id.0 as usize * size_of::<MaterialUniform>());
+    }
+
+    /// The slot is reused by the next material that is created.
+    pub fn remove(&mut self, id: MaterialId) {
+        assert!(!self.free.contains(&id), "Material was already removed");
+        self.free.push(id);
+    }
+
+    pub fn get(&self, id: MaterialId) -> &MaterialUniform {
+        &self.materials[id.0 as usize]
+    }
+
+    /// Address of the material, for a `Material` buffer reference in a shader.
+    pub fn device_address(&self, id: MaterialId) -> u64 {
+        self.buffer.as_ref().unwrap().device_addr
+            + id.0 as u64 * size_of::<MaterialUniform>() as u64
+    }
+
+    /// Material of a [`Material`] asset.
+    pub fn asset_id(&self, key: HandleId) -> Option<MaterialId> {
+        self.assets.get(&key).copied()
+    }
+
+    /// Creates or updates the material of an asset, registering its loaded textures. Textures
+    /// that are still loading are left out, insert the material again once they're loaded.
+    pub fn insert_asset(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        global_descriptors: &mut GlobalDescriptorSet,
+        streamer: &mut TextureStreamer,
+        key: HandleId,
+        material: &Material,
+        texture_assets: &Assets<Image>,
+    ) -> Result<MaterialId> {
+        let mut texture_index = |texture: &Option<Handle<Image>>| -> Result<i32> {
+            let Some(handle) = texture else {
+                return Ok(-1);
+            };
+            let Some(image) = texture_assets.get(handle) else {
+                return Ok(-1);
+            };
+            let index = global_descriptors.register_texture(
+                render_instance,
+                render_allocator,
+                streamer,
+                handle,
+                image,
+            )?;
+            Ok(index as i32)
+        };
+
+        let uniform = MaterialUniform {
+            base_color_texture_index: texture_index(&material.base_color_texture)?,
+            emissive_texture_index: texture_index(&material.emissive_texture)?,
+            metallic_roughness_texture_index: texture_index(&material.metallic_roughness_texture)?,
+            normal_map_texture_index: texture_index(&material.normal_map_texture)?,
+            occlusion_texture_index: texture_index(&material.occlusion_texture)?,
+            ..MaterialUniform::from_material(material)
+        };
+
+        match self.asset_id(key) {
+            Some(id) => {
+                self.update(id, uniform);
+                Ok(id)
+            }
+            None => {
+                let id = self.create(render_instance, render_allocator, uniform)?;
+                self.assets.insert(key, id);
+                Ok(id)
+            }
+        }
+    }
+
+    pub fn remove_asset(&mut self, key: HandleId) {
+        if let Some(id) = self.assets.remove(&key) {
+            self.remove(id);
+        }
+    }
+
+    /// Moves the materials to a buffer twice the size, the old one is destroyed once the frames
+    /// in flight are done with it.
+    fn grow(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<()> {
+        let capacity = (self.materials.len() * 2).max(INITIAL_CAPACITY);
+        let mut buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size((capacity * size_of::<MaterialUniform>()) as u64)
+                .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+        buffer.set_name("material table");
+        buffer.copy_from_slice(&self.materials, 0);
+        self.buffer = Some(buffer);
+        Ok(())
+    }
+}
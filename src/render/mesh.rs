@@ -13,12 +13,23 @@ pub struct Mesh {
     pub indices: Vec<u32>,
 }
 
+/// Interleaved vertex layout of all meshes, 64 bytes per vertex with the attributes at the
+/// shader locations 0 to 4 in field order:
+///
+/// | location | attribute  | format                | offset |
+/// |----------|------------|-----------------------|--------|
+/// | 0        | `position` | `R32G32B32_SFLOAT`    | 0      |
+/// | 1        | `normal`   | `R32G32B32_SFLOAT`    | 12     |
+/// | 2        | `uv`       | `R32G32_SFLOAT`       | 24     |
+/// | 3        | `tangent`  | `R32G32B32_SFLOAT`    | 32     |
+/// | 4        | `color`    | `R32G32B32A32_SFLOAT` | 44     |
 #[repr(C, align(16))]
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
     pub position: [f32; 3],
     pub normal: [f32; 3],
     pub uv: [f32; 2],
     pub tangent: [f32; 3],
     pub color: [f32; 4],
+    pub _padding: f32,
 }
@@ -0,0 +1,507 @@
+use std::path::Path;
+
+use ash::vk;
+use bevy::math::Vec3;
+use thiserror::Error;
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{
+    mesh::{Mesh, Vertex},
+    RenderAllocator, RenderInstance,
+};
+
+#[derive(Error, Debug)]
+pub enum MeshImportError {
+    #[error("failed to read mesh file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid OBJ file: {0}")]
+    Obj(#[from] tobj::LoadError),
+    #[error("invalid PLY file: {0}")]
+    Ply(String),
+    #[error("unsupported mesh file extension: {0:?}")]
+    UnsupportedExtension(Option<String>),
+}
+
+fn ply_error<T>(message: impl Into<String>) -> Result<T, MeshImportError> {
+    Err(MeshImportError::Ply(message.into()))
+}
+
+/// Loads an `.obj` or `.ply` file into a single triangle list, picking the format by extension.
+pub fn load_mesh(path: impl AsRef<Path>) -> Result<Mesh, MeshImportError> {
+    let path = path.as_ref();
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("obj") => load_obj(path),
+        Some("ply") => load_ply(path),
+        extension => Err(MeshImportError::UnsupportedExtension(
+            extension.map(str::to_owned),
+        )),
+    }
+}
+
+/// Loads all models of an OBJ file into one mesh, polygons are triangulated and materials are
+/// ignored.
+pub fn load_obj(path: impl AsRef<Path>) -> Result<Mesh, MeshImportError> {
+    let (models, _) = tobj::load_obj(path.as_ref(), &tobj::GPU_LOAD_OPTIONS)?;
+
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    for model in models.iter() {
+        let mesh = &model.mesh;
+        let base = vertices.len();
+        vertices.extend((0..mesh.positions.len() / 3).map(|i| {
+            Vertex {
+                position: [
+                    mesh.positions[i * 3],
+                    mesh.positions[i * 3 + 1],
+                    mesh.positions[i * 3 + 2],
+                ],
+                normal: mesh
+                    .normals
+                    .get(i * 3..i * 3 + 3)
+                    .map_or([0.0; 3], |normal| [normal[0], normal[1], normal[2]]),
+                // OBJ has its origin in the bottom left of the texture
+                uv: mesh
+                    .texcoords
+                    .get(i * 2..i * 2 + 2)
+                    .map_or([0.0; 2], |uv| [uv[0], 1.0 - uv[1]]),
+                color: mesh
+                    .vertex_color
+                    .get(i * 3..i * 3 + 3)
+                    .map_or([1.0; 4], |color| [color[0], color[1], color[2], 1.0]),
+                ..Default::default()
+            }
+        }));
+        // models without normals get smooth ones, the others keep their own
+        if mesh.normals.is_empty() {
+            compute_normals(&mut vertices[base..], &mesh.indices);
+        }
+        indices.extend(mesh.indices.iter().map(|index| base as u32 + index));
+    }
+
+    Ok(Mesh {
+        primitive_topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+        vertices,
+        indices,
+    })
+}
+
+pub fn load_ply(path: impl AsRef<Path>) -> Result<Mesh, MeshImportError> {
+    parse_ply(&std::fs::read(path)?)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlyScalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyScalar {
+    fn parse(name: &str) -> Result<Self, MeshImportError> {
+        Ok(match name {
+            "char" | "int8" => PlyScalar::I8,
+            "uchar" | "uint8" => PlyScalar::U8,
+            "short" | "int16" => PlyScalar::I16,
+            "ushort" | "uint16" => PlyScalar::U16,
+            "int" | "int32" => PlyScalar::I32,
+            "uint" | "uint32" => PlyScalar::U32,
+            "float" | "float32" => PlyScalar::F32,
+            "double" | "float64" => PlyScalar::F64,
+            _ => return ply_error(format!("unknown property type {}", name)),
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            PlyScalar::I8 | PlyScalar::U8 => 1,
+            PlyScalar::I16 | PlyScalar::U16 => 2,
+            PlyScalar::I32 | PlyScalar::U32 | PlyScalar::F32 => 4,
+            PlyScalar::F64 => 8,
+        }
+    }
+
+    /// Integer colors use the full range of their type.
+    fn color_scale(self) -> f64 {
+        match self {
+            PlyScalar::U8 => 255.0,
+            PlyScalar::U16 => 65535.0,
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum PlyProperty {
+    Scalar(PlyScalar),
+    List { count: PlyScalar, item: PlyScalar },
+}
+
+#[derive(Debug)]
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<(String, PlyProperty)>,
+}
+
+enum PlyValues<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { bytes: &'a [u8], big_endian: bool },
+}
+
+impl PlyValues<'_> {
+    fn next(&mut self, scalar: PlyScalar) -> Result<f64, MeshImportError> {
+        match self {
+            PlyValues::Ascii(tokens) => {
+                let Some(token) = tokens.next() else {
+                    return ply_error("unexpected end of data");
+                };
+                token
+                    .parse::<f64>()
+                    .or_else(|_| ply_error(format!("invalid value {}", token)))
+            }
+            PlyValues::Binary { bytes, big_endian } => {
+                if bytes.len() < scalar.size() {
+                    return ply_error("unexpected end of data");
+                }
+                let (value, rest) = bytes.split_at(scalar.size());
+                *bytes = rest;
+                macro_rules! read {
+                    ($ty:ty) => {{
+                        let value = value.try_into().unwrap();
+                        (if *big_endian {
+                            <$ty>::from_be_bytes(value)
+                        } else {
+                            <$ty>::from_le_bytes(value)
+                        }) as f64
+                    }};
+                }
+                Ok(match scalar {
+                    PlyScalar::I8 => read!(i8),
+                    PlyScalar::U8 => read!(u8),
+                    PlyScalar::I16 => read!(i16),
+                    PlyScalar::U16 => read!(u16),
+                    PlyScalar::I32 => read!(i32),
+                    PlyScalar::U32 => read!(u32),
+                    PlyScalar::F32 => read!(f32),
+                    PlyScalar::F64 => read!(f64),
+                })
+            }
+        }
+    }
+}
+
+/// Parses ASCII and binary PLY files. Vertices take their position, normal, texture coordinate
+/// and color properties, faces are triangulated as fans and other elements are skipped.
+pub fn parse_ply(bytes: &[u8]) -> Result<Mesh, MeshImportError> {
+    const END_HEADER: &[u8] = b"end_header";
+    let Some(header_end) = bytes
+        .windows(END_HEADER.len())
+        .position(|window| window == END_HEADER)
+    else {
+        return ply_error("missing end_header");
+    };
+    let Ok(header) = std::str::from_utf8(&bytes[..header_end]) else {
+        return ply_error("header isn't valid UTF-8");
+    };
+    // the body starts after the line break that ends the header
+    let mut body = &bytes[header_end + END_HEADER.len()..];
+    if body.first() == Some(&b'\r') {
+        body = &body[1..];
+    }
+    if body.first() == Some(&b'\n') {
+        body = &body[1..];
+    }
+
+    let mut lines = header.lines().map(str::trim);
+    if lines.next() != Some("ply") {
+        return ply_error("missing ply magic");
+    }
+    let mut values = None;
+    let mut elements: Vec<PlyElement> = vec![];
+    for line in lines {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["format", format, _] => {
+                values = Some(match *format {
+                    "ascii" => {
+                        let Ok(body) = std::str::from_utf8(body) else {
+                            return ply_error("ASCII data isn't valid UTF-8");
+                        };
+                        PlyValues::Ascii(body.split_ascii_whitespace())
+                    }
+                    "binary_little_endian" => PlyValues::Binary {
+                        bytes: body,
+                        big_endian: false,
+                    },
+                    "binary_big_endian" => PlyValues::Binary {
+                        bytes: body,
+                        big_endian: true,
+                    },
+                    _ => return ply_error(format!("unknown format {}", format)),
+                })
+            }
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count
+                    .parse()
+                    .or_else(|_| ply_error(format!("invalid element count {}", count)))?,
+                properties: vec![],
+            }),
+            ["property", "list", count, item, name] => {
+                let Some(element) = elements.last_mut() else {
+                    return ply_error("property outside of an element");
+                };
+                element.properties.push((
+                    name.to_string(),
+                    PlyProperty::List {
+                        count: PlyScalar::parse(count)?,
+                        item: PlyScalar::parse(item)?,
+                    },
+                ));
+            }
+            ["property", scalar, name] => {
+                let Some(element) = elements.last_mut() else {
+                    return ply_error("property outside of an element");
+                };
+                element.properties.push((
+                    name.to_string(),
+                    PlyProperty::Scalar(PlyScalar::parse(scalar)?),
+                ));
+            }
+            ["comment", ..] | ["obj_info", ..] | [] => {}
+            _ => return ply_error(format!("unexpected header line {}", line)),
+        }
+    }
+    let Some(mut values) = values else {
+        return ply_error("missing format");
+    };
+
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    let mut has_normals = false;
+    for element in elements.iter() {
+        for _ in 0..element.count {
+            let mut vertex = Vertex {
+                color: [1.0; 4],
+                ..Default::default()
+            };
+            for (name, property) in element.properties.iter() {
+                match *property {
+                    PlyProperty::Scalar(scalar) => {
+                        let value = values.next(scalar)?;
+                        if element.name != "vertex" {
+                            continue;
+                        }
+                        let value = value as f32;
+                        match name.as_str() {
+                            "x" => vertex.position[0] = value,
+                            "y" => vertex.position[1] = value,
+                            "z" => vertex.position[2] = value,
+                            "nx" => vertex.normal[0] = value,
+                            "ny" => vertex.normal[1] = value,
+                            "nz" => vertex.normal[2] = value,
+                            "u" | "s" | "texture_u" | "texture_s" => vertex.uv[0] = value,
+                            "v" | "t" | "texture_v" | "texture_t" => vertex.uv[1] = value,
+                            "red" | "green" | "blue" | "alpha" => {
+                                let channel = ["red", "green", "blue", "alpha"]
+                                    .iter()
+                                    .position(|channel| channel == name)
+                                    .unwrap();
+                                vertex.color[channel] = value / scalar.color_scale() as f32;
+                            }
+                            _ => {}
+                        }
+                        has_normals |= name == "nx";
+                    }
+                    PlyProperty::List { count, item } => {
+                        let count = values.next(count)? as usize;
+                        // the count comes from the file, so it can't size the allocation
+                        let mut face = vec![];
+                        for _ in 0..count {
+                            face.push(values.next(item)? as u32);
+                        }
+                        if element.name == "face"
+                            && (name == "vertex_indices" || name == "vertex_index")
+                        {
+                            for i in 1..face.len().saturating_sub(1) {
+                                indices.extend([face[0], face[i], face[i + 1]]);
+                            }
+                        }
+                    }
+                }
+            }
+            if element.name == "vertex" {
+                vertices.push(vertex);
+            }
+        }
+    }
+
+    if let Some(index) = indices
+        .iter()
+        .find(|index| **index as usize >= vertices.len())
+    {
+        return ply_error(format!("face references missing vertex {}", index));
+    }
+    if !has_normals {
+        compute_normals(&mut vertices, &indices);
+    }
+    Ok(Mesh {
+        primitive_topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+        vertices,
+        indices,
+    })
+}
+
+/// Smooth normals, the area weighted average of the normals of the triangles around a vertex.
+fn compute_normals(vertices: &mut [Vertex], indices: &[u32]) {
+    let mut normals = vec![Vec3::ZERO; vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(vertices[triangle[i] as usize].position));
+        let normal = (b - a).cross(c - a);
+        for index in triangle {
+            normals[*index as usize] += normal;
+        }
+    }
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        vertex.normal = normal.normalize_or_zero().into();
+    }
+}
+
+/// Vertex and index buffers of an imported [`Mesh`]. The vertices are interleaved in the
+/// [`Vertex`] layout, which the vertex input descriptors of the mesh pipelines expect.
+#[derive(Debug)]
+pub struct MeshBuffers {
+    pub vertex_buffer: Buffer,
+    pub index_buffer: Buffer,
+    pub vertex_count: u32,
+    pub index_count: u32,
+}
+
+impl MeshBuffers {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        mesh: &Mesh,
+    ) -> Result<Self> {
+        let mut vertex_buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(std::mem::size_of_val(mesh.vertices.as_slice()) as u64)
+                .usage(vk::BufferUsageFlags::VERTEX_BUFFER)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+        vertex_buffer.copy_from_slice(&mesh.vertices, 0);
+
+        let mut index_buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(std::mem::size_of_val(mesh.indices.as_slice()) as u64)
+                .usage(vk::BufferUsageFlags::INDEX_BUFFER)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+        index_buffer.copy_from_slice(&mesh.indices, 0);
+
+        Ok(Self {
+            vertex_buffer,
+            index_buffer,
+            vertex_count: mesh.vertices.len() as u32,
+            index_count: mesh.indices.len() as u32,
+        })
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.vertex_buffer
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+        self.index_buffer
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ascii_ply() {
+        let ply = b"ply
+format ascii 1.0
+comment a unit quad
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+1 0 0 255 0 0
+1 1 0 255 0 0
+0 1 0 255 0 0
+4 0 1 2 3
+";
+        let mesh = parse_ply(ply).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.vertices[2].position, [1.0, 1.0, 0.0]);
+        assert_eq!(mesh.vertices[0].color, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(mesh.vertices[0].normal, [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn test_parse_ply_list_count_past_the_end() {
+        let mut ply = b"ply
+format binary_little_endian 1.0
+element vertex 0
+element face 1
+property list uint int vertex_indices
+end_header
+"
+        .to_vec();
+        ply.extend(u32::MAX.to_le_bytes());
+        assert!(parse_ply(&ply).is_err());
+    }
+
+    #[test]
+    fn test_load_obj_normals_per_model() {
+        let path = std::env::temp_dir().join("someday_test_load_obj_normals_per_model.obj");
+        std::fs::write(
+            &path,
+            "o a
+v 0 0 0
+v 1 0 0
+v 0 1 0
+vn 1 0 0
+f 1//1 2//1 3//1
+o b
+v 0 0 0
+v 1 0 0
+v 0 1 0
+f 4 5 6
+",
+        )
+        .unwrap();
+        let mesh = load_obj(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mesh.vertices.len(), 6);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(mesh.vertices[0].normal, [1.0, 0.0, 0.0]);
+        assert_eq!(mesh.vertices[3].normal, [0.0, 0.0, 1.0]);
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{
+    command::DrawContext,
+    mesh::Mesh,
+    pipeline::{
+        BlendMode, DepthStencilState, GraphicsPipeline, GraphicsPipelineDescriptor, PrimitiveState,
+    },
+    shaders::{Shader, ShaderKind},
+    RenderAllocator, RenderInstance,
+};
+
+/// Limits recommended for `VK_EXT_mesh_shader` on most hardware, they match the
+/// `max_vertices` and `max_primitives` of `shader/meshlet.mesh`.
+pub const MAX_MESHLET_VERTICES: usize = 64;
+pub const MAX_MESHLET_TRIANGLES: usize = 124;
+/// Meshlets culled by one workgroup of `shader/meshlet.task`.
+pub const TASK_GROUP_SIZE: u32 = 32;
+
+/// A cluster of up to [`MAX_MESHLET_TRIANGLES`] triangles, laid out to be read by the
+/// reference task and mesh shaders.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Meshlet {
+    /// First entry in [`Meshlets::vertices`].
+    pub vertex_offset: u32,
+    /// First entry in [`Meshlets::triangles`].
+    pub triangle_offset: u32,
+    pub vertex_count: u32,
+    pub triangle_count: u32,
+    /// Object space bounding sphere, `w` is the radius.
+    pub bounds: Vec4,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Meshlets {
+    pub meshlets: Vec<Meshlet>,
+    /// Indices into the vertex buffer of the mesh.
+    pub vertices: Vec<u32>,
+    /// One triangle per entry, three 8 bit indices into the vertices of the meshlet.
+    pub triangles: Vec<u32>,
+}
+
+impl Meshlets {
+    /// Splits a triangle list into meshlets, greedily adding triangles in index order until
+    /// either the vertex or triangle limit is hit. Meshes without indices are treated as if
+    /// every vertex is used once.
+    pub fn build(mesh: &Mesh) -> Self {
+        let sequential_indices;
+        let indices = if mesh.indices.is_empty() {
+            sequential_indices = (0..mesh.vertices.len() as u32).collect::<Vec<_>>();
+            &sequential_indices
+        } else {
+            &mesh.indices
+        };
+
+        let mut result = Self::default();
+        let mut local_indices: HashMap<u32, u32> = HashMap::new();
+        let mut meshlet_vertices: Vec<u32> = vec![];
+        let mut meshlet_triangles: Vec<u32> = vec![];
+
+        for triangle in indices.chunks_exact(3) {
+            let mut new_vertices = 0;
+            for (index, vertex) in triangle.iter().enumerate() {
+                if !local_indices.contains_key(vertex) && !triangle[..index].contains(vertex) {
+                    new_vertices += 1;
+                }
+            }
+
+            if meshlet_vertices.len() + new_vertices > MAX_MESHLET_VERTICES
+                || meshlet_triangles.len() == MAX_MESHLET_TRIANGLES
+            {
+                result.push(mesh, &mut meshlet_vertices, &mut meshlet_triangles);
+                local_indices.clear();
+            }
+
+            let mut packed = 0;
+            for (corner, vertex) in triangle.iter().enumerate() {
+                let local = *local_indices.entry(*vertex).or_insert_with(|| {
+                    meshlet_vertices.push(*vertex);
+                    meshlet_vertices.len() as u32 - 1
+                });
+                packed |= local << (corner * 8);
+            }
+            meshlet_triangles.push(packed);
+        }
+
+        if !meshlet_triangles.is_empty() {
+            result.push(mesh, &mut meshlet_vertices, &mut meshlet_triangles);
+        }
+
+        result
+    }
+
+    /// The triangles of all meshlets as a triangle list indexing the vertex buffer of the mesh,
+    /// for drawing them without mesh shaders.
+    pub fn indices(&self) -> Vec<u32> {
+        self.meshlets
+            .iter()
+            .flat_map(|meshlet| {
+                let start = meshlet.triangle_offset as usize;
+                let end = start + meshlet.triangle_count as usize;
+                self.triangles[start..end].iter().flat_map(move |packed| {
+                    (0..3).map(move |corner| {
+                        let local = (packed >> (corner * 8)) & 0xff;
+                        self.vertices[(meshlet.vertex_offset + local) as usize]
+                    })
+                })
+            })
+            .collect()
+    }
+
+    fn push(&mut self, mesh: &Mesh, vertices: &mut Vec<u32>, triangles: &mut Vec<u32>) {
+        let positions = vertices
+            .iter()
+            .map(|index| Vec3::from(mesh.vertices[*index as usize].position))
+            .collect::<Vec<_>>();
+        let min = positions
+            .iter()
+            .fold(Vec3::splat(f32::MAX), |a, b| a.min(*b));
+        let max = positions
+            .iter()
+            .fold(Vec3::splat(f32::MIN), |a, b| a.max(*b));
+        let center = (min + max) * 0.5;
+        let radius = positions
+            .iter()
+            .map(|position| position.distance(center))
+            .fold(0.0, f32::max);
+
+        self.meshlets.push(Meshlet {
+            vertex_offset: self.vertices.len() as u32,
+            triangle_offset: self.triangles.len() as u32,
+            vertex_count: vertices.len() as u32,
+            triangle_count: triangles.len() as u32,
+            bounds: center.extend(radius),
+        });
+        self.vertices.append(vertices);
+        self.triangles.append(triangles);
+    }
+}
+
+/// Push constants of `shader/meshlet.task` and `shader/meshlet.mesh`, the task shader should
+/// be dispatched with `meshlet_count` divided by [`TASK_GROUP_SIZE`], rounded up.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MeshletPushConstants {
+    pub model_view_proj: Mat4,
+    pub meshlets: u64,
+    pub meshlet_vertices: u64,
+    pub meshlet_triangles: u64,
+    /// Device address of the vertex buffer of the mesh.
+    pub vertices: u64,
+    pub meshlet_count: u32,
+    /// Largest scale of the model matrix.
+    pub scale: f32,
+    pub _padding: [u32; 2],
+}
+crate::push_constants!(MeshletPushConstants {
+    model_view_proj,
+    meshlets,
+    meshlet_vertices,
+    meshlet_triangles,
+    vertices,
+    meshlet_count,
+    scale
+});
+
+/// [`Meshlets`] uploaded to the GPU, the shaders read them through their device addresses.
+#[derive(Debug)]
+pub struct GpuMeshlets {
+    pub meshlets: Buffer,
+    pub vertices: Buffer,
+    pub triangles: Buffer,
+    /// [`Meshlets::indices`], for devices without mesh shaders.
+    pub indices: Buffer,
+    pub meshlet_count: u32,
+    pub index_count: u32,
+}
+
+impl GpuMeshlets {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        meshlets: &Meshlets,
+    ) -> Result<Self> {
+        let mut upload = |size: usize, usage: vk::BufferUsageFlags| {
+            Buffer::new(
+                render_instance.device(),
+                &mut render_allocator.allocator(),
+                &vk::BufferCreateInfo::default()
+                    .size(size.max(4) as u64)
+                    .usage(usage)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                MemoryLocation::CpuToGpu,
+            )
+        };
+
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let indices = meshlets.indices();
+        let mut meshlet_buffer =
+            upload(std::mem::size_of_val(meshlets.meshlets.as_slice()), storage)?;
+        let mut vertex_buffer =
+            upload(std::mem::size_of_val(meshlets.vertices.as_slice()), storage)?;
+        let mut triangle_buffer = upload(
+            std::mem::size_of_val(meshlets.triangles.as_slice()),
+            storage,
+        )?;
+        let mut index_buffer = upload(
+            std::mem::size_of_val(indices.as_slice()),
+            vk::BufferUsageFlags::INDEX_BUFFER,
+        )?;
+        meshlet_buffer.copy_from_slice(&meshlets.meshlets, 0);
+        vertex_buffer.copy_from_slice(&meshlets.vertices, 0);
+        triangle_buffer.copy_from_slice(&meshlets.triangles, 0);
+        index_buffer.copy_from_slice(&indices, 0);
+
+        Ok(Self {
+            meshlets: meshlet_buffer,
+            vertices: vertex_buffer,
+            triangles: triangle_buffer,
+            indices: index_buffer,
+            meshlet_count: meshlets.meshlets.len() as u32,
+            index_count: indices.len() as u32,
+        })
+    }
+
+    pub fn push_constants(
+        &self,
+        model: Mat4,
+        view_proj: Mat4,
+        vertex_buffer: &Buffer,
+    ) -> MeshletPushConstants {
+        let (scale, _, _) = model.to_scale_rotation_translation();
+
+        MeshletPushConstants {
+            model_view_proj: view_proj * model,
+            meshlets: self.meshlets.device_addr,
+            meshlet_vertices: self.vertices.device_addr,
+            meshlet_triangles: self.triangles.device_addr,
+            vertices: vertex_buffer.device_addr,
+            meshlet_count: self.meshlet_count,
+            scale: scale.max_element(),
+            _padding: [0; 2],
+        }
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.meshlets.destroy(device, &mut allocator);
+        self.vertices.destroy(device, &mut allocator);
+        self.triangles.destroy(device, &mut allocator);
+        self.indices.destroy(device, &mut allocator);
+    }
+}
+
+/// Draws [`GpuMeshlets`] with `shader/meshlet.task` and `shader/meshlet.mesh` when the device
+/// supports `VK_EXT_mesh_shader`. Other devices draw [`GpuMeshlets::indices`] with
+/// `shader/meshlet.vert` instead, which looks the same but culls nothing.
+#[derive(Debug)]
+pub struct MeshletPipeline {
+    pub pipeline: GraphicsPipeline,
+    /// Whether `pipeline` is the task/mesh pipeline rather than the indexed fallback.
+    pub mesh_shader: bool,
+}
+
+impl MeshletPipeline {
+    pub fn new(
+        render_instance: &RenderInstance,
+        color_formats: &[vk::Format],
+        depth_stencil: Option<DepthStencilState>,
+    ) -> Result<Self> {
+        let mesh_shader = render_instance.0.mesh_shader.is_some();
+        let (task_shader, vertex_shader, stage_flags) = if mesh_shader {
+            (
+                Some(Shader::from_file(
+                    render_instance,
+                    "./shader/meshlet.task",
+                    ShaderKind::Task,
+                    "main",
+                )?),
+                Shader::from_file(
+                    render_instance,
+                    "./shader/meshlet.mesh",
+                    ShaderKind::Mesh,
+                    "main",
+                )?,
+                vk::ShaderStageFlags::TASK_EXT
+                    | vk::ShaderStageFlags::MESH_EXT
+                    | vk::ShaderStageFlags::FRAGMENT,
+            )
+        } else {
+            println!("VK_EXT_mesh_shader isn't supported, meshlets are drawn with indices");
+            (
+                None,
+                Shader::from_file(
+                    render_instance,
+                    "./shader/meshlet.vert",
+                    ShaderKind::Vertex,
+                    "main",
+                )?,
+                vk::ShaderStageFlags::ALL_GRAPHICS,
+            )
+        };
+        let fragment_shader = Shader::from_file(
+            render_instance,
+            "./shader/meshlet.frag",
+            ShaderKind::Fragment,
+            "main",
+        )?;
+
+        let pipeline = GraphicsPipeline::new(
+            render_instance,
+            GraphicsPipelineDescriptor {
+                vertex_shader,
+                task_shader,
+                fragment_shader,
+                // the vertices are read through their device address
+                vertex_input: vk::PipelineVertexInputStateCreateInfo::default(),
+                viewport: render_instance.0.surface_resolution,
+                primitive: PrimitiveState {
+                    topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                    ..Default::default()
+                },
+                depth_stencil,
+                blend: BlendMode::Replace,
+                multisample: Default::default(),
+                shading_rate_image: false,
+                view_mask: 0,
+                push_constant_range: Some(
+                    vk::PushConstantRange::default()
+                        .stage_flags(stage_flags)
+                        .offset(0)
+                        .size(std::mem::size_of::<MeshletPushConstants>() as u32),
+                ),
+                color_formats,
+            },
+        )?;
+
+        Ok(Self {
+            pipeline,
+            mesh_shader,
+        })
+    }
+
+    /// Draws `meshlets`, `ctx` has to have the pipeline bound along with the viewport and
+    /// scissor.
+    pub fn draw(
+        &self,
+        ctx: &DrawContext,
+        meshlets: &GpuMeshlets,
+        push_constants: &MeshletPushConstants,
+    ) -> Result<()> {
+        ctx.set_push_constants(&self.pipeline, push_constants)?;
+        if self.mesh_shader {
+            ctx.draw_mesh_tasks(meshlets.meshlet_count.div_ceil(TASK_GROUP_SIZE), 1, 1);
+        } else {
+            ctx.bind_index_buffer(&meshlets.indices);
+            ctx.draw_indexed(meshlets.index_count, 0, 0);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::mesh::Vertex;
+
+    /// A grid of `size` by `size` quads, two triangles each.
+    fn grid(size: u32) -> Mesh {
+        let row = size + 1;
+        let vertices = (0..row * row)
+            .map(|index| Vertex {
+                position: [(index % row) as f32, (index / row) as f32, 0.0],
+                ..Default::default()
+            })
+            .collect();
+        let indices = (0..size * size)
+            .flat_map(|quad| {
+                let corner = quad / size * row + quad % size;
+                [
+                    corner,
+                    corner + 1,
+                    corner + row,
+                    corner + 1,
+                    corner + row + 1,
+                    corner + row,
+                ]
+            })
+            .collect();
+        Mesh {
+            primitive_topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            vertices,
+            indices,
+        }
+    }
+
+    #[test]
+    fn test_indices_match_the_mesh() {
+        let mesh = grid(16);
+        let meshlets = Meshlets::build(&mesh);
+
+        assert!(meshlets.meshlets.len() > 1);
+        for meshlet in meshlets.meshlets.iter() {
+            assert!(meshlet.vertex_count as usize <= MAX_MESHLET_VERTICES);
+            assert!(meshlet.triangle_count as usize <= MAX_MESHLET_TRIANGLES);
+        }
+        assert_eq!(meshlets.indices(), mesh.indices);
+    }
+}
@@ -1,20 +1,65 @@
+pub mod acceleration_structure;
+pub mod async_compute;
+pub mod atlas;
+pub mod barrier;
 pub mod bundles;
+pub mod camera;
+pub mod capture;
+pub mod command;
+pub mod config;
+pub mod debug_view;
+pub mod deferred_destroy;
+pub mod depth_readback;
+pub mod descriptor_allocator;
+pub mod descriptor_cache;
+pub mod descriptor_writer;
 pub mod extract;
+pub mod frame_arena;
+pub mod frame_dump;
+pub mod frame_pacing;
+pub mod frame_time;
 pub mod global_descriptors;
+pub mod glsl_struct;
+#[cfg(feature = "gltf")]
 pub mod gltf;
+pub mod gpu_scope;
+pub mod graph;
+pub mod graph_file;
+pub mod hdr;
+pub mod ibl;
 pub mod image;
+pub mod instancing;
+pub mod interop;
 pub mod material;
+pub mod material_table;
 pub mod mesh;
+pub mod mesh_import;
+pub mod meshlet;
 pub mod nodes;
+pub mod passes;
 pub mod pipeline;
 pub mod primitives;
+pub mod push_constants;
+pub mod query;
+pub mod readback;
+pub mod registry;
+pub mod ring;
+pub mod shader_bind;
+pub mod shader_binding_table;
 pub mod shaders;
+pub mod staging_belt;
+pub mod stats;
+pub mod streaming;
+pub mod tracking;
+pub mod video;
+#[cfg(feature = "openxr")]
+pub mod xr;
 
 use std::{
     collections::{BTreeMap, HashMap},
     mem::size_of,
     ops::{Deref, DerefMut},
-    sync::Arc,
+    sync::{Arc, Mutex, MutexGuard, RwLockReadGuard, RwLockWriteGuard},
 };
 
 use ash::vk::{
@@ -24,7 +69,6 @@ use ash::vk::{
 };
 use bevy::{
     app::{AppExit, AppLabel, SubApp},
-    asset::HandleId,
     ecs::{event::ManualEventReader, schedule::ScheduleLabel, system::SystemState},
     prelude::*,
     time::{create_time_channels, TimeSender},
@@ -32,21 +76,38 @@ use bevy::{
     window::{PrimaryWindow, RawHandleWrapper},
 };
 use bytemuck::offset_of;
-use gpu_allocator::{
-    vulkan::{Allocator, AllocatorCreateDesc},
-    MemoryLocation,
-};
 
-use crate::{buffer::Buffer, ctx::ExampleBase};
+use crate::{
+    allocator::{Allocator, MemoryLocation},
+    buffer::Buffer,
+    ctx::{DeviceRequirements, ExampleBase},
+};
 
 use self::{
-    bundles::{Camera, MaterialMeshBundle},
+    async_compute::{AsyncCompute, AsyncComputeJoins},
+    bundles::MaterialMeshBundle,
+    camera::{Camera, ViewUniforms},
+    capture::FrameCaptureState,
+    config::{RendererConfig, MAX_FRAMES_IN_FLIGHT, MIN_UNIFORM_RING_FRAMES},
+    debug_view::DebugView,
+    deferred_destroy::{advance_deferred_destroy_queue, DeferredDestroyQueue},
+    frame_arena::FrameArena,
+    frame_pacing::FramePacing,
+    frame_time::FrameTimeBuffer,
+    descriptor_allocator::begin_descriptor_frame,
+    descriptor_cache::DescriptorSetCache,
     extract::Extract,
     global_descriptors::GlobalDescriptorSet,
+    graph::{PassQueue, RenderGraph},
+    hdr::HdrMetadata,
     image::Image,
-    material::{Material, MaterialUniform},
+    material::Material,
+    material_table::MaterialTable,
     mesh::Mesh,
     nodes::PresentNode,
+    registry::ResourceRegistry,
+    ring::{RingAllocation, RingBuffer},
+    streaming::TextureStreamer,
 };
 
 /// Contains the default Bevy rendering backend based on wgpu.
@@ -186,28 +247,54 @@ impl Plugin for RenderPlugin {
             .add_asset::<crate::render::image::Image>()
             .add_asset_loader(crate::render::image::ImageTextureLoader);
 
+        let requirements = app
+            .world
+            .remove_resource::<DeviceRequirements>()
+            .unwrap_or_default();
+        let mut config = app
+            .world
+            .remove_resource::<RendererConfig>()
+            .unwrap_or_default();
+        config.apply_env(|name| std::env::var(name).ok());
+        assert!(
+            config.uniform_ring_frames >= MIN_UNIFORM_RING_FRAMES,
+            "Uniform rings need at least {} frames",
+            MIN_UNIFORM_RING_FRAMES
+        );
+        assert!(
+            (1..=MAX_FRAMES_IN_FLIGHT).contains(&config.frames_in_flight),
+            "Frames in flight have to be between 1 and {}",
+            MAX_FRAMES_IN_FLIGHT
+        );
         let mut system_state: SystemState<
             Query<(&RawHandleWrapper, &Window), With<PrimaryWindow>>,
         > = SystemState::new(&mut app.world);
         let window_query = system_state.get(&app.world);
         let (window_handle, window) = window_query.get_single().unwrap();
-        let render_instance = RenderInstance(Arc::new(ExampleBase::new(
-            window_handle,
-            window.present_mode,
-        )));
+        let render_instance = RenderInstance(Arc::new(
+            ExampleBase::new(window_handle, window.present_mode, &requirements, &config)
+                .expect("Failed to create the Vulkan context"),
+        ));
 
-        let render_allocator = RenderAllocator(
-            Allocator::new(&AllocatorCreateDesc {
-                instance: render_instance.0.instance.clone(),
-                device: render_instance.0.device.clone(),
-                physical_device: render_instance.0.pdevice,
-                debug_settings: Default::default(),
-                buffer_device_address: true, // Ideally, check the BufferDeviceAddressFeatures struct.
-                allocation_sizes: Default::default(),
-            })
-            .unwrap(),
-        );
+        let mut render_allocator = RenderAllocator(Arc::new(Mutex::new(
+            Allocator::from_features(
+                &render_instance.0.instance,
+                &render_instance.0.device,
+                render_instance.0.pdevice,
+            )
+            .expect("Failed to create the allocator"),
+        )));
+        render_instance
+            .0
+            .allocator
+            .get_or_init(|| render_allocator.0.clone());
         let global_descriptor_set = GlobalDescriptorSet::new(&render_instance);
+        let view_uniform_buffer = ViewUniformBuffer::new(&render_instance, &mut render_allocator)
+            .expect("Failed to create the view uniform buffer");
+        let frame_time_buffer = FrameTimeBuffer::new(&render_instance, &mut render_allocator)
+            .expect("Failed to create the frame time buffer");
+        let async_compute =
+            AsyncCompute::new(&render_instance).expect("Failed to create the async compute queue");
 
         let mut render_app = App::empty();
         render_app.main_schedule_label = Box::new(Render);
@@ -228,15 +315,69 @@ impl Plugin for RenderPlugin {
             .init_non_send_resource::<NonSendMarker>()
             .init_resource::<ProcessedRenderAssets>()
             .init_resource::<SequentialPassSystem>()
+            .init_resource::<RenderGraph>()
+            .init_resource::<AsyncComputeJoins>()
+            .init_resource::<DeferredDestroyQueue>()
+            .init_resource::<DescriptorSetCache>()
+            .init_resource::<FrameArena>()
+            .init_resource::<stats::FrameStats>()
+            .init_resource::<FramePacing>()
+            .init_resource::<DebugView>()
+            .init_resource::<HdrMetadata>()
+            .init_resource::<FrameCaptureState>()
+            .init_resource::<TextureStreamer>()
+            .init_resource::<MaterialTable>()
             .insert_resource(render_instance)
             .insert_resource(render_allocator)
             .insert_resource(global_descriptor_set)
+            .insert_resource(view_uniform_buffer)
+            .insert_resource(frame_time_buffer)
+            .insert_resource(async_compute)
             .add_systems(ExtractSchedule, extract_meshes)
             .add_systems(ExtractSchedule, extract_materials)
             .add_systems(ExtractSchedule, extract_camera_uniform)
+            .add_systems(ExtractSchedule, frame_time::extract_frame_time)
             .add_systems(ExtractSchedule, extract_objects)
             .add_systems(ExtractSchedule, extract_textures_from_materials)
-            .add_systems(Render, basic_renderer_setup.in_set(RenderSet::Prepare));
+            .add_systems(ExtractSchedule, frame_pacing::extract_frame_pacing)
+            .add_systems(ExtractSchedule, debug_view::extract_debug_view)
+            .add_systems(ExtractSchedule, hdr::extract_hdr_metadata)
+            .add_systems(ExtractSchedule, capture::extract_frame_capture)
+            .add_systems(
+                ExtractSchedule,
+                streaming::extract_texture_streaming
+                    .before(extract_materials)
+                    .before(extract_textures_from_materials),
+            )
+            .add_systems(Render, basic_renderer_setup.in_set(RenderSet::Prepare))
+            .add_systems(Render, frame_pacing::pace_frame.in_set(RenderSet::Prepare))
+            .add_systems(Render, hdr::apply_hdr_metadata.in_set(RenderSet::Prepare))
+            .add_systems(
+                Render,
+                capture::prepare_frame_capture.in_set(RenderSet::Prepare),
+            )
+            .add_systems(
+                Render,
+                streaming::stream_textures.in_set(RenderSet::Prepare),
+            )
+            .add_systems(
+                Render,
+                advance_deferred_destroy_queue.in_set(RenderSet::Cleanup),
+            )
+            .add_systems(Render, begin_descriptor_frame.in_set(RenderSet::Cleanup))
+            .add_systems(
+                Render,
+                descriptor_cache::evict_descriptor_sets.in_set(RenderSet::Cleanup),
+            )
+            .add_systems(
+                Render,
+                frame_arena::reset_frame_arena.in_set(RenderSet::Cleanup),
+            )
+            .add_systems(
+                Render,
+                graph_file::reload_graph_file.in_set(RenderSet::Prepare),
+            )
+            .add_systems(Render, stats::collect_frame_stats.in_set(RenderSet::Cleanup));
 
         let (sender, receiver) = create_time_channels();
         app.insert_resource(receiver);
@@ -308,6 +449,23 @@ pub trait SequentialNode: Send + Sync + 'static {
     fn update(&mut self, _world: &mut World) {}
 
     fn run(&self, world: &mut World) -> anyhow::Result<()>;
+
+    /// Records the pass into the submission of the async compute queue, instead of calling
+    /// [`SequentialNode::run`], when the [`RenderGraph`] has it as an async compute pass. Only
+    /// compute work can be recorded.
+    fn record_async_compute(
+        &self,
+        _world: &World,
+        _command_buffer: vk::CommandBuffer,
+    ) -> anyhow::Result<()> {
+        anyhow::bail!("The pass can't run on the async compute queue")
+    }
+
+    /// Stages in which the pass reads what async compute passes of the same frame wrote, the
+    /// graphics submission waits for the async compute queue there.
+    fn async_compute_wait_stages(&self) -> vk::PipelineStageFlags {
+        vk::PipelineStageFlags::ALL_COMMANDS
+    }
 }
 
 struct SequentialPass {
@@ -333,6 +491,17 @@ impl SequentialPassSystem {
         self.passes.iter().find(|pass| pass.id == id)
     }
 
+    /// Runs the passes in `order` first, passes that aren't listed keep running after them in
+    /// their current order.
+    pub fn sort_by_pass_order(&mut self, order: &[&str]) {
+        self.passes.sort_by_key(|pass| {
+            order
+                .iter()
+                .position(|id| *id == pass.id)
+                .unwrap_or(usize::MAX)
+        });
+    }
+
     pub fn update(&mut self, world: &mut World) {
         for pass in self.passes.iter_mut() {
             pass.node.update(world);
@@ -340,9 +509,82 @@ impl SequentialPassSystem {
     }
 
     pub fn run(&mut self, world: &mut World) {
+        frame_dump::next_frame();
+        if let Err(e) = self.submit_async_compute(world) {
+            println!("Failed to submit the async compute passes: {}", e);
+        }
         for pass in self.passes.iter_mut() {
+            let graph = world.resource::<RenderGraph>();
+            if graph.is_culled(&pass.id)
+                || graph.pass_queue(&pass.id) == Some(PassQueue::AsyncCompute)
+            {
+                continue;
+            }
+            frame_dump::checkpoint(&pass.id);
             pass.node.run(world).unwrap();
         }
+
+        let render_instance = world.resource::<RenderInstance>();
+        if let Err(e) = world
+            .resource::<AsyncComputeJoins>()
+            .wait_remaining(render_instance)
+        {
+            println!("Failed to wait for the async compute passes: {}", e);
+        }
+    }
+
+    /// Records the async compute passes of the [`RenderGraph`] into one submission, ahead of
+    /// the graphics passes since those are submitted at the end of the frame. The graphics
+    /// submission joins it through [`AsyncComputeJoins`], in the stages of the passes that use
+    /// its results according to [`RenderGraph::queue_joins`].
+    fn submit_async_compute(&self, world: &mut World) -> crate::error::Result<()> {
+        let graph = world.resource::<RenderGraph>();
+        let async_passes = self
+            .passes
+            .iter()
+            .filter(|pass| {
+                !graph.is_culled(&pass.id)
+                    && graph.pass_queue(&pass.id) == Some(PassQueue::AsyncCompute)
+            })
+            .collect::<Vec<_>>();
+        if async_passes.is_empty() {
+            return Ok(());
+        }
+        let wait_stages = graph
+            .queue_joins()
+            .iter()
+            .map(|join| &graph.passes()[join.to_pass])
+            .filter(|consumer| consumer.queue == PassQueue::Graphics)
+            .filter_map(|consumer| self.get_pass(&consumer.name))
+            .fold(vk::PipelineStageFlags::empty(), |stages, pass| {
+                stages | pass.node.async_compute_wait_stages()
+            });
+
+        world.resource_scope(|world, mut async_compute: Mut<AsyncCompute>| {
+            let world: &World = world;
+            let render_instance = world.resource::<RenderInstance>();
+            // the transient resources are shared by all frames, so the work overlaps the
+            // graphics work of its own frame but not of the frames before
+            render_instance.0.wait_for_frames()?;
+            let semaphore = async_compute.submit(render_instance, &[], |_, command_buffer| {
+                for pass in async_passes.iter() {
+                    frame_dump::checkpoint(&pass.id);
+                    if let Err(e) = pass.node.record_async_compute(world, command_buffer) {
+                        println!("Failed to record the async compute pass {}: {}", pass.id, e);
+                    }
+                }
+            })?;
+            // nothing reads the results this frame, the wait only consumes the semaphore
+            let wait_stages = if wait_stages.is_empty() {
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE
+            } else {
+                wait_stages
+            };
+            world
+                .resource::<AsyncComputeJoins>()
+                .push(semaphore, wait_stages);
+            Ok(())
+        })
     }
 }
 
@@ -362,24 +604,34 @@ fn render_system(world: &mut World) {
     );
 }
 
-#[derive(Resource)]
+#[derive(Resource, Clone)]
 pub struct RenderInstance(pub Arc<ExampleBase>);
 impl RenderInstance {
     pub fn device(&self) -> &ash::Device {
         &self.0.device
     }
+
+    pub fn registry(&self) -> RwLockReadGuard<'_, ResourceRegistry> {
+        self.0.registry.read().unwrap()
+    }
+
+    pub fn registry_mut(&self) -> RwLockWriteGuard<'_, ResourceRegistry> {
+        self.0.registry.write().unwrap()
+    }
 }
 
-#[derive(Resource)]
-pub struct RenderAllocator(Allocator);
+/// Cheap to clone, so asset-loading threads can allocate while the render world runs.
+#[derive(Resource, Clone)]
+pub struct RenderAllocator(Arc<Mutex<Allocator>>);
 impl RenderAllocator {
-    pub fn allocator(&mut self) -> &mut Allocator {
-        &mut self.0
+    /// Blocks while another thread is allocating, so don't hold the guard for long.
+    pub fn allocator(&self) -> MutexGuard<'_, Allocator> {
+        self.0.lock().unwrap()
     }
 }
 
 #[derive(Debug)]
-struct GpuMesh {
+pub(crate) struct GpuMesh {
     vertex_buffer: Buffer,
     index_buffer: Option<Buffer>,
     vertex_count: u32,
@@ -485,7 +737,7 @@ fn extract_meshes(
         let vertex_buffer = {
             let mut buf = Buffer::new(
                 &render_instance.0.device,
-                &mut render_allocator.0,
+                &mut render_allocator.allocator(),
                 &vk::BufferCreateInfo {
                     size: mesh.vertices.len() as u64 * std::mem::size_of::<mesh::Vertex>() as u64,
                     usage: vk::BufferUsageFlags::VERTEX_BUFFER,
@@ -493,7 +745,8 @@ fn extract_meshes(
                     ..Default::default()
                 },
                 MemoryLocation::CpuToGpu,
-            );
+            )
+            .expect("Failed to create the vertex buffer");
 
             buf.copy_from_slice(&mesh.vertices, 0);
             buf
@@ -505,13 +758,14 @@ fn extract_meshes(
             }
             let mut buf = Buffer::new(
                 &render_instance.0.device,
-                &mut render_allocator.0,
+                &mut render_allocator.allocator(),
                 &vk::BufferCreateInfo::default()
                     .size((size_of::<u32>() * mesh.indices.len()) as vk::DeviceSize)
                     .usage(vk::BufferUsageFlags::INDEX_BUFFER)
                     .sharing_mode(vk::SharingMode::EXCLUSIVE),
                 MemoryLocation::CpuToGpu,
-            );
+            )
+            .expect("Failed to create the index buffer");
 
             buf.copy_from_slice(&mesh.indices, 0);
             (Some(buf), mesh.indices.len() as u32)
@@ -535,10 +789,10 @@ fn extract_meshes(
     //     if !objects_with_mesh.into_iter().any(|h| h.0 == handle) {
     //         gpu_mesh
     //             .vertex_buffer
-    //             .destroy(render_instance.device(), render_allocator.allocator());
+    //             .destroy(render_instance.device(), &mut render_allocator.allocator());
 
     //         if let Some(index_buffer) = &mut gpu_mesh.index_buffer {
-    //             index_buffer.destroy(render_instance.device(), render_allocator.allocator());
+    //             index_buffer.destroy(render_instance.device(), &mut render_allocator.allocator());
     //         }
 
     //         keys_to_delete.push(handle.clone());
@@ -574,6 +828,7 @@ fn extract_objects(
     commands.insert_or_spawn_batch(values);
 }
 
+/// Fills in the textures of the materials that were waiting for them to load.
 fn extract_textures_from_materials(
     material_assets: Extract<Res<Assets<Material>>>,
     texture_assets: Extract<Res<Assets<Image>>>,
@@ -581,104 +836,37 @@ fn extract_textures_from_materials(
     render_instance: Res<RenderInstance>,
     mut render_allocator: ResMut<RenderAllocator>,
     mut global_descriptors: ResMut<GlobalDescriptorSet>,
+    mut streamer: ResMut<TextureStreamer>,
+    mut material_table: ResMut<MaterialTable>,
 ) {
     for ev in ev_asset.iter() {
         match ev {
             AssetEvent::Created { handle } => {
-                let material = material_assets
-                    .iter()
-                    .map(|(material_handle_id, material)| {
-                        if let Some(base_color_texture) = material.base_color_texture.as_ref() {
-                            if base_color_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    base_color_texture,
-                                    offset_of!(MaterialUniform, base_color_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(emissive_texture) = material.emissive_texture.as_ref() {
-                            if emissive_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    emissive_texture,
-                                    offset_of!(MaterialUniform, emissive_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(occlusion_texture) = material.occlusion_texture.as_ref() {
-                            if occlusion_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    occlusion_texture,
-                                    offset_of!(MaterialUniform, occlusion_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(normal_map_texture) = material.normal_map_texture.as_ref() {
-                            if normal_map_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    normal_map_texture,
-                                    offset_of!(MaterialUniform, normal_map_texture_index),
-                                ));
-                            }
-                        }
-
-                        if let Some(metallic_roughness_texture) =
-                            material.metallic_roughness_texture.as_ref()
-                        {
-                            if metallic_roughness_texture == handle {
-                                return Some((
-                                    material_handle_id,
-                                    metallic_roughness_texture,
-                                    offset_of!(MaterialUniform, metallic_roughness_texture_index),
-                                ));
-                            }
-                        }
-
-                        None
-                    })
-                    .find(|x| x.is_some())
-                    .flatten();
-
-                let Some((material_handle_id, texture_handle, bytes_offset))  = material else {
-                    continue;
-                };
-
-                let texture = texture_assets.get(texture_handle).unwrap();
-                global_descriptors.textures.insert(
-                    texture_handle.clone(),
-                    crate::buffer::Image::from_image_buffer(
-                        &render_instance,
-                        &mut render_allocator,
-                        texture.data.clone(),
-                        texture.format,
-                    ),
-                );
-                let index = global_descriptors
-                    .get_texture_index(texture_handle)
-                    .unwrap() as i32;
-
-                if let Some(buffer) = global_descriptors.buffers.get_mut(&material_handle_id) {
-                    buffer.copy_from_slice(&[index], bytes_offset);
-                } else {
-                    let mut buffer: Buffer = Buffer::new(
-                        render_instance.device(),
-                        render_allocator.allocator(),
-                        &vk::BufferCreateInfo::default()
-                            .size(std::mem::size_of::<material::MaterialUniform>() as u64)
-                            .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
-                            .sharing_mode(vk::SharingMode::EXCLUSIVE),
-                        MemoryLocation::CpuToGpu,
-                    );
-                    buffer.copy_from_slice(&[index], bytes_offset);
-                    global_descriptors
-                        .buffers
-                        .insert(material_handle_id, buffer);
+                for (material_handle_id, material) in material_assets.iter() {
+                    let uses_texture = [
+                        &material.base_color_texture,
+                        &material.emissive_texture,
+                        &material.metallic_roughness_texture,
+                        &material.normal_map_texture,
+                        &material.occlusion_texture,
+                    ]
+                    .into_iter()
+                    .any(|texture| texture.as_ref() == Some(handle));
+                    // materials that weren't extracted yet pick up the texture when they are
+                    if !uses_texture || material_table.asset_id(material_handle_id).is_none() {
+                        continue;
+                    }
+                    material_table
+                        .insert_asset(
+                            &render_instance,
+                            &mut render_allocator,
+                            &mut global_descriptors,
+                            &mut streamer,
+                            material_handle_id,
+                            material,
+                            &texture_assets,
+                        )
+                        .expect("Failed to update the material");
                 }
             }
             AssetEvent::Modified { handle } => {
@@ -686,6 +874,7 @@ fn extract_textures_from_materials(
             }
             AssetEvent::Removed { handle } => {
                 // an image was unloaded
+                streamer.remove(handle);
             }
         }
     }
@@ -698,109 +887,93 @@ fn extract_materials(
     render_instance: Res<RenderInstance>,
     mut render_allocator: ResMut<RenderAllocator>,
     mut global_descriptors: ResMut<GlobalDescriptorSet>,
+    mut streamer: ResMut<TextureStreamer>,
+    mut material_table: ResMut<MaterialTable>,
 ) {
     for handle in materials.iter() {
         let _ = info_span!("Extracting material").entered();
         let material = material_assets.get(handle).unwrap();
-        let mut material_buffer = MaterialUniform::from_material(material);
-
-        if let Some(handle) = material.base_color_texture.as_ref() {
-            if let Some(img) = texture_assets.get(handle) {
-                let mut texture = crate::buffer::Image::from_image_buffer(
-                    &render_instance,
-                    &mut render_allocator,
-                    img.data.clone(),
-                    img.format,
-                );
-
-                let _ = texture.create_view(render_instance.device());
-                global_descriptors.textures.insert(handle.clone(), texture);
-                material_buffer.base_color_texture_index =
-                    global_descriptors.get_texture_index(handle).unwrap() as i32;
-            }
-        }
-
-        if let Some(buffer) = global_descriptors.buffers.get_mut(&handle.id()) {
-            buffer.copy_from_slice(&[material_buffer], 0);
-        } else {
-            let buffer = {
-                let mut buf = Buffer::new(
-                    render_instance.device(),
-                    render_allocator.allocator(),
-                    &vk::BufferCreateInfo {
-                        size: std::mem::size_of::<material::MaterialUniform>() as u64,
-                        usage: vk::BufferUsageFlags::UNIFORM_BUFFER,
-                        sharing_mode: vk::SharingMode::EXCLUSIVE,
-                        ..Default::default()
-                    },
-                    MemoryLocation::CpuToGpu,
-                );
-
-                buf.copy_from_slice(&[material_buffer], 0);
-                buf
-            };
-
-            global_descriptors.buffers.insert(handle.id(), buffer);
-        }
+        material_table
+            .insert_asset(
+                &render_instance,
+                &mut render_allocator,
+                &mut global_descriptors,
+                &mut streamer,
+                handle.id(),
+                material,
+                &texture_assets,
+            )
+            .expect("Failed to create the material");
     }
 }
 
-#[repr(C, align(16))]
-#[derive(Copy, Clone, Debug)]
-struct CameraBuffer {
-    view_proj: Mat4,
-    inverse_view_proj: Mat4,
-    view: Mat4,
-    inverse_view: Mat4,
-    proj: Mat4,
-    inverse_proj: Mat4,
-    world_position: Vec3,
+/// The [`ViewUniforms`] of the camera, pushed into a ring buffer every frame so the GPU can
+/// still read the ones of earlier frames.
+#[derive(Resource)]
+pub struct ViewUniformBuffer {
+    ring: RingBuffer,
+    /// Uniforms of the current frame, `None` until a camera has been extracted.
+    pub current: Option<RingAllocation>,
+    previous_view_proj: Option<Mat4>,
+    frame_index: u32,
+}
+
+impl ViewUniformBuffer {
+    fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> crate::error::Result<Self> {
+        Ok(Self {
+            ring: RingBuffer::new(
+                render_instance,
+                render_allocator,
+                size_of::<ViewUniforms>() as u64,
+                render_instance.0.config.uniform_ring_frames,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+            )?,
+            current: None,
+            previous_view_proj: None,
+            frame_index: 0,
+        })
+    }
 }
-pub static CAMERA_HANDLE: once_cell::sync::Lazy<HandleId> =
-    once_cell::sync::Lazy::new(|| HandleId::from(String::from("camera")));
 
-/// only runs whenever the camera component or transform component changes
 fn extract_camera_uniform(
-    camera: Extract<Query<(&Camera, &Transform), Or<(Changed<Camera>, Changed<Transform>)>>>,
-    mut global_descriptor_set: ResMut<GlobalDescriptorSet>,
+    camera: Extract<Query<(&Camera, &Transform)>>,
+    mut view_uniform_buffer: ResMut<ViewUniformBuffer>,
     render_instance: Res<RenderInstance>,
-    mut render_allocator: ResMut<RenderAllocator>,
 ) {
     let Ok((camera, camera_transform)) = camera.get_single() else {
         return;
     };
     let _ = info_span!("Extracting camera uniform").entered();
 
-    let view = camera_transform.compute_matrix();
-    let inverse_view = view.inverse();
-    let projection = camera.projection;
-    let inverse_projection = projection.inverse();
-
-    let uniform = CameraBuffer {
-        view_proj: projection * inverse_view,
-        inverse_view_proj: view * inverse_projection,
-        view,
-        inverse_view,
-        proj: projection,
-        inverse_proj: inverse_projection,
-        world_position: camera_transform.translation,
+    // the jitter is in pixels of the resolution the view is rendered at, not the upscaled one
+    let resolution = render_instance.0.surface_resolution;
+    let viewport_size = camera
+        .render_resolution
+        .unwrap_or(UVec2::new(resolution.width, resolution.height))
+        .as_vec2();
+    let view_uniform_buffer = &mut *view_uniform_buffer;
+    let uniforms = ViewUniforms::new(
+        camera,
+        camera_transform,
+        viewport_size,
+        Mat4::IDENTITY,
+        view_uniform_buffer.frame_index,
+    );
+    let uniforms = ViewUniforms {
+        // the first frame has no history, so it's its own previous frame
+        previous_view_proj: view_uniform_buffer
+            .previous_view_proj
+            .unwrap_or(uniforms.unjittered_view_proj),
+        ..uniforms
     };
 
-    if let Some(buffer) = global_descriptor_set.buffers.get_mut(&CAMERA_HANDLE) {
-        buffer.copy_from_slice(&[uniform], 0);
-    } else {
-        let mut buffer: Buffer = Buffer::new(
-            render_instance.device(),
-            render_allocator.allocator(),
-            &vk::BufferCreateInfo::default()
-                .size(std::mem::size_of::<CameraBuffer>() as u64)
-                .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE),
-            MemoryLocation::CpuToGpu,
-        );
-        buffer.copy_from_slice(&[uniform], 0);
-        global_descriptor_set.buffers.insert(*CAMERA_HANDLE, buffer);
-    }
+    view_uniform_buffer.ring.begin_frame();
+    view_uniform_buffer.current = Some(view_uniform_buffer.ring.push(&uniforms));
+    view_uniform_buffer.previous_view_proj = Some(uniforms.unjittered_view_proj);
+    view_uniform_buffer.frame_index = view_uniform_buffer.frame_index.wrapping_add(1);
 }
 
 fn basic_renderer_setup(
@@ -814,6 +987,9 @@ fn basic_renderer_setup(
 
     sequential_pass_system.add_pass(
         "present_node".into(),
-        Box::new(PresentNode::new(&render_instance, &mut render_allocator)),
+        Box::new(
+            PresentNode::new(&render_instance, &mut render_allocator)
+                .expect("Failed to create the present node"),
+        ),
     );
 }
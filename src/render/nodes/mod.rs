@@ -1,24 +1,47 @@
-use std::mem::size_of;
+use std::{mem::size_of, sync::atomic::Ordering};
 
 use ash::vk::{self, PipelineBindPoint, RenderingFlags, SampleCountFlags, ShaderStageFlags};
 use bevy::prelude::*;
 
-use crate::ctx::record_submit_commandbuffer;
+use crate::{buffer::Image, error::Result};
 
 use super::{
+    async_compute::AsyncComputeJoins,
+    barrier::{self, Usage},
+    capture::FrameCaptureState,
+    debug_view::DebugView,
+    gpu_scope,
     material::Material,
+    material_table::MaterialTable,
     mesh::Mesh,
-    pipeline::{GraphicsPipeline, GraphicsPipelineDescriptor, PrimitiveState},
+    passes::{
+        composite::{CompositePass, CompositeSettings},
+        graphics::{ColorAttachment, RenderTarget},
+    },
+    pipeline::{BlendMode, GraphicsPipeline, GraphicsPipelineDescriptor, PrimitiveState},
+    push_constants::{set_push_constants, verify_push_constants},
     shaders::Shader,
-    GpuMesh, ProcessedRenderAssets, RenderAllocator, RenderInstance, SequentialNode, CAMERA_HANDLE,
+    stats, GpuMesh, ProcessedRenderAssets, RenderAllocator, RenderInstance, SequentialNode,
+    ViewUniformBuffer,
 };
 
 #[derive(Debug)]
 pub struct PresentNode {
     pipeline: GraphicsPipeline,
+    /// Drawn over the frame for [`DebugView::Wireframe`], `None` without `fillModeNonSolid`.
+    wireframe: Option<GraphicsPipeline>,
+    overdraw: GraphicsPipeline,
+    mip_level: GraphicsPipeline,
+    /// Linear working image the scene is drawn into, [`CompositePass`] encodes it to the
+    /// swapchain format.
+    color: Image,
+    composite: CompositePass,
     draw_command_recording_chunk_size: usize,
 }
 
+/// Format of the working image, float so nothing is quantized before the composite.
+const COLOR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
 #[repr(C, align(16))]
 #[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 struct PushConstants {
@@ -26,35 +49,127 @@ struct PushConstants {
     material_pointer: u64,
     camera_pointer: u64,
 }
+crate::push_constants!(PushConstants {
+    model,
+    material_pointer,
+    camera_pointer
+});
 
 impl PresentNode {
-    pub fn new(render_instance: &RenderInstance, _render_allocator: &mut RenderAllocator) -> Self {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<Self> {
+        let pipeline = Self::create_pipeline(
+            render_instance,
+            "./shader/main.frag",
+            vk::PolygonMode::FILL,
+            BlendMode::Replace,
+        )?;
+        let wireframe = if render_instance.0.supports_fill_mode_non_solid {
+            Some(Self::create_pipeline(
+                render_instance,
+                "./shader/debug_view/wireframe.frag",
+                vk::PolygonMode::LINE,
+                BlendMode::Replace,
+            )?)
+        } else {
+            println!("fillModeNonSolid isn't supported, the wireframe debug view is disabled");
+            None
+        };
+        let overdraw = Self::create_pipeline(
+            render_instance,
+            "./shader/debug_view/overdraw.frag",
+            vk::PolygonMode::FILL,
+            BlendMode::Additive,
+        )?;
+        let mip_level = Self::create_pipeline(
+            render_instance,
+            "./shader/debug_view/mip_level.frag",
+            vk::PolygonMode::FILL,
+            BlendMode::Replace,
+        )?;
+
+        let resolution = render_instance.0.surface_resolution;
+        let mut color = Image::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(COLOR_FORMAT)
+                .extent(vk::Extent3D {
+                    width: resolution.width,
+                    height: resolution.height,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?;
+        color.set_name("PresentNode color");
+        let color_view = color.create_view(render_instance.device())?;
+        // picks the encode from whether the swapchain format is sRGB, so it happens once
+        let composite = CompositePass::new(
+            render_instance,
+            render_instance.0.surface_format.format,
+            CompositeSettings::default(),
+        )?;
+        composite.set_input(render_instance, color_view);
+
+        Ok(Self {
+            pipeline,
+            wireframe,
+            overdraw,
+            mip_level,
+            color,
+            composite,
+            draw_command_recording_chunk_size: 50,
+        })
+    }
+
+    /// The scene pipeline with another fragment shader, the vertex shader and push constants
+    /// are shared so every pipeline can draw the same objects.
+    fn create_pipeline(
+        render_instance: &RenderInstance,
+        fragment_path: &str,
+        polygon_mode: vk::PolygonMode,
+        blend: BlendMode,
+    ) -> Result<GraphicsPipeline> {
         let vert = Shader::from_file(
             render_instance,
             "./shader/main.vert",
             super::shaders::ShaderKind::Vertex,
             "main",
-        );
+        )?;
         let frag = Shader::from_file(
             render_instance,
-            "./shader/main.frag",
+            fragment_path,
             super::shaders::ShaderKind::Fragment,
             "main",
-        );
+        )?;
 
-        let pipeline = GraphicsPipeline::new(
+        GraphicsPipeline::new(
             render_instance,
             GraphicsPipelineDescriptor {
                 vertex_shader: vert,
+                task_shader: None,
                 vertex_input: vk::PipelineVertexInputStateCreateInfo::default()
                     .vertex_binding_descriptions(&[GpuMesh::vertex_binding_descriptors()])
                     .vertex_attribute_descriptions(&GpuMesh::vertex_input_descriptors()),
                 fragment_shader: frag,
                 primitive: PrimitiveState {
                     topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                    polygon_mode,
                     ..Default::default()
                 },
                 depth_stencil: None,
+                blend,
+                multisample: Default::default(),
+                shading_rate_image: false,
+                view_mask: 0,
                 push_constant_range: Some(
                     vk::PushConstantRange::default()
                         .stage_flags(ShaderStageFlags::ALL_GRAPHICS)
@@ -62,13 +177,9 @@ impl PresentNode {
                         .size(size_of::<PushConstants>() as u32),
                 ),
                 viewport: render_instance.0.surface_resolution,
+                color_formats: &[COLOR_FORMAT],
             },
-        );
-
-        Self {
-            pipeline,
-            draw_command_recording_chunk_size: 50,
-        }
+        )
     }
 }
 
@@ -82,12 +193,22 @@ impl SequentialNode for PresentNode {
             return;
         }
 
+        // the frames in flight have the sets bound
+        world
+            .resource::<RenderInstance>()
+            .0
+            .wait_for_frames()
+            .expect("Failed to wait for the frames in flight");
         world.resource_scope(
             |world, mut global_descriptors: Mut<super::global_descriptors::GlobalDescriptorSet>| {
-                global_descriptors.update_descriptor_set(
-                    self.pipeline.descriptor_sets[0],
-                    world.resource::<RenderInstance>(),
-                )
+                for pipeline in [&self.pipeline, &self.mip_level] {
+                    global_descriptors
+                        .update_descriptor_set(
+                            pipeline.descriptor_sets[0],
+                            world.resource::<RenderInstance>(),
+                        )
+                        .expect("Failed to update the global descriptor set")
+                }
             },
         );
     }
@@ -96,9 +217,25 @@ impl SequentialNode for PresentNode {
     fn run(&self, world: &mut bevy::prelude::World) -> anyhow::Result<()> {
         let mut objects = world.query::<(&Handle<Mesh>, &Handle<Material>, &Transform)>();
         let assets = world.resource::<ProcessedRenderAssets>();
-        let global_descriptors = world.resource::<super::global_descriptors::GlobalDescriptorSet>();
+        let material_table = world.resource::<MaterialTable>();
+        let frame_capture = world.resource::<FrameCaptureState>();
+        let debug_view = *world.resource::<DebugView>();
+        let pipeline = match debug_view {
+            DebugView::Overdraw => &self.overdraw,
+            DebugView::MipLevel => &self.mip_level,
+            DebugView::None | DebugView::Wireframe => &self.pipeline,
+        };
+        let overlay = match debug_view {
+            DebugView::Wireframe => self.wireframe.as_ref(),
+            _ => None,
+        };
 
         let render_instance = world.resource::<RenderInstance>();
+        let composite_frame = world.resource::<ViewUniformBuffer>().frame_index;
+        let color_view = self
+            .color
+            .view
+            .expect("The color image is created with a view");
         let objects_count = objects.iter(world).count();
 
         if objects_count == 0 {
@@ -106,45 +243,92 @@ impl SequentialNode for PresentNode {
         }
 
         let renderer = render_instance.0.as_ref();
+        // waits for the frame that used the context before, the ones after it keep running
+        let frame = renderer.begin_frame()?;
+        let frame_index = renderer.frame_index();
         let present_index = unsafe {
             renderer
                 .swapchain_loader
                 .acquire_next_image(
                     renderer.swapchain,
                     std::u64::MAX,
-                    renderer.present_complete_semaphore,
+                    frame.image_available,
                     vk::Fence::null(),
                 )
                 .unwrap()
                 .0
         };
 
-        record_submit_commandbuffer(
-            &renderer.device,
-            renderer.draw_command_buffer,
-            renderer.draw_commands_reuse_fence,
-            renderer.present_queue,
-            &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT],
-            &[renderer.present_complete_semaphore],
-            &[renderer.rendering_complete_semaphore],
+        // everything uploaded this frame is copied by a single submission we wait on
+        let staging_wait = renderer.staging_belt.lock().unwrap().flush(renderer)?;
+        let mut wait_mask = vec![vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let mut wait_semaphores = vec![frame.image_available];
+        if let Some(staging_wait) = staging_wait.as_ref() {
+            wait_mask.push(staging_wait.stage);
+            wait_semaphores.push(staging_wait.semaphore);
+        }
+        for (semaphore, stage) in world.resource::<AsyncComputeJoins>().take() {
+            wait_mask.push(stage);
+            wait_semaphores.push(semaphore);
+        }
+
+        // the draws are recorded on the thread pool, which has no way to return an error
+        for program in std::iter::once(pipeline).chain(overlay) {
+            verify_push_constants::<PushConstants>(program)?;
+        }
+
+        renderer.record_submit_frame(
+            frame,
+            &wait_mask,
+            &wait_semaphores,
+            &[renderer.rendering_complete_semaphores[present_index as usize]],
             |device, draw_command_buffer| unsafe {
+                if let Some(staging_wait) = staging_wait.as_ref() {
+                    staging_wait.record_acquire_barriers(renderer, draw_command_buffer);
+                }
+                renderer
+                    .staging_belt
+                    .lock()
+                    .unwrap()
+                    .record_frame_start(renderer, draw_command_buffer);
+                gpu_scope::begin_frame(render_instance, draw_command_buffer);
+                crate::gpu_scope!((render_instance, draw_command_buffer), "PresentNode");
+
                 {
+                    // the composite of the frame before may still be sampling it
                     let image_memory_barrier = vk::ImageMemoryBarrier2::default()
-                        .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
-                        .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_READ)
+                        .src_stage_mask(
+                            vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT
+                                | vk::PipelineStageFlags2::FRAGMENT_SHADER,
+                        )
+                        .src_access_mask(vk::AccessFlags2::NONE)
                         .old_layout(vk::ImageLayout::UNDEFINED)
                         .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
-                        .new_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
-                        .image(renderer.present_images[present_index as usize])
+                        .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                        .image(self.color.image)
                         .subresource_range(vk::ImageSubresourceRange {
                             aspect_mask: vk::ImageAspectFlags::COLOR,
                             layer_count: 1,
                             level_count: 1,
                             ..Default::default()
                         });
+                    // the depth image is shared by all frames, the one before may still be
+                    // testing against it
+                    let depth_memory_barrier = vk::MemoryBarrier2::default()
+                        .src_stage_mask(vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS)
+                        .src_access_mask(vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                        .dst_stage_mask(
+                            vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS
+                                | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS,
+                        )
+                        .dst_access_mask(
+                            vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ
+                                | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                        );
 
                     let dependency_info = vk::DependencyInfo::default()
+                        .memory_barriers(std::slice::from_ref(&depth_memory_barrier))
                         .image_memory_barriers(std::slice::from_ref(&image_memory_barrier));
 
                     renderer
@@ -153,13 +337,18 @@ impl SequentialNode for PresentNode {
                 }
 
                 let color_attach = &[vk::RenderingAttachmentInfo::default()
-                    .image_view(renderer.present_image_views[present_index as usize])
+                    .image_view(color_view)
                     .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
                     .load_op(vk::AttachmentLoadOp::CLEAR)
                     .store_op(vk::AttachmentStoreOp::STORE)
                     .clear_value(vk::ClearValue {
                         color: vk::ClearColorValue {
-                            float32: [0.1, 0.1, 0.1, 1.0],
+                            // the overdraw adds up from black
+                            float32: if debug_view == DebugView::Overdraw {
+                                [0.0, 0.0, 0.0, 1.0]
+                            } else {
+                                [0.1, 0.1, 0.1, 1.0]
+                            },
                         },
                     })];
 
@@ -186,44 +375,16 @@ impl SequentialNode for PresentNode {
                     .dynamic_rendering
                     .cmd_begin_rendering(draw_command_buffer, &render_pass_begin_info);
 
-                device.cmd_bind_pipeline(
-                    draw_command_buffer,
-                    PipelineBindPoint::GRAPHICS,
-                    self.pipeline.pipeline,
-                );
-
-                device.cmd_bind_descriptor_sets(
-                    draw_command_buffer,
-                    PipelineBindPoint::GRAPHICS,
-                    self.pipeline.layout,
-                    0,
-                    &self.pipeline.descriptor_sets,
-                    &[],
-                );
-
-                device.cmd_set_viewport(
-                    draw_command_buffer,
-                    0,
-                    &[vk::Viewport {
-                        x: 0.0,
-                        y: 0.0,
-                        width: renderer.surface_resolution.width as f32,
-                        height: renderer.surface_resolution.height as f32,
-                        min_depth: 0.0,
-                        max_depth: 1.0,
-                    }],
-                );
-
-                device.cmd_set_scissor(
-                    draw_command_buffer,
-                    0,
-                    &[renderer.surface_resolution.into()],
-                );
-
-                let secondary_command_buffers = renderer.threaded_command_buffers.read().unwrap();
+                let secondary_command_buffers = renderer
+                    .threaded_command_buffers
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(thread_index, buffers)| (*thread_index, buffers[frame_index]))
+                    .collect::<Vec<_>>();
                 // reset all secondary command buffers
                 secondary_command_buffers.iter().for_each(|(_, buffer)| {
-                    let color_attachment_formats = &[renderer.surface_format.format];
+                    let color_attachment_formats = &[COLOR_FORMAT];
                     let mut command_buffer_inheritance_info =
                         vk::CommandBufferInheritanceRenderingInfo::default()
                             .view_mask(0)
@@ -254,69 +415,116 @@ impl SequentialNode for PresentNode {
 
                 let queue =
                     crossbeam_queue::ArrayQueue::<usize>::new(chunked_handles.len() * chunk_amount);
-                let camera_pointer = global_descriptors
-                    .buffers
-                    .get(&CAMERA_HANDLE)
+                let camera_pointer = world
+                    .resource::<ViewUniformBuffer>()
+                    .current
                     .unwrap()
                     .device_addr;
 
-                render_instance.0.command_thread_pool.scope(|scope| {
-                    let _ = info_span!("PresentNode::run::recording_draw_commands").entered();
-                    for chunk in chunked_handles.iter() {
-                        scope.spawn(|_| {
-                            let thread_index = rayon::current_thread_index().unwrap();
-                            let command_buffers = renderer.threaded_command_buffers.read().unwrap();
-                            let command_buffer = command_buffers.get(&thread_index).unwrap();
-                            let draw_command_buffer = *command_buffer;
-                            for (mesh_handle, material_handle, transform) in chunk.iter() {
-                                device.cmd_push_constants(
+                // secondary command buffers don't inherit the bound state, so every chunk binds
+                // the pipeline itself
+                let bind_pipeline =
+                    |draw_command_buffer: vk::CommandBuffer, pipeline: &GraphicsPipeline| {
+                        device.cmd_bind_pipeline(
+                            draw_command_buffer,
+                            PipelineBindPoint::GRAPHICS,
+                            pipeline.pipeline,
+                        );
+                        if !pipeline.descriptor_sets.is_empty() {
+                            device.cmd_bind_descriptor_sets(
+                                draw_command_buffer,
+                                PipelineBindPoint::GRAPHICS,
+                                pipeline.layout,
+                                0,
+                                &pipeline.descriptor_sets,
+                                &[],
+                            );
+                        }
+                        device.cmd_set_viewport(
+                            draw_command_buffer,
+                            0,
+                            &[vk::Viewport {
+                                x: 0.0,
+                                y: 0.0,
+                                width: renderer.surface_resolution.width as f32,
+                                height: renderer.surface_resolution.height as f32,
+                                min_depth: 0.0,
+                                max_depth: 1.0,
+                            }],
+                        );
+                        device.cmd_set_scissor(
+                            draw_command_buffer,
+                            0,
+                            &[renderer.surface_resolution.into()],
+                        );
+                        if pipeline.primitive.draws_lines() {
+                            device.cmd_set_line_width(draw_command_buffer, 1.0);
+                        }
+                    };
+
+                let draw_objects =
+                    |draw_command_buffer: vk::CommandBuffer,
+                     pipeline: &GraphicsPipeline,
+                     chunk: &[(&Handle<Mesh>, &Handle<Material>, &Transform)]| {
+                        for (mesh_handle, material_handle, transform) in chunk.iter() {
+                            set_push_constants(
+                                device,
+                                draw_command_buffer,
+                                pipeline,
+                                &PushConstants {
+                                    model: transform.compute_matrix(),
+                                    camera_pointer,
+                                    material_pointer: material_table.device_address(
+                                        material_table.asset_id(material_handle.id()).unwrap(),
+                                    ),
+                                },
+                            )
+                            .expect("Push constants are verified before recording");
+
+                            let mesh = &assets.meshes.get(mesh_handle).unwrap();
+
+                            device.cmd_bind_vertex_buffers(
+                                draw_command_buffer,
+                                0,
+                                &[mesh.vertex_buffer.buffer],
+                                &[0],
+                            );
+                            if let Some(index_buffer) = &mesh.index_buffer {
+                                device.cmd_bind_index_buffer(
                                     draw_command_buffer,
-                                    self.pipeline.layout,
-                                    vk::ShaderStageFlags::ALL_GRAPHICS,
+                                    index_buffer.buffer,
                                     0,
-                                    bytemuck::bytes_of(&PushConstants {
-                                        model: transform.compute_matrix(),
-                                        camera_pointer,
-                                        material_pointer: global_descriptors
-                                            .buffers
-                                            .get(&material_handle.id())
-                                            .unwrap()
-                                            .device_addr,
-                                    }),
+                                    vk::IndexType::UINT32,
                                 );
-
-                                let mesh = &assets.meshes.get(mesh_handle).unwrap();
-
-                                device.cmd_bind_vertex_buffers(
+                                device.cmd_draw_indexed(
                                     draw_command_buffer,
+                                    mesh.index_count,
+                                    1,
+                                    0,
                                     0,
-                                    &[mesh.vertex_buffer.buffer],
-                                    &[0],
+                                    1,
                                 );
-                                if let Some(index_buffer) = &mesh.index_buffer {
-                                    device.cmd_bind_index_buffer(
-                                        draw_command_buffer,
-                                        index_buffer.buffer,
-                                        0,
-                                        vk::IndexType::UINT32,
-                                    );
-                                    device.cmd_draw_indexed(
-                                        draw_command_buffer,
-                                        mesh.index_count,
-                                        1,
-                                        0,
-                                        0,
-                                        1,
-                                    );
-                                } else {
-                                    device.cmd_draw(
-                                        draw_command_buffer,
-                                        mesh.vertex_count,
-                                        1,
-                                        0,
-                                        1,
-                                    );
-                                }
+                                stats::count_draw(mesh.index_count as u64 / 3);
+                            } else {
+                                device.cmd_draw(draw_command_buffer, mesh.vertex_count, 1, 0, 1);
+                                stats::count_draw(mesh.vertex_count as u64 / 3);
+                            }
+                        }
+                    };
+
+                render_instance.0.command_thread_pool.scope(|scope| {
+                    let _ = info_span!("PresentNode::run::recording_draw_commands").entered();
+                    for chunk in chunked_handles.iter() {
+                        scope.spawn(|_| {
+                            let thread_index = rayon::current_thread_index().unwrap();
+                            let command_buffers = renderer.threaded_command_buffers.read().unwrap();
+                            let draw_command_buffer =
+                                command_buffers.get(&thread_index).unwrap()[frame_index];
+                            bind_pipeline(draw_command_buffer, pipeline);
+                            draw_objects(draw_command_buffer, pipeline, chunk);
+                            if let Some(overlay) = overlay {
+                                bind_pipeline(draw_command_buffer, overlay);
+                                draw_objects(draw_command_buffer, overlay, chunk);
                             }
                             queue.push(thread_index).unwrap();
                         });
@@ -344,11 +552,42 @@ impl SequentialNode for PresentNode {
                     .dynamic_rendering
                     .cmd_end_rendering(draw_command_buffer);
 
-                {
+                let to_sampled = barrier::image_barrier(
+                    self.color.image,
+                    vk::ImageAspectFlags::COLOR,
+                    Usage::ColorAttachmentWrite,
+                    Usage::FragmentSampled,
+                );
+                renderer.synchronization2.cmd_pipeline_barrier2(
+                    draw_command_buffer,
+                    &vk::DependencyInfo::default().image_memory_barriers(&[to_sampled]),
+                );
+                let mut target = RenderTarget::new(renderer.surface_resolution);
+                target.color_attachments.push(ColorAttachment {
+                    image: renderer.present_images[present_index as usize],
+                    view: renderer.present_image_views[present_index as usize],
+                    format: renderer.surface_format.format,
+                    clear: None,
+                    initial_usage: Usage::Undefined,
+                    final_usage: Usage::ColorAttachmentWrite,
+                });
+                self.composite.record(
+                    render_instance,
+                    draw_command_buffer,
+                    &target,
+                    composite_frame,
+                );
+
+                let captured = frame_capture.record_copy(
+                    renderer,
+                    draw_command_buffer,
+                    renderer.present_images[present_index as usize],
+                );
+                if !captured {
                     let image_memory_barrier = vk::ImageMemoryBarrier2::default()
                         .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
-                        .old_layout(vk::ImageLayout::ATTACHMENT_OPTIMAL)
+                        .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
                         .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_READ)
                         .new_layout(vk::ImageLayout::PRESENT_SRC_KHR)
@@ -368,16 +607,23 @@ impl SequentialNode for PresentNode {
                         .cmd_pipeline_barrier2(draw_command_buffer, &dependency_info);
                 }
             },
-        );
+        )?;
 
-        let wait_semaphors = [renderer.rendering_complete_semaphore];
+        let wait_semaphors = [renderer.rendering_complete_semaphores[present_index as usize]];
         let swapchains = [renderer.swapchain];
         let image_indices = [present_index];
-        let present_info = vk::PresentInfoKHR::default()
+        let mut present_info = vk::PresentInfoKHR::default()
             .wait_semaphores(&wait_semaphors)
             .swapchains(&swapchains)
             .image_indices(&image_indices);
+        // the frame pacing waits on these to know when a frame is on screen
+        let present_ids = [renderer.present_id.fetch_add(1, Ordering::Relaxed) + 1];
+        let mut present_id_info = vk::PresentIdKHR::default().present_ids(&present_ids);
+        if renderer.present_wait.is_some() {
+            present_info = present_info.push_next(&mut present_id_info);
+        }
 
+        let _queue = renderer.queue_lock.lock().unwrap();
         unsafe {
             renderer
                 .swapchain_loader
@@ -386,4 +632,8 @@ impl SequentialNode for PresentNode {
         };
         Ok(())
     }
+
+    fn async_compute_wait_stages(&self) -> vk::PipelineStageFlags {
+        vk::PipelineStageFlags::VERTEX_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER
+    }
 }
@@ -0,0 +1,238 @@
+use std::mem::size_of;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        graph::{RenderGraph, ResourceId},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{compute::ComputePass, post_process::create_image, write_image_descriptors};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct AccumulateConstants {
+    /// Clip space of the current frame to clip space of the previous frame.
+    reprojection: Mat4,
+    frame_index: u32,
+    max_samples: u32,
+    _padding: [u32; 2],
+}
+
+const HISTORY_FORMAT: vk::Format = vk::Format::R32G32B32A32_SFLOAT;
+const OUTPUT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Progressively averages a noisy, e.g. path traced, input over frames. The history is kept in
+/// two ping-pong images with the sample count in alpha, and is reprojected with the depth buffer
+/// so the camera can move without starting over. Pixels whose history falls off screen restart.
+///
+/// `shader/accumulate.comp` reads `input_texture` at binding 0, `depth_texture` at binding 1,
+/// the previous history at binding 3 and writes the new history and `output_image` at
+/// bindings 4 and 5.
+#[derive(Debug)]
+pub struct TemporalAccumulation {
+    /// Average of the accumulated samples, readable after [`TemporalAccumulation::record`].
+    pub output: Image,
+    /// Once reached, the average turns into an exponential moving average so changes in the
+    /// scene still show up.
+    pub max_samples: u32,
+    history: [Image; 2],
+    /// `passes[i]` writes `history[i]` and reads the other one.
+    passes: [ComputePass; 2],
+    frame_index: u32,
+    previous_view_proj: Mat4,
+}
+
+impl TemporalAccumulation {
+    /// `input` has to be created with `SAMPLED` usage and be in the `SHADER_READ_ONLY_OPTIMAL`
+    /// layout when recorded, just like the depth image behind `depth_view`.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        input: &mut Image,
+        depth_view: vk::ImageView,
+        max_samples: u32,
+        output_usage: vk::ImageUsageFlags,
+    ) -> Result<Self> {
+        let device = render_instance.device();
+        let extent = vk::Extent2D {
+            width: input.extent.width,
+            height: input.extent.height,
+        };
+        let input_view = input.create_view(device)?;
+
+        let mut output = create_image(
+            render_instance,
+            render_allocator,
+            extent,
+            OUTPUT_FORMAT,
+            output_usage,
+        )?;
+        let mut create_history = || {
+            create_image(
+                render_instance,
+                render_allocator,
+                extent,
+                HISTORY_FORMAT,
+                vk::ImageUsageFlags::empty(),
+            )
+        };
+        let mut history = [create_history()?, create_history()?];
+
+        let output_view = output.create_view(device)?;
+        let history_views = [
+            history[0].create_view(device)?,
+            history[1].create_view(device)?,
+        ];
+        let create_pass = |index: usize| -> Result<ComputePass> {
+            let pass = ComputePass::from_file(
+                render_instance,
+                "./shader/accumulate.comp",
+                size_of::<AccumulateConstants>() as u32,
+            )?;
+            write_image_descriptors(
+                render_instance,
+                &pass.pipeline.set_layout_info,
+                &pass.pipeline.descriptor_sets,
+                &[
+                    (0, input_view),
+                    (1, depth_view),
+                    (3, history_views[1 - index]),
+                    (4, history_views[index]),
+                    (5, output_view),
+                ],
+            );
+            Ok(pass)
+        };
+        let passes = [create_pass(0)?, create_pass(1)?];
+
+        Ok(Self {
+            output,
+            max_samples,
+            history,
+            passes,
+            frame_index: 0,
+            previous_view_proj: Mat4::IDENTITY,
+        })
+    }
+
+    /// Throws away the history, e.g. when the scene or the camera cut changes.
+    pub fn reset(&mut self) {
+        self.frame_index = 0;
+    }
+
+    /// Amount of frames recorded since the last reset.
+    pub fn frame_index(&self) -> u32 {
+        self.frame_index
+    }
+
+    /// Declares the pass in the render graph, ordered after the passes producing the noisy
+    /// `input` and `depth` and culled together with whatever consumes `output`.
+    pub fn add_to_graph(
+        &self,
+        graph: &mut RenderGraph,
+        input: ResourceId,
+        depth: ResourceId,
+        output: ResourceId,
+    ) {
+        graph.add_pass("temporal_accumulation", &[input, depth], &[output]);
+    }
+
+    /// Accumulates the current input, `view_proj` is the camera the input was rendered with.
+    /// Afterwards `output` is transitioned to `output_usage`.
+    pub fn record(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        view_proj: Mat4,
+        output_usage: Usage,
+    ) {
+        let renderer = render_instance.0.as_ref();
+        let current = (self.frame_index % 2) as usize;
+        let previous = 1 - current;
+
+        // the history written now is fully overwritten, the previous one is only valid after
+        // the first frame, but has to be in a readable layout either way
+        let previous_usage = if self.frame_index == 0 {
+            Usage::Undefined
+        } else {
+            Usage::ComputeWrite
+        };
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[
+                    barrier::image_barrier(
+                        self.history[previous].image,
+                        vk::ImageAspectFlags::COLOR,
+                        previous_usage,
+                        Usage::ComputeSampled,
+                    ),
+                    barrier::image_barrier(
+                        self.history[current].image,
+                        vk::ImageAspectFlags::COLOR,
+                        Usage::Undefined,
+                        Usage::ComputeWrite,
+                    ),
+                    barrier::image_barrier(
+                        self.output.image,
+                        vk::ImageAspectFlags::COLOR,
+                        Usage::Undefined,
+                        Usage::ComputeWrite,
+                    ),
+                ]),
+            )
+        };
+
+        let constants = AccumulateConstants {
+            reprojection: self.previous_view_proj * view_proj.inverse(),
+            frame_index: self.frame_index,
+            max_samples: self.max_samples.max(1),
+            _padding: [0; 2],
+        };
+        self.passes[current].record(
+            render_instance,
+            command_buffer,
+            (self.output.extent.width, self.output.extent.height, 1),
+            bytemuck::bytes_of(&constants),
+        );
+
+        if output_usage != Usage::ComputeWrite {
+            unsafe {
+                renderer.synchronization2.cmd_pipeline_barrier2(
+                    command_buffer,
+                    &vk::DependencyInfo::default().image_memory_barriers(&[
+                        barrier::image_barrier(
+                            self.output.image,
+                            vk::ImageAspectFlags::COLOR,
+                            Usage::ComputeWrite,
+                            output_usage,
+                        ),
+                    ]),
+                )
+            };
+        }
+
+        self.previous_view_proj = view_proj;
+        self.frame_index += 1;
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.output.destroy(device, &mut allocator);
+        for history in self.history.iter_mut() {
+            history.destroy(device, &mut allocator);
+        }
+    }
+}
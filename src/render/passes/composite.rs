@@ -0,0 +1,185 @@
+use std::mem::size_of;
+
+use ash::vk;
+
+use crate::{
+    error::Result,
+    render::{
+        graph::{RenderGraph, ResourceId},
+        RenderInstance,
+    },
+};
+
+use super::{fullscreen::FullscreenPass, graphics::RenderTarget};
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CompositeConstants {
+    input_srgb: u32,
+    output_encoding: u32,
+    /// Steps per channel of the output, `0.0` disables the dithering.
+    dither_steps: f32,
+    frame_index: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompositeSettings {
+    /// The input holds sRGB encoded values in a `_UNORM` format, like the output of the
+    /// [`PostProcessStack`](super::post_process::PostProcessStack). Otherwise it's linear.
+    pub input_srgb: bool,
+    /// Adds noise below a step of the output before it's quantized, hiding the banding of
+    /// 8 and 10 bit formats in dark gradients.
+    pub dither: bool,
+}
+
+impl Default for CompositeSettings {
+    fn default() -> Self {
+        Self {
+            input_srgb: false,
+            dither: true,
+        }
+    }
+}
+
+/// How the shader writes to a color format, matching `shader/composite.frag`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputEncoding {
+    /// `_SRGB` formats encode on write, so the shader outputs linear values.
+    Hardware = 0,
+    /// `_UNORM` formats store the values as they are, so the shader encodes them.
+    Shader = 1,
+    /// Float formats store linear values.
+    Linear = 2,
+}
+
+/// The color formats a surface can have that encode to sRGB when written.
+pub fn is_srgb_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8G8B8A8_SRGB
+            | vk::Format::B8G8R8A8_SRGB
+            | vk::Format::A8B8G8R8_SRGB_PACK32
+            | vk::Format::R8G8B8_SRGB
+            | vk::Format::B8G8R8_SRGB
+    )
+}
+
+/// The encoding and the steps per color channel of a format, `0` for float formats.
+fn output_encoding(format: vk::Format) -> (OutputEncoding, u32) {
+    if is_srgb_format(format) {
+        return (OutputEncoding::Hardware, 255);
+    }
+    match format {
+        vk::Format::A2B10G10R10_UNORM_PACK32 | vk::Format::A2R10G10B10_UNORM_PACK32 => {
+            (OutputEncoding::Shader, 1023)
+        }
+        vk::Format::R16G16B16A16_UNORM => (OutputEncoding::Shader, 65535),
+        vk::Format::R16G16B16A16_SFLOAT
+        | vk::Format::B10G11R11_UFLOAT_PACK32
+        | vk::Format::R32G32B32A32_SFLOAT => (OutputEncoding::Linear, 0),
+        // the 8 bit unorm formats, and a guess for anything else
+        _ => (OutputEncoding::Shader, 255),
+    }
+}
+
+/// The last step of a frame, converting the linear working image to the format of the
+/// swapchain. `_SRGB` and `_UNORM` surface formats both end up encoded once, which is easy to
+/// get wrong when blitting by hand.
+///
+/// `shader/composite.frag` reads the input at binding 0 with the `sampler_llc` sampler at
+/// binding 1.
+#[derive(Debug)]
+pub struct CompositePass {
+    pub settings: CompositeSettings,
+    output_encoding: OutputEncoding,
+    dither_steps: u32,
+    pass: FullscreenPass,
+}
+
+impl CompositePass {
+    /// `output_format` is the format of the target, usually `ExampleBase::surface_format`.
+    pub fn new(
+        render_instance: &RenderInstance,
+        output_format: vk::Format,
+        settings: CompositeSettings,
+    ) -> Result<Self> {
+        let (output_encoding, dither_steps) = output_encoding(output_format);
+        let pass = FullscreenPass::from_file(
+            render_instance,
+            "./shader/composite.frag",
+            &[output_format],
+            size_of::<CompositeConstants>() as u32,
+        )?;
+
+        Ok(Self {
+            settings,
+            output_encoding,
+            dither_steps,
+            pass,
+        })
+    }
+
+    /// Points the pass at the image to composite, which has to be in the
+    /// `SHADER_READ_ONLY_OPTIMAL` layout when the pass is recorded.
+    pub fn set_input(&self, render_instance: &RenderInstance, input: vk::ImageView) {
+        self.pass.set_textures(render_instance, &[(0, input)]);
+    }
+
+    pub fn add_to_graph(&self, graph: &mut RenderGraph, input: ResourceId, output: ResourceId) {
+        graph.add_pass("composite", &[input], &[output]);
+    }
+
+    /// `frame_index` varies the dithering noise, so it averages out over frames.
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        target: &RenderTarget,
+        frame_index: u32,
+    ) {
+        let dither_steps = if self.settings.dither {
+            self.dither_steps as f32
+        } else {
+            0.0
+        };
+        let constants = CompositeConstants {
+            input_srgb: self.settings.input_srgb as u32,
+            output_encoding: self.output_encoding as u32,
+            dither_steps,
+            frame_index,
+        };
+        self.pass.record(
+            render_instance,
+            command_buffer,
+            target,
+            bytemuck::bytes_of(&constants),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::{output_encoding, OutputEncoding};
+
+    #[test]
+    fn test_output_encoding() {
+        assert_eq!(
+            output_encoding(vk::Format::B8G8R8A8_SRGB),
+            (OutputEncoding::Hardware, 255)
+        );
+        assert_eq!(
+            output_encoding(vk::Format::B8G8R8A8_UNORM),
+            (OutputEncoding::Shader, 255)
+        );
+        assert_eq!(
+            output_encoding(vk::Format::A2B10G10R10_UNORM_PACK32),
+            (OutputEncoding::Shader, 1023)
+        );
+        assert_eq!(
+            output_encoding(vk::Format::R16G16B16A16_SFLOAT),
+            (OutputEncoding::Linear, 0)
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use ash::vk::{self, PipelineBindPoint};
+
+use crate::{
+    buffer::{Buffer, Image},
+    error::Result,
+    render::{
+        acceleration_structure::Tlas,
+        barrier::{self, BarrierBatch, Usage},
+        pipeline::{ComputePipeline, ComputePipelineDescriptor},
+        shaders::{Shader, ShaderKind},
+        stats, RenderInstance,
+    },
+};
+
+use super::write_acceleration_structure_descriptor;
+
+#[derive(Debug)]
+enum PassWrite {
+    Image {
+        image: vk::Image,
+        aspect_mask: vk::ImageAspectFlags,
+        next: Usage,
+    },
+    Buffer {
+        buffer: vk::Buffer,
+        next: Usage,
+    },
+}
+
+/// A compute shader with its descriptor sets, dispatched over a number of invocations
+/// instead of workgroups. Resources written by the shader are declared up front so the
+/// right barriers can be emitted after the dispatch.
+#[derive(Debug)]
+pub struct ComputePass {
+    pub pipeline: ComputePipeline,
+    writes: Vec<PassWrite>,
+}
+
+impl ComputePass {
+    pub fn new(
+        render_instance: &RenderInstance,
+        shader: Shader,
+        push_constant_size: u32,
+    ) -> Result<Self> {
+        let pipeline = ComputePipeline::new(
+            render_instance,
+            ComputePipelineDescriptor {
+                shader,
+                push_constant_range: (push_constant_size > 0).then(|| {
+                    vk::PushConstantRange::default()
+                        .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                        .offset(0)
+                        .size(push_constant_size)
+                }),
+            },
+        )?;
+
+        Ok(Self {
+            pipeline,
+            writes: vec![],
+        })
+    }
+
+    pub fn from_file(
+        render_instance: &RenderInstance,
+        path: &str,
+        push_constant_size: u32,
+    ) -> Result<Self> {
+        let shader = Shader::from_file(render_instance, path, ShaderKind::Compute, "main")?;
+        Self::new(render_instance, shader, push_constant_size)
+    }
+
+    /// Binds `tlas` to the `accelerationStructureEXT` at `binding` of set 0.
+    pub fn set_acceleration_structure(
+        &self,
+        render_instance: &RenderInstance,
+        binding: u32,
+        tlas: &Tlas,
+    ) {
+        write_acceleration_structure_descriptor(
+            render_instance,
+            &self.pipeline.set_layout_info,
+            &self.pipeline.descriptor_sets,
+            binding,
+            tlas,
+        );
+    }
+
+    /// Declares that the shader writes to `image`, which is expected to be in the `GENERAL`
+    /// layout during the dispatch. After the dispatch it's transitioned for the `next` usage.
+    pub fn add_image_write(&mut self, image: &Image, next: Usage) {
+        self.writes.push(PassWrite::Image {
+            image: image.image,
+            aspect_mask: barrier::aspect_mask_from_format(image.format),
+            next,
+        });
+    }
+
+    /// Declares that the shader writes to `buffer` and how the `next` pass is going to read it.
+    pub fn add_buffer_write(&mut self, buffer: &Buffer, next: Usage) {
+        self.writes.push(PassWrite::Buffer {
+            buffer: buffer.buffer,
+            next,
+        });
+    }
+
+    /// Amount of workgroups needed to cover `invocations`, based on the reflected `local_size`.
+    pub fn workgroup_count(&self, invocations: (u32, u32, u32)) -> (u32, u32, u32) {
+        let (x, y, z) = self.pipeline.workgroup_size;
+        (
+            (invocations.0 + x - 1) / x,
+            (invocations.1 + y - 1) / y,
+            (invocations.2 + z - 1) / z,
+        )
+    }
+
+    /// Binds the pipeline, its descriptor sets and the push constants.
+    pub(crate) fn bind(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        push_constants: &[u8],
+    ) {
+        let device = render_instance.device();
+
+        unsafe {
+            device.cmd_bind_pipeline(
+                command_buffer,
+                PipelineBindPoint::COMPUTE,
+                self.pipeline.pipeline,
+            );
+
+            if !self.pipeline.descriptor_sets.is_empty() {
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    PipelineBindPoint::COMPUTE,
+                    self.pipeline.layout,
+                    0,
+                    &self.pipeline.descriptor_sets,
+                    &[],
+                );
+            }
+
+            if !push_constants.is_empty() {
+                device.cmd_push_constants(
+                    command_buffer,
+                    self.pipeline.layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    push_constants,
+                );
+            }
+        }
+    }
+
+    /// Like [`ComputePass::record`], but the workgroup count is read from a
+    /// `VkDispatchIndirectCommand` in `buffer` at `offset`, written by an earlier pass.
+    pub fn record_indirect(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        buffer: &Buffer,
+        offset: u64,
+        push_constants: &[u8],
+    ) {
+        validate_dispatch_indirect_buffer(buffer, offset);
+        self.bind(render_instance, command_buffer, push_constants);
+        unsafe {
+            render_instance
+                .device()
+                .cmd_dispatch_indirect(command_buffer, buffer.buffer, offset)
+        };
+        stats::count_dispatch();
+        self.record_write_barriers(render_instance, command_buffer);
+    }
+
+    /// Records the dispatch followed by the barriers for all declared writes.
+    /// `push_constants` can be empty, otherwise use `bytemuck::bytes_of` to create it.
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        invocations: (u32, u32, u32),
+        push_constants: &[u8],
+    ) {
+        self.bind(render_instance, command_buffer, push_constants);
+        let (x, y, z) = self.workgroup_count(invocations);
+        unsafe {
+            render_instance
+                .device()
+                .cmd_dispatch(command_buffer, x, y, z)
+        };
+        stats::count_dispatch();
+        self.record_write_barriers(render_instance, command_buffer);
+    }
+
+    fn record_write_barriers(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+    ) {
+        let renderer = render_instance.0.as_ref();
+
+        if self.writes.is_empty() {
+            return;
+        }
+
+        let mut batch = BarrierBatch::new();
+        for write in self.writes.iter() {
+            match *write {
+                PassWrite::Image {
+                    image,
+                    aspect_mask,
+                    next,
+                } => batch.image(image, aspect_mask, Usage::ComputeWrite, next),
+                PassWrite::Buffer { buffer, next } => {
+                    batch.buffer(buffer, Usage::ComputeWrite, next)
+                }
+            };
+        }
+        batch.flush(&renderer.synchronization2, command_buffer);
+    }
+}
+
+pub(crate) fn validate_dispatch_indirect_buffer(buffer: &Buffer, offset: u64) {
+    assert!(
+        buffer.usage.contains(vk::BufferUsageFlags::INDIRECT_BUFFER),
+        "Buffer used for indirect dispatches was created without INDIRECT_BUFFER usage"
+    );
+    assert!(
+        offset % 4 == 0,
+        "Indirect buffer offset has to be a multiple of 4"
+    );
+    let end = offset + std::mem::size_of::<vk::DispatchIndirectCommand>() as u64;
+    assert!(
+        end <= buffer.size,
+        "Indirect dispatch reads up to byte {}, but the buffer is only {} bytes",
+        end,
+        buffer.size
+    );
+}
@@ -0,0 +1,245 @@
+use std::mem::size_of;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::Buffer,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        command::DrawContext,
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{compute::ComputePass, hiz::HiZPyramid, write_image_descriptors};
+
+/// World space bounding sphere of an object, `center_radius.w` is the radius.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ObjectBounds {
+    pub center_radius: Vec4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullView {
+    planes: [Vec4; 6],
+    view_proj: Mat4,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct PushConstants {
+    bounds: u64,
+    view: u64,
+    input_commands: u64,
+    output_commands: u64,
+    draw_count: u64,
+    object_count: u32,
+    _padding: u32,
+}
+
+/// Frustum culls objects on the GPU. Every object has a bounding sphere and a
+/// `vk::DrawIndexedIndirectCommand` at the same index, the commands of the visible objects are
+/// compacted into `output_commands` and their amount is written to `draw_count`.
+///
+/// Both outputs are made available for indirect draws by the barrier after the dispatch.
+#[derive(Debug)]
+pub struct CullPass {
+    pub pass: ComputePass,
+    /// Replaces `pass` once occlusion culling is enabled.
+    occlusion: Option<ComputePass>,
+    pub output_commands: Buffer,
+    /// A single `u32`, usable as the count buffer of an indirect count draw.
+    pub draw_count: Buffer,
+    view: Buffer,
+    max_objects: u32,
+}
+
+impl CullPass {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        max_objects: u32,
+    ) -> Result<Self> {
+        let device = render_instance.device();
+        let mut pass = ComputePass::from_file(
+            render_instance,
+            "./shader/cull.comp",
+            size_of::<PushConstants>() as u32,
+        )?;
+
+        let output_commands = Buffer::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size((max_objects as usize * size_of::<vk::DrawIndexedIndirectCommand>()) as u64)
+                .usage(vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::GpuOnly,
+        )?;
+        let draw_count = Buffer::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(size_of::<u32>() as u64)
+                .usage(
+                    vk::BufferUsageFlags::STORAGE_BUFFER
+                        | vk::BufferUsageFlags::INDIRECT_BUFFER
+                        | vk::BufferUsageFlags::TRANSFER_DST,
+                )
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::GpuOnly,
+        )?;
+        let view = Buffer::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(size_of::<CullView>() as u64)
+                .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+
+        pass.add_buffer_write(&output_commands, Usage::IndirectBuffer);
+        pass.add_buffer_write(&draw_count, Usage::IndirectBuffer);
+
+        Ok(Self {
+            pass,
+            occlusion: None,
+            output_commands,
+            draw_count,
+            view,
+            max_objects,
+        })
+    }
+
+    /// Extracts the frustum planes from the view projection matrix of the camera.
+    pub fn update_view(&mut self, view_proj: Mat4) {
+        let row = |index: usize| view_proj.row(index);
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            // vulkan clip space depth goes from 0 to w
+            row(2),
+            row(3) - row(2),
+        ]
+        .map(|plane| plane / plane.truncate().length());
+
+        self.view
+            .copy_from_slice(&[CullView { planes, view_proj }], 0);
+    }
+
+    /// Also culls objects hidden behind the depth in `hiz`, which has to be recorded before this
+    /// pass. The pyramid is usually built from the depth of the previous frame, so objects that
+    /// come into view show up a frame late.
+    pub fn enable_occlusion(
+        &mut self,
+        render_instance: &RenderInstance,
+        hiz: &HiZPyramid,
+    ) -> Result<()> {
+        let mut pass = ComputePass::from_file(
+            render_instance,
+            "./shader/hiz/cull.comp",
+            size_of::<PushConstants>() as u32,
+        )?;
+        write_image_descriptors(
+            render_instance,
+            &pass.pipeline.set_layout_info,
+            &pass.pipeline.descriptor_sets,
+            &[(0, hiz.view)],
+        );
+        pass.add_buffer_write(&self.output_commands, Usage::IndirectBuffer);
+        pass.add_buffer_write(&self.draw_count, Usage::IndirectBuffer);
+        self.occlusion = Some(pass);
+        Ok(())
+    }
+
+    /// `bounds` holds an [`ObjectBounds`] and `input_commands` a `vk::DrawIndexedIndirectCommand`
+    /// for each of the `object_count` objects.
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        bounds: &Buffer,
+        input_commands: &Buffer,
+        object_count: u32,
+    ) {
+        assert!(
+            object_count <= self.max_objects,
+            "CullPass was created for {} objects, but {} were submitted",
+            self.max_objects,
+            object_count
+        );
+
+        let renderer = render_instance.0.as_ref();
+        let device = render_instance.device();
+
+        // the count of the previous frame may still be read by its indirect draw
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().buffer_memory_barriers(&[barrier::buffer_barrier(
+                    self.draw_count.buffer,
+                    Usage::IndirectBuffer,
+                    Usage::TransferWrite,
+                )]),
+            );
+            device.cmd_fill_buffer(command_buffer, self.draw_count.buffer, 0, vk::WHOLE_SIZE, 0);
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().buffer_memory_barriers(&[barrier::buffer_barrier(
+                    self.draw_count.buffer,
+                    Usage::TransferWrite,
+                    Usage::ComputeWrite,
+                )]),
+            );
+        }
+
+        let push_constants = PushConstants {
+            bounds: bounds.device_addr,
+            view: self.view.device_addr,
+            input_commands: input_commands.device_addr,
+            output_commands: self.output_commands.device_addr,
+            draw_count: self.draw_count.device_addr,
+            object_count,
+            _padding: 0,
+        };
+
+        self.occlusion.as_ref().unwrap_or(&self.pass).record(
+            render_instance,
+            command_buffer,
+            (object_count, 1, 1),
+            bytemuck::bytes_of(&push_constants),
+        );
+    }
+
+    /// Draws the visible objects, with the index and vertex buffers they share already bound.
+    pub fn draw_visible(&self, ctx: &DrawContext) {
+        ctx.draw_indexed_indirect_count(
+            &self.output_commands,
+            0,
+            &self.draw_count,
+            0,
+            self.max_objects,
+            size_of::<vk::DrawIndexedIndirectCommand>() as u32,
+        );
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.output_commands.destroy(device, &mut allocator);
+        self.draw_count.destroy(device, &mut allocator);
+        self.view.destroy(device, &mut allocator);
+    }
+}
@@ -0,0 +1,312 @@
+use std::{f32::consts::TAU, mem::size_of};
+
+use ash::vk;
+use bevy::math::{Mat4, Vec3, Vec4};
+
+use crate::{
+    buffer::Buffer,
+    error::Result,
+    render::{
+        deferred_destroy::DeferredDestroyQueue,
+        pipeline::{BlendMode, CompareFunction, DepthStencilState, LineMode, PrimitiveState},
+        shaders::{Shader, ShaderKind},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{
+    graphics::{GraphicsPass, GraphicsPassDescriptor, GraphicsState, RenderTarget},
+    reserve,
+};
+
+/// Line segments used to approximate circles and spheres.
+const CIRCLE_SEGMENTS: u32 = 32;
+/// Dashes of 8 pixels with gaps as long.
+const DASH_FACTOR: u32 = 2;
+const DASH_PATTERN: u16 = 0x0f0f;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+
+/// Immediate-mode line drawing in world space, for visualizing bounds, lights and physics
+/// shapes. Shapes are added during the frame and streamed into a host visible vertex buffer by
+/// [`DebugDraw::record`], which draws them as a line list and forgets them again.
+///
+/// With a depth format the lines are depth tested against the depth attachment of the target
+/// without writing to it, otherwise they're drawn on top of everything.
+#[derive(Debug)]
+pub struct DebugDraw {
+    /// Widths other than 1.0 need `LineSupport::wide`.
+    pub line_width: f32,
+    pass: GraphicsPass,
+    vertices: Vec<DebugVertex>,
+    dashed_vertices: Vec<DebugVertex>,
+    dashed: bool,
+    vertex_buffer: Option<Buffer>,
+}
+
+impl DebugDraw {
+    pub fn new(
+        render_instance: &RenderInstance,
+        color_format: vk::Format,
+        depth: Option<(vk::Format, CompareFunction)>,
+    ) -> Result<Self> {
+        let vertex_shader = Shader::from_file(
+            render_instance,
+            "./shader/debug_draw.vert",
+            ShaderKind::Vertex,
+            "main",
+        )?;
+        let fragment_shader = Shader::from_file(
+            render_instance,
+            "./shader/debug_draw.frag",
+            ShaderKind::Fragment,
+            "main",
+        )?;
+
+        let vertex_bindings = [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: size_of::<DebugVertex>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }];
+        let vertex_attributes = [
+            vk::VertexInputAttributeDescription {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32B32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32B32A32_SFLOAT,
+                offset: 12,
+            },
+        ];
+
+        // dashes need stippled lines, without them they're drawn solid
+        let line_support = render_instance.0.line_support;
+        let (line_mode, stippled_lines) = if line_support.stippled_bresenham {
+            (LineMode::Bresenham, true)
+        } else if line_support.bresenham {
+            (LineMode::Bresenham, false)
+        } else {
+            (LineMode::Default, false)
+        };
+
+        let pass = GraphicsPass::new(
+            render_instance,
+            GraphicsPassDescriptor {
+                vertex_shader,
+                fragment_shader,
+                vertex_input: vk::PipelineVertexInputStateCreateInfo::default()
+                    .vertex_binding_descriptions(&vertex_bindings)
+                    .vertex_attribute_descriptions(&vertex_attributes),
+                state: GraphicsState {
+                    primitive: PrimitiveState {
+                        topology: vk::PrimitiveTopology::LINE_LIST,
+                        cull_mode: vk::CullModeFlags::NONE,
+                        line_mode,
+                        stippled_lines,
+                        ..Default::default()
+                    },
+                    depth_stencil: depth.map(|(format, depth_compare)| DepthStencilState {
+                        format,
+                        depth_write_enabled: false,
+                        depth_compare,
+                        stencil: Default::default(),
+                        bias: Default::default(),
+                    }),
+                    blend: BlendMode::AlphaBlend,
+                    multisample: Default::default(),
+                    shading_rate_image: false,
+                    view_mask: 0,
+                },
+                push_constant_size: size_of::<Mat4>() as u32,
+                color_formats: &[color_format],
+            },
+        )?;
+
+        Ok(Self {
+            line_width: 1.0,
+            pass,
+            vertices: vec![],
+            dashed_vertices: vec![],
+            dashed: false,
+            vertex_buffer: None,
+        })
+    }
+
+    /// Shapes added afterwards are drawn dashed, e.g. for hidden or inactive gizmos.
+    pub fn set_dashed(&mut self, dashed: bool) {
+        self.dashed = dashed;
+    }
+
+    pub fn line(&mut self, start: Vec3, end: Vec3, color: Vec4) {
+        let color = color.to_array();
+        let vertices = if self.dashed {
+            &mut self.dashed_vertices
+        } else {
+            &mut self.vertices
+        };
+        vertices.extend([
+            DebugVertex {
+                position: start.to_array(),
+                color,
+            },
+            DebugVertex {
+                position: end.to_array(),
+                color,
+            },
+        ]);
+    }
+
+    /// Edges of the cube from -1 to 1, placed by `transform`.
+    pub fn wire_box(&mut self, transform: Mat4, color: Vec4) {
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            transform.transform_point3(Vec3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { -1.0 } else { 1.0 },
+            ))
+        });
+        self.box_edges(corners, color);
+    }
+
+    pub fn aabb(&mut self, min: Vec3, max: Vec3, color: Vec4) {
+        let center = (min + max) * 0.5;
+        let half_extents = (max - min) * 0.5;
+        self.wire_box(
+            Mat4::from_translation(center) * Mat4::from_scale(half_extents),
+            color,
+        );
+    }
+
+    pub fn circle(&mut self, center: Vec3, normal: Vec3, radius: f32, color: Vec4) {
+        let (tangent, bitangent) = normal.normalize().any_orthonormal_pair();
+        let point = |i: u32| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * TAU;
+            center + (tangent * angle.cos() + bitangent * angle.sin()) * radius
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    /// Three circles around the axes.
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: Vec4) {
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            self.circle(center, axis, radius, color);
+        }
+    }
+
+    /// The axes of `transform` as red, green and blue lines of `size`.
+    pub fn axes(&mut self, transform: Mat4, size: f32) {
+        let origin = transform.transform_point3(Vec3::ZERO);
+        for (axis, color) in [
+            (Vec3::X, Vec4::new(1.0, 0.0, 0.0, 1.0)),
+            (Vec3::Y, Vec4::new(0.0, 1.0, 0.0, 1.0)),
+            (Vec3::Z, Vec4::new(0.0, 0.0, 1.0, 1.0)),
+        ] {
+            let end = origin + transform.transform_vector3(axis).normalize_or_zero() * size;
+            self.line(origin, end, color);
+        }
+    }
+
+    /// The volume seen by a camera with `view_proj`, which needs a finite far plane.
+    pub fn frustum(&mut self, view_proj: Mat4, color: Vec4) {
+        let inverse = view_proj.inverse();
+        let corners = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
+            inverse.project_point3(Vec3::new(
+                if i & 1 == 0 { -1.0 } else { 1.0 },
+                if i & 2 == 0 { -1.0 } else { 1.0 },
+                if i & 4 == 0 { 0.0 } else { 1.0 },
+            ))
+        });
+        self.box_edges(corners, color);
+    }
+
+    /// Corners are indexed by their x, y and z bit.
+    fn box_edges(&mut self, corners: [Vec3; 8], color: Vec4) {
+        for i in 0..8 {
+            for axis in [1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corners[i], corners[i | axis], color);
+                }
+            }
+        }
+    }
+
+    /// Draws everything added since the last call into `target` and clears the list.
+    pub fn record(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        deferred_destroy: &mut DeferredDestroyQueue,
+        command_buffer: vk::CommandBuffer,
+        target: &RenderTarget,
+        view_proj: Mat4,
+    ) -> Result<()> {
+        if self.vertices.is_empty() && self.dashed_vertices.is_empty() {
+            return Ok(());
+        }
+
+        // the dashed lines follow the solid ones in the same buffer
+        let solid_count = self.vertices.len() as u32;
+        let dashed_count = self.dashed_vertices.len() as u32;
+        let vertex_buffer = reserve(
+            render_instance,
+            render_allocator,
+            deferred_destroy,
+            &mut self.vertex_buffer,
+            ((solid_count + dashed_count) as usize * size_of::<DebugVertex>()) as u64,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?;
+        vertex_buffer.copy_from_slice(&self.vertices, 0);
+        vertex_buffer.copy_from_slice(
+            &self.dashed_vertices,
+            self.vertices.len() * size_of::<DebugVertex>(),
+        );
+        self.vertices.clear();
+        self.dashed_vertices.clear();
+
+        let vertex_buffer = &*vertex_buffer;
+        let view_proj = view_proj.to_cols_array();
+        let line_width = self.line_width;
+        let stippled = self.pass.pipeline.primitive.stippled_lines;
+        self.pass
+            .record(render_instance, command_buffer, target, |ctx| {
+                ctx.bind_vertex_buffer(vertex_buffer);
+                ctx.push_constants(&view_proj);
+                ctx.set_line_width(line_width);
+                if solid_count > 0 {
+                    // the stipple is dynamic state, so the solid lines need an unbroken pattern
+                    if stippled {
+                        ctx.set_line_stipple(1, 0xffff);
+                    }
+                    ctx.draw(solid_count, 0);
+                }
+                if dashed_count > 0 {
+                    if stippled {
+                        ctx.set_line_stipple(DASH_FACTOR, DASH_PATTERN);
+                    }
+                    ctx.draw(dashed_count, solid_count);
+                }
+            });
+        Ok(())
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        if let Some(mut buffer) = self.vertex_buffer.take() {
+            buffer.destroy(render_instance.device(), &mut render_allocator.allocator());
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+use crate::{
+    buffer::{Buffer, Image},
+    render::{
+        barrier::{self, Usage},
+        stats, RenderInstance,
+    },
+};
+
+use super::compute::{validate_dispatch_indirect_buffer, ComputePass};
+
+#[derive(Clone, Copy, Debug)]
+enum AccessResource {
+    Image {
+        image: vk::Image,
+        aspect_mask: vk::ImageAspectFlags,
+    },
+    Buffer(vk::Buffer),
+}
+
+/// How a dispatch uses one of the resources bound to its shader.
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    resource: AccessResource,
+    usage: Usage,
+}
+
+impl Access {
+    pub fn image(image: &Image, usage: Usage) -> Self {
+        Self {
+            resource: AccessResource::Image {
+                image: image.image,
+                aspect_mask: barrier::aspect_mask_from_format(image.format),
+            },
+            usage,
+        }
+    }
+
+    pub fn buffer(buffer: &Buffer, usage: Usage) -> Self {
+        Self {
+            resource: AccessResource::Buffer(buffer.buffer),
+            usage,
+        }
+    }
+
+    /// A `texture2D` sampled by the shader.
+    pub fn sampled(image: &Image) -> Self {
+        Self::image(image, Usage::ComputeSampled)
+    }
+
+    /// An `image2D` written by the shader.
+    pub fn storage_image(image: &Image) -> Self {
+        Self::image(image, Usage::ComputeWrite)
+    }
+
+    pub fn read_buffer(buffer: &Buffer) -> Self {
+        Self::buffer(buffer, Usage::ComputeRead)
+    }
+
+    pub fn write_buffer(buffer: &Buffer) -> Self {
+        Self::buffer(buffer, Usage::ComputeWrite)
+    }
+}
+
+/// Records compute dispatches into a command buffer while keeping track of the last usage of
+/// every resource passed to it, so the barriers between dispatches are derived from the declared
+/// accesses instead of being written by hand.
+///
+/// Resources coming from outside, like an image written by a graphics pass, should be
+/// [imported](DispatchContext::import_image) with their current usage first. Unknown images are
+/// treated as undefined. The declared writes of a [`ComputePass`] are not used here.
+pub struct DispatchContext<'a> {
+    render_instance: &'a RenderInstance,
+    pub command_buffer: vk::CommandBuffer,
+    images: HashMap<vk::Image, Usage>,
+    buffers: HashMap<vk::Buffer, Usage>,
+}
+
+impl<'a> DispatchContext<'a> {
+    pub fn new(render_instance: &'a RenderInstance, command_buffer: vk::CommandBuffer) -> Self {
+        Self {
+            render_instance,
+            command_buffer,
+            images: HashMap::new(),
+            buffers: HashMap::new(),
+        }
+    }
+
+    pub fn import_image(&mut self, image: &Image, usage: Usage) {
+        self.images.insert(image.image, usage);
+    }
+
+    pub fn import_buffer(&mut self, buffer: &Buffer, usage: Usage) {
+        self.buffers.insert(buffer.buffer, usage);
+    }
+
+    /// Binds `pass` and dispatches `workgroups`, after emitting the barriers `accesses` need.
+    /// `push_constants` can be empty, otherwise use `bytemuck::bytes_of` to create it.
+    pub fn dispatch(
+        &mut self,
+        pass: &ComputePass,
+        accesses: &[Access],
+        workgroups: [u32; 3],
+        push_constants: &[u8],
+    ) {
+        self.transition(accesses);
+        pass.bind(self.render_instance, self.command_buffer, push_constants);
+        unsafe {
+            self.render_instance.device().cmd_dispatch(
+                self.command_buffer,
+                workgroups[0],
+                workgroups[1],
+                workgroups[2],
+            )
+        };
+        stats::count_dispatch();
+    }
+
+    /// Like [`DispatchContext::dispatch`], with the workgroup count read from a
+    /// `VkDispatchIndirectCommand` in `buffer` at `offset`. The buffer is tracked as well, so a
+    /// dispatch writing the arguments right before this one gets the barrier it needs.
+    pub fn dispatch_indirect(
+        &mut self,
+        pass: &ComputePass,
+        accesses: &[Access],
+        buffer: &Buffer,
+        offset: u64,
+        push_constants: &[u8],
+    ) {
+        validate_dispatch_indirect_buffer(buffer, offset);
+        self.transition(accesses);
+        self.transition(&[Access::buffer(buffer, Usage::IndirectBuffer)]);
+        pass.bind(self.render_instance, self.command_buffer, push_constants);
+        unsafe {
+            self.render_instance.device().cmd_dispatch_indirect(
+                self.command_buffer,
+                buffer.buffer,
+                offset,
+            )
+        };
+        stats::count_dispatch();
+    }
+
+    /// Emits the barriers needed to go from the tracked usages to `accesses`, also used to hand
+    /// resources over to whatever runs after the dispatches.
+    pub fn transition(&mut self, accesses: &[Access]) {
+        let mut image_barriers = vec![];
+        let mut buffer_barriers = vec![];
+
+        for access in accesses {
+            match access.resource {
+                AccessResource::Image { image, aspect_mask } => {
+                    let previous = self
+                        .images
+                        .insert(image, access.usage)
+                        .unwrap_or(Usage::Undefined);
+                    if needs_barrier(previous, access.usage, true) {
+                        image_barriers.push(barrier::image_barrier(
+                            image,
+                            aspect_mask,
+                            previous,
+                            access.usage,
+                        ));
+                    }
+                }
+                AccessResource::Buffer(buffer) => {
+                    let Some(previous) = self.buffers.insert(buffer, access.usage) else {
+                        continue;
+                    };
+                    if needs_barrier(previous, access.usage, false) {
+                        buffer_barriers.push(barrier::buffer_barrier(
+                            buffer,
+                            previous,
+                            access.usage,
+                        ));
+                    }
+                }
+            }
+        }
+
+        if image_barriers.is_empty() && buffer_barriers.is_empty() {
+            return;
+        }
+
+        unsafe {
+            self.render_instance
+                .0
+                .synchronization2
+                .cmd_pipeline_barrier2(
+                    self.command_buffer,
+                    &vk::DependencyInfo::default()
+                        .image_memory_barriers(&image_barriers)
+                        .buffer_memory_barriers(&buffer_barriers),
+                )
+        };
+    }
+}
+
+/// Consecutive reads don't need a barrier, unless an image has to change its layout.
+fn needs_barrier(previous: Usage, next: Usage, is_image: bool) -> bool {
+    if previous.is_write() || next.is_write() {
+        return true;
+    }
+    is_image && previous.image_layout() != next.image_layout()
+}
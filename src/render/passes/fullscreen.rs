@@ -0,0 +1,119 @@
+use ash::vk;
+
+use crate::{
+    error::Result,
+    render::{
+        pipeline::{BlendMode, PrimitiveState},
+        shaders::{Shader, ShaderKind},
+        stats, RenderInstance,
+    },
+};
+
+use super::{
+    graphics::{GraphicsPass, GraphicsPassDescriptor, GraphicsState, RenderTarget},
+    write_image_descriptors,
+};
+
+/// Draws a single triangle covering the whole [`RenderTarget`] with the built-in
+/// `fullscreen.vert` and a user fragment shader, which receives the uv at location 0.
+/// This is the building block for composite and post-process steps that run as a fragment shader.
+#[derive(Debug)]
+pub struct FullscreenPass {
+    pub pass: GraphicsPass,
+}
+
+impl FullscreenPass {
+    pub fn new(
+        render_instance: &RenderInstance,
+        fragment_shader: Shader,
+        color_formats: &[vk::Format],
+        push_constant_size: u32,
+    ) -> Result<Self> {
+        let vertex_shader = Shader::from_file(
+            render_instance,
+            "./shader/fullscreen.vert",
+            ShaderKind::Vertex,
+            "main",
+        )?;
+
+        let pass = GraphicsPass::new(
+            render_instance,
+            GraphicsPassDescriptor {
+                vertex_shader,
+                fragment_shader,
+                vertex_input: vk::PipelineVertexInputStateCreateInfo::default(),
+                state: GraphicsState {
+                    primitive: PrimitiveState {
+                        topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                        cull_mode: vk::CullModeFlags::NONE,
+                        ..Default::default()
+                    },
+                    depth_stencil: None,
+                    blend: BlendMode::Replace,
+                    multisample: Default::default(),
+                    shading_rate_image: false,
+                    view_mask: 0,
+                },
+                push_constant_size,
+                color_formats,
+            },
+        )?;
+
+        Ok(Self { pass })
+    }
+
+    pub fn from_file(
+        render_instance: &RenderInstance,
+        path: &str,
+        color_formats: &[vk::Format],
+        push_constant_size: u32,
+    ) -> Result<Self> {
+        let fragment_shader =
+            Shader::from_file(render_instance, path, ShaderKind::Fragment, "main")?;
+        Self::new(
+            render_instance,
+            fragment_shader,
+            color_formats,
+            push_constant_size,
+        )
+    }
+
+    /// Copies `input_texture` to the target with a linear sampler, scaling it to the target extent.
+    pub fn blit(render_instance: &RenderInstance, color_format: vk::Format) -> Result<Self> {
+        Self::from_file(render_instance, "./shader/blit.frag", &[color_format], 0)
+    }
+
+    /// Points the sampled image bindings of set 0 at `views`, as `(binding, view)` pairs.
+    pub fn set_textures(&self, render_instance: &RenderInstance, views: &[(u32, vk::ImageView)]) {
+        write_image_descriptors(
+            render_instance,
+            &self.pass.pipeline.set_layout_info,
+            &self.pass.pipeline.descriptor_sets,
+            views,
+        );
+    }
+
+    /// `push_constants` can be empty, otherwise use `bytemuck::bytes_of` to create it.
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        target: &RenderTarget,
+        push_constants: &[u8],
+    ) {
+        self.pass
+            .record(render_instance, command_buffer, target, |ctx| unsafe {
+                if !push_constants.is_empty() {
+                    ctx.device().cmd_push_constants(
+                        ctx.command_buffer,
+                        ctx.layout,
+                        vk::ShaderStageFlags::ALL_GRAPHICS,
+                        0,
+                        push_constants,
+                    );
+                }
+                ctx.device().cmd_draw(ctx.command_buffer, 3, 1, 0, 0);
+                stats::count_draw(1);
+            });
+    }
+}
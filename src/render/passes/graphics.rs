@@ -0,0 +1,479 @@
+use ash::vk::{self, PipelineBindPoint};
+
+use crate::{
+    buffer::Image,
+    ctx::ExampleBase,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        command::DrawContext,
+        pipeline::{
+            BlendMode, CompareFunction, DepthStencilState, GraphicsPipeline,
+            GraphicsPipelineDescriptor, MultisampleState, PrimitiveState,
+        },
+        shaders::{Shader, ShaderKind},
+        RenderInstance,
+    },
+};
+
+use super::picking::ObjectPicker;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ColorAttachment {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub format: vk::Format,
+    /// `None` keeps the previous contents of the attachment.
+    pub clear: Option<[f32; 4]>,
+    /// How the image was used before the pass, `Undefined` discards its contents.
+    pub initial_usage: Usage,
+    /// How the image is going to be used after the pass.
+    pub final_usage: Usage,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DepthAttachment {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub format: vk::Format,
+    /// `None` keeps the previous contents of the attachment.
+    pub clear: Option<f32>,
+    pub initial_usage: Usage,
+    pub final_usage: Usage,
+}
+
+/// An image with a shading rate per texel, see
+/// [`ShadingRatePass`](super::shading_rate::ShadingRatePass). It has to be in the
+/// [`Usage::ShadingRateAttachment`] layout when the pass is recorded.
+#[derive(Clone, Copy, Debug)]
+pub struct ShadingRateAttachment {
+    pub view: vk::ImageView,
+    /// Pixels covered by a texel, `ExampleBase::shading_rate_texel_size`.
+    pub texel_size: vk::Extent2D,
+}
+
+/// The attachments a [`GraphicsPass`] renders into.
+#[derive(Clone, Debug)]
+pub struct RenderTarget {
+    pub extent: vk::Extent2D,
+    pub color_attachments: Vec<ColorAttachment>,
+    pub depth_attachment: Option<DepthAttachment>,
+    /// Varies the shading rate over the target, only passes created with
+    /// [`GraphicsState::shading_rate_image`] can render to it.
+    pub shading_rate: Option<ShadingRateAttachment>,
+    /// Every set bit renders the draws to that layer of the attachments with multiview, where
+    /// shaders read `gl_ViewIndex`. 0 renders to a single layer.
+    pub view_mask: u32,
+}
+
+impl RenderTarget {
+    pub fn new(extent: vk::Extent2D) -> Self {
+        Self {
+            extent,
+            color_attachments: vec![],
+            depth_attachment: None,
+            shading_rate: None,
+            view_mask: 0,
+        }
+    }
+
+    /// Targets the acquired swapchain image and the depth buffer of the renderer.
+    /// The swapchain image is transitioned for presentation at the end of the pass.
+    pub fn swapchain(renderer: &ExampleBase, present_index: u32) -> Self {
+        Self {
+            extent: renderer.surface_resolution,
+            color_attachments: vec![ColorAttachment {
+                image: renderer.present_images[present_index as usize],
+                view: renderer.present_image_views[present_index as usize],
+                format: renderer.surface_format.format,
+                clear: Some([0.1, 0.1, 0.1, 1.0]),
+                initial_usage: Usage::Undefined,
+                final_usage: Usage::Present,
+            }],
+            depth_attachment: Some(DepthAttachment {
+                image: renderer.depth_image,
+                view: renderer.depth_image_view,
+                format: renderer.depth_image_format,
+                clear: Some(1.0),
+                initial_usage: Usage::Undefined,
+                final_usage: Usage::DepthAttachmentWrite,
+            }),
+            shading_rate: None,
+            view_mask: 0,
+        }
+    }
+
+    /// Target of a depth prepass, the depth is kept for the passes that follow.
+    pub fn depth_only(extent: vk::Extent2D, depth_attachment: DepthAttachment) -> Self {
+        Self {
+            extent,
+            color_attachments: vec![],
+            depth_attachment: Some(DepthAttachment {
+                final_usage: Usage::DepthAttachmentWrite,
+                ..depth_attachment
+            }),
+            shading_rate: None,
+            view_mask: 0,
+        }
+    }
+
+    /// Renders to both layers of array attachments at once, e.g. the two eyes of an OpenXR
+    /// swapchain. Needs a device created with multiview.
+    pub fn stereo(mut self) -> Self {
+        self.view_mask = 0b11;
+        self
+    }
+
+    /// Loads the depth written by a depth prepass instead of clearing it,
+    /// pair it with [`GraphicsState::after_depth_prepass`].
+    pub fn load_prepass_depth(mut self) -> Self {
+        if let Some(attachment) = self.depth_attachment.as_mut() {
+            attachment.clear = None;
+            attachment.initial_usage = Usage::DepthAttachmentWrite;
+        }
+        self
+    }
+
+    pub fn add_color(
+        &mut self,
+        image: &mut Image,
+        device: &ash::Device,
+        clear: Option<[f32; 4]>,
+    ) -> Result<()> {
+        let view = image.create_view(device)?;
+        self.color_attachments.push(ColorAttachment {
+            image: image.image,
+            view,
+            format: image.format,
+            clear,
+            initial_usage: Usage::Undefined,
+            final_usage: Usage::ColorAttachmentWrite,
+        });
+        Ok(())
+    }
+
+    /// Adds the object id attachment of `picker` after the color attachments, the id output of
+    /// the fragment shaders goes at the matching location. Only the first pass writing ids
+    /// should `clear` it.
+    pub fn with_object_ids(mut self, picker: &ObjectPicker, clear: bool) -> Self {
+        self.color_attachments.push(picker.attachment(clear));
+        self
+    }
+
+    pub fn color_formats(&self) -> Vec<vk::Format> {
+        self.color_attachments
+            .iter()
+            .map(|attachment| attachment.format)
+            .collect()
+    }
+
+    fn barriers(&self, before: bool) -> Vec<vk::ImageMemoryBarrier2<'static>> {
+        let mut barriers = vec![];
+        for attachment in self.color_attachments.iter() {
+            let (from, to) = if before {
+                (attachment.initial_usage, Usage::ColorAttachmentWrite)
+            } else {
+                (Usage::ColorAttachmentWrite, attachment.final_usage)
+            };
+            if from != to {
+                barriers.push(barrier::image_barrier(
+                    attachment.image,
+                    vk::ImageAspectFlags::COLOR,
+                    from,
+                    to,
+                ));
+            }
+        }
+        if let Some(attachment) = self.depth_attachment.as_ref() {
+            let (from, to) = if before {
+                (attachment.initial_usage, Usage::DepthAttachmentWrite)
+            } else {
+                (Usage::DepthAttachmentWrite, attachment.final_usage)
+            };
+            if from != to {
+                barriers.push(barrier::image_barrier(
+                    attachment.image,
+                    barrier::aspect_mask_from_format(attachment.format),
+                    from,
+                    to,
+                ));
+            }
+        }
+        barriers
+    }
+}
+
+/// Fixed function state of a [`GraphicsPass`].
+#[derive(Clone, Debug, Default)]
+pub struct GraphicsState {
+    pub primitive: PrimitiveState,
+    pub depth_stencil: Option<DepthStencilState>,
+    pub blend: BlendMode,
+    /// Has to match the sample count of the [`RenderTarget`]s the pass records into.
+    pub multisample: MultisampleState,
+    /// Takes the shading rate from the [`RenderTarget::shading_rate`] image.
+    pub shading_rate_image: bool,
+    /// Multiview mask of the [`RenderTarget`]s the pass records into.
+    pub view_mask: u32,
+}
+
+impl GraphicsState {
+    /// Writes depth only, used by [`GraphicsPass::depth_prepass`].
+    pub fn depth_prepass(format: vk::Format) -> Self {
+        Self {
+            primitive: PrimitiveState {
+                topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                ..Default::default()
+            },
+            depth_stencil: Some(DepthStencilState {
+                format,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Less,
+                stencil: Default::default(),
+                bias: Default::default(),
+            }),
+            blend: BlendMode::Replace,
+            multisample: Default::default(),
+            shading_rate_image: false,
+            view_mask: 0,
+        }
+    }
+
+    /// Only shades the fragments that ended up closest in the depth prepass, depth writes
+    /// are turned off since the depth buffer is already complete.
+    pub fn after_depth_prepass(mut self, format: vk::Format) -> Self {
+        self.depth_stencil = Some(DepthStencilState {
+            format,
+            depth_write_enabled: false,
+            depth_compare: CompareFunction::Equal,
+            stencil: Default::default(),
+            bias: Default::default(),
+        });
+        self
+    }
+}
+
+pub struct GraphicsPassDescriptor<'a> {
+    pub vertex_shader: Shader,
+    pub fragment_shader: Shader,
+    pub vertex_input: vk::PipelineVertexInputStateCreateInfo<'a>,
+    pub state: GraphicsState,
+    /// Size of the push constant block, shared between all graphics stages.
+    pub push_constant_size: u32,
+    pub color_formats: &'a [vk::Format],
+}
+
+/// A graphics pipeline that records into a [`RenderTarget`]. It takes care of the attachment
+/// transitions, dynamic rendering, binding and the viewport so only the draws are left to the user.
+#[derive(Debug)]
+pub struct GraphicsPass {
+    pub pipeline: GraphicsPipeline,
+}
+
+impl GraphicsPass {
+    pub fn new(render_instance: &RenderInstance, desc: GraphicsPassDescriptor) -> Result<Self> {
+        let pipeline = GraphicsPipeline::new(
+            render_instance,
+            GraphicsPipelineDescriptor {
+                vertex_shader: desc.vertex_shader,
+                task_shader: None,
+                fragment_shader: desc.fragment_shader,
+                vertex_input: desc.vertex_input,
+                viewport: render_instance.0.surface_resolution,
+                primitive: desc.state.primitive,
+                depth_stencil: desc.state.depth_stencil,
+                blend: desc.state.blend,
+                multisample: desc.state.multisample,
+                shading_rate_image: desc.state.shading_rate_image,
+                view_mask: desc.state.view_mask,
+                push_constant_range: (desc.push_constant_size > 0).then(|| {
+                    vk::PushConstantRange::default()
+                        .stage_flags(vk::ShaderStageFlags::ALL_GRAPHICS)
+                        .offset(0)
+                        .size(desc.push_constant_size)
+                }),
+                color_formats: desc.color_formats,
+            },
+        )?;
+
+        Ok(Self { pipeline })
+    }
+
+    /// A pass without color attachments that fills the depth buffer, so later passes using
+    /// [`GraphicsState::after_depth_prepass`] only shade visible fragments. The vertex shader
+    /// has to produce the exact same positions as the one of the later passes.
+    pub fn depth_prepass(
+        render_instance: &RenderInstance,
+        vertex_shader: Shader,
+        vertex_input: vk::PipelineVertexInputStateCreateInfo,
+        push_constant_size: u32,
+        depth_format: vk::Format,
+    ) -> Result<Self> {
+        let fragment_shader = Shader::from_file(
+            render_instance,
+            "./shader/depth_only.frag",
+            ShaderKind::Fragment,
+            "main",
+        )?;
+
+        Self::new(
+            render_instance,
+            GraphicsPassDescriptor {
+                vertex_shader,
+                fragment_shader,
+                vertex_input,
+                state: GraphicsState::depth_prepass(depth_format),
+                push_constant_size,
+                color_formats: &[],
+            },
+        )
+    }
+
+    pub fn record<F: FnOnce(&DrawContext)>(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        target: &RenderTarget,
+        draw: F,
+    ) {
+        let renderer = render_instance.0.as_ref();
+        let device = render_instance.device();
+        assert_eq!(
+            target.view_mask, self.pipeline.view_mask,
+            "Render target and pass have a different view mask"
+        );
+        assert!(
+            target.shading_rate.is_none() || self.pipeline.shading_rate_image,
+            "Pass wasn't created for a render target with a shading rate image"
+        );
+
+        let barriers = target.barriers(true);
+        if !barriers.is_empty() {
+            unsafe {
+                renderer.synchronization2.cmd_pipeline_barrier2(
+                    command_buffer,
+                    &vk::DependencyInfo::default().image_memory_barriers(&barriers),
+                )
+            };
+        }
+
+        let color_attachments = target
+            .color_attachments
+            .iter()
+            .map(|attachment| {
+                vk::RenderingAttachmentInfo::default()
+                    .image_view(attachment.view)
+                    .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .load_op(if attachment.clear.is_some() {
+                        vk::AttachmentLoadOp::CLEAR
+                    } else {
+                        vk::AttachmentLoadOp::LOAD
+                    })
+                    .store_op(vk::AttachmentStoreOp::STORE)
+                    .clear_value(vk::ClearValue {
+                        color: vk::ClearColorValue {
+                            float32: attachment.clear.unwrap_or_default(),
+                        },
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let depth_attachment = target.depth_attachment.as_ref().map(|attachment| {
+            vk::RenderingAttachmentInfo::default()
+                .image_view(attachment.view)
+                .image_layout(vk::ImageLayout::DEPTH_ATTACHMENT_OPTIMAL)
+                .load_op(if attachment.clear.is_some() {
+                    vk::AttachmentLoadOp::CLEAR
+                } else {
+                    vk::AttachmentLoadOp::LOAD
+                })
+                .store_op(vk::AttachmentStoreOp::STORE)
+                .clear_value(vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        depth: attachment.clear.unwrap_or(1.0),
+                        stencil: 0,
+                    },
+                })
+        });
+
+        let mut rendering_info = vk::RenderingInfo::default()
+            .render_area(target.extent.into())
+            .layer_count(1)
+            .view_mask(target.view_mask)
+            .color_attachments(&color_attachments);
+        if let Some(depth_attachment) = depth_attachment.as_ref() {
+            rendering_info = rendering_info.depth_attachment(depth_attachment);
+        }
+        let mut shading_rate_attachment = target.shading_rate.as_ref().map(|attachment| {
+            vk::RenderingFragmentShadingRateAttachmentInfoKHR::default()
+                .image_view(attachment.view)
+                .image_layout(Usage::ShadingRateAttachment.image_layout())
+                .shading_rate_attachment_texel_size(attachment.texel_size)
+        });
+        if let Some(shading_rate_attachment) = shading_rate_attachment.as_mut() {
+            rendering_info = rendering_info.push_next(shading_rate_attachment);
+        }
+
+        unsafe {
+            renderer
+                .dynamic_rendering
+                .cmd_begin_rendering(command_buffer, &rendering_info);
+
+            device.cmd_bind_pipeline(
+                command_buffer,
+                PipelineBindPoint::GRAPHICS,
+                self.pipeline.pipeline,
+            );
+
+            if !self.pipeline.descriptor_sets.is_empty() {
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    PipelineBindPoint::GRAPHICS,
+                    self.pipeline.layout,
+                    0,
+                    &self.pipeline.descriptor_sets,
+                    &[],
+                );
+            }
+
+            device.cmd_set_viewport(
+                command_buffer,
+                0,
+                &[vk::Viewport {
+                    x: 0.0,
+                    y: 0.0,
+                    width: target.extent.width as f32,
+                    height: target.extent.height as f32,
+                    min_depth: 0.0,
+                    max_depth: 1.0,
+                }],
+            );
+            device.cmd_set_scissor(command_buffer, 0, &[target.extent.into()]);
+        }
+
+        let ctx = DrawContext {
+            render_instance,
+            command_buffer,
+            layout: self.pipeline.layout,
+        };
+        if self.pipeline.primitive.draws_lines() {
+            ctx.set_line_width(1.0);
+        }
+        if self.pipeline.primitive.stippled_lines {
+            ctx.set_line_stipple(1, u16::MAX);
+        }
+        draw(&ctx);
+
+        unsafe { renderer.dynamic_rendering.cmd_end_rendering(command_buffer) };
+
+        let barriers = target.barriers(false);
+        if !barriers.is_empty() {
+            unsafe {
+                renderer.synchronization2.cmd_pipeline_barrier2(
+                    command_buffer,
+                    &vk::DependencyInfo::default().image_memory_barriers(&barriers),
+                )
+            };
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use ash::vk;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{compute::ComputePass, write_image_descriptors};
+
+const HIZ_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+/// A depth pyramid for occlusion culling. Every mip halves the resolution and keeps the farthest
+/// depth of the texels below it, the first mip is half the depth resolution. Assumes reversed
+/// depth, like [`Camera`](crate::render::camera::Camera) produces.
+#[derive(Debug)]
+pub struct HiZPyramid {
+    pub image: Image,
+    /// All mips, bound by [`CullPass::enable_occlusion`](super::cull::CullPass::enable_occlusion).
+    pub view: vk::ImageView,
+    mip_views: Vec<vk::ImageView>,
+    depth_view: vk::ImageView,
+    /// `passes[0]` reads the depth target, every next one the mip before it.
+    passes: Vec<ComputePass>,
+}
+
+impl HiZPyramid {
+    /// `depth` has to be created with `SAMPLED` usage and be in the `SHADER_READ_ONLY_OPTIMAL`
+    /// layout when the pyramid is recorded.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        depth: &Image,
+    ) -> Result<Self> {
+        let device = render_instance.device();
+        let width = (depth.extent.width / 2).max(1);
+        let height = (depth.extent.height / 2).max(1);
+        let mip_count = u32::BITS - width.max(height).leading_zeros();
+
+        let image = Image::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(HIZ_FORMAT)
+                .extent(vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1,
+                })
+                .mip_levels(mip_count)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?;
+
+        let mips = |base_mip_level, level_count| vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level,
+            level_count,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let view = create_view(device, image.image, HIZ_FORMAT, mips(0, mip_count))?;
+        let mip_views = (0..mip_count)
+            .map(|level| create_view(device, image.image, HIZ_FORMAT, mips(level, 1)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let depth_view = create_view(
+            device,
+            depth.image,
+            depth.format,
+            vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                ..mips(0, 1)
+            },
+        )?;
+
+        let passes = (0..mip_count as usize)
+            .map(|level| {
+                let (path, source) = if level == 0 {
+                    ("./shader/hiz/depth_reduce.comp", depth_view)
+                } else {
+                    ("./shader/hiz/downsample.comp", mip_views[level - 1])
+                };
+                let mut pass = ComputePass::from_file(render_instance, path, 0)?;
+                write_image_descriptors(
+                    render_instance,
+                    &pass.pipeline.set_layout_info,
+                    &pass.pipeline.descriptor_sets,
+                    &[(0, source), (2, mip_views[level])],
+                );
+                // the whole pyramid stays in the general layout until the last mip is written
+                let next = if level + 1 == mip_count as usize {
+                    Usage::ComputeSampled
+                } else {
+                    Usage::ComputeRead
+                };
+                pass.add_image_write(&image, next);
+                Ok(pass)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            image,
+            view,
+            mip_views,
+            depth_view,
+            passes,
+        })
+    }
+
+    pub fn mip_count(&self) -> u32 {
+        self.mip_views.len() as u32
+    }
+
+    /// Builds the pyramid from the depth target, afterwards it's ready to be sampled by a
+    /// compute shader.
+    pub fn record(&self, render_instance: &RenderInstance, command_buffer: vk::CommandBuffer) {
+        let renderer = render_instance.0.as_ref();
+        // every mip is fully overwritten
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[barrier::image_barrier(
+                    self.image.image,
+                    vk::ImageAspectFlags::COLOR,
+                    Usage::Undefined,
+                    Usage::ComputeWrite,
+                )]),
+            )
+        };
+
+        for (level, pass) in self.passes.iter().enumerate() {
+            pass.record(
+                render_instance,
+                command_buffer,
+                (
+                    (self.image.extent.width >> level).max(1),
+                    (self.image.extent.height >> level).max(1),
+                    1,
+                ),
+                &[],
+            );
+        }
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        unsafe {
+            for view in self.mip_views.drain(..) {
+                device.destroy_image_view(view, None);
+            }
+            device.destroy_image_view(self.view, None);
+            device.destroy_image_view(self.depth_view, None);
+        }
+        self.image
+            .destroy(device, &mut render_allocator.allocator());
+    }
+}
+
+fn create_view(
+    device: &ash::Device,
+    image: vk::Image,
+    format: vk::Format,
+    subresource_range: vk::ImageSubresourceRange,
+) -> ash::prelude::VkResult<vk::ImageView> {
+    unsafe {
+        device.create_image_view(
+            &vk::ImageViewCreateInfo::default()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .subresource_range(subresource_range),
+            None,
+        )
+    }
+}
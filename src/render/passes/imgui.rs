@@ -0,0 +1,429 @@
+use std::mem::size_of;
+
+use ash::vk;
+use imgui::{
+    internal::RawWrapper, DrawCmd, DrawCmdParams, DrawData, DrawIdx, DrawVert, TextureId, Textures,
+};
+
+use crate::{
+    buffer::{Buffer, Image},
+    error::Result,
+    render::{
+        deferred_destroy::DeferredDestroyQueue,
+        pipeline::{BlendMode, PrimitiveState},
+        shaders::{Shader, ShaderKind},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{
+    composite::is_srgb_format,
+    graphics::{GraphicsPass, GraphicsPassDescriptor, GraphicsState, RenderTarget},
+    reserve, write_image_descriptors,
+};
+
+/// Amount of textures that can be registered besides the font atlas.
+const MAX_TEXTURES: u32 = 64;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ImguiConstants {
+    scale: [f32; 2],
+    translate: [f32; 2],
+    srgb_target: u32,
+}
+
+/// Same layout as [`DrawVert`], which isn't [`bytemuck::Pod`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ImguiVertex {
+    pos: [f32; 2],
+    uv: [f32; 2],
+    col: [u8; 4],
+}
+
+/// Renders the [`DrawData`] of an `imgui` context into a [`RenderTarget`], blending over its
+/// contents. Vertices and indices are streamed into host visible buffers that grow as needed,
+/// the buffers they replace go through the [`DeferredDestroyQueue`].
+///
+/// Textures used with `imgui::Image` have to be registered with [`ImguiRenderer::register_texture`]
+/// and be in the `FragmentSampled` usage when the draw data is recorded.
+#[derive(Debug)]
+pub struct ImguiRenderer {
+    pass: GraphicsPass,
+    font_atlas: Image,
+    descriptor_pool: vk::DescriptorPool,
+    textures: Textures<vk::DescriptorSet>,
+    vertex_buffer: Option<Buffer>,
+    index_buffer: Option<Buffer>,
+}
+
+impl ImguiRenderer {
+    /// Builds the font atlas of `context` and uploads it, `color_format` is the format of the
+    /// render targets the draw data is recorded into.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        context: &mut imgui::Context,
+        color_format: vk::Format,
+    ) -> Result<Self> {
+        let vertex_shader = Shader::from_file(
+            render_instance,
+            "./shader/imgui.vert",
+            ShaderKind::Vertex,
+            "main",
+        )?;
+        let fragment_shader = Shader::from_file(
+            render_instance,
+            "./shader/imgui.frag",
+            ShaderKind::Fragment,
+            "main",
+        )?;
+
+        let vertex_bindings = [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: size_of::<DrawVert>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }];
+        let vertex_attributes = [
+            vk::VertexInputAttributeDescription {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 0,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 8,
+            },
+            vk::VertexInputAttributeDescription {
+                location: 2,
+                binding: 0,
+                format: vk::Format::R8G8B8A8_UNORM,
+                offset: 16,
+            },
+        ];
+
+        let pass = GraphicsPass::new(
+            render_instance,
+            GraphicsPassDescriptor {
+                vertex_shader,
+                fragment_shader,
+                vertex_input: vk::PipelineVertexInputStateCreateInfo::default()
+                    .vertex_binding_descriptions(&vertex_bindings)
+                    .vertex_attribute_descriptions(&vertex_attributes),
+                state: GraphicsState {
+                    primitive: PrimitiveState {
+                        topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                        cull_mode: vk::CullModeFlags::NONE,
+                        ..Default::default()
+                    },
+                    depth_stencil: None,
+                    blend: BlendMode::AlphaBlend,
+                    multisample: Default::default(),
+                    shading_rate_image: false,
+                    view_mask: 0,
+                },
+                push_constant_size: size_of::<ImguiConstants>() as u32,
+                color_formats: &[color_format],
+            },
+        )?;
+
+        let fonts = context.fonts();
+        let atlas = fonts.build_rgba32_texture();
+        let pixels = image::RgbaImage::from_raw(atlas.width, atlas.height, atlas.data.to_vec())
+            .expect("Font atlas size doesn't match its data");
+        let mut font_atlas = Image::from_image_buffer(
+            render_instance,
+            render_allocator,
+            image::DynamicImage::ImageRgba8(pixels),
+            vk::Format::R8G8B8A8_UNORM,
+        )?;
+        let font_view = font_atlas.create_view(render_instance.device())?;
+
+        // the font atlas uses the descriptor set of the pipeline, registered textures get their
+        // own sets from this pool
+        let font_set = pass.pipeline.descriptor_sets[0];
+        write_image_descriptors(
+            render_instance,
+            &pass.pipeline.set_layout_info,
+            &[font_set],
+            &[(0, font_view)],
+        );
+        let mut textures = Textures::new();
+        fonts.tex_id = textures.insert(font_set);
+
+        let descriptor_pool = unsafe {
+            render_instance.device().create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::default()
+                    .pool_sizes(&[
+                        vk::DescriptorPoolSize {
+                            ty: vk::DescriptorType::SAMPLED_IMAGE,
+                            descriptor_count: MAX_TEXTURES,
+                        },
+                        vk::DescriptorPoolSize {
+                            ty: vk::DescriptorType::SAMPLER,
+                            descriptor_count: MAX_TEXTURES,
+                        },
+                    ])
+                    .max_sets(MAX_TEXTURES),
+                None,
+            )?
+        };
+
+        Ok(Self {
+            pass,
+            font_atlas,
+            descriptor_pool,
+            textures,
+            vertex_buffer: None,
+            index_buffer: None,
+        })
+    }
+
+    /// Makes `view` drawable by `imgui::Image` and friends with the returned id.
+    pub fn register_texture(
+        &mut self,
+        render_instance: &RenderInstance,
+        view: vk::ImageView,
+    ) -> TextureId {
+        let descriptor_set = unsafe {
+            render_instance
+                .device()
+                .allocate_descriptor_sets(
+                    &vk::DescriptorSetAllocateInfo::default()
+                        .descriptor_pool(self.descriptor_pool)
+                        .set_layouts(&self.pass.pipeline.descriptor_set_layouts[..1]),
+                )
+                .unwrap_or_else(|_| panic!("More than {} imgui textures", MAX_TEXTURES))[0]
+        };
+        write_image_descriptors(
+            render_instance,
+            &self.pass.pipeline.set_layout_info,
+            &[descriptor_set],
+            &[(0, view)],
+        );
+        self.textures.insert(descriptor_set)
+    }
+
+    /// Records `draw_data` into `target`, with a scissor per draw command. Does nothing when the
+    /// display is minimized.
+    pub fn record(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        deferred_destroy: &mut DeferredDestroyQueue,
+        command_buffer: vk::CommandBuffer,
+        target: &RenderTarget,
+        draw_data: &DrawData,
+    ) -> Result<()> {
+        let framebuffer_width = draw_data.display_size[0] * draw_data.framebuffer_scale[0];
+        let framebuffer_height = draw_data.display_size[1] * draw_data.framebuffer_scale[1];
+        if framebuffer_width <= 0.0 || framebuffer_height <= 0.0 {
+            return Ok(());
+        }
+
+        let vertex_count = draw_data.total_vtx_count as usize;
+        let index_count = draw_data.total_idx_count as usize;
+        if vertex_count > 0 {
+            self.upload(
+                render_instance,
+                render_allocator,
+                deferred_destroy,
+                draw_data,
+                vertex_count,
+                index_count,
+            )?;
+        }
+
+        let scale = [
+            2.0 / draw_data.display_size[0],
+            2.0 / draw_data.display_size[1],
+        ];
+        let constants = ImguiConstants {
+            scale,
+            translate: [
+                -1.0 - draw_data.display_pos[0] * scale[0],
+                -1.0 - draw_data.display_pos[1] * scale[1],
+            ],
+            srgb_target: is_srgb(target) as u32,
+        };
+
+        self.pass
+            .record(render_instance, command_buffer, target, |ctx| {
+                if vertex_count == 0 {
+                    return;
+                }
+
+                let bind_buffers = || unsafe {
+                    ctx.device().cmd_bind_vertex_buffers(
+                        ctx.command_buffer,
+                        0,
+                        &[self.vertex_buffer.as_ref().unwrap().buffer],
+                        &[0],
+                    );
+                    ctx.device().cmd_bind_index_buffer(
+                        ctx.command_buffer,
+                        self.index_buffer.as_ref().unwrap().buffer,
+                        0,
+                        if size_of::<DrawIdx>() == 2 {
+                            vk::IndexType::UINT16
+                        } else {
+                            vk::IndexType::UINT32
+                        },
+                    );
+                    ctx.push_constants(&constants);
+                };
+                bind_buffers();
+
+                let mut vertex_base = 0;
+                let mut index_base = 0;
+                for draw_list in draw_data.draw_lists() {
+                    for command in draw_list.commands() {
+                        match command {
+                            DrawCmd::Elements {
+                                count,
+                                cmd_params:
+                                    DrawCmdParams {
+                                        clip_rect,
+                                        texture_id,
+                                        vtx_offset,
+                                        idx_offset,
+                                    },
+                            } => {
+                                let Some(scissor) = scissor(draw_data, clip_rect, target.extent)
+                                else {
+                                    continue;
+                                };
+                                let descriptor_set = *self.textures.get(texture_id).expect(
+                                    "Texture id was not registered with the imgui renderer",
+                                );
+
+                                unsafe {
+                                    ctx.device().cmd_bind_descriptor_sets(
+                                        ctx.command_buffer,
+                                        vk::PipelineBindPoint::GRAPHICS,
+                                        ctx.layout,
+                                        0,
+                                        &[descriptor_set],
+                                        &[],
+                                    );
+                                    ctx.device()
+                                        .cmd_set_scissor(ctx.command_buffer, 0, &[scissor]);
+                                }
+                                ctx.draw_indexed(
+                                    count as u32,
+                                    (index_base + idx_offset) as u32,
+                                    (vertex_base + vtx_offset) as i32,
+                                );
+                            }
+                            DrawCmd::ResetRenderState => bind_buffers(),
+                            // like the reference backends, callbacks only get the draw list and
+                            // the command, anything else goes through its `UserCallbackData`
+                            DrawCmd::RawCallback { callback, raw_cmd } => unsafe {
+                                callback(draw_list.raw(), raw_cmd)
+                            },
+                        }
+                    }
+                    vertex_base += draw_list.vtx_buffer().len();
+                    index_base += draw_list.idx_buffer().len();
+                }
+            });
+        Ok(())
+    }
+
+    /// Copies the vertices and indices of all draw lists after each other, growing the buffers
+    /// when they're too small.
+    fn upload(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        deferred_destroy: &mut DeferredDestroyQueue,
+        draw_data: &DrawData,
+        vertex_count: usize,
+        index_count: usize,
+    ) -> Result<()> {
+        let vertex_buffer = reserve(
+            render_instance,
+            render_allocator,
+            deferred_destroy,
+            &mut self.vertex_buffer,
+            (vertex_count * size_of::<DrawVert>()) as u64,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?;
+        let mut offset = 0;
+        for draw_list in draw_data.draw_lists() {
+            // SAFETY: ImguiVertex has the fields of DrawVert, which the size and alignment are
+            // checked against
+            let vertices = unsafe { draw_list.transmute_vtx_buffer::<ImguiVertex>() };
+            vertex_buffer.copy_from_slice(vertices, offset);
+            offset += draw_list.vtx_buffer().len() * size_of::<DrawVert>();
+        }
+
+        let index_buffer = reserve(
+            render_instance,
+            render_allocator,
+            deferred_destroy,
+            &mut self.index_buffer,
+            (index_count * size_of::<DrawIdx>()) as u64,
+            vk::BufferUsageFlags::INDEX_BUFFER,
+        )?;
+        let mut offset = 0;
+        for draw_list in draw_data.draw_lists() {
+            index_buffer.copy_from_slice(draw_list.idx_buffer(), offset);
+            offset += draw_list.idx_buffer().len() * size_of::<DrawIdx>();
+        }
+        Ok(())
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        if let Some(mut buffer) = self.vertex_buffer.take() {
+            buffer.destroy(device, &mut allocator);
+        }
+        if let Some(mut buffer) = self.index_buffer.take() {
+            buffer.destroy(device, &mut allocator);
+        }
+        self.font_atlas.destroy(device, &mut allocator);
+        unsafe { device.destroy_descriptor_pool(self.descriptor_pool, None) };
+    }
+}
+
+/// The clip rectangle of a draw command in framebuffer pixels, `None` when nothing is visible.
+fn scissor(draw_data: &DrawData, clip_rect: [f32; 4], extent: vk::Extent2D) -> Option<vk::Rect2D> {
+    let [scale_x, scale_y] = draw_data.framebuffer_scale;
+    let [x, y] = draw_data.display_pos;
+
+    let min_x = ((clip_rect[0] - x) * scale_x).max(0.0);
+    let min_y = ((clip_rect[1] - y) * scale_y).max(0.0);
+    let max_x = ((clip_rect[2] - x) * scale_x).min(extent.width as f32);
+    let max_y = ((clip_rect[3] - y) * scale_y).min(extent.height as f32);
+    if max_x <= min_x || max_y <= min_y {
+        return None;
+    }
+
+    Some(vk::Rect2D {
+        offset: vk::Offset2D {
+            x: min_x as i32,
+            y: min_y as i32,
+        },
+        extent: vk::Extent2D {
+            width: (max_x - min_x) as u32,
+            height: (max_y - min_y) as u32,
+        },
+    })
+}
+
+fn is_srgb(target: &RenderTarget) -> bool {
+    target
+        .color_attachments
+        .first()
+        .is_some_and(|attachment| is_srgb_format(attachment.format))
+}
@@ -0,0 +1,127 @@
+use std::mem::size_of;
+
+use ash::vk;
+
+use crate::{
+    buffer::Buffer,
+    ctx::CooperativeMatrixConfig,
+    error::{Error, Result},
+    render::{barrier::Usage, RenderInstance},
+};
+
+use super::{compute::ComputePass, scan::record_barrier};
+
+/// Size of the tile every subgroup computes, see `shader/cooperative_matmul.comp`.
+const TILE: u32 = 16;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct MatMulConstants {
+    a: u64,
+    b: u64,
+    c: u64,
+    m: u32,
+    n: u32,
+    k: u32,
+    _padding: u32,
+}
+
+/// The configuration the shader is written for, float16 inputs accumulated in float32.
+fn is_supported_config(config: &CooperativeMatrixConfig) -> bool {
+    config.m == TILE
+        && config.n == TILE
+        && config.k == TILE
+        && config.a_type == vk::ComponentTypeKHR::FLOAT16
+        && config.b_type == vk::ComponentTypeKHR::FLOAT16
+        && config.c_type == vk::ComponentTypeKHR::FLOAT32
+        && config.result_type == vk::ComponentTypeKHR::FLOAT32
+        && config.scope == vk::ScopeKHR::SUBGROUP
+}
+
+/// Multiplies row major matrices on the tensor cores with `VK_KHR_cooperative_matrix`, mostly
+/// an example of a kernel using it. `a` and `b` hold IEEE half precision floats and
+/// `c` gets `f32`s, every dimension has to be a multiple of 16.
+#[derive(Debug)]
+pub struct CooperativeMatMul {
+    pass: ComputePass,
+}
+
+impl CooperativeMatMul {
+    /// Fails when the device can't do 16x16x16 float16 multiplies, or the cooperative matrix
+    /// wasn't requested through [`DeviceRequirements`](crate::ctx::DeviceRequirements).
+    pub fn new(render_instance: &RenderInstance) -> Result<Self> {
+        if !render_instance
+            .0
+            .cooperative_matrix
+            .iter()
+            .any(is_supported_config)
+        {
+            return Err(Error::Unsupported(
+                "The device doesn't support 16x16x16 float16 cooperative matrices".to_string(),
+            ));
+        }
+
+        Ok(Self {
+            pass: ComputePass::from_file(
+                render_instance,
+                "./shader/cooperative_matmul.comp",
+                size_of::<MatMulConstants>() as u32,
+            )?,
+        })
+    }
+
+    /// Writes `a * b` to `c`, `a` being `m` by `k` and `b` being `k` by `n`. Previous writes to
+    /// `a` and `b` have to be visible to compute shaders, afterwards `c` is made visible for the
+    /// `next` usage.
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        a: &Buffer,
+        b: &Buffer,
+        c: &Buffer,
+        (m, n, k): (u32, u32, u32),
+        next: Usage,
+    ) {
+        assert!(
+            m % TILE == 0 && n % TILE == 0 && k % TILE == 0,
+            "Matrix dimensions have to be multiples of {}, got {}x{}x{}",
+            TILE,
+            m,
+            n,
+            k
+        );
+        assert!(
+            a.size >= m as u64 * k as u64 * 2 && b.size >= k as u64 * n as u64 * 2,
+            "The input buffers are too small for {}x{}x{} float16 matrices",
+            m,
+            n,
+            k
+        );
+        assert!(
+            c.size >= m as u64 * n as u64 * 4,
+            "The output buffer is too small for a {}x{} float32 matrix",
+            m,
+            n
+        );
+
+        let constants = MatMulConstants {
+            a: a.device_addr,
+            b: b.device_addr,
+            c: c.device_addr,
+            m,
+            n,
+            k,
+            _padding: 0,
+        };
+        let subgroup_size = self.pass.pipeline.workgroup_size.0;
+        self.pass.record(
+            render_instance,
+            command_buffer,
+            (n / TILE * subgroup_size, m / TILE, 1),
+            bytemuck::bytes_of(&constants),
+        );
+        record_barrier(render_instance, command_buffer, Usage::ComputeWrite, next);
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{
+    acceleration_structure::Tlas, deferred_destroy::DeferredDestroyQueue,
+    descriptor_writer::DescriptorWriter, RenderAllocator, RenderInstance,
+};
+
+pub mod accumulation;
+pub mod composite;
+pub mod compute;
+pub mod cull;
+pub mod debug_draw;
+pub mod dispatch;
+pub mod fullscreen;
+pub mod graphics;
+pub mod hiz;
+#[cfg(feature = "imgui")]
+pub mod imgui;
+pub mod matmul;
+pub mod offscreen;
+pub mod particles;
+pub mod picking;
+pub mod post_process;
+pub mod scan;
+pub mod shading_rate;
+pub mod shadow;
+pub mod sort;
+#[cfg(feature = "text")]
+pub mod text;
+pub mod upscale;
+
+/// Writes image views to the bindings of set 0, the descriptor type is taken from the reflected
+/// layout so bindings the shader doesn't declare are skipped.
+pub fn write_image_descriptors(
+    render_instance: &RenderInstance,
+    set_layout_info: &[HashMap<u32, vk::DescriptorType>],
+    descriptor_sets: &[vk::DescriptorSet],
+    views: &[(u32, vk::ImageView)],
+) {
+    let Some(set_info) = set_layout_info.first() else {
+        return;
+    };
+
+    let mut writer = DescriptorWriter::new();
+    for (binding, view) in views.iter() {
+        if let Some(ty) = set_info.get(binding) {
+            writer.image(
+                descriptor_sets[0],
+                *binding,
+                *ty,
+                *view,
+                vk::Sampler::null(),
+            );
+        }
+    }
+    writer.flush(render_instance.device());
+}
+
+/// Binds `tlas` to an `accelerationStructureEXT` binding of set 0, for ray queries from compute
+/// and fragment shaders.
+pub fn write_acceleration_structure_descriptor(
+    render_instance: &RenderInstance,
+    set_layout_info: &[HashMap<u32, vk::DescriptorType>],
+    descriptor_sets: &[vk::DescriptorSet],
+    binding: u32,
+    tlas: &Tlas,
+) {
+    let ty = set_layout_info
+        .first()
+        .and_then(|set_info| set_info.get(&binding));
+    assert_eq!(
+        ty,
+        Some(&vk::DescriptorType::ACCELERATION_STRUCTURE_KHR),
+        "Binding {} isn't an acceleration structure",
+        binding
+    );
+    tlas.write_descriptor(render_instance, descriptor_sets[0], binding);
+}
+
+/// Returns a buffer of at least `size` bytes, replacing `buffer` with one twice as large when
+/// it's too small.
+pub(crate) fn reserve<'a>(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    deferred_destroy: &mut DeferredDestroyQueue,
+    buffer: &'a mut Option<Buffer>,
+    size: u64,
+    usage: vk::BufferUsageFlags,
+) -> Result<&'a mut Buffer> {
+    if buffer.as_ref().is_some_and(|buffer| buffer.size < size) {
+        deferred_destroy.push(buffer.take().unwrap());
+    }
+
+    if buffer.is_none() {
+        *buffer = Some(Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(size.next_power_of_two())
+                .usage(usage)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?);
+    }
+    Ok(buffer.as_mut().unwrap())
+}
@@ -0,0 +1,176 @@
+use ash::vk;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{barrier::Usage, deferred_destroy, RenderAllocator, RenderInstance},
+};
+
+use super::graphics::{ColorAttachment, RenderTarget};
+
+/// How big an [`OffscreenTarget`] is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OffscreenSize {
+    Fixed(vk::Extent2D),
+    /// The swapchain resolution times `scale`, followed by [`OffscreenTarget::resize`].
+    Swapchain {
+        scale: f32,
+    },
+}
+
+impl OffscreenSize {
+    pub fn extent(self, render_instance: &RenderInstance) -> vk::Extent2D {
+        match self {
+            OffscreenSize::Fixed(extent) => extent,
+            OffscreenSize::Swapchain { scale } => {
+                let resolution = render_instance.0.surface_resolution;
+                vk::Extent2D {
+                    width: ((resolution.width as f32 * scale) as u32).max(1),
+                    height: ((resolution.height as f32 * scale) as u32).max(1),
+                }
+            }
+        }
+    }
+}
+
+/// An image that one pass renders into and later passes sample, like a minimap or the scene
+/// shown in an editor viewport. It's always left in the layout of `sampled_usage`: the
+/// attachment from [`Self::color_attachment`] transitions it to a color attachment for the
+/// producing pass and back after it, so consuming passes can bind [`Self::view`] as is.
+#[derive(Debug)]
+pub struct OffscreenTarget {
+    pub image: Image,
+    pub view: vk::ImageView,
+    pub size: OffscreenSize,
+    /// How the consuming passes read the image, `FragmentSampled` or `ComputeSampled`.
+    pub sampled_usage: Usage,
+}
+
+impl OffscreenTarget {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        format: vk::Format,
+        size: OffscreenSize,
+        sampled_usage: Usage,
+    ) -> Result<Self> {
+        assert!(
+            matches!(
+                sampled_usage,
+                Usage::FragmentSampled | Usage::ComputeSampled | Usage::VertexSampled
+            ),
+            "Offscreen targets are sampled, not used as {:?}",
+            sampled_usage
+        );
+        let (image, view) = create_image(
+            render_instance,
+            render_allocator,
+            format,
+            size.extent(render_instance),
+            sampled_usage,
+        )?;
+        Ok(Self {
+            image,
+            view,
+            size,
+            sampled_usage,
+        })
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.image.extent.width,
+            height: self.image.extent.height,
+        }
+    }
+
+    /// The image as an attachment of the producing pass, which leaves it ready for sampling.
+    /// With `clear` the previous contents are discarded instead of loaded.
+    pub fn color_attachment(&self, clear: Option<[f32; 4]>) -> ColorAttachment {
+        ColorAttachment {
+            image: self.image.image,
+            view: self.view,
+            format: self.image.format,
+            clear,
+            initial_usage: if clear.is_some() {
+                Usage::Undefined
+            } else {
+                self.sampled_usage
+            },
+            final_usage: self.sampled_usage,
+        }
+    }
+
+    /// A render target with only this image attached.
+    pub fn render_target(&self, clear: Option<[f32; 4]>) -> RenderTarget {
+        let mut target = RenderTarget::new(self.extent());
+        target.color_attachments.push(self.color_attachment(clear));
+        target
+    }
+
+    /// Recreates the image when its size no longer matches [`Self::size`], e.g. after the
+    /// swapchain was resized. Returns true when it did, descriptors holding the old view have
+    /// to be written again.
+    pub fn resize(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) -> Result<bool> {
+        let extent = self.size.extent(render_instance);
+        if extent == self.extent() {
+            return Ok(false);
+        }
+        let (image, view) = create_image(
+            render_instance,
+            render_allocator,
+            self.image.format,
+            extent,
+            self.sampled_usage,
+        )?;
+        // passes recorded before the resize might still be sampling the old image
+        deferred_destroy::release(std::mem::replace(&mut self.image, image));
+        self.view = view;
+        Ok(true)
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.image
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+    }
+}
+
+fn create_image(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    format: vk::Format,
+    extent: vk::Extent2D,
+    sampled_usage: Usage,
+) -> Result<(Image, vk::ImageView)> {
+    let mut image = Image::new(
+        render_instance.device(),
+        &mut render_allocator.allocator(),
+        &vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+    )?
+    // consumers can sample it before anything was rendered to it
+    .initial_layout(render_instance, sampled_usage);
+    image.set_name("offscreen target");
+    let view = image.create_view(render_instance.device())?;
+    Ok((image, view))
+}
@@ -0,0 +1,596 @@
+use std::mem::size_of;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::Buffer,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        camera::ViewUniforms,
+        pipeline::{BlendMode, CompareFunction, DepthStencilState, PrimitiveState},
+        shaders::{Shader, ShaderKind},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{
+    compute::ComputePass,
+    graphics::{GraphicsPass, GraphicsPassDescriptor, GraphicsState, RenderTarget},
+    scan::record_barrier,
+    sort::RadixSort,
+};
+
+/// Offset of the `VkDispatchIndirectCommand` over the alive particles in the state buffer.
+const UPDATE_DISPATCH_OFFSET: u64 = 16;
+/// Offset of the `VkDrawIndirectCommand` in the state buffer.
+const DRAW_OFFSET: u64 = 32;
+const STATE_SIZE: u64 = 64;
+
+/// A particle as the kernels store it, std430.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Particle {
+    pub position: Vec3,
+    pub age: f32,
+    pub velocity: Vec3,
+    pub lifetime: f32,
+    pub color: Vec4,
+    pub size: f32,
+    _padding: [f32; 3],
+}
+
+/// Where and how [`ParticleSystem::emit`] spawns particles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParticleEmitter {
+    pub position: Vec3,
+    pub velocity: Vec3,
+    /// Largest random speed added to `velocity` in any direction.
+    pub spread: f32,
+    pub color: Vec4,
+    /// Lifetimes are picked between `x` and `y` seconds.
+    pub lifetime: Vec2,
+    /// Half the width of the quad.
+    pub size: f32,
+}
+
+impl Default for ParticleEmitter {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            velocity: Vec3::Y,
+            spread: 0.5,
+            color: Vec4::ONE,
+            lifetime: Vec2::new(1.0, 2.0),
+            size: 0.05,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct EmitConstants {
+    particles: u64,
+    alive: u64,
+    dead: u64,
+    state: u64,
+    position: Vec3,
+    count: u32,
+    velocity: Vec3,
+    spread: f32,
+    color: Vec4,
+    lifetime: Vec2,
+    size: f32,
+    seed: u32,
+    current: u32,
+    max_particles: u32,
+    _padding: [u32; 2],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct UpdateConstants {
+    particles: u64,
+    alive: u64,
+    state: u64,
+    current: u32,
+    delta_time: f32,
+    gravity: Vec3,
+    drag: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct CompactConstants {
+    particles: u64,
+    alive_in: u64,
+    alive_out: u64,
+    dead: u64,
+    keys: u64,
+    state: u64,
+    camera_position: Vec3,
+    current: u32,
+    sort: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ArgsConstants {
+    state: u64,
+    current: u32,
+    stage: u32,
+    max_particles: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct DrawConstants {
+    view_proj: Mat4,
+    camera_right: Vec4,
+    camera_up: Vec4,
+    particles: u64,
+    alive: u64,
+}
+
+/// GPU simulated particles drawn as camera facing quads. Particles live in a fixed pool of
+/// `max_particles` slots, with two alive lists of slot indices that swap roles every frame and
+/// a list of dead slots that emitting reuses:
+///
+/// - emit pops free slots and appends them to the alive list,
+/// - update integrates the alive particles,
+/// - compact moves the survivors to the other alive list and frees the rest.
+///
+/// The counts never reach the CPU, update, compact and the draw are indirect. With `sorted`
+/// the alive list is radix sorted back to front before drawing, which alpha blending needs
+/// once particles overlap.
+#[derive(Debug)]
+pub struct ParticleSystem {
+    pub gravity: Vec3,
+    /// Fraction of the velocity lost per second, roughly.
+    pub drag: f32,
+    emit: ComputePass,
+    update: ComputePass,
+    compact: ComputePass,
+    args: ComputePass,
+    draw: GraphicsPass,
+    sort: Option<RadixSort>,
+    particles: Buffer,
+    alive: [Buffer; 2],
+    dead: Buffer,
+    keys: Buffer,
+    state: Buffer,
+    max_particles: u32,
+    /// The alive list updated next, the other one is compacted into.
+    current: usize,
+    emits: Vec<(ParticleEmitter, u32)>,
+    seed: u32,
+    needs_reset: bool,
+}
+
+impl ParticleSystem {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        max_particles: u32,
+        color_format: vk::Format,
+        depth: Option<(vk::Format, CompareFunction)>,
+        sorted: bool,
+    ) -> Result<Self> {
+        let compute_pass = |path: &str, push_constant_size: usize| {
+            ComputePass::from_file(render_instance, path, push_constant_size as u32)
+        };
+        let emit = compute_pass("./shader/particles/emit.comp", size_of::<EmitConstants>())?;
+        let update = compute_pass(
+            "./shader/particles/update.comp",
+            size_of::<UpdateConstants>(),
+        )?;
+        let compact = compute_pass(
+            "./shader/particles/compact.comp",
+            size_of::<CompactConstants>(),
+        )?;
+        let args = compute_pass("./shader/particles/args.comp", size_of::<ArgsConstants>())?;
+
+        let draw = GraphicsPass::new(
+            render_instance,
+            GraphicsPassDescriptor {
+                vertex_shader: Shader::from_file(
+                    render_instance,
+                    "./shader/particles/particle.vert",
+                    ShaderKind::Vertex,
+                    "main",
+                )?,
+                fragment_shader: Shader::from_file(
+                    render_instance,
+                    "./shader/particles/particle.frag",
+                    ShaderKind::Fragment,
+                    "main",
+                )?,
+                vertex_input: vk::PipelineVertexInputStateCreateInfo::default(),
+                state: GraphicsState {
+                    primitive: PrimitiveState {
+                        topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                        cull_mode: vk::CullModeFlags::NONE,
+                        ..Default::default()
+                    },
+                    // tested against the scene, but particles don't occlude each other
+                    depth_stencil: depth.map(|(format, depth_compare)| DepthStencilState {
+                        format,
+                        depth_write_enabled: false,
+                        depth_compare,
+                        stencil: Default::default(),
+                        bias: Default::default(),
+                    }),
+                    blend: BlendMode::AlphaBlend,
+                    multisample: Default::default(),
+                    shading_rate_image: false,
+                    view_mask: 0,
+                },
+                push_constant_size: size_of::<DrawConstants>() as u32,
+                color_formats: &[color_format],
+            },
+        )?;
+
+        let list_size = max_particles.max(1) as u64 * size_of::<u32>() as u64;
+        let storage = vk::BufferUsageFlags::STORAGE_BUFFER;
+        let particles = create_buffer(
+            render_instance,
+            render_allocator,
+            max_particles.max(1) as u64 * size_of::<Particle>() as u64,
+            storage,
+            "particles",
+        )?;
+        let alive = [
+            create_buffer(
+                render_instance,
+                render_allocator,
+                list_size,
+                storage,
+                "alive particles 0",
+            )?,
+            create_buffer(
+                render_instance,
+                render_allocator,
+                list_size,
+                storage,
+                "alive particles 1",
+            )?,
+        ];
+        let dead = create_buffer(
+            render_instance,
+            render_allocator,
+            list_size,
+            storage,
+            "dead particles",
+        )?;
+        let keys = create_buffer(
+            render_instance,
+            render_allocator,
+            list_size,
+            storage | vk::BufferUsageFlags::TRANSFER_DST,
+            "particle sort keys",
+        )?;
+        let state = create_buffer(
+            render_instance,
+            render_allocator,
+            STATE_SIZE,
+            storage | vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+            "particle state",
+        )?;
+
+        let sort = sorted
+            .then(|| RadixSort::new(render_instance, render_allocator, max_particles))
+            .transpose()?;
+
+        Ok(Self {
+            gravity: Vec3::new(0.0, -9.81, 0.0),
+            drag: 0.0,
+            emit,
+            update,
+            compact,
+            args,
+            draw,
+            sort,
+            particles,
+            alive,
+            dead,
+            keys,
+            state,
+            max_particles,
+            current: 0,
+            emits: vec![],
+            seed: 0,
+            needs_reset: true,
+        })
+    }
+
+    /// Spawns `count` particles in the next [`Self::simulate`]. Particles that don't fit in the
+    /// pool anymore are dropped.
+    pub fn emit(&mut self, emitter: &ParticleEmitter, count: u32) {
+        if count > 0 {
+            self.emits.push((*emitter, count));
+        }
+    }
+
+    /// Removes all particles in the next [`Self::simulate`].
+    pub fn clear(&mut self) {
+        self.needs_reset = true;
+    }
+
+    /// Records emitting, updating and compacting the particles, and sorting them for `view`
+    /// when the system is sorted. Call once per frame before [`Self::record_draw`].
+    pub fn simulate(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        delta_time: f32,
+        view: &ViewUniforms,
+    ) {
+        let renderer = render_instance.0.as_ref();
+        let device = render_instance.device();
+        let current = self.current;
+        let next = 1 - current;
+
+        // the draw of the previous frame reads the state and the alive list
+        let previous_draw = barrier::memory_barrier(Usage::IndirectBuffer, Usage::ComputeWrite)
+            .src_stage_mask(
+                vk::PipelineStageFlags2::DRAW_INDIRECT | vk::PipelineStageFlags2::VERTEX_SHADER,
+            );
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().memory_barriers(&[previous_draw]),
+            )
+        };
+        if std::mem::take(&mut self.needs_reset) {
+            unsafe {
+                device.cmd_fill_buffer(command_buffer, self.state.buffer, 0, vk::WHOLE_SIZE, 0)
+            };
+            record_barrier(
+                render_instance,
+                command_buffer,
+                Usage::TransferWrite,
+                Usage::ComputeWrite,
+            );
+        }
+
+        for (emitter, count) in std::mem::take(&mut self.emits) {
+            self.seed = self.seed.wrapping_add(1);
+            let constants = EmitConstants {
+                particles: self.particles.device_addr,
+                alive: self.alive[current].device_addr,
+                dead: self.dead.device_addr,
+                state: self.state.device_addr,
+                position: emitter.position,
+                count,
+                velocity: emitter.velocity,
+                spread: emitter.spread,
+                color: emitter.color,
+                lifetime: emitter.lifetime,
+                size: emitter.size,
+                seed: self.seed,
+                current: current as u32,
+                max_particles: self.max_particles,
+                _padding: [0; 2],
+            };
+            self.emit.record(
+                render_instance,
+                command_buffer,
+                (count, 1, 1),
+                bytemuck::bytes_of(&constants),
+            );
+            record_barrier(
+                render_instance,
+                command_buffer,
+                Usage::ComputeWrite,
+                Usage::ComputeWrite,
+            );
+        }
+
+        self.record_args(render_instance, command_buffer, current, 0);
+        record_barrier(
+            render_instance,
+            command_buffer,
+            Usage::ComputeWrite,
+            Usage::IndirectBuffer,
+        );
+
+        let update = UpdateConstants {
+            particles: self.particles.device_addr,
+            alive: self.alive[current].device_addr,
+            state: self.state.device_addr,
+            current: current as u32,
+            delta_time,
+            gravity: self.gravity,
+            drag: self.drag,
+        };
+        self.update.record_indirect(
+            render_instance,
+            command_buffer,
+            &self.state,
+            UPDATE_DISPATCH_OFFSET,
+            bytemuck::bytes_of(&update),
+        );
+        record_barrier(
+            render_instance,
+            command_buffer,
+            Usage::ComputeWrite,
+            Usage::ComputeWrite,
+        );
+
+        if self.sort.is_some() {
+            // slots past the alive count sort to the end
+            unsafe {
+                device.cmd_fill_buffer(command_buffer, self.keys.buffer, 0, vk::WHOLE_SIZE, !0)
+            };
+            record_barrier(
+                render_instance,
+                command_buffer,
+                Usage::TransferWrite,
+                Usage::ComputeWrite,
+            );
+        }
+        let compact = CompactConstants {
+            particles: self.particles.device_addr,
+            alive_in: self.alive[current].device_addr,
+            alive_out: self.alive[next].device_addr,
+            dead: self.dead.device_addr,
+            keys: self.keys.device_addr,
+            state: self.state.device_addr,
+            camera_position: view.world_position,
+            current: current as u32,
+            sort: self.sort.is_some() as u32,
+            _padding: 0,
+        };
+        self.compact.record_indirect(
+            render_instance,
+            command_buffer,
+            &self.state,
+            UPDATE_DISPATCH_OFFSET,
+            bytemuck::bytes_of(&compact),
+        );
+        record_barrier(
+            render_instance,
+            command_buffer,
+            Usage::ComputeWrite,
+            Usage::ComputeWrite,
+        );
+
+        if let Some(sort) = self.sort.as_ref() {
+            // the whole list is sorted, the alive count isn't known on the CPU
+            sort.sort(
+                render_instance,
+                command_buffer,
+                &self.keys,
+                &self.alive[next],
+                self.max_particles,
+                Usage::ComputeWrite,
+            );
+        }
+        self.record_args(render_instance, command_buffer, next, 1);
+
+        let draw = barrier::memory_barrier(Usage::ComputeWrite, Usage::IndirectBuffer)
+            .dst_stage_mask(
+                vk::PipelineStageFlags2::DRAW_INDIRECT | vk::PipelineStageFlags2::VERTEX_SHADER,
+            )
+            .dst_access_mask(
+                vk::AccessFlags2::INDIRECT_COMMAND_READ | vk::AccessFlags2::SHADER_STORAGE_READ,
+            );
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().memory_barriers(&[draw]),
+            )
+        };
+
+        self.current = next;
+    }
+
+    fn record_args(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        current: usize,
+        stage: u32,
+    ) {
+        let constants = ArgsConstants {
+            state: self.state.device_addr,
+            current: current as u32,
+            stage,
+            max_particles: self.max_particles,
+            _padding: 0,
+        };
+        self.args.record(
+            render_instance,
+            command_buffer,
+            (1, 1, 1),
+            bytemuck::bytes_of(&constants),
+        );
+    }
+
+    /// Draws the particles simulated last into `target`, facing the camera of `view`.
+    pub fn record_draw(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        target: &RenderTarget,
+        view: &ViewUniforms,
+    ) {
+        let constants = DrawConstants {
+            view_proj: view.view_proj,
+            camera_right: view.view.x_axis,
+            camera_up: view.view.y_axis,
+            particles: self.particles.device_addr,
+            // `simulate` already swapped the lists
+            alive: self.alive[self.current].device_addr,
+        };
+        let state = &self.state;
+        self.draw
+            .record(render_instance, command_buffer, target, |ctx| {
+                ctx.push_constants(&constants);
+                ctx.draw_indirect(state, DRAW_OFFSET, 1, 0);
+            });
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        if let Some(sort) = self.sort.as_mut() {
+            sort.destroy(render_instance, render_allocator);
+        }
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.particles.destroy(device, &mut allocator);
+        for buffer in self.alive.iter_mut() {
+            buffer.destroy(device, &mut allocator);
+        }
+        self.dead.destroy(device, &mut allocator);
+        self.keys.destroy(device, &mut allocator);
+        self.state.destroy(device, &mut allocator);
+    }
+}
+
+fn create_buffer(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    size: u64,
+    usage: vk::BufferUsageFlags,
+    name: &str,
+) -> Result<Buffer> {
+    let buffer = Buffer::new(
+        render_instance.device(),
+        &mut render_allocator.allocator(),
+        &vk::BufferCreateInfo::default()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        MemoryLocation::GpuOnly,
+    )?;
+    buffer.set_name(name);
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layouts_match_shaders() {
+        assert_eq!(size_of::<Particle>(), 64);
+        assert_eq!(std::mem::offset_of!(Particle, velocity), 16);
+        assert_eq!(std::mem::offset_of!(Particle, color), 32);
+        assert_eq!(std::mem::offset_of!(EmitConstants, position), 32);
+        assert_eq!(std::mem::offset_of!(EmitConstants, lifetime), 80);
+        assert_eq!(std::mem::offset_of!(EmitConstants, current), 96);
+        assert_eq!(size_of::<EmitConstants>(), 112);
+        assert_eq!(std::mem::offset_of!(UpdateConstants, gravity), 32);
+        assert_eq!(std::mem::offset_of!(CompactConstants, camera_position), 48);
+        assert!(size_of::<DrawConstants>() <= 128);
+        assert!(size_of::<EmitConstants>() <= 128);
+    }
+}
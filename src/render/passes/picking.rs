@@ -0,0 +1,169 @@
+use ash::vk;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{
+        barrier::Usage,
+        readback::{Readback, ReadbackRing, ReadbackTicket},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::graphics::ColorAttachment;
+
+/// Format of the object id attachment. Fragment shaders write the id of the object they draw
+/// to it as a `uint`, 0 is left for the background.
+pub const OBJECT_ID_FORMAT: vk::Format = vk::Format::R32_UINT;
+
+/// Finds the object under the cursor by rendering object ids into an extra attachment and
+/// reading a single texel back, so editors can select without raycasting on the CPU. Add
+/// [`Self::attachment`] to the passes drawing pickable objects with
+/// [`RenderTarget::with_object_ids`](super::graphics::RenderTarget::with_object_ids).
+#[derive(Debug)]
+pub struct ObjectPicker {
+    pub image: Image,
+    pub view: vk::ImageView,
+    readback: ReadbackRing,
+    /// Read back next, once the readback in flight finished.
+    requested: Option<(u32, u32)>,
+    pending: Option<((u32, u32), ReadbackTicket)>,
+    /// Position and id of the last finished readback.
+    result: Option<((u32, u32), u32)>,
+}
+
+impl ObjectPicker {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        extent: vk::Extent2D,
+    ) -> Result<Self> {
+        let mut image = Image::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(OBJECT_ID_FORMAT)
+                .extent(vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(
+                    vk::ImageUsageFlags::COLOR_ATTACHMENT
+                        | vk::ImageUsageFlags::TRANSFER_SRC
+                        | vk::ImageUsageFlags::TRANSFER_DST,
+                )
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?
+        // a pick before the first frame was rendered reads the background
+        .zero_initialized(render_instance, Usage::ColorAttachmentWrite);
+        image.set_name("object ids");
+        let view = image.create_view(render_instance.device())?;
+
+        Ok(Self {
+            image,
+            view,
+            readback: ReadbackRing::new(render_instance, render_allocator, 16)?,
+            requested: None,
+            pending: None,
+            result: None,
+        })
+    }
+
+    /// The id attachment, cleared to the background by the first pass that writes ids when
+    /// `clear` is set. It's left in the color attachment layout.
+    pub fn attachment(&self, clear: bool) -> ColorAttachment {
+        ColorAttachment {
+            image: self.image.image,
+            view: self.view,
+            format: OBJECT_ID_FORMAT,
+            clear: clear.then_some([0.0; 4]),
+            initial_usage: if clear {
+                Usage::Undefined
+            } else {
+                Usage::ColorAttachmentWrite
+            },
+            final_usage: Usage::ColorAttachmentWrite,
+        }
+    }
+
+    /// The id of the object at `x`, `y`, once the readback of that texel finished a few
+    /// frames after it was first asked for. `None` while it's in flight, see
+    /// [`Self::has_result`], or when only the background is there. Keep calling it for the
+    /// same position to keep reading it back as the scene changes.
+    pub fn pick(&mut self, x: u32, y: u32) -> Option<u32> {
+        if x >= self.image.extent.width || y >= self.image.extent.height {
+            return None;
+        }
+        self.requested = Some((x, y));
+        match self.result {
+            Some((position, id)) if position == (x, y) => (id != 0).then_some(id),
+            _ => None,
+        }
+    }
+
+    /// Whether a readback of `x`, `y` finished, so [`Self::pick`] returning `None` means there
+    /// is only background.
+    pub fn has_result(&self, x: u32, y: u32) -> bool {
+        self.result.is_some_and(|(position, _)| position == (x, y))
+    }
+
+    /// Picks up the finished readback, call once per frame before recording.
+    pub fn begin_frame(&mut self, render_instance: &RenderInstance) {
+        self.readback.begin_frame();
+        let Some((position, ticket)) = self.pending else {
+            return;
+        };
+        match self.readback.read(render_instance, ticket) {
+            Readback::Pending => {}
+            Readback::Ready(bytes) => {
+                let id = u32::from_ne_bytes(bytes.try_into().unwrap());
+                self.result = Some((position, id));
+                self.pending = None;
+            }
+            Readback::Expired => self.pending = None,
+        }
+    }
+
+    /// Records the copy of the requested texel, after the passes writing the ids.
+    pub fn record_readback(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+    ) {
+        if self.pending.is_some() {
+            return;
+        }
+        let Some((x, y)) = self.requested.take() else {
+            return;
+        };
+        let ticket = self
+            .readback
+            .copy_texel(
+                render_instance,
+                command_buffer,
+                self.image.image,
+                OBJECT_ID_FORMAT,
+                Usage::ColorAttachmentWrite,
+                x,
+                y,
+            )
+            .expect("Object picker copies one texel per frame");
+        self.pending = Some(((x, y), ticket));
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.image
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+        self.readback.destroy(render_instance, render_allocator);
+    }
+}
@@ -0,0 +1,613 @@
+use std::mem::size_of;
+
+use ash::vk;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        graph::{RenderGraph, ResourceId},
+        shaders::{Shader, ShaderKind},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{compute::ComputePass, write_image_descriptors};
+
+#[derive(Clone, Copy, Debug)]
+pub struct BloomSettings {
+    /// Amount of downsample steps, each one halves the resolution.
+    pub mip_count: u32,
+    /// Only the part of the input brighter than this contributes to the bloom, `0.0` disables it.
+    pub threshold: f32,
+    pub intensity: f32,
+    /// Radius of the upsample filter in uv space.
+    pub filter_radius: f32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            mip_count: 6,
+            threshold: 1.0,
+            intensity: 0.04,
+            filter_radius: 0.005,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FxaaSettings {
+    pub edge_threshold: f32,
+    pub edge_threshold_min: f32,
+}
+
+impl Default for FxaaSettings {
+    fn default() -> Self {
+        Self {
+            edge_threshold: 0.125,
+            edge_threshold_min: 0.0312,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VignetteSettings {
+    pub intensity: f32,
+    pub smoothness: f32,
+}
+
+impl Default for VignetteSettings {
+    fn default() -> Self {
+        Self {
+            intensity: 0.5,
+            smoothness: 0.6,
+        }
+    }
+}
+
+/// Output for an HDR10 swapchain, see `ExampleBase::hdr_output`. Luminances are in nits.
+#[derive(Clone, Copy, Debug)]
+pub struct Hdr10Settings {
+    /// Luminance a linear value of `1.0` is shown at.
+    pub paper_white: f32,
+    /// Brighter values are clipped, usually the `max_luminance` of the
+    /// [`HdrMetadata`](crate::render::hdr::HdrMetadata).
+    pub max_luminance: f32,
+}
+
+impl Default for Hdr10Settings {
+    fn default() -> Self {
+        Self {
+            paper_white: 200.0,
+            max_luminance: 1000.0,
+        }
+    }
+}
+
+/// Which effects are part of a [`PostProcessStack`], tonemapping always runs since it
+/// produces the low dynamic range output the other effects work on.
+#[derive(Clone, Copy, Debug)]
+pub struct PostProcessSettings {
+    pub exposure: f32,
+    pub bloom: Option<BloomSettings>,
+    pub fxaa: Option<FxaaSettings>,
+    pub vignette: Option<VignetteSettings>,
+    /// Encodes Rec. 2020 with the ST 2084 (PQ) curve into a 10 bit output instead of
+    /// tonemapping to sRGB.
+    pub hdr10: Option<Hdr10Settings>,
+}
+
+impl Default for PostProcessSettings {
+    fn default() -> Self {
+        Self {
+            exposure: 1.0,
+            bloom: Some(BloomSettings::default()),
+            fxaa: Some(FxaaSettings::default()),
+            vignette: Some(VignetteSettings::default()),
+            hdr10: None,
+        }
+    }
+}
+
+impl PostProcessSettings {
+    /// Format of the output, and of the storage images the shaders write.
+    pub fn output_format(&self) -> vk::Format {
+        if self.hdr10.is_some() {
+            HDR10_OUTPUT_FORMAT
+        } else {
+            OUTPUT_FORMAT
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct BloomDownsampleConstants {
+    threshold: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct BloomUpsampleConstants {
+    filter_radius: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct TonemapConstants {
+    exposure: f32,
+    bloom_intensity: f32,
+    hdr10: u32,
+    paper_white: f32,
+    max_luminance: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct FxaaConstants {
+    edge_threshold: f32,
+    edge_threshold_min: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct VignetteConstants {
+    intensity: f32,
+    smoothness: f32,
+}
+
+#[derive(Debug)]
+struct Bloom {
+    settings: BloomSettings,
+    /// Mip chain as separate images, `mips[0]` is half the input resolution.
+    mips: Vec<Image>,
+    downsample: Vec<ComputePass>,
+    upsample: Vec<ComputePass>,
+}
+
+/// Bloom, tonemapping, FXAA and vignette as a chain of compute passes, turning an HDR input
+/// image into an LDR `output` image that can be blitted or sampled by the next pass.
+///
+/// All shaders follow the same binding layout: `input_texture` at binding 0, the `sampler_llc`
+/// immutable sampler at binding 1 and `output_image` at binding 2, which is what the
+/// descriptor writes below rely on.
+#[derive(Debug)]
+pub struct PostProcessStack {
+    pub settings: PostProcessSettings,
+    pub output: Image,
+    /// Target of the tonemap pass when FXAA is enabled.
+    intermediate: Option<Image>,
+    bloom: Option<Bloom>,
+    tonemap: ComputePass,
+    fxaa: Option<ComputePass>,
+    vignette: Option<ComputePass>,
+}
+
+const OUTPUT_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+const HDR10_OUTPUT_FORMAT: vk::Format = vk::Format::A2B10G10R10_UNORM_PACK32;
+const BLOOM_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+impl PostProcessStack {
+    /// `input` is the HDR image the stack reads from, it has to be created with `SAMPLED` usage
+    /// and be in the `SHADER_READ_ONLY_OPTIMAL` layout when the stack is recorded.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        input: &mut Image,
+        settings: PostProcessSettings,
+        output_usage: vk::ImageUsageFlags,
+    ) -> Result<Self> {
+        let device = render_instance.device();
+        let extent = vk::Extent2D {
+            width: input.extent.width,
+            height: input.extent.height,
+        };
+        let input_view = input.create_view(device)?;
+
+        let mut output = create_image(
+            render_instance,
+            render_allocator,
+            extent,
+            settings.output_format(),
+            output_usage,
+        )?;
+        let mut intermediate = settings
+            .fxaa
+            .map(|_| {
+                create_image(
+                    render_instance,
+                    render_allocator,
+                    extent,
+                    settings.output_format(),
+                    vk::ImageUsageFlags::empty(),
+                )
+            })
+            .transpose()?;
+
+        let mut bloom = settings
+            .bloom
+            .map(|bloom_settings| -> Result<Bloom> {
+                let mut mips = (1..=bloom_settings.mip_count)
+                    .map(|level| {
+                        create_image(
+                            render_instance,
+                            render_allocator,
+                            vk::Extent2D {
+                                width: (extent.width >> level).max(1),
+                                height: (extent.height >> level).max(1),
+                            },
+                            BLOOM_FORMAT,
+                            vk::ImageUsageFlags::empty(),
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let views = mips
+                    .iter_mut()
+                    .map(|mip| mip.create_view(device))
+                    .collect::<Result<Vec<_>>>()?;
+
+                let downsample = (0..mips.len())
+                    .map(|index| {
+                        let mut pass = ComputePass::from_file(
+                            render_instance,
+                            "./shader/post/bloom_downsample.comp",
+                            size_of::<BloomDownsampleConstants>() as u32,
+                        )?;
+                        let source = if index == 0 {
+                            input_view
+                        } else {
+                            views[index - 1]
+                        };
+                        write_descriptors(
+                            render_instance,
+                            &pass,
+                            &[(0, source), (2, views[index])],
+                        );
+                        pass.add_image_write(&mips[index], Usage::ComputeSampled);
+                        Ok(pass)
+                    })
+                    .collect::<Result<_>>()?;
+
+                // upsample from the smallest mip into the next larger one
+                let upsample = (0..mips.len().saturating_sub(1))
+                    .rev()
+                    .map(|index| {
+                        let mut pass = ComputePass::from_file(
+                            render_instance,
+                            "./shader/post/bloom_upsample.comp",
+                            size_of::<BloomUpsampleConstants>() as u32,
+                        )?;
+                        write_descriptors(
+                            render_instance,
+                            &pass,
+                            &[(0, views[index + 1]), (2, views[index])],
+                        );
+                        pass.add_image_write(&mips[index], Usage::ComputeSampled);
+                        Ok(pass)
+                    })
+                    .collect::<Result<_>>()?;
+
+                Ok(Bloom {
+                    settings: bloom_settings,
+                    mips,
+                    downsample,
+                    upsample,
+                })
+            })
+            .transpose()?;
+
+        let output_view = output.create_view(device)?;
+        // without bloom the input is bound in its place, with an intensity of zero
+        let bloom_view = match bloom.as_mut() {
+            Some(bloom) if !bloom.mips.is_empty() => bloom.mips[0].create_view(device)?,
+            _ => input_view,
+        };
+
+        let mut tonemap = output_pass(
+            render_instance,
+            "./shader/post/tonemap.comp",
+            size_of::<TonemapConstants>() as u32,
+            &settings,
+        )?;
+        let tonemap_target = match intermediate.as_mut() {
+            Some(intermediate) => intermediate.create_view(device)?,
+            None => output_view,
+        };
+        write_descriptors(
+            render_instance,
+            &tonemap,
+            &[(0, input_view), (2, tonemap_target), (3, bloom_view)],
+        );
+        match intermediate.as_ref() {
+            Some(intermediate) => tonemap.add_image_write(intermediate, Usage::ComputeSampled),
+            None => tonemap.add_image_write(&output, Usage::ComputeWrite),
+        }
+
+        let fxaa = intermediate
+            .as_mut()
+            .map(|intermediate| -> Result<ComputePass> {
+                let mut pass = output_pass(
+                    render_instance,
+                    "./shader/post/fxaa.comp",
+                    size_of::<FxaaConstants>() as u32,
+                    &settings,
+                )?;
+                let intermediate_view = intermediate.create_view(device)?;
+                write_descriptors(
+                    render_instance,
+                    &pass,
+                    &[(0, intermediate_view), (2, output_view)],
+                );
+                pass.add_image_write(&output, Usage::ComputeWrite);
+                Ok(pass)
+            })
+            .transpose()?;
+
+        let vignette = settings
+            .vignette
+            .map(|_| -> Result<ComputePass> {
+                let mut pass = output_pass(
+                    render_instance,
+                    "./shader/post/vignette.comp",
+                    size_of::<VignetteConstants>() as u32,
+                    &settings,
+                )?;
+                write_descriptors(render_instance, &pass, &[(2, output_view)]);
+                pass.add_image_write(&output, Usage::ComputeWrite);
+                Ok(pass)
+            })
+            .transpose()?;
+
+        Ok(Self {
+            settings,
+            output,
+            intermediate,
+            bloom,
+            tonemap,
+            fxaa,
+            vignette,
+        })
+    }
+
+    /// Declares the passes of the stack in the render graph, so they are ordered after the pass
+    /// producing `input` and culled together with whatever consumes `output`.
+    pub fn add_to_graph(&self, graph: &mut RenderGraph, input: ResourceId, output: ResourceId) {
+        graph.add_pass("post_process", &[input], &[output]);
+    }
+
+    /// Records the whole chain, afterwards `output` is transitioned to `output_usage`.
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        output_usage: Usage,
+    ) {
+        let renderer = render_instance.0.as_ref();
+
+        // everything but the input is fully overwritten, so previous contents can be discarded
+        let mut barriers = vec![barrier::image_barrier(
+            self.output.image,
+            vk::ImageAspectFlags::COLOR,
+            Usage::Undefined,
+            Usage::ComputeWrite,
+        )];
+        if let Some(intermediate) = self.intermediate.as_ref() {
+            barriers.push(barrier::image_barrier(
+                intermediate.image,
+                vk::ImageAspectFlags::COLOR,
+                Usage::Undefined,
+                Usage::ComputeWrite,
+            ));
+        }
+        if let Some(bloom) = self.bloom.as_ref() {
+            for mip in bloom.mips.iter() {
+                barriers.push(barrier::image_barrier(
+                    mip.image,
+                    vk::ImageAspectFlags::COLOR,
+                    Usage::Undefined,
+                    Usage::ComputeWrite,
+                ));
+            }
+        }
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&barriers),
+            )
+        };
+
+        let extent = (self.output.extent.width, self.output.extent.height, 1);
+
+        if let Some(bloom) = self.bloom.as_ref() {
+            for (index, pass) in bloom.downsample.iter().enumerate() {
+                let mip = &bloom.mips[index];
+                let constants = BloomDownsampleConstants {
+                    threshold: if index == 0 {
+                        bloom.settings.threshold
+                    } else {
+                        0.0
+                    },
+                };
+                pass.record(
+                    render_instance,
+                    command_buffer,
+                    (mip.extent.width, mip.extent.height, 1),
+                    bytemuck::bytes_of(&constants),
+                );
+            }
+
+            let constants = BloomUpsampleConstants {
+                filter_radius: bloom.settings.filter_radius,
+            };
+            for (pass, index) in bloom
+                .upsample
+                .iter()
+                .zip((0..bloom.mips.len().saturating_sub(1)).rev())
+            {
+                // the target was sampled by the downsample chain, but is added onto now
+                let mip = &bloom.mips[index];
+                unsafe {
+                    renderer.synchronization2.cmd_pipeline_barrier2(
+                        command_buffer,
+                        &vk::DependencyInfo::default().image_memory_barriers(&[
+                            barrier::image_barrier(
+                                mip.image,
+                                vk::ImageAspectFlags::COLOR,
+                                Usage::ComputeSampled,
+                                Usage::ComputeWrite,
+                            ),
+                        ]),
+                    )
+                };
+                pass.record(
+                    render_instance,
+                    command_buffer,
+                    (mip.extent.width, mip.extent.height, 1),
+                    bytemuck::bytes_of(&constants),
+                );
+            }
+        }
+
+        let hdr10 = self.settings.hdr10.unwrap_or_default();
+        let constants = TonemapConstants {
+            exposure: self.settings.exposure,
+            bloom_intensity: match self.bloom.as_ref() {
+                Some(bloom) if !bloom.mips.is_empty() => bloom.settings.intensity,
+                _ => 0.0,
+            },
+            hdr10: self.settings.hdr10.is_some() as u32,
+            paper_white: hdr10.paper_white,
+            max_luminance: hdr10.max_luminance,
+        };
+        self.tonemap.record(
+            render_instance,
+            command_buffer,
+            extent,
+            bytemuck::bytes_of(&constants),
+        );
+
+        if let (Some(pass), Some(settings)) = (self.fxaa.as_ref(), self.settings.fxaa) {
+            let constants = FxaaConstants {
+                edge_threshold: settings.edge_threshold,
+                edge_threshold_min: settings.edge_threshold_min,
+            };
+            pass.record(
+                render_instance,
+                command_buffer,
+                extent,
+                bytemuck::bytes_of(&constants),
+            );
+        }
+
+        if let (Some(pass), Some(settings)) = (self.vignette.as_ref(), self.settings.vignette) {
+            let constants = VignetteConstants {
+                intensity: settings.intensity,
+                smoothness: settings.smoothness,
+            };
+            pass.record(
+                render_instance,
+                command_buffer,
+                extent,
+                bytemuck::bytes_of(&constants),
+            );
+        }
+
+        if output_usage != Usage::ComputeWrite {
+            unsafe {
+                renderer.synchronization2.cmd_pipeline_barrier2(
+                    command_buffer,
+                    &vk::DependencyInfo::default().image_memory_barriers(&[
+                        barrier::image_barrier(
+                            self.output.image,
+                            vk::ImageAspectFlags::COLOR,
+                            Usage::ComputeWrite,
+                            output_usage,
+                        ),
+                    ]),
+                )
+            };
+        }
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.output.destroy(device, &mut allocator);
+        if let Some(intermediate) = self.intermediate.as_mut() {
+            intermediate.destroy(device, &mut allocator);
+        }
+        if let Some(bloom) = self.bloom.as_mut() {
+            for mip in bloom.mips.iter_mut() {
+                mip.destroy(device, &mut allocator);
+            }
+        }
+    }
+}
+
+/// A pass writing the output, its shader declares the storage image with `OUTPUT_FORMAT`.
+fn output_pass(
+    render_instance: &RenderInstance,
+    path: &str,
+    push_constant_size: u32,
+    settings: &PostProcessSettings,
+) -> Result<ComputePass> {
+    let format = if settings.hdr10.is_some() {
+        "rgb10_a2"
+    } else {
+        "rgba8"
+    };
+    let shader = Shader::from_file_with_defines(
+        render_instance,
+        path,
+        ShaderKind::Compute,
+        "main",
+        &[("OUTPUT_FORMAT", format)],
+    )?;
+    ComputePass::new(render_instance, shader, push_constant_size)
+}
+
+pub(super) fn create_image(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    extent: vk::Extent2D,
+    format: vk::Format,
+    extra_usage: vk::ImageUsageFlags,
+) -> Result<Image> {
+    Image::new(
+        render_instance.device(),
+        &mut render_allocator.allocator(),
+        &vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D {
+                width: extent.width,
+                height: extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED | extra_usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+    )
+}
+
+fn write_descriptors(
+    render_instance: &RenderInstance,
+    pass: &ComputePass,
+    views: &[(u32, vk::ImageView)],
+) {
+    write_image_descriptors(
+        render_instance,
+        &pass.pipeline.set_layout_info,
+        &pass.pipeline.descriptor_sets,
+        views,
+    );
+}
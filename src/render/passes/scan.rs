@@ -0,0 +1,376 @@
+use std::{marker::PhantomData, mem::size_of};
+
+use ash::vk;
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::Buffer,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        shaders::{Shader, ShaderKind},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::compute::ComputePass;
+
+/// Elements handled by one workgroup of the scan and reduce shaders.
+const BLOCK_SIZE: u32 = 256;
+
+/// Element types the scan and reduction shaders can be compiled for.
+pub trait ScanElement: bytemuck::Pod {
+    const GLSL_TYPE: &'static str;
+    /// Largest value, the identity of a min reduction.
+    const GLSL_MAX: &'static str;
+    /// Smallest value, the identity of a max reduction.
+    const GLSL_MIN: &'static str;
+}
+
+impl ScanElement for u32 {
+    const GLSL_TYPE: &'static str = "uint";
+    const GLSL_MAX: &'static str = "0xffffffffu";
+    const GLSL_MIN: &'static str = "0u";
+}
+
+impl ScanElement for i32 {
+    const GLSL_TYPE: &'static str = "int";
+    const GLSL_MAX: &'static str = "0x7fffffff";
+    const GLSL_MIN: &'static str = "(-0x7fffffff - 1)";
+}
+
+impl ScanElement for f32 {
+    const GLSL_TYPE: &'static str = "float";
+    const GLSL_MAX: &'static str = "uintBitsToFloat(0x7f800000u)";
+    const GLSL_MIN: &'static str = "uintBitsToFloat(0xff800000u)";
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ScanConstants {
+    source: u64,
+    destination: u64,
+    block_sums: u64,
+    count: u32,
+    _padding: u32,
+}
+
+fn block_count(count: u32) -> u32 {
+    ((count + BLOCK_SIZE - 1) / BLOCK_SIZE).max(1)
+}
+
+pub(super) fn create_storage_buffer<T: ScanElement>(
+    render_instance: &RenderInstance,
+    render_allocator: &mut RenderAllocator,
+    count: u32,
+) -> Result<Buffer> {
+    Buffer::new(
+        render_instance.device(),
+        &mut render_allocator.allocator(),
+        &vk::BufferCreateInfo::default()
+            .size(count as u64 * size_of::<T>() as u64)
+            .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        MemoryLocation::GpuOnly,
+    )
+}
+
+fn create_pass<T: ScanElement>(
+    render_instance: &RenderInstance,
+    path: &str,
+    defines: &[(&str, &str)],
+) -> Result<ComputePass> {
+    let mut defines = defines.to_vec();
+    defines.push(("ELEMENT_TYPE", T::GLSL_TYPE));
+    let shader = Shader::from_file_with_defines(
+        render_instance,
+        path,
+        ShaderKind::Compute,
+        "main",
+        &defines,
+    )?;
+    ComputePass::new(render_instance, shader, size_of::<ScanConstants>() as u32)
+}
+
+pub(super) fn record_barrier(
+    render_instance: &RenderInstance,
+    command_buffer: vk::CommandBuffer,
+    from: Usage,
+    to: Usage,
+) {
+    unsafe {
+        render_instance.0.synchronization2.cmd_pipeline_barrier2(
+            command_buffer,
+            &vk::DependencyInfo::default().memory_barriers(&[barrier::memory_barrier(from, to)]),
+        )
+    };
+}
+
+/// Exclusive prefix sum over a storage buffer of up to `max_count` elements, scanning blocks of
+/// 256 elements and recursively scanning the block totals. Up to 16M elements are supported.
+#[derive(Debug)]
+pub struct PrefixSum<T: ScanElement> {
+    scan_blocks: ComputePass,
+    add_offsets: ComputePass,
+    /// Block totals of every level of the recursion.
+    block_sums: Vec<Buffer>,
+    max_count: u32,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ScanElement> PrefixSum<T> {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        max_count: u32,
+    ) -> Result<Self> {
+        let mut block_sums = vec![];
+        let mut count = max_count;
+        loop {
+            let blocks = block_count(count);
+            block_sums.push(create_storage_buffer::<T>(
+                render_instance,
+                render_allocator,
+                blocks,
+            )?);
+            if blocks == 1 {
+                break;
+            }
+            count = blocks;
+        }
+
+        Ok(Self {
+            scan_blocks: create_pass::<T>(render_instance, "./shader/scan/scan_blocks.comp", &[])?,
+            add_offsets: create_pass::<T>(render_instance, "./shader/scan/add_offsets.comp", &[])?,
+            block_sums,
+            max_count,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Writes the exclusive prefix sum of the first `count` elements of `source` to
+    /// `destination`, which may be the same buffer. Previous writes to `source` have to be
+    /// visible to compute shaders, afterwards `destination` is made visible for the `next` usage.
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        source: &Buffer,
+        destination: &Buffer,
+        count: u32,
+        next: Usage,
+    ) {
+        assert!(
+            count <= self.max_count,
+            "PrefixSum was created for {} elements, got {}",
+            self.max_count,
+            count
+        );
+        self.record_level(
+            render_instance,
+            command_buffer,
+            0,
+            source.device_addr,
+            destination.device_addr,
+            count,
+        );
+        record_barrier(render_instance, command_buffer, Usage::ComputeWrite, next);
+    }
+
+    fn record_level(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        level: usize,
+        source: u64,
+        destination: u64,
+        count: u32,
+    ) {
+        let block_sums = self.block_sums[level].device_addr;
+        let constants = ScanConstants {
+            source,
+            destination,
+            block_sums,
+            count,
+            _padding: 0,
+        };
+        self.scan_blocks.record(
+            render_instance,
+            command_buffer,
+            (count, 1, 1),
+            bytemuck::bytes_of(&constants),
+        );
+
+        let blocks = block_count(count);
+        if blocks == 1 {
+            return;
+        }
+
+        // the block totals are scanned in place, then added to every element of their block
+        record_barrier(
+            render_instance,
+            command_buffer,
+            Usage::ComputeWrite,
+            Usage::ComputeWrite,
+        );
+        self.record_level(
+            render_instance,
+            command_buffer,
+            level + 1,
+            block_sums,
+            block_sums,
+            blocks,
+        );
+        record_barrier(
+            render_instance,
+            command_buffer,
+            Usage::ComputeWrite,
+            Usage::ComputeWrite,
+        );
+        self.add_offsets.record(
+            render_instance,
+            command_buffer,
+            (count, 1, 1),
+            bytemuck::bytes_of(&constants),
+        );
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        for buffer in self.block_sums.iter_mut() {
+            buffer.destroy(render_instance.device(), &mut render_allocator.allocator());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReduceOp {
+    Sum,
+    Min,
+    Max,
+}
+
+/// Reduces a storage buffer of up to `max_count` elements to a single value, by repeatedly
+/// reducing blocks of 256 elements until one is left.
+#[derive(Debug)]
+pub struct Reduction<T: ScanElement> {
+    pub op: ReduceOp,
+    pass: ComputePass,
+    /// Results of the blocks of every level but the last one.
+    partials: Vec<Buffer>,
+    max_count: u32,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ScanElement> Reduction<T> {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        op: ReduceOp,
+        max_count: u32,
+    ) -> Result<Self> {
+        let (op_define, identity) = match op {
+            ReduceOp::Sum => ("0", "ELEMENT_TYPE(0)"),
+            ReduceOp::Min => ("1", T::GLSL_MAX),
+            ReduceOp::Max => ("2", T::GLSL_MIN),
+        };
+        let pass = create_pass::<T>(
+            render_instance,
+            "./shader/scan/reduce.comp",
+            &[("REDUCE_OP", op_define), ("IDENTITY", identity)],
+        )?;
+
+        let mut partials = vec![];
+        let mut count = block_count(max_count);
+        while count > 1 {
+            partials.push(create_storage_buffer::<T>(
+                render_instance,
+                render_allocator,
+                count,
+            )?);
+            count = block_count(count);
+        }
+
+        Ok(Self {
+            op,
+            pass,
+            partials,
+            max_count,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Writes the reduction of the first `count` elements of `source` to the element at
+    /// `destination_index` of `destination`, made visible for the `next` usage afterwards.
+    pub fn record(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        source: &Buffer,
+        destination: &Buffer,
+        destination_index: u32,
+        count: u32,
+        next: Usage,
+    ) {
+        assert!(
+            count <= self.max_count,
+            "Reduction was created for {} elements, got {}",
+            self.max_count,
+            count
+        );
+        let destination =
+            destination.device_addr + destination_index as u64 * size_of::<T>() as u64;
+
+        let mut source = source.device_addr;
+        let mut count = count;
+        let mut level = 0;
+        loop {
+            let blocks = block_count(count);
+            let target = if blocks == 1 {
+                destination
+            } else {
+                self.partials[level].device_addr
+            };
+            let constants = ScanConstants {
+                source,
+                destination: target,
+                block_sums: 0,
+                count,
+                _padding: 0,
+            };
+            self.pass.record(
+                render_instance,
+                command_buffer,
+                (count, 1, 1),
+                bytemuck::bytes_of(&constants),
+            );
+            if blocks == 1 {
+                break;
+            }
+
+            record_barrier(
+                render_instance,
+                command_buffer,
+                Usage::ComputeWrite,
+                Usage::ComputeRead,
+            );
+            source = target;
+            count = blocks;
+            level += 1;
+        }
+        record_barrier(render_instance, command_buffer, Usage::ComputeWrite, next);
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        for buffer in self.partials.iter_mut() {
+            buffer.destroy(render_instance.device(), &mut render_allocator.allocator());
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::mem::size_of;
+
+use ash::vk;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        graph::{RenderGraph, ResourceId},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{
+    compute::ComputePass, graphics::ShadingRateAttachment, post_process::create_image,
+    write_image_descriptors,
+};
+
+const RATE_FORMAT: vk::Format = vk::Format::R8_UINT;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadingRateConstants {
+    tile_size: [u32; 2],
+    half_rate_contrast: f32,
+    quarter_rate_contrast: f32,
+    motion_scale: f32,
+    max_rate: u32,
+    use_motion: u32,
+}
+
+/// How far [`ShadingRatePass`] lowers the shading rate of flat and moving parts of the frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ShadingRateQuality {
+    /// Shades every pixel, while keeping the pass in the frame.
+    Off,
+    /// Only goes down to 2x2, for tiles that are close to flat.
+    #[default]
+    Quality,
+    Balanced,
+    Performance,
+}
+
+impl ShadingRateQuality {
+    /// Contrast for 2x2 and 4x4, motion scale and the coarsest rate as log2.
+    fn thresholds(self) -> (f32, f32, f32, u32) {
+        match self {
+            ShadingRateQuality::Off => (0.0, 0.0, 0.0, 0),
+            ShadingRateQuality::Quality => (0.03, 0.0, 0.05, 1),
+            ShadingRateQuality::Balanced => (0.06, 0.02, 0.1, 2),
+            ShadingRateQuality::Performance => (0.12, 0.05, 0.2, 2),
+        }
+    }
+}
+
+/// Builds the shading rate image of a frame from the luminance and motion of the previous one,
+/// attach it to a [`RenderTarget`](super::graphics::RenderTarget) through
+/// [`ShadingRatePass::attachment`]. Needs `ExampleBase::shading_rate_texel_size`.
+///
+/// `shader/shading_rate.comp` reads the color at binding 0, the motion vectors at binding 1 and
+/// writes the rates at binding 3.
+#[derive(Debug)]
+pub struct ShadingRatePass {
+    pub image: Image,
+    /// Can be changed between frames.
+    pub quality: ShadingRateQuality,
+    view: vk::ImageView,
+    texel_size: vk::Extent2D,
+    use_motion: bool,
+    pass: ComputePass,
+}
+
+impl ShadingRatePass {
+    /// `color` is the lit frame and `motion_vectors` the uv offsets to the frame before it, both
+    /// have to be created with `SAMPLED` usage and be in the `SHADER_READ_ONLY_OPTIMAL` layout
+    /// when recorded. Record the pass before they're overwritten, so they still hold the
+    /// previous frame.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        color: &mut Image,
+        motion_vectors: Option<vk::ImageView>,
+        quality: ShadingRateQuality,
+    ) -> Result<Self> {
+        let device = render_instance.device();
+        let texel_size = render_instance
+            .0
+            .shading_rate_texel_size
+            .expect("Shading rate images aren't supported by the device");
+        let extent = vk::Extent2D {
+            width: color.extent.width.div_ceil(texel_size.width),
+            height: color.extent.height.div_ceil(texel_size.height),
+        };
+
+        let mut image = create_image(
+            render_instance,
+            render_allocator,
+            extent,
+            RATE_FORMAT,
+            vk::ImageUsageFlags::FRAGMENT_SHADING_RATE_ATTACHMENT_KHR,
+        )?;
+        let view = image.create_view(device)?;
+        let color_view = color.create_view(device)?;
+
+        let mut pass = ComputePass::from_file(
+            render_instance,
+            "./shader/shading_rate.comp",
+            size_of::<ShadingRateConstants>() as u32,
+        )?;
+        write_image_descriptors(
+            render_instance,
+            &pass.pipeline.set_layout_info,
+            &pass.pipeline.descriptor_sets,
+            &[
+                (0, color_view),
+                // the shader skips it when there's no motion, but the binding can't be empty
+                (1, motion_vectors.unwrap_or(color_view)),
+                (3, view),
+            ],
+        );
+        pass.add_image_write(&image, Usage::ShadingRateAttachment);
+
+        Ok(Self {
+            image,
+            quality,
+            view,
+            texel_size,
+            use_motion: motion_vectors.is_some(),
+            pass,
+        })
+    }
+
+    /// The image for [`RenderTarget::shading_rate`](super::graphics::RenderTarget::shading_rate),
+    /// valid after [`Self::record`].
+    pub fn attachment(&self) -> ShadingRateAttachment {
+        ShadingRateAttachment {
+            view: self.view,
+            texel_size: self.texel_size,
+        }
+    }
+
+    /// Declares the pass in the render graph, ordered after the passes producing `color` and
+    /// `motion_vectors` and culled together with the passes shading with `output`.
+    pub fn add_to_graph(
+        &self,
+        graph: &mut RenderGraph,
+        color: ResourceId,
+        motion_vectors: Option<ResourceId>,
+        output: ResourceId,
+    ) {
+        let mut reads = vec![color];
+        reads.extend(motion_vectors);
+        graph.add_pass("shading_rate", &reads, &[output]);
+    }
+
+    /// Writes the rates, afterwards the image is ready to be attached.
+    pub fn record(&self, render_instance: &RenderInstance, command_buffer: vk::CommandBuffer) {
+        let renderer = render_instance.0.as_ref();
+        // every texel is overwritten
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[barrier::image_barrier(
+                    self.image.image,
+                    vk::ImageAspectFlags::COLOR,
+                    Usage::Undefined,
+                    Usage::ComputeWrite,
+                )]),
+            )
+        };
+
+        let (half_rate_contrast, quarter_rate_contrast, motion_scale, max_rate) =
+            self.quality.thresholds();
+        let constants = ShadingRateConstants {
+            tile_size: [self.texel_size.width, self.texel_size.height],
+            half_rate_contrast,
+            quarter_rate_contrast,
+            motion_scale,
+            max_rate,
+            use_motion: self.use_motion as u32,
+        };
+        self.pass.record(
+            render_instance,
+            command_buffer,
+            (self.image.extent.width, self.image.extent.height, 1),
+            bytemuck::bytes_of(&constants),
+        );
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.image
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+    }
+}
@@ -0,0 +1,254 @@
+use ash::vk;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        command::DrawContext,
+        pipeline::{BlendMode, CompareFunction, DepthBiasState, DepthStencilState, PrimitiveState},
+        shaders::{Shader, ShaderKind},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::graphics::{
+    DepthAttachment, GraphicsPass, GraphicsPassDescriptor, GraphicsState, RenderTarget,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShadowMapDescriptor {
+    /// Width and height of every cascade.
+    pub resolution: u32,
+    pub cascade_count: u32,
+    pub format: vk::Format,
+    pub bias: DepthBiasState,
+    /// `BACK` by default, rendering the faces that face the light keeps shadows attached to
+    /// their casters and leaves the acne to `bias`. `FRONT` hides the acne on closed meshes at
+    /// the cost of peter panning, `NONE` is needed for single sided geometry.
+    pub cull_mode: vk::CullModeFlags,
+}
+
+impl Default for ShadowMapDescriptor {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            cascade_count: 4,
+            format: vk::Format::D32_SFLOAT,
+            bias: DepthBiasState {
+                constant: 2,
+                slope_scale: 2.0,
+                clamp: 0.0,
+            },
+            cull_mode: vk::CullModeFlags::BACK,
+        }
+    }
+}
+
+/// Depth-only pass rendering into a layered shadow map, one layer per cascade.
+///
+/// The whole map is sampled through `array_view`, bind it next to a comparison sampler
+/// such as `sampler_llc_cmp` and sample it with `sampler2DArrayShadow`.
+#[derive(Debug)]
+pub struct ShadowPass {
+    pub pass: GraphicsPass,
+    pub map: Image,
+    pub array_view: vk::ImageView,
+    /// Single layer views used as the depth attachment of every cascade.
+    pub cascade_views: Vec<vk::ImageView>,
+    pub desc: ShadowMapDescriptor,
+}
+
+impl ShadowPass {
+    /// `vertex_shader` transforms the geometry into light space, a built-in empty fragment
+    /// shader is used since only depth is written.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        vertex_shader: Shader,
+        vertex_input: vk::PipelineVertexInputStateCreateInfo,
+        push_constant_size: u32,
+        desc: ShadowMapDescriptor,
+    ) -> Result<Self> {
+        let device = render_instance.device();
+
+        let map = Image::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(desc.format)
+                .extent(vk::Extent3D {
+                    width: desc.resolution,
+                    height: desc.resolution,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(desc.cascade_count)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?;
+
+        let create_view =
+            |view_type: vk::ImageViewType, base_array_layer: u32, layer_count: u32| unsafe {
+                device.create_image_view(
+                    &vk::ImageViewCreateInfo::default()
+                        .image(map.image)
+                        .view_type(view_type)
+                        .format(desc.format)
+                        .subresource_range(vk::ImageSubresourceRange {
+                            aspect_mask: barrier::aspect_mask_from_format(desc.format)
+                                & vk::ImageAspectFlags::DEPTH,
+                            base_mip_level: 0,
+                            level_count: 1,
+                            base_array_layer,
+                            layer_count,
+                        }),
+                    None,
+                )
+            };
+
+        let array_view = create_view(vk::ImageViewType::TYPE_2D_ARRAY, 0, desc.cascade_count)?;
+        let cascade_views = (0..desc.cascade_count)
+            .map(|cascade| create_view(vk::ImageViewType::TYPE_2D, cascade, 1))
+            .collect::<Result<_, vk::Result>>()?;
+
+        let fragment_shader = Shader::from_file(
+            render_instance,
+            "./shader/depth_only.frag",
+            ShaderKind::Fragment,
+            "main",
+        )?;
+
+        let pass = GraphicsPass::new(
+            render_instance,
+            GraphicsPassDescriptor {
+                vertex_shader,
+                fragment_shader,
+                vertex_input,
+                state: GraphicsState {
+                    primitive: PrimitiveState {
+                        topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+                        cull_mode: desc.cull_mode,
+                        ..Default::default()
+                    },
+                    depth_stencil: Some(DepthStencilState {
+                        format: desc.format,
+                        depth_write_enabled: true,
+                        depth_compare: CompareFunction::LessEqual,
+                        stencil: Default::default(),
+                        bias: desc.bias,
+                    }),
+                    blend: BlendMode::Replace,
+                    multisample: Default::default(),
+                    shading_rate_image: false,
+                    view_mask: 0,
+                },
+                push_constant_size,
+                color_formats: &[],
+            },
+        )?;
+
+        Ok(Self {
+            pass,
+            map,
+            array_view,
+            cascade_views,
+            desc,
+        })
+    }
+
+    /// Discards the previous contents of every cascade, call before the first [`ShadowPass::record`].
+    pub fn begin(&self, render_instance: &RenderInstance, command_buffer: vk::CommandBuffer) {
+        self.transition(
+            render_instance,
+            command_buffer,
+            Usage::Undefined,
+            Usage::DepthAttachmentWrite,
+        );
+    }
+
+    /// Renders a single cascade, the whole map is transitioned in [`ShadowPass::begin`] and
+    /// [`ShadowPass::end`] so cascades don't discard each other.
+    pub fn record<F: FnOnce(&DrawContext)>(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        cascade: u32,
+        draw: F,
+    ) {
+        let target = RenderTarget {
+            extent: vk::Extent2D {
+                width: self.desc.resolution,
+                height: self.desc.resolution,
+            },
+            color_attachments: vec![],
+            depth_attachment: Some(DepthAttachment {
+                image: self.map.image,
+                view: self.cascade_views[cascade as usize],
+                format: self.desc.format,
+                clear: Some(1.0),
+                initial_usage: Usage::DepthAttachmentWrite,
+                final_usage: Usage::DepthAttachmentWrite,
+            }),
+            shading_rate: None,
+            view_mask: 0,
+        };
+
+        self.pass
+            .record(render_instance, command_buffer, &target, draw);
+    }
+
+    /// Makes all cascades available for the `next` usage, usually [`Usage::FragmentSampled`].
+    pub fn end(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        next: Usage,
+    ) {
+        self.transition(
+            render_instance,
+            command_buffer,
+            Usage::DepthAttachmentWrite,
+            next,
+        );
+    }
+
+    fn transition(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        from: Usage,
+        to: Usage,
+    ) {
+        let barriers = [barrier::image_barrier(
+            self.map.image,
+            barrier::aspect_mask_from_format(self.desc.format),
+            from,
+            to,
+        )];
+        unsafe {
+            render_instance.0.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&barriers),
+            )
+        };
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        unsafe {
+            device.destroy_image_view(self.array_view, None);
+            for view in self.cascade_views.drain(..) {
+                device.destroy_image_view(view, None);
+            }
+        }
+        self.map.destroy(device, &mut render_allocator.allocator());
+    }
+}
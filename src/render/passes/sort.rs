@@ -0,0 +1,180 @@
+use std::mem::size_of;
+
+use ash::vk;
+
+use crate::{
+    buffer::Buffer,
+    error::Result,
+    render::{barrier::Usage, RenderAllocator, RenderInstance},
+};
+
+use super::{
+    compute::ComputePass,
+    scan::{create_storage_buffer, record_barrier, PrefixSum},
+};
+
+const BLOCK_SIZE: u32 = 256;
+const RADIX_BITS: u32 = 4;
+const RADIX: u32 = 1 << RADIX_BITS;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct RadixSortConstants {
+    keys_in: u64,
+    values_in: u64,
+    keys_out: u64,
+    values_out: u64,
+    histograms: u64,
+    count: u32,
+    shift: u32,
+    block_count: u32,
+    _padding: u32,
+}
+
+/// Stable least significant digit radix sort of `u32` keys with a `u32` value each, sorting 4
+/// bits per pass. Every pass builds per block digit histograms, scans them with [`PrefixSum`]
+/// and scatters the elements into a scratch buffer, so 8 passes leave the sorted result in the
+/// original buffers again.
+#[derive(Debug)]
+pub struct RadixSort {
+    histogram: ComputePass,
+    scatter: ComputePass,
+    prefix_sum: PrefixSum<u32>,
+    keys_scratch: Buffer,
+    values_scratch: Buffer,
+    histograms: Buffer,
+    max_count: u32,
+}
+
+impl RadixSort {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        max_count: u32,
+    ) -> Result<Self> {
+        let max_blocks = ((max_count + BLOCK_SIZE - 1) / BLOCK_SIZE).max(1);
+        let push_constant_size = size_of::<RadixSortConstants>() as u32;
+
+        Ok(Self {
+            histogram: ComputePass::from_file(
+                render_instance,
+                "./shader/sort/radix_histogram.comp",
+                push_constant_size,
+            )?,
+            scatter: ComputePass::from_file(
+                render_instance,
+                "./shader/sort/radix_scatter.comp",
+                push_constant_size,
+            )?,
+            prefix_sum: PrefixSum::new(render_instance, render_allocator, max_blocks * RADIX)?,
+            keys_scratch: create_storage_buffer::<u32>(
+                render_instance,
+                render_allocator,
+                max_count.max(1),
+            )?,
+            values_scratch: create_storage_buffer::<u32>(
+                render_instance,
+                render_allocator,
+                max_count.max(1),
+            )?,
+            histograms: create_storage_buffer::<u32>(
+                render_instance,
+                render_allocator,
+                max_blocks * RADIX,
+            )?,
+            max_count,
+        })
+    }
+
+    /// Sorts the first `count` elements of `keys` and `values` by key. Previous writes to them
+    /// have to be visible to compute shaders, afterwards they're made visible for the `next` usage.
+    pub fn sort(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        keys: &Buffer,
+        values: &Buffer,
+        count: u32,
+        next: Usage,
+    ) {
+        assert!(
+            count <= self.max_count,
+            "RadixSort was created for {} elements, got {}",
+            self.max_count,
+            count
+        );
+        let block_count = ((count + BLOCK_SIZE - 1) / BLOCK_SIZE).max(1);
+
+        let mut source = (keys.device_addr, values.device_addr);
+        let mut destination = (
+            self.keys_scratch.device_addr,
+            self.values_scratch.device_addr,
+        );
+
+        for pass in 0..32 / RADIX_BITS {
+            let constants = RadixSortConstants {
+                keys_in: source.0,
+                values_in: source.1,
+                keys_out: destination.0,
+                values_out: destination.1,
+                histograms: self.histograms.device_addr,
+                count,
+                shift: pass * RADIX_BITS,
+                block_count,
+                _padding: 0,
+            };
+
+            self.histogram.record(
+                render_instance,
+                command_buffer,
+                (count, 1, 1),
+                bytemuck::bytes_of(&constants),
+            );
+            record_barrier(
+                render_instance,
+                command_buffer,
+                Usage::ComputeWrite,
+                Usage::ComputeRead,
+            );
+            self.prefix_sum.record(
+                render_instance,
+                command_buffer,
+                &self.histograms,
+                &self.histograms,
+                block_count * RADIX,
+                Usage::ComputeRead,
+            );
+            self.scatter.record(
+                render_instance,
+                command_buffer,
+                (count, 1, 1),
+                bytemuck::bytes_of(&constants),
+            );
+            // the next histogram pass reads the scattered keys, and overwrites the histograms
+            // the scatter pass just read
+            record_barrier(
+                render_instance,
+                command_buffer,
+                Usage::ComputeWrite,
+                Usage::ComputeWrite,
+            );
+
+            std::mem::swap(&mut source, &mut destination);
+        }
+
+        record_barrier(render_instance, command_buffer, Usage::ComputeWrite, next);
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.prefix_sum.destroy(render_instance, render_allocator);
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.keys_scratch.destroy(device, &mut allocator);
+        self.values_scratch.destroy(device, &mut allocator);
+        self.histograms.destroy(device, &mut allocator);
+    }
+}
@@ -0,0 +1,434 @@
+use std::{collections::HashMap, mem::size_of};
+
+use ash::vk;
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::{Buffer, Image},
+    error::{Error, Result},
+    render::{
+        atlas::AtlasAllocator,
+        barrier::{self, Usage},
+        deferred_destroy::DeferredDestroyQueue,
+        pipeline::{BlendMode, PrimitiveState},
+        shaders::{Shader, ShaderKind},
+        stats, RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{
+    graphics::{GraphicsPass, GraphicsPassDescriptor, GraphicsState, RenderTarget},
+    reserve, write_image_descriptors,
+};
+
+const ATLAS_SIZE: u32 = 1024;
+/// Empty texels around every glyph, so linear filtering doesn't pick up its neighbours.
+const GLYPH_PADDING: u32 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct GlyphInstance {
+    /// Top left corner and size in pixels.
+    rect: [f32; 4],
+    /// Top left and bottom right corner in the atlas.
+    uv_rect: [f32; 4],
+    color: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Glyph {
+    /// From the pen position on the baseline to the top left corner.
+    offset: [f32; 2],
+    size: [f32; 2],
+    uv_rect: [f32; 4],
+    advance: f32,
+}
+
+/// Draws screen-space text with a single font and size, for debug overlays and HUDs. Glyphs are
+/// rasterized with `fontdue` the first time they're used and packed into a coverage atlas,
+/// every glyph is drawn as an instanced quad blended over the target.
+///
+/// Text is queued with [`TextRenderer::queue`] during the frame and drawn by
+/// [`TextRenderer::record`], which also uploads the glyphs that were added since the last call.
+pub struct TextRenderer {
+    pass: GraphicsPass,
+    font: fontdue::Font,
+    /// Font size in pixels.
+    pub size: f32,
+    ascent: f32,
+    line_height: f32,
+    atlas: Image,
+    atlas_allocator: AtlasAllocator,
+    atlas_initialized: bool,
+    glyphs: HashMap<char, Glyph>,
+    /// Rasterized glyphs that still have to be copied into the atlas, with their position.
+    pending_glyphs: Vec<([u32; 4], Vec<u8>)>,
+    instances: Vec<GlyphInstance>,
+    instance_buffer: Option<Buffer>,
+}
+
+impl TextRenderer {
+    /// `font` is the contents of a TrueType or OpenType file, `color_format` the format of the
+    /// render targets the text is recorded into.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        font: &[u8],
+        size: f32,
+        color_format: vk::Format,
+    ) -> Result<Self> {
+        let font = fontdue::Font::from_bytes(font, fontdue::FontSettings::default())
+            .map_err(|message| Error::Font(message.to_string()))?;
+        let line_metrics = font
+            .horizontal_line_metrics(size)
+            .ok_or_else(|| Error::Font("it has no horizontal line metrics".to_string()))?;
+
+        let vertex_shader = Shader::from_file(
+            render_instance,
+            "./shader/text.vert",
+            ShaderKind::Vertex,
+            "main",
+        )?;
+        let fragment_shader = Shader::from_file(
+            render_instance,
+            "./shader/text.frag",
+            ShaderKind::Fragment,
+            "main",
+        )?;
+
+        let vertex_bindings = [vk::VertexInputBindingDescription {
+            binding: 0,
+            stride: size_of::<GlyphInstance>() as u32,
+            input_rate: vk::VertexInputRate::INSTANCE,
+        }];
+        let vertex_attributes = [0, 1, 2].map(|location| vk::VertexInputAttributeDescription {
+            location,
+            binding: 0,
+            format: vk::Format::R32G32B32A32_SFLOAT,
+            offset: location * 16,
+        });
+
+        let pass = GraphicsPass::new(
+            render_instance,
+            GraphicsPassDescriptor {
+                vertex_shader,
+                fragment_shader,
+                vertex_input: vk::PipelineVertexInputStateCreateInfo::default()
+                    .vertex_binding_descriptions(&vertex_bindings)
+                    .vertex_attribute_descriptions(&vertex_attributes),
+                state: GraphicsState {
+                    primitive: PrimitiveState {
+                        topology: vk::PrimitiveTopology::TRIANGLE_STRIP,
+                        cull_mode: vk::CullModeFlags::NONE,
+                        ..Default::default()
+                    },
+                    depth_stencil: None,
+                    blend: BlendMode::AlphaBlend,
+                    multisample: Default::default(),
+                    shading_rate_image: false,
+                    view_mask: 0,
+                },
+                push_constant_size: size_of::<[f32; 2]>() as u32,
+                color_formats: &[color_format],
+            },
+        )?;
+
+        let mut atlas = Image::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(vk::Format::R8_UNORM)
+                .extent(vk::Extent3D {
+                    width: ATLAS_SIZE,
+                    height: ATLAS_SIZE,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?;
+        let atlas_view = atlas.create_view(render_instance.device())?;
+        write_image_descriptors(
+            render_instance,
+            &pass.pipeline.set_layout_info,
+            &pass.pipeline.descriptor_sets,
+            &[(0, atlas_view)],
+        );
+
+        Ok(Self {
+            pass,
+            font,
+            size,
+            ascent: line_metrics.ascent,
+            line_height: line_metrics.new_line_size,
+            atlas,
+            atlas_allocator: AtlasAllocator::new(ATLAS_SIZE, ATLAS_SIZE),
+            atlas_initialized: false,
+            glyphs: HashMap::new(),
+            pending_glyphs: vec![],
+            instances: vec![],
+            instance_buffer: None,
+        })
+    }
+
+    /// Queues `text` with its top left corner at `position` in pixels, line breaks move down by
+    /// the line height of the font. Returns the size of the text in pixels.
+    pub fn queue(&mut self, text: &str, position: [f32; 2], color: [f32; 4]) -> [f32; 2] {
+        let mut pen = [position[0], position[1] + self.ascent];
+        let mut width: f32 = 0.0;
+        for character in text.chars() {
+            if character == '\n' {
+                pen = [position[0], pen[1] + self.line_height];
+                continue;
+            }
+
+            let glyph = self.glyph(character);
+            if glyph.size[0] > 0.0 && glyph.size[1] > 0.0 {
+                self.instances.push(GlyphInstance {
+                    rect: [
+                        (pen[0] + glyph.offset[0]).round(),
+                        (pen[1] + glyph.offset[1]).round(),
+                        glyph.size[0],
+                        glyph.size[1],
+                    ],
+                    uv_rect: glyph.uv_rect,
+                    color,
+                });
+            }
+            pen[0] += glyph.advance;
+            width = width.max(pen[0] - position[0]);
+        }
+
+        [width, pen[1] - self.ascent + self.line_height - position[1]]
+    }
+
+    /// Looks up the glyph of `character`, rasterizing it into the atlas the first time. Glyphs
+    /// that don't fit in the atlas anymore only advance the pen.
+    fn glyph(&mut self, character: char) -> Glyph {
+        if let Some(glyph) = self.glyphs.get(&character) {
+            return *glyph;
+        }
+
+        let (metrics, coverage) = self.font.rasterize(character, self.size);
+        let mut size = [0, 0];
+        let mut uv_rect = [0.0; 4];
+        if metrics.width > 0 && metrics.height > 0 {
+            let [width, height] = [metrics.width as u32, metrics.height as u32];
+            match self
+                .atlas_allocator
+                .allocate(width + GLYPH_PADDING * 2, height + GLYPH_PADDING * 2)
+            {
+                Some([x, y]) => {
+                    let [x, y] = [x + GLYPH_PADDING, y + GLYPH_PADDING];
+                    size = [width, height];
+                    uv_rect = [
+                        x as f32 / ATLAS_SIZE as f32,
+                        y as f32 / ATLAS_SIZE as f32,
+                        (x + width) as f32 / ATLAS_SIZE as f32,
+                        (y + height) as f32 / ATLAS_SIZE as f32,
+                    ];
+                    self.pending_glyphs.push(([x, y, width, height], coverage));
+                }
+                None => println!("Glyph atlas is full, {:?} isn't drawn", character),
+            }
+        }
+
+        let glyph = Glyph {
+            // fontdue measures from the baseline upwards, to the bottom of the glyph
+            offset: [
+                metrics.xmin as f32,
+                -(metrics.ymin as f32 + metrics.height as f32),
+            ],
+            size: [size[0] as f32, size[1] as f32],
+            uv_rect,
+            advance: metrics.advance_width,
+        };
+        self.glyphs.insert(character, glyph);
+        glyph
+    }
+
+    /// Uploads new glyphs and draws all queued text into `target`, then clears the queue.
+    pub fn record(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        deferred_destroy: &mut DeferredDestroyQueue,
+        command_buffer: vk::CommandBuffer,
+        target: &RenderTarget,
+    ) -> Result<()> {
+        if !self.atlas_initialized || !self.pending_glyphs.is_empty() {
+            self.upload_glyphs(
+                render_instance,
+                render_allocator,
+                deferred_destroy,
+                command_buffer,
+            )?;
+        }
+        if self.instances.is_empty() {
+            return Ok(());
+        }
+
+        let instance_count = self.instances.len() as u32;
+        let instance_buffer = reserve(
+            render_instance,
+            render_allocator,
+            deferred_destroy,
+            &mut self.instance_buffer,
+            (self.instances.len() * size_of::<GlyphInstance>()) as u64,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?;
+        instance_buffer.copy_from_slice(&self.instances, 0);
+        self.instances.clear();
+
+        let scale = [
+            2.0 / target.extent.width as f32,
+            2.0 / target.extent.height as f32,
+        ];
+        let instance_buffer = &*instance_buffer;
+        self.pass
+            .record(render_instance, command_buffer, target, |ctx| {
+                ctx.bind_vertex_buffer(instance_buffer);
+                ctx.push_constants(&scale);
+                unsafe {
+                    ctx.device()
+                        .cmd_draw(ctx.command_buffer, 4, instance_count, 0, 0)
+                };
+                stats::count_draw(2 * instance_count as u64);
+            });
+        Ok(())
+    }
+
+    /// Copies the pending glyphs into the atlas, clearing it first the first time around.
+    fn upload_glyphs(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        deferred_destroy: &mut DeferredDestroyQueue,
+        command_buffer: vk::CommandBuffer,
+    ) -> Result<()> {
+        let device = render_instance.device();
+        let synchronization2 = &render_instance.0.synchronization2;
+        let from = if self.atlas_initialized {
+            Usage::FragmentSampled
+        } else {
+            Usage::Undefined
+        };
+
+        unsafe {
+            synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[barrier::image_barrier(
+                    self.atlas.image,
+                    vk::ImageAspectFlags::COLOR,
+                    from,
+                    Usage::TransferWrite,
+                )]),
+            );
+
+            if !self.atlas_initialized {
+                device.cmd_clear_color_image(
+                    command_buffer,
+                    self.atlas.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &vk::ClearColorValue::default(),
+                    &[vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    }],
+                );
+                if !self.pending_glyphs.is_empty() {
+                    synchronization2.cmd_pipeline_barrier2(
+                        command_buffer,
+                        &vk::DependencyInfo::default().memory_barriers(&[barrier::memory_barrier(
+                            Usage::TransferWrite,
+                            Usage::TransferWrite,
+                        )]),
+                    );
+                }
+                self.atlas_initialized = true;
+            }
+
+            if !self.pending_glyphs.is_empty() {
+                let size = self
+                    .pending_glyphs
+                    .iter()
+                    .map(|(_, coverage)| coverage.len())
+                    .sum::<usize>();
+                let mut staging = Buffer::new(
+                    device,
+                    &mut render_allocator.allocator(),
+                    &vk::BufferCreateInfo::default()
+                        .size(size as u64)
+                        .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                    MemoryLocation::CpuToGpu,
+                )?;
+                let mut offset = 0;
+                let mut regions = vec![];
+                for ([x, y, width, height], coverage) in self.pending_glyphs.drain(..) {
+                    staging.copy_from_slice(&coverage, offset);
+                    regions.push(
+                        vk::BufferImageCopy::default()
+                            .buffer_offset(offset as u64)
+                            .image_subresource(vk::ImageSubresourceLayers {
+                                aspect_mask: vk::ImageAspectFlags::COLOR,
+                                mip_level: 0,
+                                base_array_layer: 0,
+                                layer_count: 1,
+                            })
+                            .image_offset(vk::Offset3D {
+                                x: x as i32,
+                                y: y as i32,
+                                z: 0,
+                            })
+                            .image_extent(vk::Extent3D {
+                                width,
+                                height,
+                                depth: 1,
+                            }),
+                    );
+                    offset += coverage.len();
+                }
+                device.cmd_copy_buffer_to_image(
+                    command_buffer,
+                    staging.buffer,
+                    self.atlas.image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &regions,
+                );
+                deferred_destroy.push(staging);
+            }
+
+            synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[barrier::image_barrier(
+                    self.atlas.image,
+                    vk::ImageAspectFlags::COLOR,
+                    Usage::TransferWrite,
+                    Usage::FragmentSampled,
+                )]),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        if let Some(mut buffer) = self.instance_buffer.take() {
+            buffer.destroy(device, &mut allocator);
+        }
+        self.atlas.destroy(device, &mut allocator);
+    }
+}
@@ -0,0 +1,296 @@
+use std::mem::size_of;
+
+use ash::vk;
+use bevy::prelude::*;
+
+use crate::{
+    buffer::Image,
+    error::Result,
+    render::{
+        barrier::{self, Usage},
+        camera::halton_jitter_sequence,
+        graph::{RenderGraph, ResourceId},
+        RenderAllocator, RenderInstance,
+    },
+};
+
+use super::{compute::ComputePass, post_process::create_image, write_image_descriptors};
+
+/// Images at render resolution an upscaler reads, all of them have to be in the
+/// `SHADER_READ_ONLY_OPTIMAL` layout when the upscaler is recorded.
+#[derive(Clone, Copy, Debug)]
+pub struct UpscaleInputs {
+    pub color: vk::ImageView,
+    /// Reverse depth, like the projections of [`Camera`](crate::render::camera::Camera).
+    pub depth: vk::ImageView,
+    /// Two channels holding the uv offset from the current to the previous frame.
+    pub motion_vectors: vk::ImageView,
+}
+
+/// What changes between the frames an upscaler records.
+#[derive(Clone, Copy, Debug)]
+pub struct UpscaleFrame {
+    /// Jitter in render pixels the frame was rendered with, the `jitter` of the camera.
+    pub jitter: Vec2,
+    /// Throws away the history, e.g. after a camera cut.
+    pub reset: bool,
+    /// `output` is transitioned to this usage afterwards.
+    pub output_usage: Usage,
+}
+
+/// Turns a jittered frame at render resolution into one at display resolution using the
+/// frames before it. The built-in implementation is [`TemporalUpscaler`], libraries like FSR 2
+/// or DLSS can be hooked in by implementing this for a wrapper that records their dispatch.
+///
+/// The camera has to be jittered with [`Upscaler::jitter`] and render at
+/// [`Upscaler::render_resolution`], which goes into its
+/// [`render_resolution`](crate::render::camera::Camera::render_resolution).
+pub trait Upscaler: Send + Sync {
+    fn render_resolution(&self) -> vk::Extent2D;
+
+    fn display_resolution(&self) -> vk::Extent2D;
+
+    /// The upscaled image at display resolution.
+    fn output(&self) -> &Image;
+
+    /// Jitter in render pixels for `frame_index`, the amount of phases grows with the upscaling
+    /// ratio so every display pixel gets covered.
+    fn jitter(&self, frame_index: u32) -> Vec2 {
+        let ratio = self.display_resolution().width as f32 / self.render_resolution().width as f32;
+        let phase_count = (8.0 * ratio * ratio).ceil() as u32;
+        halton_jitter_sequence(frame_index, phase_count)
+    }
+
+    /// Declares the upscale pass in the render graph, ordered after the passes producing the
+    /// inputs and culled together with whatever consumes `output`.
+    fn add_to_graph(
+        &self,
+        graph: &mut RenderGraph,
+        color: ResourceId,
+        depth: ResourceId,
+        motion_vectors: ResourceId,
+        output: ResourceId,
+    ) {
+        graph.add_pass("upscale", &[color, depth, motion_vectors], &[output]);
+    }
+
+    fn record(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        frame: &UpscaleFrame,
+    );
+
+    fn destroy(&mut self, render_instance: &RenderInstance, render_allocator: &mut RenderAllocator);
+}
+
+/// Render resolution for upscaling to `display` with a ratio like 1.5 or 2.0 per axis.
+pub fn render_resolution(display: vk::Extent2D, ratio: f32) -> vk::Extent2D {
+    vk::Extent2D {
+        width: ((display.width as f32 / ratio) as u32).max(1),
+        height: ((display.height as f32 / ratio) as u32).max(1),
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct UpscaleConstants {
+    jitter: Vec2,
+    min_blend: f32,
+    reset: u32,
+}
+
+const HISTORY_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const OUTPUT_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Temporal upscaler in the style of FSR 2. Every display pixel blends the render sample
+/// closest to it into the reprojected history, weighted by the distance to that sample, while
+/// the history is clamped to the neighborhood of the current frame to avoid ghosting. The
+/// history keeps its accumulated confidence in alpha.
+///
+/// `shader/upscale.comp` reads the inputs at bindings 0 to 2 and the previous history at
+/// binding 4, and writes the new history and `output_image` at bindings 5 and 6.
+#[derive(Debug)]
+pub struct TemporalUpscaler {
+    pub output: Image,
+    /// Weight of the current frame once the history has converged, higher values trade
+    /// stability for less ghosting.
+    pub min_blend: f32,
+    render_resolution: vk::Extent2D,
+    history: [Image; 2],
+    /// `passes[i]` writes `history[i]` and reads the other one.
+    passes: [ComputePass; 2],
+    frame_index: u32,
+}
+
+impl TemporalUpscaler {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        inputs: UpscaleInputs,
+        render_resolution: vk::Extent2D,
+        display_resolution: vk::Extent2D,
+        output_usage: vk::ImageUsageFlags,
+    ) -> Result<Self> {
+        let device = render_instance.device();
+        let mut output = create_image(
+            render_instance,
+            render_allocator,
+            display_resolution,
+            OUTPUT_FORMAT,
+            output_usage,
+        )?;
+        let mut create_history = || {
+            create_image(
+                render_instance,
+                render_allocator,
+                display_resolution,
+                HISTORY_FORMAT,
+                vk::ImageUsageFlags::empty(),
+            )
+        };
+        let mut history = [create_history()?, create_history()?];
+
+        let output_view = output.create_view(device)?;
+        let history_views = [
+            history[0].create_view(device)?,
+            history[1].create_view(device)?,
+        ];
+        let create_pass = |index: usize| -> Result<ComputePass> {
+            let pass = ComputePass::from_file(
+                render_instance,
+                "./shader/upscale.comp",
+                size_of::<UpscaleConstants>() as u32,
+            )?;
+            write_image_descriptors(
+                render_instance,
+                &pass.pipeline.set_layout_info,
+                &pass.pipeline.descriptor_sets,
+                &[
+                    (0, inputs.color),
+                    (1, inputs.depth),
+                    (2, inputs.motion_vectors),
+                    (4, history_views[1 - index]),
+                    (5, history_views[index]),
+                    (6, output_view),
+                ],
+            );
+            Ok(pass)
+        };
+        let passes = [create_pass(0)?, create_pass(1)?];
+
+        Ok(Self {
+            output,
+            min_blend: 0.05,
+            render_resolution,
+            history,
+            passes,
+            frame_index: 0,
+        })
+    }
+}
+
+impl Upscaler for TemporalUpscaler {
+    fn render_resolution(&self) -> vk::Extent2D {
+        self.render_resolution
+    }
+
+    fn display_resolution(&self) -> vk::Extent2D {
+        vk::Extent2D {
+            width: self.output.extent.width,
+            height: self.output.extent.height,
+        }
+    }
+
+    fn output(&self) -> &Image {
+        &self.output
+    }
+
+    fn record(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        frame: &UpscaleFrame,
+    ) {
+        let renderer = render_instance.0.as_ref();
+        let current = (self.frame_index % 2) as usize;
+        let previous = 1 - current;
+        let reset = frame.reset || self.frame_index == 0;
+
+        // the previous history is only valid after the first frame, but has to be in a
+        // readable layout either way
+        let previous_usage = if self.frame_index == 0 {
+            Usage::Undefined
+        } else {
+            Usage::ComputeWrite
+        };
+        unsafe {
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[
+                    barrier::image_barrier(
+                        self.history[previous].image,
+                        vk::ImageAspectFlags::COLOR,
+                        previous_usage,
+                        Usage::ComputeSampled,
+                    ),
+                    barrier::image_barrier(
+                        self.history[current].image,
+                        vk::ImageAspectFlags::COLOR,
+                        Usage::Undefined,
+                        Usage::ComputeWrite,
+                    ),
+                    barrier::image_barrier(
+                        self.output.image,
+                        vk::ImageAspectFlags::COLOR,
+                        Usage::Undefined,
+                        Usage::ComputeWrite,
+                    ),
+                ]),
+            )
+        };
+
+        let constants = UpscaleConstants {
+            jitter: frame.jitter,
+            min_blend: self.min_blend,
+            reset: reset as u32,
+        };
+        self.passes[current].record(
+            render_instance,
+            command_buffer,
+            (self.output.extent.width, self.output.extent.height, 1),
+            bytemuck::bytes_of(&constants),
+        );
+
+        if frame.output_usage != Usage::ComputeWrite {
+            unsafe {
+                renderer.synchronization2.cmd_pipeline_barrier2(
+                    command_buffer,
+                    &vk::DependencyInfo::default().image_memory_barriers(&[
+                        barrier::image_barrier(
+                            self.output.image,
+                            vk::ImageAspectFlags::COLOR,
+                            Usage::ComputeWrite,
+                            frame.output_usage,
+                        ),
+                    ]),
+                )
+            };
+        }
+
+        self.frame_index += 1;
+    }
+
+    fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        self.output.destroy(device, &mut allocator);
+        for history in self.history.iter_mut() {
+            history.destroy(device, &mut allocator);
+        }
+    }
+}
@@ -5,7 +5,14 @@ use std::{
 
 use ash::vk::{self, CullModeFlags, DescriptorType, FrontFace, PolygonMode, PrimitiveTopology};
 
-use super::{shaders::Shader, RenderInstance};
+use crate::error::{Error, Result};
+
+use super::{
+    deferred_destroy::{self, DeferredResource},
+    push_constants::PushConstantLayout,
+    shaders::{self, Shader, ShaderKind, StageDescriptorSetLayouts},
+    RenderInstance,
+};
 
 #[repr(C)]
 #[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
@@ -287,60 +294,257 @@ pub struct PrimitiveState {
     pub unclipped_depth: bool,
     pub polygon_mode: PolygonMode,
     pub conservative: bool,
+    pub line_mode: LineMode,
+    /// Lines follow the pattern set with
+    /// [`DrawContext::set_line_stipple`](super::command::DrawContext::set_line_stipple).
+    pub stippled_lines: bool,
+}
+
+impl PrimitiveState {
+    /// Line topologies and polygon modes have a dynamic line width.
+    pub fn draws_lines(&self) -> bool {
+        matches!(
+            self.topology,
+            PrimitiveTopology::LINE_LIST
+                | PrimitiveTopology::LINE_STRIP
+                | PrimitiveTopology::LINE_LIST_WITH_ADJACENCY
+                | PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY
+        ) || self.polygon_mode == PolygonMode::LINE
+    }
+}
+
+/// How lines are rasterized, check `ExampleBase::line_support` for anything but `Default`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum LineMode {
+    #[default]
+    Default,
+    /// Parallelograms as wide as the line width.
+    Rectangular,
+    /// Single pixel steps, like most debug line renderers.
+    Bresenham,
+    /// Antialiased by coverage.
+    Smooth,
+}
+
+impl LineMode {
+    fn to_vk(self) -> vk::LineRasterizationModeEXT {
+        match self {
+            LineMode::Default => vk::LineRasterizationModeEXT::DEFAULT,
+            LineMode::Rectangular => vk::LineRasterizationModeEXT::RECTANGULAR,
+            LineMode::Bresenham => vk::LineRasterizationModeEXT::BRESENHAM,
+            LineMode::Smooth => vk::LineRasterizationModeEXT::RECTANGULAR_SMOOTH,
+        }
+    }
+}
+
+/// How the fragment output is combined with the color attachments, the same for all of them
+/// except integer attachments like object ids, which can't be blended and are replaced.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    #[default]
+    Replace,
+    /// Non-premultiplied alpha blending, for UI and other transparent overlays.
+    AlphaBlend,
+    /// Adds the output to the attachment, e.g. to count overlapping fragments.
+    Additive,
+}
+
+impl BlendMode {
+    fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        match self {
+            BlendMode::Replace => vk::PipelineColorBlendAttachmentState {
+                blend_enable: 0,
+                src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
+                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ZERO,
+                dst_alpha_blend_factor: vk::BlendFactor::ZERO,
+                alpha_blend_op: vk::BlendOp::ADD,
+                color_write_mask: vk::ColorComponentFlags::RGBA,
+            },
+            BlendMode::AlphaBlend => vk::PipelineColorBlendAttachmentState {
+                blend_enable: 1,
+                src_color_blend_factor: vk::BlendFactor::SRC_ALPHA,
+                dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ONE,
+                dst_alpha_blend_factor: vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                alpha_blend_op: vk::BlendOp::ADD,
+                color_write_mask: vk::ColorComponentFlags::RGBA,
+            },
+            BlendMode::Additive => vk::PipelineColorBlendAttachmentState {
+                blend_enable: 1,
+                src_color_blend_factor: vk::BlendFactor::ONE,
+                dst_color_blend_factor: vk::BlendFactor::ONE,
+                color_blend_op: vk::BlendOp::ADD,
+                src_alpha_blend_factor: vk::BlendFactor::ONE,
+                dst_alpha_blend_factor: vk::BlendFactor::ONE,
+                alpha_blend_op: vk::BlendOp::ADD,
+                color_write_mask: vk::ColorComponentFlags::RGBA,
+            },
+        }
+    }
+}
+
+fn is_integer_format(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::R8_UINT
+            | vk::Format::R16_UINT
+            | vk::Format::R32_UINT
+            | vk::Format::R32_SINT
+            | vk::Format::R32G32_UINT
+            | vk::Format::R32G32B32A32_UINT
+    )
+}
+
+/// How the samples of a multisampled target are covered and shaded, e.g. alpha-to-coverage
+/// for alpha-tested foliage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MultisampleState {
+    pub samples: vk::SampleCountFlags,
+    /// Shades at least this fraction of the samples separately instead of once per pixel.
+    /// Needs the `sampleRateShading` feature, see `ExampleBase::supports_sample_rate_shading`.
+    pub min_sample_shading: Option<f32>,
+    /// Bit `n` lets the fragments cover sample `n`.
+    pub sample_mask: u64,
+    /// Derives the coverage from the alpha of the first color output.
+    pub alpha_to_coverage: bool,
+}
+
+impl Default for MultisampleState {
+    fn default() -> Self {
+        Self {
+            samples: vk::SampleCountFlags::TYPE_1,
+            min_sample_shading: None,
+            sample_mask: !0,
+            alpha_to_coverage: false,
+        }
+    }
+}
+
+impl MultisampleState {
+    /// For alpha-tested geometry, the alpha fades the edges out over the samples instead of
+    /// cutting them off.
+    pub fn alpha_tested(samples: vk::SampleCountFlags) -> Self {
+        Self {
+            samples,
+            alpha_to_coverage: true,
+            ..Default::default()
+        }
+    }
 }
 
 pub struct GraphicsPipelineDescriptor<'a> {
+    /// Either a vertex or a mesh shader. Mesh pipelines ignore `vertex_input` and the topology
+    /// and need `VK_EXT_mesh_shader`.
     pub vertex_shader: Shader,
+    /// Launches the workgroups of the mesh shader, only for mesh pipelines.
+    pub task_shader: Option<Shader>,
     pub fragment_shader: Shader,
     pub vertex_input: vk::PipelineVertexInputStateCreateInfo<'a>,
     pub viewport: vk::Extent2D,
     pub primitive: PrimitiveState,
     pub depth_stencil: Option<DepthStencilState>,
+    pub blend: BlendMode,
+    pub multisample: MultisampleState,
+    /// Takes the shading rate from the shading rate image of the render targets.
+    pub shading_rate_image: bool,
+    /// Multiview mask, has to match the one of the render targets.
+    pub view_mask: u32,
     pub push_constant_range: Option<vk::PushConstantRange>,
+    pub color_formats: &'a [vk::Format],
 }
 
 #[derive(Debug)]
 pub struct GraphicsPipeline {
     pub pipeline: vk::Pipeline,
     pub layout: vk::PipelineLayout,
+    /// Shared pool the sets were allocated from, null when the shaders have no bindings.
+    pub descriptor_pool: vk::DescriptorPool,
     pub descriptor_sets: Vec<vk::DescriptorSet>,
     pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
     pub set_layout_info: Vec<HashMap<u32, DescriptorType>>,
+    /// Reflected from the fragment shader, like the set layouts.
+    pub reflected_layouts: StageDescriptorSetLayouts,
+    pub view_mask: u32,
+    pub shading_rate_image: bool,
+    pub primitive: PrimitiveState,
+    /// The block is reflected from the vertex shader, or the fragment shader when the vertex
+    /// shader has none.
+    pub push_constants: Option<PushConstantLayout>,
 }
 
 impl GraphicsPipeline {
-    pub fn new(render_instance: &RenderInstance, desc: GraphicsPipelineDescriptor) -> Self {
-        let multisample_state_info = vk::PipelineMultisampleStateCreateInfo {
-            rasterization_samples: vk::SampleCountFlags::TYPE_1,
-            ..Default::default()
-        };
-
-        let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState {
-            blend_enable: 0,
-            src_color_blend_factor: vk::BlendFactor::SRC_COLOR,
-            dst_color_blend_factor: vk::BlendFactor::ONE_MINUS_DST_COLOR,
-            color_blend_op: vk::BlendOp::ADD,
-            src_alpha_blend_factor: vk::BlendFactor::ZERO,
-            dst_alpha_blend_factor: vk::BlendFactor::ZERO,
-            alpha_blend_op: vk::BlendOp::ADD,
-            color_write_mask: vk::ColorComponentFlags::RGBA,
-        }];
+    pub fn new(
+        render_instance: &RenderInstance,
+        mut desc: GraphicsPipelineDescriptor,
+    ) -> Result<Self> {
+        profile_scope!("GraphicsPipeline::new");
+        let multisample = desc.multisample;
+        assert!(
+            multisample.min_sample_shading.is_none()
+                || render_instance.0.supports_sample_rate_shading,
+            "Sample rate shading isn't supported by the device"
+        );
+        assert!(
+            desc.primitive.polygon_mode == PolygonMode::FILL
+                || render_instance.0.supports_fill_mode_non_solid,
+            "Non-solid fill modes aren't supported by the device"
+        );
+        let mesh_pipeline = matches!(desc.vertex_shader.kind, ShaderKind::Mesh);
+        assert!(
+            !mesh_pipeline || render_instance.0.mesh_shader.is_some(),
+            "VK_EXT_mesh_shader isn't supported by the device"
+        );
+        assert!(
+            desc.task_shader.is_none() || mesh_pipeline,
+            "A task shader needs a mesh shader instead of a vertex shader"
+        );
+        let sample_mask = [
+            multisample.sample_mask as u32,
+            (multisample.sample_mask >> 32) as u32,
+        ];
+        let multisample_state_info = vk::PipelineMultisampleStateCreateInfo::default()
+            .rasterization_samples(multisample.samples)
+            .sample_shading_enable(multisample.min_sample_shading.is_some())
+            .min_sample_shading(multisample.min_sample_shading.unwrap_or(0.0))
+            .sample_mask(&sample_mask[..(multisample.samples.as_raw() as usize).div_ceil(32)])
+            .alpha_to_coverage_enable(multisample.alpha_to_coverage);
+
+        let color_blend_attachment_states: Vec<_> = desc
+            .color_formats
+            .iter()
+            .map(|format| {
+                if is_integer_format(*format) {
+                    BlendMode::Replace.attachment_state()
+                } else {
+                    desc.blend.attachment_state()
+                }
+            })
+            .collect();
         let color_blend_state = vk::PipelineColorBlendStateCreateInfo::default()
             .logic_op(vk::LogicOp::CLEAR)
             .attachments(&color_blend_attachment_states);
 
-        let dynamic_state = [
+        let mut dynamic_state = vec![
             vk::DynamicState::VIEWPORT,
             vk::DynamicState::SCISSOR,
             vk::DynamicState::BLEND_CONSTANTS,
             vk::DynamicState::STENCIL_REFERENCE,
         ];
+        if desc.primitive.draws_lines() {
+            dynamic_state.push(vk::DynamicState::LINE_WIDTH);
+        }
+        if desc.primitive.stippled_lines {
+            dynamic_state.push(vk::DynamicState::LINE_STIPPLE_EXT);
+        }
         let dynamic_state_info =
             vk::PipelineDynamicStateCreateInfo::default().dynamic_states(&dynamic_state);
 
         let (descriptor_set_layouts, set_layout_info) = desc
             .fragment_shader
-            .create_descriptor_set_layouts(render_instance);
+            .create_descriptor_set_layouts(render_instance)?;
         let pipeline_layout = unsafe {
             render_instance
                 .device()
@@ -353,8 +557,7 @@ impl GraphicsPipeline {
                                 .map_or(&[], |range| std::slice::from_ref(range)),
                         ),
                     None,
-                )
-                .unwrap()
+                )?
         };
 
         let mut rasterization = vk::PipelineRasterizationStateCreateInfo::default()
@@ -374,6 +577,19 @@ impl GraphicsPipeline {
             rasterization = rasterization.push_next(&mut rasterization_conservative_state);
         }
 
+        let mut line_state = vk::PipelineRasterizationLineStateCreateInfoEXT::default()
+            .line_rasterization_mode(desc.primitive.line_mode.to_vk())
+            .stippled_line_enable(desc.primitive.stippled_lines)
+            .line_stipple_factor(1)
+            .line_stipple_pattern(u16::MAX);
+        if desc.primitive.line_mode != LineMode::Default || desc.primitive.stippled_lines {
+            assert!(
+                render_instance.0.line_rasterization.is_some(),
+                "VK_EXT_line_rasterization isn't supported by the device"
+            );
+            rasterization = rasterization.push_next(&mut line_state);
+        }
+
         let mut depth_stencil = vk::PipelineDepthStencilStateCreateInfo::default();
         if let Some(ref ds) = desc.depth_stencil {
             // let vk_format = ds.format;
@@ -408,16 +624,17 @@ impl GraphicsPipeline {
             }
         }
 
-        let shader_stages = [
-            vk::PipelineShaderStageCreateInfo::default()
-                .name(&desc.vertex_shader.entry_point_cstr)
-                .stage(vk::ShaderStageFlags::VERTEX)
-                .module(desc.vertex_shader.module),
-            vk::PipelineShaderStageCreateInfo::default()
-                .name(&desc.fragment_shader.entry_point_cstr)
-                .stage(vk::ShaderStageFlags::FRAGMENT)
-                .module(desc.fragment_shader.module),
-        ];
+        let shader_stages = desc
+            .task_shader
+            .iter()
+            .chain([&desc.vertex_shader, &desc.fragment_shader])
+            .map(|shader| {
+                vk::PipelineShaderStageCreateInfo::default()
+                    .name(&shader.entry_point_cstr)
+                    .stage(shader.kind.to_vk_shader_stage_flag())
+                    .module(shader.module)
+            })
+            .collect::<Vec<_>>();
 
         let input_assembly_state =
             vk::PipelineInputAssemblyStateCreateInfo::default().topology(desc.primitive.topology).primitive_restart_enable(false);
@@ -437,47 +654,316 @@ impl GraphicsPipeline {
             .scissors(scissors)
             .viewports(viewports);
 
-        let color_attachment_formats = [render_instance.0.surface_format.format];
         let mut rendering_info = vk::PipelineRenderingCreateInfo::default()
-            .color_attachment_formats(&color_attachment_formats);
+            .view_mask(desc.view_mask)
+            .color_attachment_formats(desc.color_formats);
+        if let Some(ref ds) = desc.depth_stencil {
+            rendering_info = rendering_info.depth_attachment_format(ds.format);
+        }
 
-        let graphic_pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+        let mut shading_rate_state = vk::PipelineFragmentShadingRateStateCreateInfoKHR::default()
+            .fragment_size(vk::Extent2D {
+                width: 1,
+                height: 1,
+            })
+            // the rate of the shading rate image replaces the one of the pipeline
+            .combiner_ops([
+                vk::FragmentShadingRateCombinerOpKHR::KEEP,
+                vk::FragmentShadingRateCombinerOpKHR::REPLACE,
+            ]);
+
+        let mut graphic_pipeline_info = vk::GraphicsPipelineCreateInfo::default()
             .stages(&shader_stages)
-            .vertex_input_state(&desc.vertex_input)
-            .input_assembly_state(&input_assembly_state)
             .viewport_state(&viewport_state)
             .rasterization_state(&rasterization)
             .depth_stencil_state(&depth_stencil)
             .dynamic_state(&dynamic_state_info)
             .layout(pipeline_layout)
-            // todo
             .multisample_state(&multisample_state_info)
             .color_blend_state(&color_blend_state)
             .push_next(&mut rendering_info);
+        // mesh shaders fetch their own vertices
+        if !mesh_pipeline {
+            graphic_pipeline_info = graphic_pipeline_info
+                .vertex_input_state(&desc.vertex_input)
+                .input_assembly_state(&input_assembly_state);
+        }
+        if desc.shading_rate_image {
+            assert!(
+                render_instance.0.shading_rate_texel_size.is_some(),
+                "Shading rate images aren't supported by the device"
+            );
+            graphic_pipeline_info = graphic_pipeline_info
+                .flags(vk::PipelineCreateFlags::RENDERING_FRAGMENT_SHADING_RATE_ATTACHMENT_KHR)
+                .push_next(&mut shading_rate_state);
+        }
 
         let pipeline = unsafe {
             render_instance
                 .device()
                 .create_graphics_pipelines(
-                    vk::PipelineCache::null(),
+                    render_instance.0.pipeline_cache,
                     &[graphic_pipeline_info],
                     None,
                 )
-                .unwrap()[0]
+                .map_err(|(_, err)| err)?[0]
         };
 
-        let descriptor_sets = desc.fragment_shader.create_descriptor_sets(
+        let (descriptor_pool, descriptor_sets) =
+            if set_layout_info.iter().all(|bindings| bindings.is_empty()) {
+                (vk::DescriptorPool::null(), vec![])
+            } else {
+                desc.fragment_shader.create_descriptor_sets(
+                    render_instance,
+                    &descriptor_set_layouts,
+                    &set_layout_info,
+                )?
+            };
+
+        Ok(Self {
+            pipeline,
+            layout: pipeline_layout,
+            descriptor_set_layouts,
+            set_layout_info,
+            reflected_layouts: std::mem::take(
+                &mut desc.fragment_shader.spirv_descripor_set_layouts,
+            ),
+            descriptor_pool,
+            descriptor_sets,
+            view_mask: desc.view_mask,
+            shading_rate_image: desc.shading_rate_image,
+            primitive: desc.primitive,
+            push_constants: desc.push_constant_range.map(|range| {
+                let members = if desc.vertex_shader.push_constant_members.is_empty() {
+                    std::mem::take(&mut desc.fragment_shader.push_constant_members)
+                } else {
+                    std::mem::take(&mut desc.vertex_shader.push_constant_members)
+                };
+                PushConstantLayout::new(range, members)
+            }),
+        })
+    }
+
+    /// Checks `write` against the reflected layout of the set it targets, which has to be one of
+    /// the sets of the pipeline.
+    pub fn validate_descriptor_write(&self, write: &vk::WriteDescriptorSet) -> Result<()> {
+        validate_pipeline_write(&self.reflected_layouts, &self.descriptor_sets, write)
+    }
+
+    /// The reflected sets of the fragment shader and the push constant range as JSON, like
+    /// [`Shader::reflection_json`].
+    pub fn reflection_json(&self) -> String {
+        shaders::reflection_json(
+            &self.reflected_layouts,
+            self.push_constants.as_ref().map(|layout| layout.range),
+        )
+    }
+
+    /// Fresh sets for a one-off draw, see [`allocate_transient_descriptor_sets`].
+    pub fn transient_descriptor_sets(
+        &self,
+        render_instance: &RenderInstance,
+    ) -> Result<Vec<vk::DescriptorSet>> {
+        allocate_transient_descriptor_sets(
             render_instance,
-            &descriptor_set_layouts,
-            &set_layout_info,
-        );
+            &self.descriptor_set_layouts,
+            &self.set_layout_info,
+        )
+    }
+}
 
-        Self {
+pub struct ComputePipelineDescriptor {
+    pub shader: Shader,
+    pub push_constant_range: Option<vk::PushConstantRange>,
+}
+
+#[derive(Debug)]
+pub struct ComputePipeline {
+    pub pipeline: vk::Pipeline,
+    pub layout: vk::PipelineLayout,
+    /// Shared pool the sets were allocated from, null when the shader has no bindings.
+    pub descriptor_pool: vk::DescriptorPool,
+    pub descriptor_sets: Vec<vk::DescriptorSet>,
+    pub descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    pub set_layout_info: Vec<HashMap<u32, DescriptorType>>,
+    pub reflected_layouts: StageDescriptorSetLayouts,
+    pub workgroup_size: (u32, u32, u32),
+    pub push_constants: Option<PushConstantLayout>,
+}
+
+impl ComputePipeline {
+    pub fn new(
+        render_instance: &RenderInstance,
+        mut desc: ComputePipelineDescriptor,
+    ) -> Result<Self> {
+        profile_scope!("ComputePipeline::new");
+        let (descriptor_set_layouts, set_layout_info) =
+            desc.shader.create_descriptor_set_layouts(render_instance)?;
+
+        let pipeline_layout = unsafe {
+            render_instance
+                .device()
+                .create_pipeline_layout(
+                    &vk::PipelineLayoutCreateInfo::default()
+                        .set_layouts(&descriptor_set_layouts)
+                        .push_constant_ranges(
+                            desc.push_constant_range
+                                .as_ref()
+                                .map_or(&[], |range| std::slice::from_ref(range)),
+                        ),
+                    None,
+                )?
+        };
+
+        let stage = vk::PipelineShaderStageCreateInfo::default()
+            .name(&desc.shader.entry_point_cstr)
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(desc.shader.module);
+
+        let pipeline = unsafe {
+            render_instance
+                .device()
+                .create_compute_pipelines(
+                    render_instance.0.pipeline_cache,
+                    &[vk::ComputePipelineCreateInfo::default()
+                        .stage(stage)
+                        .layout(pipeline_layout)],
+                    None,
+                )
+                .map_err(|(_, err)| err)?[0]
+        };
+
+        // a pool without any pool sizes is invalid, so shaders without bindings get no sets
+        let (descriptor_pool, descriptor_sets) =
+            if set_layout_info.iter().all(|bindings| bindings.is_empty()) {
+                (vk::DescriptorPool::null(), vec![])
+            } else {
+                desc.shader.create_descriptor_sets(
+                    render_instance,
+                    &descriptor_set_layouts,
+                    &set_layout_info,
+                )?
+            };
+
+        Ok(Self {
             pipeline,
             layout: pipeline_layout,
             descriptor_set_layouts,
             set_layout_info,
+            reflected_layouts: std::mem::take(&mut desc.shader.spirv_descripor_set_layouts),
+            descriptor_pool,
             descriptor_sets,
-        }
+            workgroup_size: desc
+                .shader
+                .workgroup_size
+                .expect("Compute shader is missing a local_size declaration"),
+            push_constants: desc.push_constant_range.map(|range| {
+                PushConstantLayout::new(
+                    range,
+                    std::mem::take(&mut desc.shader.push_constant_members),
+                )
+            }),
+        })
+    }
+
+    /// Checks `write` against the reflected layout of the set it targets, which has to be one of
+    /// the sets of the pipeline.
+    pub fn validate_descriptor_write(&self, write: &vk::WriteDescriptorSet) -> Result<()> {
+        validate_pipeline_write(&self.reflected_layouts, &self.descriptor_sets, write)
+    }
+
+    /// The reflected sets and push constant range as JSON, like [`Shader::reflection_json`].
+    pub fn reflection_json(&self) -> String {
+        shaders::reflection_json(
+            &self.reflected_layouts,
+            self.push_constants.as_ref().map(|layout| layout.range),
+        )
+    }
+
+    /// Fresh sets for a one-off draw, see [`allocate_transient_descriptor_sets`].
+    pub fn transient_descriptor_sets(
+        &self,
+        render_instance: &RenderInstance,
+    ) -> Result<Vec<vk::DescriptorSet>> {
+        allocate_transient_descriptor_sets(
+            render_instance,
+            &self.descriptor_set_layouts,
+            &self.set_layout_info,
+        )
+    }
+}
+
+fn validate_pipeline_write(
+    layouts: &StageDescriptorSetLayouts,
+    descriptor_sets: &[vk::DescriptorSet],
+    write: &vk::WriteDescriptorSet,
+) -> Result<()> {
+    let Some(set) = descriptor_sets.iter().position(|set| *set == write.dst_set) else {
+        return Err(Error::ForeignDescriptorSet(write.dst_set));
+    };
+    shaders::validate_descriptor_write(layouts, set as u32, write)
+}
+
+/// Allocates a set for every layout from the transient pools of the frame, which are reset as a
+/// whole once the frame is no longer in flight. The sets are only valid until the frame ends and
+/// are never freed, so they suit descriptors that change every draw.
+pub fn allocate_transient_descriptor_sets(
+    render_instance: &RenderInstance,
+    set_layouts: &[vk::DescriptorSetLayout],
+    set_layout_info: &[HashMap<u32, DescriptorType>],
+) -> Result<Vec<vk::DescriptorSet>> {
+    if set_layout_info.iter().all(|bindings| bindings.is_empty()) {
+        return Ok(vec![]);
+    }
+    render_instance
+        .0
+        .descriptor_allocator
+        .lock()
+        .unwrap()
+        .allocate_transient(
+            render_instance.device(),
+            set_layouts,
+            &shaders::descriptor_pool_sizes(render_instance, set_layout_info),
+        )
+}
+
+/// Queues the pipeline, its layout and descriptor objects for destruction.
+fn release_pipeline(
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    set_layouts: &mut Vec<vk::DescriptorSetLayout>,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_sets: &mut Vec<vk::DescriptorSet>,
+) {
+    deferred_destroy::release(DeferredResource::Pipeline {
+        pipeline,
+        layout,
+        set_layouts: std::mem::take(set_layouts),
+        descriptor_pool,
+        descriptor_sets: std::mem::take(descriptor_sets),
+    });
+}
+
+impl Drop for GraphicsPipeline {
+    fn drop(&mut self) {
+        release_pipeline(
+            self.pipeline,
+            self.layout,
+            &mut self.descriptor_set_layouts,
+            self.descriptor_pool,
+            &mut self.descriptor_sets,
+        );
+    }
+}
+
+impl Drop for ComputePipeline {
+    fn drop(&mut self) {
+        release_pipeline(
+            self.pipeline,
+            self.layout,
+            &mut self.descriptor_set_layouts,
+            self.descriptor_pool,
+            &mut self.descriptor_sets,
+        );
     }
 }
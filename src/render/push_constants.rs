@@ -0,0 +1,287 @@
+use std::{any::TypeId, collections::HashSet, sync::Mutex};
+
+use ash::vk;
+
+use crate::error::{Error, Result};
+
+use super::{
+    pipeline::{ComputePipeline, GraphicsPipeline},
+    shaders::PushConstantMember,
+};
+
+/// A field of a [`PushConstants`] struct.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PushConstantField {
+    pub name: &'static str,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// A struct that is pushed as the push constant block of a shader, with its fields in the
+/// order of the block. Implement it with [`push_constants!`](crate::push_constants).
+pub trait PushConstants: bytemuck::Pod {
+    fn fields() -> Vec<PushConstantField>;
+}
+
+/// Implements [`PushConstants`] for a struct by listing its fields in declaration order.
+///
+/// ```ignore
+/// push_constants!(DrawPushConstants { model, material_pointer, camera_pointer });
+/// ```
+#[macro_export]
+macro_rules! push_constants {
+    ($ty:path { $($field:ident),* $(,)? }) => {
+        impl $crate::render::push_constants::PushConstants for $ty {
+            fn fields() -> Vec<$crate::render::push_constants::PushConstantField> {
+                use $crate::render::push_constants::{field_size, PushConstantField};
+                let zeroed = <$ty as bytemuck::Zeroable>::zeroed();
+                vec![$(PushConstantField {
+                    name: stringify!($field),
+                    offset: bytemuck::offset_of!(zeroed, $ty, $field),
+                    size: field_size(|value: &$ty| &value.$field),
+                }),*]
+            }
+        }
+    };
+}
+
+/// Size of the field `field` returns, used by [`push_constants!`].
+pub fn field_size<T, F>(_field: impl Fn(&T) -> &F) -> usize {
+    std::mem::size_of::<F>()
+}
+
+/// The push constant range of a pipeline together with the block the shaders declare, so the
+/// structs pushed to it can be checked.
+#[derive(Debug)]
+pub struct PushConstantLayout {
+    pub range: vk::PushConstantRange,
+    pub members: Vec<PushConstantMember>,
+    /// Types that passed [`Self::verify`], so they're only checked once.
+    verified: Mutex<HashSet<TypeId>>,
+}
+
+impl PushConstantLayout {
+    pub fn new(range: vk::PushConstantRange, members: Vec<PushConstantMember>) -> Self {
+        Self {
+            range,
+            members,
+            verified: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Checks that every member of the block has a field at the same offset into the range,
+    /// which fits before the next member, and that `T` fits in the range.
+    pub fn verify<T: PushConstants>(&self) -> Result<()> {
+        if self.verified.lock().unwrap().contains(&TypeId::of::<T>()) {
+            return Ok(());
+        }
+        check_fields(
+            &T::fields(),
+            std::mem::size_of::<T>(),
+            &self.members,
+            self.range,
+        )
+        .map_err(|message| Error::PushConstantLayout {
+            type_name: std::any::type_name::<T>(),
+            message,
+        })?;
+        self.verified.lock().unwrap().insert(TypeId::of::<T>());
+        Ok(())
+    }
+}
+
+/// `T` is pushed at the start of `range`, while the offsets of the members are relative to the
+/// start of the block.
+fn check_fields(
+    fields: &[PushConstantField],
+    size: usize,
+    members: &[PushConstantMember],
+    range: vk::PushConstantRange,
+) -> std::result::Result<(), String> {
+    if fields.len() != members.len() {
+        return Err(format!(
+            "it has {} fields, the shader block has {} members",
+            fields.len(),
+            members.len()
+        ));
+    }
+    let range_end = range.offset + range.size;
+    for (index, (field, member)) in fields.iter().zip(members).enumerate() {
+        let Some(offset) = member.offset.checked_sub(range.offset) else {
+            return Err(format!(
+                "member {} is at offset {}, before the range at offset {}",
+                member.name, member.offset, range.offset
+            ));
+        };
+        if field.offset != offset as usize {
+            return Err(format!(
+                "field {} is at offset {}, but member {} is at offset {} of the range",
+                field.name, field.offset, member.name, offset
+            ));
+        }
+        // the space up to the next member, padding included
+        let member_end = members.get(index + 1).map_or(range_end, |next| next.offset);
+        let member_size = member_end.saturating_sub(member.offset) as usize;
+        if field.size > member_size {
+            return Err(format!(
+                "field {} is {} bytes, but member {} only has room for {}",
+                field.name, field.size, member.name, member_size
+            ));
+        }
+    }
+    if size > range.size as usize {
+        return Err(format!(
+            "it's {} bytes, the push constant range is {} bytes",
+            size, range.size
+        ));
+    }
+    Ok(())
+}
+
+/// A pipeline that push constants can be set for.
+pub trait PushConstantProgram {
+    fn layout(&self) -> vk::PipelineLayout;
+
+    fn push_constants(&self) -> Option<&PushConstantLayout>;
+}
+
+impl PushConstantProgram for GraphicsPipeline {
+    fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    fn push_constants(&self) -> Option<&PushConstantLayout> {
+        self.push_constants.as_ref()
+    }
+}
+
+impl PushConstantProgram for ComputePipeline {
+    fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    fn push_constants(&self) -> Option<&PushConstantLayout> {
+        self.push_constants.as_ref()
+    }
+}
+
+/// Checks `T` against the push constant block of the shaders of `program`, see
+/// [`PushConstantLayout::verify`].
+pub fn verify_push_constants<T: PushConstants>(
+    program: &impl PushConstantProgram,
+) -> Result<&PushConstantLayout> {
+    let Some(layout) = program.push_constants() else {
+        return Err(Error::PushConstantLayout {
+            type_name: std::any::type_name::<T>(),
+            message: "the pipeline has no push constant range".to_string(),
+        });
+    };
+    layout.verify::<T>()?;
+    Ok(layout)
+}
+
+/// Pushes `data` for `program`, nothing is pushed when `T` doesn't match the push constant
+/// block of its shaders.
+pub fn set_push_constants<T: PushConstants>(
+    device: &ash::Device,
+    command_buffer: vk::CommandBuffer,
+    program: &impl PushConstantProgram,
+    data: &T,
+) -> Result<()> {
+    let layout = verify_push_constants::<T>(program)?;
+    unsafe {
+        device.cmd_push_constants(
+            command_buffer,
+            program.layout(),
+            layout.range.stage_flags,
+            layout.range.offset,
+            bytemuck::bytes_of(data),
+        )
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+    use bevy::prelude::Mat4;
+
+    use super::{check_fields, PushConstantField, PushConstants};
+    use crate::render::shaders::{reflect_push_constant_members, Shader, ShaderKind};
+
+    #[repr(C, align(16))]
+    #[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+    struct DrawPushConstants {
+        model: Mat4,
+        material_pointer: u64,
+        camera_pointer: u64,
+    }
+    push_constants!(DrawPushConstants {
+        model,
+        material_pointer,
+        camera_pointer
+    });
+
+    #[test]
+    fn test_verify_push_constants() {
+        let spirv = Shader::compile("./shader/main.vert", ShaderKind::Vertex, "main", &[]).unwrap();
+        let members = reflect_push_constant_members(spirv.as_binary());
+        let offsets = members
+            .iter()
+            .map(|member| (member.name.as_str(), member.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, [("model", 0), ("material", 64), ("camera", 72)]);
+
+        let fields = DrawPushConstants::fields();
+        assert_eq!(fields[1].offset, 64);
+        assert_eq!(fields[2].size, 8);
+        let range = |offset, size| vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::ALL,
+            offset,
+            size,
+        };
+        assert!(check_fields(&fields, 80, &members, range(0, 80)).is_ok());
+        let packed = [
+            fields[0],
+            PushConstantField {
+                name: "flags",
+                offset: 64,
+                size: 4,
+            },
+            PushConstantField {
+                name: "material_pointer",
+                offset: 68,
+                size: 8,
+            },
+        ];
+        assert!(check_fields(&packed, 80, &members, range(0, 80)).is_err());
+        assert!(check_fields(&fields[..2], 80, &members, range(0, 80)).is_err());
+        assert!(check_fields(&fields, 88, &members, range(0, 80)).is_err());
+        let wide = [
+            fields[0],
+            fields[1],
+            PushConstantField {
+                name: "camera_pointer",
+                offset: 72,
+                size: 16,
+            },
+        ];
+        assert!(check_fields(&wide, 80, &members, range(0, 80)).is_err());
+
+        // a range that skips the model matrix, which another stage pushes
+        let tail = [
+            PushConstantField {
+                name: "material_pointer",
+                offset: 0,
+                size: 8,
+            },
+            PushConstantField {
+                name: "camera_pointer",
+                offset: 8,
+                size: 8,
+            },
+        ];
+        assert!(check_fields(&tail, 16, &members[1..], range(64, 16)).is_ok());
+        assert!(check_fields(&fields[1..], 16, &members[1..], range(64, 16)).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use ash::vk;
+
+use crate::error::Result;
+
+use super::RenderInstance;
+
+/// What the queries of a [`QueryPoolRing`] measure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryKind {
+    Timestamp,
+    /// `precise` counts the samples that passed, otherwise the result is only zero or not.
+    Occlusion {
+        precise: bool,
+    },
+    /// One value per set flag, in the order of the bits.
+    PipelineStatistics(vk::QueryPipelineStatisticFlags),
+}
+
+impl QueryKind {
+    fn query_type(self) -> vk::QueryType {
+        match self {
+            QueryKind::Timestamp => vk::QueryType::TIMESTAMP,
+            QueryKind::Occlusion { .. } => vk::QueryType::OCCLUSION,
+            QueryKind::PipelineStatistics(_) => vk::QueryType::PIPELINE_STATISTICS,
+        }
+    }
+
+    fn values_per_query(self) -> usize {
+        match self {
+            QueryKind::PipelineStatistics(flags) => flags.as_raw().count_ones() as usize,
+            _ => 1,
+        }
+    }
+}
+
+/// A query pool per frame in flight, so queries can be written every frame without waiting on
+/// the results. The results of a frame are read back when the ring wraps around to its pool
+/// again, `frame_count` frames later, and stay available until the next read.
+#[derive(Debug)]
+pub struct QueryPoolRing {
+    kind: QueryKind,
+    pools: Vec<vk::QueryPool>,
+    capacity: u32,
+    frame: usize,
+    /// Queries written into each pool since it was last reset.
+    used: Vec<u32>,
+    results: Vec<u64>,
+    available: Vec<bool>,
+    timestamp_period: f32,
+}
+
+impl QueryPoolRing {
+    /// `capacity` is the amount of queries a single frame can write.
+    pub fn new(
+        render_instance: &RenderInstance,
+        kind: QueryKind,
+        capacity: u32,
+        frame_count: u32,
+    ) -> Result<Self> {
+        let renderer = render_instance.0.as_ref();
+        let device = render_instance.device();
+        let pools = (0..frame_count)
+            .map(|_| {
+                let mut create_info = vk::QueryPoolCreateInfo::default()
+                    .query_type(kind.query_type())
+                    .query_count(capacity);
+                if let QueryKind::PipelineStatistics(flags) = kind {
+                    create_info = create_info.pipeline_statistics(flags);
+                }
+                unsafe { device.create_query_pool(&create_info, None) }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let timestamp_period = unsafe {
+            renderer
+                .instance
+                .get_physical_device_properties(renderer.pdevice)
+                .limits
+                .timestamp_period
+        };
+
+        Ok(Self {
+            kind,
+            pools,
+            capacity,
+            frame: 0,
+            used: vec![0; frame_count as usize],
+            results: Vec::new(),
+            available: Vec::new(),
+            timestamp_period,
+        })
+    }
+
+    pub fn kind(&self) -> QueryKind {
+        self.kind
+    }
+
+    /// Moves on to the pool of the next frame, reading back what was written into it
+    /// `frame_count` frames ago and resetting it. Record it outside a render pass, before the
+    /// first query of the frame.
+    pub fn begin_frame(&mut self, device: &ash::Device, command_buffer: vk::CommandBuffer) {
+        self.frame = (self.frame + 1) % self.pools.len();
+        let pool = self.pools[self.frame];
+        let count = std::mem::take(&mut self.used[self.frame]);
+
+        self.results.clear();
+        self.available.clear();
+        if count > 0 {
+            // every query is followed by its availability
+            let stride = self.kind.values_per_query() + 1;
+            let mut data = vec![0u64; count as usize * stride];
+            let result = unsafe {
+                (device.fp_v1_0().get_query_pool_results)(
+                    device.handle(),
+                    pool,
+                    0,
+                    count,
+                    std::mem::size_of_val(data.as_slice()),
+                    data.as_mut_ptr().cast(),
+                    (stride * std::mem::size_of::<u64>()) as u64,
+                    vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY,
+                )
+            };
+            match result {
+                vk::Result::SUCCESS | vk::Result::NOT_READY => {
+                    (self.results, self.available) = split_availability(&data, stride);
+                }
+                e => println!("Reading {:?} queries failed: {}", self.kind, e),
+            }
+        }
+
+        unsafe { device.cmd_reset_query_pool(command_buffer, pool, 0, self.capacity) };
+    }
+
+    /// Writes a timestamp once the commands before it have passed `stage`. Nothing is written
+    /// when all queries of the frame are used.
+    pub fn write_timestamp(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        stage: vk::PipelineStageFlags,
+    ) -> Option<u32> {
+        assert_eq!(
+            self.kind,
+            QueryKind::Timestamp,
+            "Not a timestamp query pool"
+        );
+        let query = self.next_query()?;
+        unsafe { device.cmd_write_timestamp(command_buffer, stage, self.pools[self.frame], query) };
+        Some(query)
+    }
+
+    /// Starts an occlusion or pipeline statistics query, end it with [`Self::end_query`] in the
+    /// same command buffer. Nothing is started when all queries of the frame are used.
+    pub fn begin_query(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+    ) -> Option<u32> {
+        let flags = match self.kind {
+            QueryKind::Timestamp => panic!("Timestamps are written, not begun"),
+            QueryKind::Occlusion { precise: true } => vk::QueryControlFlags::PRECISE,
+            _ => vk::QueryControlFlags::empty(),
+        };
+        let query = self.next_query()?;
+        unsafe { device.cmd_begin_query(command_buffer, self.pools[self.frame], query, flags) };
+        Some(query)
+    }
+
+    pub fn end_query(&self, device: &ash::Device, command_buffer: vk::CommandBuffer, query: u32) {
+        unsafe { device.cmd_end_query(command_buffer, self.pools[self.frame], query) };
+    }
+
+    /// Values of a query from the frame that was read back by the last [`Self::begin_frame`],
+    /// `None` when the GPU hadn't written it yet.
+    pub fn result(&self, query: u32) -> Option<&[u64]> {
+        let values = self.kind.values_per_query();
+        let start = query as usize * values;
+        if *self.available.get(query as usize)? {
+            Some(&self.results[start..start + values])
+        } else {
+            None
+        }
+    }
+
+    /// A timestamp of the frame that was read back in nanoseconds, comparable to the other
+    /// timestamps of the device.
+    pub fn timestamp_nanos(&self, query: u32) -> Option<u64> {
+        let ticks = self.result(query)?[0];
+        Some((ticks as f64 * self.timestamp_period as f64) as u64)
+    }
+
+    /// Time between two timestamps of the frame that was read back.
+    pub fn elapsed(&self, start: u32, end: u32) -> Option<Duration> {
+        let ticks = self.result(end)?[0].saturating_sub(self.result(start)?[0]);
+        Some(Duration::from_nanos(
+            (ticks as f64 * self.timestamp_period as f64) as u64,
+        ))
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device) {
+        for pool in self.pools.drain(..) {
+            unsafe { device.destroy_query_pool(pool, None) };
+        }
+    }
+
+    fn next_query(&mut self) -> Option<u32> {
+        let query = self.used[self.frame];
+        if query >= self.capacity {
+            return None;
+        }
+        self.used[self.frame] += 1;
+        Some(query)
+    }
+}
+
+/// Splits results written `WITH_AVAILABILITY` into the values and whether each query was
+/// available.
+fn split_availability(data: &[u64], stride: usize) -> (Vec<u64>, Vec<bool>) {
+    let mut values = Vec::with_capacity(data.len() / stride * (stride - 1));
+    let mut available = Vec::with_capacity(data.len() / stride);
+    for query in data.chunks_exact(stride) {
+        values.extend_from_slice(&query[..stride - 1]);
+        available.push(query[stride - 1] != 0);
+    }
+    (values, available)
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::{split_availability, QueryKind, QueryPoolRing};
+
+    #[test]
+    fn test_split_availability() {
+        let data = [10, 20, 1, 0, 0, 0, 30, 40, 1];
+        let (values, available) = split_availability(&data, 3);
+        assert_eq!(values, [10, 20, 0, 0, 30, 40]);
+        assert_eq!(available, [true, false, true]);
+    }
+
+    #[test]
+    fn test_queries_run_out_without_panicking() {
+        let mut ring = QueryPoolRing {
+            kind: QueryKind::Timestamp,
+            pools: vec![vk::QueryPool::null()],
+            capacity: 2,
+            frame: 0,
+            used: vec![0],
+            results: vec![],
+            available: vec![],
+            timestamp_period: 1.0,
+        };
+        assert_eq!(ring.next_query(), Some(0));
+        assert_eq!(ring.next_query(), Some(1));
+        assert_eq!(ring.next_query(), None);
+    }
+}
@@ -0,0 +1,201 @@
+use ash::vk;
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{
+    barrier::{self, BarrierBatch, Usage},
+    deferred_destroy::DESTROY_DELAY_FRAMES,
+    RenderAllocator, RenderInstance,
+};
+
+/// Offsets of copies into a [`ReadbackRing`] are aligned to this, which covers the texel size
+/// of every uncompressed format.
+const READBACK_ALIGNMENT: u64 = 16;
+
+/// A copy recorded into a [`ReadbackRing`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadbackTicket {
+    frame: u64,
+    offset: u64,
+    size: u64,
+}
+
+/// What [`ReadbackRing::read`] found for a ticket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Readback {
+    /// The frame the copy was recorded in is still in flight.
+    Pending,
+    Ready(Vec<u8>),
+    /// The region was already reused for the copies of a later frame.
+    Expired,
+}
+
+/// A host visible buffer split into one region per frame in flight, for small copies from the
+/// GPU like the texel under the cursor. The GPU to CPU counterpart of
+/// [`RingBuffer`](super::ring::RingBuffer): a copy can be read [`DESTROY_DELAY_FRAMES`] frames
+/// after it was recorded, before the ring wraps around to its region again.
+#[derive(Debug)]
+pub struct ReadbackRing {
+    buffer: Buffer,
+    frame_size: u64,
+    frame_count: u32,
+    frame: u64,
+    head: u64,
+}
+
+impl ReadbackRing {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        frame_size: u64,
+    ) -> Result<Self> {
+        let frame_size = frame_size.next_multiple_of(READBACK_ALIGNMENT);
+        let frame_count = DESTROY_DELAY_FRAMES as u32 + 1;
+        let buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(frame_size * frame_count as u64)
+                .usage(vk::BufferUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::GpuToCpu,
+        )?;
+        buffer.set_name("readback ring");
+
+        Ok(Self {
+            buffer,
+            frame_size,
+            frame_count,
+            frame: 0,
+            head: 0,
+        })
+    }
+
+    /// Moves on to the region of the next frame, copies recorded `frame_count` frames ago
+    /// expire.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+        self.head = 0;
+    }
+
+    /// Records a copy of the texel at `x`, `y` of the first mip and layer of `image`, or only
+    /// its depth for depth/stencil formats. The image needs `TRANSFER_SRC` usage and is in the
+    /// layout of `usage`, which it's left in. Returns `None` when the region of this frame is
+    /// full.
+    pub fn copy_texel(
+        &mut self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        image: vk::Image,
+        format: vk::Format,
+        usage: Usage,
+        x: u32,
+        y: u32,
+    ) -> Option<ReadbackTicket> {
+        let size = texel_size(format);
+        if self.head + size > self.frame_size {
+            return None;
+        }
+        let offset = (self.frame % self.frame_count as u64) * self.frame_size + self.head;
+        self.head = (self.head + size).next_multiple_of(READBACK_ALIGNMENT);
+
+        let renderer = render_instance.0.as_ref();
+        let aspect_mask = barrier::aspect_mask_from_format(format);
+        let copy_aspect = if aspect_mask.contains(vk::ImageAspectFlags::DEPTH) {
+            vk::ImageAspectFlags::DEPTH
+        } else {
+            vk::ImageAspectFlags::COLOR
+        };
+
+        BarrierBatch::new()
+            .image(image, aspect_mask, usage, Usage::TransferRead)
+            .flush(&renderer.synchronization2, command_buffer);
+        unsafe {
+            renderer.device.cmd_copy_image_to_buffer(
+                command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                self.buffer.buffer,
+                &[vk::BufferImageCopy::default()
+                    .buffer_offset(offset)
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: copy_aspect,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_offset(vk::Offset3D {
+                        x: x as i32,
+                        y: y as i32,
+                        z: 0,
+                    })
+                    .image_extent(vk::Extent3D {
+                        width: 1,
+                        height: 1,
+                        depth: 1,
+                    })],
+            );
+        }
+        // the copy has to be visible to the host once the frame's fence is signaled
+        BarrierBatch::new()
+            .image(image, aspect_mask, Usage::TransferRead, usage)
+            .push_buffer(
+                barrier::buffer_barrier(
+                    self.buffer.buffer,
+                    Usage::TransferWrite,
+                    Usage::TransferWrite,
+                )
+                .dst_stage_mask(vk::PipelineStageFlags2::HOST)
+                .dst_access_mask(vk::AccessFlags2::HOST_READ),
+            )
+            .flush(&renderer.synchronization2, command_buffer);
+
+        Some(ReadbackTicket {
+            frame: self.frame,
+            offset,
+            size,
+        })
+    }
+
+    pub fn read(&mut self, render_instance: &RenderInstance, ticket: ReadbackTicket) -> Readback {
+        if self.frame < ticket.frame + DESTROY_DELAY_FRAMES {
+            return Readback::Pending;
+        }
+        if self.frame >= ticket.frame + self.frame_count as u64 {
+            return Readback::Expired;
+        }
+        let bytes = self.buffer.map::<u8>(render_instance.device());
+        Readback::Ready(
+            bytes[ticket.offset as usize..(ticket.offset + ticket.size) as usize].to_vec(),
+        )
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.buffer
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+    }
+}
+
+/// Bytes copied out of a texel of `format`, only the depth of depth/stencil formats.
+fn texel_size(format: vk::Format) -> u64 {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::D16_UNORM_S8_UINT => 2,
+        vk::Format::R32_UINT
+        | vk::Format::R32_SFLOAT
+        | vk::Format::R8G8B8A8_UNORM
+        | vk::Format::R8G8B8A8_SRGB
+        | vk::Format::B8G8R8A8_UNORM
+        | vk::Format::B8G8R8A8_SRGB
+        | vk::Format::D32_SFLOAT
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::X8_D24_UNORM_PACK32
+        | vk::Format::D32_SFLOAT_S8_UINT => 4,
+        vk::Format::R32G32_UINT | vk::Format::R16G16B16A16_SFLOAT => 8,
+        vk::Format::R32G32B32A32_SFLOAT | vk::Format::R32G32B32A32_UINT => 16,
+        _ => panic!("Reading back {:?} texels isn't supported", format),
+    }
+}
@@ -0,0 +1,249 @@
+use std::{fmt, hash::Hash, marker::PhantomData};
+
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::{Buffer, Image};
+
+/// Generational index into a [`ResourcePool`]. Handles stay valid when a resource is replaced,
+/// and stop resolving once it's removed, even if its slot gets reused.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct ResourceHandle<T> {
+    index: u32,
+    generation: u32,
+    #[serde(skip)]
+    _marker: PhantomData<fn() -> T>,
+}
+
+pub type BufferHandle = ResourceHandle<Buffer>;
+pub type ImageHandle = ResourceHandle<Image>;
+
+impl<T> ResourceHandle<T> {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+// derives would require `T` to implement these as well
+impl<T> Clone for ResourceHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ResourceHandle<T> {}
+
+impl<T> PartialEq for ResourceHandle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for ResourceHandle<T> {}
+
+impl<T> Hash for ResourceHandle<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for ResourceHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ResourceHandle({}v{})", self.index, self.generation)
+    }
+}
+
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+pub struct ResourcePool<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+impl<T> Default for ResourcePool<T> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl<T> ResourcePool<T> {
+    pub fn insert(&mut self, value: T) -> ResourceHandle<T> {
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize].value = Some(value);
+                index
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                });
+                self.slots.len() as u32 - 1
+            }
+        };
+        ResourceHandle {
+            index,
+            generation: self.slots[index as usize].generation,
+            _marker: PhantomData,
+        }
+    }
+
+    fn slot(&self, handle: ResourceHandle<T>) -> Option<&Slot<T>> {
+        self.slots
+            .get(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+    }
+
+    fn slot_mut(&mut self, handle: ResourceHandle<T>) -> Option<&mut Slot<T>> {
+        self.slots
+            .get_mut(handle.index as usize)
+            .filter(|slot| slot.generation == handle.generation)
+    }
+
+    /// `None` once the resource has been removed.
+    pub fn get(&self, handle: ResourceHandle<T>) -> Option<&T> {
+        self.slot(handle)?.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, handle: ResourceHandle<T>) -> Option<&mut T> {
+        self.slot_mut(handle)?.value.as_mut()
+    }
+
+    pub fn contains(&self, handle: ResourceHandle<T>) -> bool {
+        self.get(handle).is_some()
+    }
+
+    /// Swaps in `value` behind an existing handle, like a reloaded texture, and returns the old
+    /// resource. Dropping it defers its destruction, so frames in flight can still use it.
+    pub fn replace(&mut self, handle: ResourceHandle<T>, value: T) -> Option<T> {
+        self.slot_mut(handle)?.value.replace(value)
+    }
+
+    /// Invalidates `handle` and every copy of it.
+    pub fn remove(&mut self, handle: ResourceHandle<T>) -> Option<T> {
+        let slot = self.slot_mut(handle)?;
+        let value = slot.value.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ResourceHandle<T>, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let handle = ResourceHandle {
+                index: index as u32,
+                generation: slot.generation,
+                _marker: PhantomData,
+            };
+            slot.value.as_ref().map(|value| (handle, value))
+        })
+    }
+}
+
+/// Buffers and images owned by the [`RenderInstance`](super::RenderInstance), so they can be
+/// referred to by [`BufferHandle`] and [`ImageHandle`] instead of raw Vulkan handles, like the
+/// bindless textures of the [`GlobalDescriptorSet`](super::global_descriptors::GlobalDescriptorSet)
+/// and the transient resources of the [`RenderGraph`](super::graph::RenderGraph).
+#[derive(Default)]
+pub struct ResourceRegistry {
+    pub buffers: ResourcePool<Buffer>,
+    pub images: ResourcePool<Image>,
+}
+
+impl ResourceRegistry {
+    pub fn insert_buffer(&mut self, buffer: Buffer) -> BufferHandle {
+        self.buffers.insert(buffer)
+    }
+
+    pub fn insert_image(&mut self, image: Image) -> ImageHandle {
+        self.images.insert(image)
+    }
+
+    /// Panics when the buffer has been removed, like indexing a `Vec` out of bounds.
+    pub fn buffer(&self, handle: BufferHandle) -> &Buffer {
+        self.buffers
+            .get(handle)
+            .unwrap_or_else(|| panic!("{:?} doesn't refer to a live buffer", handle))
+    }
+
+    pub fn buffer_mut(&mut self, handle: BufferHandle) -> &mut Buffer {
+        self.buffers
+            .get_mut(handle)
+            .unwrap_or_else(|| panic!("{:?} doesn't refer to a live buffer", handle))
+    }
+
+    /// Panics when the image has been removed, like indexing a `Vec` out of bounds.
+    pub fn image(&self, handle: ImageHandle) -> &Image {
+        self.images
+            .get(handle)
+            .unwrap_or_else(|| panic!("{:?} doesn't refer to a live image", handle))
+    }
+
+    pub fn image_mut(&mut self, handle: ImageHandle) -> &mut Image {
+        self.images
+            .get_mut(handle)
+            .unwrap_or_else(|| panic!("{:?} doesn't refer to a live image", handle))
+    }
+
+    /// Drops the buffer, which queues it for destruction.
+    pub fn remove_buffer(&mut self, handle: BufferHandle) {
+        self.buffers.remove(handle);
+    }
+
+    /// Drops the image, which queues it for destruction.
+    pub fn remove_image(&mut self, handle: ImageHandle) {
+        self.images.remove(handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ResourcePool;
+
+    #[test]
+    fn test_resource_pool_generations() {
+        let mut pool = ResourcePool::default();
+        let a = pool.insert("a");
+        let b = pool.insert("b");
+        assert_eq!(pool.get(a), Some(&"a"));
+
+        assert_eq!(pool.replace(a, "a2"), Some("a"));
+        assert_eq!(pool.get(a), Some(&"a2"));
+
+        assert_eq!(pool.remove(a), Some("a2"));
+        assert_eq!(pool.get(a), None);
+        assert_eq!(pool.remove(a), None);
+
+        // the slot is reused, but the stale handle doesn't resolve to the new value
+        let c = pool.insert("c");
+        assert_eq!(c.index(), a.index());
+        assert_ne!(c, a);
+        assert_eq!(pool.get(a), None);
+        assert_eq!(pool.get(c), Some(&"c"));
+        assert_eq!(pool.get(b), Some(&"b"));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(
+            pool.iter().map(|(_, value)| *value).collect::<Vec<_>>(),
+            ["c", "b"]
+        );
+    }
+}
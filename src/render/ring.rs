@@ -0,0 +1,104 @@
+use ash::vk;
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{RenderAllocator, RenderInstance};
+
+/// Offsets handed out by [`RingBuffer::push`] are aligned to this, which covers the
+/// `minUniformBufferOffsetAlignment` of every device and the alignment of `buffer_reference`
+/// blocks.
+pub const RING_ALIGNMENT: u64 = 256;
+
+/// Where a value pushed into a [`RingBuffer`] ended up, bind it with `offset` or pass the
+/// `device_addr` to a `buffer_reference`.
+#[derive(Clone, Copy, Debug)]
+pub struct RingAllocation {
+    pub buffer: vk::Buffer,
+    pub offset: u64,
+    pub size: u64,
+    pub device_addr: u64,
+}
+
+/// A host visible buffer split into one region per frame, for small per-frame data like
+/// uniforms. Values pushed during a frame stay untouched until the ring wraps around to the
+/// same region again, so `frame_count` has to be larger than the number of frames the GPU can
+/// be behind.
+#[derive(Debug)]
+pub struct RingBuffer {
+    buffer: Buffer,
+    frame_size: u64,
+    frame_count: u32,
+    frame: u32,
+    head: u64,
+}
+
+impl RingBuffer {
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        frame_size: u64,
+        frame_count: u32,
+        usage: vk::BufferUsageFlags,
+    ) -> Result<Self> {
+        let frame_size = frame_size.next_multiple_of(RING_ALIGNMENT);
+        let buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(frame_size * frame_count as u64)
+                .usage(usage)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+
+        Ok(Self {
+            buffer,
+            frame_size,
+            frame_count,
+            frame: 0,
+            head: 0,
+        })
+    }
+
+    /// Moves on to the region of the next frame, overwriting what was pushed `frame_count`
+    /// frames ago.
+    pub fn begin_frame(&mut self) {
+        self.frame = (self.frame + 1) % self.frame_count;
+        self.head = 0;
+    }
+
+    /// Copies `values` into the region of the current frame.
+    pub fn push_slice<T: bytemuck::Pod>(&mut self, values: &[T]) -> RingAllocation {
+        let size = std::mem::size_of_val(values) as u64;
+        assert!(
+            self.head + size <= self.frame_size,
+            "Ring buffer frame of {} bytes is full, {} bytes were already pushed",
+            self.frame_size,
+            self.head
+        );
+
+        let offset = self.frame as u64 * self.frame_size + self.head;
+        self.buffer.copy_from_slice(values, offset as usize);
+        self.head = (self.head + size).next_multiple_of(RING_ALIGNMENT);
+
+        RingAllocation {
+            buffer: self.buffer.buffer,
+            offset,
+            size,
+            device_addr: self.buffer.device_addr + offset,
+        }
+    }
+
+    pub fn push<T: bytemuck::Pod>(&mut self, value: &T) -> RingAllocation {
+        self.push_slice(std::slice::from_ref(value))
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.buffer
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+    }
+}
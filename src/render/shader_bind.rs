@@ -0,0 +1,331 @@
+use ash::vk;
+
+use crate::{
+    buffer::Buffer,
+    error::{Error, Result},
+};
+
+use super::{
+    acceleration_structure::Tlas,
+    descriptor_writer::DescriptorWriter,
+    glsl_struct::GlslLayout,
+    pipeline::{ComputePipeline, GraphicsPipeline},
+    ring::{RingAllocation, RingBuffer},
+    shaders::{self, StageDescriptorSetLayouts},
+    RenderAllocator, RenderInstance,
+};
+
+#[cfg(feature = "derive")]
+pub use someday_derive::ShaderBind;
+
+/// How a [`ShaderBind`] struct is bound, which decides the layout rules its fields follow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockKind {
+    /// A std140 `uniform` block.
+    Uniform,
+    /// A std430 `buffer` block.
+    Storage,
+}
+
+impl BlockKind {
+    pub fn descriptor_type(self) -> vk::DescriptorType {
+        match self {
+            BlockKind::Uniform => vk::DescriptorType::UNIFORM_BUFFER,
+            BlockKind::Storage => vk::DescriptorType::STORAGE_BUFFER,
+        }
+    }
+}
+
+/// A `repr(C)` struct that is bound as a uniform or storage block, implement it with
+/// `#[derive(ShaderBind)]` which checks the field layout at compile time against the
+/// [`GlslLayout`] of its fields.
+pub trait ShaderBind: bytemuck::Pod + GlslLayout {
+    const KIND: BlockKind;
+}
+
+/// A pipeline whose resources can be looked up by the names the shaders give them.
+pub trait BindingProgram {
+    fn reflected_layouts(&self) -> &StageDescriptorSetLayouts;
+
+    fn descriptor_sets(&self) -> &[vk::DescriptorSet];
+}
+
+impl BindingProgram for GraphicsPipeline {
+    fn reflected_layouts(&self) -> &StageDescriptorSetLayouts {
+        &self.reflected_layouts
+    }
+
+    fn descriptor_sets(&self) -> &[vk::DescriptorSet] {
+        &self.descriptor_sets
+    }
+}
+
+impl BindingProgram for ComputePipeline {
+    fn reflected_layouts(&self) -> &StageDescriptorSetLayouts {
+        &self.reflected_layouts
+    }
+
+    fn descriptor_sets(&self) -> &[vk::DescriptorSet] {
+        &self.descriptor_sets
+    }
+}
+
+/// A resource that can be bound by name with [`Bindings::set`].
+pub trait BindResource {
+    /// Whether the resource can be written to a binding the shader declares as `ty`.
+    fn accepts(&self, ty: vk::DescriptorType) -> bool;
+
+    fn write(
+        &self,
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+    );
+}
+
+impl BindResource for vk::ImageView {
+    fn accepts(&self, ty: vk::DescriptorType) -> bool {
+        matches!(
+            ty,
+            vk::DescriptorType::SAMPLED_IMAGE
+                | vk::DescriptorType::STORAGE_IMAGE
+                | vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        )
+    }
+
+    fn write(
+        &self,
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+    ) {
+        writer.image(set, binding, ty, *self, vk::Sampler::null());
+    }
+}
+
+impl BindResource for vk::Sampler {
+    fn accepts(&self, ty: vk::DescriptorType) -> bool {
+        ty == vk::DescriptorType::SAMPLER
+    }
+
+    fn write(
+        &self,
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+    ) {
+        writer.images(
+            set,
+            binding,
+            0,
+            ty,
+            &[vk::DescriptorImageInfo::default().sampler(*self)],
+        );
+    }
+}
+
+/// Binds the whole buffer, which needs the usage flag matching the descriptor type.
+impl BindResource for Buffer {
+    fn accepts(&self, ty: vk::DescriptorType) -> bool {
+        match ty {
+            vk::DescriptorType::UNIFORM_BUFFER => {
+                self.usage.contains(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            }
+            vk::DescriptorType::STORAGE_BUFFER => {
+                self.usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER)
+            }
+            _ => false,
+        }
+    }
+
+    fn write(
+        &self,
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+    ) {
+        writer.buffer(set, binding, ty, self);
+    }
+}
+
+impl BindResource for RingAllocation {
+    fn accepts(&self, ty: vk::DescriptorType) -> bool {
+        matches!(
+            ty,
+            vk::DescriptorType::UNIFORM_BUFFER | vk::DescriptorType::STORAGE_BUFFER
+        )
+    }
+
+    fn write(
+        &self,
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        binding: u32,
+        ty: vk::DescriptorType,
+    ) {
+        writer.buffers(
+            set,
+            binding,
+            0,
+            ty,
+            &[vk::DescriptorBufferInfo::default()
+                .buffer(self.buffer)
+                .offset(self.offset)
+                .range(self.size)],
+        );
+    }
+}
+
+impl BindResource for Tlas {
+    fn accepts(&self, ty: vk::DescriptorType) -> bool {
+        ty == vk::DescriptorType::ACCELERATION_STRUCTURE_KHR
+    }
+
+    fn write(
+        &self,
+        writer: &mut DescriptorWriter,
+        set: vk::DescriptorSet,
+        binding: u32,
+        _ty: vk::DescriptorType,
+    ) {
+        writer.acceleration_structure(set, binding, self);
+    }
+}
+
+/// Writes resources to the descriptor sets of a pipeline by the names the shaders give them,
+/// instead of by set and binding index.
+///
+/// ```ignore
+/// Bindings::new(&pass.pipeline)
+///     .set("u_albedo", &albedo_view)?
+///     .set("lights", &light_buffer)?
+///     .flush(render_instance);
+/// ```
+pub struct Bindings<'a> {
+    layouts: &'a StageDescriptorSetLayouts,
+    descriptor_sets: &'a [vk::DescriptorSet],
+    writer: DescriptorWriter,
+}
+
+impl<'a> Bindings<'a> {
+    pub fn new(program: &'a impl BindingProgram) -> Self {
+        Self {
+            layouts: program.reflected_layouts(),
+            descriptor_sets: program.descriptor_sets(),
+            writer: DescriptorWriter::new(),
+        }
+    }
+
+    /// Queues the write of `resource` to the binding called `name`, which fails when the
+    /// shaders don't declare it or declare it as something `resource` can't be bound as.
+    pub fn set(&mut self, name: &str, resource: &impl BindResource) -> Result<&mut Self> {
+        let Some((set, binding, info)) = shaders::find_binding(self.layouts, name) else {
+            return Err(Error::UnknownBinding(name.to_string()));
+        };
+        let ty = shaders::reflected_descriptor_type(info);
+        if !resource.accepts(ty) {
+            return Err(Error::DescriptorWrite {
+                set,
+                binding,
+                message: format!(
+                    "{} is a {:?}, which a {} can't be bound to",
+                    name,
+                    ty,
+                    std::any::type_name_of_val(resource)
+                ),
+            });
+        }
+        resource.write(
+            &mut self.writer,
+            self.descriptor_sets[set as usize],
+            binding,
+            ty,
+        );
+        Ok(self)
+    }
+
+    /// Writes the queued descriptors.
+    pub fn flush(&mut self, render_instance: &RenderInstance) {
+        self.writer.flush(render_instance.device());
+    }
+}
+
+/// Uploads [`ShaderBind`] structs and binds them to blocks by name. Values go through a ring
+/// buffer, so binding a new value doesn't overwrite the one a frame in flight reads.
+#[derive(Debug)]
+pub struct ShaderBlocks {
+    ring: RingBuffer,
+}
+
+impl ShaderBlocks {
+    /// `frame_size` bytes are available for the values bound during a frame.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        frame_size: u64,
+    ) -> Result<Self> {
+        Ok(Self {
+            ring: RingBuffer::new(
+                render_instance,
+                render_allocator,
+                frame_size,
+                render_instance.0.config.uniform_ring_frames,
+                vk::BufferUsageFlags::UNIFORM_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+            )?,
+        })
+    }
+
+    /// Call once per frame before binding, the values of
+    /// [`RendererConfig::uniform_ring_frames`](super::config::RendererConfig::uniform_ring_frames)
+    /// frames ago are overwritten from here on.
+    pub fn begin_frame(&mut self) {
+        self.ring.begin_frame();
+    }
+
+    /// Uploads `value` and binds it to the block `program` calls `name`, which has to be a
+    /// uniform or storage block matching [`ShaderBind::KIND`].
+    pub fn bind<T: ShaderBind>(
+        &mut self,
+        render_instance: &RenderInstance,
+        program: &impl BindingProgram,
+        name: &str,
+        value: &T,
+    ) -> Result<()> {
+        let Some((set, binding, info)) = shaders::find_binding(program.reflected_layouts(), name)
+        else {
+            return Err(Error::UnknownBinding(name.to_string()));
+        };
+        let ty = T::KIND.descriptor_type();
+        if shaders::reflected_descriptor_type(info) != ty {
+            return Err(Error::DescriptorWrite {
+                set,
+                binding,
+                message: format!(
+                    "{} is a {:?}, but {} is bound as a {:?}",
+                    name,
+                    shaders::reflected_descriptor_type(info),
+                    std::any::type_name::<T>(),
+                    ty
+                ),
+            });
+        }
+
+        let allocation = self.ring.push(value);
+        Bindings::new(program)
+            .set(name, &allocation)?
+            .flush(render_instance);
+        Ok(())
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.ring.destroy(render_instance, render_allocator);
+    }
+}
@@ -0,0 +1,195 @@
+use ash::{extensions::khr::RayTracingPipeline, vk};
+
+use crate::{allocator::MemoryLocation, buffer::Buffer, error::Result};
+
+use super::{RenderAllocator, RenderInstance};
+
+pub(crate) fn align_up(value: u64, alignment: u64) -> u64 {
+    let alignment = alignment.max(1);
+    (value + alignment - 1) / alignment * alignment
+}
+
+pub(crate) fn ray_tracing_pipeline_loader(render_instance: &RenderInstance) -> &RayTracingPipeline {
+    render_instance
+        .0
+        .ray_tracing_pipeline
+        .as_ref()
+        .expect("Device doesn't support VK_KHR_ray_tracing_pipeline")
+}
+
+/// Lists which shader groups of a ray tracing pipeline go in which region of the [`Sbt`].
+/// Groups are referenced by their index in `VkRayTracingPipelineCreateInfoKHR::pGroups`, the
+/// order of the miss and hit groups is the index used by `traceRayEXT`.
+#[derive(Clone, Debug)]
+pub struct SbtBuilder {
+    raygen: u32,
+    miss: Vec<u32>,
+    hit: Vec<u32>,
+    callable: Vec<u32>,
+}
+
+impl SbtBuilder {
+    pub fn new(raygen_group: u32) -> Self {
+        Self {
+            raygen: raygen_group,
+            miss: Vec::new(),
+            hit: Vec::new(),
+            callable: Vec::new(),
+        }
+    }
+
+    pub fn miss(mut self, group: u32) -> Self {
+        self.miss.push(group);
+        self
+    }
+
+    pub fn hit(mut self, group: u32) -> Self {
+        self.hit.push(group);
+        self
+    }
+
+    pub fn callable(mut self, group: u32) -> Self {
+        self.callable.push(group);
+        self
+    }
+
+    pub fn build(
+        &self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        pipeline: vk::Pipeline,
+        group_count: u32,
+    ) -> Result<Sbt> {
+        let renderer = render_instance.0.as_ref();
+        let loader = ray_tracing_pipeline_loader(render_instance);
+
+        let handle_size = renderer.shader_group_handle_size as u64;
+        let base_alignment = renderer.shader_group_base_alignment as u64;
+        let stride = align_up(handle_size, renderer.shader_group_handle_alignment as u64);
+
+        for group in std::iter::once(&self.raygen)
+            .chain(&self.miss)
+            .chain(&self.hit)
+            .chain(&self.callable)
+        {
+            assert!(
+                *group < group_count,
+                "Shader group {} is out of range, the pipeline has {} groups",
+                group,
+                group_count
+            );
+        }
+
+        let handles = unsafe {
+            loader.get_ray_tracing_shader_group_handles(
+                pipeline,
+                0,
+                group_count,
+                (group_count as u64 * handle_size) as usize,
+            )?
+        };
+
+        // the raygen region must have a stride equal to its size
+        let raygen_size = align_up(stride, base_alignment);
+        let region_size = |count: usize| align_up(count as u64 * stride, base_alignment);
+        let regions = [
+            (std::slice::from_ref(&self.raygen), raygen_size, raygen_size),
+            (self.miss.as_slice(), stride, region_size(self.miss.len())),
+            (self.hit.as_slice(), stride, region_size(self.hit.len())),
+            (
+                self.callable.as_slice(),
+                stride,
+                region_size(self.callable.len()),
+            ),
+        ];
+        let total_size = regions.iter().map(|(_, _, size)| size).sum::<u64>();
+
+        let mut data = vec![0u8; total_size as usize];
+        let mut offset = 0;
+        for (groups, stride, size) in regions.iter() {
+            for (i, group) in groups.iter().enumerate() {
+                let src = (*group as u64 * handle_size) as usize;
+                let dst = (offset + i as u64 * stride) as usize;
+                data[dst..dst + handle_size as usize]
+                    .copy_from_slice(&handles[src..src + handle_size as usize]);
+            }
+            offset += size;
+        }
+
+        // the allocation isn't guaranteed to satisfy the base alignment, so pad and offset into it
+        let mut buffer = Buffer::new(
+            render_instance.device(),
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(total_size + base_alignment)
+                .usage(vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+            MemoryLocation::CpuToGpu,
+        )?;
+        let base_address = align_up(buffer.device_addr, base_alignment);
+        buffer.copy_from_slice(&data, (base_address - buffer.device_addr) as usize);
+
+        let mut offset = 0;
+        let mut region = |(_, stride, size): &(&[u32], u64, u64)| {
+            let region = vk::StridedDeviceAddressRegionKHR {
+                device_address: if *size == 0 { 0 } else { base_address + offset },
+                stride: *stride,
+                size: *size,
+            };
+            offset += size;
+            region
+        };
+
+        Ok(Sbt {
+            raygen: region(&regions[0]),
+            miss: region(&regions[1]),
+            hit: region(&regions[2]),
+            callable: region(&regions[3]),
+            buffer,
+        })
+    }
+}
+
+/// Shader binding table built by [`SbtBuilder`], holding the regions passed to `vkCmdTraceRaysKHR`.
+pub struct Sbt {
+    pub buffer: Buffer,
+    pub raygen: vk::StridedDeviceAddressRegionKHR,
+    pub miss: vk::StridedDeviceAddressRegionKHR,
+    pub hit: vk::StridedDeviceAddressRegionKHR,
+    pub callable: vk::StridedDeviceAddressRegionKHR,
+}
+
+impl Sbt {
+    /// The ray tracing pipeline and its descriptor sets have to be bound already.
+    pub fn trace_rays(
+        &self,
+        render_instance: &RenderInstance,
+        command_buffer: vk::CommandBuffer,
+        width: u32,
+        height: u32,
+        depth: u32,
+    ) {
+        let loader = ray_tracing_pipeline_loader(render_instance);
+        unsafe {
+            loader.cmd_trace_rays(
+                command_buffer,
+                &self.raygen,
+                &self.miss,
+                &self.hit,
+                &self.callable,
+                width,
+                height,
+                depth,
+            )
+        };
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        self.buffer
+            .destroy(render_instance.device(), &mut render_allocator.allocator());
+    }
+}
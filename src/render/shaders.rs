@@ -2,23 +2,45 @@ use std::{
     collections::{BTreeMap, HashMap},
     ffi::CString,
     path::Path,
+    sync::{Mutex, OnceLock},
 };
 
 use ash::vk::{self};
 use rspirv_reflect::BindingCount;
 use shaderc::CompilationArtifact;
 
-use crate::{chunky_list::TempList, ctx::SamplerDesc};
+use crate::{
+    chunky_list::TempList,
+    ctx::SamplerDesc,
+    error::{Error, Result},
+};
 
-use super::RenderInstance;
+use super::{
+    deferred_destroy::{self, DeferredResource},
+    tracking::{self, ResourceKind},
+    RenderInstance,
+};
 
-#[derive(Clone)]
+/// The module is queued for destruction on drop, which is fine as soon as the pipelines using
+/// it have been created.
 pub struct Shader {
     pub kind: ShaderKind,
     pub spirv_descripor_set_layouts: StageDescriptorSetLayouts,
     pub entry_point: String,
     pub entry_point_cstr: CString,
     pub module: vk::ShaderModule,
+    /// `local_size_{x,y,z}` of the entry point, only present for compute shaders.
+    pub workgroup_size: Option<(u32, u32, u32)>,
+    pub push_constant_range: Option<vk::PushConstantRange>,
+    /// Members of the push constant block, in declaration order.
+    pub push_constant_members: Vec<PushConstantMember>,
+}
+
+/// A member of a push constant block, the name is empty when the SPIR-V has no debug names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushConstantMember {
+    pub name: String,
+    pub offset: u32,
 }
 
 #[derive(Clone)]
@@ -26,6 +48,8 @@ pub enum ShaderKind {
     Vertex,
     Fragment,
     Compute,
+    Task,
+    Mesh,
 }
 impl ShaderKind {
     pub fn to_shaderc_kind(&self) -> shaderc::ShaderKind {
@@ -33,6 +57,8 @@ impl ShaderKind {
             Self::Vertex => shaderc::ShaderKind::Vertex,
             Self::Fragment => shaderc::ShaderKind::Fragment,
             Self::Compute => shaderc::ShaderKind::Compute,
+            Self::Task => shaderc::ShaderKind::Task,
+            Self::Mesh => shaderc::ShaderKind::Mesh,
         }
     }
 
@@ -41,12 +67,280 @@ impl ShaderKind {
             Self::Vertex => vk::ShaderStageFlags::VERTEX,
             Self::Fragment => vk::ShaderStageFlags::FRAGMENT,
             Self::Compute => vk::ShaderStageFlags::COMPUTE,
+            Self::Task => vk::ShaderStageFlags::TASK_EXT,
+            Self::Mesh => vk::ShaderStageFlags::MESH_EXT,
         }
     }
 }
 
-type DescriptorSetLayout = BTreeMap<u32, rspirv_reflect::DescriptorInfo>;
-type StageDescriptorSetLayouts = BTreeMap<u32, DescriptorSetLayout>;
+pub type DescriptorSetLayout = BTreeMap<u32, rspirv_reflect::DescriptorInfo>;
+pub type StageDescriptorSetLayouts = BTreeMap<u32, DescriptorSetLayout>;
+
+/// Checks `write` against the reflected layout of `set`, so a binding the shader doesn't
+/// declare, a mismatched descriptor type or an out of bounds array element is reported instead
+/// of crashing the driver.
+pub fn validate_descriptor_write(
+    layouts: &StageDescriptorSetLayouts,
+    set: u32,
+    write: &vk::WriteDescriptorSet,
+) -> Result<()> {
+    validate_set_write(layouts.get(&set), set, write)
+}
+
+fn validate_set_write(
+    layout: Option<&DescriptorSetLayout>,
+    set: u32,
+    write: &vk::WriteDescriptorSet,
+) -> Result<()> {
+    let binding = write.dst_binding;
+    let invalid = |message: String| {
+        Err(Error::DescriptorWrite {
+            set,
+            binding,
+            message,
+        })
+    };
+
+    let Some(info) = layout.and_then(|layout| layout.get(&binding)) else {
+        return invalid(format!("binding {} isn't declared by the shader", binding));
+    };
+
+    let expected = reflected_descriptor_type(info);
+    if write.descriptor_type != expected {
+        return invalid(format!(
+            "binding {} expects {:?}, got {:?}",
+            binding, expected, write.descriptor_type
+        ));
+    }
+
+    // `u_` bindings are sized to the device limit, like unbounded arrays
+    let count = match info.binding_count {
+        _ if info.name.starts_with("u_") => None,
+        BindingCount::One => Some(1),
+        BindingCount::StaticSized(size) => Some(size as u64),
+        BindingCount::Unbounded => None,
+    };
+    let end = write.dst_array_element as u64 + write.descriptor_count as u64;
+    if let Some(count) = count.filter(|count| end > *count) {
+        return invalid(format!(
+            "binding {} holds {} descriptors, but elements {}..{} are written",
+            binding, count, write.dst_array_element, end
+        ));
+    }
+    Ok(())
+}
+
+/// Reflection of the layouts created by [`Shader::create_descriptor_set_layouts`] and the layout
+/// every set was allocated with, only kept in debug builds.
+#[derive(Default)]
+struct ReflectedSets {
+    layouts: HashMap<vk::DescriptorSetLayout, (u32, DescriptorSetLayout)>,
+    sets: HashMap<vk::DescriptorSet, vk::DescriptorSetLayout>,
+}
+
+fn reflected_sets() -> &'static Mutex<ReflectedSets> {
+    static REFLECTED: OnceLock<Mutex<ReflectedSets>> = OnceLock::new();
+    REFLECTED.get_or_init(Default::default)
+}
+
+pub(crate) fn register_descriptor_sets(
+    layouts: &[vk::DescriptorSetLayout],
+    sets: &[vk::DescriptorSet],
+) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let mut reflected = reflected_sets().lock().unwrap();
+    for (layout, set) in layouts.iter().zip(sets) {
+        // the handle may have belonged to a set of a reflected layout before
+        if reflected.layouts.contains_key(layout) {
+            reflected.sets.insert(*set, *layout);
+        } else {
+            reflected.sets.remove(set);
+        }
+    }
+}
+
+pub(crate) fn forget_descriptor_sets(sets: &[vk::DescriptorSet]) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let mut reflected = reflected_sets().lock().unwrap();
+    for set in sets {
+        reflected.sets.remove(set);
+    }
+}
+
+pub(crate) fn forget_descriptor_set_layouts(layouts: &[vk::DescriptorSetLayout]) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let mut reflected = reflected_sets().lock().unwrap();
+    for layout in layouts {
+        reflected.layouts.remove(layout);
+    }
+}
+
+/// Panics in debug builds when `write` doesn't match the reflection of the shader its set was
+/// allocated for, see [`validate_descriptor_write`]. Called by
+/// [`DescriptorWriter::flush`](super::descriptor_writer::DescriptorWriter::flush), sets of
+/// layouts that weren't created from a [`Shader`] aren't checked.
+pub fn assert_valid_descriptor_write(write: &vk::WriteDescriptorSet) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let reflected = reflected_sets().lock().unwrap();
+    let Some((set, layout)) = reflected
+        .sets
+        .get(&write.dst_set)
+        .and_then(|layout| reflected.layouts.get(layout))
+    else {
+        return;
+    };
+    if let Err(error) = validate_set_write(Some(layout), *set, write) {
+        panic!("{}", error);
+    }
+}
+
+/// Set, binding and reflection of the resource called `name`, which is the name of the variable
+/// or, for blocks without an instance name, of the block.
+pub fn find_binding<'a>(
+    layouts: &'a StageDescriptorSetLayouts,
+    name: &str,
+) -> Option<(u32, u32, &'a rspirv_reflect::DescriptorInfo)> {
+    layouts.iter().find_map(|(set, bindings)| {
+        bindings
+            .iter()
+            .find(|(_, info)| info.name == name)
+            .map(|(binding, info)| (*set, *binding, info))
+    })
+}
+
+/// Reads the members of the push constant block straight from the SPIR-V, reflection only
+/// reports the range it covers.
+pub fn reflect_push_constant_members(spirv: &[u32]) -> Vec<PushConstantMember> {
+    const OP_MEMBER_NAME: u32 = 6;
+    const OP_TYPE_POINTER: u32 = 32;
+    const OP_VARIABLE: u32 = 59;
+    const OP_MEMBER_DECORATE: u32 = 72;
+    const DECORATION_OFFSET: u32 = 35;
+    const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+    const HEADER_WORDS: usize = 5;
+
+    let mut names = HashMap::new();
+    let mut offsets: HashMap<u32, Vec<(u32, u32)>> = HashMap::new();
+    let mut pointers = HashMap::new();
+    let mut block_pointer = None;
+    let mut words = spirv.get(HEADER_WORDS..).unwrap_or_default();
+    while let Some(&first) = words.first() {
+        let word_count = (first >> 16) as usize;
+        if word_count == 0 || word_count > words.len() {
+            break;
+        }
+        let operands = &words[1..word_count];
+        match first & 0xffff {
+            OP_MEMBER_NAME => {
+                let bytes = operands[2..]
+                    .iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .take_while(|byte| *byte != 0)
+                    .collect::<Vec<_>>();
+                let name = String::from_utf8_lossy(&bytes).into_owned();
+                names.insert((operands[0], operands[1]), name);
+            }
+            OP_TYPE_POINTER if operands[1] == STORAGE_CLASS_PUSH_CONSTANT => {
+                pointers.insert(operands[0], operands[2]);
+            }
+            OP_VARIABLE if operands[2] == STORAGE_CLASS_PUSH_CONSTANT => {
+                block_pointer = Some(operands[0]);
+            }
+            OP_MEMBER_DECORATE if operands[2] == DECORATION_OFFSET => {
+                offsets
+                    .entry(operands[0])
+                    .or_default()
+                    .push((operands[1], operands[3]));
+            }
+            _ => {}
+        }
+        words = &words[word_count..];
+    }
+
+    let Some(block) = block_pointer.and_then(|pointer| pointers.get(&pointer)) else {
+        return Vec::new();
+    };
+    let mut members = offsets.remove(block).unwrap_or_default();
+    members.sort_by_key(|(member, _)| *member);
+    members
+        .into_iter()
+        .map(|(member, offset)| PushConstantMember {
+            name: names.remove(&(*block, member)).unwrap_or_default(),
+            offset,
+        })
+        .collect()
+}
+
+pub(crate) fn reflected_descriptor_type(
+    info: &rspirv_reflect::DescriptorInfo,
+) -> vk::DescriptorType {
+    if info.ty == rspirv_reflect::DescriptorType::STORAGE_BUFFER && info.name.ends_with("_dyn") {
+        vk::DescriptorType::STORAGE_BUFFER_DYNAMIC
+    } else {
+        // the reflected types share their values with Vulkan
+        vk::DescriptorType::from_raw(info.ty.0 as i32)
+    }
+}
+
+/// The sets with their bindings and the push constant range, shared by the reflection JSON of
+/// shaders and pipelines.
+fn reflection_value(
+    sets: &StageDescriptorSetLayouts,
+    push_constant_range: Option<vk::PushConstantRange>,
+) -> serde_json::Value {
+    let sets = sets
+        .iter()
+        .map(|(set, bindings)| {
+            let bindings = bindings
+                .iter()
+                .map(|(binding, info)| {
+                    let count = match info.binding_count {
+                        BindingCount::One => serde_json::json!(1),
+                        BindingCount::StaticSized(size) => serde_json::json!(size),
+                        BindingCount::Unbounded => serde_json::json!("unbounded"),
+                    };
+                    serde_json::json!({
+                        "binding": binding,
+                        "name": info.name,
+                        "type": format!("{:?}", reflected_descriptor_type(info)),
+                        "count": count,
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({ "set": set, "bindings": bindings })
+        })
+        .collect::<Vec<_>>();
+    let push_constants = push_constant_range
+        .map(|range| serde_json::json!({ "offset": range.offset, "size": range.size }));
+    serde_json::json!({ "sets": sets, "push_constants": push_constants })
+}
+
+/// [`Shader::reflection_json`] of the sets a pipeline kept from its shaders.
+pub(crate) fn reflection_json(
+    sets: &StageDescriptorSetLayouts,
+    push_constant_range: Option<vk::PushConstantRange>,
+) -> String {
+    serde_json::to_string_pretty(&reflection_value(sets, push_constant_range)).unwrap()
+}
+
+/// FNV-1a over the words, stable between runs and builds so hashes in bug reports can be
+/// compared.
+pub fn spirv_hash(spirv: &[u32]) -> u64 {
+    spirv
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
 
 impl Shader {
     pub fn new(
@@ -54,27 +348,60 @@ impl Shader {
         spirv: CompilationArtifact,
         kind: ShaderKind,
         entry_point: &str,
-    ) -> Self {
-        let refl_info = rspirv_reflect::Reflection::new_from_spirv(spirv.as_binary_u8()).unwrap();
-        let descriptor_sets = refl_info.get_descriptor_sets().unwrap();
+    ) -> Result<Self> {
+        profile_scope!("Shader::new");
+        let refl_info = rspirv_reflect::Reflection::new_from_spirv(spirv.as_binary_u8())?;
+        let descriptor_sets = refl_info.get_descriptor_sets()?;
+        let workgroup_size = refl_info.get_compute_group_size();
+        let push_constant_range =
+            refl_info
+                .get_push_constant_range()?
+                .map(|range| vk::PushConstantRange {
+                    stage_flags: kind.to_vk_shader_stage_flag(),
+                    offset: range.offset,
+                    size: range.size,
+                });
+
+        let push_constant_members = reflect_push_constant_members(spirv.as_binary());
 
         let module = unsafe {
-            render_instance
-                .device()
-                .create_shader_module(
-                    &vk::ShaderModuleCreateInfo::default().code(&spirv.as_binary()),
-                    None,
-                )
-                .expect("Vertex shader module error")
+            render_instance.device().create_shader_module(
+                &vk::ShaderModuleCreateInfo::default().code(&spirv.as_binary()),
+                None,
+            )?
         };
+        tracking::track(
+            ResourceKind::ShaderModule,
+            module,
+            format!(
+                "{:?} spirv {:016x}",
+                kind.to_vk_shader_stage_flag(),
+                spirv_hash(spirv.as_binary())
+            ),
+        );
 
-        Self {
+        Ok(Self {
             kind,
             spirv_descripor_set_layouts: descriptor_sets,
             entry_point: entry_point.to_string(),
             entry_point_cstr: CString::new(entry_point).unwrap(),
             module,
-        }
+            workgroup_size,
+            push_constant_range,
+            push_constant_members,
+        })
+    }
+
+    /// The reflected sets, bindings and push constant range as JSON, for tools outside the
+    /// crate. Pipelines keep the sets of their shader, see
+    /// [`GraphicsPipeline::reflection_json`](super::pipeline::GraphicsPipeline::reflection_json).
+    pub fn reflection_json(&self) -> String {
+        let mut json =
+            reflection_value(&self.spirv_descripor_set_layouts, self.push_constant_range);
+        json["stage"] = serde_json::json!(format!("{:?}", self.kind.to_vk_shader_stage_flag()));
+        json["entry_point"] = serde_json::json!(self.entry_point);
+        json["workgroup_size"] = serde_json::json!(self.workgroup_size.map(|(x, y, z)| [x, y, z]));
+        serde_json::to_string_pretty(&json).unwrap()
     }
 
     pub fn create_descriptor_sets(
@@ -82,44 +409,16 @@ impl Shader {
         render_instance: &RenderInstance,
         descriptor_set_layouts: &[vk::DescriptorSetLayout],
         set_layout_info: &[HashMap<u32, vk::DescriptorType>],
-    ) -> Vec<vk::DescriptorSet> {
-        let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
-        for bindings in set_layout_info.iter() {
-            for ty in bindings.values() {
-                if let Some(mut dps) = descriptor_pool_sizes.iter_mut().find(|item| item.ty == *ty)
-                {
-                    dps.descriptor_count += 1;
-                } else {
-                    descriptor_pool_sizes.push(vk::DescriptorPoolSize {
-                        ty: *ty,
-                        descriptor_count: render_instance.0.max_descriptor_count,
-                    })
-                }
-            }
-        }
-
-        let descriptor_pool_info = vk::DescriptorPoolCreateInfo::default()
-            .pool_sizes(&descriptor_pool_sizes)
-            .max_sets(2);
-
-        let descriptor_pool = unsafe {
-            render_instance
-                .device()
-                .create_descriptor_pool(&descriptor_pool_info, None)
-                .unwrap()
-        };
-
-        let desc_alloc_info = vk::DescriptorSetAllocateInfo::default()
-            .descriptor_pool(descriptor_pool)
-            .set_layouts(descriptor_set_layouts);
-        let descriptor_sets = unsafe {
-            render_instance
-                .device()
-                .allocate_descriptor_sets(&desc_alloc_info)
-                .unwrap()
-        };
-
-        descriptor_sets
+    ) -> Result<(vk::DescriptorPool, Vec<vk::DescriptorSet>)> {
+        profile_scope!("Shader::create_descriptor_sets");
+        let descriptor_pool_sizes = descriptor_pool_sizes(render_instance, set_layout_info);
+
+        render_instance
+            .0
+            .descriptor_allocator
+            .lock()
+            .unwrap()
+            .allocate(render_instance.device(), descriptor_set_layouts, &descriptor_pool_sizes)
     }
 
     // pub fn ext_shader_create_info(&self) -> ShaderCreateInfoEXT {
@@ -133,10 +432,11 @@ impl Shader {
     pub fn create_descriptor_set_layouts(
         &self,
         render_instance: &RenderInstance,
-    ) -> (
+    ) -> Result<(
         Vec<vk::DescriptorSetLayout>,
         Vec<HashMap<u32, vk::DescriptorType>>,
-    ) {
+    )> {
+        profile_scope!("Shader::create_descriptor_set_layouts");
         let samplers = TempList::new();
         let set_count = self
             .spirv_descripor_set_layouts
@@ -149,6 +449,15 @@ impl Shader {
         let mut set_layout_info: Vec<HashMap<u32, vk::DescriptorType>> =
             Vec::with_capacity(set_count as usize);
 
+        let descriptor_indexing = render_instance.0.capabilities.descriptor_indexing;
+        // without it every descriptor has to be written, which the robustness null descriptors
+        // at least make safe
+        let default_binding_flags = if descriptor_indexing.partially_bound {
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND
+        } else {
+            vk::DescriptorBindingFlags::empty()
+        };
+
         for set_index in 0..set_count {
             let stage_flags = vk::ShaderStageFlags::ALL;
             let set = self.spirv_descripor_set_layouts.get(&set_index);
@@ -157,7 +466,7 @@ impl Shader {
                 let mut bindings: Vec<vk::DescriptorSetLayoutBinding> =
                     Vec::with_capacity(set.len());
                 let mut binding_flags: Vec<vk::DescriptorBindingFlags> =
-                    vec![vk::DescriptorBindingFlags::PARTIALLY_BOUND; set.len()];
+                    vec![default_binding_flags; set.len()];
 
                 let mut set_layout_create_flags = vk::DescriptorSetLayoutCreateFlags::empty();
 
@@ -173,6 +482,15 @@ impl Shader {
                     //         vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL;
                     // }
 
+                    if matches!(binding.binding_count, BindingCount::Unbounded)
+                        && !descriptor_indexing.runtime_descriptor_array
+                    {
+                        return Err(Error::Unsupported(format!(
+                            "{} is an unbounded array, which needs runtimeDescriptorArray",
+                            binding.name
+                        )));
+                    }
+
                     let descriptor_count: u32 = if binding.name.starts_with("u_") {
                         render_instance.0.max_descriptor_count
                     } else {
@@ -247,6 +565,14 @@ impl Shader {
                                 };
                                 spec = &spec[1..];
 
+                                // `_cmp` suffix selects a depth comparison sampler, e.g. `sampler_llc_cmp`
+                                let compare = if let Some(stripped) = spec.strip_suffix("_cmp") {
+                                    spec = stripped;
+                                    true
+                                } else {
+                                    false
+                                };
+
                                 let address_modes = match spec {
                                     "r" => vk::SamplerAddressMode::REPEAT,
                                     "mr" => vk::SamplerAddressMode::MIRRORED_REPEAT,
@@ -267,6 +593,7 @@ impl Shader {
                                                 texel_filter,
                                                 mipmap_mode,
                                                 address_modes,
+                                                compare,
                                             }),
                                         ))),
                                 );
@@ -292,16 +619,13 @@ impl Shader {
                         .binding_flags(&binding_flags);
 
                 let set_layout = unsafe {
-                    render_instance
-                        .device()
-                        .create_descriptor_set_layout(
-                            &vk::DescriptorSetLayoutCreateInfo::default()
-                                .flags(set_layout_create_flags)
-                                .bindings(&bindings)
-                                .push_next(&mut binding_flags_create_info),
-                            None,
-                        )
-                        .unwrap()
+                    render_instance.device().create_descriptor_set_layout(
+                        &vk::DescriptorSetLayoutCreateInfo::default()
+                            .flags(set_layout_create_flags)
+                            .bindings(&bindings)
+                            .push_next(&mut binding_flags_create_info),
+                        None,
+                    )?
                 };
 
                 set_layouts.push(set_layout);
@@ -313,13 +637,10 @@ impl Shader {
                 );
             } else {
                 let set_layout = unsafe {
-                    render_instance
-                        .device()
-                        .create_descriptor_set_layout(
-                            &vk::DescriptorSetLayoutCreateInfo::default(),
-                            None,
-                        )
-                        .unwrap()
+                    render_instance.device().create_descriptor_set_layout(
+                        &vk::DescriptorSetLayoutCreateInfo::default(),
+                        None,
+                    )?
                 };
 
                 set_layouts.push(set_layout);
@@ -327,7 +648,20 @@ impl Shader {
             }
         }
 
-        (set_layouts, set_layout_info)
+        if cfg!(debug_assertions) {
+            let mut reflected = reflected_sets().lock().unwrap();
+            for (set_index, set_layout) in set_layouts.iter().enumerate() {
+                let set_index = set_index as u32;
+                let layout = self
+                    .spirv_descripor_set_layouts
+                    .get(&set_index)
+                    .cloned()
+                    .unwrap_or_default();
+                reflected.layouts.insert(*set_layout, (set_index, layout));
+            }
+        }
+
+        Ok((set_layouts, set_layout_info))
     }
 
     pub fn from_file(
@@ -335,17 +669,53 @@ impl Shader {
         path: &str,
         kind: ShaderKind,
         entry_point: &str,
-    ) -> Self {
-        let compiler = shaderc::Compiler::new().unwrap();
-        let mut options = shaderc::CompileOptions::new().unwrap();
-        options.add_macro_definition("EP", Some("main"));
-        options.set_target_env(
-            shaderc::TargetEnv::Vulkan,
-            shaderc::EnvVersion::Vulkan1_2 as u32,
-        );
-        options.set_optimization_level(shaderc::OptimizationLevel::Zero);
-        options.set_generate_debug_info();
+    ) -> Result<Self> {
+        Self::from_file_with_defines(render_instance, path, kind, entry_point, &[])
+    }
+
+    /// Like [`Shader::from_file`], with extra `#define`s as `(name, value)` pairs.
+    pub fn from_file_with_defines(
+        render_instance: &RenderInstance,
+        path: &str,
+        kind: ShaderKind,
+        entry_point: &str,
+        defines: &[(&str, &str)],
+    ) -> Result<Self> {
+        let spirv = Self::compile(path, kind.clone(), entry_point, defines)?;
+        let shader = Self::new(render_instance, spirv, kind, entry_point)?;
+        shader.set_name(path);
+        Ok(shader)
+    }
+
+    /// Shown in the leak report when the module is still alive at shutdown.
+    pub fn set_name(&self, name: &str) {
+        tracking::set_name(ResourceKind::ShaderModule, self.module, name);
+    }
+
+    /// Compiles a GLSL file to SPIR-V, includes are resolved relative to the file or from `shader/`,
+    /// `<...>` includes from [`add_include`] first.
+    pub fn compile(
+        path: &str,
+        kind: ShaderKind,
+        entry_point: &str,
+        defines: &[(&str, &str)],
+    ) -> Result<CompilationArtifact> {
+        profile_scope!("Shader::compile", path);
+        let compiler = shader_compiler().lock().unwrap();
+        // the include callback isn't cloned along with the options
+        let mut options = compiler.base_options.clone().unwrap();
+        for (name, value) in defines {
+            options.add_macro_definition(name, Some(value));
+        }
         options.set_include_callback(|name, include_type, source_file, _depth| {
+            if include_type == shaderc::IncludeType::Standard {
+                if let Some(content) = includes().lock().unwrap().get(name) {
+                    return Ok(shaderc::ResolvedInclude {
+                        resolved_name: String::from(name),
+                        content: content.clone(),
+                    });
+                }
+            }
             let path = if include_type == shaderc::IncludeType::Relative {
                 Path::new(Path::new(source_file).parent().unwrap()).join(name)
             } else {
@@ -364,16 +734,188 @@ impl Shader {
             }
         });
 
-        let spirv = compiler
+        let compile_error = |message: String| Error::ShaderCompile {
+            path: path.to_string(),
+            message,
+        };
+        let source = std::fs::read_to_string(path).map_err(|err| compile_error(err.to_string()))?;
+        compiler
+            .compiler
             .compile_into_spirv(
-                &std::fs::read_to_string(path).unwrap(),
+                &source,
                 kind.to_shaderc_kind(),
                 path,
                 entry_point,
                 Some(&options),
             )
-            .unwrap();
+            .map_err(|err| compile_error(err.to_string()))
+    }
+}
+
+/// The descriptors a set for every one of `set_layout_info` needs together.
+pub(crate) fn descriptor_pool_sizes(
+    render_instance: &RenderInstance,
+    set_layout_info: &[HashMap<u32, vk::DescriptorType>],
+) -> Vec<vk::DescriptorPoolSize> {
+    let mut descriptor_pool_sizes: Vec<vk::DescriptorPoolSize> = Vec::new();
+    for bindings in set_layout_info.iter() {
+        for ty in bindings.values() {
+            if let Some(dps) = descriptor_pool_sizes.iter_mut().find(|item| item.ty == *ty) {
+                dps.descriptor_count += 1;
+            } else {
+                descriptor_pool_sizes.push(vk::DescriptorPoolSize {
+                    ty: *ty,
+                    descriptor_count: render_instance.0.max_descriptor_count,
+                })
+            }
+        }
+    }
+    descriptor_pool_sizes
+}
+
+/// Creating a compiler is expensive, so one is shared by all compilations together with the
+/// options every shader is compiled with.
+struct ShaderCompiler {
+    compiler: shaderc::Compiler,
+    base_options: shaderc::CompileOptions<'static>,
+}
+
+// shaderc objects aren't tied to a thread, and this one is only used behind a mutex
+unsafe impl Send for ShaderCompiler {}
+
+fn shader_compiler() -> &'static Mutex<ShaderCompiler> {
+    static COMPILER: OnceLock<Mutex<ShaderCompiler>> = OnceLock::new();
+    COMPILER.get_or_init(|| {
+        let mut base_options = shaderc::CompileOptions::new().unwrap();
+        base_options.add_macro_definition("EP", Some("main"));
+        base_options.set_target_env(
+            shaderc::TargetEnv::Vulkan,
+            shaderc::EnvVersion::Vulkan1_2 as u32,
+        );
+        base_options.set_optimization_level(shaderc::OptimizationLevel::Zero);
+        base_options.set_generate_debug_info();
+        Mutex::new(ShaderCompiler {
+            compiler: shaderc::Compiler::new().unwrap(),
+            base_options,
+        })
+    })
+}
+
+/// Includes that don't come from a file, resolved before looking in `shader/`.
+fn includes() -> &'static Mutex<HashMap<String, String>> {
+    static INCLUDES: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    INCLUDES.get_or_init(|| {
+        Mutex::new(HashMap::from([(
+            "someday/buffer_reference.glsl".to_string(),
+            include_str!("../../shader/someday/buffer_reference.glsl").to_string(),
+        )]))
+    })
+}
+
+/// Makes `source` available to every shader compiled afterwards as `#include <name>`, like
+/// the declarations generated by [`glsl_struct!`](crate::glsl_struct).
+pub fn add_include(name: &str, source: String) {
+    includes().lock().unwrap().insert(name.to_string(), source);
+}
+
+/// Defines `name` for every shader compiled afterwards, like `SHADER_PRINTF` when shaders can
+/// call `debugPrintfEXT`, so they can guard optional features with it.
+pub(crate) fn define_global(name: &str, value: &str) {
+    shader_compiler()
+        .lock()
+        .unwrap()
+        .base_options
+        .add_macro_definition(name, Some(value));
+}
 
-        Self::new(render_instance, spirv, kind, entry_point)
+impl Drop for Shader {
+    fn drop(&mut self) {
+        deferred_destroy::release(DeferredResource::ShaderModule(self.module));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+    use rspirv_reflect::{BindingCount, DescriptorInfo, DescriptorType};
+
+    use super::{
+        reflection_json, validate_descriptor_write, Shader, ShaderKind, StageDescriptorSetLayouts,
+    };
+
+    #[test]
+    fn test_compile_ray_query_shader() {
+        let spirv = Shader::compile(
+            "./shader/ray_query_shadow.comp",
+            ShaderKind::Compute,
+            "main",
+            &[],
+        )
+        .unwrap();
+        let reflection = rspirv_reflect::Reflection::new_from_spirv(spirv.as_binary_u8()).unwrap();
+        let sets = reflection.get_descriptor_sets().unwrap();
+        assert_eq!(
+            sets[&0][&0].ty,
+            rspirv_reflect::DescriptorType::ACCELERATION_STRUCTURE_KHR
+        );
+    }
+
+    #[test]
+    fn test_validate_descriptor_write() {
+        let lights = DescriptorInfo {
+            ty: DescriptorType::STORAGE_BUFFER,
+            binding_count: BindingCount::StaticSized(2),
+            name: "lights".to_string(),
+        };
+        let layouts = StageDescriptorSetLayouts::from([(0, [(1, lights)].into())]);
+        let write = |binding, ty, array_element, count| vk::WriteDescriptorSet {
+            dst_binding: binding,
+            descriptor_type: ty,
+            dst_array_element: array_element,
+            descriptor_count: count,
+            ..Default::default()
+        };
+
+        let storage = vk::DescriptorType::STORAGE_BUFFER;
+        assert!(validate_descriptor_write(&layouts, 0, &write(1, storage, 1, 1)).is_ok());
+        assert!(validate_descriptor_write(&layouts, 0, &write(0, storage, 0, 1)).is_err());
+        assert!(validate_descriptor_write(&layouts, 1, &write(1, storage, 0, 1)).is_err());
+        assert!(validate_descriptor_write(&layouts, 0, &write(1, storage, 1, 2)).is_err());
+        let uniform = vk::DescriptorType::UNIFORM_BUFFER;
+        assert!(validate_descriptor_write(&layouts, 0, &write(1, uniform, 0, 1)).is_err());
+    }
+
+    #[test]
+    fn test_reflection_json() {
+        let lights = DescriptorInfo {
+            ty: DescriptorType::STORAGE_BUFFER,
+            binding_count: BindingCount::Unbounded,
+            name: "lights".to_string(),
+        };
+        let layouts = StageDescriptorSetLayouts::from([(1, [(2, lights)].into())]);
+        let range = vk::PushConstantRange {
+            stage_flags: vk::ShaderStageFlags::COMPUTE,
+            offset: 0,
+            size: 16,
+        };
+
+        let json: serde_json::Value =
+            serde_json::from_str(&reflection_json(&layouts, Some(range))).unwrap();
+        assert_eq!(
+            json["sets"],
+            serde_json::json!([{
+                "set": 1,
+                "bindings": [{
+                    "binding": 2,
+                    "name": "lights",
+                    "type": "STORAGE_BUFFER",
+                    "count": "unbounded",
+                }],
+            }])
+        );
+        assert_eq!(
+            json["push_constants"],
+            serde_json::json!({ "offset": 0, "size": 16 })
+        );
     }
 }
@@ -0,0 +1,607 @@
+use ash::{vk, Device};
+
+use crate::{
+    allocator::MemoryLocation,
+    buffer::{Buffer, Image},
+    ctx::ExampleBase,
+    error::Result,
+};
+
+use super::{
+    barrier::{self, BarrierBatch, Usage},
+    stats,
+    tracking::{self, ResourceKind},
+    RenderAllocator,
+};
+
+/// Staging memory is handed out from chunks of this size, larger uploads get a chunk of their
+/// own that is freed again once the copy is done.
+const CHUNK_SIZE: vk::DeviceSize = 8 * 1024 * 1024;
+/// Copies into images need an offset that is a multiple of the texel size.
+const COPY_ALIGNMENT: vk::DeviceSize = 16;
+
+const WHOLE_COLOR_IMAGE: vk::ImageSubresourceRange = vk::ImageSubresourceRange {
+    aspect_mask: vk::ImageAspectFlags::COLOR,
+    base_mip_level: 0,
+    level_count: vk::REMAINING_MIP_LEVELS,
+    base_array_layer: 0,
+    layer_count: vk::REMAINING_ARRAY_LAYERS,
+};
+
+struct Chunk {
+    buffer: Buffer,
+    used: vk::DeviceSize,
+}
+
+enum StagingCopy {
+    Buffer {
+        src: vk::Buffer,
+        dst: vk::Buffer,
+        region: vk::BufferCopy,
+    },
+    Image {
+        src: vk::Buffer,
+        dst: vk::Image,
+        region: vk::BufferImageCopy,
+    },
+}
+
+/// A zero-initialized resource that hasn't been cleared yet.
+enum PendingClear {
+    Buffer(vk::Buffer),
+    Image {
+        image: vk::Image,
+        aspect_mask: vk::ImageAspectFlags,
+        first_usage: Usage,
+    },
+}
+
+/// An image that still has to be moved out of `UNDEFINED`.
+struct PendingTransition {
+    image: vk::Image,
+    aspect_mask: vk::ImageAspectFlags,
+    first_usage: Usage,
+}
+
+/// What the graphics submission has to do before it may use the uploads of a
+/// [`StagingBelt::flush`].
+pub struct StagingWait {
+    pub semaphore: vk::Semaphore,
+    pub stage: vk::PipelineStageFlags,
+    buffer_barriers: Vec<vk::BufferMemoryBarrier2<'static>>,
+    image_barriers: Vec<vk::ImageMemoryBarrier2<'static>>,
+}
+
+impl StagingWait {
+    /// Acquires the uploaded resources from the transfer queue family, a no-op when the uploads
+    /// were submitted to the graphics queue.
+    pub fn record_acquire_barriers(&self, base: &ExampleBase, command_buffer: vk::CommandBuffer) {
+        if self.buffer_barriers.is_empty() && self.image_barriers.is_empty() {
+            return;
+        }
+        unsafe {
+            base.synchronization2.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default()
+                    .buffer_memory_barriers(&self.buffer_barriers)
+                    .image_memory_barriers(&self.image_barriers),
+            )
+        };
+    }
+}
+
+/// Collects the staging copies of a frame, so they are recorded into one command buffer and
+/// submitted once, instead of every upload waiting for a submission of its own. Uploads go to
+/// the transfer queue when the device has one, in which case ownership of the destinations is
+/// handed to the graphics queue family through [`StagingWait::record_acquire_barriers`].
+///
+/// Uploaded images end up in `SHADER_READ_ONLY_OPTIMAL`.
+pub struct StagingBelt {
+    queue: vk::Queue,
+    queue_family_index: u32,
+    graphics_queue_family_index: u32,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    /// Alternated, so a flush whose semaphore was never waited on can be waited on by the next.
+    semaphores: [vk::Semaphore; 2],
+    semaphore_index: usize,
+    chunks: Vec<Chunk>,
+    copies: Vec<StagingCopy>,
+    /// The chunks are read by a submission that may not have finished yet.
+    in_flight: bool,
+    pending_wait: Option<StagingWait>,
+    /// Recorded on the graphics queue, transfer queues can't clear images.
+    clears: Vec<PendingClear>,
+    transitions: Vec<PendingTransition>,
+}
+
+impl StagingBelt {
+    pub fn new(
+        device: &Device,
+        queue: vk::Queue,
+        queue_family_index: u32,
+        graphics_queue_family_index: u32,
+    ) -> Result<Self> {
+        unsafe {
+            let command_pool = device.create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+                    .queue_family_index(queue_family_index),
+                None,
+            )?;
+            let command_buffer = device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::default()
+                    .command_buffer_count(1)
+                    .command_pool(command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY),
+            )?[0];
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            let semaphores = [
+                device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?,
+                device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)?,
+            ];
+
+            Ok(Self {
+                queue,
+                queue_family_index,
+                graphics_queue_family_index,
+                command_pool,
+                command_buffer,
+                fence,
+                semaphores,
+                semaphore_index: 0,
+                chunks: Vec::new(),
+                copies: Vec::new(),
+                in_flight: false,
+                pending_wait: None,
+                clears: Vec::new(),
+                transitions: Vec::new(),
+            })
+        }
+    }
+
+    fn transfers_ownership(&self) -> bool {
+        self.queue_family_index != self.graphics_queue_family_index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.copies.is_empty()
+    }
+
+    /// Queues a copy of `data` to `dst` at `dst_offset`, which happens with the next
+    /// [`Self::flush`].
+    pub fn write_buffer(
+        &mut self,
+        device: &Device,
+        render_allocator: &RenderAllocator,
+        data: &[u8],
+        dst: &Buffer,
+        dst_offset: vk::DeviceSize,
+    ) -> Result<()> {
+        tracking::assert_alive(ResourceKind::Buffer, dst.buffer);
+        let (src, src_offset) = self.stage(device, render_allocator, data)?;
+        self.copies.push(StagingCopy::Buffer {
+            src,
+            dst: dst.buffer,
+            region: vk::BufferCopy {
+                src_offset,
+                dst_offset,
+                size: data.len() as vk::DeviceSize,
+            },
+        });
+        Ok(())
+    }
+
+    /// Queues a copy of `data` to the first mip level of `dst`, tightly packed texels.
+    pub fn write_image(
+        &mut self,
+        device: &Device,
+        render_allocator: &RenderAllocator,
+        data: &[u8],
+        dst: &Image,
+    ) -> Result<()> {
+        self.write_image_mip(device, render_allocator, data, dst, 0)
+    }
+
+    /// Like [`Self::write_image`], for `mip_level` of `dst`. The other mips of the image have
+    /// to be written in the same flush, the whole image is transitioned.
+    pub fn write_image_mip(
+        &mut self,
+        device: &Device,
+        render_allocator: &RenderAllocator,
+        data: &[u8],
+        dst: &Image,
+        mip_level: u32,
+    ) -> Result<()> {
+        tracking::assert_alive(ResourceKind::Image, dst.image);
+        let (src, buffer_offset) = self.stage(device, render_allocator, data)?;
+        let extent = vk::Extent3D {
+            width: (dst.extent.width >> mip_level).max(1),
+            height: (dst.extent.height >> mip_level).max(1),
+            depth: 1,
+        };
+        self.copies.push(StagingCopy::Image {
+            src,
+            dst: dst.image,
+            region: vk::BufferImageCopy::default()
+                .buffer_offset(buffer_offset)
+                .buffer_row_length(extent.width)
+                .buffer_image_height(0)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_extent(extent),
+        });
+        Ok(())
+    }
+
+    /// Queues `buffer` to be filled with zeroes by [`Self::record_frame_start`].
+    pub fn clear_buffer(&mut self, buffer: &Buffer) {
+        self.clears.push(PendingClear::Buffer(buffer.buffer));
+    }
+
+    /// Queues `image` to be cleared to zero by [`Self::record_frame_start`], after which it's in the
+    /// layout of `first_usage`. The first barrier of the image has to come from that usage,
+    /// coming from [`Usage::Undefined`] would discard the clear again.
+    pub fn clear_image(&mut self, image: &Image, first_usage: Usage) {
+        assert_ne!(
+            first_usage.image_layout(),
+            vk::ImageLayout::UNDEFINED,
+            "Zero-initialized images need a usage with a layout"
+        );
+        self.clears.push(PendingClear::Image {
+            image: image.image,
+            aspect_mask: barrier::aspect_mask_from_format(image.format),
+            first_usage,
+        });
+    }
+
+    /// Queues the transition of `image` from `UNDEFINED` to the layout of `first_usage`, for
+    /// images created in the middle of a frame. The transitions of a frame are recorded as a
+    /// single barrier by [`Self::record_frame_start`], the first barrier of the image has to
+    /// come from `first_usage`.
+    pub fn transition_image(&mut self, image: &Image, first_usage: Usage) {
+        assert_ne!(
+            first_usage.image_layout(),
+            vk::ImageLayout::UNDEFINED,
+            "Images can only be transitioned to a usage with a layout"
+        );
+        self.transitions.push(PendingTransition {
+            image: image.image,
+            aspect_mask: barrier::aspect_mask_from_format(image.format),
+            first_usage,
+        });
+    }
+
+    /// Records the queued transitions and clears the zero-initialized resources, before
+    /// anything else is recorded into the graphics command buffer of the frame.
+    pub fn record_frame_start(&mut self, base: &ExampleBase, command_buffer: vk::CommandBuffer) {
+        if self.clears.is_empty() && self.transitions.is_empty() {
+            return;
+        }
+        let device = &base.device;
+        let clears = std::mem::take(&mut self.clears);
+
+        let mut batch = BarrierBatch::new();
+        for transition in self.transitions.drain(..) {
+            batch.image(
+                transition.image,
+                transition.aspect_mask,
+                Usage::Undefined,
+                transition.first_usage,
+            );
+        }
+        for clear in clears.iter() {
+            if let PendingClear::Image {
+                image, aspect_mask, ..
+            } = *clear
+            {
+                batch.image(image, aspect_mask, Usage::Undefined, Usage::TransferWrite);
+            }
+        }
+        batch.flush(&base.synchronization2, command_buffer);
+
+        for clear in clears.iter() {
+            match *clear {
+                PendingClear::Buffer(buffer) => {
+                    tracking::assert_alive(ResourceKind::Buffer, buffer);
+                    unsafe { device.cmd_fill_buffer(command_buffer, buffer, 0, vk::WHOLE_SIZE, 0) };
+                    batch.push_buffer(
+                        vk::BufferMemoryBarrier2::default()
+                            .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                            .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                            .dst_access_mask(
+                                vk::AccessFlags2::MEMORY_READ | vk::AccessFlags2::MEMORY_WRITE,
+                            )
+                            .buffer(buffer)
+                            .offset(0)
+                            .size(vk::WHOLE_SIZE),
+                    );
+                }
+                PendingClear::Image {
+                    image,
+                    aspect_mask,
+                    first_usage,
+                } => {
+                    let range = vk::ImageSubresourceRange {
+                        aspect_mask,
+                        level_count: vk::REMAINING_MIP_LEVELS,
+                        layer_count: vk::REMAINING_ARRAY_LAYERS,
+                        ..Default::default()
+                    };
+                    unsafe {
+                        if aspect_mask.contains(vk::ImageAspectFlags::COLOR) {
+                            device.cmd_clear_color_image(
+                                command_buffer,
+                                image,
+                                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                &vk::ClearColorValue::default(),
+                                &[range],
+                            );
+                        } else {
+                            device.cmd_clear_depth_stencil_image(
+                                command_buffer,
+                                image,
+                                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                &vk::ClearDepthStencilValue::default(),
+                                &[range],
+                            );
+                        }
+                    }
+                    batch.image(image, aspect_mask, Usage::TransferWrite, first_usage);
+                }
+            }
+        }
+        batch.flush(&base.synchronization2, command_buffer);
+    }
+
+    /// Copies `data` into a chunk with room left, returning the chunk and the offset.
+    fn stage(
+        &mut self,
+        device: &Device,
+        render_allocator: &RenderAllocator,
+        data: &[u8],
+    ) -> Result<(vk::Buffer, vk::DeviceSize)> {
+        self.reclaim(device)?;
+
+        let size = data.len() as vk::DeviceSize;
+        let chunk = match self
+            .chunks
+            .iter()
+            .position(|chunk| align_up(chunk.used, COPY_ALIGNMENT) + size <= chunk.buffer.size)
+        {
+            Some(index) => &mut self.chunks[index],
+            None => {
+                let mut buffer = Buffer::new(
+                    device,
+                    &mut render_allocator.allocator(),
+                    &vk::BufferCreateInfo::default()
+                        .size(size.max(CHUNK_SIZE))
+                        .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+                        .sharing_mode(vk::SharingMode::EXCLUSIVE),
+                    MemoryLocation::CpuToGpu,
+                )?;
+                buffer.set_name("staging belt chunk");
+                self.chunks.push(Chunk { buffer, used: 0 });
+                self.chunks.last_mut().unwrap()
+            }
+        };
+
+        let offset = align_up(chunk.used, COPY_ALIGNMENT);
+        chunk.buffer.copy_from_slice(data, offset as usize);
+        chunk.used = offset + size;
+        stats::count_bytes_uploaded(size);
+        Ok((chunk.buffer.buffer, offset))
+    }
+
+    /// Waits until the chunks are no longer read by the GPU, so they can be written again.
+    fn reclaim(&mut self, device: &Device) -> Result<()> {
+        if !self.in_flight {
+            return Ok(());
+        }
+        unsafe { device.wait_for_fences(&[self.fence], true, u64::MAX)? };
+        self.in_flight = false;
+        // oversized chunks are only kept around for the upload they were made for
+        self.chunks.retain(|chunk| chunk.buffer.size <= CHUNK_SIZE);
+        for chunk in self.chunks.iter_mut() {
+            chunk.used = 0;
+        }
+        Ok(())
+    }
+
+    /// Records all queued copies into one command buffer and submits it without waiting. The
+    /// returned wait has to be passed to the next graphics submission, and includes the ones of
+    /// earlier flushes that were never taken.
+    pub fn flush(&mut self, base: &ExampleBase) -> Result<Option<StagingWait>> {
+        profile_scope!("StagingBelt::flush");
+        if self.copies.is_empty() {
+            return Ok(self.pending_wait.take());
+        }
+
+        let device = &base.device;
+        let copies = std::mem::take(&mut self.copies);
+        let transfers_ownership = self.transfers_ownership();
+        let (src_queue_family_index, dst_queue_family_index) = if transfers_ownership {
+            (self.queue_family_index, self.graphics_queue_family_index)
+        } else {
+            (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+        };
+
+        let mut to_transfer_dst = Vec::new();
+        let mut release_buffers = Vec::new();
+        let mut release_images = Vec::new();
+        for copy in copies.iter() {
+            match copy {
+                StagingCopy::Buffer { dst, region, .. } => {
+                    release_buffers.push(
+                        vk::BufferMemoryBarrier2::default()
+                            .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                            .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                            .src_queue_family_index(src_queue_family_index)
+                            .dst_queue_family_index(dst_queue_family_index)
+                            .buffer(*dst)
+                            .offset(region.dst_offset)
+                            .size(region.size),
+                    );
+                }
+                StagingCopy::Image { dst, .. } => {
+                    // an image with several copies, like one per mip, is only transitioned once
+                    if to_transfer_dst
+                        .iter()
+                        .any(|barrier: &vk::ImageMemoryBarrier2| barrier.image == *dst)
+                    {
+                        continue;
+                    }
+                    to_transfer_dst.push(
+                        vk::ImageMemoryBarrier2::default()
+                            .dst_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                            .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                            .old_layout(vk::ImageLayout::UNDEFINED)
+                            .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                            .image(*dst)
+                            .subresource_range(WHOLE_COLOR_IMAGE),
+                    );
+                    release_images.push(
+                        vk::ImageMemoryBarrier2::default()
+                            .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                            .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                            .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                            .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                            .src_queue_family_index(src_queue_family_index)
+                            .dst_queue_family_index(dst_queue_family_index)
+                            .image(*dst)
+                            .subresource_range(WHOLE_COLOR_IMAGE),
+                    );
+                }
+            }
+        }
+
+        unsafe {
+            device.reset_fences(&[self.fence])?;
+            device.reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::empty())?;
+            device.begin_command_buffer(
+                self.command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+
+            base.synchronization2.cmd_pipeline_barrier2(
+                self.command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&to_transfer_dst),
+            );
+            for copy in copies.iter() {
+                match copy {
+                    StagingCopy::Buffer { src, dst, region } => device.cmd_copy_buffer(
+                        self.command_buffer,
+                        *src,
+                        *dst,
+                        std::slice::from_ref(region),
+                    ),
+                    StagingCopy::Image { src, dst, region } => device.cmd_copy_buffer_to_image(
+                        self.command_buffer,
+                        *src,
+                        *dst,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        std::slice::from_ref(region),
+                    ),
+                }
+            }
+            base.synchronization2.cmd_pipeline_barrier2(
+                self.command_buffer,
+                &vk::DependencyInfo::default()
+                    .buffer_memory_barriers(&release_buffers)
+                    .image_memory_barriers(&release_images),
+            );
+            device.end_command_buffer(self.command_buffer)?;
+
+            let previous_wait = self.pending_wait.take();
+            let wait_semaphores = previous_wait
+                .as_ref()
+                .map(|wait| wait.semaphore)
+                .into_iter()
+                .collect::<Vec<_>>();
+            let wait_mask = vec![vk::PipelineStageFlags::TRANSFER; wait_semaphores.len()];
+            self.semaphore_index = (self.semaphore_index + 1) % self.semaphores.len();
+            let signal_semaphores = [self.semaphores[self.semaphore_index]];
+            let command_buffers = [self.command_buffer];
+            let submit_info = vk::SubmitInfo::default()
+                .wait_semaphores(&wait_semaphores)
+                .wait_dst_stage_mask(&wait_mask)
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&signal_semaphores);
+
+            if self.queue == base.present_queue {
+                let _queue = base.queue_lock.lock().unwrap();
+                device.queue_submit(self.queue, &[submit_info], self.fence)?;
+            } else {
+                device.queue_submit(self.queue, &[submit_info], self.fence)?;
+            }
+            self.in_flight = true;
+
+            let mut wait = StagingWait {
+                semaphore: signal_semaphores[0],
+                stage: vk::PipelineStageFlags::ALL_COMMANDS,
+                buffer_barriers: Vec::new(),
+                image_barriers: Vec::new(),
+            };
+            if let Some(previous_wait) = previous_wait {
+                wait.buffer_barriers = previous_wait.buffer_barriers;
+                wait.image_barriers = previous_wait.image_barriers;
+            }
+            if transfers_ownership {
+                // the acquire has to repeat the release, minus the source scope
+                wait.buffer_barriers
+                    .extend(release_buffers.into_iter().map(|barrier| {
+                        barrier
+                            .src_stage_mask(vk::PipelineStageFlags2::NONE)
+                            .src_access_mask(vk::AccessFlags2::NONE)
+                            .dst_access_mask(vk::AccessFlags2::MEMORY_READ)
+                    }));
+                wait.image_barriers
+                    .extend(release_images.into_iter().map(|barrier| {
+                        barrier
+                            .src_stage_mask(vk::PipelineStageFlags2::NONE)
+                            .src_access_mask(vk::AccessFlags2::NONE)
+                            .dst_access_mask(vk::AccessFlags2::SHADER_READ)
+                    }));
+            }
+            Ok(Some(wait))
+        }
+    }
+
+    /// Puts a wait that wasn't used back, so the next [`Self::flush`] returns it again.
+    pub fn restore_wait(&mut self, wait: StagingWait) {
+        assert!(
+            self.pending_wait.is_none(),
+            "Only one staging wait can be pending"
+        );
+        self.pending_wait = Some(wait);
+    }
+
+    /// The device has to be idle. The chunks are dropped, like any other buffer.
+    pub fn destroy(&mut self, device: &Device) {
+        self.copies.clear();
+        self.clears.clear();
+        self.transitions.clear();
+        self.chunks.clear();
+        self.pending_wait = None;
+        unsafe {
+            for semaphore in self.semaphores {
+                device.destroy_semaphore(semaphore, None);
+            }
+            device.destroy_fence(self.fence, None);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (value + alignment - 1) / alignment * alignment
+}
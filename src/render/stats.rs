@@ -0,0 +1,192 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use bevy::prelude::*;
+
+use super::tracking::ResourceKind;
+
+/// Counters of the frame that is being recorded. Atomics, because secondary command buffers are
+/// recorded on the rayon pool and assets are uploaded from their own threads.
+struct Counters {
+    draws: AtomicU64,
+    dispatches: AtomicU64,
+    triangles: AtomicU64,
+    buffers_created: AtomicU64,
+    buffers_destroyed: AtomicU64,
+    images_created: AtomicU64,
+    images_destroyed: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    descriptor_writes: AtomicU64,
+    dropped_gpu_scopes: AtomicU64,
+    async_compute_nanos: AtomicU64,
+    async_compute_overlap_nanos: AtomicU64,
+}
+
+static COUNTERS: Counters = Counters {
+    draws: AtomicU64::new(0),
+    dispatches: AtomicU64::new(0),
+    triangles: AtomicU64::new(0),
+    buffers_created: AtomicU64::new(0),
+    buffers_destroyed: AtomicU64::new(0),
+    images_created: AtomicU64::new(0),
+    images_destroyed: AtomicU64::new(0),
+    bytes_uploaded: AtomicU64::new(0),
+    descriptor_writes: AtomicU64::new(0),
+    dropped_gpu_scopes: AtomicU64::new(0),
+    async_compute_nanos: AtomicU64::new(0),
+    async_compute_overlap_nanos: AtomicU64::new(0),
+};
+
+static GPU_PASS_TIMES: Mutex<Vec<(String, Duration)>> = Mutex::new(Vec::new());
+
+/// `triangles` is what the draw submits, indirect draws only count as a draw.
+pub(crate) fn count_draw(triangles: u64) {
+    COUNTERS.draws.fetch_add(1, Ordering::Relaxed);
+    COUNTERS.triangles.fetch_add(triangles, Ordering::Relaxed);
+}
+
+pub(crate) fn count_dispatch() {
+    COUNTERS.dispatches.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn count_created(kind: ResourceKind) {
+    match kind {
+        ResourceKind::Buffer => COUNTERS.buffers_created.fetch_add(1, Ordering::Relaxed),
+        ResourceKind::Image => COUNTERS.images_created.fetch_add(1, Ordering::Relaxed),
+        ResourceKind::ShaderModule => return,
+    };
+}
+
+pub(crate) fn count_destroyed(kind: ResourceKind) {
+    match kind {
+        ResourceKind::Buffer => COUNTERS.buffers_destroyed.fetch_add(1, Ordering::Relaxed),
+        ResourceKind::Image => COUNTERS.images_destroyed.fetch_add(1, Ordering::Relaxed),
+        ResourceKind::ShaderModule => return,
+    };
+}
+
+pub(crate) fn count_bytes_uploaded(bytes: u64) {
+    COUNTERS.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn count_descriptor_writes(writes: u64) {
+    COUNTERS
+        .descriptor_writes
+        .fetch_add(writes, Ordering::Relaxed);
+}
+
+pub(crate) fn count_dropped_gpu_scope() {
+    COUNTERS.dropped_gpu_scopes.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Adds an async compute submission that took `busy`, `overlap` of which the graphics queue was
+/// busy as well.
+pub(crate) fn record_async_compute(busy: Duration, overlap: Duration) {
+    COUNTERS
+        .async_compute_nanos
+        .fetch_add(busy.as_nanos() as u64, Ordering::Relaxed);
+    COUNTERS
+        .async_compute_overlap_nanos
+        .fetch_add(overlap.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Adds the GPU time a pass took to the stats of the current frame.
+pub fn record_gpu_pass_time(name: impl Into<String>, duration: Duration) {
+    GPU_PASS_TIMES.lock().unwrap().push((name.into(), duration));
+}
+
+/// What the renderer did during the previous frame, for overlays and logs. Updated at the end
+/// of every frame in the render world.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct FrameStats {
+    pub draws: u64,
+    pub dispatches: u64,
+    /// Assumes triangle lists.
+    pub triangles: u64,
+    pub buffers_created: u64,
+    pub buffers_destroyed: u64,
+    pub images_created: u64,
+    pub images_destroyed: u64,
+    /// Written to mapped buffers or queued on the staging belt.
+    pub bytes_uploaded: u64,
+    pub descriptor_writes: u64,
+    /// [`gpu_scope!`](crate::gpu_scope) scopes that weren't timed, because the frame ran out of
+    /// timestamp queries.
+    pub dropped_gpu_scopes: u64,
+    pub gpu_pass_times: Vec<(String, Duration)>,
+    /// Time the async compute queue was busy, measured by the GPU profiler a few frames late.
+    pub async_compute_time: Duration,
+    /// Part of [`FrameStats::async_compute_time`] the graphics queue was busy as well.
+    pub async_compute_overlap: Duration,
+}
+
+impl FrameStats {
+    /// Takes the counters collected since the last call, starting the next frame at zero.
+    fn take() -> Self {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+        Self {
+            draws: take(&COUNTERS.draws),
+            dispatches: take(&COUNTERS.dispatches),
+            triangles: take(&COUNTERS.triangles),
+            buffers_created: take(&COUNTERS.buffers_created),
+            buffers_destroyed: take(&COUNTERS.buffers_destroyed),
+            images_created: take(&COUNTERS.images_created),
+            images_destroyed: take(&COUNTERS.images_destroyed),
+            bytes_uploaded: take(&COUNTERS.bytes_uploaded),
+            descriptor_writes: take(&COUNTERS.descriptor_writes),
+            dropped_gpu_scopes: take(&COUNTERS.dropped_gpu_scopes),
+            gpu_pass_times: std::mem::take(&mut *GPU_PASS_TIMES.lock().unwrap()),
+            async_compute_time: Duration::from_nanos(take(&COUNTERS.async_compute_nanos)),
+            async_compute_overlap: Duration::from_nanos(take(
+                &COUNTERS.async_compute_overlap_nanos,
+            )),
+        }
+    }
+
+    pub fn gpu_time(&self) -> Duration {
+        self.gpu_pass_times
+            .iter()
+            .map(|(_, duration)| *duration)
+            .sum()
+    }
+
+    pub fn print(&self) {
+        println!(
+            "{} draws ({} triangles), {} dispatches, {} descriptor writes, {} bytes uploaded",
+            self.draws,
+            self.triangles,
+            self.dispatches,
+            self.descriptor_writes,
+            self.bytes_uploaded
+        );
+        println!(
+            "buffers +{} -{}, images +{} -{}",
+            self.buffers_created,
+            self.buffers_destroyed,
+            self.images_created,
+            self.images_destroyed
+        );
+        for (name, duration) in self.gpu_pass_times.iter() {
+            println!("{}: {:.3}ms", name, duration.as_secs_f64() * 1000.0);
+        }
+        if self.async_compute_time > Duration::ZERO {
+            println!(
+                "async compute: {:.3}ms, {:.3}ms overlapping graphics",
+                self.async_compute_time.as_secs_f64() * 1000.0,
+                self.async_compute_overlap.as_secs_f64() * 1000.0
+            );
+        }
+        if self.dropped_gpu_scopes > 0 {
+            println!("{} GPU scopes weren't timed", self.dropped_gpu_scopes);
+        }
+    }
+}
+
+pub(crate) fn collect_frame_stats(mut stats: ResMut<FrameStats>) {
+    *stats = FrameStats::take();
+}
@@ -0,0 +1,376 @@
+use std::collections::{BTreeMap, HashMap};
+
+use ash::vk;
+use bevy::prelude::*;
+use image::imageops::FilterType;
+
+use crate::error::Result;
+
+use super::{
+    extract::Extract, global_descriptors::GlobalDescriptorSet, image::Image, RenderAllocator,
+    RenderInstance,
+};
+
+/// Texture streaming settings, insert or change it in the main app. Without it every texture is
+/// uploaded at its full resolution.
+///
+/// Streamed textures start out with only their smallest mip resident. Finer mips are uploaded
+/// over the transfer queue in the frames after, as long as they fit in the budget. Going over
+/// the budget drops the finest mips of the textures with the lowest priority first.
+#[derive(Resource, Clone, Debug)]
+pub struct TextureStreaming {
+    /// Bytes the streamed textures may use together.
+    pub budget: u64,
+    /// Bytes uploaded per frame, at least one texture is grown every frame.
+    pub upload_limit: u64,
+    /// Textures without an entry use [`TextureResidency::default`].
+    pub residency: HashMap<Handle<Image>, TextureResidency>,
+}
+
+impl Default for TextureStreaming {
+    fn default() -> Self {
+        Self {
+            budget: 256 * 1024 * 1024,
+            upload_limit: 16 * 1024 * 1024,
+            residency: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TextureResidency {
+    /// Textures with a higher priority get their detail first and lose it last.
+    pub priority: f32,
+    /// Finest mip that should be resident, 0 for the full resolution.
+    pub min_mip: u32,
+}
+
+/// A texture of which only the mips from `resident_mip` down are on the GPU. All mips are kept
+/// on the CPU, so detail can be dropped and uploaded again. Uploaded as RGBA8, like
+/// [`crate::buffer::Image::from_image_buffer`].
+struct StreamedTexture {
+    format: vk::Format,
+    /// Tightly packed texels of every mip, the full resolution first.
+    mips: Vec<Vec<u8>>,
+    extent: vk::Extent2D,
+    resident_mip: u32,
+}
+
+impl StreamedTexture {
+    fn new(image: &Image) -> Self {
+        let extent = vk::Extent2D {
+            width: image.data.width(),
+            height: image.data.height(),
+        };
+        let mip_count = u32::BITS - extent.width.max(extent.height).leading_zeros();
+        let mips = (0..mip_count)
+            .map(|mip| {
+                let (width, height) = mip_extent(extent, mip);
+                if mip == 0 {
+                    image.data.to_rgba8().into_raw()
+                } else {
+                    image
+                        .data
+                        .resize_exact(width, height, FilterType::Triangle)
+                        .to_rgba8()
+                        .into_raw()
+                }
+            })
+            .collect::<Vec<_>>();
+        Self {
+            format: image.format,
+            extent,
+            resident_mip: mips.len() as u32,
+            mips,
+        }
+    }
+
+    fn mip_count(&self) -> u32 {
+        self.mips.len() as u32
+    }
+
+    /// Creates an image holding the mips from `resident_mip` down, and queues their upload.
+    fn upload(
+        &self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        resident_mip: u32,
+    ) -> Result<crate::buffer::Image> {
+        let device = render_instance.device();
+        let (width, height) = mip_extent(self.extent, resident_mip);
+        let level_count = self.mip_count() - resident_mip;
+        let mut image = crate::buffer::Image::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(self.format)
+                .extent(vk::Extent3D {
+                    width,
+                    height,
+                    depth: 1,
+                })
+                .mip_levels(level_count)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        )?;
+        image.set_name("streamed texture");
+
+        let mut staging_belt = render_instance.0.staging_belt.lock().unwrap();
+        for level in 0..level_count {
+            staging_belt.write_image_mip(
+                device,
+                render_allocator,
+                &self.mips[(resident_mip + level) as usize],
+                &image,
+                level,
+            )?;
+        }
+
+        image.set_view(unsafe {
+            device.create_image_view(
+                &vk::ImageViewCreateInfo::default()
+                    .image(image.image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(self.format)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    }),
+                None,
+            )?
+        });
+        Ok(image)
+    }
+}
+
+fn mip_extent(extent: vk::Extent2D, mip: u32) -> (u32, u32) {
+    ((extent.width >> mip).max(1), (extent.height >> mip).max(1))
+}
+
+/// Render world side of [`TextureStreaming`], decides which mips of every streamed texture are
+/// resident and swaps their images in [`GlobalDescriptorSet`].
+#[derive(Resource, Default)]
+pub struct TextureStreamer {
+    settings: Option<TextureStreaming>,
+    textures: BTreeMap<Handle<Image>, StreamedTexture>,
+}
+
+impl TextureStreamer {
+    /// When disabled, textures are uploaded at their full resolution.
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    /// Finest mip of the texture that is on the GPU, `None` for textures that aren't streamed.
+    pub fn resident_mip(&self, handle: &Handle<Image>) -> Option<u32> {
+        self.textures
+            .get(handle)
+            .map(|texture| texture.resident_mip)
+    }
+
+    /// Bytes of all resident mips together.
+    pub fn resident_bytes(&self) -> u64 {
+        self.textures
+            .values()
+            .map(|texture| resident_size(&mip_sizes(texture), texture.resident_mip))
+            .sum()
+    }
+
+    /// Starts streaming `image`, registering it with its smallest mip so it can be indexed right
+    /// away.
+    pub fn insert(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        global_descriptors: &mut GlobalDescriptorSet,
+        handle: Handle<Image>,
+        image: &Image,
+    ) -> Result<()> {
+        let mut texture = StreamedTexture::new(image);
+        let resident_mip = texture.mip_count() - 1;
+        let gpu_image = texture.upload(render_instance, render_allocator, resident_mip)?;
+        texture.resident_mip = resident_mip;
+        global_descriptors.insert_texture(render_instance, handle.clone(), gpu_image);
+        self.textures.insert(handle, texture);
+        Ok(())
+    }
+
+    /// Stops tracking the texture, its image stays registered until it's replaced.
+    pub fn remove(&mut self, handle: &Handle<Image>) {
+        self.textures.remove(handle);
+    }
+}
+
+fn mip_sizes(texture: &StreamedTexture) -> Vec<u64> {
+    texture.mips.iter().map(|mip| mip.len() as u64).collect()
+}
+
+fn resident_size(mip_sizes: &[u64], resident_mip: u32) -> u64 {
+    mip_sizes.iter().skip(resident_mip as usize).sum()
+}
+
+/// Input of [`plan_residency`].
+#[derive(Clone, Debug)]
+struct Residency {
+    mip_sizes: Vec<u64>,
+    /// `mip_sizes.len()` when nothing is resident yet.
+    resident_mip: u32,
+    min_mip: u32,
+    priority: f32,
+}
+
+/// Returns the resident mip every texture should have next. Every texture gets at least its
+/// smallest mip. Past the budget, the finest mips of the lowest priority textures are dropped.
+/// Under it, every texture that wants more detail grows by one mip, the highest priorities
+/// first, taking the room of textures with a lower priority when needed.
+fn plan_residency(textures: &[Residency], budget: u64, upload_limit: u64) -> Vec<u32> {
+    let coarsest = |i: usize| textures[i].mip_sizes.len() as u32 - 1;
+    let mut target = textures
+        .iter()
+        .enumerate()
+        .map(|(i, texture)| {
+            let wanted = texture.min_mip.min(coarsest(i));
+            texture.resident_mip.max(wanted).min(coarsest(i))
+        })
+        .collect::<Vec<_>>();
+    let mut resident = (0..textures.len())
+        .map(|i| resident_size(&textures[i].mip_sizes, target[i]))
+        .sum::<u64>();
+
+    // drops the finest mip of the lowest priority texture with one to spare, below `below`
+    let evict = |target: &mut [u32], resident: &mut u64, below: f32| {
+        let victim = (0..textures.len())
+            .filter(|&i| target[i] < coarsest(i) && textures[i].priority < below)
+            .min_by(|&a, &b| textures[a].priority.total_cmp(&textures[b].priority));
+        let Some(i) = victim else {
+            return false;
+        };
+        *resident -= textures[i].mip_sizes[target[i] as usize];
+        target[i] += 1;
+        true
+    };
+
+    while resident > budget {
+        if !evict(&mut target, &mut resident, f32::INFINITY) {
+            break;
+        }
+    }
+
+    let mut order = (0..textures.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| textures[b].priority.total_cmp(&textures[a].priority));
+    let mut uploaded = 0;
+    for i in order {
+        let texture = &textures[i];
+        // textures that were just evicted don't grow back in the same frame
+        if target[i] <= texture.min_mip || target[i] != texture.resident_mip {
+            continue;
+        }
+        let mip_size = texture.mip_sizes[target[i] as usize - 1];
+        let upload_size = resident_size(&texture.mip_sizes, target[i] - 1);
+        if uploaded > 0 && uploaded + upload_size > upload_limit {
+            break;
+        }
+        let mut fits = resident + mip_size <= budget;
+        while !fits && evict(&mut target, &mut resident, texture.priority) {
+            fits = resident + mip_size <= budget;
+        }
+        if !fits {
+            continue;
+        }
+        target[i] -= 1;
+        resident += mip_size;
+        uploaded += upload_size;
+    }
+    target
+}
+
+pub(crate) fn extract_texture_streaming(
+    settings: Extract<Option<Res<TextureStreaming>>>,
+    mut streamer: ResMut<TextureStreamer>,
+) {
+    if let Some(settings) = settings.as_ref() {
+        if settings.is_changed() {
+            streamer.settings = Some(TextureStreaming::clone(settings));
+        }
+    }
+}
+
+/// Uploads or drops mips of the streamed textures, every change replaces the image of the
+/// texture with one holding the new mips.
+pub(crate) fn stream_textures(
+    mut streamer: ResMut<TextureStreamer>,
+    render_instance: Res<RenderInstance>,
+    mut render_allocator: ResMut<RenderAllocator>,
+    mut global_descriptors: ResMut<GlobalDescriptorSet>,
+) {
+    let streamer = &mut *streamer;
+    let Some(settings) = streamer.settings.as_ref() else {
+        return;
+    };
+    let residency = streamer
+        .textures
+        .iter()
+        .map(|(handle, texture)| {
+            let residency = settings.residency.get(handle).copied().unwrap_or_default();
+            Residency {
+                mip_sizes: mip_sizes(texture),
+                resident_mip: texture.resident_mip,
+                min_mip: residency.min_mip,
+                priority: residency.priority,
+            }
+        })
+        .collect::<Vec<_>>();
+    let target = plan_residency(&residency, settings.budget, settings.upload_limit);
+
+    for ((handle, texture), resident_mip) in streamer.textures.iter_mut().zip(target) {
+        if texture.resident_mip == resident_mip {
+            continue;
+        }
+        let image = texture
+            .upload(&render_instance, &mut render_allocator, resident_mip)
+            .expect("Failed to upload the streamed texture");
+        // the old image is destroyed once the frames in flight are done with it
+        global_descriptors.insert_texture(&render_instance, handle.clone(), image);
+        texture.resident_mip = resident_mip;
+    }
+}
+
+#[test]
+fn test_plan_residency() {
+    // 4 mips of 64, 16, 4 and 1 bytes
+    let texture = |resident_mip, priority| Residency {
+        mip_sizes: vec![64, 16, 4, 1],
+        resident_mip,
+        min_mip: 0,
+        priority,
+    };
+
+    // new textures start with their smallest mip, and don't grow in the same frame
+    assert_eq!(plan_residency(&[texture(4, 0.0)], 1000, 1000), vec![3]);
+    // one mip per frame
+    assert_eq!(plan_residency(&[texture(3, 0.0)], 1000, 1000), vec![2]);
+    // the finest mip doesn't fit in the budget
+    assert_eq!(plan_residency(&[texture(1, 0.0)], 50, 1000), vec![1]);
+    // over budget drops the lowest priority first
+    assert_eq!(
+        plan_residency(&[texture(0, 1.0), texture(0, 0.0)], 100, 1000),
+        vec![0, 2]
+    );
+    // the higher priority takes the room of the lower priority
+    assert_eq!(
+        plan_residency(&[texture(0, 0.0), texture(1, 1.0)], 106, 1000),
+        vec![1, 0]
+    );
+    // the upload limit stops the lower priority from growing in the same frame
+    assert_eq!(
+        plan_residency(&[texture(2, 0.0), texture(2, 1.0)], 1000, 30),
+        vec![2, 1]
+    );
+}
@@ -0,0 +1,340 @@
+use std::{
+    backtrace::Backtrace,
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use ash::vk::{self, Handle};
+
+use super::stats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ResourceKind {
+    Buffer,
+    Image,
+    ShaderModule,
+}
+
+/// A buffer, image or shader module that has been created and not destroyed yet.
+#[derive(Debug, Clone)]
+pub struct LiveResource {
+    pub kind: ResourceKind,
+    pub handle: u64,
+    pub name: Option<String>,
+    /// Size and format of buffers and images, the SPIR-V hash of shader modules.
+    pub description: String,
+    /// Layout the last image barrier moved an image to.
+    pub layout: Option<vk::ImageLayout>,
+    /// View owned by an image, destroyed together with it.
+    pub view: Option<vk::ImageView>,
+    /// Dropped, but still waiting in the deferred destroy queue.
+    pub released: bool,
+    /// Only captured in debug builds, and like panics only when `RUST_BACKTRACE` is set.
+    pub backtrace: Option<Arc<Backtrace>>,
+    /// Where the resource was dropped, captured like `backtrace`.
+    pub released_backtrace: Option<Arc<Backtrace>>,
+}
+
+/// Destroyed resources remembered by [`assert_alive`], older ones are forgotten so the
+/// backtraces don't pile up in long sessions.
+const MAX_DESTROYED_RESOURCES: usize = 4096;
+
+/// Kept in debug builds, so recording a command that uses a destroyed handle panics with a
+/// useful message instead of losing the device later on.
+#[derive(Debug, Clone)]
+struct DestroyedResource {
+    name: Option<String>,
+    backtrace: Option<Arc<Backtrace>>,
+    /// Tells a destruction apart from an earlier one of a reused handle in the eviction order.
+    sequence: u64,
+}
+
+/// The last [`MAX_DESTROYED_RESOURCES`] destructions.
+#[derive(Debug, Default)]
+struct DestroyedResources {
+    resources: HashMap<(ResourceKind, u64), DestroyedResource>,
+    order: VecDeque<((ResourceKind, u64), u64)>,
+    next_sequence: u64,
+}
+
+impl DestroyedResources {
+    fn insert(&mut self, key: (ResourceKind, u64), mut resource: DestroyedResource) {
+        resource.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.order.push_back((key, resource.sequence));
+        self.resources.insert(key, resource);
+
+        while self.order.len() > MAX_DESTROYED_RESOURCES {
+            let (key, sequence) = self.order.pop_front().unwrap();
+            if self
+                .resources
+                .get(&key)
+                .is_some_and(|resource| resource.sequence == sequence)
+            {
+                self.resources.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &(ResourceKind, u64)) {
+        // the stale entry in `order` is skipped when it's evicted
+        self.resources.remove(key);
+    }
+
+    fn get(&self, key: &(ResourceKind, u64)) -> Option<&DestroyedResource> {
+        self.resources.get(key)
+    }
+}
+
+impl fmt::Display for LiveResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:#x} ({})",
+            self.kind,
+            self.handle,
+            self.name.as_deref().unwrap_or("unnamed")
+        )?;
+        if let Some(backtrace) = &self.backtrace {
+            write!(f, ", created at:\n{}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+fn live_resources() -> &'static Mutex<HashMap<(ResourceKind, u64), LiveResource>> {
+    static LIVE: OnceLock<Mutex<HashMap<(ResourceKind, u64), LiveResource>>> = OnceLock::new();
+    LIVE.get_or_init(Default::default)
+}
+
+fn destroyed_resources() -> &'static Mutex<DestroyedResources> {
+    static DESTROYED: OnceLock<Mutex<DestroyedResources>> = OnceLock::new();
+    DESTROYED.get_or_init(Default::default)
+}
+
+fn capture_backtrace() -> Option<Arc<Backtrace>> {
+    if cfg!(debug_assertions) {
+        Some(Arc::new(Backtrace::capture()))
+    } else {
+        None
+    }
+}
+
+pub(crate) fn track(kind: ResourceKind, handle: impl Handle, description: String) {
+    let handle = handle.as_raw();
+    stats::count_created(kind);
+    if cfg!(debug_assertions) {
+        // drivers are free to hand out the handle of a destroyed resource again
+        destroyed_resources()
+            .lock()
+            .unwrap()
+            .remove(&(kind, handle));
+    }
+    live_resources().lock().unwrap().insert(
+        (kind, handle),
+        LiveResource {
+            kind,
+            handle,
+            name: None,
+            description,
+            layout: None,
+            view: None,
+            released: false,
+            backtrace: capture_backtrace(),
+            released_backtrace: None,
+        },
+    );
+}
+
+pub(crate) fn untrack(kind: ResourceKind, handle: impl Handle) {
+    untrack_raw(kind, handle.as_raw());
+}
+
+fn untrack_raw(kind: ResourceKind, handle: u64) {
+    let resource = live_resources().lock().unwrap().remove(&(kind, handle));
+    stats::count_destroyed(kind);
+    if cfg!(debug_assertions) {
+        // dropped resources point at the drop instead of the deferred destroy queue
+        let destroyed = DestroyedResource {
+            name: resource.as_ref().and_then(|resource| resource.name.clone()),
+            backtrace: resource
+                .and_then(|resource| resource.released_backtrace)
+                .or_else(capture_backtrace),
+            sequence: 0,
+        };
+        destroyed_resources()
+            .lock()
+            .unwrap()
+            .insert((kind, handle), destroyed);
+    }
+}
+
+/// Panics in debug builds when `handle` has been destroyed, with its name and where it was
+/// destroyed. Called by the helpers that bind or copy resources.
+pub fn assert_alive(kind: ResourceKind, handle: impl Handle) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let handle = handle.as_raw();
+    let destroyed = destroyed_resources()
+        .lock()
+        .unwrap()
+        .get(&(kind, handle))
+        .cloned();
+    if let Some(destroyed) = destroyed {
+        panic!(
+            "{:?} {:#x} ({}) is used after it was destroyed{}",
+            kind,
+            handle,
+            destroyed.name.as_deref().unwrap_or("unnamed"),
+            destroyed
+                .backtrace
+                .map(|backtrace| format!(" at:\n{}", backtrace))
+                .unwrap_or_default()
+        );
+    }
+}
+
+pub(crate) fn mark_released(kind: ResourceKind, handle: impl Handle) {
+    if let Some(resource) = live_resources()
+        .lock()
+        .unwrap()
+        .get_mut(&(kind, handle.as_raw()))
+    {
+        resource.released = true;
+        resource.released_backtrace = capture_backtrace();
+    }
+}
+
+/// Name shown in the [`LeakReport`], untracked handles are ignored.
+pub fn set_name(kind: ResourceKind, handle: impl Handle, name: &str) {
+    if let Some(resource) = live_resources()
+        .lock()
+        .unwrap()
+        .get_mut(&(kind, handle.as_raw()))
+    {
+        resource.name = Some(name.to_string());
+    }
+}
+
+pub(crate) fn set_layout(image: vk::Image, layout: vk::ImageLayout) {
+    if let Some(resource) = live_resources()
+        .lock()
+        .unwrap()
+        .get_mut(&(ResourceKind::Image, image.as_raw()))
+    {
+        resource.layout = Some(layout);
+    }
+}
+
+pub(crate) fn set_view(image: vk::Image, view: vk::ImageView) {
+    if let Some(resource) = live_resources()
+        .lock()
+        .unwrap()
+        .get_mut(&(ResourceKind::Image, image.as_raw()))
+    {
+        resource.view = Some(view);
+    }
+}
+
+/// Resources that were still alive when the report was made.
+#[derive(Debug, Default)]
+pub struct LeakReport {
+    pub resources: Vec<LiveResource>,
+}
+
+impl LeakReport {
+    pub fn live() -> Self {
+        let mut resources = live_resources()
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        resources.sort_by_key(|resource| (resource.released, resource.handle));
+        Self { resources }
+    }
+
+    /// Never dropped, as opposed to still waiting for the deferred destroy queue.
+    pub fn leaked(&self) -> impl Iterator<Item = &LiveResource> {
+        self.resources.iter().filter(|resource| !resource.released)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+
+    pub fn print(&self) {
+        for resource in self.leaked() {
+            println!("Leaked {}", resource);
+        }
+        let queued = self.resources.len() - self.leaked().count();
+        if queued > 0 {
+            println!(
+                "{} dropped resources were never destroyed by the deferred destroy queue",
+                queued
+            );
+        }
+    }
+
+    /// Destroys the Vulkan handles of the leaked resources together with the views of leaked
+    /// images, their memory is freed together with the allocator. Released resources are left
+    /// to the deferred destroy queue. The device has to be idle.
+    pub fn destroy(&self, device: &ash::Device) {
+        for resource in self.leaked() {
+            unsafe {
+                match resource.kind {
+                    ResourceKind::Buffer => {
+                        device.destroy_buffer(vk::Buffer::from_raw(resource.handle), None)
+                    }
+                    ResourceKind::Image => {
+                        if let Some(view) = resource.view {
+                            device.destroy_image_view(view, None);
+                        }
+                        device.destroy_image(vk::Image::from_raw(resource.handle), None)
+                    }
+                    ResourceKind::ShaderModule => device
+                        .destroy_shader_module(vk::ShaderModule::from_raw(resource.handle), None),
+                }
+            }
+            untrack_raw(resource.kind, resource.handle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destroyed_resources_are_capped() {
+        let destroyed = |name: &str| DestroyedResource {
+            name: Some(name.to_string()),
+            backtrace: None,
+            sequence: 0,
+        };
+        let mut resources = DestroyedResources::default();
+        resources.insert((ResourceKind::Buffer, 0), destroyed("first"));
+        // the handle is reused and destroyed again, which shouldn't be evicted with the first
+        resources.remove(&(ResourceKind::Buffer, 0));
+        resources.insert((ResourceKind::Buffer, 0), destroyed("reused"));
+        for handle in 1..MAX_DESTROYED_RESOURCES as u64 {
+            resources.insert((ResourceKind::Image, handle), destroyed("image"));
+        }
+
+        assert_eq!(resources.resources.len(), MAX_DESTROYED_RESOURCES);
+        assert_eq!(
+            resources
+                .get(&(ResourceKind::Buffer, 0))
+                .and_then(|resource| resource.name.as_deref()),
+            Some("reused")
+        );
+        assert!(resources.get(&(ResourceKind::Image, 1)).is_some());
+
+        resources.insert((ResourceKind::Image, 0), destroyed("image"));
+        assert_eq!(resources.resources.len(), MAX_DESTROYED_RESOURCES);
+        assert!(resources.get(&(ResourceKind::Buffer, 0)).is_none());
+        assert!(resources.get(&(ResourceKind::Image, 0)).is_some());
+    }
+}
@@ -0,0 +1,829 @@
+use std::{mem, ptr};
+
+use ash::vk::{self, native};
+
+use crate::{
+    allocator::{Allocation, AllocationCreateDesc, AllocationScheme, MemoryHints, MemoryLocation},
+    buffer::{Buffer, Image},
+    error::{Error, Result},
+};
+
+use super::{shader_binding_table::align_up, RenderAllocator, RenderInstance};
+
+/// Decoded pictures are 8-bit 4:2:0, with luma and interleaved chroma in two planes.
+pub const PICTURE_FORMAT: vk::Format = vk::Format::G8_B8R8_2PLANE_420_UNORM;
+
+/// Upper bound of the decoded picture buffer, 16 references plus the picture being decoded.
+const MAX_DPB_SLOTS: u32 = 17;
+
+/// Parameter sets of a stream, parsed from its VPS, SPS and PPS NAL units. The codec of the
+/// [`VideoDecoder`] follows from them.
+#[derive(Clone, Copy, Debug)]
+pub enum VideoParameters<'a> {
+    H264 {
+        sps: &'a [native::StdVideoH264SequenceParameterSet],
+        pps: &'a [native::StdVideoH264PictureParameterSet],
+    },
+    H265 {
+        vps: &'a [native::StdVideoH265VideoParameterSet],
+        sps: &'a [native::StdVideoH265SequenceParameterSet],
+        pps: &'a [native::StdVideoH265PictureParameterSet],
+    },
+}
+
+/// Parsed slice header information of the picture being decoded.
+#[derive(Clone, Copy, Debug)]
+pub enum PictureInfo<'a> {
+    H264(&'a native::StdVideoDecodeH264PictureInfo),
+    H265(&'a native::StdVideoDecodeH265PictureInfo),
+}
+
+/// What later pictures need to know about a reference picture, like its picture order count.
+#[derive(Clone, Copy, Debug)]
+pub enum ReferenceInfo {
+    H264(native::StdVideoDecodeH264ReferenceInfo),
+    H265(native::StdVideoDecodeH265ReferenceInfo),
+}
+
+/// A single picture to decode.
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeFrame<'a> {
+    /// The slice NAL units of the picture, including their start codes.
+    pub bitstream: &'a [u8],
+    /// Offsets of the slices in `bitstream`.
+    pub slice_offsets: &'a [u32],
+    pub picture: PictureInfo<'a>,
+    /// Keeps the picture in the DPB under this id, so later pictures can reference it.
+    pub reference: Option<(u64, ReferenceInfo)>,
+    /// Ids of the pictures in the DPB this one is predicted from.
+    pub references: &'a [u64],
+}
+
+#[derive(Clone, Copy, Debug)]
+struct DpbSlot {
+    id: u64,
+    info: ReferenceInfo,
+}
+
+enum CodecDpbSlotInfo<'a> {
+    H264(vk::VideoDecodeH264DpbSlotInfoKHR<'a>),
+    H265(vk::VideoDecodeH265DpbSlotInfoKHR<'a>),
+}
+
+impl<'a> CodecDpbSlotInfo<'a> {
+    fn new(info: &'a ReferenceInfo) -> Self {
+        match info {
+            ReferenceInfo::H264(info) => CodecDpbSlotInfo::H264(
+                vk::VideoDecodeH264DpbSlotInfoKHR::default().std_reference_info(info),
+            ),
+            ReferenceInfo::H265(info) => CodecDpbSlotInfo::H265(
+                vk::VideoDecodeH265DpbSlotInfoKHR::default().std_reference_info(info),
+            ),
+        }
+    }
+
+    fn chain(
+        &'a mut self,
+        slot: vk::VideoReferenceSlotInfoKHR<'a>,
+    ) -> vk::VideoReferenceSlotInfoKHR<'a> {
+        match self {
+            CodecDpbSlotInfo::H264(info) => slot.push_next(info),
+            CodecDpbSlotInfo::H265(info) => slot.push_next(info),
+        }
+    }
+}
+
+/// Hardware H.264 and H.265 decoding through `VK_KHR_video_decode_queue`, on the video decode
+/// queue of the [`RenderInstance`]. Bitstream parsing is left to the caller, which passes the
+/// parameter sets and per picture slice header information as the `StdVideo` structs of the
+/// Vulkan video headers.
+///
+/// Decoded pictures are written to [`VideoDecoder::output`], a YCbCr image that has to be
+/// sampled with [`VideoDecoder::sampler`] as an immutable sampler, through
+/// [`VideoDecoder::output_view`]. The reference pictures live in their own array image, one
+/// layer per DPB slot, which requires the implementation to support a DPB that is distinct from
+/// the output.
+pub struct VideoDecoder {
+    pub extent: vk::Extent2D,
+    video_queue_fn: vk::KhrVideoQueueFn,
+    video_decode_fn: vk::KhrVideoDecodeQueueFn,
+    session: vk::VideoSessionKHR,
+    session_memory: Vec<Allocation>,
+    parameters: vk::VideoSessionParametersKHR,
+    dpb: Image,
+    dpb_view: vk::ImageView,
+    slots: Vec<Option<DpbSlot>>,
+    pub output: Image,
+    /// View of [`VideoDecoder::output`] with the YCbCr conversion, for sampling.
+    pub output_view: vk::ImageView,
+    decode_view: vk::ImageView,
+    ycbcr_conversion: vk::SamplerYcbcrConversion,
+    pub sampler: vk::Sampler,
+    bitstream: Buffer,
+    bitstream_size_alignment: u64,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    /// The session has to be reset before its first decode.
+    initialized: bool,
+}
+
+impl VideoDecoder {
+    /// Creates a session for pictures up to `extent`, with room for `max_bitstream_size` bytes
+    /// of slice data per picture.
+    pub fn new(
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+        parameters: VideoParameters,
+        extent: vk::Extent2D,
+        max_bitstream_size: u64,
+    ) -> Result<Self> {
+        let renderer = render_instance.0.as_ref();
+        let device = render_instance.device();
+        let queue_family_index = renderer
+            .video_decode_queue_family_index
+            .ok_or_else(|| Error::Unsupported("Device has no video decode queue".to_string()))?;
+
+        // the physical device queries aren't device level functions, so everything is loaded
+        // through the instance
+        let load = |name: &std::ffi::CStr| unsafe {
+            mem::transmute(
+                renderer
+                    .entry
+                    .get_instance_proc_addr(renderer.instance.handle(), name.as_ptr()),
+            )
+        };
+        let video_queue_fn = vk::KhrVideoQueueFn::load(load);
+        let video_decode_fn = vk::KhrVideoDecodeQueueFn::load(load);
+
+        let mut h264_profile = vk::VideoDecodeH264ProfileInfoKHR::default()
+            .std_profile_idc(native::StdVideoH264ProfileIdc_STD_VIDEO_H264_PROFILE_IDC_HIGH)
+            .picture_layout(vk::VideoDecodeH264PictureLayoutFlagsKHR::PROGRESSIVE);
+        let mut h265_profile = vk::VideoDecodeH265ProfileInfoKHR::default()
+            .std_profile_idc(native::StdVideoH265ProfileIdc_STD_VIDEO_H265_PROFILE_IDC_MAIN);
+        let profile = vk::VideoProfileInfoKHR::default()
+            .chroma_subsampling(vk::VideoChromaSubsamplingFlagsKHR::TYPE_420)
+            .luma_bit_depth(vk::VideoComponentBitDepthFlagsKHR::TYPE_8)
+            .chroma_bit_depth(vk::VideoComponentBitDepthFlagsKHR::TYPE_8);
+        let profile = match parameters {
+            VideoParameters::H264 { .. } => {
+                if !renderer.supports_video_decode_h264 {
+                    return Err(Error::Unsupported("Device can't decode H.264".to_string()));
+                }
+                profile
+                    .video_codec_operation(vk::VideoCodecOperationFlagsKHR::DECODE_H264)
+                    .push_next(&mut h264_profile)
+            }
+            VideoParameters::H265 { .. } => {
+                if !renderer.supports_video_decode_h265 {
+                    return Err(Error::Unsupported("Device can't decode H.265".to_string()));
+                }
+                profile
+                    .video_codec_operation(vk::VideoCodecOperationFlagsKHR::DECODE_H265)
+                    .push_next(&mut h265_profile)
+            }
+        };
+        let profiles = std::slice::from_ref(&profile);
+
+        let mut decode_capabilities = vk::VideoDecodeCapabilitiesKHR::default();
+        let mut h264_capabilities = vk::VideoDecodeH264CapabilitiesKHR::default();
+        let mut h265_capabilities = vk::VideoDecodeH265CapabilitiesKHR::default();
+        let mut capabilities =
+            vk::VideoCapabilitiesKHR::default().push_next(&mut decode_capabilities);
+        capabilities = match parameters {
+            VideoParameters::H264 { .. } => capabilities.push_next(&mut h264_capabilities),
+            VideoParameters::H265 { .. } => capabilities.push_next(&mut h265_capabilities),
+        };
+        unsafe {
+            (video_queue_fn.get_physical_device_video_capabilities_khr)(
+                renderer.pdevice,
+                &profile,
+                &mut capabilities,
+            )
+            .result()?
+        };
+        if extent.width > capabilities.max_coded_extent.width
+            || extent.height > capabilities.max_coded_extent.height
+        {
+            return Err(Error::Unsupported(format!(
+                "Video decoding is limited to {:?}",
+                capabilities.max_coded_extent
+            )));
+        }
+        let max_dpb_slots = capabilities.max_dpb_slots.min(MAX_DPB_SLOTS);
+        let max_active_reference_pictures = capabilities.max_active_reference_pictures;
+        let std_header_version = capabilities.std_header_version;
+        let bitstream_size_alignment = capabilities.min_bitstream_buffer_size_alignment;
+        if !decode_capabilities
+            .flags
+            .contains(vk::VideoDecodeCapabilityFlagsKHR::DPB_AND_OUTPUT_DISTINCT)
+        {
+            return Err(Error::Unsupported(
+                "Decoding into the DPB images isn't supported".to_string(),
+            ));
+        }
+
+        let session = unsafe {
+            let mut session = vk::VideoSessionKHR::null();
+            (video_queue_fn.create_video_session_khr)(
+                device.handle(),
+                &vk::VideoSessionCreateInfoKHR::default()
+                    .queue_family_index(queue_family_index)
+                    .video_profile(&profile)
+                    .picture_format(PICTURE_FORMAT)
+                    .max_coded_extent(extent)
+                    .reference_picture_format(PICTURE_FORMAT)
+                    .max_dpb_slots(max_dpb_slots)
+                    .max_active_reference_pictures(max_active_reference_pictures)
+                    .std_header_version(&std_header_version),
+                ptr::null(),
+                &mut session,
+            )
+            .result()?;
+            session
+        };
+
+        let session_memory = unsafe {
+            let mut count = 0;
+            (video_queue_fn.get_video_session_memory_requirements_khr)(
+                device.handle(),
+                session,
+                &mut count,
+                ptr::null_mut(),
+            )
+            .result()?;
+            let mut requirements =
+                vec![vk::VideoSessionMemoryRequirementsKHR::default(); count as usize];
+            (video_queue_fn.get_video_session_memory_requirements_khr)(
+                device.handle(),
+                session,
+                &mut count,
+                requirements.as_mut_ptr(),
+            )
+            .result()?;
+
+            let allocations = requirements
+                .iter()
+                .map(|requirement| {
+                    render_allocator
+                        .allocator()
+                        .allocate(&AllocationCreateDesc {
+                            name: "video session",
+                            requirements: requirement.memory_requirements,
+                            location: MemoryLocation::GpuOnly,
+                            linear: false,
+                            allocation_scheme: AllocationScheme::Managed,
+                            hints: MemoryHints::default(),
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let binds = requirements
+                .iter()
+                .zip(allocations.iter())
+                .map(|(requirement, allocation)| {
+                    vk::BindVideoSessionMemoryInfoKHR::default()
+                        .memory_bind_index(requirement.memory_bind_index)
+                        .memory(allocation.memory())
+                        .memory_offset(allocation.offset())
+                        .memory_size(requirement.memory_requirements.size)
+                })
+                .collect::<Vec<_>>();
+            (video_queue_fn.bind_video_session_memory_khr)(
+                device.handle(),
+                session,
+                binds.len() as u32,
+                binds.as_ptr(),
+            )
+            .result()?;
+            allocations
+        };
+
+        let create_parameters = |info: &vk::VideoSessionParametersCreateInfoKHR| unsafe {
+            let mut parameters = vk::VideoSessionParametersKHR::null();
+            (video_queue_fn.create_video_session_parameters_khr)(
+                device.handle(),
+                info,
+                ptr::null(),
+                &mut parameters,
+            )
+            .result()
+            .map(|_| parameters)
+        };
+        let parameters = match parameters {
+            VideoParameters::H264 { sps, pps } => {
+                let add_info = vk::VideoDecodeH264SessionParametersAddInfoKHR::default()
+                    .std_sp_ss(sps)
+                    .std_pp_ss(pps);
+                let mut codec_info = vk::VideoDecodeH264SessionParametersCreateInfoKHR::default()
+                    .max_std_sps_count(sps.len() as u32)
+                    .max_std_pps_count(pps.len() as u32)
+                    .parameters_add_info(&add_info);
+                create_parameters(
+                    &vk::VideoSessionParametersCreateInfoKHR::default()
+                        .video_session(session)
+                        .push_next(&mut codec_info),
+                )?
+            }
+            VideoParameters::H265 { vps, sps, pps } => {
+                let add_info = vk::VideoDecodeH265SessionParametersAddInfoKHR::default()
+                    .std_vp_ss(vps)
+                    .std_sp_ss(sps)
+                    .std_pp_ss(pps);
+                let mut codec_info = vk::VideoDecodeH265SessionParametersCreateInfoKHR::default()
+                    .max_std_vps_count(vps.len() as u32)
+                    .max_std_sps_count(sps.len() as u32)
+                    .max_std_pps_count(pps.len() as u32)
+                    .parameters_add_info(&add_info);
+                create_parameters(
+                    &vk::VideoSessionParametersCreateInfoKHR::default()
+                        .video_session(session)
+                        .push_next(&mut codec_info),
+                )?
+            }
+        };
+
+        let image_extent = vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        };
+        let mut dpb_profiles = vk::VideoProfileListInfoKHR::default().profiles(profiles);
+        let dpb = Image::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(PICTURE_FORMAT)
+                .extent(image_extent)
+                .mip_levels(1)
+                .array_layers(max_dpb_slots)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .push_next(&mut dpb_profiles),
+        )?;
+        let dpb_view = create_view(
+            device,
+            dpb.image,
+            vk::ImageViewType::TYPE_2D_ARRAY,
+            max_dpb_slots,
+            vk::ImageUsageFlags::VIDEO_DECODE_DPB_KHR,
+            None,
+        )?;
+
+        // decoded on the video queue and sampled on the graphics queue
+        let queue_family_indices = [renderer.queue_family_index, queue_family_index];
+        let mut output_profiles = vk::VideoProfileListInfoKHR::default().profiles(profiles);
+        let output = Image::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(PICTURE_FORMAT)
+                .extent(image_extent)
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR | vk::ImageUsageFlags::SAMPLED)
+                .sharing_mode(if queue_family_indices[0] == queue_family_indices[1] {
+                    vk::SharingMode::EXCLUSIVE
+                } else {
+                    vk::SharingMode::CONCURRENT
+                })
+                .queue_family_indices(&queue_family_indices)
+                .push_next(&mut output_profiles),
+        )?;
+
+        let ycbcr_conversion = unsafe {
+            device.create_sampler_ycbcr_conversion(
+                &vk::SamplerYcbcrConversionCreateInfo::default()
+                    .format(PICTURE_FORMAT)
+                    .ycbcr_model(vk::SamplerYcbcrModelConversion::YCBCR_709)
+                    .ycbcr_range(vk::SamplerYcbcrRange::ITU_NARROW)
+                    .x_chroma_offset(vk::ChromaLocation::COSITED_EVEN)
+                    .y_chroma_offset(vk::ChromaLocation::MIDPOINT)
+                    .chroma_filter(vk::Filter::LINEAR),
+                None,
+            )?
+        };
+        let sampler = unsafe {
+            device.create_sampler(
+                &vk::SamplerCreateInfo::default()
+                    .mag_filter(vk::Filter::LINEAR)
+                    .min_filter(vk::Filter::LINEAR)
+                    .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .push_next(
+                        &mut vk::SamplerYcbcrConversionInfo::default().conversion(ycbcr_conversion),
+                    ),
+                None,
+            )?
+        };
+        let output_view = create_view(
+            device,
+            output.image,
+            vk::ImageViewType::TYPE_2D,
+            1,
+            vk::ImageUsageFlags::SAMPLED,
+            Some(ycbcr_conversion),
+        )?;
+        let decode_view = create_view(
+            device,
+            output.image,
+            vk::ImageViewType::TYPE_2D,
+            1,
+            vk::ImageUsageFlags::VIDEO_DECODE_DST_KHR,
+            None,
+        )?;
+
+        let mut bitstream_profiles = vk::VideoProfileListInfoKHR::default().profiles(profiles);
+        let bitstream = Buffer::new(
+            device,
+            &mut render_allocator.allocator(),
+            &vk::BufferCreateInfo::default()
+                .size(align_up(
+                    max_bitstream_size,
+                    bitstream_size_alignment.max(1),
+                ))
+                .usage(vk::BufferUsageFlags::VIDEO_DECODE_SRC_KHR)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .push_next(&mut bitstream_profiles),
+            MemoryLocation::CpuToGpu,
+        )?;
+
+        let (command_pool, command_buffer, fence) = unsafe {
+            let command_pool = device.create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                    .queue_family_index(queue_family_index),
+                None,
+            )?;
+            let command_buffer = device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::default()
+                    .command_buffer_count(1)
+                    .command_pool(command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY),
+            )?[0];
+            let fence = device.create_fence(
+                &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
+                None,
+            )?;
+            (command_pool, command_buffer, fence)
+        };
+
+        Ok(Self {
+            extent,
+            video_queue_fn,
+            video_decode_fn,
+            session,
+            session_memory,
+            parameters,
+            dpb,
+            dpb_view,
+            slots: vec![None; max_dpb_slots as usize],
+            output,
+            output_view,
+            decode_view,
+            ycbcr_conversion,
+            sampler,
+            bitstream,
+            bitstream_size_alignment: bitstream_size_alignment.max(1),
+            command_pool,
+            command_buffer,
+            fence,
+            initialized: false,
+        })
+    }
+
+    /// Decodes `frame` into [`VideoDecoder::output`] and waits for it, so it can be sampled by
+    /// the graphics queue afterwards. The previous output must not be in use by the GPU anymore.
+    pub fn decode(&mut self, render_instance: &RenderInstance, frame: &DecodeFrame) -> Result<()> {
+        let renderer = render_instance.0.as_ref();
+        let device = render_instance.device();
+        assert!(
+            frame.bitstream.len() as u64 <= self.bitstream.size,
+            "Bitstream of {} bytes doesn't fit in the {} bytes the decoder was created for",
+            frame.bitstream.len(),
+            self.bitstream.size
+        );
+
+        let setup_slot = frame.reference.map(|(id, info)| {
+            let index = self
+                .slots
+                .iter()
+                .position(|slot| slot.is_none())
+                .expect("DPB is full, release pictures that are no longer referenced");
+            (index, DpbSlot { id, info })
+        });
+        let reference_slots = frame
+            .references
+            .iter()
+            .map(|id| {
+                let index = self
+                    .slot_index(*id)
+                    .unwrap_or_else(|| panic!("Picture {} is not in the DPB", id));
+                (index, self.slots[index].unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        unsafe {
+            device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+            device.reset_fences(&[self.fence])?;
+            device.reset_command_buffer(
+                self.command_buffer,
+                vk::CommandBufferResetFlags::RELEASE_RESOURCES,
+            )?;
+        }
+        self.bitstream.copy_from_slice(frame.bitstream, 0);
+
+        let picture_resource = |view: vk::ImageView, layer: u32| {
+            vk::VideoPictureResourceInfoKHR::default()
+                .coded_extent(self.extent)
+                .base_array_layer(layer)
+                .image_view_binding(view)
+        };
+        let slot_resources = (0..self.slots.len() as u32)
+            .map(|layer| picture_resource(self.dpb_view, layer))
+            .collect::<Vec<_>>();
+        let output_resource = picture_resource(self.decode_view, 0);
+
+        let mut reference_infos = reference_slots
+            .iter()
+            .map(|(_, slot)| CodecDpbSlotInfo::new(&slot.info))
+            .collect::<Vec<_>>();
+        let references = reference_slots
+            .iter()
+            .zip(reference_infos.iter_mut())
+            .map(|((index, _), info)| {
+                info.chain(
+                    vk::VideoReferenceSlotInfoKHR::default()
+                        .slot_index(*index as i32)
+                        .picture_resource(&slot_resources[*index]),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut setup_info = setup_slot
+            .as_ref()
+            .map(|(_, slot)| CodecDpbSlotInfo::new(&slot.info));
+        let setup = setup_slot
+            .as_ref()
+            .zip(setup_info.as_mut())
+            .map(|((index, _), info)| {
+                info.chain(
+                    vk::VideoReferenceSlotInfoKHR::default()
+                        .slot_index(*index as i32)
+                        .picture_resource(&slot_resources[*index]),
+                )
+            });
+
+        // the slot being set up isn't active yet, which is signaled with a negative index
+        let mut bound_slots = reference_slots
+            .iter()
+            .map(|(index, _)| {
+                vk::VideoReferenceSlotInfoKHR::default()
+                    .slot_index(*index as i32)
+                    .picture_resource(&slot_resources[*index])
+            })
+            .collect::<Vec<_>>();
+        if let Some((index, _)) = setup_slot.as_ref() {
+            bound_slots.push(
+                vk::VideoReferenceSlotInfoKHR::default()
+                    .slot_index(-1)
+                    .picture_resource(&slot_resources[*index]),
+            );
+        }
+
+        let mut h264_picture;
+        let mut h265_picture;
+        let mut decode_info = vk::VideoDecodeInfoKHR::default()
+            .src_buffer(self.bitstream.buffer)
+            .src_buffer_offset(0)
+            .src_buffer_range(align_up(
+                frame.bitstream.len() as u64,
+                self.bitstream_size_alignment,
+            ))
+            .dst_picture_resource(output_resource)
+            .reference_slots(&references);
+        if let Some(setup) = setup.as_ref() {
+            decode_info = decode_info.setup_reference_slot(setup);
+        }
+        decode_info = match frame.picture {
+            PictureInfo::H264(info) => {
+                h264_picture = vk::VideoDecodeH264PictureInfoKHR::default()
+                    .std_picture_info(info)
+                    .slice_offsets(frame.slice_offsets);
+                decode_info.push_next(&mut h264_picture)
+            }
+            PictureInfo::H265(info) => {
+                h265_picture = vk::VideoDecodeH265PictureInfoKHR::default()
+                    .std_picture_info(info)
+                    .slice_segment_offsets(frame.slice_offsets);
+                decode_info.push_next(&mut h265_picture)
+            }
+        };
+
+        unsafe {
+            device.begin_command_buffer(
+                self.command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+
+            let mut barriers = vec![layout_barrier(
+                self.output.image,
+                1,
+                if self.initialized {
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+                } else {
+                    vk::ImageLayout::UNDEFINED
+                },
+                vk::ImageLayout::VIDEO_DECODE_DST_KHR,
+                vk::AccessFlags2::VIDEO_DECODE_WRITE_KHR,
+            )];
+            if !self.initialized {
+                barriers.push(layout_barrier(
+                    self.dpb.image,
+                    self.slots.len() as u32,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::VIDEO_DECODE_DPB_KHR,
+                    vk::AccessFlags2::VIDEO_DECODE_READ_KHR
+                        | vk::AccessFlags2::VIDEO_DECODE_WRITE_KHR,
+                ));
+            }
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                self.command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&barriers),
+            );
+
+            (self.video_queue_fn.cmd_begin_video_coding_khr)(
+                self.command_buffer,
+                &vk::VideoBeginCodingInfoKHR::default()
+                    .video_session(self.session)
+                    .video_session_parameters(self.parameters)
+                    .reference_slots(&bound_slots),
+            );
+            if !self.initialized {
+                (self.video_queue_fn.cmd_control_video_coding_khr)(
+                    self.command_buffer,
+                    &vk::VideoCodingControlInfoKHR::default()
+                        .flags(vk::VideoCodingControlFlagsKHR::RESET),
+                );
+            }
+            (self.video_decode_fn.cmd_decode_video_khr)(self.command_buffer, &decode_info);
+            (self.video_queue_fn.cmd_end_video_coding_khr)(
+                self.command_buffer,
+                &vk::VideoEndCodingInfoKHR::default(),
+            );
+
+            // the fence wait makes the output visible to the graphics queue
+            renderer.synchronization2.cmd_pipeline_barrier2(
+                self.command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[vk::ImageMemoryBarrier2 {
+                    dst_stage_mask: vk::PipelineStageFlags2::NONE,
+                    dst_access_mask: vk::AccessFlags2::NONE,
+                    ..layout_barrier(
+                        self.output.image,
+                        1,
+                        vk::ImageLayout::VIDEO_DECODE_DST_KHR,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        vk::AccessFlags2::VIDEO_DECODE_WRITE_KHR,
+                    )
+                }]),
+            );
+
+            device.end_command_buffer(self.command_buffer)?;
+
+            let command_buffers = [self.command_buffer];
+            device.queue_submit(
+                renderer.video_decode_queue.unwrap(),
+                &[vk::SubmitInfo::default().command_buffers(&command_buffers)],
+                self.fence,
+            )?;
+            device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+        }
+
+        self.initialized = true;
+        if let Some((index, slot)) = setup_slot {
+            self.slots[index] = Some(slot);
+        }
+        Ok(())
+    }
+
+    /// Removes a picture from the DPB once no later picture references it anymore.
+    pub fn release(&mut self, id: u64) {
+        if let Some(index) = self.slot_index(id) {
+            self.slots[index] = None;
+        }
+    }
+
+    /// Drops all reference pictures, e.g. before an IDR picture.
+    pub fn release_all(&mut self) {
+        self.slots.iter_mut().for_each(|slot| *slot = None);
+    }
+
+    fn slot_index(&self, id: u64) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.is_some_and(|slot| slot.id == id))
+    }
+
+    pub fn destroy(
+        &mut self,
+        render_instance: &RenderInstance,
+        render_allocator: &mut RenderAllocator,
+    ) {
+        let device = render_instance.device();
+        let mut allocator = render_allocator.allocator();
+        unsafe {
+            device
+                .wait_for_fences(&[self.fence], true, u64::MAX)
+                .unwrap();
+            device.destroy_fence(self.fence, None);
+            device.destroy_command_pool(self.command_pool, None);
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_sampler_ycbcr_conversion(self.ycbcr_conversion, None);
+            device.destroy_image_view(self.output_view, None);
+            device.destroy_image_view(self.decode_view, None);
+            device.destroy_image_view(self.dpb_view, None);
+            (self.video_queue_fn.destroy_video_session_parameters_khr)(
+                device.handle(),
+                self.parameters,
+                ptr::null(),
+            );
+            (self.video_queue_fn.destroy_video_session_khr)(
+                device.handle(),
+                self.session,
+                ptr::null(),
+            );
+        }
+        for allocation in self.session_memory.drain(..) {
+            allocator.free(allocation).unwrap();
+        }
+        self.output.destroy(device, &mut allocator);
+        self.dpb.destroy(device, &mut allocator);
+        self.bitstream.destroy(device, &mut allocator);
+    }
+}
+
+fn create_view(
+    device: &ash::Device,
+    image: vk::Image,
+    view_type: vk::ImageViewType,
+    layer_count: u32,
+    usage: vk::ImageUsageFlags,
+    ycbcr_conversion: Option<vk::SamplerYcbcrConversion>,
+) -> Result<vk::ImageView> {
+    // views of the multi-planar output need a conversion if they can be sampled
+    let mut usage_info = vk::ImageViewUsageCreateInfo::default().usage(usage);
+    let mut conversion_info =
+        vk::SamplerYcbcrConversionInfo::default().conversion(ycbcr_conversion.unwrap_or_default());
+    let mut info = vk::ImageViewCreateInfo::default()
+        .image(image)
+        .view_type(view_type)
+        .format(PICTURE_FORMAT)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count,
+        })
+        .push_next(&mut usage_info);
+    if ycbcr_conversion.is_some() {
+        info = info.push_next(&mut conversion_info);
+    }
+    Ok(unsafe { device.create_image_view(&info, None) }?)
+}
+
+fn layout_barrier(
+    image: vk::Image,
+    layer_count: u32,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    access: vk::AccessFlags2,
+) -> vk::ImageMemoryBarrier2<'static> {
+    vk::ImageMemoryBarrier2::default()
+        .src_stage_mask(vk::PipelineStageFlags2::VIDEO_DECODE_KHR)
+        .src_access_mask(if old_layout == vk::ImageLayout::UNDEFINED {
+            vk::AccessFlags2::NONE
+        } else {
+            access
+        })
+        .old_layout(old_layout)
+        .dst_stage_mask(vk::PipelineStageFlags2::VIDEO_DECODE_KHR)
+        .dst_access_mask(access)
+        .new_layout(new_layout)
+        .image(image)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count,
+        })
+}
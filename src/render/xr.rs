@@ -0,0 +1,228 @@
+use std::{ffi::CString, mem::ManuallyDrop};
+
+use ash::vk::{self, Handle};
+use openxr as xr;
+
+use crate::{
+    buffer::Image,
+    ctx::DeviceRequirements,
+    error::{Error, Result},
+};
+
+use super::{
+    barrier::Usage,
+    passes::graphics::{ColorAttachment, RenderTarget},
+    RenderInstance,
+};
+
+/// One layer per eye in the swapchain images, rendered at once with multiview.
+pub const VIEW_COUNT: u32 = 2;
+
+fn parse_extensions(names: String) -> Result<Vec<CString>> {
+    names
+        .split_whitespace()
+        .map(|name| {
+            CString::new(name)
+                .map_err(|_| Error::Unsupported(format!("Invalid extension name {:?}", name)))
+        })
+        .collect()
+}
+
+/// What the runtime needs from the Vulkan device, through `XR_KHR_vulkan_enable`. Insert the
+/// result as a resource before adding the render plugin, so the device is created on the
+/// physical device of the headset.
+pub fn device_requirements(
+    xr_instance: &xr::Instance,
+    system: xr::SystemId,
+) -> Result<DeviceRequirements> {
+    let instance_extensions =
+        parse_extensions(xr_instance.vulkan_legacy_instance_extensions(system)?)?;
+    let device_extensions = parse_extensions(xr_instance.vulkan_legacy_device_extensions(system)?)?;
+
+    let xr_instance = xr_instance.clone();
+    Ok(DeviceRequirements {
+        instance_extensions,
+        device_extensions,
+        multiview: true,
+        hdr_output: false,
+        surface_formats: vec![],
+        shader_printf: false,
+        cooperative_matrix: false,
+        small_types: Default::default(),
+        scalar_block_layout: false,
+        gpu_assisted_validation: false,
+        robustness: false,
+        physical_device: Some(Box::new(
+            move |instance: &ash::Instance| -> Result<vk::PhysicalDevice> {
+                let physical_device = unsafe {
+                    xr_instance.vulkan_graphics_device(system, instance.handle().as_raw() as _)?
+                };
+                Ok(vk::PhysicalDevice::from_raw(physical_device as u64))
+            },
+        )),
+    })
+}
+
+/// An OpenXR session that renders with the device of the [`RenderInstance`], submitting on its
+/// present queue.
+pub struct XrSession {
+    pub session: xr::Session<xr::Vulkan>,
+    pub frame_waiter: xr::FrameWaiter,
+    pub frame_stream: xr::FrameStream<xr::Vulkan>,
+}
+
+impl XrSession {
+    /// The render instance has to be created with the [`device_requirements`] of `system`.
+    pub fn new(
+        render_instance: &RenderInstance,
+        xr_instance: &xr::Instance,
+        system: xr::SystemId,
+    ) -> Result<Self> {
+        let renderer = render_instance.0.as_ref();
+        assert!(
+            renderer.multiview,
+            "Create the render instance with xr::device_requirements"
+        );
+
+        // the runtime refuses to create a session if the requirements weren't queried first
+        xr_instance.graphics_requirements::<xr::Vulkan>(system)?;
+
+        let (session, frame_waiter, frame_stream) = unsafe {
+            xr_instance.create_session::<xr::Vulkan>(
+                system,
+                &xr::vulkan::SessionCreateInfo {
+                    instance: renderer.instance.handle().as_raw() as _,
+                    physical_device: renderer.pdevice.as_raw() as _,
+                    device: renderer.device.handle().as_raw() as _,
+                    queue_family_index: renderer.queue_family_index,
+                    queue_index: 0,
+                },
+            )
+        }?;
+
+        Ok(Self {
+            session,
+            frame_waiter,
+            frame_stream,
+        })
+    }
+}
+
+/// A swapchain of the XR runtime, its images are array images with a layer per eye. They are
+/// owned by the runtime, so only their views are destroyed here.
+pub struct XrSwapchain {
+    pub handle: xr::Swapchain<xr::Vulkan>,
+    pub images: Vec<ManuallyDrop<Image>>,
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+}
+
+impl XrSwapchain {
+    pub fn new(
+        render_instance: &RenderInstance,
+        session: &xr::Session<xr::Vulkan>,
+        format: vk::Format,
+        extent: vk::Extent2D,
+    ) -> Result<Self> {
+        let handle = session.create_swapchain(&xr::SwapchainCreateInfo {
+            create_flags: xr::SwapchainCreateFlags::EMPTY,
+            usage_flags: xr::SwapchainUsageFlags::COLOR_ATTACHMENT
+                | xr::SwapchainUsageFlags::SAMPLED,
+            format: format.as_raw() as u32,
+            sample_count: 1,
+            width: extent.width,
+            height: extent.height,
+            face_count: 1,
+            array_size: VIEW_COUNT,
+            mip_count: 1,
+        })?;
+        let raw_images = handle.enumerate_images()?;
+
+        let mut swapchain = Self {
+            handle,
+            images: Vec::with_capacity(raw_images.len()),
+            format,
+            extent,
+        };
+        for raw_image in raw_images {
+            let image = vk::Image::from_raw(raw_image);
+            let view = unsafe {
+                render_instance.device().create_image_view(
+                    &vk::ImageViewCreateInfo::default()
+                        .image(image)
+                        .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+                        .format(format)
+                        .subresource_range(vk::ImageSubresourceRange {
+                            aspect_mask: vk::ImageAspectFlags::COLOR,
+                            base_mip_level: 0,
+                            level_count: 1,
+                            base_array_layer: 0,
+                            layer_count: VIEW_COUNT,
+                        }),
+                    None,
+                )
+            }
+            .map_err(|error| {
+                // the views created so far
+                swapchain.destroy(render_instance);
+                error
+            })?;
+
+            swapchain.images.push(ManuallyDrop::new(Image {
+                image,
+                allocation: None,
+                view: Some(view),
+                format,
+                extent: vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                },
+                offset: 0,
+            }));
+        }
+
+        Ok(swapchain)
+    }
+
+    /// Acquires the next image and waits until the runtime is done with it, returns its index
+    /// into [`XrSwapchain::images`].
+    pub fn acquire(&mut self) -> Result<usize> {
+        let index = self.handle.acquire_image()?;
+        self.handle.wait_image(xr::Duration::INFINITE)?;
+        Ok(index as usize)
+    }
+
+    /// Hands the acquired image back to the runtime, after the commands rendering to it have
+    /// been submitted.
+    pub fn release(&mut self) -> Result<()> {
+        self.handle.release_image()?;
+        Ok(())
+    }
+
+    /// Renders to both eyes of the image at `index` with multiview. The runtime expects the
+    /// image in the color attachment layout when it's released, so that's where it's left.
+    pub fn render_target(&self, index: usize, clear: Option<[f32; 4]>) -> RenderTarget {
+        let image = &self.images[index];
+        let mut target = RenderTarget::new(self.extent).stereo();
+        target.color_attachments.push(ColorAttachment {
+            image: image.image,
+            view: image
+                .view
+                .expect("Swapchain images keep their view until destroyed"),
+            format: self.format,
+            clear,
+            initial_usage: Usage::Undefined,
+            final_usage: Usage::ColorAttachmentWrite,
+        });
+        target
+    }
+
+    pub fn destroy(&mut self, render_instance: &RenderInstance) {
+        for image in self.images.iter_mut() {
+            if let Some(view) = image.view.take() {
+                unsafe { render_instance.device().destroy_image_view(view, None) };
+            }
+        }
+    }
+}